@@ -0,0 +1,26 @@
+extern crate nes;
+
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+
+use nes::graphics::{NES_HEIGHT, NES_WIDTH};
+use nes::Emulator;
+
+// Runs a ROM without any window or audio device and saves the last frame as
+// a PPM image. Works with `--no-default-features`.
+fn main() -> Result<(), String> {
+    let mut nes_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    nes_path.push("tests/resources/smb.nes");
+    let mut emulator = Emulator::from_file(nes_path)?;
+
+    for _ in 0..180 {
+        emulator.run_frame().map_err(|e| e.to_string())?;
+    }
+
+    let mut file = File::create("frame.ppm").map_err(|e| e.to_string())?;
+    write!(file, "P6\n{} {}\n255\n", NES_WIDTH, NES_HEIGHT).map_err(|e| e.to_string())?;
+    file.write_all(emulator.frame().as_bytes())
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
@@ -0,0 +1,273 @@
+// Building blocks shared by several APU channels
+// Ref: https://wiki.nesdev.org/w/index.php/APU
+
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+// ----------------------------------------------------------------------------
+// LengthCounter
+// ----------------------------------------------------------------------------
+
+// Indexed by the 5-bit length counter load value written to $4003/$4007/$400B/$400F
+#[rustfmt::skip]
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20,  2, 40,  4, 80,  6, 160,  8, 60, 10, 14, 12, 26, 14,
+    12,  16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LengthCounter {
+    // channel enable bit from $4015
+    enabled: bool,
+    // halt flag (shared with envelope loop / triangle control flag)
+    pub halt: bool,
+    counter: u8,
+    // the counter before the half frame clock of the current CPU cycle, if
+    // it was clocked
+    #[cfg_attr(feature = "serde", serde(skip))]
+    clocked_from: Option<u8>,
+}
+
+impl LengthCounter {
+    pub fn new() -> Self {
+        LengthCounter {
+            enabled: false,
+            halt: false,
+            counter: 0,
+            clocked_from: None,
+        }
+    }
+
+    // A reload in the same cycle as a half frame clock is ignored, unless
+    // the counter was 0. The halt flag needs nothing like it: writes come
+    // after the clock in the cycle, so a change only counts for the next.
+    // Ref: https://wiki.nesdev.org/w/index.php/APU_Length_Counter
+    pub fn load(&mut self, idx: u8) {
+        let reload_ignored = matches!(self.clocked_from, Some(counter) if counter > 0);
+        if self.enabled && !reload_ignored {
+            self.counter = LENGTH_TABLE[(idx & 0b1_1111) as usize];
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        // clearing the enable bit immediately silences the channel
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    // clocked by the frame counter on half frames
+    pub fn clock(&mut self) {
+        self.clocked_from = Some(self.counter);
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    // Called before the frame counter on every CPU cycle
+    pub fn start_cycle(&mut self) {
+        self.clocked_from = None;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.counter > 0
+    }
+}
+
+impl Default for LengthCounter {
+    fn default() -> Self {
+        LengthCounter::new()
+    }
+}
+
+// ----------------------------------------------------------------------------
+// Envelope
+// ----------------------------------------------------------------------------
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Envelope {
+    start: bool,
+    pub loop_flag: bool,
+    constant_volume: bool,
+    // volume in constant mode, or the divider period in envelope mode
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Envelope {
+            start: false,
+            loop_flag: false,
+            constant_volume: false,
+            volume: 0,
+            divider: 0,
+            decay: 0,
+        }
+    }
+
+    // --LC VVVV
+    pub fn write(&mut self, value: u8) {
+        self.loop_flag = value & 0b0010_0000 != 0;
+        self.constant_volume = value & 0b0001_0000 != 0;
+        self.volume = value & 0b0000_1111;
+    }
+
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    // clocked by the frame counter on quarter frames
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+            return;
+        }
+
+        if self.divider > 0 {
+            self.divider -= 1;
+            return;
+        }
+
+        self.divider = self.volume;
+        if self.decay > 0 {
+            self.decay -= 1;
+        } else if self.loop_flag {
+            self.decay = 15;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant_volume {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Envelope::new()
+    }
+}
+
+impl SaveState for LengthCounter {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_bool(self.halt);
+        w.write_u8(self.counter);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.enabled = r.read_bool()?;
+        self.halt = r.read_bool()?;
+        self.counter = r.read_u8()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Envelope {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.start);
+        w.write_bool(self.loop_flag);
+        w.write_bool(self.constant_volume);
+        w.write_u8(self.volume);
+        w.write_u8(self.divider);
+        w.write_u8(self.decay);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.start = r.read_bool()?;
+        self.loop_flag = r.read_bool()?;
+        self.constant_volume = r.read_bool()?;
+        self.volume = r.read_u8()?;
+        self.divider = r.read_u8()?;
+        self.decay = r.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_length_counter_load_requires_enabled() {
+        let mut length = LengthCounter::new();
+        length.load(0);
+        assert!(!length.is_active());
+
+        length.set_enabled(true);
+        length.load(3); // 2 ticks
+        assert!(length.is_active());
+        length.clock();
+        length.clock();
+        assert!(!length.is_active());
+    }
+
+    #[test]
+    fn test_length_counter_halt() {
+        let mut length = LengthCounter::new();
+        length.set_enabled(true);
+        length.load(3);
+        length.halt = true;
+        for _ in 0..10 {
+            length.clock();
+        }
+        assert!(length.is_active());
+
+        length.set_enabled(false);
+        assert!(!length.is_active());
+    }
+
+    #[test]
+    fn test_length_counter_reload_during_clock() {
+        let mut length = LengthCounter::new();
+        length.set_enabled(true);
+        length.load(3);
+        length.clock();
+        // ignored, the counter was 2
+        length.load(1);
+        assert_eq!(length.counter, 1);
+        length.start_cycle();
+        length.load(1);
+        assert_eq!(length.counter, 254);
+
+        // a counter clocked from 0 is reloaded
+        length.set_enabled(false);
+        length.set_enabled(true);
+        length.start_cycle();
+        length.clock();
+        length.load(3);
+        assert_eq!(length.counter, 2);
+    }
+
+    #[test]
+    fn test_envelope_decay() {
+        let mut envelope = Envelope::new();
+        // period 0, no loop
+        envelope.write(0b0000_0000);
+        envelope.restart();
+        envelope.clock();
+        assert_eq!(envelope.output(), 15);
+        for _ in 0..15 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.output(), 0);
+        envelope.clock();
+        assert_eq!(envelope.output(), 0);
+
+        // looping envelope restarts from 15
+        envelope.write(0b0010_0000);
+        envelope.clock();
+        assert_eq!(envelope.output(), 15);
+
+        // constant volume
+        envelope.write(0b0001_0111);
+        assert_eq!(envelope.output(), 7);
+    }
+}
@@ -0,0 +1,276 @@
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+// Ref: https://wiki.nesdev.org/w/index.php/APU_DMC
+// NTSC rates in CPU cycles
+#[rustfmt::skip]
+const DMC_RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DMC {
+    irq_enabled: bool,
+    loop_flag: bool,
+    timer_period: u16,
+    timer: u16,
+
+    // 7-bit output level
+    output_level: u8,
+
+    // memory reader
+    sample_addr: u16,
+    sample_len: u16,
+    current_addr: u16,
+    bytes_remaining: u16,
+    // a fetched sample byte waiting to be shifted out
+    sample_buffer: Option<u8>,
+
+    // output unit
+    shift_reg: u8,
+    bits_remaining: u8,
+    silence: bool,
+
+    pub irq: bool,
+}
+
+impl DMC {
+    pub fn new() -> Self {
+        DMC {
+            irq_enabled: false,
+            loop_flag: false,
+            timer_period: DMC_RATE_TABLE[0],
+            timer: 0,
+            output_level: 0,
+            sample_addr: 0xC000,
+            sample_len: 1,
+            current_addr: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_reg: 0,
+            bits_remaining: 8,
+            silence: true,
+            irq: false,
+        }
+    }
+
+    // reg_idx is the register offset from $4010
+    pub fn write(&mut self, reg_idx: u16, value: u8) {
+        match reg_idx {
+            // IL-- RRRR
+            0 => {
+                self.irq_enabled = value & 0b1000_0000 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.loop_flag = value & 0b0100_0000 != 0;
+                self.timer_period = DMC_RATE_TABLE[(value & 0b1111) as usize];
+            }
+            // -DDD DDDD
+            1 => self.output_level = value & 0b0111_1111,
+            // AAAA AAAA: sample address = %11AAAAAA.AA000000
+            2 => self.sample_addr = 0xC000 | ((value as u16) << 6),
+            // LLLL LLLL: sample length = %LLLL.LLLL0001
+            3 => self.sample_len = ((value as u16) << 4) | 1,
+            _ => panic!("invalid DMC register index {}", reg_idx),
+        }
+    }
+
+    // enable bit from $4015
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_len;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    // Address the memory reader wants to fetch from, if the sample buffer
+    // is empty and there are bytes remaining. The bus is responsible for
+    // reading the byte, stalling the CPU and handing it to `fill_sample_buffer`.
+    pub fn pending_fetch_addr(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_addr)
+        } else {
+            None
+        }
+    }
+
+    pub fn fill_sample_buffer(&mut self, value: u8) {
+        self.sample_buffer = Some(value);
+        // the address wraps around to $8000, not $0000
+        self.current_addr = if self.current_addr == 0xFFFF {
+            0x8000
+        } else {
+            self.current_addr + 1
+        };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    // clocked every CPU cycle, the rate table is expressed in CPU cycles
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period - 1;
+
+        if !self.silence {
+            if self.shift_reg & 1 == 1 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_reg >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            // start a new output cycle
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(sample) => {
+                    self.silence = false;
+                    self.shift_reg = sample;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    // The reset button clears the output level but its lowest bit
+    pub fn reset(&mut self) {
+        self.output_level &= 1;
+    }
+
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+}
+
+impl Default for DMC {
+    fn default() -> Self {
+        DMC::new()
+    }
+}
+
+impl SaveState for DMC {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.loop_flag);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+        w.write_u8(self.output_level);
+        w.write_u16(self.sample_addr);
+        w.write_u16(self.sample_len);
+        w.write_u16(self.current_addr);
+        w.write_u16(self.bytes_remaining);
+        w.write_bool(self.sample_buffer.is_some());
+        w.write_u8(self.sample_buffer.unwrap_or(0));
+        w.write_u8(self.shift_reg);
+        w.write_u8(self.bits_remaining);
+        w.write_bool(self.silence);
+        w.write_bool(self.irq);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.irq_enabled = r.read_bool()?;
+        self.loop_flag = r.read_bool()?;
+        self.timer_period = r.read_u16()?.max(1);
+        self.timer = r.read_u16()?;
+        self.output_level = r.read_u8()? & 0b0111_1111;
+        self.sample_addr = r.read_u16()?;
+        self.sample_len = r.read_u16()?;
+        self.current_addr = r.read_u16()?;
+        self.bytes_remaining = r.read_u16()?;
+        let has_sample = r.read_bool()?;
+        let sample = r.read_u8()?;
+        self.sample_buffer = if has_sample { Some(sample) } else { None };
+        self.shift_reg = r.read_u8()?;
+        self.bits_remaining = r.read_u8()?.clamp(1, 8);
+        self.silence = r.read_bool()?;
+        self.irq = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sample_fetch() {
+        let mut dmc = DMC::new();
+        dmc.write(2, 0x01); // $C040
+        dmc.write(3, 0x00); // 1 byte
+        assert_eq!(dmc.pending_fetch_addr(), None);
+
+        dmc.set_enabled(true);
+        assert!(dmc.is_active());
+        assert_eq!(dmc.pending_fetch_addr(), Some(0xC040));
+
+        dmc.fill_sample_buffer(0xFF);
+        assert!(!dmc.is_active());
+        assert_eq!(dmc.pending_fetch_addr(), None);
+        assert!(!dmc.irq);
+    }
+
+    #[test]
+    fn test_sample_end_irq_and_loop() {
+        let mut dmc = DMC::new();
+        dmc.write(0, 0b1000_0000);
+        dmc.set_enabled(true);
+        dmc.fill_sample_buffer(0x00);
+        assert!(dmc.irq);
+
+        let mut dmc = DMC::new();
+        dmc.write(0, 0b1100_0000);
+        dmc.set_enabled(true);
+        dmc.fill_sample_buffer(0x00);
+        assert!(!dmc.irq);
+        assert_eq!(dmc.pending_fetch_addr(), None);
+        dmc.sample_buffer = None;
+        assert_eq!(dmc.pending_fetch_addr(), Some(0xC000));
+    }
+
+    #[test]
+    fn test_output_level_follows_sample_bits() {
+        let mut dmc = DMC::new();
+        dmc.write(0, 0x0F); // fastest rate
+        dmc.write(1, 0x40);
+        dmc.set_enabled(true);
+        dmc.fill_sample_buffer(0b0000_1111);
+
+        // drain the initial (silent) output cycle, which then picks up the sample
+        for _ in 0..8 * 54 {
+            dmc.clock_timer();
+        }
+        assert_eq!(dmc.output(), 0x40);
+
+        for _ in 0..4 * 54 {
+            dmc.clock_timer();
+        }
+        assert_eq!(dmc.output(), 0x48);
+        for _ in 0..4 * 54 {
+            dmc.clock_timer();
+        }
+        assert_eq!(dmc.output(), 0x40);
+    }
+}
@@ -0,0 +1,186 @@
+use super::APU;
+
+// Extra sound channels on the cartridge (VRC6, FDS, MMC5, Namco 163,
+// Sunsoft 5B). A mapper with expansion audio returns it from
+// `Mapper::expansion_audio`, and the mixer adds its channels to the APU
+// output.
+pub trait ExpansionAudio {
+    // A name for each channel, e.g. "VRC6 pulse 1". Channels are numbered
+    // in this order.
+    fn channels(&self) -> &'static [&'static str];
+
+    // Current output of `channel` on the same scale as `APU::output`: at
+    // full volume a channel is about as loud as its APU counterpart, and
+    // silent is 0.0
+    fn sample(&self, channel: usize) -> f32;
+}
+
+// What can be muted and soloed: the APU channels, and the expansion audio
+// as a whole
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+    Expansion,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 6] = [
+        Channel::Pulse1,
+        Channel::Pulse2,
+        Channel::Triangle,
+        Channel::Noise,
+        Channel::Dmc,
+        Channel::Expansion,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "pulse 1",
+            Channel::Pulse2 => "pulse 2",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::Dmc => "DMC",
+            Channel::Expansion => "expansion",
+        }
+    }
+}
+
+// Mixes the APU with the expansion channels, each at its own volume, and
+// leaves out the muted channels. These are host side settings, resets and
+// power cycles keep them.
+#[derive(Default)]
+pub struct Mixer {
+    // by expansion channel, channels past the end are at full volume
+    expansion_volumes: Vec<f32>,
+    // by `Channel`
+    muted: [bool; 6],
+    // only this channel is heard, whatever is muted
+    solo: Option<Channel>,
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        Mixer {
+            expansion_volumes: vec![],
+            muted: [false; 6],
+            solo: None,
+        }
+    }
+
+    pub fn is_muted(&self, channel: Channel) -> bool {
+        self.muted[channel as usize]
+    }
+
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel as usize] = muted;
+    }
+
+    pub fn solo(&self) -> Option<Channel> {
+        self.solo
+    }
+
+    // None goes back to the channels that aren't muted
+    pub fn set_solo(&mut self, channel: Option<Channel>) {
+        self.solo = channel;
+    }
+
+    // Whether the channel is in the mix, after mutes and solo
+    pub fn is_audible(&self, channel: Channel) -> bool {
+        match self.solo {
+            Some(solo) => solo == channel,
+            None => !self.is_muted(channel),
+        }
+    }
+
+    // 1.0 is the channel's normal level, 0.0 silences it
+    pub fn expansion_volume(&self, channel: usize) -> f32 {
+        self.expansion_volumes.get(channel).copied().unwrap_or(1.0)
+    }
+
+    pub fn set_expansion_volume(&mut self, channel: usize, volume: f32) {
+        if channel >= self.expansion_volumes.len() {
+            self.expansion_volumes.resize(channel + 1, 1.0);
+        }
+        self.expansion_volumes[channel] = volume.max(0.0);
+    }
+
+    // One sample of the console's audio, in range [0.0, 1.0] without
+    // expansion audio
+    pub fn mix(&self, apu: &APU, expansion: Option<&dyn ExpansionAudio>) -> f32 {
+        let mut output = apu.output_of(|channel| self.is_audible(channel));
+        if let (Some(expansion), true) = (expansion, self.is_audible(Channel::Expansion)) {
+            for channel in 0..expansion.channels().len() {
+                output += expansion.sample(channel) * self.expansion_volume(channel);
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct TestAudio;
+
+    impl ExpansionAudio for TestAudio {
+        fn channels(&self) -> &'static [&'static str] {
+            &["one", "two"]
+        }
+
+        fn sample(&self, channel: usize) -> f32 {
+            [0.25, 0.125][channel]
+        }
+    }
+
+    #[test]
+    fn test_mix() {
+        let apu = APU::new();
+        // the triangle holds a level even when silent
+        let apu_output = apu.output();
+        let mut mixer = Mixer::new();
+        assert_eq!(mixer.mix(&apu, None), apu_output);
+        assert_eq!(mixer.mix(&apu, Some(&TestAudio)), apu_output + 0.375);
+
+        mixer.set_expansion_volume(1, 0.0);
+        assert_eq!(mixer.mix(&apu, Some(&TestAudio)), apu_output + 0.25);
+        mixer.set_expansion_volume(0, 0.5);
+        assert_eq!(mixer.mix(&apu, Some(&TestAudio)), apu_output + 0.125);
+        mixer.set_expansion_volume(0, -1.0);
+        assert_eq!(mixer.expansion_volume(0), 0.0);
+        // not set yet
+        assert_eq!(mixer.expansion_volume(5), 1.0);
+    }
+
+    #[test]
+    fn test_mute_and_solo() {
+        let mut apu = APU::new();
+        // the triangle's first step is at level 15, the pulses are silent
+        // until enabled
+        apu.cpu_write(0x4011, 0x40);
+        let triangle = apu.output_of(|channel| channel == Channel::Triangle);
+        let dmc = apu.output_of(|channel| channel == Channel::Dmc);
+        assert!(triangle > 0.0 && dmc > 0.0);
+
+        let mut mixer = Mixer::new();
+        mixer.set_muted(Channel::Triangle, true);
+        assert!(mixer.is_muted(Channel::Triangle));
+        assert_eq!(mixer.mix(&apu, None), dmc);
+        mixer.set_muted(Channel::Expansion, true);
+        assert_eq!(mixer.mix(&apu, Some(&TestAudio)), dmc);
+
+        // a solo channel is heard even when muted
+        mixer.set_solo(Some(Channel::Expansion));
+        assert_eq!(mixer.mix(&apu, Some(&TestAudio)), 0.375);
+        mixer.set_solo(Some(Channel::Triangle));
+        assert_eq!(mixer.mix(&apu, Some(&TestAudio)), triangle);
+        assert!(!mixer.is_audible(Channel::Dmc));
+        mixer.set_solo(None);
+        assert_eq!(mixer.solo(), None);
+        assert_eq!(mixer.mix(&apu, Some(&TestAudio)), dmc);
+    }
+}
@@ -0,0 +1,369 @@
+pub mod components;
+pub mod dmc;
+pub mod mixer;
+pub mod noise;
+pub mod pulse;
+pub mod triangle;
+
+use dmc::DMC;
+use mixer::Channel;
+use noise::Noise;
+use pulse::Pulse;
+use triangle::Triangle;
+
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+// Frame counter step timings in CPU cycles
+// Ref: https://wiki.nesdev.org/w/index.php/APU_Frame_Counter
+const FRAME_STEP_1: u32 = 7457;
+const FRAME_STEP_2: u32 = 14913;
+const FRAME_STEP_3: u32 = 22371;
+const FRAME_STEP_4: u32 = 29829;
+const FRAME_STEP_5: u32 = 37281;
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct APU {
+    pub pulse_1: Pulse,
+    pub pulse_2: Pulse,
+    pub triangle: Triangle,
+    pub noise: Noise,
+    pub dmc: DMC,
+
+    // frame counter
+    five_step_mode: bool,
+    frame_irq_inhibit: bool,
+    frame_irq: bool,
+    frame_cycles: u32,
+
+    // total CPU cycles, pulse timers are clocked on every other one
+    cycles: u64,
+}
+
+impl APU {
+    pub fn new() -> Self {
+        APU {
+            pulse_1: Pulse::new(true),
+            pulse_2: Pulse::new(false),
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            dmc: DMC::new(),
+            five_step_mode: false,
+            frame_irq_inhibit: false,
+            frame_irq: false,
+            frame_cycles: 0,
+            cycles: 0,
+        }
+    }
+
+    // The reset button silences all channels and restarts the frame
+    // counter in the mode last written to $4017
+    // Ref: https://wiki.nesdev.org/w/index.php/CPU_power_up_state
+    pub fn reset(&mut self) {
+        self.cpu_write(0x4015, 0);
+        self.triangle.reset();
+        self.dmc.reset();
+        self.frame_irq = false;
+        self.frame_cycles = 0;
+    }
+
+    // one CPU cycle of APU execution
+    pub fn tick(&mut self) {
+        self.pulse_1.length.start_cycle();
+        self.pulse_2.length.start_cycle();
+        self.triangle.length.start_cycle();
+        self.noise.length.start_cycle();
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.cycles % 2 == 1 {
+            self.pulse_1.clock_timer();
+            self.pulse_2.clock_timer();
+        }
+        self.clock_frame_counter();
+        self.cycles = self.cycles.wrapping_add(1);
+    }
+
+    fn clock_frame_counter(&mut self) {
+        self.frame_cycles += 1;
+        match (self.frame_cycles, self.five_step_mode) {
+            (FRAME_STEP_1, _) | (FRAME_STEP_3, _) => self.clock_quarter_frame(),
+            (FRAME_STEP_2, _) => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            (FRAME_STEP_4, false) => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                if !self.frame_irq_inhibit {
+                    self.frame_irq = true;
+                }
+                self.frame_cycles = 0;
+            }
+            (FRAME_STEP_5, true) => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                self.frame_cycles = 0;
+            }
+            _ => (),
+        }
+    }
+
+    // envelopes and the triangle's linear counter
+    fn clock_quarter_frame(&mut self) {
+        self.pulse_1.envelope.clock();
+        self.pulse_2.envelope.clock();
+        self.triangle.clock_linear_counter();
+        self.noise.envelope.clock();
+    }
+
+    // length counters and sweep units
+    fn clock_half_frame(&mut self) {
+        self.pulse_1.length.clock();
+        self.pulse_1.clock_sweep();
+        self.pulse_2.length.clock();
+        self.pulse_2.clock_sweep();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+    }
+
+    pub fn cpu_read(&mut self, cpu_addr: u16) -> u8 {
+        match cpu_addr {
+            // Status register
+            0x4015 => {
+                let mut value = 0u8;
+                if self.pulse_1.length.is_active() {
+                    value |= 0b0000_0001;
+                }
+                if self.pulse_2.length.is_active() {
+                    value |= 0b0000_0010;
+                }
+                if self.triangle.length.is_active() {
+                    value |= 0b0000_0100;
+                }
+                if self.noise.length.is_active() {
+                    value |= 0b0000_1000;
+                }
+                if self.dmc.is_active() {
+                    value |= 0b0001_0000;
+                }
+                if self.frame_irq {
+                    value |= 0b0100_0000;
+                }
+                if self.dmc.irq {
+                    value |= 0b1000_0000;
+                }
+                // reading the status clears the frame interrupt flag
+                self.frame_irq = false;
+                value
+            }
+            // the remaining APU registers are write-only
+            _ => 0,
+        }
+    }
+
+    pub fn cpu_write(&mut self, cpu_addr: u16, value: u8) {
+        match cpu_addr {
+            0x4000..=0x4003 => self.pulse_1.write(cpu_addr - 0x4000, value),
+            0x4004..=0x4007 => self.pulse_2.write(cpu_addr - 0x4004, value),
+            0x4008..=0x400B => self.triangle.write(cpu_addr - 0x4008, value),
+            0x400C..=0x400F => self.noise.write(cpu_addr - 0x400C, value),
+            0x4010..=0x4013 => self.dmc.write(cpu_addr - 0x4010, value),
+            // Status register: ---D NT21 channel enables. Disabled channels
+            // are silenced, enabling the DMC restarts its sample if it was
+            // done. Writes acknowledge the DMC interrupt.
+            0x4015 => {
+                self.dmc.irq = false;
+                self.pulse_1.length.set_enabled(value & 0b0000_0001 != 0);
+                self.pulse_2.length.set_enabled(value & 0b0000_0010 != 0);
+                self.triangle.length.set_enabled(value & 0b0000_0100 != 0);
+                self.noise.length.set_enabled(value & 0b0000_1000 != 0);
+                self.dmc.set_enabled(value & 0b0001_0000 != 0);
+            }
+            // Frame counter: MI-- ----
+            0x4017 => {
+                self.five_step_mode = value & 0b1000_0000 != 0;
+                self.frame_irq_inhibit = value & 0b0100_0000 != 0;
+                if self.frame_irq_inhibit {
+                    self.frame_irq = false;
+                }
+                self.frame_cycles = 0;
+                // entering 5-step mode clocks all units immediately
+                if self.five_step_mode {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            _ => panic!("CPU write address {:04X?} not supported for APU!", cpu_addr),
+        }
+    }
+
+    pub fn has_irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    pub fn acknowledge_irq(&mut self) {
+        self.frame_irq = false;
+        self.dmc.irq = false;
+    }
+
+    // Mixed output of all channels in range [0.0, 1.0]
+    // Ref: https://wiki.nesdev.org/w/index.php/APU_Mixer
+    pub fn output(&self) -> f32 {
+        self.output_of(|_| true)
+    }
+
+    // The mixed output of the channels `audible` is true for, the others
+    // are silent
+    pub fn output_of<F: Fn(Channel) -> bool>(&self, audible: F) -> f32 {
+        let level = |channel, output: u8| if audible(channel) { output as f32 } else { 0.0 };
+        let pulse_1 = level(Channel::Pulse1, self.pulse_1.output());
+        let pulse_2 = level(Channel::Pulse2, self.pulse_2.output());
+        let triangle = level(Channel::Triangle, self.triangle.output());
+        let noise = level(Channel::Noise, self.noise.output());
+        let dmc = level(Channel::Dmc, self.dmc.output());
+
+        let pulse_out = if pulse_1 + pulse_2 == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / (pulse_1 + pulse_2) + 100.0)
+        };
+        let tnd_sum = triangle / 8227.0 + noise / 12241.0 + dmc / 22638.0;
+        let tnd_out = if tnd_sum == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd_sum + 100.0)
+        };
+        pulse_out + tnd_out
+    }
+}
+
+impl Default for APU {
+    fn default() -> Self {
+        APU::new()
+    }
+}
+
+impl SaveState for APU {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.pulse_1.save_state(w);
+        self.pulse_2.save_state(w);
+        self.triangle.save_state(w);
+        self.noise.save_state(w);
+        self.dmc.save_state(w);
+        w.write_bool(self.five_step_mode);
+        w.write_bool(self.frame_irq_inhibit);
+        w.write_bool(self.frame_irq);
+        w.write_u32(self.frame_cycles);
+        w.write_u64(self.cycles);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.pulse_1.load_state(r)?;
+        self.pulse_2.load_state(r)?;
+        self.triangle.load_state(r)?;
+        self.noise.load_state(r)?;
+        self.dmc.load_state(r)?;
+        self.five_step_mode = r.read_bool()?;
+        self.frame_irq_inhibit = r.read_bool()?;
+        self.frame_irq = r.read_bool()?;
+        self.frame_cycles = r.read_u32()?;
+        self.cycles = r.read_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_status_reflects_length_counters() {
+        let mut apu = APU::new();
+        apu.cpu_write(0x4015, 0b0000_1111);
+        apu.cpu_write(0x4003, 0b0000_1000);
+        apu.cpu_write(0x400B, 0b0000_1000);
+        assert_eq!(apu.cpu_read(0x4015), 0b0000_0101);
+
+        apu.cpu_write(0x4015, 0b0000_0001);
+        assert_eq!(apu.cpu_read(0x4015), 0b0000_0001);
+    }
+
+    #[test]
+    fn test_frame_counter_clocks_length_counters() {
+        let mut apu = APU::new();
+        apu.cpu_write(0x4017, 0b0100_0000);
+        apu.cpu_write(0x4015, 0b0000_1000);
+        // noise length index 3 -> 2 half frames
+        apu.cpu_write(0x400F, 0b0001_1000);
+        assert_eq!(apu.cpu_read(0x4015), 0b0000_1000);
+        for _ in 0..FRAME_STEP_4 {
+            apu.tick();
+        }
+        assert_eq!(apu.cpu_read(0x4015), 0);
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = APU::new();
+        for _ in 0..FRAME_STEP_4 {
+            apu.tick();
+        }
+        assert!(apu.has_irq());
+        assert_eq!(apu.cpu_read(0x4015), 0b0100_0000);
+        assert!(!apu.has_irq());
+
+        // inhibited in 4-step mode with bit 6 set, never raised in 5-step mode
+        for value in [0b0100_0000, 0b1000_0000] {
+            apu.cpu_write(0x4017, value);
+            for _ in 0..FRAME_STEP_5 {
+                apu.tick();
+            }
+            assert!(!apu.has_irq());
+        }
+    }
+
+    #[test]
+    fn test_status_dmc_irq() {
+        let mut apu = APU::new();
+        // IRQ on, a 1 byte sample
+        apu.cpu_write(0x4010, 0b1000_0000);
+        apu.cpu_write(0x4013, 0x00);
+        apu.cpu_write(0x4015, 0b0001_0000);
+        apu.dmc.fill_sample_buffer(0x55);
+        assert!(apu.has_irq());
+        // reads don't clear it
+        assert_eq!(apu.cpu_read(0x4015), 0b1000_0000);
+        assert_eq!(apu.cpu_read(0x4015), 0b1000_0000);
+        // writes do, and restart the sample
+        apu.cpu_write(0x4015, 0b0001_0000);
+        assert!(!apu.has_irq());
+        assert_eq!(apu.cpu_read(0x4015), 0b0001_0000);
+    }
+
+    #[test]
+    fn test_length_reload_during_half_frame() {
+        let mut apu = APU::new();
+        apu.cpu_write(0x4017, 0b0100_0000);
+        apu.cpu_write(0x4015, 0b0000_0001);
+        apu.cpu_write(0x4003, 0b0001_1000); // 2 half frames
+        for _ in 0..FRAME_STEP_2 {
+            apu.tick();
+        }
+        // the write in the cycle of the clock is ignored
+        apu.cpu_write(0x4003, 0b0000_1000);
+        for _ in FRAME_STEP_2..FRAME_STEP_4 {
+            apu.tick();
+        }
+        assert_eq!(apu.cpu_read(0x4015), 0);
+    }
+
+    #[test]
+    fn test_dmc_requests_fetch() {
+        let mut apu = APU::new();
+        apu.cpu_write(0x4012, 0x00);
+        apu.cpu_write(0x4013, 0x01);
+        apu.cpu_write(0x4015, 0b0001_0000);
+        assert_eq!(apu.dmc.pending_fetch_addr(), Some(0xC000));
+        assert_eq!(apu.cpu_read(0x4015), 0b0001_0000);
+    }
+}
@@ -0,0 +1,144 @@
+use super::components::{Envelope, LengthCounter};
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+// Ref: https://wiki.nesdev.org/w/index.php/APU_Noise
+// NTSC timer periods in CPU cycles
+#[rustfmt::skip]
+const NOISE_PERIOD_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Noise {
+    // 15-bit linear feedback shift register
+    shift_reg: u16,
+    // mode flag: when set, feedback is taken from bit 6 instead of bit 1,
+    // producing a short, metallic 93-step sequence
+    short_mode: bool,
+    timer_period: u16,
+    timer: u16,
+
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Noise {
+            // the shift register is loaded with 1 on power-up
+            shift_reg: 1,
+            short_mode: false,
+            timer_period: NOISE_PERIOD_TABLE[0],
+            timer: 0,
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+        }
+    }
+
+    // reg_idx is the register offset from $400C
+    pub fn write(&mut self, reg_idx: u16, value: u8) {
+        match reg_idx {
+            // --LC VVVV
+            0 => {
+                self.length.halt = value & 0b0010_0000 != 0;
+                self.envelope.write(value);
+            }
+            // $400D is unused
+            1 => (),
+            // M--- PPPP
+            2 => {
+                self.short_mode = value & 0b1000_0000 != 0;
+                self.timer_period = NOISE_PERIOD_TABLE[(value & 0b1111) as usize];
+            }
+            // llll l---
+            3 => {
+                self.length.load(value >> 3);
+                self.envelope.restart();
+            }
+            _ => panic!("invalid noise register index {}", reg_idx),
+        }
+    }
+
+    // clocked every CPU cycle, the period table is expressed in CPU cycles
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            self.clock_shift_reg();
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_shift_reg(&mut self) {
+        let other_bit = if self.short_mode { 6 } else { 1 };
+        let feedback = (self.shift_reg & 1) ^ ((self.shift_reg >> other_bit) & 1);
+        self.shift_reg >>= 1;
+        self.shift_reg |= feedback << 14;
+    }
+
+    pub fn output(&self) -> u8 {
+        // the channel is silenced while bit 0 of the shift register is set
+        if !self.length.is_active() || self.shift_reg & 1 == 1 {
+            return 0;
+        }
+        self.envelope.output()
+    }
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Noise::new()
+    }
+}
+
+impl SaveState for Noise {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.shift_reg);
+        w.write_bool(self.short_mode);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+        self.envelope.save_state(w);
+        self.length.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.shift_reg = r.read_u16()?;
+        self.short_mode = r.read_bool()?;
+        self.timer_period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        self.envelope.load_state(r)?;
+        self.length.load_state(r)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sequence_len(noise: &mut Noise) -> usize {
+        let start = noise.shift_reg;
+        let mut steps = 0;
+        loop {
+            noise.clock_shift_reg();
+            steps += 1;
+            if noise.shift_reg == start {
+                return steps;
+            }
+        }
+    }
+
+    #[test]
+    fn test_lfsr_sequence_length() {
+        let mut noise = Noise::new();
+        assert_eq!(sequence_len(&mut noise), 32767);
+
+        let mut noise = Noise::new();
+        noise.write(2, 0b1000_0000);
+        // skip the transient steps before entering the 93-step loop
+        for _ in 0..100 {
+            noise.clock_shift_reg();
+        }
+        assert_eq!(sequence_len(&mut noise), 93);
+    }
+}
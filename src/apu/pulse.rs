@@ -0,0 +1,169 @@
+use super::components::{Envelope, LengthCounter};
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+// Ref: https://wiki.nesdev.org/w/index.php/APU_Pulse
+#[rustfmt::skip]
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0], // 12.5%
+    [0, 1, 1, 0, 0, 0, 0, 0], // 25%
+    [0, 1, 1, 1, 1, 0, 0, 0], // 50%
+    [1, 0, 0, 1, 1, 1, 1, 1], // 25% negated
+];
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Pulse {
+    // pulse 1 and 2 differ in how the sweep unit negates the period
+    is_pulse_1: bool,
+
+    duty: u8,
+    sequence_pos: u8,
+    timer_period: u16,
+    timer: u16,
+
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+
+    // sweep unit
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    pub fn new(is_pulse_1: bool) -> Self {
+        Pulse {
+            is_pulse_1,
+            duty: 0,
+            sequence_pos: 0,
+            timer_period: 0,
+            timer: 0,
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
+        }
+    }
+
+    // reg_idx is the register offset from the channel base address ($4000 or $4004)
+    pub fn write(&mut self, reg_idx: u16, value: u8) {
+        match reg_idx {
+            // DDLC VVVV
+            0 => {
+                self.duty = value >> 6;
+                self.length.halt = value & 0b0010_0000 != 0;
+                self.envelope.write(value);
+            }
+            // EPPP NSSS
+            1 => {
+                self.sweep_enabled = value & 0b1000_0000 != 0;
+                self.sweep_period = (value >> 4) & 0b111;
+                self.sweep_negate = value & 0b0000_1000 != 0;
+                self.sweep_shift = value & 0b111;
+                self.sweep_reload = true;
+            }
+            // LLLL LLLL
+            2 => self.timer_period = (self.timer_period & 0xFF00) | value as u16,
+            // llll lHHH
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((value as u16 & 0b111) << 8);
+                self.length.load(value >> 3);
+                self.sequence_pos = 0;
+                self.envelope.restart();
+            }
+            _ => panic!("invalid pulse register index {}", reg_idx),
+        }
+    }
+
+    // clocked every APU cycle (every other CPU cycle)
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence_pos = (self.sequence_pos + 1) & 0b111;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // clocked by the frame counter on half frames
+    pub fn clock_sweep(&mut self) {
+        let target = self.sweep_target_period();
+        if self.sweep_divider == 0
+            && self.sweep_enabled
+            && self.sweep_shift > 0
+            && !self.is_sweep_muting(target)
+        {
+            self.timer_period = target;
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn sweep_target_period(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            // pulse 1 uses ones' complement, pulse 2 uses two's complement
+            let change = if self.is_pulse_1 { change + 1 } else { change };
+            self.timer_period.saturating_sub(change)
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    fn is_sweep_muting(&self, target: u16) -> bool {
+        self.timer_period < 8 || target > 0x07FF
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length.is_active()
+            || self.is_sweep_muting(self.sweep_target_period())
+            || DUTY_TABLE[self.duty as usize][self.sequence_pos as usize] == 0
+        {
+            return 0;
+        }
+        self.envelope.output()
+    }
+}
+
+impl SaveState for Pulse {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.duty);
+        w.write_u8(self.sequence_pos);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+        self.envelope.save_state(w);
+        self.length.save_state(w);
+        w.write_bool(self.sweep_enabled);
+        w.write_u8(self.sweep_period);
+        w.write_bool(self.sweep_negate);
+        w.write_u8(self.sweep_shift);
+        w.write_u8(self.sweep_divider);
+        w.write_bool(self.sweep_reload);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.duty = r.read_u8()? & 0b11;
+        self.sequence_pos = r.read_u8()? & 0b111;
+        self.timer_period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        self.envelope.load_state(r)?;
+        self.length.load_state(r)?;
+        self.sweep_enabled = r.read_bool()?;
+        self.sweep_period = r.read_u8()?;
+        self.sweep_negate = r.read_bool()?;
+        self.sweep_shift = r.read_u8()?;
+        self.sweep_divider = r.read_u8()?;
+        self.sweep_reload = r.read_bool()?;
+        Ok(())
+    }
+}
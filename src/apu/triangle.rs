@@ -0,0 +1,167 @@
+use super::components::LengthCounter;
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+// Ref: https://wiki.nesdev.org/w/index.php/APU_Triangle
+#[rustfmt::skip]
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10,  9,  8,  7,  6,  5,  4,  3,  2,  1,  0,
+     0,  1,  2,  3,  4,  5,  6,  7,  8,  9, 10, 11, 12, 13, 14, 15,
+];
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Triangle {
+    sequence_pos: u8,
+    timer_period: u16,
+    timer: u16,
+
+    pub length: LengthCounter,
+
+    // linear counter
+    // the control flag doubles as the length counter halt flag
+    control_flag: bool,
+    linear_counter: u8,
+    linear_reload_value: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    pub fn new() -> Self {
+        Triangle {
+            sequence_pos: 0,
+            timer_period: 0,
+            timer: 0,
+            length: LengthCounter::new(),
+            control_flag: false,
+            linear_counter: 0,
+            linear_reload_value: 0,
+            linear_reload: false,
+        }
+    }
+
+    // reg_idx is the register offset from $4008
+    pub fn write(&mut self, reg_idx: u16, value: u8) {
+        match reg_idx {
+            // CRRR RRRR
+            0 => {
+                self.control_flag = value & 0b1000_0000 != 0;
+                self.length.halt = self.control_flag;
+                self.linear_reload_value = value & 0b0111_1111;
+            }
+            // $4009 is unused
+            1 => (),
+            // LLLL LLLL
+            2 => self.timer_period = (self.timer_period & 0xFF00) | value as u16,
+            // llll lHHH
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((value as u16 & 0b111) << 8);
+                self.length.load(value >> 3);
+                self.linear_reload = true;
+            }
+            _ => panic!("invalid triangle register index {}", reg_idx),
+        }
+    }
+
+    // unlike the other channels, the triangle timer is clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            // the sequencer only steps while both counters are non-zero
+            if self.length.is_active() && self.linear_counter > 0 {
+                self.sequence_pos = (self.sequence_pos + 1) & 0b1_1111;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // clocked by the frame counter on quarter frames
+    pub fn clock_linear_counter(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control_flag {
+            self.linear_reload = false;
+        }
+    }
+
+    // The reset button restarts the waveform at its first step
+    pub fn reset(&mut self) {
+        self.sequence_pos = 0;
+    }
+
+    pub fn output(&self) -> u8 {
+        // periods below 2 produce ultrasonic frequencies which real hardware
+        // smooths out to the middle of the waveform; output that level to avoid pops
+        if self.timer_period < 2 {
+            return 7;
+        }
+        TRIANGLE_SEQUENCE[self.sequence_pos as usize]
+    }
+}
+
+impl Default for Triangle {
+    fn default() -> Self {
+        Triangle::new()
+    }
+}
+
+impl SaveState for Triangle {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.sequence_pos);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+        self.length.save_state(w);
+        w.write_bool(self.control_flag);
+        w.write_u8(self.linear_counter);
+        w.write_u8(self.linear_reload_value);
+        w.write_bool(self.linear_reload);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.sequence_pos = r.read_u8()? % TRIANGLE_SEQUENCE.len() as u8;
+        self.timer_period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        self.length.load_state(r)?;
+        self.control_flag = r.read_bool()?;
+        self.linear_counter = r.read_u8()?;
+        self.linear_reload_value = r.read_u8()?;
+        self.linear_reload = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_linear_counter_gates_sequencer() {
+        let mut triangle = Triangle::new();
+        triangle.length.set_enabled(true);
+        triangle.write(0, 0b0000_0010); // linear counter reload value 2
+        triangle.write(2, 0x10);
+        triangle.write(3, 0b0000_1000); // load length counter
+
+        // linear counter not loaded yet: sequencer stays still
+        for _ in 0..0x20 {
+            triangle.clock_timer();
+        }
+        assert_eq!(triangle.output(), 15);
+
+        triangle.clock_linear_counter();
+        for _ in 0..0x11 {
+            triangle.clock_timer();
+        }
+        assert_eq!(triangle.output(), 14);
+
+        // counter runs out after 2 more quarter frames
+        triangle.clock_linear_counter();
+        triangle.clock_linear_counter();
+        for _ in 0..0x22 {
+            triangle.clock_timer();
+        }
+        assert_eq!(triangle.output(), 14);
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::graphics::{NesFrame, NES_HEIGHT, NES_WIDTH};
+
+// APU register log, for when the audio sounds wrong. With a log attached to
+// the bus (see `Bus::apu_log`) every write to $4000-$4013, $4015 and $4017
+// is recorded with the frame and the CPU cycle within the frame it happened
+// at, to compare with another emulator's log. The log keeps the most recent
+// writes up to its capacity.
+
+// About 10 seconds of a busy sound engine
+pub const DEFAULT_CAPACITY: usize = 64 * 1024;
+
+// CPU cycles in a frame, 262 scanlines of 341 dots with 3 dots per cycle
+pub const FRAME_CYCLES: u32 = 262 * 341 / 3;
+
+// Rows of `ApuLog::render`, one per register from $4000 to $4017
+const REGISTERS: u32 = 0x18;
+const ROW_HEIGHT: u32 = NES_HEIGHT / REGISTERS;
+
+// The register names of the NES dev wiki
+pub fn register_name(addr: u16) -> &'static str {
+    match addr {
+        0x4000 => "SQ1_VOL",
+        0x4001 => "SQ1_SWEEP",
+        0x4002 => "SQ1_LO",
+        0x4003 => "SQ1_HI",
+        0x4004 => "SQ2_VOL",
+        0x4005 => "SQ2_SWEEP",
+        0x4006 => "SQ2_LO",
+        0x4007 => "SQ2_HI",
+        0x4008 => "TRI_LINEAR",
+        0x400A => "TRI_LO",
+        0x400B => "TRI_HI",
+        0x400C => "NOISE_VOL",
+        0x400E => "NOISE_LO",
+        0x400F => "NOISE_HI",
+        0x4010 => "DMC_FREQ",
+        0x4011 => "DMC_RAW",
+        0x4012 => "DMC_START",
+        0x4013 => "DMC_LEN",
+        0x4015 => "SND_CHN",
+        0x4017 => "FRAME_CNT",
+        _ => "",
+    }
+}
+
+// Marker color in `ApuLog::render`, by channel
+fn register_color(addr: u16) -> (u8, u8, u8) {
+    match addr {
+        0x4000..=0x4003 => (0xFF, 0x40, 0x40),
+        0x4004..=0x4007 => (0xFF, 0xA0, 0x00),
+        0x4008..=0x400B => (0x40, 0xA0, 0xFF),
+        0x400C..=0x400F => (0xFF, 0xFF, 0xFF),
+        0x4010..=0x4013 => (0x40, 0xFF, 0x40),
+        _ => (0xFF, 0x80, 0xFF),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ApuWrite {
+    // frames since the log was attached
+    pub frame: u64,
+    // CPU cycles since the frame started
+    pub cycle: u32,
+    pub addr: u16,
+    pub value: u8,
+}
+
+impl fmt::Display for ApuWrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:6} {:5}  {:04X} = {:02X}  {}",
+            self.frame,
+            self.cycle,
+            self.addr,
+            self.value,
+            register_name(self.addr)
+        )
+    }
+}
+
+pub struct ApuLog {
+    writes: VecDeque<ApuWrite>,
+    capacity: usize,
+    frame: u64,
+}
+
+impl ApuLog {
+    // Keeps the last `capacity` writes
+    pub fn new(capacity: usize) -> Self {
+        ApuLog {
+            writes: VecDeque::with_capacity(capacity),
+            capacity,
+            frame: 0,
+        }
+    }
+
+    // The frame being recorded
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn writes(&self) -> impl Iterator<Item = &ApuWrite> + '_ {
+        self.writes.iter()
+    }
+
+    // Writes of one frame, possibly incomplete if older writes of the frame
+    // were dropped
+    pub fn frame_writes(&self, frame: u64) -> impl Iterator<Item = &ApuWrite> + '_ {
+        self.writes.iter().filter(move |write| write.frame == frame)
+    }
+
+    pub fn clear(&mut self) {
+        self.writes.clear();
+    }
+
+    // Writes of `frame` one per line: frame, cycle, register and value
+    pub fn dump<W: Write>(&self, frame: u64, out: &mut W) -> io::Result<()> {
+        for write in self.frame_writes(frame) {
+            writeln!(out, "{}", write)?;
+        }
+        Ok(())
+    }
+
+    // Timeline of `frame`: a row per register from $4000 at the top to
+    // $4017, with a marker per write where it happened in the frame. Rows
+    // of a channel share a shade.
+    pub fn render(&self, frame: u64, out: &mut NesFrame) {
+        for y in 0..NES_HEIGHT {
+            let addr = 0x4000 + (y / ROW_HEIGHT) as u16;
+            let shade = match addr {
+                0x4000..=0x4003 | 0x4008..=0x400B | 0x4010..=0x4013 => 0x30,
+                0x4004..=0x4007 | 0x400C..=0x400F => 0x20,
+                _ => 0,
+            };
+            for x in 0..NES_WIDTH {
+                out.set_pixel(x, y, shade, shade, shade);
+            }
+        }
+        for write in self.frame_writes(frame) {
+            let x = write.cycle.min(FRAME_CYCLES - 1) * NES_WIDTH / FRAME_CYCLES;
+            let y = (write.addr - 0x4000) as u32 * ROW_HEIGHT;
+            let (r, g, b) = register_color(write.addr);
+            for dy in 1..ROW_HEIGHT - 1 {
+                out.set_pixel(x, y + dy, r, g, b);
+            }
+        }
+    }
+
+    pub(crate) fn record(&mut self, cycle: u32, addr: u16, value: u8) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.writes.len() == self.capacity {
+            self.writes.pop_front();
+        }
+        self.writes.push_back(ApuWrite {
+            frame: self.frame,
+            cycle,
+            addr,
+            value,
+        });
+    }
+
+    pub(crate) fn next_frame(&mut self) {
+        self.frame += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::emulator::Emulator;
+
+    #[test]
+    fn test_log() {
+        let mut log = ApuLog::new(3);
+        log.record(100, 0x4015, 0x0F);
+        log.next_frame();
+        log.record(10, 0x4000, 0xBF);
+        log.record(20, 0x4003, 0x08);
+        log.record(FRAME_CYCLES / 2, 0x4011, 0x40);
+        // the oldest write was dropped
+        assert_eq!(log.writes().count(), 3);
+        assert_eq!(log.frame_writes(0).count(), 0);
+
+        let mut out = vec![];
+        log.dump(1, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "     1    10  4000 = BF  SQ1_VOL\n\
+             \x20    1    20  4003 = 08  SQ1_HI\n\
+             \x20    1 14890  4011 = 40  DMC_RAW\n"
+        );
+
+        let mut frame = NesFrame::new();
+        log.render(1, &mut frame);
+        // $4011 is the 18th row
+        assert_eq!(frame.pixel(128, 17 * 10 + 5), [0x40, 0xFF, 0x40]);
+        assert_eq!(frame.pixel(0, 3 * 10 + 5), [0xFF, 0x40, 0x40]);
+        assert_eq!(frame.pixel(10, 5), [0x30, 0x30, 0x30]);
+        assert_eq!(frame.pixel(10, 4 * 10 + 5), [0x20, 0x20, 0x20]);
+    }
+
+    #[test]
+    fn test_emulator() {
+        let program = [
+            0xA9, 0x0F, 0x8D, 0x15, 0x40, // 8000 $4015 = $0F
+            0x8D, 0x16, 0x40, // 8005 joypad strobe, not logged
+            0xA9, 0x40, 0x8D, 0x17, 0x40, // 8008 $4017 = $40
+            0x4C, 0x0D, 0x80, // 800D JMP $800D
+        ];
+        let mut prg = program.to_vec();
+        prg.resize(0x3FFC, 0);
+        prg.extend_from_slice(&[0x00, 0x80]);
+        let mut emulator = Emulator::new(Cartridge::new_from_program(prg));
+        emulator.attach_apu_log(16);
+        emulator.run_frame().unwrap();
+
+        let log = emulator.apu_log().unwrap();
+        let writes: Vec<(u16, u8)> = log.writes().map(|w| (w.addr, w.value)).collect();
+        assert_eq!(writes, [(0x4015, 0x0F), (0x4017, 0x40)]);
+        let cycles: Vec<u32> = log.writes().map(|w| w.cycle).collect();
+        // STA $4016 4, LDA #$40 2, STA $4017 4
+        assert_eq!(cycles[1] - cycles[0], 10);
+        assert_eq!(log.frame(), 1);
+        emulator.detach_apu_log();
+        assert!(emulator.apu_log().is_none());
+    }
+}
@@ -0,0 +1,372 @@
+use std::f32::consts::PI;
+use std::time::Duration;
+
+#[cfg(feature = "sdl")]
+mod sdl;
+pub mod wav;
+#[cfg(feature = "sdl")]
+pub use sdl::NesSDLAudio;
+
+use wav::WavWriter;
+
+// NTSC CPU clock rate, the APU produces one sample per CPU cycle
+const CPU_CLOCK_RATE: f64 = 1_789_773.0;
+
+// The default sample rate
+pub const SAMPLE_RATE: u32 = 44_100;
+
+// The sample rates devices commonly play at natively
+pub const SAMPLE_RATES: [u32; 2] = [44_100, 48_000];
+
+// Dynamic rate control: the emulation never runs at exactly the rate the
+// audio device plays at, so its queue slowly runs dry or fills up until
+// samples are dropped, and either one crackles. Instead the sample rate is
+// nudged by up to this much to keep the queue at its target fill level,
+// far too little to hear as a change in pitch.
+// Ref: https://docs.libretro.com/development/cores/dynamic-rate-control/
+pub const MAX_RATE_ADJUSTMENT: f64 = 0.005;
+
+// How much faster to sample with `queued` samples waiting to be played and
+// `target` the level to keep: 1.005 when the queue is empty, 1.0 at the
+// target and 0.995 when it holds twice the target or more
+pub fn rate_adjustment(queued: u32, target: u32) -> f64 {
+    let fill = (queued as f64 / (2.0 * target.max(1) as f64)).min(1.0);
+    1.0 + MAX_RATE_ADJUSTMENT * (1.0 - 2.0 * fill)
+}
+
+// ----------------------------------------------------------------------------
+// AudioConfig
+// ----------------------------------------------------------------------------
+
+// How the audio is played: the emulation samples at `sample_rate` (see
+// `Emulator::set_audio_sample_rate`), the device takes `buffer_size`
+// samples at a time and the rate control keeps `latency` worth of samples
+// queued for it. Longer latencies cope better with a busy system.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioConfig {
+    pub sample_rate: u32,
+    pub buffer_size: u16,
+    pub latency: Duration,
+}
+
+impl Default for AudioConfig {
+    // about 2 frames of latency
+    fn default() -> Self {
+        AudioConfig {
+            sample_rate: SAMPLE_RATE,
+            buffer_size: 1024,
+            latency: Duration::from_millis(35),
+        }
+    }
+}
+
+impl AudioConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !SAMPLE_RATES.contains(&self.sample_rate) {
+            return Err(format!("unsupported sample rate {}", self.sample_rate));
+        }
+        if !self.buffer_size.is_power_of_two() || !(256..=8192).contains(&self.buffer_size) {
+            return Err(format!(
+                "the audio buffer size must be a power of 2 from 256 to 8192, not {}",
+                self.buffer_size
+            ));
+        }
+        if self.target_queued_samples() < self.buffer_size as u32 {
+            return Err("the audio latency is shorter than the buffer".to_string());
+        }
+        Ok(())
+    }
+
+    // The queue level for `rate_adjustment`
+    pub fn target_queued_samples(&self) -> u32 {
+        (self.latency.as_secs_f64() * self.sample_rate as f64) as u32
+    }
+}
+
+// ----------------------------------------------------------------------------
+// AudioSampler
+// ----------------------------------------------------------------------------
+
+// Downsamples the APU output to the sample rate. Each output sample is the
+// average of the APU samples in its period, which is then passed through
+// the same filter chain as the NES' analog output stage.
+// Ref: https://wiki.nesdev.org/w/index.php/APU_Mixer
+pub struct AudioSampler {
+    sample_rate: u32,
+    cycles_per_sample: f64,
+    cycles_until_sample: f64,
+    sum: f32,
+    count: u32,
+    filters: [Filter; 3],
+
+    pub buffer: RingBuffer,
+    // gets every sample too, whether the buffer is drained or not
+    pub recorder: Option<WavWriter>,
+}
+
+impl AudioSampler {
+    pub fn new() -> Self {
+        AudioSampler::new_with_sample_rate(SAMPLE_RATE)
+    }
+
+    pub fn new_with_sample_rate(sample_rate: u32) -> Self {
+        let cycles_per_sample = CPU_CLOCK_RATE / sample_rate as f64;
+        AudioSampler {
+            sample_rate,
+            cycles_per_sample,
+            cycles_until_sample: cycles_per_sample,
+            sum: 0.0,
+            count: 0,
+            filters: [
+                Filter::high_pass(sample_rate, 90.0),
+                Filter::high_pass(sample_rate, 440.0),
+                Filter::low_pass(sample_rate, 14_000.0),
+            ],
+            // about 1/3 second of audio, way more than a frame's worth
+            buffer: RingBuffer::new(sample_rate as usize / 3),
+            recorder: None,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Produce `adjustment` times as many samples as the sample rate says,
+    // see `rate_adjustment`
+    pub fn set_rate_adjustment(&mut self, adjustment: f64) {
+        self.cycles_per_sample = CPU_CLOCK_RATE / (self.sample_rate as f64 * adjustment);
+    }
+
+    // Called once per CPU cycle with the mixed APU output
+    pub fn push(&mut self, sample: f32) {
+        self.sum += sample;
+        self.count += 1;
+        self.cycles_until_sample -= 1.0;
+        if self.cycles_until_sample > 0.0 {
+            return;
+        }
+        self.cycles_until_sample += self.cycles_per_sample;
+
+        let mut output = self.sum / self.count as f32;
+        for filter in self.filters.iter_mut() {
+            output = filter.process(output);
+        }
+        self.buffer.push(output);
+        if let Some(recorder) = &mut self.recorder {
+            recorder.push(output);
+        }
+        self.sum = 0.0;
+        self.count = 0;
+    }
+}
+
+impl Default for AudioSampler {
+    fn default() -> Self {
+        AudioSampler::new()
+    }
+}
+
+// ----------------------------------------------------------------------------
+// Filter
+// ----------------------------------------------------------------------------
+
+enum FilterKind {
+    HighPass,
+    LowPass,
+}
+
+// First-order IIR filter
+struct Filter {
+    kind: FilterKind,
+    alpha: f32,
+    prev_input: f32,
+    prev_output: f32,
+}
+
+impl Filter {
+    fn high_pass(sample_rate: u32, cutoff: f32) -> Filter {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f32;
+        Filter {
+            kind: FilterKind::HighPass,
+            alpha: rc / (rc + dt),
+            prev_input: 0.0,
+            prev_output: 0.0,
+        }
+    }
+
+    fn low_pass(sample_rate: u32, cutoff: f32) -> Filter {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f32;
+        Filter {
+            kind: FilterKind::LowPass,
+            alpha: dt / (rc + dt),
+            prev_input: 0.0,
+            prev_output: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = match self.kind {
+            FilterKind::HighPass => self.alpha * (self.prev_output + input - self.prev_input),
+            FilterKind::LowPass => self.prev_output + self.alpha * (input - self.prev_output),
+        };
+        self.prev_input = input;
+        self.prev_output = output;
+        output
+    }
+}
+
+// ----------------------------------------------------------------------------
+// RingBuffer
+// ----------------------------------------------------------------------------
+
+// Fixed size sample buffer between the emulation and the audio device.
+// When the emulation runs ahead of the device the oldest samples are
+// overwritten, so audio never lags behind video.
+pub struct RingBuffer {
+    samples: Vec<f32>,
+    read_idx: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> Self {
+        RingBuffer {
+            samples: vec![0.0; capacity],
+            read_idx: 0,
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, sample: f32) {
+        let capacity = self.samples.len();
+        let write_idx = (self.read_idx + self.len) % capacity;
+        self.samples[write_idx] = sample;
+        if self.len == capacity {
+            // full: drop the oldest sample
+            self.read_idx = (self.read_idx + 1) % capacity;
+        } else {
+            self.len += 1;
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<f32> {
+        if self.len == 0 {
+            return None;
+        }
+        let sample = self.samples[self.read_idx];
+        self.read_idx = (self.read_idx + 1) % self.samples.len();
+        self.len -= 1;
+        Some(sample)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn drain(&mut self) -> Vec<f32> {
+        let mut samples = Vec::with_capacity(self.len);
+        while let Some(sample) = self.pop() {
+            samples.push(sample);
+        }
+        samples
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ring_buffer_overwrites_oldest() {
+        let mut buffer = RingBuffer::new(3);
+        assert!(buffer.is_empty());
+        for i in 0..5 {
+            buffer.push(i as f32);
+        }
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.drain(), vec![2.0, 3.0, 4.0]);
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test]
+    fn test_sampler_output_rate() {
+        let mut sampler = AudioSampler::new();
+        // one frame's worth of CPU cycles
+        for _ in 0..(CPU_CLOCK_RATE / 60.0) as usize {
+            sampler.push(0.5);
+        }
+        let expected = SAMPLE_RATE as usize / 60;
+        assert!((expected - 1..=expected).contains(&sampler.buffer.len()));
+    }
+
+    #[test]
+    fn test_rate_adjustment() {
+        assert_eq!(rate_adjustment(0, 100), 1.005);
+        assert_eq!(rate_adjustment(100, 100), 1.0);
+        assert_eq!(rate_adjustment(150, 100), 0.9975);
+        assert_eq!(rate_adjustment(1000, 100), 0.995);
+
+        let mut sampler = AudioSampler::new();
+        sampler.set_rate_adjustment(rate_adjustment(0, 100));
+        for _ in 0..CPU_CLOCK_RATE as usize / 10 {
+            sampler.push(0.5);
+        }
+        // half a percent more than the 4410 of 1/10 second
+        let samples = sampler.buffer.len() as u32;
+        assert!((4431..=4433).contains(&samples), "{}", samples);
+    }
+
+    #[test]
+    fn test_config() {
+        let config = AudioConfig::default();
+        assert_eq!(config.validate(), Ok(()));
+        assert_eq!(config.target_queued_samples(), 1543);
+
+        let config = AudioConfig {
+            sample_rate: 48_000,
+            buffer_size: 512,
+            latency: Duration::from_millis(20),
+        };
+        assert_eq!(config.validate(), Ok(()));
+        assert_eq!(config.target_queued_samples(), 960);
+
+        let invalid = [
+            AudioConfig {
+                sample_rate: 22_050,
+                ..config
+            },
+            AudioConfig {
+                buffer_size: 1000,
+                ..config
+            },
+            AudioConfig {
+                buffer_size: 128,
+                ..config
+            },
+            AudioConfig {
+                latency: Duration::from_millis(10),
+                ..config
+            },
+        ];
+        for config in invalid.iter() {
+            assert!(config.validate().is_err(), "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn test_high_pass_removes_dc_offset() {
+        let mut sampler = AudioSampler::new();
+        for _ in 0..CPU_CLOCK_RATE as usize / 10 {
+            sampler.push(0.5);
+        }
+        let last = *sampler.buffer.drain().last().unwrap();
+        assert!(last.abs() < 0.01, "{}", last);
+    }
+}
@@ -0,0 +1,52 @@
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::AudioSubsystem;
+
+use super::AudioConfig;
+
+pub struct NesSDLAudio {
+    queue: AudioQueue<f32>,
+    // The rate control keeps this many samples queued in SDL, which plays
+    // without gaps. Past twice that samples are dropped to bound the
+    // latency, e.g. when fast-forwarding.
+    target_queued_samples: u32,
+}
+
+impl NesSDLAudio {
+    pub fn new(audio: &AudioSubsystem, config: &AudioConfig) -> Result<NesSDLAudio, String> {
+        let spec = AudioSpecDesired {
+            freq: Some(config.sample_rate as i32),
+            channels: Some(1),
+            samples: Some(config.buffer_size),
+        };
+        let queue: AudioQueue<f32> = audio.open_queue(None, &spec)?;
+        queue.resume();
+        Ok(NesSDLAudio {
+            queue,
+            target_queued_samples: config.target_queued_samples(),
+        })
+    }
+
+    // Queue samples for playback. Samples are dropped instead of queued
+    // when the device is already far behind, which keeps the audio in sync
+    // with the video.
+    pub fn queue_samples(&mut self, samples: &[f32]) -> Result<(), String> {
+        if self.queued_samples() > self.target_queued_samples * 2 {
+            return Ok(());
+        }
+        if self.queue.queue(samples) {
+            Ok(())
+        } else {
+            Err(sdl2::get_error())
+        }
+    }
+
+    pub fn queued_samples(&self) -> u32 {
+        self.queue.size() / std::mem::size_of::<f32>() as u32
+    }
+
+    // For `Emulator::set_audio_rate_adjustment`, to bring the queue back to
+    // its target
+    pub fn rate_adjustment(&self) -> f64 {
+        super::rate_adjustment(self.queued_samples(), self.target_queued_samples)
+    }
+}
@@ -0,0 +1,159 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// 16-bit mono PCM WAV files. Samples are written as they come, the sizes
+// in the header are filled in by `finish` or when the writer is dropped.
+
+pub const HEADER_SIZE: u32 = 44;
+
+pub struct WavWriter {
+    path: PathBuf,
+    out: BufWriter<File>,
+    sample_rate: u32,
+    samples: u64,
+    // The first write error, writing stops when there is one
+    error: Option<io::Error>,
+    finished: bool,
+}
+
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut out = BufWriter::new(File::create(&path)?);
+        // sizes are filled in by `finish`
+        write_header(&mut out, sample_rate, 0)?;
+        Ok(WavWriter {
+            path,
+            out,
+            sample_rate,
+            samples: 0,
+            error: None,
+            finished: false,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    pub fn seconds(&self) -> f64 {
+        self.samples as f64 / self.sample_rate as f64
+    }
+
+    // Samples are in range [-1.0, 1.0], louder ones are clipped
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.out.write_all(&sample.to_le_bytes())?;
+        }
+        self.samples += samples.len() as u64;
+        Ok(())
+    }
+
+    // One sample at a time, as the emulation produces them. A write error
+    // is returned by `finish`.
+    pub fn push(&mut self, sample: f32) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.write_samples(&[sample]) {
+            self.error = Some(e);
+        }
+    }
+
+    // Completes the header
+    pub fn finish(mut self) -> io::Result<()> {
+        self.complete()
+    }
+
+    pub(crate) fn complete(&mut self) -> io::Result<()> {
+        self.finished = true;
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        let data_size = (self.samples * 2).min((u32::MAX - HEADER_SIZE) as u64) as u32;
+        self.out.seek(SeekFrom::Start(0))?;
+        write_header(&mut self.out, self.sample_rate, data_size)?;
+        self.out.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.complete();
+        }
+    }
+}
+
+fn write_header<W: Write>(out: &mut W, sample_rate: u32, data_size: u32) -> io::Result<()> {
+    out.write_all(b"RIFF")?;
+    out.write_all(&(HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    // PCM, mono
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&sample_rate.to_le_bytes())?;
+    // byte rate, block align and bits per sample
+    out.write_all(&(sample_rate * 2).to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?;
+    out.write_all(&16u16.to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&data_size.to_le_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::emulator::Emulator;
+    use std::fs;
+
+    #[test]
+    fn test_recording() {
+        let path = std::env::temp_dir().join(format!("nes-wav-test-{}.wav", std::process::id()));
+        // a square wave on pulse 1
+        let program = [
+            0xA9, 0x01, 0x8D, 0x15, 0x40, // 8000 pulse 1 on
+            0xA9, 0xBF, 0x8D, 0x00, 0x40, // 8005 constant volume 15
+            0xA9, 0xFD, 0x8D, 0x02, 0x40, // 800A period $FD
+            0xA9, 0x08, 0x8D, 0x03, 0x40, // 800F load the length counter
+            0x4C, 0x14, 0x80, // 8014 JMP $8014
+        ];
+        let mut prg = program.to_vec();
+        prg.resize(0x3FFC, 0);
+        prg.extend_from_slice(&[0x00, 0x80]);
+        let mut emulator = Emulator::new(Cartridge::new_from_program(prg));
+        emulator.start_audio_recording(&path).unwrap();
+        for _ in 0..6 {
+            emulator.run_frame().unwrap();
+        }
+        // nothing drained the samples, they are recorded anyway
+        let recording = emulator.stop_audio_recording().unwrap();
+        assert!(emulator.stop_audio_recording().is_none());
+        let samples = recording.samples();
+        assert!((4400..4420).contains(&samples), "{}", samples);
+        assert!((recording.seconds() - 0.1).abs() < 0.001);
+        recording.finish().unwrap();
+
+        let wav = fs::read(&path).unwrap();
+        assert_eq!(wav.len(), HEADER_SIZE as usize + samples as usize * 2);
+        assert_eq!(wav[..4], *b"RIFF");
+        assert_eq!(wav[4..8], (36 + samples as u32 * 2).to_le_bytes());
+        assert_eq!(wav[24..28], 44_100u32.to_le_bytes());
+        assert_eq!(wav[40..44], (samples as u32 * 2).to_le_bytes());
+        let levels: Vec<i16> = wav[44..]
+            .chunks(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        assert!(levels.iter().any(|&level| level > 1000));
+        assert!(levels.iter().any(|&level| level < -1000));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::process;
+
+use nes::cartridge::Cartridge;
+use nes::cpu::disasm;
+use nes::symbols::Symbols;
+
+// Disassemble the PRG ROM of an iNES file to stdout, optionally with the
+// names from a .nl or .mlb symbol file and with what a .cdl code/data log
+// saw read as data shown as data:
+//   cargo run --bin disasm rom.nes [rom.nes.0.nl] [rom.cdl]
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: disasm ROM [SYMBOLS] [CDL]");
+            process::exit(2);
+        }
+    };
+    let (cdl_paths, symbols_paths): (Vec<String>, Vec<String>) =
+        env::args().skip(2).partition(|arg| arg.ends_with(".cdl"));
+    let raw = fs::read(&path).unwrap_or_else(|e| {
+        eprintln!("failed to read {}: {}", path, e);
+        process::exit(1);
+    });
+    let prg = Cartridge::prg_rom(&raw).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    });
+    let names = match symbols_paths.first() {
+        Some(symbols_path) => {
+            let prg_banks = (prg.len() / 0x4000) as u8;
+            let symbols = Symbols::load(symbols_path, prg_banks).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                process::exit(1);
+            });
+            symbols.names().clone()
+        }
+        None => BTreeMap::new(),
+    };
+    let cdl = cdl_paths.first().map(|cdl_path| {
+        let cdl = fs::read(cdl_path).unwrap_or_else(|e| {
+            eprintln!("failed to read {}: {}", cdl_path, e);
+            process::exit(1);
+        });
+        // PRG flags come first, the CHR ones after don't matter here
+        if cdl.len() < prg.len() {
+            eprintln!("{}: too short for a {} byte PRG ROM", cdl_path, prg.len());
+            process::exit(1);
+        }
+        cdl
+    });
+    let cdl = cdl.as_ref().map(|cdl| &cdl[..prg.len()]);
+    print!("{}", disasm::prg_listing(prg, &names, cdl));
+}
@@ -1,70 +1,1533 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use cpu::CPU;
-use nes::bus::Bus;
+use nes::apu::mixer::Channel;
+use nes::apu_log;
+use nes::audio::{AudioConfig, NesSDLAudio};
+use nes::bus::RamInit;
 use nes::cartridge::Cartridge;
-use nes::cpu;
-use nes::graphics::{NesFrame, NesSDLScreen};
-use nes::joypad::{Joypad, JoypadStatus};
-use nes::ppu::PPU;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use nes::desync::Desync;
+use nes::easy6502::Easy6502;
+use nes::emulator::{FRAME_DURATION, FRAME_RATE};
+use nes::event_log;
+use nes::graphics::font;
+use nes::graphics::ntsc::NtscFilter;
+use nes::graphics::{NesFrame, NesSDLScreen, ScaleMode};
+use nes::history;
+use nes::input::{InputConfig, NesSDLGamepads};
+use nes::input_log::{Movie, MovieFrame};
+use nes::joypad::JoypadStatus;
+use nes::monitor::Monitor;
+use nes::netplay::{Netplay, PowerOnSettings, DEFAULT_DELAY};
+use nes::ppu::viewer::NUM_PALETTES;
+use nes::ppu::Accuracy;
+use nes::recent_roms::RecentRoms;
+use nes::romdb::{sha1_hex, RomDb};
+use nes::savestate::crc32;
+#[cfg(feature = "scripting")]
+use nes::script::Script;
+use nes::settings::{config_dir, Settings};
+use nes::state_slots::{StateSlots, NUM_SLOTS};
+use nes::trace_log::{TraceFields, TraceFormat, TraceLogger};
+use nes::video_recorder::VideoRecorder;
+use nes::Emulator;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::mouse::MouseButton;
+
+const FAST_FORWARD_SPEED: f64 = 4.0;
+const SLOW_MOTION_SPEED: f64 = 0.5;
+// When this many frames behind (e.g. after the window was dragged) the
+// limiter starts over instead of running flat out to catch up
+const MAX_FRAMES_BEHIND: u32 = 4;
+
+// Paces frames against a running deadline. Sleeping for whatever is left of
+// each frame drifts since sleeps overshoot, the deadline absorbs that.
+struct FrameLimiter {
+    next_frame: Instant,
+}
+
+impl FrameLimiter {
+    fn new() -> Self {
+        FrameLimiter {
+            next_frame: Instant::now(),
+        }
+    }
+
+    fn wait(&mut self, frame_duration: Duration) {
+        self.next_frame += frame_duration;
+        let now = Instant::now();
+        if self.next_frame > now {
+            std::thread::sleep(self.next_frame - now);
+        } else if now - self.next_frame > frame_duration * MAX_FRAMES_BEHIND {
+            self.next_frame = now;
+        }
+    }
+}
+
+// Frames run per second of wall clock time, measured over a second
+struct FpsCounter {
+    start: Instant,
+    frames: u32,
+    fps: f64,
+}
+
+impl FpsCounter {
+    fn new() -> Self {
+        FpsCounter {
+            start: Instant::now(),
+            frames: 0,
+            fps: 0.0,
+        }
+    }
+
+    // Returns true when a second is over and `fps` was updated
+    fn add_frames(&mut self, frames: u32) -> bool {
+        self.frames += frames;
+        let elapsed = self.start.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return false;
+        }
+        self.fps = self.frames as f64 / elapsed.as_secs_f64();
+        self.frames = 0;
+        self.start = Instant::now();
+        true
+    }
+}
+
+// Print a message and show it on screen
+fn notify<S: Into<String>>(emulator: &mut Emulator, message: S) {
+    let message = message.into();
+    eprintln!("{}", message);
+    emulator.osd_mut().show(message);
+}
+
+// Netplay stops on desyncs and network errors
+fn netplay_ok(result: Result<Option<Desync>, String>, emulator: &mut Emulator) -> bool {
+    let reason = match result {
+        Ok(None) => return true,
+        Ok(Some(desync)) => desync.to_string(),
+        Err(e) => e,
+    };
+    eprintln!("netplay: {}", reason);
+    notify(emulator, "Netplay stopped");
+    false
+}
+
+// Call the script, it stops when it fails
+#[cfg(feature = "scripting")]
+fn run_script<T, F>(script: &mut Option<Script>, emulator: &mut Emulator, f: F) -> Option<T>
+where
+    F: FnOnce(&mut Script, &mut Emulator) -> Result<T, String>,
+{
+    match f(script.as_mut()?, emulator) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            eprintln!("script: {}", e);
+            notify(emulator, "Script stopped");
+            script.take().unwrap().stop(emulator);
+            None
+        }
+    }
+}
+
+// Draw the frame with `overlay` over it, a line of text each from the top
+// left corner, and the on-screen messages. `drawings` draws in RGB, it only
+// shows without the NTSC filter.
+fn draw_screen(
+    screen: &mut NesSDLScreen,
+    ntsc: &mut Option<NtscFilter>,
+    emulator: &Emulator,
+    overlay: &[String],
+    drawings: Option<&dyn Fn(&mut NesFrame)>,
+) {
+    let lines = || {
+        overlay
+            .iter()
+            .enumerate()
+            .map(|(i, line)| (2, 2 + i as u32 * (font::GLYPH_SIZE + 1), line))
+    };
+    let osd = emulator.osd();
+    let plain = overlay.is_empty() && osd.is_empty();
+    screen.clear();
+    match ntsc {
+        Some(filter) if plain => {
+            filter.apply(emulator.indexed_frame());
+            screen.draw_ntsc(filter);
+        }
+        Some(filter) => {
+            let mut pixels = emulator.indexed_frame().to_vec();
+            for (x, y, line) in lines() {
+                // $30 is white
+                font::draw_text_indexed(&mut pixels, x, y, line, 0x30);
+            }
+            osd.draw_indexed(&mut pixels);
+            filter.apply(&pixels);
+            screen.draw_ntsc(filter);
+        }
+        None if plain && drawings.is_none() => screen.draw_frame(emulator.frame()),
+        None => {
+            let mut frame = emulator.frame().clone();
+            if let Some(draw) = drawings {
+                draw(&mut frame);
+            }
+            for (x, y, line) in lines() {
+                font::draw_text(&mut frame, x, y, line, [0xFF, 0xFF, 0xFF]);
+            }
+            osd.draw(&mut frame);
+            screen.draw_frame(&frame);
+        }
+    }
+    screen.present();
+}
+
+// Shift+F1 to F10 save to slots 1 to 10 and Ctrl+F1 to F10 load them
+fn state_slot(keycode: Keycode) -> Option<usize> {
+    let keys = [
+        Keycode::F1,
+        Keycode::F2,
+        Keycode::F3,
+        Keycode::F4,
+        Keycode::F5,
+        Keycode::F6,
+        Keycode::F7,
+        Keycode::F8,
+        Keycode::F9,
+        Keycode::F10,
+    ];
+    keys.iter()
+        .take(NUM_SLOTS)
+        .position(|&key| key == keycode)
+        .map(|i| i + 1)
+}
+
+// The game's title when the ROM database knows it, the file name otherwise
+fn game_name(rom_path: &Path, emulator: &Emulator) -> String {
+    match emulator.cartridge().title() {
+        Some(title) => title.to_string(),
+        None => rom_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+    }
+}
+
+fn window_title(name: &str, fps: f64) -> String {
+    format!("NES - {} - {:.1} fps", name, fps)
+}
+
+// What the ROM is, from its hashes and the ROM database
+fn log_rom_info(cart: &Cartridge) {
+    println!("CRC32 {:08X} SHA1 {}", cart.crc32, sha1_hex(&cart.sha1));
+    match &cart.db_info {
+        Some(info) => println!(
+            "{}: mapper {}.{}{}, {:?} mirroring{}",
+            info.title.as_deref().unwrap_or("untitled"),
+            info.mapper,
+            info.submapper,
+            info.board()
+                .map_or(String::new(), |board| format!(" ({})", board)),
+            cart.mirror,
+            if info.battery { ", battery" } else { "" },
+        ),
+        None => println!("not in the ROM database"),
+    }
+    for warning in cart.load_report.warnings.iter() {
+        eprintln!("warning: {}", warning);
+    }
+}
+
+struct Args {
+    vsync: bool,
+    // a Zapper in port 2, aimed with the mouse and fired with the left button
+    zapper: bool,
+    // key bindings file, see `InputConfig`
+    input: Option<PathBuf>,
+    // movie to record from power on, written on exit
+    record: Option<PathBuf>,
+    // movie to play back before handing control to the player
+    play: Option<PathBuf>,
+    // Game Genie or Action Replay codes
+    cheats: Vec<String>,
+    // start in the monitor, which also opens on breakpoints and F12
+    debug: bool,
+    // instruction trace, toggled with F11
+    trace: Option<PathBuf>,
+    // how the picture fills the window, F6 cycles through the modes
+    scale: ScaleMode,
+    // what RAM holds at power on
+    ram_init: RamInit,
+    // whether the PPU has the hardware's bugs
+    accuracy: Accuracy,
+    // an Easy6502 program to assemble and run instead of the ROM
+    asm: Option<PathBuf>,
+    // NES 2.0 database XML to identify games by, nes20db.xml in the config
+    // directory when it exists
+    romdb: Option<PathBuf>,
+    // netplay host to join, "host:port"
+    netplay: Option<String>,
+    // port to host netplay on
+    listen: Option<u16>,
+    // netplay frames to guess the peer's input for, 0 for lockstep
+    rollback: u32,
+    // Rhai script to run, needs the scripting feature
+    script: Option<PathBuf>,
+    // FCEUX code/data log of the game, continued when it exists
+    cdl: Option<PathBuf>,
+    // where to write the cycles spent in each subroutine
+    profile: Option<PathBuf>,
+    audio: AudioConfig,
+    rom: PathBuf,
+}
+
+// nes [--vsync] [--zapper] [--input FILE] [--record FILE] [--play FILE]
+//     [--cheat CODE]... [--debug] [--trace FILE]
+//     [--scale integer|stretch|aspect] [--ram zero|ff|striped|random[:SEED]]
+//     [--accuracy correct|hardware] [--asm FILE] [--romdb FILE] [--netplay HOST:PORT | --listen PORT]
+//     [--rollback FRAMES] [--script FILE] [--cdl FILE] [--profile FILE]
+//     [--sample-rate 44100|48000] [--audio-buffer SAMPLES] [--latency MS]
+//     [ROM]
+//
+// Random RAM without a seed differs on every run, movies recorded with it
+// won't play back.
+//
+// --accuracy hardware emulates the PPU bugs some test ROMs check, like the
+// sprite overflow flag missing sprites.
+//
+// Movies ending in .fm2 use the FCEUX format, traces ending in .bin the
+// binary trace format.
+//
+// With --listen the game waits for a player to join with --netplay, the
+// host plays with the first controller and the guest with the second. Both
+// play with the player 1 keys. The guest uses the --ram and --accuracy of
+// the host. --rollback runs ahead of the other player's input instead of
+// waiting for it, the frames are run again when it comes.
+//
+// --script runs a Rhai script with the game, see src/script.rs.
+//
+// --cdl logs which bytes of the first game ran as code and which were read
+// as data, saved on exit or when another ROM is loaded. The disasm binary
+// takes the file to tell them apart.
+//
+// --profile counts the cycles the first game spends in each subroutine, the
+// report is written like --cdl.
+//
+// --audio-buffer is how many samples the audio device takes at a time, a
+// power of 2, and --latency how much audio is kept queued for it. Raise
+// them if the sound crackles.
+fn parse_args() -> Result<Args, String> {
+    let mut vsync = false;
+    let mut zapper = false;
+    let mut input = None;
+    let mut record = None;
+    let mut play = None;
+    let mut cheats = vec![];
+    let mut debug = false;
+    let mut trace = None;
+    let mut scale = ScaleMode::Integer;
+    let mut ram_init = RamInit::AllZero;
+    let mut accuracy = Accuracy::Correct;
+    let mut asm = None;
+    let mut romdb = None;
+    let mut netplay = None;
+    let mut listen = None;
+    let mut rollback = 0;
+    let mut script = None;
+    let mut cdl = None;
+    let mut profile = None;
+    let mut audio = AudioConfig::default();
+    let mut rom = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--vsync" => vsync = true,
+            "--debug" => debug = true,
+            "--zapper" => zapper = true,
+            "--input" => input = Some(PathBuf::from(args.next().ok_or("--input needs a file")?)),
+            "--record" => record = Some(PathBuf::from(args.next().ok_or("--record needs a file")?)),
+            "--play" => play = Some(PathBuf::from(args.next().ok_or("--play needs a file")?)),
+            "--cheat" => cheats.push(args.next().ok_or("--cheat needs a code")?),
+            "--trace" => trace = Some(PathBuf::from(args.next().ok_or("--trace needs a file")?)),
+            "--asm" => asm = Some(PathBuf::from(args.next().ok_or("--asm needs a file")?)),
+            "--romdb" => romdb = Some(PathBuf::from(args.next().ok_or("--romdb needs a file")?)),
+            "--script" => script = Some(PathBuf::from(args.next().ok_or("--script needs a file")?)),
+            "--profile" => {
+                profile = Some(PathBuf::from(args.next().ok_or("--profile needs a file")?))
+            }
+            "--cdl" => cdl = Some(PathBuf::from(args.next().ok_or("--cdl needs a file")?)),
+            "--netplay" => netplay = Some(args.next().ok_or("--netplay needs host:port")?),
+            "--listen" => {
+                let port = args.next().ok_or("--listen needs a port")?;
+                listen = Some(port.parse().map_err(|_| format!("invalid port {}", port))?);
+            }
+            "--rollback" => {
+                let frames = args.next().ok_or("--rollback needs a number of frames")?;
+                rollback = frames
+                    .parse()
+                    .map_err(|_| format!("invalid number of frames {}", frames))?;
+            }
+            "--scale" => {
+                scale = match args.next().as_deref() {
+                    Some("integer") => ScaleMode::Integer,
+                    Some("stretch") => ScaleMode::Stretch,
+                    Some("aspect") => ScaleMode::AspectCorrect,
+                    _ => return Err("--scale needs integer, stretch or aspect".to_string()),
+                }
+            }
+            "--ram" => {
+                ram_init = match args.next().as_deref() {
+                    Some("zero") => RamInit::AllZero,
+                    Some("ff") => RamInit::AllFF,
+                    Some("striped") => RamInit::Striped,
+                    Some("random") => RamInit::Random(
+                        SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .map_or(1, |time| time.subsec_nanos()),
+                    ),
+                    other => match other.and_then(|arg| arg.strip_prefix("random:")) {
+                        Some(seed) => RamInit::Random(
+                            seed.parse()
+                                .map_err(|_| format!("invalid RAM seed {}", seed))?,
+                        ),
+                        None => return Err("--ram needs zero, ff, striped or random".to_string()),
+                    },
+                }
+            }
+            "--accuracy" => {
+                accuracy = match args.next().as_deref() {
+                    Some("correct") => Accuracy::Correct,
+                    Some("hardware") => Accuracy::Hardware,
+                    _ => return Err("--accuracy needs correct or hardware".to_string()),
+                }
+            }
+            "--sample-rate" => {
+                let rate = args.next().ok_or("--sample-rate needs 44100 or 48000")?;
+                audio.sample_rate = rate
+                    .parse()
+                    .map_err(|_| format!("invalid sample rate {}", rate))?;
+            }
+            "--audio-buffer" => {
+                let size = args
+                    .next()
+                    .ok_or("--audio-buffer needs a number of samples")?;
+                audio.buffer_size = size
+                    .parse()
+                    .map_err(|_| format!("invalid audio buffer size {}", size))?;
+            }
+            "--latency" => {
+                let ms = args.next().ok_or("--latency needs milliseconds")?;
+                let ms = ms.parse().map_err(|_| format!("invalid latency {}", ms))?;
+                audio.latency = Duration::from_millis(ms);
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => rom = Some(PathBuf::from(arg)),
+        }
+    }
+    if netplay.is_some() && listen.is_some() {
+        return Err("--netplay and --listen can't be used together".to_string());
+    }
+    if (netplay.is_some() || listen.is_some()) && play.is_some() {
+        return Err("movies can't be played during netplay".to_string());
+    }
+    // the peer doesn't run it
+    if (netplay.is_some() || listen.is_some()) && script.is_some() {
+        return Err("scripts can't run during netplay".to_string());
+    }
+    // frames that are run again would be recorded twice
+    if rollback > 0 && record.is_some() {
+        return Err("movies can't be recorded with rollback".to_string());
+    }
+    audio.validate()?;
+    let rom = rom.unwrap_or_else(|| {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("tests/resources/smb.nes");
+        path
+    });
+    let romdb = romdb.or_else(|| {
+        config_dir()
+            .map(|dir| dir.join("nes20db.xml"))
+            .filter(|path| path.exists())
+    });
+    Ok(Args {
+        vsync,
+        zapper,
+        input,
+        record,
+        play,
+        cheats,
+        debug,
+        trace,
+        scale,
+        ram_init,
+        accuracy,
+        asm,
+        romdb,
+        netplay,
+        listen,
+        rollback,
+        script,
+        cdl,
+        profile,
+        audio,
+        rom,
+    })
+}
+
+fn open_rom(path: &Path, args: &Args, romdb: &RomDb) -> Result<(Vec<u8>, Emulator), String> {
+    let rom = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let cart = Cartridge::new_with_db(&rom, Some(romdb))?;
+    log_rom_info(&cart);
+    let mut emulator = Emulator::new(cart);
+    emulator.connect_zapper(args.zapper);
+    emulator.set_ram_init(args.ram_init);
+    emulator.set_accuracy(args.accuracy);
+    emulator.set_audio_sample_rate(args.audio.sample_rate);
+    Ok((rom, emulator))
+}
+
+// Ctrl+1 to Ctrl+9 pick an entry of the recent ROMs
+fn recent_rom_index(keycode: Keycode, keymod: Mod) -> Option<usize> {
+    if !keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) {
+        return None;
+    }
+    let index = keycode as i32 - Keycode::Num1 as i32;
+    (0..9).contains(&index).then_some(index as usize)
+}
+
+// Alt+1 to Alt+6 mute pulse 1, pulse 2, the triangle, the noise, the DMC
+// and the expansion audio, with Shift they solo it
+fn audio_channel(keycode: Keycode, keymod: Mod) -> Option<Channel> {
+    if !keymod.intersects(Mod::LALTMOD | Mod::RALTMOD) {
+        return None;
+    }
+    let index = keycode as i32 - Keycode::Num1 as i32;
+    let channels = Channel::ALL.len() as i32;
+    (0..channels)
+        .contains(&index)
+        .then(|| Channel::ALL[index as usize])
+}
+
+fn toggle_channel(emulator: &mut Emulator, channel: Channel, solo: bool) {
+    let message = if solo && emulator.solo() == Some(channel) {
+        emulator.set_solo(None);
+        "Solo off".to_string()
+    } else if solo {
+        emulator.set_solo(Some(channel));
+        format!("Solo {}", channel.name())
+    } else {
+        let muted = !emulator.is_muted(channel);
+        emulator.set_muted(channel, muted);
+        let state = if muted { "muted" } else { "on" };
+        format!("Audio {} {}", channel.name(), state)
+    };
+    notify(emulator, message);
+}
+
+// Blocks the window until the user continues. Returns false to quit.
+fn run_monitor(monitor: &mut Monitor, emulator: &mut Emulator) -> Result<bool, String> {
+    let stdin = std::io::stdin();
+    monitor
+        .prompt(emulator, &mut stdin.lock(), &mut std::io::stdout())
+        .map_err(|e| e.to_string())
+}
+
+fn is_fm2(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "fm2")
+}
+
+fn load_movie(path: &Path, rom: &[u8]) -> Result<Movie, String> {
+    let movie = if is_fm2(path) {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Movie::from_fm2(&text)?
+    } else {
+        let data = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Movie::from_bytes(&data)?
+    };
+    movie.check_rom(rom)?;
+    Ok(movie)
+}
+
+fn save_movie(path: &Path, movie: &Movie, rom_path: &Path) -> Result<(), String> {
+    let data = if is_fm2(path) {
+        let rom_name = rom_path.file_stem().unwrap_or_default().to_string_lossy();
+        movie.to_fm2(&rom_name)?.into_bytes()
+    } else {
+        movie.to_bytes()
+    };
+    std::fs::write(path, data).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn save_code_data_log(path: &Path, emulator: &mut Emulator) -> Result<(), String> {
+    if let Some(log) = emulator.stop_code_data_log() {
+        std::fs::write(path, log.to_bytes()).map_err(|e| format!("{}: {}", path.display(), e))?;
+        eprintln!("{}: {}", path.display(), log);
+    }
+    Ok(())
+}
+
+fn save_profile(path: &Path, emulator: &mut Emulator) -> Result<(), String> {
+    if let Some(report) = emulator.profile_report(usize::MAX) {
+        emulator.stop_profiling();
+        std::fs::write(path, report).map_err(|e| format!("{}: {}", path.display(), e))?;
+        eprintln!("wrote the profile to {}", path.display());
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum DebugView {
+    PatternTables,
+    Sprites,
+    // timing diagram of the previous frame, the event log is attached while
+    // this is shown
+    Events,
+    // APU register writes of the previous frame, with the APU log attached
+    Apu,
+}
+
+// A directory for a new video dump next to the working directory, named
+// after the ROM
+fn video_dir(rom_path: &Path) -> PathBuf {
+    let rom_name = rom_path.file_stem().unwrap_or_default().to_string_lossy();
+    (1..)
+        .map(|n| PathBuf::from(format!("{}-video-{}", rom_name, n)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+// A new WAV file next to the working directory, named after the ROM
+fn audio_path(rom_path: &Path) -> PathBuf {
+    let rom_name = rom_path.file_stem().unwrap_or_default().to_string_lossy();
+    (1..)
+        .map(|n| PathBuf::from(format!("{}-audio-{}.wav", rom_name, n)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+fn stop_audio_recording(emulator: &mut Emulator) {
+    if let Some(recorder) = emulator.stop_audio_recording() {
+        let (path, seconds) = (recorder.path().to_path_buf(), recorder.seconds());
+        match recorder.finish() {
+            Ok(()) => eprintln!("recorded {:.1}s of audio to {}", seconds, path.display()),
+            Err(e) => eprintln!("failed to write the audio: {}", e),
+        }
+    }
+}
+
+fn stop_video(recorder: VideoRecorder) {
+    let (dir, frames) = (recorder.dir().to_path_buf(), recorder.frames());
+    match recorder.finish() {
+        Ok(()) => eprintln!("recorded {} frames of video to {}", frames, dir.display()),
+        Err(e) => eprintln!("failed to write the video: {}", e),
+    }
+}
+
+// PPU and APU debug views in a second window. F9 cycles through the views and
+// closes the window, F10 cycles through the loaded palettes in the pattern
+// table view.
+struct DebugWindow {
+    screen: NesSDLScreen,
+    frame: NesFrame,
+    view: DebugView,
+    palette: u8,
+}
+
+impl DebugWindow {
+    fn new(video: &sdl2::VideoSubsystem) -> Self {
+        DebugWindow {
+            screen: NesSDLScreen::new(video, 3),
+            frame: NesFrame::new(),
+            view: DebugView::PatternTables,
+            palette: 0,
+        }
+    }
+
+    fn window_id(&self) -> u32 {
+        self.screen.window().id()
+    }
+
+    fn next_palette(&mut self) {
+        self.palette = (self.palette + 1) % NUM_PALETTES;
+    }
+
+    fn draw(&mut self, emulator: &Emulator) {
+        let ppu = &emulator.cpu().bus.ppu;
+        let title = match self.view {
+            DebugView::PatternTables => {
+                ppu.render_pattern_tables(&mut self.frame, self.palette);
+                format!("Pattern tables (palette {})", self.palette)
+            }
+            DebugView::Sprites => {
+                ppu.render_sprites(&mut self.frame);
+                "Sprites".to_string()
+            }
+            DebugView::Events => {
+                // the monitor can detach the log
+                if let Some(log) = emulator.event_log() {
+                    log.render(log.frame().saturating_sub(1), &mut self.frame);
+                }
+                "Events".to_string()
+            }
+            DebugView::Apu => {
+                if let Some(log) = emulator.apu_log() {
+                    log.render(log.frame().saturating_sub(1), &mut self.frame);
+                }
+                "APU writes".to_string()
+            }
+        };
+        if self.screen.window().title() != title {
+            let _ = self.screen.window_mut().set_title(&title);
+        }
+        self.screen.clear();
+        self.screen.draw_frame(&self.frame);
+        self.screen.present();
+    }
+}
+
+// Program mode, see `easy6502`. Keys are passed on as ASCII codes.
+fn run_program(
+    path: &Path,
+    screen: &mut NesSDLScreen,
+    event_pump: &mut sdl2::EventPump,
+    vsync: bool,
+) -> Result<(), String> {
+    let mut sandbox = Easy6502::from_file(path)?;
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(1, |time| time.subsec_nanos());
+    sandbox.set_seed(seed);
+    let mut frame = NesFrame::new();
+    let mut limiter = FrameLimiter::new();
+    loop {
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => return Ok(()),
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    ..
+                } => {
+                    // letters are lower case
+                    if let Ok(ascii) = u8::try_from(keycode as i32) {
+                        sandbox.press_key(ascii);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let was_stopped = sandbox.is_stopped();
+        sandbox.run_frame().map_err(|e| e.to_string())?;
+        if sandbox.is_stopped() && !was_stopped {
+            eprintln!("the program stopped at ${:04X}", sandbox.cpu().pc);
+        }
+        sandbox.render(&mut frame);
+        screen.clear();
+        screen.draw_frame(&frame);
+        screen.present();
+        if !vsync {
+            limiter.wait(FRAME_DURATION);
+        }
+    }
+}
+
+// Key -> (player, button)
+fn key_map(config: &InputConfig) -> Result<HashMap<Keycode, (usize, JoypadStatus)>, String> {
+    let mut key_map = HashMap::new();
+    for (name, player, button) in config.bindings() {
+        let keycode = Keycode::from_name(name).ok_or(format!("unknown key \"{}\"", name))?;
+        key_map.insert(keycode, (player, button));
+    }
+    Ok(key_map)
+}
 
 fn main() -> Result<(), String> {
+    let args = parse_args()?;
+    let vsync = args.vsync;
     let sdl_context = sdl2::init()?;
     let video_subsystem = sdl_context.video()?;
-    let mut screen = NesSDLScreen::new(&video_subsystem, 3);
-    let mut frame = NesFrame::new();
+    let audio_subsystem = sdl_context.audio()?;
+    let controller_subsystem = sdl_context.game_controller()?;
+    let mut screen = NesSDLScreen::new_with_vsync(&video_subsystem, 3, vsync);
+    screen.set_scale_mode(args.scale);
+    // the window keeps its size between runs
+    let settings_file = Settings::default_file();
+    let mut settings = settings_file
+        .as_ref()
+        .map(Settings::load)
+        .unwrap_or_default();
+    if let Some((width, height)) = settings.window_size {
+        screen.set_window_size(width, height)?;
+    }
+    // F8 switches between the plain RGB picture and the NTSC filter
+    let mut ntsc: Option<NtscFilter> = None;
+    // F4 starts and stops dumping frames and audio
+    let mut video: Option<VideoRecorder> = None;
+    let mut audio = NesSDLAudio::new(&audio_subsystem, &args.audio)?;
+    let mut gamepads = NesSDLGamepads::new(&controller_subsystem);
     let mut event_pump = sdl_context.event_pump()?;
 
-    let mut nes_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    nes_path.push("tests/resources/smb.nes");
-    let cart = Cartridge::new_from_file(nes_path).unwrap();
-    let bus = Bus::new_with_gameloop_callback(cart, move |ppu: &PPU, joypads: &mut [Joypad; 2]| {
-        ppu.render_ppu(&mut frame);
-        screen.clear();
-        screen.draw_frame(&frame);
-        screen.present();
+    if let Some(path) = &args.asm {
+        return run_program(path, &mut screen, &mut event_pump, vsync);
+    }
+
+    let romdb = match &args.romdb {
+        Some(path) => {
+            let romdb = RomDb::load(path)?;
+            println!("{} games in {}", romdb.len(), path.display());
+            romdb
+        }
+        None => RomDb::new(),
+    };
+    let mut rom_path = args.rom.clone();
+    let (rom, mut emulator) = open_rom(&rom_path, &args, &romdb)?;
+    let mut rom_name = game_name(&rom_path, &emulator);
+    for code in args.cheats.iter() {
+        emulator.cheats_mut().add(code)?;
+        emulator.osd_mut().show(format!("Cheat {} enabled", code));
+    }
+
+    if let Some(path) = &args.trace {
+        let format = if path.extension().is_some_and(|ext| ext == "bin") {
+            TraceFormat::Binary
+        } else {
+            TraceFormat::Text(TraceFields::all())
+        };
+        let logger =
+            TraceLogger::to_file(path, format).map_err(|e| format!("{}: {}", path.display(), e))?;
+        emulator.start_trace(logger);
+    }
+
+    if let Some(path) = &args.cdl {
+        let saved = match std::fs::read(path) {
+            Ok(saved) => Some(saved),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        emulator
+            .start_code_data_log(saved.as_deref())
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
 
-        let mut key_map = HashMap::new();
-        key_map.insert(Keycode::Up, JoypadStatus::UP);
-        key_map.insert(Keycode::Down, JoypadStatus::DOWN);
-        key_map.insert(Keycode::Left, JoypadStatus::LEFT);
-        key_map.insert(Keycode::Right, JoypadStatus::RIGHT);
-        key_map.insert(Keycode::Space, JoypadStatus::SELECT);
-        key_map.insert(Keycode::Return, JoypadStatus::START);
-        key_map.insert(Keycode::A, JoypadStatus::BUTTON_A);
-        key_map.insert(Keycode::S, JoypadStatus::BUTTON_B);
+    if args.profile.is_some() {
+        emulator.start_profiling();
+    }
 
+    #[cfg(feature = "scripting")]
+    let mut script = match &args.script {
+        Some(path) => Some(Script::load(path, &mut emulator)?),
+        None => None,
+    };
+    #[cfg(not(feature = "scripting"))]
+    if args.script.is_some() {
+        return Err("scripts need a build with --features scripting".to_string());
+    }
+
+    let input_config = match &args.input {
+        Some(path) => InputConfig::from_file(path)?,
+        None => InputConfig::new(),
+    };
+    let key_map = key_map(&input_config)?;
+
+    // a ROM dropped on the window or picked from the recent ones replaces
+    // the running one after the events are handled
+    let mut pending_rom: Option<PathBuf> = None;
+    let mut recent_roms = RecentRoms::default_file().map(RecentRoms::load);
+    if let Some(recent_roms) = &mut recent_roms {
+        recent_roms.add(&rom_path);
+        if let Err(e) = recent_roms.save() {
+            eprintln!("failed to save the recent ROMs: {}", e);
+        }
+        for (i, path) in recent_roms.paths().iter().enumerate().skip(1) {
+            eprintln!("Ctrl+{}: {}", i, path.display());
+        }
+    }
+
+    let mut buttons = [JoypadStatus::empty(); 2];
+    let mut saved_state: Option<Vec<u8>> = None;
+    let mut slots = StateSlots::for_rom(&rom);
+    // Tab fast-forwards while held, F3 toggles slow motion
+    let mut fast_forward = false;
+    let mut slow_motion = false;
+    let mut limiter = FrameLimiter::new();
+    let mut fps = FpsCounter::new();
+    // Ctrl+F shows the frame rate, speed and frame number over the picture
+    let mut show_stats = false;
+    screen.set_title(&window_title(&rom_name, 0.0));
+    let mut zapper_aim = None;
+    let mut zapper_trigger = false;
+    // F1 presses reset on the next frame, which goes into the movie
+    let mut reset_pressed = false;
+    // an emulation error stops the game until it's reset, a state is loaded
+    // or another game is opened
+    let mut crashed = false;
+
+    // movies start at power on, the emulator was just created
+    let mut playback = match &args.play {
+        Some(path) => Some(load_movie(path, &rom)?),
+        None => None,
+    };
+    let mut playback_frame = 0;
+    let mut recording = args
+        .record
+        .as_ref()
+        .map(|_| Movie::new(Some(crc32(&rom)), None));
+
+    let mut monitor = if args.debug {
+        emulator.debugger();
+        // for `history` to show how a break was reached
+        emulator.attach_history(history::DEFAULT_CAPACITY);
+        Some(Monitor::new())
+    } else {
+        None
+    };
+    let mut enter_monitor = args.debug;
+    let mut debug_window: Option<DebugWindow> = None;
+
+    // both sides start from power on
+    let mut netplay = match (&args.listen, &args.netplay) {
+        (Some(port), _) => {
+            eprintln!("waiting for a player on port {}", port);
+            let settings = PowerOnSettings {
+                ram_init: args.ram_init,
+                accuracy: args.accuracy,
+            };
+            Some(Netplay::host(*port, &rom, DEFAULT_DELAY, settings)?)
+        }
+        (None, Some(addr)) => Some(Netplay::connect(addr.as_str(), &rom)?),
+        (None, None) => None,
+    };
+    if let Some(netplay) = &mut netplay {
+        // with the host's RAM init and accuracy, e.g. its random seed
+        netplay.power_on(&mut emulator);
+        netplay.set_rollback_window(args.rollback);
+        notify(
+            &mut emulator,
+            format!("Netplay started, you are player {}", netplay.player() + 1),
+        );
+    }
+
+    'main: loop {
         for event in event_pump.poll_iter() {
+            gamepads.handle_event(&event);
             match event {
                 Event::Quit { .. }
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
-                } => std::process::exit(0),
+                } => break 'main,
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    keymod,
+                    repeat: false,
+                    ..
+                } if state_slot(keycode).is_some()
+                    && keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) =>
+                {
+                    let slot = state_slot(keycode).unwrap();
+                    if let Some(slots) = &slots {
+                        match slots.save(slot, &emulator) {
+                            Ok(_) => notify(&mut emulator, format!("State saved to slot {}", slot)),
+                            Err(e) => notify(&mut emulator, format!("Failed to save state: {}", e)),
+                        }
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    keymod,
+                    repeat: false,
+                    ..
+                } if state_slot(keycode).is_some()
+                    && keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) =>
+                {
+                    let slot = state_slot(keycode).unwrap();
+                    match &slots {
+                        // the movie would no longer replay
+                        Some(_) if recording.is_some() => {
+                            notify(&mut emulator, "Can't load states while recording")
+                        }
+                        // the peer's emulator would not follow
+                        Some(_) if netplay.is_some() => {
+                            notify(&mut emulator, "Can't load states during netplay")
+                        }
+                        Some(slots) if slots.info(slot).is_none() => {
+                            notify(&mut emulator, format!("Slot {} is empty", slot))
+                        }
+                        Some(slots) => match slots.load(slot, &mut emulator) {
+                            Ok(info) => notify(
+                                &mut emulator,
+                                format!(
+                                    "State loaded from slot {}, frame {}",
+                                    slot, info.frame_number
+                                ),
+                            ),
+                            Err(e) => notify(&mut emulator, format!("Failed to load state: {}", e)),
+                        },
+                        None => {}
+                    }
+                }
                 Event::KeyDown {
                     keycode: Some(Keycode::D),
                     ..
-                } => ppu.print_debug_info(),
-                Event::KeyDown { keycode, .. } => {
-                    if let Some(btn) = key_map.get(&keycode.unwrap_or(Keycode::Escape)) {
-                        joypads[0].set(btn);
+                } => emulator.cpu().bus.ppu.print_debug_info(),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    ..
+                } => enter_monitor = monitor.is_some(),
+                Event::Window {
+                    win_event: WindowEvent::Close,
+                    window_id,
+                    ..
+                } => {
+                    if debug_window.as_ref().map(|w| w.window_id()) == Some(window_id) {
+                        match debug_window.take().unwrap().view {
+                            DebugView::Events => emulator.detach_event_log(),
+                            DebugView::Apu => emulator.detach_apu_log(),
+                            _ => {}
+                        }
+                    } else {
+                        break 'main;
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F9),
+                    repeat: false,
+                    ..
+                } => {
+                    debug_window = match debug_window {
+                        None => Some(DebugWindow::new(&video_subsystem)),
+                        Some(mut window) if window.view == DebugView::PatternTables => {
+                            window.view = DebugView::Sprites;
+                            Some(window)
+                        }
+                        Some(mut window) if window.view == DebugView::Sprites => {
+                            emulator.attach_event_log(event_log::DEFAULT_CAPACITY);
+                            window.view = DebugView::Events;
+                            Some(window)
+                        }
+                        Some(mut window) if window.view == DebugView::Events => {
+                            emulator.detach_event_log();
+                            emulator.attach_apu_log(apu_log::DEFAULT_CAPACITY);
+                            window.view = DebugView::Apu;
+                            Some(window)
+                        }
+                        Some(_) => {
+                            emulator.detach_apu_log();
+                            None
+                        }
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F10),
+                    ..
+                } => {
+                    if let Some(window) = &mut debug_window {
+                        window.next_palette();
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F11),
+                    repeat: false,
+                    ..
+                } => {
+                    if let Some(logger) = emulator.trace_logger() {
+                        logger.set_enabled(!logger.is_enabled());
+                        let state = if logger.is_enabled() { "on" } else { "off" };
+                        notify(&mut emulator, format!("Tracing {}", state));
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F6),
+                    repeat: false,
+                    ..
+                } => {
+                    screen.set_scale_mode(screen.scale_mode().next());
+                    notify(&mut emulator, format!("Scaling: {:?}", screen.scale_mode()));
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F8),
+                    repeat: false,
+                    ..
+                } => {
+                    ntsc = match ntsc {
+                        None => Some(NtscFilter::new()),
+                        Some(_) => None,
+                    };
+                    let video = if ntsc.is_some() { "NTSC" } else { "RGB" };
+                    notify(&mut emulator, format!("Video: {}", video));
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F4),
+                    repeat: false,
+                    ..
+                } => match video.take() {
+                    Some(recorder) => stop_video(recorder),
+                    None => {
+                        let dir = video_dir(&rom_path);
+                        match VideoRecorder::create(&dir, emulator.audio_sample_rate()) {
+                            Ok(recorder) => {
+                                eprintln!("recording video to {}", dir.display());
+                                emulator.osd_mut().show("Recording video");
+                                video = Some(recorder);
+                            }
+                            Err(e) => eprintln!("{}: {}", dir.display(), e),
+                        }
+                    }
+                },
+                // Ctrl+W starts and stops writing the audio to a WAV file
+                Event::KeyDown {
+                    keycode: Some(Keycode::W),
+                    keymod,
+                    repeat: false,
+                    ..
+                } if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) => {
+                    if emulator.audio_recording().is_some() {
+                        stop_audio_recording(&mut emulator);
+                    } else {
+                        let path = audio_path(&rom_path);
+                        match emulator.start_audio_recording(&path) {
+                            Ok(()) => {
+                                eprintln!("recording audio to {}", path.display());
+                                emulator.osd_mut().show("Recording audio");
+                            }
+                            Err(e) => eprintln!("{}: {}", path.display(), e),
+                        }
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F1),
+                    repeat: false,
+                    ..
+                } => {
+                    if playback.is_some() {
+                        notify(&mut emulator, "Can't reset while playing a movie");
+                    } else {
+                        reset_pressed = true;
+                        crashed = false;
+                        notify(&mut emulator, "Reset");
                     }
                 }
-                Event::KeyUp { keycode, .. } => {
-                    if let Some(btn) = key_map.get(&keycode.unwrap_or(Keycode::Escape)) {
-                        joypads[0].unset(btn);
+                // F2 turns the console off and on
+                Event::KeyDown {
+                    keycode: Some(Keycode::F2),
+                    repeat: false,
+                    ..
+                } => {
+                    if recording.is_some() || playback.is_some() {
+                        notify(&mut emulator, "Can't power cycle with a movie");
+                    } else if netplay.is_some() {
+                        notify(&mut emulator, "Can't power cycle during netplay");
+                    } else {
+                        emulator.power_cycle();
+                        crashed = false;
+                        notify(&mut emulator, "Power cycled");
+                    }
+                }
+                // F5 saves and F7 loads a state
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    ..
+                } => {
+                    saved_state = Some(emulator.save_state());
+                    notify(&mut emulator, "State saved");
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F7),
+                    ..
+                } => match &saved_state {
+                    // the movie would no longer replay
+                    Some(_) if recording.is_some() => {
+                        notify(&mut emulator, "Can't load states while recording")
+                    }
+                    Some(_) if netplay.is_some() => {
+                        notify(&mut emulator, "Can't load states during netplay")
+                    }
+                    Some(state) => match emulator.load_state(state) {
+                        Ok(()) => {
+                            crashed = false;
+                            notify(&mut emulator, "State loaded");
+                        }
+                        Err(e) => notify(&mut emulator, format!("Failed to load state: {}", e)),
+                    },
+                    None => notify(&mut emulator, "No saved state"),
+                },
+                Event::MouseMotion { x, y, .. } => zapper_aim = screen.to_nes_coords(x, y),
+                Event::Window {
+                    win_event: WindowEvent::Leave,
+                    ..
+                } => zapper_aim = None,
+                Event::MouseButtonDown {
+                    mouse_btn: MouseButton::Left,
+                    ..
+                } => zapper_trigger = true,
+                Event::MouseButtonUp {
+                    mouse_btn: MouseButton::Left,
+                    ..
+                } => zapper_trigger = false,
+                Event::KeyDown {
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => fast_forward = true,
+                Event::KeyUp {
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => fast_forward = false,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F3),
+                    repeat: false,
+                    ..
+                } => slow_motion = !slow_motion,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F),
+                    keymod,
+                    repeat: false,
+                    ..
+                } if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) => show_stats = !show_stats,
+                // Alt+Enter toggles fullscreen
+                Event::KeyDown {
+                    keycode: Some(Keycode::Return),
+                    keymod,
+                    repeat: false,
+                    ..
+                } if keymod.intersects(Mod::LALTMOD | Mod::RALTMOD) => {
+                    let fullscreen = !screen.is_fullscreen();
+                    if let Err(e) = screen.set_fullscreen(fullscreen) {
+                        eprintln!("failed to switch fullscreen: {}", e);
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    keymod,
+                    repeat: false,
+                    ..
+                } if audio_channel(keycode, keymod).is_some() => {
+                    let channel = audio_channel(keycode, keymod).unwrap();
+                    let solo = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                    toggle_channel(&mut emulator, channel, solo);
+                }
+                Event::DropFile { filename, .. } => pending_rom = Some(PathBuf::from(filename)),
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    keymod,
+                    repeat: false,
+                    ..
+                } if recent_rom_index(keycode, keymod).is_some() => {
+                    let index = recent_rom_index(keycode, keymod).unwrap();
+                    match recent_roms.as_ref().and_then(|r| r.paths().get(index)) {
+                        Some(path) => pending_rom = Some(path.clone()),
+                        None => eprintln!("no recent ROM {}", index),
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    ..
+                } => {
+                    if let Some(&(player, btn)) = key_map.get(&keycode) {
+                        buttons[player].insert(btn);
+                    }
+                }
+                Event::KeyUp {
+                    keycode: Some(keycode),
+                    ..
+                } => {
+                    if let Some(&(player, btn)) = key_map.get(&keycode) {
+                        buttons[player].remove(btn);
                     }
                 }
                 _ => {}
             }
         }
-    });
-    let mut cpu = CPU::new_with_nes_clock_rate(bus);
-    cpu.reset();
-    cpu.run();
+        if netplay.is_some() && pending_rom.take().is_some() {
+            notify(&mut emulator, "Can't change ROMs during netplay");
+        }
+        if let Some(path) = pending_rom.take() {
+            match open_rom(&path, &args, &romdb) {
+                Ok((rom, mut new_emulator)) => {
+                    // movies, states and recordings belong to the old ROM
+                    if let (Some(movie_path), Some(movie)) = (&args.record, recording.take()) {
+                        save_movie(movie_path, &movie, &rom_path)?;
+                        eprintln!(
+                            "recorded {} frames to {}",
+                            movie.len(),
+                            movie_path.display()
+                        );
+                    }
+                    playback = None;
+                    saved_state = None;
+                    slots = StateSlots::for_rom(&rom);
+                    if let Some(recorder) = video.take() {
+                        stop_video(recorder);
+                    }
+                    stop_audio_recording(&mut emulator);
+                    if let Some(cdl_path) = &args.cdl {
+                        save_code_data_log(cdl_path, &mut emulator)?;
+                    }
+                    if let Some(profile_path) = &args.profile {
+                        save_profile(profile_path, &mut emulator)?;
+                    }
+                    if let Some(logger) = emulator.stop_trace() {
+                        new_emulator.start_trace(logger);
+                    }
+                    if monitor.is_some() {
+                        new_emulator.debugger();
+                    }
+                    if emulator.event_log().is_some() {
+                        new_emulator.attach_event_log(event_log::DEFAULT_CAPACITY);
+                    }
+                    if emulator.apu_log().is_some() {
+                        new_emulator.attach_apu_log(apu_log::DEFAULT_CAPACITY);
+                    }
+                    if let Some(history) = emulator.history() {
+                        new_emulator.attach_history(history.capacity());
+                    }
+                    for channel in Channel::ALL {
+                        new_emulator.set_muted(channel, emulator.is_muted(channel));
+                    }
+                    new_emulator.set_solo(emulator.solo());
+                    // the script starts over with the new game
+                    #[cfg(feature = "scripting")]
+                    if let Some(script_path) = &args.script {
+                        script = Script::load(script_path, &mut new_emulator)
+                            .map_err(|e| eprintln!("{}", e))
+                            .ok();
+                    }
+                    emulator = new_emulator;
+                    crashed = false;
+                    rom_path = path;
+                    if let Some(recent_roms) = &mut recent_roms {
+                        recent_roms.add(&rom_path);
+                        if let Err(e) = recent_roms.save() {
+                            eprintln!("failed to save the recent ROMs: {}", e);
+                        }
+                    }
+                    rom_name = game_name(&rom_path, &emulator);
+                    eprintln!("loaded {}", rom_path.display());
+                    emulator.osd_mut().show(format!("Loaded {}", rom_name));
+                    screen.set_title(&window_title(&rom_name, fps.fps));
+                }
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        let held = [
+            buttons[0] | gamepads.buttons(0),
+            buttons[1] | gamepads.buttons(1),
+        ];
+        emulator.set_zapper(zapper_aim, zapper_trigger);
+        // the peer runs at normal speed
+        emulator.set_speed(if netplay.is_some() {
+            1.0
+        } else if fast_forward {
+            FAST_FORWARD_SPEED
+        } else if slow_motion {
+            SLOW_MOTION_SPEED
+        } else {
+            1.0
+        });
 
+        // Fast-forward runs several frames per presented one, so it also
+        // works when vsync holds presenting to the display refresh rate
+        let speed = emulator.speed();
+        let frames = if crashed {
+            0
+        } else if speed > 1.0 {
+            speed.round() as u32
+        } else {
+            1
+        };
+        let mut crash = None;
+        'frames: for _ in 0..frames {
+            // rollback runs frames again, it drives the emulator
+            if let Some(session) = netplay.as_mut().filter(|s| s.rollback_window() > 0) {
+                let reset = std::mem::take(&mut reset_pressed);
+                let result = session.advance(&mut emulator, held[0], reset);
+                if !netplay_ok(result, &mut emulator) {
+                    netplay = None;
+                }
+            } else {
+                let input = match (&playback, &mut netplay) {
+                    (Some(movie), _) if playback_frame < movie.len() => {
+                        playback_frame += 1;
+                        movie.frames[playback_frame - 1]
+                    }
+                    (_, Some(session)) => {
+                        match session.exchange(held[0], std::mem::take(&mut reset_pressed)) {
+                            Ok(input) => input,
+                            Err(e) => {
+                                eprintln!("{}", e);
+                                notify(&mut emulator, "Netplay stopped");
+                                netplay = None;
+                                break;
+                            }
+                        }
+                    }
+                    _ => MovieFrame {
+                        buttons: held,
+                        reset: std::mem::take(&mut reset_pressed),
+                    },
+                };
+                if playback
+                    .as_ref()
+                    .is_some_and(|movie| playback_frame == movie.len())
+                {
+                    notify(
+                        &mut emulator,
+                        format!("Movie finished after {} frames", playback_frame),
+                    );
+                    playback = None;
+                }
+                input.apply(&mut emulator);
+                if let Some(movie) = &mut recording {
+                    movie.record(input);
+                }
+                if let (true, Some(monitor)) = (enter_monitor, &mut monitor) {
+                    enter_monitor = false;
+                    if !run_monitor(monitor, &mut emulator)? {
+                        break 'main;
+                    }
+                }
+                #[cfg(feature = "scripting")]
+                run_script(&mut script, &mut emulator, |script, emulator| {
+                    script.frame_start(emulator)
+                });
+                if let Err(e) = emulator.run_frame() {
+                    crash = Some(e);
+                    break 'frames;
+                }
+                // the debugger stops in the middle of the frame
+                while let Some(reason) = emulator.take_break() {
+                    // for the script's memory hooks
+                    #[cfg(feature = "scripting")]
+                    if run_script(&mut script, &mut emulator, |script, emulator| {
+                        script.handle_break(&reason, emulator)
+                    }) == Some(true)
+                    {
+                        if let Err(e) = emulator.run_frame() {
+                            crash = Some(e);
+                            break 'frames;
+                        }
+                        continue;
+                    }
+                    println!("{}", reason);
+                    if let Some(monitor) = &mut monitor {
+                        if !run_monitor(monitor, &mut emulator)? {
+                            break 'main;
+                        }
+                    }
+                    if let Err(e) = emulator.run_frame() {
+                        crash = Some(e);
+                        break 'frames;
+                    }
+                }
+                #[cfg(feature = "scripting")]
+                run_script(&mut script, &mut emulator, |script, emulator| {
+                    script.frame_end(emulator)
+                });
+                if let Some(session) = &mut netplay {
+                    let result = session.frame_done(emulator.state_hash());
+                    if !netplay_ok(result, &mut emulator) {
+                        netplay = None;
+                    }
+                }
+            }
+            // the audio queue drops what it can't keep up with
+            let samples = emulator.audio_samples();
+            audio.queue_samples(&samples)?;
+            // recordings stay at the exact rate, in sync with the video
+            let adjustment = if video.is_some() || emulator.audio_recording().is_some() {
+                1.0
+            } else {
+                audio.rate_adjustment()
+            };
+            emulator.set_audio_rate_adjustment(adjustment);
+            if let Some(recorder) = &mut video {
+                if let Err(e) = recorder.add_frame(emulator.frame(), &samples) {
+                    eprintln!("failed to write the video: {}", e);
+                    video = None;
+                }
+            }
+        }
+        if let Some(e) = crash {
+            eprint!("{}", e.report());
+            notify(&mut emulator, e.to_string());
+            emulator.osd_mut().show("Press F1 to reset");
+            crashed = true;
+            if let Some(monitor) = &mut monitor {
+                if !run_monitor(monitor, &mut emulator)? {
+                    break 'main;
+                }
+            }
+        }
+        let mut overlay = vec![];
+        if show_stats {
+            overlay.push(format!("FPS {:.1}", fps.fps));
+            overlay.push(format!("SPEED {:.0}%", fps.fps / FRAME_RATE * 100.0));
+            overlay.push(format!("FRAME {}", emulator.frame_number()));
+            if let Some(session) = netplay.as_ref().filter(|s| s.rollback_window() > 0) {
+                overlay.push(format!("ROLLBACK {}", session.rolled_back_frames()));
+            }
+        }
+        #[cfg(feature = "scripting")]
+        let drawings = script
+            .as_ref()
+            .filter(|script| script.has_drawings())
+            .map(|script| move |frame: &mut NesFrame| script.draw(frame));
+        #[cfg(not(feature = "scripting"))]
+        let drawings = None::<fn(&mut NesFrame)>;
+        draw_screen(
+            &mut screen,
+            &mut ntsc,
+            &emulator,
+            &overlay,
+            drawings.as_ref().map(|draw| draw as &dyn Fn(&mut NesFrame)),
+        );
+        if let Some(window) = &mut debug_window {
+            window.draw(&emulator);
+        }
+        if fps.add_frames(frames) {
+            screen.set_title(&window_title(&rom_name, fps.fps));
+        }
+
+        // vsync already paces frames at normal speed, the display refresh
+        // is close enough to 60.0988 Hz
+        if !vsync || speed < 1.0 {
+            // a crashed game waits too
+            limiter.wait(emulator.frame_duration() * frames.max(1));
+        }
+    }
+
+    if let Some(file) = &settings_file {
+        // fullscreen keeps the size of the window it was switched from
+        if !screen.is_fullscreen() {
+            settings.window_size = Some(screen.window_size());
+        }
+        if let Err(e) = settings.save(file) {
+            eprintln!("failed to save the settings: {}", e);
+        }
+    }
+    if let (Some(path), Some(movie)) = (&args.record, &recording) {
+        save_movie(path, movie, &rom_path)?;
+        eprintln!("recorded {} frames to {}", movie.len(), path.display());
+    }
+    if let Some(recorder) = video {
+        stop_video(recorder);
+    }
+    stop_audio_recording(&mut emulator);
+    if let Some(path) = &args.cdl {
+        save_code_data_log(path, &mut emulator)?;
+    }
+    if let Some(path) = &args.profile {
+        save_profile(path, &mut emulator)?;
+    }
+    if let Some(mut logger) = emulator.stop_trace() {
+        if let Some(e) = logger.take_error().or_else(|| logger.flush().err()) {
+            eprintln!("failed to write the trace: {}", e);
+        }
+    }
     Ok(())
 }
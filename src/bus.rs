@@ -1,6 +1,20 @@
+use std::cell::RefCell;
+use std::ops::ControlFlow;
+use std::rc::Rc;
+
+use crate::apu::mixer::Mixer;
+use crate::apu::APU;
+use crate::apu_log::ApuLog;
+use crate::audio::{AudioSampler, RingBuffer};
 use crate::cartridge::Cartridge;
+use crate::cdl;
+use crate::cheats::{Cheats, Freezes};
+use crate::clock::{Clock, PPU_TICKS_PER_CPU_CYCLE};
+use crate::event_log::{EventKind, EventLog};
 use crate::joypad::Joypad;
 use crate::ppu::PPU;
+use crate::savestate::{SaveState, StateReader, StateWriter};
+use crate::zapper::Zapper;
 
 /*
   _______________ $10000  _______________
@@ -35,14 +49,101 @@ use crate::ppu::PPU;
 #[allow(dead_code)]
 const CPU_RAM_SIZE: usize = 2048;
 
+// The bits of $4016 and $4017 the controller ports drive: bit 0 for the
+// joypads, bits 3 and 4 for the Zapper and other accessories. The rest is
+// open bus.
+const CONTROLLER_PORT_BITS: u8 = 0x1F;
+
+// What the internal RAM holds at power on. On a real console it's whatever
+// the chips come up with, often stripes of $00 and $FF, and some games read
+// it before writing (e.g. to seed random numbers).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RamInit {
+    #[default]
+    AllZero,
+    AllFF,
+    // 4 bytes of $00, 4 of $FF and so on, like FCEUX
+    Striped,
+    // the same bytes for the same seed
+    Random(u32),
+}
+
+impl RamInit {
+    pub fn fill(&self, ram: &mut [u8]) {
+        match *self {
+            RamInit::AllZero => ram.fill(0),
+            RamInit::AllFF => ram.fill(0xFF),
+            RamInit::Striped => {
+                for (i, byte) in ram.iter_mut().enumerate() {
+                    *byte = if i & 4 == 0 { 0x00 } else { 0xFF };
+                }
+            }
+            RamInit::Random(seed) => {
+                // xorshift never leaves 0
+                let mut state = seed.max(1);
+                for byte in ram.iter_mut() {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    *byte = state as u8;
+                }
+            }
+        }
+    }
+}
+
+// The CPU is clocked on every third system tick, unless DMA halts it.
+// See `clock` for the ratios.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CpuCycle {
+    // not a CPU cycle
+    None,
+    // the CPU executes the cycle
+    Run,
+    // the cycle passes while OAM DMA or a DMC fetch holds the bus
+    Stalled,
+}
+
 #[allow(dead_code)]
+// Deserialize is implemented by hand below, the cartridge is shared with the
+// PPU and the callback can't be restored
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Bus<'call> {
+    #[cfg_attr(feature = "serde", serde(with = "crate::savestate::byte_array"))]
     pub cpu_ram: [u8; CPU_RAM_SIZE],
-    pub cart: Cartridge,
+    // applied at power on, see `set_ram_init`
+    ram_init: RamInit,
+    // shared with the PPU, which reads CHR through the mapper
+    pub cart: Rc<RefCell<Cartridge>>,
     pub ppu: PPU,
+    pub apu: APU,
+    // channel volumes
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub mixer: Mixer,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub audio: AudioSampler,
     pub joypads: [Joypad; 2],
+    // a Zapper in the second port replaces the joypad there
+    pub zapper: Option<Zapper>,
+    // patches applied to CPU reads
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub cheats: Cheats,
+    // RAM locked against CPU writes, see `apply_freezes`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub freezes: Freezes,
+    // records PPU register accesses and interrupts for debugging
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub event_log: Option<EventLog>,
+    // records APU register writes for debugging audio
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub apu_log: Option<ApuLog>,
 
-    pub total_system_cycles: u32,
+    // master clock, drives the PPU, CPU and APU at their ratios
+    pub clock: Clock,
+    // The last value on the CPU data bus. Reads of addresses nothing
+    // responds to return it, as do the bits registers don't drive.
+    open_bus: u8,
 
     // DMA
     pub dma_page: u8,
@@ -55,90 +156,198 @@ pub struct Bus<'call> {
     pub dma_dummy: bool,
     // Flag to indicate that a DMA transfer is happening
     pub dma_transfer: bool,
+    // Number of CPU cycles the CPU is still halted for while the DMC
+    // channel fetches a sample byte
+    pub dmc_stall_cycles: u8,
 
-    gameloop_callback: Box<dyn FnMut(&PPU, &mut [Joypad; 2]) + 'call>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    gameloop_callback:
+        Box<dyn FnMut(&PPU, &mut [Joypad; 2], &mut RingBuffer) -> ControlFlow<()> + 'call>,
 }
 
 impl Bus<'_> {
     pub fn new<'call>(cart: Cartridge) -> Bus<'call> {
-        Bus::new_with_gameloop_callback(cart, move |_ppu: &PPU, _joypads: &mut [Joypad; 2]| {})
+        Bus::new_with_gameloop_callback(
+            cart,
+            move |_ppu: &PPU, _joypads: &mut [Joypad; 2], _audio: &mut RingBuffer| {
+                ControlFlow::Continue(())
+            },
+        )
     }
 
     pub fn new_with_gameloop_callback<'call, F>(cart: Cartridge, callback: F) -> Bus<'call>
     where
-        F: FnMut(&PPU, &mut [Joypad; 2]) + 'call,
+        F: FnMut(&PPU, &mut [Joypad; 2], &mut RingBuffer) -> ControlFlow<()> + 'call,
     {
-        let ppu = PPU::new(&cart);
+        let cart = Rc::new(RefCell::new(cart));
+        let ppu = PPU::new(cart.clone());
         Bus {
             cpu_ram: [0; CPU_RAM_SIZE],
+            ram_init: RamInit::default(),
             cart: cart,
             ppu: ppu,
+            apu: APU::new(),
+            mixer: Mixer::new(),
+            audio: AudioSampler::new(),
             joypads: [Joypad::new(), Joypad::new()],
-            total_system_cycles: 0,
+            zapper: None,
+            cheats: Cheats::new(),
+            freezes: Freezes::new(),
+            event_log: None,
+            apu_log: None,
+            clock: Clock::new(),
+            open_bus: 0,
             dma_page: 0,
             dma_addr: 0,
             dma_data: 0,
             dma_dummy: true,
             dma_transfer: false,
+            dmc_stall_cycles: 0,
             gameloop_callback: Box::from(callback),
         }
     }
 
-    // Execute a system tick and return true if CPU should tick
-    pub fn system_tick(&mut self) -> bool {
-        // The CPU runs 3 times slower than the PPU
-        if self.total_system_cycles % 3 == 0 {
-            // Is the system performing a DMA transfer form CPU memory to
-            // OAM memory on PPU?...
-            if self.dma_transfer {
-                // ...Yes! We need to wait until the next even CPU clock cycle
-                // before it starts...
-                if self.dma_dummy {
-                    // ...So hang around in here each clock until 1 or 2 cycles
-                    // have elapsed...
-                    if self.total_system_cycles % 2 == 1 {
-                        // ...and finally allow DMA to start
-                        self.dma_dummy = false;
-                    }
-                } else {
-                    // DMA can take place!
-                    if self.total_system_cycles % 2 == 0 {
-                        // On even clock cycles, read from CPU bus
-                        self.dma_data =
-                            self.cpu_read(((self.dma_page as u16) << 8) | self.dma_addr as u16);
-                    } else {
-                        // On odd clock cycles, write to PPU OAM
-                        self.ppu.oam_data[self.dma_addr as usize] = self.dma_data;
-                        // Increment the lo byte of the address
-                        self.dma_addr = self.dma_addr.wrapping_add(1);
-                        // If this wraps around, we know that 256
-                        // bytes have been written, so end the DMA
-                        // transfer, and proceed as normal
-                        if self.dma_addr == 0x00 {
-                            self.dma_transfer = false;
-                            self.dma_dummy = true;
-                        }
-                    }
-                }
-                self.total_system_cycles = self.total_system_cycles.wrapping_add(1);
-                return false;
-            } else {
-                // No DMA happening, the CPU can tick
-                self.total_system_cycles = self.total_system_cycles.wrapping_add(1);
-                return true;
+    // Advance the master clock by one PPU dot, clocking the PPU and, every
+    // third tick, the APU and the cartridge. Returns what the CPU does
+    // during the tick.
+    pub fn system_tick(&mut self) -> CpuCycle {
+        let sprite_zero_hit = self.ppu.has_sprite_zero_hit();
+        self.ppu.tick();
+        if let Some(log) = &mut self.event_log {
+            if self.ppu.scanline() == 0 && self.ppu.dot() == 0 {
+                log.next_frame();
+            }
+            if !sprite_zero_hit && self.ppu.has_sprite_zero_hit() {
+                log.record(
+                    self.ppu.scanline(),
+                    self.ppu.dot(),
+                    EventKind::SpriteZeroHit,
+                );
+            }
+        }
+
+        if let (Some(log), 0, 0) = (&mut self.apu_log, self.ppu.scanline(), self.ppu.dot()) {
+            log.next_frame();
+        }
+
+        let tick = self.clock.tick();
+        if !tick.cpu {
+            return CpuCycle::None;
+        }
+
+        // The APU and the mapper are clocked on every CPU cycle, even when
+        // the CPU is halted
+        self.apu_tick();
+        self.cart.borrow_mut().cpu_clock();
+
+        // Is the DMC channel fetching a sample? The CPU is halted meanwhile
+        if self.dmc_stall_cycles > 0 {
+            self.dmc_stall_cycles -= 1;
+            return CpuCycle::Stalled;
+        }
+
+        // Is the system performing a DMA transfer form CPU memory to
+        // OAM memory on PPU?...
+        if !self.dma_transfer {
+            // No DMA happening, the CPU can tick
+            return CpuCycle::Run;
+        }
+
+        // ...Yes! We need to wait until the next even CPU clock cycle
+        // before it starts...
+        if self.dma_dummy {
+            // ...So hang around in here each clock until 1 or 2 cycles
+            // have elapsed...
+            if !tick.even_cpu_cycle {
+                // ...and finally allow DMA to start
+                self.dma_dummy = false;
             }
+        } else if tick.even_cpu_cycle {
+            // DMA can take place! On even clock cycles, read from CPU bus
+            self.dma_data = self.cpu_read(((self.dma_page as u16) << 8) | self.dma_addr as u16);
         } else {
-            self.total_system_cycles = self.total_system_cycles.wrapping_add(1);
-            return false;
+            // On odd clock cycles, write to PPU OAM through OAMDATA,
+            // starting at OAMADDR
+            self.ppu.write_oam_data(self.dma_data);
+            // Increment the lo byte of the address
+            self.dma_addr = self.dma_addr.wrapping_add(1);
+            // If this wraps around, we know that 256 bytes have been
+            // written, so end the DMA transfer, and proceed as normal
+            if self.dma_addr == 0x00 {
+                self.dma_transfer = false;
+                self.dma_dummy = true;
+            }
+        }
+        CpuCycle::Stalled
+    }
+
+    fn apu_tick(&mut self) {
+        self.apu.tick();
+        let output = self
+            .mixer
+            .mix(&self.apu, self.cart.borrow().expansion_audio());
+        self.audio.push(output);
+
+        // The DMC memory reader fetches sample bytes through the CPU bus,
+        // which halts the CPU for (up to) 4 cycles
+        if let Some(addr) = self.apu.dmc.pending_fetch_addr() {
+            let value = self.cpu_read(addr);
+            self.cart.borrow_mut().log_prg(addr, cdl::PCM);
+            self.apu.dmc.fill_sample_buffer(value);
+            self.dmc_stall_cycles = 4;
+        }
+    }
+
+    pub fn run_gameloop_callback(&mut self) -> ControlFlow<()> {
+        (self.gameloop_callback)(&self.ppu, &mut self.joypads, &mut self.audio.buffer)
+    }
+
+    // Scanline and dot the PPU is at when the current CPU cycle ends. The CPU
+    // runs on the first of its three dots, so between instructions the PPU
+    // is up to two dots behind. Traces report this position, like the
+    // nestest log does.
+    pub fn ppu_position(&self) -> (u32, u32) {
+        let ticks = self.clock.ticks() % PPU_TICKS_PER_CPU_CYCLE;
+        let pending = ((PPU_TICKS_PER_CPU_CYCLE - ticks) % PPU_TICKS_PER_CPU_CYCLE) as u32;
+        let (mut scanline, mut dot) = (self.ppu.scanline(), self.ppu.dot() + pending);
+        if dot >= 341 {
+            dot -= 341;
+            scanline = (scanline + 1) % 262;
+        }
+        (scanline, dot)
+    }
+
+    // Read memory for debugging tools. I/O registers and unmapped
+    // addresses read as FF like in Nintendulator's logs, reading registers
+    // would have side effects.
+    pub fn peek(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x1FFF => self.cpu_ram[(addr & 0x07FF) as usize],
+            0x2000..=0x401F => 0xFF,
+            _ => self.cart.borrow_mut().cpu_read(addr).unwrap_or(0xFF),
         }
     }
 
-    pub fn run_gameloop_callback(&mut self) {
-        (self.gameloop_callback)(&self.ppu, &mut self.joypads);
+    pub fn open_bus(&self) -> u8 {
+        self.open_bus
     }
 
     pub fn cpu_read(&mut self, addr: u16) -> u8 {
-        let v = self.cart.cpu_read(addr);
+        let value = self.cpu_read_unpatched(addr);
+        let value = if self.cheats.is_empty() {
+            value
+        } else {
+            self.cheats.patch(addr, value)
+        };
+        // $4015 is inside the CPU, reading it doesn't reach the data bus
+        if addr != 0x4015 {
+            self.open_bus = value;
+        }
+        value
+    }
+
+    fn cpu_read_unpatched(&mut self, addr: u16) -> u8 {
+        let v = self.cart.borrow_mut().cpu_read(addr);
         if v.is_some() {
             return v.unwrap();
         }
@@ -146,54 +355,287 @@ impl Bus<'_> {
         match addr {
             0x0000..=0x1FFF => self.cpu_ram[(addr & 0b0000_0111_1111_1111) as usize],
             // PPU registers mapping
-            0x2000..=0x3FFF => self.ppu.cpu_read(addr),
-            // TODO APU
-            0x4000..=0x4015 => 0,
-            // controller register
-            0x4016 => self.joypads[0].read(),
-            // ignore 2nd joypad
-            0x4017 => 0,
-            _ => 0,
+            0x2000..=0x3FFF => {
+                let value = self.ppu.cpu_read(addr);
+                let addr = 0x2000 | (addr & 0x7);
+                self.log_event(EventKind::PpuRead { addr, value });
+                value
+            }
+            // APU status, bit 5 isn't driven
+            0x4015 => self.apu.cpu_read(addr) | (self.open_bus & 0x20),
+            0x4016 => {
+                let value = self.joypads[0].read();
+                (value & CONTROLLER_PORT_BITS) | (self.open_bus & !CONTROLLER_PORT_BITS)
+            }
+            0x4017 => {
+                let value = match &self.zapper {
+                    Some(zapper) => zapper.read(self.ppu.frame(), self.ppu.scanline()),
+                    None => self.joypads[1].read(),
+                };
+                (value & CONTROLLER_PORT_BITS) | (self.open_bus & !CONTROLLER_PORT_BITS)
+            }
+            // write-only APU registers, the test mode registers and
+            // whatever the cartridge doesn't map
+            _ => self.open_bus,
         }
     }
 
     pub fn cpu_write(&mut self, addr: u16, value: u8) {
-        let ok = self.cart.cpu_write(addr, value);
+        self.open_bus = value;
+        if !self.freezes.is_empty() && self.freezes.is_frozen(addr) {
+            return;
+        }
+        let ok = self.cart.borrow_mut().cpu_write(addr, value);
         if ok {
+            // PRG RAM writes aren't interesting
+            if !(0x6000..=0x7FFF).contains(&addr) {
+                self.log_event(EventKind::MapperWrite { addr, value });
+            }
+            // the write may have changed the mapper's mirroring
+            self.ppu.set_mirroring(self.cart.borrow().mirroring());
             return;
         }
 
         match addr {
             0x0000..=0x1FFF => self.cpu_ram[(addr & 0b0000_0111_1111_1111) as usize] = value,
-            0x2000..=0x3FFF => self.ppu.cpu_write(addr, value),
+            0x2000..=0x3FFF => {
+                self.ppu.cpu_write(addr, value);
+                let addr = 0x2000 | (addr & 0x7);
+                self.log_event(EventKind::PpuWrite { addr, value });
+            }
             0x4014 => {
+                self.log_event(EventKind::OamDma { page: value });
                 // A write to this address initiates a DMA transfer
                 self.dma_page = value;
                 self.dma_addr = 0x00;
                 self.dma_transfer = true;
             }
-            // TODO APU
-            0x4000..=0x4013 | 0x4015 => (),
-            // controller register
-            0x4016 => self.joypads[0].write(value),
-            // ignore 2nd joypad
-            0x4017 => (),
+            // APU registers
+            0x4000..=0x4013 | 0x4015 => {
+                self.log_apu_write(addr, value);
+                self.apu.cpu_write(addr, value);
+            }
+            // controller strobe, shared by both ports
+            0x4016 => {
+                self.joypads[0].write(value);
+                self.joypads[1].write(value);
+            }
+            // APU frame counter (shares the address with the 2nd joypad)
+            0x4017 => {
+                self.log_apu_write(addr, value);
+                self.apu.cpu_write(addr, value);
+            }
             _ => (),
         }
     }
 
+    // Set the frozen addresses that have a value to it. Called at the end
+    // of every frame.
+    pub fn apply_freezes(&mut self) {
+        for freeze in self.freezes.iter() {
+            match (freeze.addr, freeze.value) {
+                (_, None) => {}
+                (0x0000..=0x07FF, Some(value)) => self.cpu_ram[freeze.addr as usize] = value,
+                // straight into the PRG RAM, a CPU write could hit mapper
+                // registers
+                (addr, Some(value)) => {
+                    let mut cart = self.cart.borrow_mut();
+                    let byte = cart
+                        .mapper
+                        .prg_ram_mut()
+                        .and_then(|ram| ram.get_mut((addr - 0x6000) as usize));
+                    if let Some(byte) = byte {
+                        *byte = value;
+                    }
+                }
+            }
+        }
+    }
+
     pub fn has_nmi(&self) -> bool {
         self.ppu.has_nmi()
     }
 
+    // Whether the PPU finished a frame since the last call. Frames end with
+    // the pre-render line, at that point the whole picture has been drawn
+    // and the NMI handler has run.
+    pub fn take_completed_frame(&mut self) -> bool {
+        self.ppu.take_frame_complete()
+    }
+
+    // Record an event at the current PPU position, if the log is attached
+    pub(crate) fn log_event(&mut self, kind: EventKind) {
+        if let Some(log) = &mut self.event_log {
+            log.record(self.ppu.scanline(), self.ppu.dot(), kind);
+        }
+    }
+
+    fn log_apu_write(&mut self, addr: u16, value: u8) {
+        if let Some(log) = &mut self.apu_log {
+            let cycle = (self.ppu.scanline() * 341 + self.ppu.dot()) / 3;
+            log.record(cycle, addr, value);
+        }
+    }
+
     pub fn reset_nmi(&mut self) {
         self.ppu.reset_nmi();
     }
+
+    // How RAM comes up at power on. RAM is filled right away, set it before
+    // running the console.
+    pub fn set_ram_init(&mut self, init: RamInit) {
+        self.ram_init = init;
+        init.fill(&mut self.cpu_ram);
+    }
+
+    pub fn ram_init(&self) -> RamInit {
+        self.ram_init
+    }
+
+    // The reset button, the CPU side is `CPU::reset`. RAM and the cartridge
+    // are left alone.
+    pub fn reset(&mut self) {
+        self.ppu.reset();
+        self.apu.reset();
+        self.dma_transfer = false;
+        self.dma_dummy = true;
+        self.dmc_stall_cycles = 0;
+    }
+
+    // Turn the console off and on. Everything but the host side (audio
+    // output and mixer, cheats, freezes, the controllers plugged in and the
+    // event log) starts over.
+    pub fn power_cycle(&mut self) {
+        self.ram_init.fill(&mut self.cpu_ram);
+        self.cart.borrow_mut().power_on();
+        self.ppu.power_on();
+        self.ppu.set_mirroring(self.cart.borrow().mirroring());
+        self.apu = APU::new();
+        for joypad in self.joypads.iter_mut() {
+            joypad.power_on();
+        }
+        self.clock = Clock::new();
+        self.open_bus = 0;
+        self.dma_page = 0;
+        self.dma_addr = 0;
+        self.dma_data = 0;
+        self.dma_transfer = false;
+        self.dma_dummy = true;
+        self.dmc_stall_cycles = 0;
+    }
+
+    // IRQ line is asserted by the APU (frame counter, DMC) or the mapper
+    pub fn has_irq(&self) -> bool {
+        self.apu.has_irq() || self.cart.borrow().has_irq()
+    }
+
+    // Clear all pending IRQ sources
+    pub fn acknowledge_irq(&mut self) {
+        self.apu.acknowledge_irq();
+        self.cart.borrow_mut().acknowledge_irq();
+    }
+}
+
+// The mixer, the audio sampler, the gameloop callback, cheats, freezes and the event log
+// are host side and not saved
+impl<'call> Bus<'call> {
+    pub fn set_gameloop_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(&PPU, &mut [Joypad; 2], &mut RingBuffer) -> ControlFlow<()> + 'call,
+    {
+        self.gameloop_callback = Box::from(callback);
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Bus<'_> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // what Serialize writes
+        #[derive(serde::Deserialize)]
+        struct State {
+            #[serde(with = "crate::savestate::byte_array")]
+            cpu_ram: [u8; CPU_RAM_SIZE],
+            ram_init: RamInit,
+            cart: Cartridge,
+            ppu: PPU,
+            apu: APU,
+            joypads: [Joypad; 2],
+            zapper: Option<Zapper>,
+            clock: Clock,
+            open_bus: u8,
+            dma_page: u8,
+            dma_addr: u8,
+            dma_data: u8,
+            dma_dummy: bool,
+            dma_transfer: bool,
+            dmc_stall_cycles: u8,
+        }
+
+        let state = State::deserialize(deserializer)?;
+        let mut bus = Bus::new(state.cart);
+        bus.cpu_ram = state.cpu_ram;
+        bus.ram_init = state.ram_init;
+        bus.ppu = state.ppu;
+        bus.ppu.set_cartridge(bus.cart.clone());
+        bus.apu = state.apu;
+        bus.joypads = state.joypads;
+        bus.zapper = state.zapper;
+        bus.clock = state.clock;
+        bus.open_bus = state.open_bus;
+        bus.dma_page = state.dma_page;
+        bus.dma_addr = state.dma_addr;
+        bus.dma_data = state.dma_data;
+        bus.dma_dummy = state.dma_dummy;
+        bus.dma_transfer = state.dma_transfer;
+        bus.dmc_stall_cycles = state.dmc_stall_cycles;
+        Ok(bus)
+    }
+}
+
+impl SaveState for Bus<'_> {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.cpu_ram);
+        self.clock.save_state(w);
+        w.write_u8(self.open_bus);
+        w.write_u8(self.dma_page);
+        w.write_u8(self.dma_addr);
+        w.write_u8(self.dma_data);
+        w.write_bool(self.dma_dummy);
+        w.write_bool(self.dma_transfer);
+        w.write_u8(self.dmc_stall_cycles);
+        self.ppu.save_state(w);
+        self.apu.save_state(w);
+        self.cart.borrow().save_state(w);
+        for joypad in self.joypads.iter() {
+            joypad.save_state(w);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.read_bytes(&mut self.cpu_ram)?;
+        self.clock.load_state(r)?;
+        self.open_bus = r.read_u8()?;
+        self.dma_page = r.read_u8()?;
+        self.dma_addr = r.read_u8()?;
+        self.dma_data = r.read_u8()?;
+        self.dma_dummy = r.read_bool()?;
+        self.dma_transfer = r.read_bool()?;
+        self.dmc_stall_cycles = r.read_u8()?;
+        self.ppu.load_state(r)?;
+        self.apu.load_state(r)?;
+        self.cart.borrow_mut().load_state(r)?;
+        self.ppu.set_mirroring(self.cart.borrow().mirroring());
+        for joypad in self.joypads.iter_mut() {
+            joypad.load_state(r)?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::joypad::{JoypadKind, JoypadStatus};
 
     #[test]
     fn test_mem_read_write() {
@@ -204,4 +646,66 @@ mod test {
         assert_eq!(bus.cpu_read(0x1000), 0xFF);
         assert_eq!(bus.cpu_read(0x1800), 0xFF);
     }
+
+    #[test]
+    fn test_ram_init() {
+        let mut bus = Bus::new(Cartridge::new_dummy());
+        assert!(bus.cpu_ram.iter().all(|&byte| byte == 0));
+        bus.set_ram_init(RamInit::Striped);
+        assert_eq!(bus.cpu_ram[..9], [0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0]);
+
+        bus.set_ram_init(RamInit::Random(1));
+        let random = bus.cpu_ram;
+        assert!(random.iter().any(|&byte| byte != random[0]));
+        bus.cpu_ram[0] = !random[0];
+        // the same pattern comes back at power on
+        bus.power_cycle();
+        assert_eq!(bus.cpu_ram, random);
+        bus.set_ram_init(RamInit::Random(2));
+        assert_ne!(bus.cpu_ram, random);
+
+        bus.set_ram_init(RamInit::AllFF);
+        assert!(bus.cpu_ram.iter().all(|&byte| byte == 0xFF));
+    }
+
+    #[test]
+    fn test_open_bus() {
+        let mut bus = Bus::new(Cartridge::new_dummy());
+        bus.cpu_ram[0] = 0x5A;
+        bus.cpu_read(0x0000);
+        // unmapped and write-only addresses return the last value read
+        assert_eq!(bus.cpu_read(0x4018), 0x5A);
+        assert_eq!(bus.cpu_read(0x4000), 0x5A);
+        bus.cpu_write(0x0000, 0xA5);
+        assert_eq!(bus.cpu_read(0x401F), 0xA5);
+
+        // the controller ports only drive bits 0-4, LDA $4016 typically
+        // reads $41 with the high byte of the address still on the bus
+        bus.joypads[0].set_status(JoypadStatus::BUTTON_A);
+        bus.cpu_write(0x4016, 1);
+        bus.cpu_write(0x4016, 0);
+        bus.open_bus = 0x40;
+        assert_eq!(bus.cpu_read(0x4016), 0x41);
+        assert_eq!(bus.open_bus(), 0x41);
+        // past the 8 buttons an official controller returns 1s, a third
+        // party one 0s
+        bus.joypads[1].set_kind(JoypadKind::ThirdParty);
+        for _ in 0..8 {
+            bus.cpu_read(0x4016);
+            bus.cpu_read(0x4017);
+        }
+        bus.open_bus = 0x40;
+        assert_eq!(bus.cpu_read(0x4016), 0x41);
+        bus.open_bus = 0x40;
+        assert_eq!(bus.cpu_read(0x4017), 0x40);
+        // the Zapper drives bits 3 and 4
+        bus.zapper = Some(Zapper::new());
+        bus.open_bus = 0xFF;
+        assert_eq!(bus.cpu_read(0x4017), 0xE8);
+
+        // only bit 5 of $4015 is open, and reading it doesn't touch the bus
+        bus.open_bus = 0xFF;
+        assert_eq!(bus.cpu_read(0x4015), 0x20);
+        assert_eq!(bus.open_bus(), 0xFF);
+    }
 }
@@ -1,35 +1,104 @@
+use std::convert::TryFrom;
+
+use crate::apu::mixer::ExpansionAudio;
+use crate::cdl::CodeDataLog;
 use crate::mapper::mapper;
+use crate::romdb::{sha1, RomDb, RomInfo};
+use crate::savestate::{crc32, SaveState, StateReader, StateWriter};
+use crate::unif;
 
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
+const TRAINER_SIZE: usize = 512;
+// where the trainer is loaded, in PRG RAM
+const TRAINER_ADDR: u16 = 0x7000;
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Cartridge {
     pub mapper_id: u8,
     pub mapper: Box<dyn mapper::Mapper>,
     pub mirror: Mirror,
     pub num_prg_banks: u8,
     pub num_chr_banks: u8,
+    // of the PRG ROM followed by the CHR ROM, what ROM databases go by
+    pub crc32: u32,
+    pub sha1: [u8; 20],
+    // what the ROM database knows about the game, if it has it
+    pub db_info: Option<RomInfo>,
+    // what was off with the file
+    pub load_report: CartridgeLoadReport,
+    // 512 bytes of code some iNES files have before the PRG ROM, which
+    // copiers loaded at $7000-$71FF. Mapper hacks of games use it for
+    // their patches.
+    pub trainer: Option<Vec<u8>>,
+    // $7000-$71FF with the trainer on boards without PRG RAM
+    trainer_ram: Vec<u8>,
+    // what the CPU and PPU did with the ROM, while logging
+    #[cfg_attr(feature = "serde", serde(skip))]
+    code_data_log: Option<CodeDataLog>,
+}
+
+// The ROM and board of a file, before the mapper is built
+pub(crate) struct RomImage {
+    pub mapper_id: u8,
+    pub mirror: Mirror,
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
+    pub trainer: Option<Vec<u8>>,
+    // the file can't be trusted to give the right mapper, a ROM database
+    // match can still make it load
+    pub mapper_error: Option<String>,
+    pub report: CartridgeLoadReport,
+}
+
+// How an iNES or UNIF file was read. Files that load can still have problems worth
+// telling the user about, like an overdump with data after the CHR ROM.
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CartridgeLoadReport {
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub has_trainer: bool,
+    // bytes after the CHR ROM, which are ignored
+    pub trailing_bytes: usize,
+    pub warnings: Vec<String>,
 }
 
 impl Cartridge {
     pub fn new(raw: &Vec<u8>) -> Result<Cartridge, String> {
-        if &raw[0..4] != [0x4Eu8, 0x45u8, 0x53u8, 0x1Au8] {
-            return Err("NES identifier not found".to_string());
+        Cartridge::new_with_db(raw, None)
+    }
+
+    // Looks the game up in `db`, and when it's there its mapper and
+    // mirroring are used instead of what the header says
+    pub fn new_with_db(raw: &[u8], db: Option<&RomDb>) -> Result<Cartridge, String> {
+        let image = if raw.starts_with(unif::MAGIC) {
+            unif::parse(raw)?
+        } else {
+            Cartridge::parse_ines(raw)?
+        };
+        Cartridge::from_image(image, db)
+    }
+
+    fn parse_ines(raw: &[u8]) -> Result<RomImage, String> {
+        if raw.len() < 16 {
+            return Err(format!(
+                "file is {} bytes, too small for the 16 byte iNES header",
+                raw.len()
+            ));
+        }
+        if raw[0..4] != [0x4Eu8, 0x45u8, 0x53u8, 0x1Au8] {
+            return Err("NES identifier not found, the file has no iNES header".to_string());
         }
+        let mut report = CartridgeLoadReport::default();
         let num_prg_banks = raw[4];
         let num_chr_banks = raw[5];
 
         let ctrl_byte_1 = raw[6];
         let ctrl_byte_2 = raw[7];
 
-        let mapper_id = (ctrl_byte_2 & 0b1111_0000) | (ctrl_byte_1 >> 4);
-        let mapper = match mapper::new(mapper_id, num_prg_banks, num_chr_banks) {
-            Some(mapper) => mapper,
-            None => return Err(format!("Mapper {} not supported", mapper_id).to_string()),
-        };
+        let mut mapper_id = (ctrl_byte_2 & 0b1111_0000) | (ctrl_byte_1 >> 4);
         let mirror: Mirror = {
             if ctrl_byte_1 & (1 << 3) != 0 {
                 Mirror::FourScreen
@@ -40,31 +109,134 @@ impl Cartridge {
             }
         };
 
-        // assert iNes 1.0 format
-        if ctrl_byte_2 & (0b0000_1111) != 0 {
-            return Err(
-                "Bit 0 to 3 of control byte 2 should be zero for iNes 1.0 format".to_string(),
-            );
+        // bytes 7 to 15 of old dumps can have garbage like "DiskDude!",
+        // which makes the upper nibble of the mapper number wrong. Bytes 12
+        // to 15 are always zero in good iNES 1.0 headers.
+        let header_garbage = raw[12..16].iter().any(|&byte| byte != 0);
+        if header_garbage {
+            mapper_id = ctrl_byte_1 >> 4;
+            report
+                .warnings
+                .push("header bytes 7 to 15 have garbage, ignored them".to_string());
         }
 
+        if num_prg_banks == 0 {
+            return Err("header says there's no PRG ROM".to_string());
+        }
         let prg_rom_size = num_prg_banks as usize * PRG_ROM_PAGE_SIZE;
         let chr_rom_size = num_chr_banks as usize * CHR_ROM_PAGE_SIZE;
         let has_trainer: bool = (ctrl_byte_1 & (1 << 2)) != 0;
-        let prg_rom_start = 16 + (if has_trainer { 512 } else { 0 });
+        let prg_rom_start = 16 + (if has_trainer { TRAINER_SIZE } else { 0 });
         let chr_rom_start = prg_rom_start + prg_rom_size;
+        let chr_rom_end = chr_rom_start + chr_rom_size;
+
+        if raw.len() < chr_rom_start {
+            return Err(format!(
+                "PRG ROM is truncated: expected {} bytes, {} available",
+                prg_rom_size,
+                raw.len().saturating_sub(prg_rom_start)
+            ));
+        }
+        if raw.len() < chr_rom_end {
+            return Err(format!(
+                "CHR ROM is truncated: expected {} bytes, {} available",
+                chr_rom_size,
+                raw.len() - chr_rom_start
+            ));
+        }
+        report.prg_rom_size = prg_rom_size;
+        report.chr_rom_size = chr_rom_size;
+        report.has_trainer = has_trainer;
+        report.trailing_bytes = raw.len() - chr_rom_end;
+        if report.trailing_bytes > 0 {
+            report.warnings.push(format!(
+                "{} bytes after the CHR ROM, ignored them",
+                report.trailing_bytes
+            ));
+        }
+
+        // assert iNes 1.0 format
+        let mapper_error = (!header_garbage && ctrl_byte_2 & (0b0000_1111) != 0)
+            .then(|| "Bit 0 to 3 of control byte 2 should be zero for iNes 1.0 format".to_string());
+
+        Ok(RomImage {
+            mapper_id,
+            mirror,
+            prg_rom: raw[prg_rom_start..chr_rom_start].to_vec(),
+            chr_rom: raw[chr_rom_start..chr_rom_end].to_vec(),
+            trainer: has_trainer.then(|| raw[16..prg_rom_start].to_vec()),
+            mapper_error,
+            report,
+        })
+    }
 
-        let prg_rom = raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec();
-        let chr_rom = raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec();
+    fn from_image(image: RomImage, db: Option<&RomDb>) -> Result<Cartridge, String> {
+        let RomImage {
+            mut mapper_id,
+            mut mirror,
+            prg_rom,
+            chr_rom,
+            trainer,
+            mapper_error,
+            report,
+        } = image;
+        let rom = [&prg_rom[..], &chr_rom[..]].concat();
+        let (crc32, sha1) = (crc32(&rom), sha1(&rom));
+        let db_info = db.and_then(|db| db.lookup(crc32, &sha1)).cloned();
+        match (&db_info, mapper_error) {
+            (Some(info), _) => {
+                // the database is right when the header isn't
+                mapper_id = u8::try_from(info.mapper)
+                    .map_err(|_| format!("Mapper {} not supported", info.mapper))?;
+                mirror = info.mirroring.unwrap_or(mirror);
+            }
+            (None, Some(error)) => return Err(error),
+            (None, None) => {}
+        }
 
-        Ok(Cartridge {
+        let num_prg_banks = prg_rom.len().div_ceil(PRG_ROM_PAGE_SIZE) as u8;
+        let num_chr_banks = chr_rom.len().div_ceil(CHR_ROM_PAGE_SIZE) as u8;
+        let mapper = match mapper::new(mapper_id, prg_rom, chr_rom) {
+            Some(mapper) => mapper,
+            None => return Err(format!("Mapper {} not supported", mapper_id).to_string()),
+        };
+
+        let mut cart = Cartridge {
             mapper_id: mapper_id,
             mapper: mapper,
             mirror: mirror,
             num_prg_banks: num_prg_banks,
             num_chr_banks: num_chr_banks,
-            prg_rom: prg_rom,
-            chr_rom: chr_rom,
-        })
+            crc32,
+            sha1,
+            db_info,
+            load_report: report,
+            trainer,
+            trainer_ram: vec![],
+            code_data_log: None,
+        };
+        cart.load_trainer();
+        Ok(cart)
+    }
+
+    // Into the mapper's PRG RAM, or RAM of its own when there's none
+    fn load_trainer(&mut self) {
+        let trainer = match &self.trainer {
+            Some(trainer) => trainer,
+            None => return,
+        };
+        let start = (TRAINER_ADDR - 0x6000) as usize;
+        match self.mapper.prg_ram_mut() {
+            Some(ram) if ram.len() >= start + TRAINER_SIZE => {
+                ram[start..start + TRAINER_SIZE].copy_from_slice(trainer)
+            }
+            _ => self.trainer_ram = trainer.clone(),
+        }
+    }
+
+    fn trainer_ram_offset(&self, addr: u16) -> Option<usize> {
+        let offset = addr.checked_sub(TRAINER_ADDR)? as usize;
+        (offset < self.trainer_ram.len()).then_some(offset)
     }
 
     pub fn new_from_file<P: AsRef<std::path::Path>>(ines_file: P) -> Result<Cartridge, String> {
@@ -79,6 +251,20 @@ impl Cartridge {
         Cartridge::new(&raw)
     }
 
+    // The PRG ROM of an iNES file, without building a cartridge or mapper
+    pub fn prg_rom(raw: &[u8]) -> Result<&[u8], String> {
+        if raw.len() < 16 || raw[0..4] != [0x4Eu8, 0x45u8, 0x53u8, 0x1Au8] {
+            return Err("NES identifier not found".to_string());
+        }
+        let has_trainer: bool = (raw[6] & (1 << 2)) != 0;
+        let prg_rom_start = 16 + (if has_trainer { TRAINER_SIZE } else { 0 });
+        let prg_rom_end = prg_rom_start + raw[4] as usize * PRG_ROM_PAGE_SIZE;
+        if raw.len() < prg_rom_end {
+            return Err("PRG ROM is truncated".to_string());
+        }
+        Ok(&raw[prg_rom_start..prg_rom_end])
+    }
+
     pub fn new_from_program(mut program: Vec<u8>) -> Cartridge {
         use crate::mapper::mapper_0::Mapper0;
         let min_len = 16 * 1024;
@@ -87,66 +273,186 @@ impl Cartridge {
         }
         Cartridge {
             mapper_id: 0u8,
-            mapper: Box::new(Mapper0::new(1, 1)),
+            crc32: crc32(&program),
+            sha1: sha1(&program),
+            mapper: Box::new(Mapper0::new(program, vec![])),
             mirror: Mirror::Horizontal,
             num_prg_banks: 1,
-            num_chr_banks: 1,
-            prg_rom: program,
-            chr_rom: vec![],
+            num_chr_banks: 0,
+            db_info: None,
+            load_report: CartridgeLoadReport::default(),
+            trainer: None,
+            trainer_ram: vec![],
+            code_data_log: None,
         }
     }
 
     pub fn new_dummy() -> Cartridge {
-        use crate::mapper::mapper_0::Mapper0;
-        Cartridge {
-            mapper_id: 0u8,
-            mapper: Box::new(Mapper0::new(1, 1)),
-            mirror: Mirror::Horizontal,
-            num_prg_banks: 1,
-            num_chr_banks: 1,
-            prg_rom: vec![],
-            chr_rom: vec![],
-        }
+        Cartridge::new_from_program(vec![])
     }
 
-    pub fn cpu_read(&self, addr: u16) -> Option<u8> {
-        self.mapper
-            .cpu_read_mapping(addr)
-            .map(|a| self.prg_rom[a as usize])
+    pub fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.mapper.cpu_read(addr).or_else(|| {
+            self.trainer_ram_offset(addr)
+                .map(|offset| self.trainer_ram[offset])
+        })
     }
 
     pub fn cpu_write(&mut self, addr: u16, value: u8) -> bool {
-        match self.mapper.cpu_read_mapping(addr) {
-            Some(mapped_addr) => {
-                self.prg_rom[mapped_addr as usize] = value;
+        if self.mapper.cpu_write(addr, value) {
+            return true;
+        }
+        match self.trainer_ram_offset(addr) {
+            Some(offset) => {
+                self.trainer_ram[offset] = value;
                 true
             }
             None => false,
         }
     }
 
-    pub fn ppu_read(&self, addr: u16) -> Option<u8> {
-        self.mapper
-            .ppu_read_mapping(addr)
-            .map(|a| self.chr_rom[a as usize])
+    pub fn ppu_read(&mut self, addr: u16) -> Option<u8> {
+        self.mapper.ppu_read(addr)
     }
 
     pub fn ppu_write(&mut self, addr: u16, value: u8) -> bool {
-        match self.mapper.ppu_read_mapping(addr) {
-            Some(mapped_addr) => {
-                self.chr_rom[mapped_addr as usize] = value;
-                true
+        self.mapper.ppu_write(addr, value)
+    }
+
+    // Current nametable mirroring, which some mappers control
+    pub fn mirroring(&self) -> Mirror {
+        self.mapper.mirroring().unwrap_or(self.mirror)
+    }
+
+    pub fn has_irq(&self) -> bool {
+        self.mapper.has_irq()
+    }
+
+    // Called on every CPU cycle
+    pub fn cpu_clock(&mut self) {
+        self.mapper.cpu_clock();
+    }
+
+    pub fn expansion_audio(&self) -> Option<&dyn ExpansionAudio> {
+        self.mapper.expansion_audio()
+    }
+
+    pub fn acknowledge_irq(&mut self) {
+        self.mapper.acknowledge_irq();
+    }
+
+    pub fn power_on(&mut self) {
+        self.mapper.power_on();
+        self.load_trainer();
+    }
+
+    // Log what the CPU and PPU do with the ROM from now on, see `cdl`.
+    // `saved` is a .cdl file of this ROM to continue.
+    pub fn start_code_data_log(&mut self, saved: Option<&[u8]>) -> Result<(), String> {
+        let (prg_rom_size, chr_rom_size) = self.mapper.rom_sizes();
+        let log = match saved {
+            Some(data) => CodeDataLog::from_bytes(data, prg_rom_size, chr_rom_size)?,
+            None => CodeDataLog::new(prg_rom_size, chr_rom_size),
+        };
+        self.code_data_log = Some(log);
+        Ok(())
+    }
+
+    pub fn stop_code_data_log(&mut self) -> Option<CodeDataLog> {
+        self.code_data_log.take()
+    }
+
+    pub fn code_data_log(&self) -> Option<&CodeDataLog> {
+        self.code_data_log.as_ref()
+    }
+
+    pub fn is_logging_code_data(&self) -> bool {
+        self.code_data_log.is_some()
+    }
+
+    // Where `addr` is in the PRG ROM as currently mapped, None when it's
+    // not in ROM or the mapper doesn't tell
+    pub fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        self.mapper.prg_rom_offset(addr)
+    }
+
+    // A CPU access to `addr` with the `cdl` flags, when it's PRG ROM
+    pub fn log_prg(&mut self, addr: u16, flags: u8) {
+        if let Some(log) = &mut self.code_data_log {
+            if let Some(offset) = self.mapper.prg_rom_offset(addr) {
+                log.log_prg(offset, addr, flags);
+            }
+        }
+    }
+
+    // A PPU access to `addr`, when it's CHR ROM
+    pub fn log_chr(&mut self, addr: u16, flags: u8) {
+        if let Some(log) = &mut self.code_data_log {
+            if let Some(offset) = self.mapper.chr_rom_offset(addr) {
+                log.log_chr(offset, flags);
             }
-            None => false,
         }
     }
+
+    // The database title, or None when the game isn't in it
+    pub fn title(&self) -> Option<&str> {
+        self.db_info.as_ref()?.title.as_deref()
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Mirror {
     Vertical,
     Horizontal,
     FourScreen,
+    // all nametables show the first/second 1K of VRAM
+    SingleScreenLo,
+    SingleScreenHi,
+}
+
+// For mappers that switch the mirroring
+impl SaveState for Mirror {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(*self as u8);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        *self = match r.read_u8()? {
+            0 => Mirror::Vertical,
+            1 => Mirror::Horizontal,
+            2 => Mirror::FourScreen,
+            3 => Mirror::SingleScreenLo,
+            4 => Mirror::SingleScreenHi,
+            value => return Err(format!("invalid mirroring {}", value)),
+        };
+        Ok(())
+    }
+}
+
+impl SaveState for Cartridge {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.mapper_id);
+        self.mapper.save_state(w);
+        if !self.trainer_ram.is_empty() {
+            w.write_vec(&self.trainer_ram);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        let mapper_id = r.read_u8()?;
+        if mapper_id != self.mapper_id {
+            return Err(format!(
+                "save state is for mapper {}, cartridge uses mapper {}",
+                mapper_id, self.mapper_id
+            ));
+        }
+        self.mapper.load_state(r)?;
+        if !self.trainer_ram.is_empty() {
+            r.read_vec_into(&mut self.trainer_ram)?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -165,4 +471,97 @@ mod test {
         assert_eq!(c.num_chr_banks, 1);
         assert_eq!(c.mirror, Mirror::Horizontal);
     }
+
+    #[test]
+    fn test_load_report() {
+        let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        p.push("tests/resources/nestest.nes");
+        let mut raw = std::fs::read(p).unwrap();
+        let c = Cartridge::new(&raw).unwrap();
+        assert_eq!(
+            c.load_report,
+            CartridgeLoadReport {
+                prg_rom_size: 0x4000,
+                chr_rom_size: 0x2000,
+                ..Default::default()
+            }
+        );
+
+        // an overdump
+        raw.extend_from_slice(&[0xFF; 100]);
+        let c = Cartridge::new(&raw).unwrap();
+        assert_eq!(c.load_report.trailing_bytes, 100);
+        assert_eq!(c.load_report.warnings.len(), 1);
+
+        // DiskDude! in the header
+        let mut dirty = raw.clone();
+        dirty[7..16].copy_from_slice(b"DiskDude!");
+        let c = Cartridge::new(&dirty).unwrap();
+        assert_eq!(c.mapper_id, 0);
+        assert_eq!(c.load_report.warnings.len(), 2);
+
+        assert_eq!(
+            Cartridge::new(&raw[..0x3010].to_vec()).unwrap_err(),
+            "PRG ROM is truncated: expected 16384 bytes, 12288 available"
+        );
+        assert_eq!(
+            Cartridge::new(&raw[..0x5010].to_vec()).unwrap_err(),
+            "CHR ROM is truncated: expected 8192 bytes, 4096 available"
+        );
+        assert!(Cartridge::new(&raw[..8].to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_trainer() {
+        let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        p.push("tests/resources/nestest.nes");
+        let raw = std::fs::read(p).unwrap();
+        let mut with_trainer = raw[..16].to_vec();
+        with_trainer[6] |= 1 << 2;
+        with_trainer.extend((0..TRAINER_SIZE).map(|i| i as u8));
+        with_trainer.extend_from_slice(&raw[16..]);
+
+        let mut c = Cartridge::new(&with_trainer).unwrap();
+        assert_eq!(c.trainer.as_ref().map(Vec::len), Some(TRAINER_SIZE));
+        assert_eq!(c.cpu_read(0x7000), Some(0));
+        assert_eq!(c.cpu_read(0x71FF), Some(0xFF));
+        assert_eq!(c.cpu_read(0x7200), None);
+        assert_eq!(
+            c.cpu_read(0x8000),
+            Cartridge::new(&raw).unwrap().cpu_read(0x8000)
+        );
+        assert!(c.cpu_write(0x7001, 0x42));
+        assert_eq!(c.cpu_read(0x7001), Some(0x42));
+        c.power_on();
+        assert_eq!(c.cpu_read(0x7001), Some(1));
+        assert!(!Cartridge::new(&raw).unwrap().cpu_write(0x7001, 0x42));
+    }
+
+    #[test]
+    fn test_romdb_override() {
+        let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        p.push("tests/resources/nestest.nes");
+        let mut raw = std::fs::read(p).unwrap();
+        let c = Cartridge::new(&raw).unwrap();
+        assert_eq!(c.db_info, None);
+
+        // a header with garbage in byte 7, as old dumps have
+        raw[7] = b'D';
+        assert!(Cartridge::new(&raw).is_err());
+        let mut db = RomDb::new();
+        let info = RomInfo {
+            title: Some("nestest".to_string()),
+            mapper: 0,
+            submapper: 0,
+            mirroring: Some(Mirror::Vertical),
+            battery: false,
+        };
+        db.insert(c.crc32, Some(c.sha1), info.clone());
+        let fixed = Cartridge::new_with_db(&raw, Some(&db)).unwrap();
+        assert_eq!((fixed.crc32, fixed.sha1), (c.crc32, c.sha1));
+        assert_eq!(fixed.mapper_id, 0);
+        assert_eq!(fixed.mirror, Mirror::Vertical);
+        assert_eq!(fixed.title(), Some("nestest"));
+        assert_eq!(fixed.db_info, Some(info));
+    }
 }
@@ -0,0 +1,187 @@
+use std::fmt;
+
+// Code/Data Logger: which PRG ROM bytes the CPU executed as code or read as
+// data, and which CHR ROM bytes the PPU drew or the CPU read through $2007.
+// Bytes left at 0 were never touched, a disassembler can tell them apart
+// from code it knows ran (see `disasm::prg_listing`).
+//
+// This is FCEUX's .cdl format: a byte of flags per PRG ROM byte followed
+// by a byte per CHR ROM byte. Logs add up, a saved one can be continued in
+// another session.
+//
+//   PRG  xPdcAADC              CHR  xxxxxxRD
+//         ||||||+- code                   |+- drawn
+//         |||||+-- data                   +-- read through $2007
+//         |||++--- CPU window it was accessed in, $8000 + AA * $2000
+//         ||+----- code jumped to through JMP ($xxxx)
+//         |+------ data read through a ($xx),Y or ($xx,X) pointer
+//         +------- DMC sample
+//
+// Ref: https://fceux.com/web/help/CodeDataLogger.html
+
+pub const CODE: u8 = 0x01;
+pub const DATA: u8 = 0x02;
+pub const INDIRECT_CODE: u8 = 0x10;
+pub const INDIRECT_DATA: u8 = 0x20;
+pub const PCM: u8 = 0x40;
+
+pub const CHR_DRAWN: u8 = 0x01;
+pub const CHR_READ: u8 = 0x02;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeDataLog {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+}
+
+impl CodeDataLog {
+    pub fn new(prg_rom_size: usize, chr_rom_size: usize) -> Self {
+        CodeDataLog {
+            prg: vec![0; prg_rom_size],
+            chr: vec![0; chr_rom_size],
+        }
+    }
+
+    // A saved log, which must be for ROMs of these sizes
+    pub fn from_bytes(
+        data: &[u8],
+        prg_rom_size: usize,
+        chr_rom_size: usize,
+    ) -> Result<Self, String> {
+        if data.len() != prg_rom_size + chr_rom_size {
+            return Err(format!(
+                "the log is {} bytes, the ROM {} ({} PRG and {} CHR)",
+                data.len(),
+                prg_rom_size + chr_rom_size,
+                prg_rom_size,
+                chr_rom_size
+            ));
+        }
+        let (prg, chr) = data.split_at(prg_rom_size);
+        Ok(CodeDataLog {
+            prg: prg.to_vec(),
+            chr: chr.to_vec(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [&self.prg[..], &self.chr[..]].concat()
+    }
+
+    // Flags of each PRG ROM byte
+    pub fn prg(&self) -> &[u8] {
+        &self.prg
+    }
+
+    pub fn chr(&self) -> &[u8] {
+        &self.chr
+    }
+
+    // An access by the CPU at `addr`, which is `offset` in the PRG ROM
+    pub fn log_prg(&mut self, offset: usize, addr: u16, flags: u8) {
+        let window = ((addr >> 13) & 0b11) as u8;
+        self.prg[offset] |= flags | (window << 2);
+    }
+
+    pub fn log_chr(&mut self, offset: usize, flags: u8) {
+        self.chr[offset] |= flags;
+    }
+
+    pub fn clear(&mut self) {
+        self.prg.fill(0);
+        self.chr.fill(0);
+    }
+}
+
+// How much of the ROM was seen, e.g. "PRG 40.1% code, 12.5% data; CHR 80.0%"
+impl fmt::Display for CodeDataLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = |bytes: &[u8], flags: u8| {
+            let count = bytes.iter().filter(|&&b| b & flags != 0).count();
+            count as f64 * 100.0 / bytes.len().max(1) as f64
+        };
+        write!(
+            f,
+            "PRG {:.1}% code, {:.1}% data",
+            percent(&self.prg, CODE),
+            percent(&self.prg, DATA | PCM)
+        )?;
+        if !self.chr.is_empty() {
+            write!(f, "; CHR {:.1}%", percent(&self.chr, CHR_DRAWN | CHR_READ))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::emulator::Emulator;
+
+    #[test]
+    fn test_log() {
+        let mut log = CodeDataLog::new(8, 2);
+        log.log_prg(0, 0x8000, CODE);
+        log.log_prg(0, 0x8000, DATA);
+        log.log_prg(6, 0xFFFE, DATA);
+        log.log_chr(1, CHR_DRAWN);
+        assert_eq!(log.prg(), [0x03, 0, 0, 0, 0, 0, 0x0E, 0]);
+        assert_eq!(log.to_string(), "PRG 12.5% code, 25.0% data; CHR 50.0%");
+
+        let bytes = log.to_bytes();
+        assert_eq!(bytes, [0x03, 0, 0, 0, 0, 0, 0x0E, 0, 0, CHR_DRAWN]);
+        assert_eq!(CodeDataLog::from_bytes(&bytes, 8, 2), Ok(log.clone()));
+        let e = CodeDataLog::from_bytes(&bytes, 4, 2).unwrap_err();
+        assert_eq!(e, "the log is 10 bytes, the ROM 6 (4 PRG and 2 CHR)");
+        log.clear();
+        assert_eq!(log.to_bytes(), [0; 10]);
+    }
+
+    #[test]
+    fn test_emulator() {
+        let program = [
+            0xA9, 0x00, 0x8D, 0x06, 0x20, 0x8D, 0x06, 0x20, // 8000 $2006 = 0
+            0xAD, 0x07, 0x20, // 8008 LDA $2007
+            0xA9, 0x08, 0x8D, 0x01, 0x20, // 800B background on
+            0xAD, 0x50, 0x80, // 8010 LDA $8050
+            0xA9, 0x60, 0x85, 0x10, 0xA9, 0x80, 0x85, 0x11, // 8013 ($10) = $8060
+            0xA0, 0x01, 0xB1, 0x10, // 801B LDY #1 : LDA ($10),Y
+            0x6C, 0x70, 0x80, // 801F JMP ($8070)
+            0x4C, 0x22, 0x80, // 8022 JMP $8022
+        ];
+        let mut rom = b"NES\x1a\x01\x01".to_vec();
+        rom.resize(16, 0);
+        let mut prg = vec![0; 0x4000];
+        prg[..program.len()].copy_from_slice(&program);
+        prg[0x70..0x72].copy_from_slice(&[0x22, 0x80]);
+        prg[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        rom.extend_from_slice(&prg);
+        rom.resize(16 + 0x4000 + 0x2000, 0);
+
+        let mut emulator = Emulator::new(Cartridge::new(&rom).unwrap());
+        emulator.start_code_data_log(None).unwrap();
+        for _ in 0..3 {
+            emulator.run_frame().unwrap();
+        }
+        let log = emulator.stop_code_data_log().unwrap();
+        assert!(emulator.cartridge().code_data_log().is_none());
+        let prg = log.prg();
+        // immediate operands are code
+        assert_eq!(prg[0x00..0x02], [CODE, CODE]);
+        assert_eq!(prg[0x50], DATA);
+        assert_eq!(prg[0x61], DATA | INDIRECT_DATA);
+        assert_eq!(prg[0x70..0x72], [DATA, DATA]);
+        assert_eq!(prg[0x22], CODE | INDIRECT_CODE);
+        assert_eq!(prg[0x25], 0);
+        let chr = log.chr();
+        assert_eq!(chr[0x00], CHR_DRAWN | CHR_READ);
+        assert_eq!(chr[0x0F], CHR_DRAWN);
+        assert_eq!(chr[0x10], 0);
+
+        // continued with what was logged
+        emulator.start_code_data_log(Some(&log.to_bytes())).unwrap();
+        assert_eq!(emulator.cartridge().code_data_log(), Some(&log));
+        assert!(emulator.start_code_data_log(Some(&[0; 16])).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+// Cheat codes, applied as patches to what the CPU reads. Two formats:
+//
+//   Game Genie     6 or 8 letters, e.g. SXIOPO. 6-letter codes replace a
+//                  ROM byte, 8-letter codes only when the original value
+//                  matches a compare byte (for bank-switched ROM).
+//   Action Replay  raw hex address and value, AAAA:VV, or AAAA?CC:VV with
+//                  a compare byte like FCEUX writes them. Usually RAM.
+//
+// Ref: https://wiki.nesdev.org/w/index.php/Game_Genie
+//
+// Freezes lock RAM instead: CPU writes to a frozen address are dropped, and
+// with a value the address is set to it again at the end of every frame,
+// which also undoes state loads and power cycles.
+
+const GAME_GENIE_LETTERS: &[u8; 16] = b"APZLGITYEOXUKSVN";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cheat {
+    pub code: String,
+    pub address: u16,
+    pub value: u8,
+    // only patch when the unpatched value is this
+    pub compare: Option<u8>,
+    pub enabled: bool,
+}
+
+impl Cheat {
+    pub fn parse(code: &str) -> Result<Cheat, String> {
+        let code = code.trim();
+        let (address, value, compare) = if code.contains(':') {
+            parse_raw(code)
+        } else {
+            parse_game_genie(code)
+        }
+        .ok_or_else(|| format!("invalid cheat code {}", code))?;
+        Ok(Cheat {
+            code: code.to_string(),
+            address,
+            value,
+            compare,
+            enabled: true,
+        })
+    }
+
+    fn patch(&self, addr: u16, value: u8) -> u8 {
+        if !self.enabled || addr != self.address {
+            return value;
+        }
+        match self.compare {
+            Some(compare) if compare != value => value,
+            _ => self.value,
+        }
+    }
+}
+
+fn parse_game_genie(code: &str) -> Option<(u16, u8, Option<u8>)> {
+    if code.len() != 6 && code.len() != 8 {
+        return None;
+    }
+    let mut n = [0u16; 8];
+    for (i, c) in code.bytes().enumerate() {
+        let c = c.to_ascii_uppercase();
+        n[i] = GAME_GENIE_LETTERS.iter().position(|&l| l == c)? as u16;
+    }
+    let address = 0x8000
+        + (((n[3] & 7) << 12)
+            | ((n[5] & 7) << 8)
+            | ((n[4] & 8) << 8)
+            | ((n[2] & 7) << 4)
+            | ((n[1] & 8) << 4)
+            | (n[4] & 7)
+            | (n[3] & 8));
+    let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7);
+    if code.len() == 6 {
+        let value = value | (n[5] & 8);
+        return Some((address, value as u8, None));
+    }
+    let value = value | (n[7] & 8);
+    let compare = ((n[7] & 7) << 4) | ((n[6] & 8) << 4) | (n[6] & 7) | (n[5] & 8);
+    Some((address, value as u8, Some(compare as u8)))
+}
+
+// AAAA:VV or AAAA?CC:VV
+fn parse_raw(code: &str) -> Option<(u16, u8, Option<u8>)> {
+    let (address, value) = code.split_once(':')?;
+    let (address, compare) = match address.split_once('?') {
+        Some((address, compare)) => (address, Some(u8::from_str_radix(compare, 16).ok()?)),
+        None => (address, None),
+    };
+    let address = u16::from_str_radix(address, 16).ok()?;
+    let value = u8::from_str_radix(value, 16).ok()?;
+    Some((address, value, compare))
+}
+
+// The cheats of a running game
+#[derive(Default)]
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+}
+
+impl Cheats {
+    pub fn new() -> Self {
+        Cheats { cheats: vec![] }
+    }
+
+    // Returns the index of the cheat, for `remove` and `set_enabled`
+    pub fn add(&mut self, code: &str) -> Result<usize, String> {
+        self.cheats.push(Cheat::parse(code)?);
+        Ok(self.cheats.len() - 1)
+    }
+
+    pub fn remove(&mut self, idx: usize) -> Cheat {
+        self.cheats.remove(idx)
+    }
+
+    pub fn set_enabled(&mut self, idx: usize, enabled: bool) {
+        self.cheats[idx].enabled = enabled;
+    }
+
+    pub fn clear(&mut self) {
+        self.cheats.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Cheat> {
+        self.cheats.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cheats.is_empty()
+    }
+
+    // The value the CPU sees when reading `value` from `addr`
+    pub fn patch(&self, addr: u16, value: u8) -> u8 {
+        self.cheats
+            .iter()
+            .fold(value, |patched, cheat| cheat.patch(addr, patched))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Freeze {
+    pub addr: u16,
+    // None keeps what the address holds
+    pub value: Option<u8>,
+}
+
+// The frozen addresses of a running game
+#[derive(Default)]
+pub struct Freezes {
+    freezes: Vec<Freeze>,
+}
+
+impl Freezes {
+    pub fn new() -> Self {
+        Freezes { freezes: vec![] }
+    }
+
+    // Only RAM can be frozen, internal RAM ($0000-$1FFF, mirrors freeze
+    // together) and PRG RAM ($6000-$7FFF) on cartridges that have it, other
+    // boards may have registers there. Freezing an address again replaces
+    // its value.
+    pub fn add(&mut self, addr: u16, value: Option<u8>, has_prg_ram: bool) -> Result<(), String> {
+        let addr = match addr {
+            0x0000..=0x1FFF => addr & 0x07FF,
+            0x6000..=0x7FFF if has_prg_ram => addr,
+            _ => return Err(format!("{:04X} isn't RAM, only RAM can be frozen", addr)),
+        };
+        self.remove(addr);
+        self.freezes.push(Freeze { addr, value });
+        Ok(())
+    }
+
+    // Returns whether the address was frozen
+    pub fn remove(&mut self, addr: u16) -> bool {
+        let addr = mirror(addr);
+        let len = self.freezes.len();
+        self.freezes.retain(|freeze| freeze.addr != addr);
+        self.freezes.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.freezes.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Freeze> {
+        self.freezes.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.freezes.is_empty()
+    }
+
+    pub fn is_frozen(&self, addr: u16) -> bool {
+        let addr = mirror(addr);
+        self.freezes.iter().any(|freeze| freeze.addr == addr)
+    }
+}
+
+fn mirror(addr: u16) -> u16 {
+    if addr < 0x2000 {
+        addr & 0x07FF
+    } else {
+        addr
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_game_genie() {
+        // the example from the nesdev wiki
+        let cheat = Cheat::parse("GOSSIP").unwrap();
+        assert_eq!(
+            (cheat.address, cheat.value, cheat.compare),
+            (0xD1DD, 0x14, None)
+        );
+        assert_eq!(Cheat::parse("gossip").unwrap().address, 0xD1DD);
+        let cheat = Cheat::parse("ZEXPYGLA").unwrap();
+        assert_eq!(
+            (cheat.address, cheat.value, cheat.compare),
+            (0x94A7, 0x02, Some(0x03))
+        );
+
+        assert!(Cheat::parse("GOSSI").is_err());
+        assert!(Cheat::parse("GOSSIB").is_err());
+    }
+
+    #[test]
+    fn test_raw_codes() {
+        let cheat = Cheat::parse("075A:09").unwrap();
+        assert_eq!(
+            (cheat.address, cheat.value, cheat.compare),
+            (0x075A, 0x09, None)
+        );
+        let cheat = Cheat::parse("C010?A9:EA").unwrap();
+        assert_eq!(
+            (cheat.address, cheat.value, cheat.compare),
+            (0xC010, 0xEA, Some(0xA9))
+        );
+        assert!(Cheat::parse("10000:00").is_err());
+        assert!(Cheat::parse("0000:100").is_err());
+    }
+
+    #[test]
+    fn test_patch() {
+        let mut cheats = Cheats::new();
+        let raw = cheats.add("0010:42").unwrap();
+        let compare = cheats.add("8000?A9:EA").unwrap();
+        assert_eq!(cheats.patch(0x0010, 0x00), 0x42);
+        assert_eq!(cheats.patch(0x0011, 0x00), 0x00);
+        // compare byte
+        assert_eq!(cheats.patch(0x8000, 0xA9), 0xEA);
+        assert_eq!(cheats.patch(0x8000, 0xA8), 0xA8);
+
+        cheats.set_enabled(raw, false);
+        assert_eq!(cheats.patch(0x0010, 0x00), 0x00);
+        cheats.remove(raw);
+        assert_eq!(cheats.iter().count(), 1);
+        assert_eq!(cheats.iter().next().unwrap().code, "8000?A9:EA");
+        assert!(compare < 2);
+        cheats.clear();
+        assert!(cheats.is_empty());
+    }
+
+    #[test]
+    fn test_freezes() {
+        let mut freezes = Freezes::new();
+        freezes.add(0x0810, None, false).unwrap();
+        freezes.add(0x6000, Some(0x42), true).unwrap();
+        assert!(freezes.add(0x2000, None, true).is_err());
+        assert!(freezes.add(0x8000, None, true).is_err());
+        assert!(freezes.add(0x6001, None, false).is_err());
+        // mirrors
+        assert!(freezes.is_frozen(0x0010));
+        assert!(freezes.is_frozen(0x1810));
+        assert!(!freezes.is_frozen(0x0011));
+
+        freezes.add(0x6000, Some(0x43), true).unwrap();
+        assert_eq!(
+            freezes.iter().collect::<Vec<_>>(),
+            [
+                &Freeze {
+                    addr: 0x0010,
+                    value: None
+                },
+                &Freeze {
+                    addr: 0x6000,
+                    value: Some(0x43)
+                }
+            ]
+        );
+        assert!(freezes.remove(0x0010));
+        assert!(!freezes.remove(0x0010));
+        freezes.clear();
+        assert!(freezes.is_empty());
+    }
+}
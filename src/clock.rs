@@ -0,0 +1,107 @@
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+// The NES derives all its clocks from a single master clock. One tick of
+// `Clock` is one PPU dot, and everything else runs at a fixed ratio of it:
+//
+//   PPU         every tick
+//   CPU         every 3rd tick
+//   APU         every CPU cycle, with the pulse channels and DMA alignment
+//               depending on whether it's an even ("get") or odd ("put")
+//               CPU cycle
+//
+// Ref: https://wiki.nesdev.org/w/index.php/Cycle_reference_chart
+pub const PPU_TICKS_PER_CPU_CYCLE: u64 = 3;
+
+// What gets clocked on a master clock tick
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockTick {
+    // the CPU (and the APU, DMA and mapper counters) run this tick
+    pub cpu: bool,
+    // the CPU cycle is even, DMA reads on even cycles and writes on odd ones
+    pub even_cpu_cycle: bool,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Clock {
+    ticks: u64,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Clock { ticks: 0 }
+    }
+
+    // Advance the master clock by one PPU dot
+    pub fn tick(&mut self) -> ClockTick {
+        let tick = ClockTick {
+            cpu: self.ticks.is_multiple_of(PPU_TICKS_PER_CPU_CYCLE),
+            even_cpu_cycle: self.cpu_cycles().is_multiple_of(2),
+        };
+        self.ticks += 1;
+        tick
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    // CPU cycles started so far, including the ones the CPU was halted for
+    pub fn cpu_cycles(&self) -> u64 {
+        self.ticks.div_ceil(PPU_TICKS_PER_CPU_CYCLE)
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock::new()
+    }
+}
+
+impl SaveState for Clock {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u64(self.ticks);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.ticks = r.read_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_clock_ratios() {
+        let mut clock = Clock::new();
+        let ticks: Vec<ClockTick> = (0..12).map(|_| clock.tick()).collect();
+
+        let cpu: Vec<bool> = ticks.iter().map(|t| t.cpu).collect();
+        assert_eq!(
+            cpu,
+            [true, false, false, true, false, false, true, false, false, true, false, false]
+        );
+        let even: Vec<bool> = ticks
+            .iter()
+            .filter(|t| t.cpu)
+            .map(|t| t.even_cpu_cycle)
+            .collect();
+        assert_eq!(even, [true, false, true, false]);
+
+        assert_eq!(clock.ticks(), 12);
+        assert_eq!(clock.cpu_cycles(), 4);
+        clock.tick();
+        assert_eq!(clock.cpu_cycles(), 5);
+    }
+
+    #[test]
+    fn test_clock_does_not_drift() {
+        // a u32 tick counter would wrap here and break the 3:1 ratio
+        let mut clock = Clock {
+            ticks: u32::MAX as u64 - 1,
+        };
+        let cpu_ticks = (0..30).filter(|_| clock.tick().cpu).count();
+        assert_eq!(cpu_ticks, 10);
+    }
+}
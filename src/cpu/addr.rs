@@ -16,7 +16,7 @@ pub enum Address {
 }
 
 #[allow(dead_code)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum AddrMode {
     Absolute,
     AbsoluteX,
@@ -50,7 +50,7 @@ impl AddrMode {
         }
     }
 
-    fn fetch<'a, I>(&self, bytes: I) -> Address
+    pub fn fetch<'a, I>(&self, bytes: I) -> Address
     where
         I: Iterator<Item = &'a u8>,
     {
@@ -1,20 +1,248 @@
 use lazy_static::lazy_static;
 use regex::Regex;
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-pub fn assemble(asm: &str) -> Vec<u8> {
+use super::addr::AddrMode as SpecMode;
+use super::spec::{self, Encoding};
+use crate::cartridge::Mirror;
+
+// Besides instructions, labels and `define`, the source can use:
+//
+//   .org $8000         continue at an address, the gap is filled with 0
+//   .byte $01, <lbl    emit bytes
+//   .word lbl, $1234   emit little endian words
+//   .include "x.asm"   assemble another file in place, relative to the
+//                      file including it
+//   name = $10         a label with a value, like a zero page variable
+//
+// Operands and data can be expressions: numbers ($hex, %binary, decimal,
+// 'c' for a character) and labels added or subtracted, like `table+2`. A
+// leading `<` or `>` takes the low or high byte. Operands with labels
+// defined further down are always absolute, the size of the instruction
+// has to be known before the label is. Labels are case sensitive,
+// mnemonics and registers are not.
+
+lazy_static! {
+    static ref ENCODINGS: HashMap<String, HashMap<SpecMode, Encoding>> = spec::encoding_table();
+}
+
+// An included file can include others, up to this depth
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+const PRG_BANK_SIZE: usize = 16384;
+const CHR_BANK_SIZE: usize = 8192;
+
+pub fn assemble(asm: &str) -> Result<Vec<u8>, AssembleError> {
     assemble_with_start_addr(asm, 0x0600)
 }
 
-pub fn assemble_with_start_addr(asm: &str, start_addr: u16) -> Vec<u8> {
+pub fn assemble_with_start_addr(asm: &str, start_addr: u16) -> Result<Vec<u8>, AssembleError> {
     let lines = asm.split("\n").into_iter().map(|x| x.to_string()).collect();
     let assembler = Assembler::new(lines);
-    assembler.assemble(start_addr)
+    assembler.assemble(start_addr).map(|a| a.bytes)
+}
+
+// Assemble a source file
+pub fn assemble_file<P: AsRef<Path>>(path: P, start_addr: u16) -> Result<Vec<u8>, AssembleError> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path).map_err(|e| AssembleError {
+        file: Some(path.to_path_buf()),
+        line: 0,
+        column: 0,
+        text: String::new(),
+        reason: format!("cannot read file: {}", e),
+    })?;
+    let lines = source.lines().map(|x| x.to_string()).collect();
+    let mut assembler = Assembler::new(lines);
+    for l in assembler.source.iter_mut() {
+        l.file = Some(path.to_path_buf());
+    }
+    assembler.assemble(start_addr).map(|a| a.bytes)
+}
+
+// How `assemble_to_ines` lays out a ROM image
+#[derive(Debug, Clone)]
+pub struct InesConfig {
+    // 16K PRG ROM banks, mapped to the end of the address space. With 1
+    // bank, $8000-$BFFF mirrors $C000-$FFFF.
+    pub prg_banks: u8,
+    // CHR ROM, padded to 8K banks. Empty for CHR RAM.
+    pub chr: Vec<u8>,
+    pub mapper: u8,
+    pub mirror: Mirror,
+    // Labels the interrupt vectors point to. Vectors without a label keep
+    // what the code put at $FFFA-$FFFF.
+    pub reset: Option<String>,
+    pub nmi: Option<String>,
+    pub irq: Option<String>,
+}
+
+impl Default for InesConfig {
+    fn default() -> Self {
+        InesConfig {
+            prg_banks: 1,
+            chr: vec![],
+            mapper: 0,
+            mirror: Mirror::Horizontal,
+            reset: Some("reset".to_string()),
+            nmi: None,
+            irq: None,
+        }
+    }
+}
+
+// Assemble into an iNES image that `Cartridge::new` loads. The code starts
+// at the first PRG ROM address unless it sets its own `.org`.
+pub fn assemble_to_ines(asm: &str, config: &InesConfig) -> Result<Vec<u8>, AssembleError> {
+    let error = |text: &str, reason: String| AssembleError {
+        file: None,
+        line: 0,
+        column: 0,
+        text: text.to_string(),
+        reason,
+    };
+
+    let prg_size = config.prg_banks as usize * PRG_BANK_SIZE;
+    if !(1..=2).contains(&config.prg_banks) {
+        // beyond 32K the layout depends on the mapper
+        let reason = format!("{} PRG banks do not fit at $8000-$FFFF", config.prg_banks);
+        return Err(error("", reason));
+    }
+    let chr_banks = config.chr.len().div_ceil(CHR_BANK_SIZE);
+    let chr_banks = u8::try_from(chr_banks)
+        .map_err(|_| error("", format!("{} CHR banks are too many", chr_banks)))?;
+
+    let lines = asm.split('\n').map(|x| x.to_string()).collect();
+    let start_addr = (0x10000 - prg_size) as u16;
+    let assembly = Assembler::new(lines).assemble(start_addr)?;
+    let end = assembly.origin as usize + assembly.bytes.len();
+    if (assembly.origin as usize) < start_addr as usize || end > 0x10000 {
+        let reason = format!(
+            "code at ${:04X}-${:04X} is outside PRG ROM",
+            assembly.origin,
+            end - 1
+        );
+        return Err(error("", reason));
+    }
+    let mut prg = vec![0u8; prg_size];
+    let offset = assembly.origin as usize - start_addr as usize;
+    prg[offset..offset + assembly.bytes.len()].copy_from_slice(&assembly.bytes);
+
+    let vectors = [
+        (0xFFFA, &config.nmi),
+        (0xFFFC, &config.reset),
+        (0xFFFE, &config.irq),
+    ];
+    for (vector, label) in vectors {
+        if let Some(label) = label {
+            let addr = match assembly.labels.get(label) {
+                Some(addr) => *addr,
+                None => return Err(error(label, "undefined label".to_string())),
+            };
+            let offset = vector - start_addr as usize;
+            prg[offset..offset + 2].copy_from_slice(&addr.to_le_bytes());
+        }
+    }
+
+    let mut flags_6 = (config.mapper & 0x0F) << 4;
+    match config.mirror {
+        Mirror::Vertical => flags_6 |= 1 << 0,
+        Mirror::FourScreen => flags_6 |= 1 << 3,
+        _ => {}
+    }
+    let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, config.prg_banks, chr_banks];
+    rom.extend([flags_6, config.mapper & 0xF0]);
+    rom.resize(16, 0);
+    rom.extend(prg);
+    rom.extend(&config.chr);
+    rom.resize(16 + prg_size + chr_banks as usize * CHR_BANK_SIZE, 0);
+    Ok(rom)
+}
+
+// Why a line of the source could not be assembled. `line` and `column` are
+// 1-based and point at `text`, the offending part of the line. They are 0
+// for errors that are not on a line, like a file that cannot be read.
+// `file` is set for lines of files, not for source given as a string.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssembleError {
+    pub file: Option<PathBuf>,
+    pub line: usize,
+    pub column: usize,
+    pub text: String,
+    pub reason: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}, ", file.display())?;
+        }
+        write!(
+            f,
+            "line {}, column {}: {}: '{}'",
+            self.line, self.column, self.reason, self.text
+        )
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+// Assembled code, which starts at `origin`
+struct Assembly {
+    origin: u16,
+    bytes: Vec<u8>,
+    labels: HashMap<String, u16>,
+}
+
+// A line of the source, before any processing
+#[derive(Debug, Clone)]
+struct SourceLine {
+    file: Option<PathBuf>,
+    // 0-based
+    line: usize,
+    text: String,
+}
+
+impl SourceLine {
+    // The line without its comment
+    fn code(&self) -> &str {
+        strip_comment(&self.text)
+    }
+
+    // An error on this line. `text` is searched for in the line, without
+    // it the whole statement is blamed.
+    fn error(&self, text: Option<&str>, reason: String) -> AssembleError {
+        let code = self.code();
+        let start = code.len() - code.trim_start().len();
+        let (start, len) = match text.and_then(|t| {
+            code.to_ascii_uppercase()[start..]
+                .find(&t.to_ascii_uppercase())
+                .map(|i| (start + i, t.len()))
+        }) {
+            Some(found) => found,
+            None => (start, code.trim().len()),
+        };
+        AssembleError {
+            file: self.file.clone(),
+            line: self.line + 1,
+            column: code[..start].chars().count() + 1,
+            text: code[start..start + len].to_string(),
+            reason,
+        }
+    }
 }
 
 #[allow(dead_code)]
 struct Assembler {
     lines: Vec<String>,
+    // the source with includes expanded, and the index of each of `lines`
+    // in it
+    source: Vec<SourceLine>,
+    line_numbers: Vec<usize>,
     params: HashMap<String, String>,
     label_to_addr: HashMap<String, u16>,
 }
@@ -22,27 +250,50 @@ struct Assembler {
 impl Assembler {
     fn new(lines: Vec<String>) -> Self {
         Assembler {
+            source: lines
+                .iter()
+                .enumerate()
+                .map(|(line, text)| SourceLine {
+                    file: None,
+                    line,
+                    text: text.to_string(),
+                })
+                .collect(),
+            line_numbers: (0..lines.len()).collect(),
             lines: lines,
             params: HashMap::new(),
             label_to_addr: HashMap::new(),
         }
     }
 
+    // Replace `.include` lines with the lines of the included files
+    fn include_files(&mut self) -> Result<(), AssembleError> {
+        self.source = include_files(std::mem::take(&mut self.source), 0)?;
+        self.lines = self.source.iter().map(|l| l.text.to_string()).collect();
+        self.line_numbers = (0..self.source.len()).collect();
+        Ok(())
+    }
+
     fn pre_process(&mut self) {
-        // remove comments, trim, and to upper case
+        // remove comments and trim, labels keep their case
         for l in self.lines.iter_mut() {
-            if let Some(i) = l.find(';') {
-                *l = l[..i].to_string();
+            *l = strip_comment(l).trim().to_string();
+        }
+        // remove empty lines, keeping track of where the others came from
+        let lines = std::mem::take(&mut self.lines);
+        let line_numbers = std::mem::take(&mut self.line_numbers);
+        for (l, n) in lines.into_iter().zip(line_numbers) {
+            if !l.trim().is_empty() {
+                self.lines.push(l);
+                self.line_numbers.push(n);
             }
-            *l = l.trim().to_uppercase().to_string();
         }
-        // remove empty lines
-        self.lines.retain(|l| !l.trim().is_empty());
     }
 
-    fn assemble(mut self, start_addr: u16) -> Vec<u8> {
+    fn assemble(mut self, start_addr: u16) -> Result<Assembly, AssembleError> {
         use Statement::*;
 
+        self.include_files()?;
         self.pre_process();
 
         // replace defined params
@@ -57,60 +308,273 @@ impl Assembler {
         }
 
         // parse to statements after params replacement
-        let mut statements: Vec<Statement> = self
-            .lines
-            .iter()
-            .map(|l| match parse_statement(&l) {
-                Some(s) => s,
-                None => panic!("failed to parse code '{}'", l),
-            })
-            .collect();
+        let mut statements: Vec<Statement> = vec![];
+        for (i, l) in self.lines.iter().enumerate() {
+            match parse_statement(&l) {
+                Some(s) => statements.push(s),
+                None => return Err(self.error(i, None, "failed to parse statement".to_string())),
+            }
+        }
 
-        // calculate addr for labels
+        // calculate addr for labels, and pick the size of each instruction
+        // with the labels defined above it
         let mut curr_addr = start_addr;
-        for s in statements.iter() {
+        let mut emitted = false;
+        for (i, s) in statements.iter_mut().enumerate() {
             match s {
                 Label { name } => {
-                    self.label_to_addr.insert(name.to_uppercase(), curr_addr);
+                    self.label_to_addr.insert(name.to_string(), curr_addr);
+                }
+                Constant { name, value } => {
+                    let value = self.eval(i, value)?;
+                    let value = u16::try_from(value).map_err(|_| self.out_of_range(i, value))?;
+                    self.label_to_addr.insert(name.to_string(), value);
                 }
                 Instruction { opcode, addr_mode } => {
-                    curr_addr += instruction_size(&opcode, &addr_mode) as u16;
+                    *addr_mode = self.fit_addr_mode(opcode, addr_mode);
+                    curr_addr =
+                        curr_addr.wrapping_add(instruction_size(&opcode, &addr_mode) as u16);
+                    emitted = true;
+                }
+                Org { addr } => {
+                    if emitted && *addr < curr_addr {
+                        let reason = format!("origin is behind ${:04X}", curr_addr);
+                        return Err(self.error(i, None, reason));
+                    }
+                    curr_addr = *addr;
+                }
+                Data { size, values } => {
+                    curr_addr = curr_addr.wrapping_add((*size as usize * values.len()) as u16);
+                    emitted = true;
                 }
                 _ => {}
             }
         }
 
-        // replace relative label to relative addr or absolute addr
+        // replace labels and expressions with addresses and values, then
+        // assemble each statement
+        let mut result: Vec<u8> = vec![];
         let mut curr_addr = start_addr;
-        for s in statements.iter_mut() {
-            if let Instruction { opcode, addr_mode } = s {
-                curr_addr += instruction_size(&opcode, &addr_mode) as u16;
-                if let AddrMode::RelativeLabel(label) = addr_mode {
-                    let label_addr: u16 = *self.label_to_addr.get(&label.to_uppercase()).unwrap();
-                    *s = Instruction {
-                        opcode: opcode.to_string(),
-                        addr_mode: label_to_relative_or_absolute(opcode, curr_addr, label_addr),
+        let mut origin = start_addr;
+        for (i, s) in statements.iter_mut().enumerate() {
+            match s {
+                Instruction { opcode, addr_mode } => {
+                    curr_addr =
+                        curr_addr.wrapping_add(instruction_size(&opcode, &addr_mode) as u16);
+                    let resolved = self.resolve(i, opcode, addr_mode, curr_addr)?;
+                    *addr_mode = resolved;
+                }
+                Org { addr } => {
+                    if result.is_empty() {
+                        origin = *addr;
+                    } else {
+                        result.resize(result.len() + (*addr - curr_addr) as usize, 0);
                     }
+                    curr_addr = *addr;
                 }
+                Data { size, values } => {
+                    for value in values.iter() {
+                        let v = self.eval(i, value)?;
+                        if *size == 1 {
+                            match i8::try_from(v)
+                                .map(|b| b as u8)
+                                .or_else(|_| u8::try_from(v))
+                            {
+                                Ok(b) => result.push(b),
+                                Err(_) => return Err(self.out_of_range(i, v)),
+                            }
+                        } else {
+                            match i16::try_from(v)
+                                .map(|w| w as u16)
+                                .or_else(|_| u16::try_from(v))
+                            {
+                                Ok(w) => result.extend(w.to_le_bytes()),
+                                Err(_) => return Err(self.out_of_range(i, v)),
+                            }
+                        }
+                    }
+                    curr_addr = curr_addr.wrapping_add((*size as usize * values.len()) as u16);
+                    continue;
+                }
+                _ => {}
+            }
+            match s.assemble() {
+                Ok(bytes) => result.extend(bytes),
+                Err(reason) => return Err(self.error(i, None, reason)),
             }
         }
+        Ok(Assembly {
+            origin,
+            bytes: result,
+            labels: self.label_to_addr,
+        })
+    }
 
-        // assemble each instruction
-        let mut result: Vec<u8> = vec![];
-        for s in statements.iter() {
-            result.extend(s.assemble());
+    // Labels defined above are known, operands with them use zero page
+    // when the value fits. Zero page operands of instructions without a
+    // zero page form use absolute.
+    fn fit_addr_mode(&self, opcode: &str, addr_mode: &AddrMode) -> AddrMode {
+        use AddrMode::*;
+
+        let supported = |mode: &AddrMode| {
+            let s = Statement::Instruction {
+                opcode: opcode.to_string(),
+                addr_mode: mode.clone(),
+            };
+            s.assemble().is_ok()
+        };
+        match addr_mode {
+            // a branch to an address, like the disassembler shows them
+            Absolute(addr) if is_branch(opcode) => {
+                Symbolic(Box::new(Absolute(0)), Expr::number(*addr as i32))
+            }
+            RelativeLabel(label) if !is_branch(opcode) => {
+                let mode = Symbolic(Box::new(Absolute(0)), Expr::label(label));
+                self.fit_addr_mode(opcode, &mode)
+            }
+            Symbolic(mode, expr) => {
+                let mut mode = mode.as_ref().clone();
+                let value = expr.eval(&self.label_to_addr);
+                if let (Some(zero_page), Ok(0..=0xFF)) = (mode.zero_page(), value) {
+                    if supported(&zero_page) {
+                        mode = zero_page;
+                    }
+                }
+                if let Some(absolute) = mode.absolute().filter(|_| !supported(&mode)) {
+                    mode = absolute;
+                }
+                Symbolic(Box::new(mode), expr.clone())
+            }
+            mode => match mode.absolute() {
+                Some(absolute) if !supported(mode) => absolute,
+                _ => mode.clone(),
+            },
         }
-        result
+    }
+
+    // Replace a label or expression operand of the `index`th statement.
+    // `next_addr` is the address of the statement after it.
+    fn resolve(
+        &self,
+        index: usize,
+        opcode: &str,
+        addr_mode: &AddrMode,
+        next_addr: u16,
+    ) -> Result<AddrMode, AssembleError> {
+        let (target, label) = match addr_mode {
+            AddrMode::RelativeLabel(label) => {
+                let value = self.eval(index, &Expr::label(label))?;
+                (value, label.to_string())
+            }
+            AddrMode::Symbolic(mode, expr) => {
+                let value = self.eval(index, expr)?;
+                match mode.as_ref() {
+                    AddrMode::Absolute(_) if is_branch(opcode) => {}
+                    mode => {
+                        return mode
+                            .with_value(value)
+                            .ok_or_else(|| self.out_of_range(index, value))
+                    }
+                }
+                (value, String::new())
+            }
+            mode => return Ok(mode.clone()),
+        };
+        let target = u16::try_from(target).map_err(|_| self.out_of_range(index, target))?;
+        label_to_relative_or_absolute(opcode, next_addr, target).ok_or_else(|| {
+            let reason = "branch target out of range".to_string();
+            self.error(
+                index,
+                Some(label.as_str()).filter(|l| !l.is_empty()),
+                reason,
+            )
+        })
+    }
+
+    fn eval(&self, index: usize, expr: &Expr) -> Result<i32, AssembleError> {
+        expr.eval(&self.label_to_addr).map_err(|label| {
+            let reason = "undefined label".to_string();
+            self.error(index, Some(&label), reason)
+        })
+    }
+
+    fn out_of_range(&self, index: usize, value: i32) -> AssembleError {
+        self.error(index, None, format!("value {} out of range", value))
+    }
+
+    // An error at the `index`th statement, see `SourceLine::error`
+    fn error(&self, index: usize, text: Option<&str>, reason: String) -> AssembleError {
+        self.source[self.line_numbers[index]].error(text, reason)
     }
 }
 
-fn label_to_relative_or_absolute(opcode: &str, curr_addr: u16, label_addr: u16) -> AddrMode {
-    let relative_opcodes: Vec<&str> = vec!["BCC", "BCS", "BEQ", "BMI", "BNE", "BPL", "BVC", "BVS"];
-    if relative_opcodes.contains(&opcode) {
-        let relative_addr: i8 = (label_addr as i32 - curr_addr as i32) as i8;
-        AddrMode::Relative(relative_addr)
+// Expand `.include` lines, recursively
+fn include_files(lines: Vec<SourceLine>, depth: usize) -> Result<Vec<SourceLine>, AssembleError> {
+    lazy_static! {
+        static ref INCLUDE_RE: Regex = Regex::new(r#"(?i)^\.include +"([^"]+)"$"#).unwrap();
+    }
+    let mut result = vec![];
+    for l in lines {
+        let path = match INCLUDE_RE.captures(l.code().trim()) {
+            Some(cap) => PathBuf::from(&cap[1]),
+            None => {
+                result.push(l);
+                continue;
+            }
+        };
+        if depth == MAX_INCLUDE_DEPTH {
+            return Err(l.error(None, "includes are nested too deep".to_string()));
+        }
+        let path = match l.file.as_ref().and_then(|f| f.parent()) {
+            Some(dir) => dir.join(path),
+            None => path,
+        };
+        let source = fs::read_to_string(&path)
+            .map_err(|e| l.error(None, format!("cannot read {}: {}", path.display(), e)))?;
+        let included = source
+            .lines()
+            .enumerate()
+            .map(|(line, text)| SourceLine {
+                file: Some(path.clone()),
+                line,
+                text: text.to_string(),
+            })
+            .collect();
+        result.extend(include_files(included, depth + 1)?);
+    }
+    Ok(result)
+}
+
+// The code of a line, a ; in quotes does not start a comment
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match quote {
+            None if c == ';' => return &line[..i],
+            None if c == '\'' || c == '"' => quote = Some(c),
+            Some(q) if c == q => quote = None,
+            _ => {}
+        }
+    }
+    line
+}
+
+fn is_branch(opcode: &str) -> bool {
+    ENCODINGS
+        .get(opcode.trim_start_matches('*'))
+        .is_some_and(|modes| modes.contains_key(&SpecMode::Relative))
+}
+
+fn label_to_relative_or_absolute(
+    opcode: &str,
+    curr_addr: u16,
+    label_addr: u16,
+) -> Option<AddrMode> {
+    if is_branch(opcode) {
+        let relative_addr = i8::try_from(label_addr as i32 - curr_addr as i32).ok()?;
+        Some(AddrMode::Relative(relative_addr))
     } else {
-        AddrMode::Absolute(label_addr)
+        Some(AddrMode::Absolute(label_addr))
     }
 }
 
@@ -119,346 +583,56 @@ enum Statement {
     Define { name: String, value: String },
     Label { name: String },
     Instruction { opcode: String, addr_mode: AddrMode },
+    Org { addr: u16 },
+    // `name = value`, a label with a value instead of an address
+    Constant { name: String, value: Expr },
+    // .byte (size 1) and .word (size 2)
+    Data { size: u8, values: Vec<Expr> },
 }
 
 impl Statement {
-    fn assemble(&self) -> Vec<u8> {
-        use AddrMode::*;
-
-        fn panic_addr_mode_not_supported(opcode: &str, addr_mode: &AddrMode) -> ! {
-            panic!("{} does not support addr mode {:?}", opcode, addr_mode)
+    // the reason is returned if the instruction does not exist
+    fn assemble(&self) -> Result<Vec<u8>, String> {
+        fn addr_mode_not_supported(opcode: &str, addr_mode: &AddrMode) -> String {
+            format!(
+                "{} does not support {} addressing",
+                opcode,
+                addr_mode.name()
+            )
         }
 
         match &self {
-            Statement::Define { .. } => vec![],
-            Statement::Label { .. } => vec![],
+            Statement::Define { .. } => Ok(vec![]),
+            Statement::Label { .. } => Ok(vec![]),
+            // emitted by the assembler, which knows the label addresses
+            Statement::Org { .. } => Ok(vec![]),
+            Statement::Constant { .. } => Ok(vec![]),
+            Statement::Data { .. } => Ok(vec![]),
             Statement::Instruction { opcode, addr_mode } => {
-                // Ref: http://www.obelisk.me.uk/6502/reference.html
-                let asm_opcode: u8 = match &opcode.to_uppercase()[..] {
-                    opcode @ "ADC" => match addr_mode {
-                        Immediate(_) => 0x69,
-                        ZeroPage(_) => 0x65,
-                        ZeroPageX(_) => 0x75,
-                        Absolute(_) => 0x6D,
-                        AbsoluteX(_) => 0x7D,
-                        AbsoluteY(_) => 0x79,
-                        IndexedIndirect(_) => 0x61,
-                        IndirectIndexed(_) => 0x71,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "AND" => match addr_mode {
-                        Immediate(_) => 0x29,
-                        ZeroPage(_) => 0x25,
-                        ZeroPageX(_) => 0x35,
-                        Absolute(_) => 0x2D,
-                        AbsoluteX(_) => 0x3D,
-                        AbsoluteY(_) => 0x39,
-                        IndexedIndirect(_) => 0x21,
-                        IndirectIndexed(_) => 0x31,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "ASL" => match addr_mode {
-                        Implicit => 0x0A,
-                        ZeroPage(_) => 0x06,
-                        ZeroPageX(_) => 0x16,
-                        Absolute(_) => 0x0E,
-                        AbsoluteX(_) => 0x1E,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "BCC" => match addr_mode {
-                        Relative(_) => 0x90,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "BCS" => match addr_mode {
-                        Relative(_) => 0xB0,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "BEQ" => match addr_mode {
-                        Relative(_) => 0xF0,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "BIT" => match addr_mode {
-                        ZeroPage(_) => 0x24,
-                        Absolute(_) => 0x2C,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "BMI" => match addr_mode {
-                        Relative(_) => 0x30,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "BNE" => match addr_mode {
-                        Relative(_) => 0xD0,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "BPL" => match addr_mode {
-                        Relative(_) => 0x10,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "BRK" => match addr_mode {
-                        Implicit => 0x00,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "BVC" => match addr_mode {
-                        Relative(_) => 0x50,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "BVS" => match addr_mode {
-                        Relative(_) => 0x70,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "CLC" => match addr_mode {
-                        Implicit => 0x18,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "CLD" => match addr_mode {
-                        Implicit => 0xD8,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "CLI" => match addr_mode {
-                        Implicit => 0x58,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "CLV" => match addr_mode {
-                        Implicit => 0xB8,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "CMP" => match addr_mode {
-                        Immediate(_) => 0xC9,
-                        ZeroPage(_) => 0xC5,
-                        ZeroPageX(_) => 0xD5,
-                        Absolute(_) => 0xCD,
-                        AbsoluteX(_) => 0xDD,
-                        AbsoluteY(_) => 0xD9,
-                        IndexedIndirect(_) => 0xC1,
-                        IndirectIndexed(_) => 0xD1,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "CPX" => match addr_mode {
-                        Immediate(_) => 0xE0,
-                        ZeroPage(_) => 0xE4,
-                        Absolute(_) => 0xEC,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "CPY" => match addr_mode {
-                        Immediate(_) => 0xC0,
-                        ZeroPage(_) => 0xC4,
-                        Absolute(_) => 0xCC,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "DEC" => match addr_mode {
-                        ZeroPage(_) => 0xC6,
-                        ZeroPageX(_) => 0xD6,
-                        Absolute(_) => 0xCE,
-                        AbsoluteX(_) => 0xDE,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "DEX" => match addr_mode {
-                        Implicit => 0xCA,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "DEY" => match addr_mode {
-                        Implicit => 0x88,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "EOR" => match addr_mode {
-                        Immediate(_) => 0x49,
-                        ZeroPage(_) => 0x45,
-                        ZeroPageX(_) => 0x55,
-                        Absolute(_) => 0x4D,
-                        AbsoluteX(_) => 0x5D,
-                        AbsoluteY(_) => 0x59,
-                        IndexedIndirect(_) => 0x41,
-                        IndirectIndexed(_) => 0x51,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "INC" => match addr_mode {
-                        ZeroPage(_) => 0xE6,
-                        ZeroPageX(_) => 0xF6,
-                        Absolute(_) => 0xEE,
-                        AbsoluteX(_) => 0xFE,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "INX" => match addr_mode {
-                        Implicit => 0xE8,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "INY" => match addr_mode {
-                        Implicit => 0xC8,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "JMP" => match addr_mode {
-                        Absolute(_) => 0x4C,
-                        Indirect(_) => 0x6C,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "JSR" => match addr_mode {
-                        Absolute(_) => 0x20,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "LDA" => match addr_mode {
-                        Immediate(_) => 0xA9,
-                        ZeroPage(_) => 0xA5,
-                        ZeroPageX(_) => 0xB5,
-                        Absolute(_) => 0xAD,
-                        AbsoluteX(_) => 0xBD,
-                        AbsoluteY(_) => 0xB9,
-                        IndexedIndirect(_) => 0xA1,
-                        IndirectIndexed(_) => 0xB1,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "LDX" => match addr_mode {
-                        Immediate(_) => 0xA2,
-                        ZeroPage(_) => 0xA6,
-                        ZeroPageY(_) => 0xB6,
-                        Absolute(_) => 0xAE,
-                        AbsoluteY(_) => 0xBE,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "LDY" => match addr_mode {
-                        Immediate(_) => 0xA0,
-                        ZeroPage(_) => 0xA4,
-                        ZeroPageY(_) => 0xB4,
-                        Absolute(_) => 0xAC,
-                        AbsoluteY(_) => 0xBC,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "LSR" => match addr_mode {
-                        Implicit => 0x4A,
-                        ZeroPage(_) => 0x46,
-                        ZeroPageX(_) => 0x56,
-                        Absolute(_) => 0x4E,
-                        AbsoluteX(_) => 0x5E,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "NOP" => match addr_mode {
-                        Implicit => 0xEA,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "ORA" => match addr_mode {
-                        Immediate(_) => 0x09,
-                        ZeroPage(_) => 0x05,
-                        ZeroPageX(_) => 0x15,
-                        Absolute(_) => 0x0D,
-                        AbsoluteX(_) => 0x1D,
-                        AbsoluteY(_) => 0x19,
-                        IndexedIndirect(_) => 0x01,
-                        IndirectIndexed(_) => 0x11,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "PHA" => match addr_mode {
-                        Implicit => 0x48,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "PHP" => match addr_mode {
-                        Implicit => 0x08,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "PLA" => match addr_mode {
-                        Implicit => 0x68,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "PLP" => match addr_mode {
-                        Implicit => 0x28,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "ROL" => match addr_mode {
-                        Implicit => 0x2A,
-                        ZeroPage(_) => 0x26,
-                        ZeroPageX(_) => 0x36,
-                        Absolute(_) => 0x2E,
-                        AbsoluteX(_) => 0x3E,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "ROR" => match addr_mode {
-                        Implicit => 0x6A,
-                        ZeroPage(_) => 0x66,
-                        ZeroPageX(_) => 0x76,
-                        Absolute(_) => 0x6E,
-                        AbsoluteX(_) => 0x7E,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "RTI" => match addr_mode {
-                        Implicit => 0x40,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "RTS" => match addr_mode {
-                        Implicit => 0x60,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "SBC" => match addr_mode {
-                        Immediate(_) => 0xE9,
-                        ZeroPage(_) => 0xE5,
-                        ZeroPageX(_) => 0xF5,
-                        Absolute(_) => 0xED,
-                        AbsoluteX(_) => 0xFD,
-                        AbsoluteY(_) => 0xF9,
-                        IndexedIndirect(_) => 0xE1,
-                        IndirectIndexed(_) => 0xF1,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "SEC" => match addr_mode {
-                        Implicit => 0x38,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "SED" => match addr_mode {
-                        Implicit => 0xF8,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "SEI" => match addr_mode {
-                        Implicit => 0x78,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "STA" => match addr_mode {
-                        ZeroPage(_) => 0x85,
-                        ZeroPageX(_) => 0x95,
-                        Absolute(_) => 0x8D,
-                        AbsoluteX(_) => 0x9D,
-                        AbsoluteY(_) => 0x99,
-                        IndexedIndirect(_) => 0x81,
-                        IndirectIndexed(_) => 0x91,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "STX" => match addr_mode {
-                        ZeroPage(_) => 0x86,
-                        ZeroPageY(_) => 0x96,
-                        Absolute(_) => 0x8E,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "STY" => match addr_mode {
-                        ZeroPage(_) => 0x84,
-                        ZeroPageY(_) => 0x94,
-                        Absolute(_) => 0x8C,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "TAX" => match addr_mode {
-                        Implicit => 0xAA,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "TAY" => match addr_mode {
-                        Implicit => 0xA8,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "TSX" => match addr_mode {
-                        Implicit => 0xBA,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "TXA" => match addr_mode {
-                        Implicit => 0x8A,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "TXS" => match addr_mode {
-                        Implicit => 0x9A,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ "TYA" => match addr_mode {
-                        Implicit => 0x98,
-                        _ => panic_addr_mode_not_supported(opcode, addr_mode),
-                    },
-                    opcode @ _ => panic!("opcode unrecognized: {}", opcode),
+                // a leading * picks an unofficial opcode, like the
+                // disassembler shows them
+                let (mnemonic, unofficial) = match opcode.strip_prefix('*') {
+                    Some(mnemonic) => (mnemonic.to_uppercase(), true),
+                    None => (opcode.to_uppercase(), false),
+                };
+                let modes = match ENCODINGS.get(&mnemonic) {
+                    Some(modes) => modes,
+                    None => return Err(format!("unknown opcode {}", mnemonic)),
                 };
+                let encoding = match addr_mode.spec_mode().and_then(|mode| modes.get(&mode)) {
+                    Some(encoding) => encoding,
+                    None => return Err(addr_mode_not_supported(&mnemonic, addr_mode)),
+                };
+                let asm_opcode = if unofficial {
+                    encoding.unofficial.or(encoding.official)
+                } else {
+                    encoding.official.or(encoding.unofficial)
+                };
+                // every encoding has one of the two
+                let asm_opcode = asm_opcode.unwrap();
                 let mut asm: Vec<u8> = vec![asm_opcode];
                 asm.extend(&addr_mode.assemble());
-                asm
+                Ok(asm)
             }
         }
     }
@@ -466,30 +640,18 @@ impl Statement {
 
 fn instruction_size(opcode: &str, addr_mode: &AddrMode) -> u8 {
     match addr_mode {
-        AddrMode::Absolute(_) => 3,
-        AddrMode::AbsoluteX(_) => 3,
-        AddrMode::AbsoluteY(_) => 3,
-        AddrMode::ZeroPage(_) => 2,
-        AddrMode::ZeroPageX(_) => 2,
-        AddrMode::ZeroPageY(_) => 2,
-        AddrMode::Immediate(_) => 2,
-        AddrMode::Relative(_) => 2,
         AddrMode::RelativeLabel(_) => {
-            let relative_opcodes: Vec<&str> =
-                vec!["BCC", "BCS", "BEQ", "BMI", "BNE", "BPL", "BVC", "BVS"];
-            if relative_opcodes
-                .iter()
-                .any(|&op| opcode.to_uppercase() == op)
-            {
+            if is_branch(opcode) {
                 2
             } else {
                 3
             }
         }
-        AddrMode::Implicit => 1,
-        AddrMode::Indirect(_) => 3,
-        AddrMode::IndexedIndirect(_) => 2,
-        AddrMode::IndirectIndexed(_) => 2,
+        AddrMode::Symbolic(mode, _) => match mode.as_ref() {
+            AddrMode::Absolute(_) if is_branch(opcode) => 2,
+            mode => instruction_size(opcode, mode),
+        },
+        mode => 1 + mode.spec_mode().map_or(0, |mode| mode.size()),
     }
 }
 
@@ -497,7 +659,11 @@ fn parse_statement(s: &str) -> Option<Statement> {
     lazy_static! {
         static ref DEFINE_RE: Regex = Regex::new(r"(?i)^define +([^ ]+) +([^ ]+)").unwrap();
         static ref LABEL_RE: Regex = Regex::new(r"(?i)^([^ :]+):$").unwrap();
-        static ref INSTRUCTION_RE: Regex = Regex::new(r"(?i)^([a-z]{3}) *([^ ]*)$").unwrap();
+        static ref INSTRUCTION_RE: Regex = Regex::new(r"(?i)^(\*?[a-z]{3})(?: +(.*))?$").unwrap();
+        static ref ORG_RE: Regex = Regex::new(r"(?i)^\.org +([^ ]+)$").unwrap();
+        static ref DATA_RE: Regex = Regex::new(r"(?i)^\.(byte|word) +(.+)$").unwrap();
+        static ref CONSTANT_RE: Regex =
+            Regex::new(r"(?i)^([a-z_][a-z0-9_]*) *= *([^ ]+)$").unwrap();
     }
     if let Some(cap) = DEFINE_RE.captures_iter(s).next() {
         Some(Statement::Define {
@@ -508,9 +674,37 @@ fn parse_statement(s: &str) -> Option<Statement> {
         Some(Statement::Label {
             name: String::from(&cap[1]),
         })
+    } else if let Some(cap) = ORG_RE.captures_iter(s).next() {
+        let addr = u16::try_from(Expr::parse(&cap[1])?.constant()?).ok()?;
+        Some(Statement::Org { addr })
+    } else if let Some(cap) = DATA_RE.captures_iter(s).next() {
+        let size = if cap[1].eq_ignore_ascii_case("byte") {
+            1
+        } else {
+            2
+        };
+        let values = cap[2]
+            .split(',')
+            .map(|v| Expr::parse(v.trim()))
+            .collect::<Option<Vec<Expr>>>()?;
+        Some(Statement::Data { size, values })
+    } else if let Some(cap) = CONSTANT_RE.captures_iter(s).next() {
+        Some(Statement::Constant {
+            name: String::from(&cap[1]),
+            value: Expr::parse(&cap[2])?,
+        })
     } else if let Some(cap) = INSTRUCTION_RE.captures_iter(s).next() {
-        let opcode = String::from(&cap[1]);
-        match parse_addr_mode(&cap[2]) {
+        let opcode = cap[1].to_uppercase();
+        let operand = cap.get(2).map_or("", |m| m.as_str());
+        // `A` is the accumulator for the shifts, which encode it as implied
+        let accumulator = ["ASL", "LSR", "ROL", "ROR"].contains(&opcode.trim_start_matches('*'));
+        if accumulator && operand.eq_ignore_ascii_case("a") {
+            return Some(Statement::Instruction {
+                opcode,
+                addr_mode: AddrMode::Implicit,
+            });
+        }
+        match parse_addr_mode(operand) {
             Some(mode) => Some(Statement::Instruction {
                 opcode: opcode,
                 addr_mode: mode,
@@ -523,7 +717,7 @@ fn parse_statement(s: &str) -> Option<Statement> {
 }
 
 #[allow(dead_code)]
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 enum AddrMode {
     Absolute(u16),
     AbsoluteX(u16),
@@ -538,9 +732,95 @@ enum AddrMode {
     Indirect(u16),
     IndexedIndirect(u8),
     IndirectIndexed(u8),
+    // An operand with labels in it, replaced by the mode with the value of
+    // the expression once the labels are known
+    Symbolic(Box<AddrMode>, Expr),
 }
 
 impl AddrMode {
+    // The mode in the opcode table, None until labels are resolved
+    fn spec_mode(&self) -> Option<SpecMode> {
+        match self {
+            AddrMode::Absolute(_) => Some(SpecMode::Absolute),
+            AddrMode::AbsoluteX(_) => Some(SpecMode::AbsoluteX),
+            AddrMode::AbsoluteY(_) => Some(SpecMode::AbsoluteY),
+            AddrMode::ZeroPage(_) => Some(SpecMode::ZeroPage),
+            AddrMode::ZeroPageX(_) => Some(SpecMode::ZeroPageX),
+            AddrMode::ZeroPageY(_) => Some(SpecMode::ZeroPageY),
+            AddrMode::Immediate(_) => Some(SpecMode::Immediate),
+            AddrMode::Relative(_) => Some(SpecMode::Relative),
+            AddrMode::Implicit => Some(SpecMode::Implicit),
+            AddrMode::Indirect(_) => Some(SpecMode::Indirect),
+            AddrMode::IndexedIndirect(_) => Some(SpecMode::IndexedIndirect),
+            AddrMode::IndirectIndexed(_) => Some(SpecMode::IndirectIndexed),
+            AddrMode::RelativeLabel(_) | AddrMode::Symbolic(..) => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            AddrMode::Absolute(_) => "absolute",
+            AddrMode::AbsoluteX(_) => "absolute,X",
+            AddrMode::AbsoluteY(_) => "absolute,Y",
+            AddrMode::ZeroPage(_) => "zero page",
+            AddrMode::ZeroPageX(_) => "zero page,X",
+            AddrMode::ZeroPageY(_) => "zero page,Y",
+            AddrMode::Immediate(_) => "immediate",
+            AddrMode::Relative(_) | AddrMode::RelativeLabel(_) => "relative",
+            AddrMode::Implicit => "implied",
+            AddrMode::Indirect(_) => "indirect",
+            AddrMode::IndexedIndirect(_) => "(indirect,X)",
+            AddrMode::IndirectIndexed(_) => "(indirect),Y",
+            AddrMode::Symbolic(mode, _) => mode.name(),
+        }
+    }
+
+    // The zero page form of an absolute mode
+    fn zero_page(&self) -> Option<AddrMode> {
+        match self {
+            AddrMode::Absolute(a) => u8::try_from(*a).ok().map(AddrMode::ZeroPage),
+            AddrMode::AbsoluteX(a) => u8::try_from(*a).ok().map(AddrMode::ZeroPageX),
+            AddrMode::AbsoluteY(a) => u8::try_from(*a).ok().map(AddrMode::ZeroPageY),
+            _ => None,
+        }
+    }
+
+    // The absolute form of a zero page mode
+    fn absolute(&self) -> Option<AddrMode> {
+        match self {
+            AddrMode::ZeroPage(a) => Some(AddrMode::Absolute(*a as u16)),
+            AddrMode::ZeroPageX(a) => Some(AddrMode::AbsoluteX(*a as u16)),
+            AddrMode::ZeroPageY(a) => Some(AddrMode::AbsoluteY(*a as u16)),
+            _ => None,
+        }
+    }
+
+    // The same mode with another operand, None if it does not fit
+    fn with_value(&self, value: i32) -> Option<AddrMode> {
+        use AddrMode::*;
+
+        let byte = u8::try_from(value).ok();
+        let word = u16::try_from(value).ok();
+        match self {
+            Absolute(_) => word.map(Absolute),
+            AbsoluteX(_) => word.map(AbsoluteX),
+            AbsoluteY(_) => word.map(AbsoluteY),
+            ZeroPage(_) => byte.map(ZeroPage),
+            ZeroPageX(_) => byte.map(ZeroPageX),
+            ZeroPageY(_) => byte.map(ZeroPageY),
+            // negative immediates are allowed
+            Immediate(_) => i8::try_from(value)
+                .map(|b| b as u8)
+                .ok()
+                .or(byte)
+                .map(Immediate),
+            Indirect(_) => word.map(Indirect),
+            IndexedIndirect(_) => byte.map(IndexedIndirect),
+            IndirectIndexed(_) => byte.map(IndirectIndexed),
+            Relative(_) | RelativeLabel(_) | Implicit | Symbolic(..) => None,
+        }
+    }
+
     fn assemble(&self) -> Vec<u8> {
         fn to_little_endian_vec(a: u16) -> Vec<u8> {
             a.to_le_bytes().to_vec()
@@ -556,6 +836,7 @@ impl AddrMode {
             AddrMode::Immediate(a) => vec![*a],
             AddrMode::Relative(a) => vec![*a as u8],
             AddrMode::RelativeLabel(_) => panic!("cannot assemble relative mode with label"),
+            AddrMode::Symbolic(..) => panic!("cannot assemble an operand with labels"),
             AddrMode::Implicit => Vec::new(),
             AddrMode::Indirect(a) => to_little_endian_vec(*a),
             AddrMode::IndexedIndirect(a) => vec![*a],
@@ -575,7 +856,6 @@ fn parse_addr_mode(s: &str) -> Option<AddrMode> {
         static ref ZERO_PAGE_X_RE: Regex = Regex::new(r"(?i)^\$([0-9a-f]{2}), *x$").unwrap();
         static ref ZERO_PAGE_Y_RE: Regex = Regex::new(r"(?i)^\$([0-9a-f]{2}), *y$").unwrap();
         static ref IMMEDIATE_HEX_RE: Regex = Regex::new(r"(?i)^#\$([0-9a-f]{1,2})$").unwrap();
-        static ref IMMEDIATE_DEC_RE: Regex = Regex::new(r"(?i)^#([0-9a-f]{1,2})$").unwrap();
         static ref RELATIVE_RE: Regex = Regex::new(r"(?i)^\*([+-][0-9]{1,3})$").unwrap();
         static ref RELATIVE_LABEL_RE: Regex = Regex::new(r"(?i)^([a-z_]+)$").unwrap();
         static ref IMPLICIT_RE: Regex = Regex::new(r"(?i)^$").unwrap();
@@ -599,10 +879,8 @@ fn parse_addr_mode(s: &str) -> Option<AddrMode> {
         Some(ZeroPageY(u8::from_str_radix(&cap[1], 16).unwrap()))
     } else if let Some(cap) = IMMEDIATE_HEX_RE.captures_iter(s).next() {
         Some(Immediate(u8::from_str_radix(&cap[1], 16).unwrap()))
-    } else if let Some(cap) = IMMEDIATE_DEC_RE.captures_iter(s).next() {
-        Some(Immediate(i8::from_str_radix(&cap[1], 16).unwrap() as u8))
     } else if let Some(cap) = RELATIVE_RE.captures_iter(s).next() {
-        Some(Relative(i8::from_str_radix(&cap[1], 10).unwrap()))
+        Some(Relative(i8::from_str_radix(&cap[1], 10).ok()?))
     } else if let Some(cap) = RELATIVE_LABEL_RE.captures_iter(s).next() {
         Some(RelativeLabel(String::from(&cap[1])))
     } else if IMPLICIT_RE.is_match(s) {
@@ -614,7 +892,172 @@ fn parse_addr_mode(s: &str) -> Option<AddrMode> {
     } else if let Some(cap) = INDIRECT_INDEXED_RE.captures_iter(s).next() {
         Some(IndirectIndexed(u8::from_str_radix(&cap[1], 16).unwrap()))
     } else {
-        None
+        parse_expression_addr_mode(s)
+    }
+}
+
+// Operands with expressions. Zero page is used when the value is known to
+// fit in a byte.
+fn parse_expression_addr_mode(s: &str) -> Option<AddrMode> {
+    use AddrMode::*;
+
+    lazy_static! {
+        static ref IMMEDIATE_RE: Regex = Regex::new(r"(?i)^#(.+)$").unwrap();
+        static ref INDEXED_INDIRECT_RE: Regex = Regex::new(r"(?i)^\((.+), *x\)$").unwrap();
+        static ref INDIRECT_INDEXED_RE: Regex = Regex::new(r"(?i)^\((.+)\), *y$").unwrap();
+        static ref INDIRECT_RE: Regex = Regex::new(r"(?i)^\((.+)\)$").unwrap();
+        static ref X_RE: Regex = Regex::new(r"(?i)^(.+), *x$").unwrap();
+        static ref Y_RE: Regex = Regex::new(r"(?i)^(.+), *y$").unwrap();
+    }
+    let (expr, mode) = if let Some(cap) = IMMEDIATE_RE.captures(s) {
+        (Expr::parse(&cap[1])?, Immediate(0))
+    } else if let Some(cap) = INDEXED_INDIRECT_RE.captures(s) {
+        (Expr::parse(&cap[1])?, IndexedIndirect(0))
+    } else if let Some(cap) = INDIRECT_INDEXED_RE.captures(s) {
+        (Expr::parse(&cap[1])?, IndirectIndexed(0))
+    } else if let Some(cap) = INDIRECT_RE.captures(s) {
+        (Expr::parse(&cap[1])?, Indirect(0))
+    } else if let Some(cap) = X_RE.captures(s) {
+        let expr = Expr::parse(&cap[1])?;
+        let mode = if expr.is_byte() {
+            ZeroPageX(0)
+        } else {
+            AbsoluteX(0)
+        };
+        (expr, mode)
+    } else if let Some(cap) = Y_RE.captures(s) {
+        let expr = Expr::parse(&cap[1])?;
+        let mode = if expr.is_byte() {
+            ZeroPageY(0)
+        } else {
+            AbsoluteY(0)
+        };
+        (expr, mode)
+    } else {
+        let expr = Expr::parse(s)?;
+        let mode = if expr.is_byte() {
+            ZeroPage(0)
+        } else {
+            Absolute(0)
+        };
+        (expr, mode)
+    };
+    match expr.constant() {
+        Some(value) => mode.with_value(value),
+        None => Some(Symbolic(Box::new(mode), expr)),
+    }
+}
+
+// A sum of numbers and labels, optionally narrowed to its low or high
+// byte
+#[derive(Debug, Clone, PartialEq)]
+struct Expr {
+    select: Select,
+    // each term is added or subtracted
+    terms: Vec<(bool, Term)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Select {
+    Word,
+    Low,
+    High,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Term {
+    Number(i32),
+    Label(String),
+}
+
+impl Expr {
+    fn label(name: &str) -> Expr {
+        Expr {
+            select: Select::Word,
+            terms: vec![(false, Term::Label(name.to_string()))],
+        }
+    }
+
+    fn number(value: i32) -> Expr {
+        Expr {
+            select: Select::Word,
+            terms: vec![(false, Term::Number(value))],
+        }
+    }
+
+    fn parse(s: &str) -> Option<Expr> {
+        lazy_static! {
+            static ref TERM_RE: Regex =
+                Regex::new(r"(?i)^([+-]?)('.'|\$[0-9a-f]+|%[01]+|[0-9]+|[a-z_][a-z0-9_]*)")
+                    .unwrap();
+        }
+        let (select, mut rest) = match s.chars().next()? {
+            '<' => (Select::Low, &s[1..]),
+            '>' => (Select::High, &s[1..]),
+            _ => (Select::Word, s),
+        };
+        let mut terms = vec![];
+        while !rest.is_empty() {
+            let cap = TERM_RE.captures(rest)?;
+            // terms after the first need an operator
+            if !terms.is_empty() && cap[1].is_empty() {
+                return None;
+            }
+            let token = &cap[2];
+            let term = if let Some(c) = token.strip_prefix('\'') {
+                Term::Number(c.chars().next()? as i32)
+            } else if let Some(hex) = token.strip_prefix('$') {
+                Term::Number(i32::from_str_radix(hex, 16).ok()?)
+            } else if let Some(binary) = token.strip_prefix('%') {
+                Term::Number(i32::from_str_radix(binary, 2).ok()?)
+            } else if token.starts_with(|c: char| c.is_ascii_digit()) {
+                Term::Number(token.parse().ok()?)
+            } else {
+                Term::Label(token.to_string())
+            };
+            terms.push((&cap[1] == "-", term));
+            rest = &rest[cap[0].len()..];
+        }
+        if terms.is_empty() {
+            return None;
+        }
+        Some(Expr { select, terms })
+    }
+
+    // The value, or the first label not in `labels`
+    fn eval(&self, labels: &HashMap<String, u16>) -> Result<i32, String> {
+        let mut value: i32 = 0;
+        for (negative, term) in &self.terms {
+            let v = match term {
+                Term::Number(n) => *n,
+                Term::Label(label) => match labels.get(label) {
+                    Some(addr) => *addr as i32,
+                    None => return Err(label.to_string()),
+                },
+            };
+            value = if *negative {
+                value.wrapping_sub(v)
+            } else {
+                value.wrapping_add(v)
+            };
+        }
+        Ok(match self.select {
+            Select::Word => value,
+            Select::Low => value & 0xFF,
+            Select::High => (value >> 8) & 0xFF,
+        })
+    }
+
+    // The value if there are no labels
+    fn constant(&self) -> Option<i32> {
+        self.eval(&HashMap::new()).ok()
+    }
+
+    fn is_byte(&self) -> bool {
+        match self.constant() {
+            Some(value) => (0..=0xFF).contains(&value),
+            None => self.select != Select::Word,
+        }
     }
 }
 
@@ -628,11 +1071,47 @@ mod tests {
             "  ldy #$01".to_string(),
             "  ;;; a comment".to_string(),
             "  Lda #$03 ; a comment".to_string(),
+            "  cmp #';' ; not a comment".to_string(),
         ]);
         assembler.pre_process();
         assert_eq!(
             assembler.lines,
-            vec!["LDY #$01".to_string(), "LDA #$03".to_string()]
+            vec![
+                "ldy #$01".to_string(),
+                "Lda #$03".to_string(),
+                "cmp #';'".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_literals() {
+        assert_eq!(assemble("lda #10").unwrap(), [0xA9, 10]);
+        assert_eq!(assemble("lda #255").unwrap(), [0xA9, 0xFF]);
+        assert_eq!(assemble("lda #-1").unwrap(), [0xA9, 0xFF]);
+        assert_eq!(assemble("lda #%10101010").unwrap(), [0xA9, 0xAA]);
+        assert_eq!(assemble("lda #'a'").unwrap(), [0xA9, 0x61]);
+        assert_eq!(assemble("cmp #' '").unwrap(), [0xC9, 0x20]);
+        assert_eq!(assemble(".byte 'A', 'z'").unwrap(), [0x41, 0x7A]);
+        assert!(assemble("lda #256").is_err());
+
+        // labels are case sensitive, mnemonics and registers are not
+        let code = "
+            Loop = $10
+            loop = $20
+            LDA Loop,x
+            sta loop,X
+            Jmp Done
+            Done:
+        ";
+        assert_eq!(
+            assemble_with_start_addr(code, 0x8000).unwrap(),
+            [0xB5, 0x10, 0x95, 0x20, 0x4C, 0x07, 0x80]
+        );
+        let e = assemble("jmp done\nDone:").unwrap_err();
+        assert_eq!(
+            (e.text.as_str(), e.reason.as_str()),
+            ("done", "undefined label")
         );
     }
 
@@ -792,7 +1271,7 @@ mod tests {
         for (c, s, e) in izip!(codes, statements, expected) {
             assert_eq!(
                 s.assemble(),
-                e,
+                Ok(e),
                 "{} was assembled wrong, statement is {:?}",
                 c,
                 s
@@ -830,283 +1309,183 @@ mod tests {
     }
 
     #[test]
-    fn test_assemble_snake_program() {
-        let code = r"
-        ;  ___           _        __ ___  __ ___
-        ; / __|_ _  __ _| |_____ / /| __|/  \_  )
-        ; \__ \ ' \/ _` | / / -_) _ \__ \ () / /
-        ; |___/_||_\__,_|_\_\___\___/___/\__/___|
-        
-        ; Change direction: W A S D
-        
-        define appleL         $00 ; screen location of apple, low byte
-        define appleH         $01 ; screen location of apple, high byte
-        define snakeHeadL     $10 ; screen location of snake head, low byte
-        define snakeHeadH     $11 ; screen location of snake head, high byte
-        define snakeBodyStart $12 ; start of snake body byte pairs
-        define snakeDirection $02 ; direction (possible values are below)
-        define snakeLength    $03 ; snake length, in bytes
-        
-        ; Directions (each using a separate bit)
-        define movingUp      1
-        define movingRight   2
-        define movingDown    4
-        define movingLeft    8
-        
-        ; ASCII values of keys controlling the snake
-        define ASCII_w      $77
-        define ASCII_a      $61
-        define ASCII_s      $73
-        define ASCII_d      $64
-        
-        ; System variables
-        define sysRandom    $fe
-        define sysLastKey   $ff
-        
-        
-          jsr init
-          jsr loop
-        
-        init:
-          jsr initSnake
-          jsr generateApplePosition
-          rts
-        
-        
-        initSnake:
-          lda #movingRight  ;start direction
-          sta snakeDirection
-        
-          lda #4  ;start length (2 segments)
-          sta snakeLength
-          
-          lda #$11
-          sta snakeHeadL
-          
-          lda #$10
-          sta snakeBodyStart
-          
-          lda #$0f
-          sta $14 ; body segment 1
-          
-          lda #$04
-          sta snakeHeadH
-          sta $13 ; body segment 1
-          sta $15 ; body segment 2
-          rts
-        
-        
-        generateApplePosition:
-          ;load a new random byte into $00
-          lda sysRandom
-          sta appleL
-        
-          ;load a new random number from 2 to 5 into $01
-          lda sysRandom
-          and #$03 ;mask out lowest 2 bits
-          clc
-          adc #2
-          sta appleH
-        
-          rts
-        
-        
-        loop:
-          jsr readKeys
-          jsr checkCollision
-          jsr updateSnake
-          jsr drawApple
-          jsr drawSnake
-          jsr spinWheels
-          jmp loop
-        
-        
-        readKeys:
-          lda sysLastKey
-          cmp #ASCII_w
-          beq upKey
-          cmp #ASCII_d
-          beq rightKey
-          cmp #ASCII_s
-          beq downKey
-          cmp #ASCII_a
-          beq leftKey
-          rts
-        upKey:
-          lda #movingDown
-          bit snakeDirection
-          bne illegalMove
-        
-          lda #movingUp
-          sta snakeDirection
-          rts
-        rightKey:
-          lda #movingLeft
-          bit snakeDirection
-          bne illegalMove
-        
-          lda #movingRight
-          sta snakeDirection
-          rts
-        downKey:
-          lda #movingUp
-          bit snakeDirection
-          bne illegalMove
-        
-          lda #movingDown
-          sta snakeDirection
-          rts
-        leftKey:
-          lda #movingRight
-          bit snakeDirection
-          bne illegalMove
-        
-          lda #movingLeft
-          sta snakeDirection
-          rts
-        illegalMove:
-          rts
-        
-        
-        checkCollision:
-          jsr checkAppleCollision
-          jsr checkSnakeCollision
-          rts
-        
-        
-        checkAppleCollision:
-          lda appleL
-          cmp snakeHeadL
-          bne doneCheckingAppleCollision
-          lda appleH
-          cmp snakeHeadH
-          bne doneCheckingAppleCollision
-        
-          ;eat apple
-          inc snakeLength
-          inc snakeLength ;increase length
-          jsr generateApplePosition
-        doneCheckingAppleCollision:
-          rts
-        
-        
-        checkSnakeCollision:
-          ldx #2 ;start with second segment
-        snakeCollisionLoop:
-          lda snakeHeadL,x
-          cmp snakeHeadL
-          bne continueCollisionLoop
-        
-        maybeCollided:
-          lda snakeHeadH,x
-          cmp snakeHeadH
-          beq didCollide
-        
-        continueCollisionLoop:
-          inx
-          inx
-          cpx snakeLength          ;got to last section with no collision
-          beq didntCollide
-          jmp snakeCollisionLoop
-        
-        didCollide:
-          jmp gameOver
-        didntCollide:
-          rts
-        
-        
-        updateSnake:
-          ldx snakeLength
-          dex
-          txa
-        updateloop:
-          lda snakeHeadL,x
-          sta snakeBodyStart,x
-          dex
-          bpl updateloop
-        
-          lda snakeDirection
-          lsr
-          bcs up
-          lsr
-          bcs right
-          lsr
-          bcs down
-          lsr
-          bcs left
-        up:
-          lda snakeHeadL
-          sec
-          sbc #$20
-          sta snakeHeadL
-          bcc upup
-          rts
-        upup:
-          dec snakeHeadH
-          lda #$1
-          cmp snakeHeadH
-          beq collision
-          rts
-        right:
-          inc snakeHeadL
-          lda #$1f
-          bit snakeHeadL
-          beq collision
-          rts
-        down:
-          lda snakeHeadL
-          clc
-          adc #$20
-          sta snakeHeadL
-          bcs downdown
-          rts
-        downdown:
-          inc snakeHeadH
-          lda #$6
-          cmp snakeHeadH
-          beq collision
-          rts
-        left:
-          dec snakeHeadL
-          lda snakeHeadL
-          and #$1f
-          cmp #$1f
-          beq collision
-          rts
-        collision:
-          jmp gameOver
-        
-        
-        drawApple:
-          ldy #0
-          lda sysRandom
-          sta (appleL),y
-          rts
-        
-        
-        drawSnake:
-          ldx snakeLength
-          lda #0
-          sta (snakeHeadL,x) ; erase end of tail
-        
-          ldx #0
-          lda #1
-          sta (snakeHeadL,x) ; paint head
-          rts
-        
-        
-        spinWheels:
-          ldx #0
-        spinloop:
-          nop
-          nop
-          dex
-          bne spinloop
-          rts
-        
-        
-        gameOver:
+    fn test_assemble_errors() {
+        let error = |line, column, text: &str, reason: &str| AssembleError {
+            file: None,
+            line,
+            column,
+            text: text.to_string(),
+            reason: reason.to_string(),
+        };
+        assert_eq!(
+            assemble("LDA #$01\n  foo bar baz ; comment"),
+            Err(error(2, 3, "foo bar baz", "failed to parse statement"))
+        );
+        assert_eq!(
+            assemble("\n\n    XYZ #$01"),
+            Err(error(3, 5, "XYZ #$01", "unknown opcode XYZ"))
+        );
+        assert_eq!(
+            assemble("loop:\n  bne nowhere\n  bne loop"),
+            Err(error(2, 7, "nowhere", "undefined label"))
+        );
+        assert_eq!(
+            assemble("stx $c000,x"),
+            Err(error(
+                1,
+                1,
+                "stx $c000,x",
+                "STX does not support absolute,X addressing"
+            ))
+        );
+        let far = format!("bne far\n{}far:", "nop\n".repeat(200));
+        let err = assemble(&far).unwrap_err();
+        assert_eq!((err.line, err.column), (1, 5));
+        assert_eq!(
+            err.to_string(),
+            "line 1, column 5: branch target out of range: 'far'"
+        );
+    }
+
+    #[test]
+    fn test_assemble_directives() {
+        let dir = std::env::temp_dir().join(format!("nes-asm-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(
+            dir.join("main.asm"),
+            r#"
+            .org $8000
+          reset:
+            lda #<table
+            ldx #>table
+            lda table+1,x
+            sta $10+1,x
+            jmp (vector)
+            bne reset
+            .include "lib/a.asm"
+            .org $8012
+          table:
+            .byte $01, 2, %11, <reset, -1
+          vector:
+            .word reset, $1234
+            "#,
+        )
+        .unwrap();
+        // relative to the including file
+        fs::write(dir.join("lib/a.asm"), "nop\n.include \"b.asm\" ; more").unwrap();
+        fs::write(dir.join("lib/b.asm"), "inx").unwrap();
+
+        assert_eq!(
+            assemble_file(dir.join("main.asm"), 0x0600).unwrap(),
+            vec![
+                0xA9, 0x12, 0xA2, 0x80, 0xBD, 0x13, 0x80, 0x95, 0x11, 0x6C, 0x17, 0x80, 0xD0, 0xF2,
+                0xEA, 0xE8, 0x00, 0x00, 0x01, 0x02, 0x03, 0x00, 0xFF, 0x00, 0x80, 0x34, 0x12,
+            ]
+        );
+
+        fs::write(dir.join("lib/b.asm"), "\n  lda missing").unwrap();
+        let err = assemble_file(dir.join("main.asm"), 0x0600).unwrap_err();
+        assert_eq!(err.file, Some(dir.join("lib/b.asm")));
+        assert_eq!((err.line, err.column), (2, 7));
+        assert_eq!(err.reason, "undefined label");
+        fs::write(dir.join("lib/b.asm"), ".include \"b.asm\"").unwrap();
+        let err = assemble_file(dir.join("main.asm"), 0x0600).unwrap_err();
+        assert_eq!(err.reason, "includes are nested too deep");
+        fs::remove_dir_all(&dir).unwrap();
+
+        let err = assemble("nop\n.org $0500").unwrap_err();
+        assert_eq!(
+            (err.line, err.reason.as_str()),
+            (2, "origin is behind $0601")
+        );
+        let err = assemble(".byte 256").unwrap_err();
+        assert_eq!(err.reason, "value 256 out of range");
+        assert_eq!(assemble("lda #>x\nx:"), Ok(vec![0xA9, 0x06]));
+    }
+
+    #[test]
+    fn test_assemble_label_operands() {
+        let code = "
+          ptr = $10
+            lda ptr
+            sta ptr+1,x
+            ldx ptr,y
+            sta ptr,y    ; no zero page,Y form
+            lda table    ; defined below
+            lda table,x
+            jmp ptr
+            sta $10,y
+          table:
+            .byte 1
         ";
+        let expected_bytes_str = "
+            a5 10 95 11 b6 10 99 10 00 ad 15 06 bd 15 06 4c 10 00 99 10 00 01
+        ";
+        assert_code_assemble_to(code, expected_bytes_str);
+    }
+
+    #[test]
+    fn test_assemble_to_ines() {
+        use crate::bus::Bus;
+        use crate::cartridge::Cartridge;
+        use crate::cpu::CPU;
+
+        let code = "
+          reset:
+            lda #$42
+            sta $0200
+          loop:
+            jmp loop
+          nmi:
+            rti
+        ";
+        let config = InesConfig {
+            chr: vec![0x55; 100],
+            mirror: Mirror::Vertical,
+            nmi: Some("nmi".to_string()),
+            ..InesConfig::default()
+        };
+        let rom = assemble_to_ines(code, &config).unwrap();
+        assert_eq!(rom.len(), 16 + 16384 + 8192);
+        assert_eq!(rom[..8], [0x4E, 0x45, 0x53, 0x1A, 1, 1, 0x01, 0x00]);
+        // NMI, reset, no IRQ
+        assert_eq!(
+            rom[16 + 0x3FFA..16 + 0x4000],
+            [0x08, 0xC0, 0x00, 0xC0, 0, 0]
+        );
+
+        let cart = Cartridge::new(&rom).unwrap();
+        assert_eq!(cart.mirror, Mirror::Vertical);
+        let mut cpu = CPU::new(Bus::new(cart));
+        cpu.reset();
+        assert_eq!(cpu.pc, 0xC000);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.bus.cpu_read(0x0200), 0x42);
+        assert_eq!(cpu.bus.cart.borrow_mut().ppu_read(0x0000), Some(0x55));
+
+        let config = InesConfig {
+            prg_banks: 2,
+            mapper: 0x42,
+            ..InesConfig::default()
+        };
+        let rom = assemble_to_ines(".org $C000\nreset:", &config).unwrap();
+        assert_eq!(rom[4..8], [2, 0, 0x20, 0x40]);
+        assert_eq!(rom[16 + 0x7FFC..16 + 0x7FFE], [0x00, 0xC0]);
+
+        let err = assemble_to_ines("nop", &InesConfig::default()).unwrap_err();
+        assert_eq!(
+            (err.text.as_str(), err.reason.as_str()),
+            ("reset", "undefined label")
+        );
+        let err = assemble_to_ines(".org $0600\nreset:\nnop", &config).unwrap_err();
+        assert_eq!(err.reason, "code at $0600-$0600 is outside PRG ROM");
+    }
+
+    #[test]
+    fn test_assemble_snake_program() {
+        let code = include_str!("../../examples/snake.asm");
         let expected_bytes_str = r"
         20 06 06 20 38 06 20 0d 06 20 2a 06 60 a9 02 85 
         02 a9 04 85 03 a9 11 85 10 a9 10 85 12 a9 0f 85 
@@ -1146,9 +1525,53 @@ mod tests {
             .filter(|s| !s.is_empty())
             .map(|byte_str| u8::from_str_radix(byte_str.trim(), 16).unwrap())
             .collect();
-        let assembled_bytes = assembler.assemble(0x0600u16);
+        let assembled_bytes = assembler.assemble(0x0600u16).unwrap().bytes;
         println!("Expected: {:02X?}", expected_bytes);
         println!("Actual:   {:02X?}", assembled_bytes);
         assert_eq!(assembled_bytes, expected_bytes);
     }
+
+    #[test]
+    fn test_round_trip() {
+        use crate::cpu::disasm::DisasmInst;
+        use std::collections::BTreeMap;
+
+        let labels = BTreeMap::new();
+        let encodings = spec::encoding_table();
+        // xorshift, for operands that are the same on every run
+        let mut seed: u32 = 0x2A03;
+        let mut random = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as u8
+        };
+        for spec in spec::specs() {
+            let encoding = encodings[&spec.opcode.mnemonic()][&spec.addr_mode];
+            let canonical = encoding.official == Some(spec.opcode_byte)
+                || encoding.unofficial == Some(spec.opcode_byte);
+            for _ in 0..16 {
+                let mut bytes = vec![spec.opcode_byte];
+                bytes.extend((0..spec.addr_mode.size()).map(|_| random()));
+                let asm = DisasmInst::decode(&bytes, 0x8000).to_asm(&labels);
+                let assembled = assemble_with_start_addr(&asm, 0x8000)
+                    .unwrap_or_else(|e| panic!("{:02X?} {}: {}", bytes, asm, e));
+                assert_eq!(
+                    DisasmInst::decode(&assembled, 0x8000).to_asm(&labels),
+                    asm,
+                    "{:02X?}",
+                    bytes
+                );
+                if canonical {
+                    assert_eq!(assembled, bytes, "{}", asm);
+                }
+            }
+        }
+
+        // unofficial opcodes need the * when an official one does the same
+        assert_eq!(assemble("sbc #$10").unwrap(), [0xE9, 0x10]);
+        assert_eq!(assemble("*sbc #$10").unwrap(), [0xEB, 0x10]);
+        assert_eq!(assemble("lax $10").unwrap(), [0xA7, 0x10]);
+        assert_eq!(assemble("rol a").unwrap(), [0x2A]);
+    }
 }
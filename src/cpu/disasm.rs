@@ -0,0 +1,316 @@
+use std::collections::BTreeMap;
+
+use lazy_static::lazy_static;
+
+use super::addr::{AddrMode, Address};
+use super::spec::{self, Opcode, Spec};
+use crate::cdl;
+
+// Static disassembly of machine code, without a CPU. Unlike `CPU::trace`
+// nothing is read from memory, so operands show only what is encoded in
+// the instruction. Listings are a linear sweep: data between code is
+// disassembled as if it were code, unless a code/data log (`cdl`, a byte of
+// flags per byte of code) says it was only ever read as data.
+
+lazy_static! {
+    static ref OPCODE_TABLE: [Option<Spec>; 256] = spec::opcode_table();
+}
+
+const PRG_BANK_SIZE: usize = 0x4000;
+
+pub struct DisasmInst {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    // None for bytes that don't decode to an instruction, shown as data
+    pub spec: Option<Spec>,
+    pub operand: Address,
+}
+
+impl DisasmInst {
+    // Decode the instruction at the start of `bytes`, which is at `addr`
+    pub fn decode(bytes: &[u8], addr: u16) -> DisasmInst {
+        let data = DisasmInst::data(bytes[0], addr);
+        let spec = match OPCODE_TABLE[bytes[0] as usize] {
+            Some(spec) => spec,
+            None => return data,
+        };
+        let len = 1 + spec.addr_mode.size() as usize;
+        if bytes.len() < len {
+            // cut off at the end of the code
+            return data;
+        }
+        DisasmInst {
+            addr,
+            bytes: bytes[..len].to_vec(),
+            spec: Some(spec),
+            operand: spec.addr_mode.fetch(bytes[1..].iter()),
+        }
+    }
+
+    // A byte shown as data
+    pub fn data(byte: u8, addr: u16) -> DisasmInst {
+        DisasmInst {
+            addr,
+            bytes: vec![byte],
+            spec: None,
+            operand: Address::Implicit,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    // Where a branch, JMP or JSR goes. Indirect jumps are unknown.
+    pub fn target(&self) -> Option<u16> {
+        match (self.spec?.opcode, &self.operand) {
+            (_, Address::Relative(offset)) => Some(
+                self.addr
+                    .wrapping_add(self.size() as u16)
+                    .wrapping_add(*offset as u16),
+            ),
+            (Opcode::JMP | Opcode::JSR, Address::Absolute(addr)) => Some(*addr),
+            _ => None,
+        }
+    }
+
+    // Assembly text, with jump targets and the addresses operands refer to
+    // replaced by their label
+    pub fn to_asm(&self, labels: &BTreeMap<u16, String>) -> String {
+        let spec = match self.spec {
+            Some(spec) => spec,
+            None => return format!(".db ${:02X}", self.bytes[0]),
+        };
+        let mnemonic = format!(
+            "{}{:?}",
+            if spec.is_official { "" } else { "*" },
+            spec.opcode
+        );
+        if let Some(label) = self.target().and_then(|target| labels.get(&target)) {
+            return format!("{} {}", mnemonic, label);
+        }
+        let absolute = |addr: u16| match labels.get(&addr) {
+            Some(label) => label.clone(),
+            None => format!("${:04X}", addr),
+        };
+        let zero_page = |addr: u8| match labels.get(&(addr as u16)) {
+            Some(label) => label.clone(),
+            None => format!("${:02X}", addr),
+        };
+        let operand = match self.operand {
+            Address::Absolute(addr) => absolute(addr),
+            Address::AbsoluteX(addr) => format!("{},X", absolute(addr)),
+            Address::AbsoluteY(addr) => format!("{},Y", absolute(addr)),
+            Address::ZeroPage(addr) => zero_page(addr),
+            Address::ZeroPageX(addr) => format!("{},X", zero_page(addr)),
+            Address::ZeroPageY(addr) => format!("{},Y", zero_page(addr)),
+            Address::Immediate(value) => format!("#${:02X}", value),
+            Address::Relative(_) => format!("${:04X}", self.target().unwrap()),
+            Address::Implicit => match (spec.opcode, spec.addr_mode) {
+                (Opcode::ASL | Opcode::LSR | Opcode::ROL | Opcode::ROR, AddrMode::Implicit) => {
+                    "A".to_string()
+                }
+                _ => return mnemonic,
+            },
+            Address::Indirect(addr) => format!("({})", absolute(addr)),
+            Address::IndexedIndirect(addr) => format!("({},X)", zero_page(addr)),
+            Address::IndirectIndexed(addr) => format!("({}),Y", zero_page(addr)),
+        };
+        format!("{} {}", mnemonic, operand)
+    }
+}
+
+// Decode `code`, loaded at `base`, from start to end. Bytes `cdl` logged
+// as data but never as code are left as data.
+pub fn disassemble(code: &[u8], base: u16, cdl: Option<&[u8]>) -> Vec<DisasmInst> {
+    let is_data = |offset: usize| {
+        cdl.is_some_and(|cdl| {
+            cdl[offset] & cdl::CODE == 0 && cdl[offset] & (cdl::DATA | cdl::PCM) != 0
+        })
+    };
+    let mut insts = vec![];
+    let mut offset = 0;
+    while offset < code.len() {
+        let addr = base.wrapping_add(offset as u16);
+        let inst = if is_data(offset) {
+            DisasmInst::data(code[offset], addr)
+        } else {
+            DisasmInst::decode(&code[offset..], addr)
+        };
+        offset += inst.size();
+        insts.push(inst);
+    }
+    insts
+}
+
+// An annotated listing of `code` at `base`. Branch, JMP and JSR targets
+// inside the code get labels, `names` overrides the generated ones.
+pub fn listing(
+    code: &[u8],
+    base: u16,
+    names: &BTreeMap<u16, String>,
+    cdl: Option<&[u8]>,
+) -> String {
+    let insts = disassemble(code, base, cdl);
+    let end = base as usize + code.len();
+    let mut labels: BTreeMap<u16, String> = insts
+        .iter()
+        .filter_map(|inst| inst.target())
+        .filter(|&target| (base as usize..end).contains(&(target as usize)))
+        .map(|target| (target, format!("L{:04X}", target)))
+        .collect();
+    labels.extend(names.iter().map(|(addr, name)| (*addr, name.clone())));
+
+    let mut out = String::new();
+    for inst in insts.iter() {
+        if let Some(label) = labels.get(&inst.addr) {
+            out.push_str(label);
+            out.push_str(":\n");
+        }
+        let bytes: Vec<String> = inst.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        out.push_str(&format!(
+            "  {:04X}  {:8}  {}\n",
+            inst.addr,
+            bytes.join(" "),
+            inst.to_asm(&labels)
+        ));
+    }
+    out
+}
+
+// Listing of a whole PRG ROM, one 16K bank at a time. The last bank is
+// shown at $C000 where most mappers fix it, the others at $8000. A 32K ROM
+// is shown as a whole at $8000. The interrupt vectors label the handlers.
+// `names` are used in every bank, banks at $8000 share the addresses.
+// `cdl` has the flags of the PRG ROM bytes, from a code/data log.
+pub fn prg_listing(prg: &[u8], names: &BTreeMap<u16, String>, cdl: Option<&[u8]>) -> String {
+    if prg.len() < PRG_BANK_SIZE {
+        return listing(prg, 0x8000, names, cdl);
+    }
+    let bank_size = if prg.len() == 2 * PRG_BANK_SIZE {
+        prg.len()
+    } else {
+        PRG_BANK_SIZE
+    };
+    let last_base = (0x10000 - bank_size) as u16;
+    let banks: Vec<&[u8]> = prg.chunks(bank_size).collect();
+
+    let last = banks[banks.len() - 1];
+    let vector = |addr: usize| {
+        let offset = addr - last_base as usize;
+        u16::from_le_bytes([last[offset], last[offset + 1]])
+    };
+    let mut last_names = BTreeMap::new();
+    // handlers may be shared, NMI and RESET win over IRQ
+    last_names.insert(vector(0xFFFE), "IRQ".to_string());
+    last_names.insert(vector(0xFFFA), "NMI".to_string());
+    last_names.insert(vector(0xFFFC), "RESET".to_string());
+    last_names.extend(names.iter().map(|(addr, name)| (*addr, name.clone())));
+
+    let mut out = String::new();
+    for (i, bank) in banks.iter().enumerate() {
+        let is_last = i == banks.len() - 1;
+        let base = if is_last { last_base } else { 0x8000 };
+        if banks.len() > 1 {
+            out.push_str(&format!("; bank {}\n", i));
+        }
+        out.push_str(&listing(
+            bank,
+            base,
+            if is_last { &last_names } else { names },
+            cdl.map(|cdl| &cdl[i * bank_size..i * bank_size + bank.len()]),
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::assembler::assemble_with_start_addr;
+
+    #[test]
+    fn test_decode() {
+        let labels = BTreeMap::new();
+        let asm = |bytes: &[u8]| DisasmInst::decode(bytes, 0x8000).to_asm(&labels);
+        assert_eq!(asm(&[0xA9, 0x10]), "LDA #$10");
+        assert_eq!(asm(&[0xBD, 0x34, 0x12]), "LDA $1234,X");
+        assert_eq!(asm(&[0xB1, 0x20]), "LDA ($20),Y");
+        assert_eq!(asm(&[0x6C, 0xFC, 0xFF]), "JMP ($FFFC)");
+        assert_eq!(asm(&[0x0A]), "ASL A");
+        assert_eq!(asm(&[0xE8]), "INX");
+        // branch back to itself
+        assert_eq!(asm(&[0xD0, 0xFE]), "BNE $8000");
+        assert_eq!(asm(&[0x04, 0x10]), "*NOP $10");
+        assert_eq!(asm(&[0x02]), "*KIL");
+        assert_eq!(asm(&[0x9B, 0x00, 0x02]), "*TAS $0200,Y");
+        // cut off instructions are data
+        assert_eq!(asm(&[0x9B]), ".db $9B");
+        assert_eq!(asm(&[0xAD, 0x00]), ".db $AD");
+    }
+
+    #[test]
+    fn test_listing() {
+        let code = assemble_with_start_addr(
+            "
+            ldx #$08
+          decrement:
+            dex
+            stx $0200
+            cpx #$03
+            bne decrement
+            jsr done
+            jmp ($0010)
+          done:
+            rts",
+            0x8000,
+        )
+        .unwrap();
+        let mut names = BTreeMap::new();
+        names.insert(0x8000, "RESET".to_string());
+        names.insert(0x0200, "counter".to_string());
+        names.insert(0x0010, "handler".to_string());
+        assert_eq!(
+            listing(&code, 0x8000, &names, None),
+            "\
+RESET:
+  8000  A2 08     LDX #$08
+L8002:
+  8002  CA        DEX
+  8003  8E 00 02  STX counter
+  8006  E0 03     CPX #$03
+  8008  D0 F8     BNE L8002
+  800A  20 10 80  JSR L8010
+  800D  6C 10 00  JMP (handler)
+L8010:
+  8010  60        RTS
+"
+        );
+    }
+
+    #[test]
+    fn test_prg_listing() {
+        let mut prg = vec![0xEA; 0x4000];
+        // RESET: JMP RESET
+        prg[0x100] = 0x4C;
+        prg[0x101] = 0x00;
+        prg[0x102] = 0xC1;
+        prg[0x3FFA..].copy_from_slice(&[0x00, 0xC0, 0x00, 0xC1, 0x00, 0xC0]);
+        let listing = prg_listing(&prg, &BTreeMap::new(), None);
+        assert!(listing.starts_with("NMI:\n  C000  EA        NOP\n"));
+        assert!(listing.contains("RESET:\n  C100  4C 00 C1  JMP RESET\n"));
+        assert!(!listing.contains("; bank"));
+
+        let mut prg = vec![0xEA; 0x8000];
+        prg.extend_from_slice(&[0xEA; 0x4000]);
+        assert!(prg_listing(&prg, &BTreeMap::new(), None).contains("; bank 2\n  C000  EA"));
+
+        // a table the code/data log saw read is data, in the right bank
+        let mut cdl = vec![0; prg.len()];
+        cdl[0x8000] = cdl::DATA;
+        cdl[0x8001] = cdl::DATA | cdl::CODE;
+        let listing = prg_listing(&prg, &BTreeMap::new(), Some(&cdl));
+        assert!(listing.contains("; bank 2\n  C000  EA        .db $EA\n  C001  EA        NOP\n"));
+        assert!(listing.starts_with("; bank 0\n  8000  EA        NOP\n"));
+    }
+}
@@ -1,15 +1,28 @@
 pub mod addr;
 pub mod assembler;
+pub mod disasm;
 pub mod spec;
 pub mod trace;
 
-use std::{collections::HashMap, time::Instant};
-
-use crate::bus::Bus;
+use std::fmt;
+use std::ops::ControlFlow;
+use std::time::Instant;
+
+use crate::bus::{Bus, CpuCycle};
+use crate::cdl;
+use crate::debugger::{Access, BreakReason, CallKind, Debugger, Frame};
+use crate::event_log::EventKind;
+use crate::expr::Expr;
+use crate::history::InstructionHistory;
+use crate::profiler::{Profiler, Routine};
+use crate::savestate::{crc32, SaveState, StateHash, StateReader, StateWriter};
+use crate::symbols::Symbols;
+use crate::trace_log::TraceLogger;
 use addr::AddrMode;
 use spec::Spec;
 
 #[allow(dead_code)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CPU<'a> {
     pub pc: u16,       // Program Counter
     sp: u8,            // Stack Pointer
@@ -25,8 +38,58 @@ pub struct CPU<'a> {
 
     use_nes_clock_rate: bool,
 
+    illegal_opcode_policy: IllegalOpcodePolicy,
+    variant: CpuVariant,
+    // A jammed CPU stops executing instructions until it is reset
+    jammed: bool,
+    // BRK or an IRQ is pushing the return address and status. An NMI that
+    // comes now takes over the vector fetch, see `tick`.
+    hijackable: bool,
+
+    // Bookkeeping for `step`: the instruction executed and the interrupt
+    // serviced since the step started
+    executed_inst: Option<(u16, u8)>,
+    serviced_interrupt: Option<Interrupt>,
+
+    // Set by `request_stop` to make `run` return at the next instruction
+    stop_requested: bool,
+
+    // Breakpoints and watchpoints, `run` stops when one is hit
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub debugger: Option<Debugger>,
+    // Memory accesses are only watched while an instruction executes, not
+    // when tracing peeks at memory
+    watching: bool,
+
+    // With the code/data log on (see `Cartridge::start_code_data_log`), the
+    // flags the reads of the executing instruction are logged with, and
+    // whether the next instruction was jumped to through JMP ($xxxx)
+    #[cfg_attr(feature = "serde", serde(skip))]
+    data_flags: u8,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    indirect_jump: bool,
+
+    // Logs every instruction before it executes, see `trace_log`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub trace_logger: Option<TraceLogger>,
+
+    // The last instructions, see `history`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub history: Option<InstructionHistory>,
+
+    // Cycles per subroutine, see `profiler`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub profiler: Option<Profiler>,
+    // Tracing reads memory without the side effects of reading I/O
+    // registers, see `Bus::peek`
+    peeking: bool,
+    // Names for addresses, used by `trace` and `disassemble_at`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub symbols: Option<Symbols>,
+
     // Internal helpers
-    opcode_to_spec: HashMap<u8, Spec>,
+    #[cfg_attr(feature = "serde", serde(skip, default = "spec::opcode_table"))]
+    opcode_table: [Option<Spec>; 256],
 }
 
 impl CPU<'_> {
@@ -42,7 +105,23 @@ impl CPU<'_> {
             total_cycles: 0,
             bus: bus,
             use_nes_clock_rate: false,
-            opcode_to_spec: spec::opcode_to_spec(),
+            illegal_opcode_policy: IllegalOpcodePolicy::Error,
+            variant: CpuVariant::Nes2A03,
+            jammed: false,
+            hijackable: false,
+            executed_inst: None,
+            serviced_interrupt: None,
+            stop_requested: false,
+            debugger: None,
+            watching: false,
+            data_flags: 0,
+            indirect_jump: false,
+            trace_logger: None,
+            history: None,
+            profiler: None,
+            peeking: false,
+            symbols: None,
+            opcode_table: spec::opcode_table(),
         }
     }
 
@@ -58,44 +137,265 @@ impl CPU<'_> {
             total_cycles: 0,
             bus: bus,
             use_nes_clock_rate: true,
-            opcode_to_spec: spec::opcode_to_spec(),
+            illegal_opcode_policy: IllegalOpcodePolicy::Error,
+            variant: CpuVariant::Nes2A03,
+            jammed: false,
+            hijackable: false,
+            executed_inst: None,
+            serviced_interrupt: None,
+            stop_requested: false,
+            debugger: None,
+            watching: false,
+            data_flags: 0,
+            indirect_jump: false,
+            trace_logger: None,
+            history: None,
+            profiler: None,
+            peeking: false,
+            symbols: None,
+            opcode_table: spec::opcode_table(),
         }
     }
 
+    // The RESET sequence. It runs like an interrupt whose pushes are turned
+    // into reads: the stack pointer goes down by 3 and nothing is written.
+    // A, X, Y and the other flags keep their values, at power on they are
+    // 0 and SP ends up at $FD.
     pub fn reset(&mut self) {
-        self.pc = self.read_u16(0xFFFC);
-        self.sp = 0xFD;
-        self.acc = 0;
-        self.reg_x = 0;
-        self.reg_y = 0;
-        self.status.reset();
+        for _ in 0..3 {
+            self.bus.cpu_read(0x0100 + self.sp as u16);
+            self.sp = self.sp.wrapping_sub(1);
+        }
         self.status.set(CPUStatusBit::I, true);
         self.status.set(CPUStatusBit::U, true);
+        self.pc = self.read_u16(0xFFFC);
 
         // Reset takes time
         self.cycles = 7;
+        self.jammed = false;
+        self.hijackable = false;
+
+        self.bus.acknowledge_irq();
+    }
+
+    // Turn the console off and on, see `Bus::power_cycle`. Unlike `reset`
+    // the registers start over.
+    pub fn power_cycle(&mut self) {
+        self.bus.power_cycle();
+        self.acc = 0;
+        self.reg_x = 0;
+        self.reg_y = 0;
+        self.sp = 0;
+        self.status = CPUStatus::new();
+        self.total_cycles = 0;
+        self.reset();
+    }
+
+    pub fn set_illegal_opcode_policy(&mut self, policy: IllegalOpcodePolicy) {
+        self.illegal_opcode_policy = policy;
+    }
+
+    // Treats the opcode byte as illegal, so the illegal opcode policy
+    // applies to it, e.g. to catch a game relying on an unstable opcode.
+    // Save states don't keep it.
+    pub fn disable_opcode(&mut self, opcode_byte: u8) {
+        self.opcode_table[opcode_byte as usize] = None;
     }
 
-    pub fn run(&mut self) {
-        self.run_with_callback(|_| {});
+    pub fn set_variant(&mut self, variant: CpuVariant) {
+        self.variant = variant;
     }
 
-    pub fn run_with_callback<F: FnMut(&mut CPU)>(&mut self, mut callback: F) {
+    pub fn variant(&self) -> CpuVariant {
+        self.variant
+    }
+
+    pub fn is_jammed(&self) -> bool {
+        self.jammed
+    }
+
+    pub fn total_cycles(&self) -> u32 {
+        self.total_cycles
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            pc: self.pc,
+            sp: self.sp,
+            a: self.acc,
+            x: self.reg_x,
+            y: self.reg_y,
+            p: self.status.bits,
+        }
+    }
+
+    // The value of a debugger expression now, memory is peeked
+    pub fn eval(&mut self, expr: &Expr) -> i64 {
+        let regs = self.registers();
+        expr.eval(&regs, &mut |addr| self.bus.peek(addr))
+    }
+
+    // Run until exactly one instruction has been executed. A pending NMI or
+    // IRQ is serviced first and its cycles are included in the result.
+    pub fn step(&mut self) -> Result<StepInfo, CpuError> {
+        let start_cycles = self.total_cycles;
+        // finish the cycles left over from a reset or interrupt
+        while self.cycles > 0 {
+            self.sys_tick()?;
+        }
+        self.executed_inst = None;
+        self.serviced_interrupt = None;
+        loop {
+            self.sys_tick()?;
+            if self.jammed {
+                return Err(CpuError::Jammed { pc: self.pc });
+            }
+            if let (Some((pc, opcode)), 0) = (self.executed_inst, self.cycles) {
+                return Ok(StepInfo {
+                    pc,
+                    opcode,
+                    cycles: self.total_cycles.wrapping_sub(start_cycles),
+                    interrupt: self.serviced_interrupt,
+                });
+            }
+        }
+    }
+
+    // Snapshot of the whole machine, see `savestate` for the format
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![];
+        self.save_state_into(&mut state);
+        state
+    }
+
+    // The same, overwriting `state` without allocating when it is big
+    // enough
+    pub fn save_state_into(&self, state: &mut Vec<u8>) {
+        let mut w = StateWriter::new_save_state_in(std::mem::take(state));
+        self.save_registers(&mut w);
+        self.bus.save_state(&mut w);
+        *state = w.into_bytes();
+    }
+
+    fn save_registers(&self, w: &mut StateWriter) {
+        w.write_u16(self.pc);
+        w.write_u8(self.sp);
+        w.write_u8(self.acc);
+        w.write_u8(self.reg_x);
+        w.write_u8(self.reg_y);
+        w.write_u8(self.status.bits);
+        w.write_u32(self.cycles);
+        w.write_u32(self.total_cycles);
+        w.write_bool(self.jammed);
+        w.write_bool(self.hijackable);
+    }
+
+    pub fn state_hash(&self) -> StateHash {
+        let mut cpu = StateWriter::new();
+        self.save_registers(&mut cpu);
+        cpu.write_bytes(&self.bus.cpu_ram);
+        let mut ppu = StateWriter::new();
+        self.bus.ppu.save_state(&mut ppu);
+        let mut apu = StateWriter::new();
+        self.bus.apu.save_state(&mut apu);
+        StateHash {
+            cpu: crc32(&cpu.into_bytes()),
+            ppu: crc32(&ppu.into_bytes()),
+            apu: crc32(&apu.into_bytes()),
+            all: crc32(&self.save_state()),
+        }
+    }
+
+    // Restore a snapshot taken by `save_state`. On error the machine is left
+    // as it was before the call.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let backup = self.save_state();
+        let result = self.load_state_unchecked(data);
+        if result.is_err() {
+            self.load_state_unchecked(&backup).unwrap();
+        }
+        result
+    }
+
+    fn load_state_unchecked(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut r = StateReader::new(data)?;
+        self.pc = r.read_u16()?;
+        self.sp = r.read_u8()?;
+        self.acc = r.read_u8()?;
+        self.reg_x = r.read_u8()?;
+        self.reg_y = r.read_u8()?;
+        self.status.set_from_bits(r.read_u8()?);
+        self.cycles = r.read_u32()?;
+        self.total_cycles = r.read_u32()?;
+        self.jammed = r.read_bool()?;
+        self.hijackable = r.read_bool()?;
+        self.bus.load_state(&mut r)?;
+        if !r.is_at_end() {
+            return Err("save state has trailing data".to_string());
+        }
+        Ok(())
+    }
+
+    // Why `run` stopped for the debugger, if it did. Execution can't continue
+    // until the break is taken.
+    pub fn take_break(&mut self) -> Option<BreakReason> {
+        self.debugger
+            .as_mut()
+            .and_then(|debugger| debugger.take_break())
+    }
+
+    // Make `run` and `run_with_callback` return before the next instruction
+    pub fn request_stop(&mut self) {
+        self.stop_requested = true;
+    }
+
+    // Runs until a stop is requested or the CPU fails to execute an instruction
+    pub fn run(&mut self) -> Result<(), CpuError> {
+        self.run_with_callback(|_| ControlFlow::Continue(()))
+    }
+
+    // Calls `callback` before every instruction. Runs until the callback
+    // returns `ControlFlow::Break`, a stop is requested (see `request_stop`,
+    // the gameloop callback can also break), the debugger breaks (see
+    // `take_break`) or the CPU fails to execute an instruction.
+    pub fn run_with_callback<F: FnMut(&mut CPU) -> ControlFlow<()>>(
+        &mut self,
+        mut callback: F,
+    ) -> Result<(), CpuError> {
         let freq_speed_up = 1.2;
         let sys_clock_time_nanos: u128 = 1_000_000_000 / (5369318 as f64 * freq_speed_up) as u128;
         let mut total_cpu_cycles_when_callback = u32::MAX;
         loop {
-            let start_time = Instant::now();
+            // the clock isn't read otherwise, it isn't available on every
+            // target (wasm32-unknown-unknown panics)
+            let start_time = self.use_nes_clock_rate.then(Instant::now);
 
             let should_callback = self.cycles == 0;
             if should_callback && total_cpu_cycles_when_callback != self.total_cycles {
-                callback(self);
+                let regs = self.registers();
+                if let Some(debugger) = &mut self.debugger {
+                    let bus = &mut self.bus;
+                    let mut peek = |addr| bus.peek(addr);
+                    if debugger.check_instruction(self.pc, self.sp, |condition| {
+                        condition.eval(&regs, &mut peek) != 0
+                    }) {
+                        return Ok(());
+                    }
+                }
+                if callback(self).is_break() {
+                    self.stop_requested = true;
+                }
                 total_cpu_cycles_when_callback = self.total_cycles;
             }
+            // a jammed CPU never finishes its instruction
+            if self.stop_requested && (self.cycles == 0 || self.jammed) {
+                self.stop_requested = false;
+                return Ok(());
+            }
 
-            self.sys_tick();
+            self.sys_tick()?;
 
-            if self.use_nes_clock_rate {
+            if let Some(start_time) = start_time {
                 while start_time.elapsed().as_nanos() < sys_clock_time_nanos {
                     assert!(true);
                 }
@@ -103,65 +403,257 @@ impl CPU<'_> {
         }
     }
 
-    fn sys_tick(&mut self) {
-        let nmi_before = self.bus.has_nmi();
-        self.bus.ppu.tick();
-        let nmi_after = self.bus.has_nmi();
-
-        if self.bus.system_tick() {
-            self.tick();
+    fn sys_tick(&mut self) -> Result<(), CpuError> {
+        // The gameloop callback runs once per frame, when the PPU completes
+        // it. This doesn't depend on NMIs or the vblank flag, which games can
+        // turn off or clear.
+        let cpu_cycle = self.bus.system_tick();
+        let frame_completed = self.bus.take_completed_frame();
+
+        match cpu_cycle {
+            CpuCycle::Run => self.tick()?,
+            // stalled cycles still count, an instruction that starts DMA
+            // takes that much longer
+            CpuCycle::Stalled => self.total_cycles = self.total_cycles.wrapping_add(1),
+            CpuCycle::None => {}
         }
 
-        if !nmi_before && nmi_after {
-            self.bus.run_gameloop_callback();
+        if frame_completed {
+            self.bus.apply_freezes();
+            if self.bus.run_gameloop_callback().is_break() {
+                self.stop_requested = true;
+            }
         }
+        Ok(())
     }
 
     // one cycle of cpu execution
-    fn tick(&mut self) {
-        if self.bus.has_nmi() {
+    fn tick(&mut self) -> Result<(), CpuError> {
+        // a jammed CPU only lets time pass for the rest of the system
+        if self.jammed {
+            self.total_cycles = self.total_cycles.wrapping_add(1);
+            return Ok(());
+        }
+
+        // An NMI during the first 4 cycles of BRK or an IRQ hijacks it: the
+        // return address and status are pushed as they were, B included,
+        // but the CPU jumps to the NMI handler. The vector is fetched in
+        // the last 2 cycles.
+        if self.hijackable {
+            if self.cycles < 3 {
+                self.hijackable = false;
+            } else if self.bus.has_nmi() {
+                self.hijackable = false;
+                self.pc = self.read_u16(0xFFFA);
+                self.bus.reset_nmi();
+                self.on_interrupt(Interrupt::NMI);
+            }
+        }
+
+        // NMI is also serviced between instructions, after the one that
+        // was executing when it was raised
+        if self.cycles == 0 && self.bus.has_nmi() {
             self.cycles = self.nmi();
             self.bus.reset_nmi();
+            self.on_interrupt(Interrupt::NMI);
+        }
+
+        // IRQs are level triggered and only serviced between instructions
+        if self.cycles == 0 && self.bus.has_irq() && !self.get_status(CPUStatusBit::I) {
+            self.cycles = self.irq();
+            self.hijackable = true;
+            self.on_interrupt(Interrupt::IRQ);
         }
 
         // if cycle is 0, it means a new instruction can be executed
         if self.cycles == 0 {
-            self.execute_next_instruction();
+            self.execute_next_instruction()?;
+            if self.jammed {
+                return Ok(());
+            }
         }
 
         self.cycles -= 1;
         self.total_cycles = self.total_cycles.wrapping_add(1);
+        Ok(())
     }
 
-    fn execute_next_instruction(&mut self) {
+    fn on_interrupt(&mut self, interrupt: Interrupt) {
+        self.serviced_interrupt = Some(interrupt);
+        self.bus.log_event(match interrupt {
+            Interrupt::NMI => EventKind::Nmi,
+            Interrupt::IRQ => EventKind::Irq,
+        });
+        // the return address and status are already pushed
+        let sp = self.sp.wrapping_add(3);
+        if let Some(debugger) = &mut self.debugger {
+            let lo = self.bus.peek(0x0100 | sp.wrapping_sub(1) as u16);
+            let hi = self.bus.peek(0x0100 | sp as u16);
+            debugger.on_interrupt(interrupt);
+            debugger.on_call(Frame {
+                kind: CallKind::Interrupt(interrupt),
+                entry: self.pc,
+                return_pc: u16::from_le_bytes([lo, hi]),
+                sp,
+            });
+        }
+        self.profile_call(sp);
+    }
+
+    // A call to the routine at PC, with the stack pointer at `sp` before it
+    fn profile_call(&mut self, sp: u8) {
+        if let Some(profiler) = &mut self.profiler {
+            let routine = Routine::new(self.pc, self.bus.cart.borrow().prg_rom_offset(self.pc));
+            profiler.call(routine, sp, self.total_cycles);
+        }
+    }
+
+    fn execute_next_instruction(&mut self) -> Result<(), CpuError> {
         // Always set the unused status flag bit to 1
         self.set_status(self::CPUStatusBit::U, true);
 
-        let inst = self.fetch_next_instruction();
+        if let Some(mut logger) = self.trace_logger.take() {
+            logger.log(self);
+            self.trace_logger = Some(logger);
+        }
+        if let Some(mut history) = self.history.take() {
+            history.push(self.trace_record());
+            self.history = Some(history);
+        }
+
+        let inst_pc = self.pc;
+        let inst = match self.fetch_next_instruction() {
+            Ok(inst) => inst,
+            Err(CpuError::IllegalOpcode { .. })
+                if self.illegal_opcode_policy == IllegalOpcodePolicy::Halt =>
+            {
+                // leave PC pointing at the offending opcode
+                self.pc = self.pc.wrapping_sub(1);
+                self.jammed = true;
+                return Ok(());
+            }
+            Err(e) => {
+                self.pc = self.pc.wrapping_sub(1);
+                return Err(e);
+            }
+        };
         self.cycles = inst.cycles as u32;
+        self.executed_inst = Some((inst_pc, inst.opcode_byte));
+        if self.bus.cart.borrow().is_logging_code_data() {
+            self.log_code_data(inst_pc, &inst);
+        }
+        let (opcode, sp) = (inst.spec.opcode, self.sp);
+        self.watching = self.debugger.is_some();
         self.execute_inst(inst);
+        self.watching = false;
+        self.data_flags = 0;
+        if self.profiler.is_some() {
+            self.profile(opcode, sp);
+        }
+        if let Some(debugger) = &mut self.debugger {
+            let entry = self.pc;
+            let call = |kind, size: u16| Frame {
+                kind,
+                entry,
+                return_pc: inst_pc.wrapping_add(size),
+                sp,
+            };
+            match opcode {
+                spec::Opcode::JSR => debugger.on_call(call(CallKind::Jsr, 3)),
+                spec::Opcode::BRK => debugger.on_call(call(CallKind::Brk, 2)),
+                spec::Opcode::RTS | spec::Opcode::RTI => debugger.on_return(self.pc, self.sp),
+                _ => {}
+            }
+        }
 
         // Always set the unused status flag bit to 1
         self.set_status(self::CPUStatusBit::U, true);
+        Ok(())
     }
 
-    fn fetch_next_instruction(&mut self) -> Instruction {
+    // Calls start when the instruction does, returns end with it, so both
+    // are part of the routine
+    fn profile(&mut self, opcode: spec::Opcode, sp: u8) {
+        match opcode {
+            spec::Opcode::JSR | spec::Opcode::BRK => self.profile_call(sp),
+            spec::Opcode::RTS | spec::Opcode::RTI => {
+                let (sp, cycle) = (self.sp, self.total_cycles.wrapping_add(self.cycles));
+                self.profiler.as_mut().unwrap().ret(sp, cycle);
+            }
+            _ => {}
+        }
+    }
+
+    // Log the instruction's bytes as code, and have what it reads logged as
+    // data. Immediate operands are code only.
+    fn log_code_data(&mut self, pc: u16, inst: &Instruction) {
+        let mut code = cdl::CODE;
+        if std::mem::take(&mut self.indirect_jump) {
+            code |= cdl::INDIRECT_CODE;
+        }
+        self.data_flags = match inst.spec.addr_mode {
+            AddrMode::Immediate => 0,
+            AddrMode::IndexedIndirect | AddrMode::IndirectIndexed => cdl::DATA | cdl::INDIRECT_DATA,
+            _ => cdl::DATA,
+        };
+        let pointer = match inst.spec.addr_mode {
+            // the pointer was read while fetching
+            AddrMode::Indirect => {
+                self.indirect_jump = true;
+                let lo = self.bus.peek(pc.wrapping_add(1));
+                let hi = self.bus.peek(pc.wrapping_add(2));
+                Some(u16::from_le_bytes([lo, hi]))
+            }
+            _ => None,
+        };
+        let mut cart = self.bus.cart.borrow_mut();
+        for i in 0..=inst.spec.addr_mode.size() as u16 {
+            cart.log_prg(pc.wrapping_add(i), code);
+        }
+        if let Some(pointer) = pointer {
+            // the high byte comes from the same page
+            let next = (pointer & 0xFF00) | (pointer.wrapping_add(1) & 0x00FF);
+            cart.log_prg(pointer, cdl::DATA);
+            cart.log_prg(next, cdl::DATA);
+        }
+    }
+
+    fn fetch_next_instruction(&mut self) -> Result<Instruction, CpuError> {
         let opcode_byte = self.read(self.pc);
         self.pc += 1;
-        let spec = *self.opcode_to_spec.get(&opcode_byte).unwrap();
+        let spec = match self.opcode_table[opcode_byte as usize] {
+            Some(spec) => spec,
+            None => match self.illegal_opcode_policy {
+                // behave like a single byte, 2 cycle NOP
+                IllegalOpcodePolicy::Nop => Spec {
+                    opcode_byte,
+                    opcode: spec::Opcode::NOP,
+                    addr_mode: AddrMode::Implicit,
+                    base_cycles: 2,
+                    inc_cycle_on_page_crossed: false,
+                    is_official: false,
+                },
+                IllegalOpcodePolicy::Halt | IllegalOpcodePolicy::Error => {
+                    return Err(CpuError::IllegalOpcode {
+                        opcode: opcode_byte,
+                        pc: self.pc.wrapping_sub(1),
+                    })
+                }
+            },
+        };
         let (oprand_addr, additional_cycles) =
             self.peak_oprand_addr_and_cycles(spec.addr_mode, spec.inc_cycle_on_page_crossed);
         self.pc += spec.addr_mode.size() as u16;
-        Instruction {
+        Ok(Instruction {
             opcode_byte,
             oprand_addr,
             spec,
             cycles: (&spec.base_cycles + additional_cycles) as usize,
-        }
+        })
     }
 
     // fetch next instruction, but keep CPU state unchanged
-    fn peak_next_instruction(&mut self) -> Instruction {
+    fn peak_next_instruction(&mut self) -> Result<Instruction, CpuError> {
         let pc = self.pc;
         let inst = self.fetch_next_instruction();
         self.pc = pc;
@@ -260,35 +752,16 @@ impl CPU<'_> {
 
         let addr_mode = inst.spec.addr_mode;
         let oprand_addr = inst.oprand_addr;
+        self.dummy_indexed_read(&inst);
 
         match inst.spec.opcode {
             ADC => {
                 let oprand = self.read(oprand_addr);
-                let result: u8 = self
-                    .acc
-                    .wrapping_add(oprand)
-                    .wrapping_add(self.get_status(C) as u8);
-                let tmp = self.acc as u16 + oprand as u16 + self.get_status(C) as u16;
-                self.set_status(C, tmp > 0xFF);
-                self.set_status(Z, result == 0);
-                let overflow: bool = ((result as u16) ^ (oprand as u16))
-                    & ((self.acc as u16) ^ (result as u16))
-                    & 0x0080
-                    != 0;
-                self.set_status(V, overflow);
-                self.set_status(N, (tmp & 0x0080) != 0);
-                self.acc = result;
+                self.add_with_carry(oprand);
             }
             SBC => {
                 let oprand = self.read(oprand_addr);
-                let value = (oprand as u16) ^ 0x00FF;
-                let tmp = self.acc as u16 + value + self.get_status(C) as u16;
-                self.set_status(C, tmp & 0xFF00 != 0);
-                self.set_status(Z, tmp & 0x00FF == 0);
-                let overflow: bool = (tmp ^ (self.acc as u16)) & (tmp ^ value) & 0x0080 != 0;
-                self.set_status(V, overflow);
-                self.set_status(N, (tmp & 0x0080) != 0);
-                self.acc = (tmp & 0x00FF) as u8;
+                self.subtract_with_borrow(oprand);
             }
             AND => {
                 let oprand = self.read(oprand_addr);
@@ -300,7 +773,7 @@ impl CPU<'_> {
                 let oprand = if let Implicit = addr_mode {
                     self.acc
                 } else {
-                    self.read(oprand_addr)
+                    self.read_for_modify(oprand_addr)
                 };
                 let tmp: u16 = (oprand as u16) << 1;
                 self.set_status(C, oprand & (1 << 7) != 0);
@@ -382,6 +855,7 @@ impl CPU<'_> {
                 self.set_status(B, false);
 
                 self.pc = (self.read(0xFFFE) as u16) | ((self.read(0xFFFF) as u16) << 8);
+                self.hijackable = true;
             }
             BVC => {
                 if self.get_status(V) == false {
@@ -424,7 +898,7 @@ impl CPU<'_> {
                 self.update_status_z_n(result);
             }
             DEC => {
-                let oprand = self.read(oprand_addr);
+                let oprand = self.read_for_modify(oprand_addr);
                 let result = oprand.wrapping_sub(1);
                 self.write(oprand_addr, result);
                 self.update_status_z_n(result);
@@ -444,7 +918,7 @@ impl CPU<'_> {
                 self.update_status_z_n(result);
             }
             INC => {
-                let oprand = self.read(oprand_addr);
+                let oprand = self.read_for_modify(oprand_addr);
                 let result = oprand.wrapping_add(1);
                 self.write(oprand_addr, result);
                 self.update_status_z_n(result);
@@ -518,7 +992,7 @@ impl CPU<'_> {
                 let oprand = if let Implicit = addr_mode {
                     self.acc
                 } else {
-                    self.read(oprand_addr)
+                    self.read_for_modify(oprand_addr)
                 };
                 self.set_status(C, oprand & 0x01 == 1);
                 let result = oprand >> 1;
@@ -570,7 +1044,7 @@ impl CPU<'_> {
                 let oprand = if let Implicit = addr_mode {
                     self.acc
                 } else {
-                    self.read(oprand_addr)
+                    self.read_for_modify(oprand_addr)
                 };
                 let c_bits: u8 = if self.get_status(C) { 1 << 0 } else { 0 };
                 let tmp: u16 = ((oprand << 1) as u16) | (c_bits as u16);
@@ -596,7 +1070,7 @@ impl CPU<'_> {
                 let oprand = if let Implicit = addr_mode {
                     self.acc
                 } else {
-                    self.read(oprand_addr)
+                    self.read_for_modify(oprand_addr)
                 };
                 let c_bits: u8 = if self.get_status(C) { 1 << 0 } else { 0 };
                 let tmp: u16 = ((c_bits << 7) as u16) | (oprand as u16 >> 1);
@@ -676,7 +1150,7 @@ impl CPU<'_> {
             }
             DCP => {
                 // Equivalent to DEC value then CMP value
-                let oprand = self.read(oprand_addr);
+                let oprand = self.read_for_modify(oprand_addr);
                 let result = oprand.wrapping_sub(1);
                 self.write(oprand_addr, result);
                 self.set_status(C, self.acc >= result);
@@ -684,26 +1158,18 @@ impl CPU<'_> {
             }
             ISB => {
                 // Equivalent to INC value then SBC value
-                let oprand = self.read(oprand_addr);
+                let oprand = self.read_for_modify(oprand_addr);
                 let result = oprand.wrapping_add(1);
                 self.write(oprand_addr, result);
                 self.update_status_z_n(result);
-
-                let value = (result as u16) ^ 0x00FF;
-                let tmp = self.acc as u16 + value + self.get_status(C) as u16;
-                self.set_status(C, tmp & 0xFF00 != 0);
-                self.set_status(Z, tmp & 0x00FF == 0);
-                let overflow: bool = (tmp ^ (self.acc as u16)) & (tmp ^ value) & 0x0080 != 0;
-                self.set_status(V, overflow);
-                self.set_status(N, (tmp & 0x0080) != 0);
-                self.acc = (tmp & 0x00FF) as u8;
+                self.subtract_with_borrow(result);
             }
             SLO => {
                 // Equivalent to ASL value then ORA value
                 let oprand = if let Implicit = addr_mode {
                     self.acc
                 } else {
-                    self.read(oprand_addr)
+                    self.read_for_modify(oprand_addr)
                 };
                 let tmp: u16 = (oprand as u16) << 1;
                 self.set_status(C, oprand & (1 << 7) != 0);
@@ -724,7 +1190,7 @@ impl CPU<'_> {
                 let oprand = if let Implicit = addr_mode {
                     self.acc
                 } else {
-                    self.read(oprand_addr)
+                    self.read_for_modify(oprand_addr)
                 };
                 let c_bits: u8 = if self.get_status(C) { 1 << 0 } else { 0 };
                 let tmp: u16 = ((oprand << 1) as u16) | (c_bits as u16);
@@ -747,7 +1213,7 @@ impl CPU<'_> {
                 let oprand = if let Implicit = addr_mode {
                     self.acc
                 } else {
-                    self.read(oprand_addr)
+                    self.read_for_modify(oprand_addr)
                 };
                 self.set_status(C, oprand & 0x01 == 1);
                 let mut result = oprand >> 1;
@@ -767,7 +1233,7 @@ impl CPU<'_> {
                 let oprand = if let Implicit = addr_mode {
                     self.acc
                 } else {
-                    self.read(oprand_addr)
+                    self.read_for_modify(oprand_addr)
                 };
                 let c_bits: u8 = if self.get_status(C) { 1 << 0 } else { 0 };
                 let tmp: u16 = ((c_bits << 7) as u16) | (oprand as u16 >> 1);
@@ -779,78 +1245,261 @@ impl CPU<'_> {
                 } else {
                     self.write(oprand_addr, result_ror);
                 }
-
-                let result_adc: u8 = self
-                    .acc
-                    .wrapping_add(result_ror)
-                    .wrapping_add(self.get_status(C) as u8);
-                let tmp = self.acc as u16 + result_ror as u16 + self.get_status(C) as u16;
-                self.set_status(C, tmp > 0xFF);
-                self.set_status(Z, result_adc == 0);
-                let overflow: bool = ((result_adc as u16) ^ (result_ror as u16))
-                    & ((self.acc as u16) ^ (result_adc as u16))
-                    & 0x0080
-                    != 0;
-                self.set_status(V, overflow);
-                self.set_status(N, (tmp & 0x0080) != 0);
-                self.acc = result_adc;
+                self.add_with_carry(result_ror);
+            }
+            ANC => {
+                // AND #imm, then copy N into C
+                self.acc &= self.read(oprand_addr);
+                self.update_status_z_n(self.acc);
+                self.set_status(C, self.acc & 0x80 != 0);
+            }
+            ALR => {
+                // Equivalent to AND #imm then LSR A
+                let value = self.acc & self.read(oprand_addr);
+                self.set_status(C, value & 0x01 != 0);
+                self.acc = value >> 1;
+                self.update_status_z_n(self.acc);
+            }
+            ARR => {
+                // AND #imm then ROR A, but C and V come from bits 6 and 5
+                let value = self.acc & self.read(oprand_addr);
+                let c_bits: u8 = if self.get_status(C) { 1 << 7 } else { 0 };
+                self.acc = (value >> 1) | c_bits;
+                self.update_status_z_n(self.acc);
+                let bit_6 = self.acc & (1 << 6) != 0;
+                let bit_5 = self.acc & (1 << 5) != 0;
+                self.set_status(C, bit_6);
+                self.set_status(V, bit_6 ^ bit_5);
+            }
+            AXS => {
+                // X = (A & X) - #imm, without borrow. Flags are set like CMP
+                let oprand = self.read(oprand_addr);
+                let value = self.acc & self.reg_x;
+                self.set_status(C, value >= oprand);
+                self.reg_x = value.wrapping_sub(oprand);
+                self.update_status_z_n(self.reg_x);
+            }
+            SHY => {
+                let base_addr = oprand_addr.wrapping_sub(self.reg_x as u16);
+                self.store_and_high_byte(self.reg_y, base_addr, oprand_addr);
+            }
+            SHX => {
+                let base_addr = oprand_addr.wrapping_sub(self.reg_y as u16);
+                self.store_and_high_byte(self.reg_x, base_addr, oprand_addr);
+            }
+            LAS => {
+                // A, X and SP are all set to memory & SP
+                let value = self.read(oprand_addr) & self.sp;
+                self.acc = value;
+                self.reg_x = value;
+                self.sp = value;
+                self.update_status_z_n(value);
+            }
+            XAA => {
+                // Highly unstable on real hardware. The "magic" constant
+                // varies between chips, 0xEE is the most commonly observed.
+                let oprand = self.read(oprand_addr);
+                self.acc = (self.acc | 0xEE) & self.reg_x & oprand;
+                self.update_status_z_n(self.acc);
+            }
+            AHX => {
+                let base_addr = oprand_addr.wrapping_sub(self.reg_y as u16);
+                self.store_and_high_byte(self.acc & self.reg_x, base_addr, oprand_addr);
+            }
+            TAS => {
+                // SP = A & X, then stored like AHX
+                self.sp = self.acc & self.reg_x;
+                let base_addr = oprand_addr.wrapping_sub(self.reg_y as u16);
+                self.store_and_high_byte(self.sp, base_addr, oprand_addr);
+            }
+            LXA => {
+                // Unstable like XAA, with the same magic constant
+                let oprand = self.read(oprand_addr);
+                self.acc = (self.acc | 0xEE) & oprand;
+                self.reg_x = self.acc;
+                self.update_status_z_n(self.acc);
+            }
+            KIL => {
+                // The CPU locks up until reset, leave PC pointing at the opcode
+                self.pc = self.pc.wrapping_sub(1);
+                self.jammed = true;
             }
         }
     }
 
-    // return: number of cycles of nmi (always 8)
+    fn add_with_carry(&mut self, oprand: u8) {
+        use self::CPUStatusBit::*;
+
+        let carry = self.get_status(C) as u16;
+        let tmp = self.acc as u16 + oprand as u16 + carry;
+        let result = tmp as u8;
+        // Z always comes from the binary sum
+        self.set_status(Z, result == 0);
+        if !self.decimal_mode() {
+            self.set_status(C, tmp > 0xFF);
+            self.set_status(V, (result ^ oprand) & (result ^ self.acc) & 0x80 != 0);
+            self.set_status(N, result & 0x80 != 0);
+            self.acc = result;
+            return;
+        }
+
+        // Add the BCD digits one at a time. N and V come from the sum
+        // before the high digit is adjusted.
+        let mut low = (self.acc & 0x0F) as u16 + (oprand & 0x0F) as u16 + carry;
+        if low >= 0x0A {
+            low = ((low + 0x06) & 0x0F) + 0x10;
+        }
+        let mut sum = (self.acc & 0xF0) as u16 + (oprand & 0xF0) as u16 + low;
+        let unadjusted = sum as u8;
+        self.set_status(
+            V,
+            (unadjusted ^ oprand) & (unadjusted ^ self.acc) & 0x80 != 0,
+        );
+        self.set_status(N, unadjusted & 0x80 != 0);
+        if sum >= 0xA0 {
+            sum += 0x60;
+        }
+        self.set_status(C, sum > 0xFF);
+        self.acc = sum as u8;
+    }
+
+    fn subtract_with_borrow(&mut self, oprand: u8) {
+        use self::CPUStatusBit::*;
+
+        // A - M - !C is A + !M + C, all flags come from the binary result
+        let value = (oprand as u16) ^ 0x00FF;
+        let carry = self.get_status(C) as u16;
+        let tmp = self.acc as u16 + value + carry;
+        self.set_status(C, tmp & 0xFF00 != 0);
+        self.set_status(Z, tmp & 0x00FF == 0);
+        let overflow: bool = (tmp ^ (self.acc as u16)) & (tmp ^ value) & 0x0080 != 0;
+        self.set_status(V, overflow);
+        self.set_status(N, (tmp & 0x0080) != 0);
+        if !self.decimal_mode() {
+            self.acc = (tmp & 0x00FF) as u8;
+            return;
+        }
+
+        let borrow = 1 - carry as i16;
+        let mut low = (self.acc & 0x0F) as i16 - (oprand & 0x0F) as i16 - borrow;
+        if low < 0 {
+            low = ((low - 0x06) & 0x0F) - 0x10;
+        }
+        let mut diff = (self.acc & 0xF0) as i16 - (oprand & 0xF0) as i16 + low;
+        if diff < 0 {
+            diff -= 0x60;
+        }
+        self.acc = diff as u8;
+    }
+
+    // The 2A03 has the D flag but no BCD arithmetic
+    fn decimal_mode(&self) -> bool {
+        self.variant == CpuVariant::Nmos6502 && self.get_status(CPUStatusBit::D)
+    }
+
+    // Indexed addressing adds the index to the low byte of the address
+    // first, and the bus sees a read there before the high byte is fixed
+    // up. Reads skip it unless a page is crossed, then it costs the extra
+    // cycle. Stores and read-modify-write instructions always spend that
+    // cycle on it.
+    fn dummy_indexed_read(&mut self, inst: &Instruction) {
+        let index = match inst.spec.addr_mode {
+            AddrMode::AbsoluteX => self.reg_x,
+            AddrMode::AbsoluteY | AddrMode::IndirectIndexed => self.reg_y,
+            _ => return,
+        };
+        let addr = inst.oprand_addr;
+        let base_addr = addr.wrapping_sub(index as u16);
+        let page_crossed = base_addr & 0xFF00 != addr & 0xFF00;
+        if page_crossed || !inst.spec.inc_cycle_on_page_crossed {
+            self.read((base_addr & 0xFF00) | (addr & 0x00FF));
+        }
+    }
+
+    // Read-modify-write instructions write the unmodified value back while
+    // they compute the result
+    fn read_for_modify(&mut self, addr: u16) -> u8 {
+        let value = self.read(addr);
+        self.write(addr, value);
+        value
+    }
+
+    // SHY, SHX, AHX and TAS store `reg & (high byte of base address + 1)`. When
+    // indexing crosses a page, the high byte of the target address is
+    // replaced by the stored value.
+    fn store_and_high_byte(&mut self, reg: u8, base_addr: u16, addr: u16) {
+        let value = reg & ((base_addr >> 8) as u8).wrapping_add(1);
+        let addr = if base_addr & 0xFF00 != addr & 0xFF00 {
+            ((value as u16) << 8) | (addr & 0x00FF)
+        } else {
+            addr
+        };
+        self.write(addr, value);
+    }
+
+    // return: number of cycles of nmi (always 7)
     fn nmi(&mut self) -> u32 {
-        // write(0x0100 + stkp, (pc >> 8) & 0x00FF);
-        // stkp--;
-        // write(0x0100 + stkp, pc & 0x00FF);
-        // stkp--;
+        use self::CPUStatusBit::*;
 
-        // SetFlag(B, 0);
-        // SetFlag(U, 1);
-        // SetFlag(I, 1);
-        // write(0x0100 + stkp, status);
-        // stkp--;
+        self.stack_push_u16(self.pc);
 
-        // addr_abs = 0xFFFA;
-        // uint16_t lo = read(addr_abs + 0);
-        // uint16_t hi = read(addr_abs + 1);
-        // pc = (hi << 8) | lo;
+        // like IRQ, B is pushed as 0 and I is set after the push
+        self.set_status(B, false);
+        self.set_status(U, true);
+        self.stack_push(self.status.bits);
+        self.set_status(I, true);
+
+        self.pc = self.read_u16(0xFFFA);
 
-        // cycles = 8;
+        // 7 cycles
+        7
+    }
 
+    // return: number of cycles of irq (always 7)
+    fn irq(&mut self) -> u32 {
         use self::CPUStatusBit::*;
 
-        self.bus
-            .cpu_write(0x0100 + self.sp as u16, ((self.pc >> 8) & 0x00FF) as u8);
-        self.sp = self.sp.wrapping_sub(1);
-        self.bus
-            .cpu_write(0x0100 + self.sp as u16, (self.pc & 0x00FF) as u8);
-        self.sp = self.sp.wrapping_sub(1);
+        self.stack_push_u16(self.pc);
 
+        // B flag is pushed as 0 to tell IRQ apart from BRK
         self.set_status(B, false);
         self.set_status(U, true);
+        self.stack_push(self.status.bits);
         self.set_status(I, true);
-        self.bus
-            .cpu_write(0x0100 + self.sp as u16, self.status.bits);
-        self.sp = self.sp.wrapping_sub(1);
 
-        let addr_abs: u16 = 0xFFFA;
-        let lo: u16 = self.bus.cpu_read(addr_abs + 0) as u16;
-        let hi: u16 = self.bus.cpu_read(addr_abs + 1) as u16;
-        self.pc = (hi << 8) | lo;
+        self.pc = self.read_u16(0xFFFE);
 
-        // 8 cycles
-        8
+        // 7 cycles
+        7
     }
 
     fn read(&mut self, addr: u16) -> u8 {
-        self.bus.cpu_read(addr)
+        if self.peeking {
+            return self.bus.peek(addr);
+        }
+        let value = self.bus.cpu_read(addr);
+        if self.watching {
+            self.watch(addr, value, Access::Read);
+        }
+        if self.data_flags != 0 {
+            self.bus.cart.borrow_mut().log_prg(addr, self.data_flags);
+        }
+        value
     }
 
     fn write(&mut self, addr: u16, value: u8) {
+        if self.watching {
+            self.watch(addr, value, Access::Write);
+        }
         self.bus.cpu_write(addr, value);
     }
 
+    fn watch(&mut self, addr: u16, value: u8, access: Access) {
+        if let (Some(debugger), Some((pc, _))) = (&mut self.debugger, self.executed_inst) {
+            debugger.on_access(addr, value, access, pc);
+        }
+    }
+
     fn read_u16(&mut self, addr: u16) -> u16 {
         let a = self.read(addr);
         let b = self.read(addr + 1);
@@ -906,6 +1555,7 @@ impl CPU<'_> {
 
 #[allow(dead_code)]
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct CPUStatus {
     bits: u8,
 }
@@ -942,10 +1592,6 @@ impl CPUStatus {
         CPUStatus { bits: 0 }
     }
 
-    fn reset(&mut self) {
-        self.bits = 0;
-    }
-
     fn set_from_bits(&mut self, bits: u8) {
         self.bits = bits;
     }
@@ -971,6 +1617,79 @@ impl CPUStatus {
     }
 }
 
+// What to do when fetching an opcode byte that has no spec
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum IllegalOpcodePolicy {
+    // Skip the byte as if it was a 1 byte, 2 cycle NOP
+    Nop,
+    // Jam the CPU until the next reset, like the KIL opcodes on real hardware
+    Halt,
+    // Stop execution and return CpuError::IllegalOpcode
+    Error,
+}
+
+// The chip the CPU core behaves like
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CpuVariant {
+    // The NES CPU: decimal mode is wired off, D is only a flag
+    Nes2A03,
+    // A stock NMOS 6502: ADC and SBC do BCD arithmetic when D is set
+    Nmos6502,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CpuError {
+    // An opcode byte without a spec was fetched
+    IllegalOpcode { opcode: u8, pc: u16 },
+    // The CPU is jammed and will not execute anything until reset
+    Jammed { pc: u16 },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpuError::IllegalOpcode { opcode, pc } => {
+                write!(f, "illegal opcode {:02X} at {:04X}", opcode, pc)
+            }
+            CpuError::Jammed { pc } => write!(f, "CPU jammed at {:04X}", pc),
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Interrupt {
+    NMI,
+    IRQ,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Registers {
+    pub pc: u16,
+    pub sp: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    // status flags NV-BDIZC
+    pub p: u8,
+}
+
+// Result of a single `CPU::step`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StepInfo {
+    // Address and opcode byte of the executed instruction
+    pub pc: u16,
+    pub opcode: u8,
+    // CPU cycles elapsed during the step
+    pub cycles: u32,
+    // Interrupt serviced before the instruction, if any
+    pub interrupt: Option<Interrupt>,
+}
+
 #[derive(Clone, Copy)]
 pub struct Instruction {
     opcode_byte: u8,
@@ -1016,60 +1735,60 @@ mod test {
 
         // STA $c000
         let mut cpu = new_cpu_with_program(vec![0x8d, 0x00, 0xc0]);
-        let inst = cpu.fetch_next_instruction();
+        let inst = cpu.fetch_next_instruction().unwrap();
         let expected: u16 = 0xC000;
         assert_addr_eq(inst.oprand_addr, expected);
 
         // STA $0200,X
         let mut cpu = new_cpu_with_program(vec![0x9d, 0x00, 0x02]);
         cpu.reg_x = 0x01;
-        let inst = cpu.fetch_next_instruction();
+        let inst = cpu.fetch_next_instruction().unwrap();
         let expected: u16 = 0x0201;
         assert_addr_eq(inst.oprand_addr, expected);
 
         // STA $0200,Y
         let mut cpu = new_cpu_with_program(vec![0x99, 0x00, 0x02]);
         cpu.reg_y = 0x01;
-        let inst = cpu.fetch_next_instruction();
+        let inst = cpu.fetch_next_instruction().unwrap();
         let expected: u16 = 0x0201;
         assert_addr_eq(inst.oprand_addr, expected);
 
         // STA $c0
         let mut cpu = new_cpu_with_program(vec![0x85, 0xc0]);
-        let inst = cpu.fetch_next_instruction();
+        let inst = cpu.fetch_next_instruction().unwrap();
         let expected: u16 = 0x00c0;
         assert_addr_eq(inst.oprand_addr, expected);
 
         // STA $c0,X
         let mut cpu = new_cpu_with_program(vec![0x95, 0xc0]);
         cpu.reg_x = 0x01;
-        let inst = cpu.fetch_next_instruction();
+        let inst = cpu.fetch_next_instruction().unwrap();
         let expected: u16 = 0x00c1;
         assert_addr_eq(inst.oprand_addr, expected);
 
         // LDX $c0,Y
         let mut cpu = new_cpu_with_program(vec![0xb6, 0xc0]);
         cpu.reg_y = 0x01;
-        let inst = cpu.fetch_next_instruction();
+        let inst = cpu.fetch_next_instruction().unwrap();
         let expected: u16 = 0x00c1;
         assert_addr_eq(inst.oprand_addr, expected);
 
         // LDX #$c0
         let mut cpu = new_cpu_with_program(vec![0xa2, 0xc0]);
-        let inst = cpu.fetch_next_instruction();
+        let inst = cpu.fetch_next_instruction().unwrap();
         let expected: u16 = 0x8001;
         assert_addr_eq(inst.oprand_addr, expected);
 
         // BNE not_equal
         // not_equal: BRK
         let mut cpu = new_cpu_with_program(vec![0xd0, 0x00, 0x00]);
-        let inst = cpu.fetch_next_instruction();
+        let inst = cpu.fetch_next_instruction().unwrap();
         let expected: u16 = 0x8002;
         assert_addr_eq(inst.oprand_addr, expected);
 
         // INX
         let mut cpu = new_cpu_with_program(vec![0xe8]);
-        let inst = cpu.fetch_next_instruction();
+        let inst = cpu.fetch_next_instruction().unwrap();
         let expected: u16 = 0;
         assert_addr_eq(inst.oprand_addr, expected);
 
@@ -1077,7 +1796,7 @@ mod test {
         let mut cpu = new_cpu_with_program(vec![0x6c, 0xf0, 0x00]);
         cpu.write(0x00f0, 0x12);
         cpu.write(0x00f1, 0x34);
-        let inst = cpu.fetch_next_instruction();
+        let inst = cpu.fetch_next_instruction().unwrap();
         let expected: u16 = 0x3412;
         assert_addr_eq(inst.oprand_addr, expected);
 
@@ -1086,7 +1805,7 @@ mod test {
         cpu.write(0x00c1, 0x12);
         cpu.write(0x00c2, 0x34);
         cpu.reg_x = 1;
-        let inst = cpu.fetch_next_instruction();
+        let inst = cpu.fetch_next_instruction().unwrap();
         let expected: u16 = 0x3412;
         assert_addr_eq(inst.oprand_addr, expected);
 
@@ -1095,11 +1814,551 @@ mod test {
         cpu.write(0x00c0, 0x12);
         cpu.write(0x00c1, 0x34);
         cpu.reg_y = 1;
-        let inst = cpu.fetch_next_instruction();
+        let inst = cpu.fetch_next_instruction().unwrap();
         let expected: u16 = 0x3413;
         assert_addr_eq(inst.oprand_addr, expected);
     }
 
+    #[test]
+    fn test_irq() {
+        // CLI; NOP; NOP, with the IRQ vector pointing to $9000
+        let mut program = vec![0u8; 0x4000];
+        program[0..3].copy_from_slice(&[0x58, 0xEA, 0xEA]);
+        program[0x3FFE] = 0x00;
+        program[0x3FFF] = 0x90;
+        let mut cpu = new_cpu_with_program(program);
+        cpu.cycles = 0;
+        cpu.bus.apu.dmc.irq = true;
+
+        // I flag is set after reset, so the IRQ is masked until CLI
+        cpu.tick().unwrap();
+        assert_eq!(cpu.pc, 0x8001);
+        while cpu.cycles > 0 {
+            cpu.tick().unwrap();
+        }
+
+        cpu.tick().unwrap();
+        assert_eq!(cpu.pc, 0x9000);
+        assert_eq!(cpu.cycles, 6);
+        assert!(cpu.get_status(CPUStatusBit::I));
+        assert_eq!(cpu.stack_pop() & 0b0011_0000, 0b0010_0000);
+        assert_eq!(cpu.stack_pop_u16(), 0x8001);
+    }
+
+    // BRK at $8000, NMI handler at $9000, IRQ/BRK handler at $A000. The
+    // PPU is in vblank with NMIs off, enabling them raises one.
+    fn new_cpu_for_interrupts(program: &[u8]) -> CPU<'static> {
+        let mut rom = vec![0xEAu8; 0x4000];
+        rom[..program.len()].copy_from_slice(program);
+        rom[0x3FFA..].copy_from_slice(&[0x00, 0x90, 0x00, 0x80, 0x00, 0xA0]);
+        let mut cpu = new_cpu_with_program(rom);
+        cpu.cycles = 0;
+        while !cpu.bus.ppu.is_in_vblank() {
+            cpu.bus.ppu.tick();
+        }
+        cpu
+    }
+
+    #[test]
+    fn test_brk_hijacked_by_nmi() {
+        let mut cpu = new_cpu_for_interrupts(&[0x00]);
+        cpu.tick().unwrap();
+        assert_eq!(cpu.pc, 0xA000);
+        cpu.tick().unwrap();
+        cpu.bus.ppu.write_ctrl_reg(0x80);
+        while cpu.cycles > 0 {
+            cpu.tick().unwrap();
+        }
+        // BRK's return address and B flag, but the NMI handler
+        assert_eq!(cpu.pc, 0x9000);
+        assert!(!cpu.bus.has_nmi());
+        assert_eq!(cpu.stack_pop() & 0b0011_0000, 0b0011_0000);
+        assert_eq!(cpu.stack_pop_u16(), 0x8002);
+
+        // too late to change the vector, the NMI follows BRK
+        let mut cpu = new_cpu_for_interrupts(&[0x00]);
+        for _ in 0..5 {
+            cpu.tick().unwrap();
+        }
+        cpu.bus.ppu.write_ctrl_reg(0x80);
+        while cpu.cycles > 0 {
+            cpu.tick().unwrap();
+        }
+        assert_eq!(cpu.pc, 0xA000);
+        cpu.tick().unwrap();
+        assert_eq!(cpu.pc, 0x9000);
+        assert_eq!(cpu.cycles, 6);
+        assert_eq!(cpu.stack_pop() & 0b0011_0000, 0b0010_0000);
+        assert_eq!(cpu.stack_pop_u16(), 0xA000);
+    }
+
+    #[test]
+    fn test_nmi_pushes_status_before_setting_i() {
+        // CLI; NOP
+        let mut cpu = new_cpu_for_interrupts(&[0x58, 0xEA]);
+        cpu.step().unwrap();
+        cpu.bus.ppu.write_ctrl_reg(0x80);
+        let info = cpu.step().unwrap();
+        assert_eq!(info.interrupt, Some(Interrupt::NMI));
+        // 7 cycles for the NMI, 2 for the NOP in the handler
+        assert_eq!(info.cycles, 9);
+        assert!(cpu.get_status(CPUStatusBit::I));
+        assert_eq!(cpu.stack_pop() & 0b0011_0100, 0b0010_0000);
+        assert_eq!(cpu.stack_pop_u16(), 0x8001);
+    }
+
+    #[test]
+    fn test_reset_sequence() {
+        let mut cpu = new_cpu_with_program(vec![0xEA]);
+        // power on: SP 0 - 3
+        assert_eq!(cpu.sp, 0xFD);
+        assert_eq!(cpu.status.bits, 0b0010_0100);
+        cpu.acc = 0x12;
+        cpu.set_status(CPUStatusBit::C, true);
+        cpu.reset();
+        // nothing is pushed, registers and flags other than I are kept
+        assert_eq!(cpu.sp, 0xFA);
+        assert_eq!(cpu.acc, 0x12);
+        assert!(cpu.get_status(CPUStatusBit::C));
+        assert_eq!(cpu.bus.cpu_ram[0x01FD], 0);
+        assert_eq!(cpu.cycles, 7);
+    }
+
+    #[test]
+    fn test_illegal_opcode_policy() {
+        // TAS, disabled; INX
+        let program = vec![0x9B, 0xE8];
+        let new_cpu = |program: &Vec<u8>| {
+            let mut cpu = new_cpu_with_program(program.clone());
+            cpu.disable_opcode(0x9B);
+            cpu
+        };
+
+        let mut cpu = new_cpu(&program);
+        assert_eq!(
+            cpu.step(),
+            Err(CpuError::IllegalOpcode {
+                opcode: 0x9B,
+                pc: 0x8000
+            })
+        );
+        assert_eq!(cpu.pc, 0x8000);
+
+        let mut cpu = new_cpu(&program);
+        cpu.set_illegal_opcode_policy(IllegalOpcodePolicy::Nop);
+        assert_eq!(cpu.step().unwrap().cycles, 9); // 7 reset cycles + 2
+        assert_eq!(cpu.step().unwrap().cycles, 2);
+        assert_eq!(cpu.reg_x, 1);
+
+        let mut cpu = new_cpu(&program);
+        cpu.set_illegal_opcode_policy(IllegalOpcodePolicy::Halt);
+        assert_eq!(cpu.step(), Err(CpuError::Jammed { pc: 0x8000 }));
+        assert!(cpu.is_jammed());
+        cpu.reset();
+        assert!(!cpu.is_jammed());
+    }
+
+    #[test]
+    fn test_step() {
+        // INX; LDA $0200
+        let mut cpu = new_cpu_with_program(vec![0xE8, 0xAD, 0x00, 0x02]);
+        cpu.cycles = 0;
+
+        let info = cpu.step().unwrap();
+        assert_eq!(
+            info,
+            StepInfo {
+                pc: 0x8000,
+                opcode: 0xE8,
+                cycles: 2,
+                interrupt: None
+            }
+        );
+
+        let info = cpu.step().unwrap();
+        assert_eq!((info.pc, info.opcode, info.cycles), (0x8001, 0xAD, 4));
+    }
+
+    #[test]
+    fn test_oam_dma_stalls_cpu() {
+        // LDA #$02; STA $4014; STA $4014; NOP; STA $4014
+        let mut cpu = new_cpu_with_program(vec![
+            0xA9, 0x02, 0x8D, 0x14, 0x40, 0x8D, 0x14, 0x40, 0xEA, 0x8D, 0x14, 0x40,
+        ]);
+        cpu.bus.cpu_ram[0x0203] = 0x42;
+        cpu.cycles = 0;
+        cpu.step().unwrap();
+
+        // 4 cycles for the STA, 512 for the transfer and 1 or 2 to align
+        // the transfer on an even cycle
+        assert_eq!(cpu.step().unwrap().cycles, 4 + 513);
+        // the first transfer took an odd number of cycles
+        assert_eq!(cpu.step().unwrap().cycles, 4 + 514);
+        assert_eq!(cpu.bus.ppu.oam_data[0x03], 0x42);
+        // NOP doesn't change the parity
+        cpu.step().unwrap();
+        assert_eq!(cpu.step().unwrap().cycles, 4 + 514);
+    }
+
+    #[test]
+    fn test_debugger_breakpoint() {
+        // INX; INX; STX $10; JMP $8000
+        let mut cpu = new_cpu_with_program(vec![0xE8, 0xE8, 0x86, 0x10, 0x4C, 0x00, 0x80]);
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(0x8001);
+        cpu.debugger = Some(debugger);
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.pc, 0x8001);
+        assert_eq!(cpu.reg_x, 1);
+        // running without taking the break doesn't do anything
+        cpu.run().unwrap();
+        assert_eq!(cpu.pc, 0x8001);
+        assert_eq!(
+            cpu.take_break(),
+            Some(BreakReason::Breakpoint { pc: 0x8001 })
+        );
+
+        // once around the loop
+        cpu.run().unwrap();
+        assert_eq!(
+            cpu.take_break(),
+            Some(BreakReason::Breakpoint { pc: 0x8001 })
+        );
+        assert_eq!(cpu.reg_x, 3);
+        assert_eq!(cpu.bus.cpu_ram[0x10], 2);
+    }
+
+    #[test]
+    fn test_debugger_watchpoint() {
+        // INX; INX; STX $10; LDA $10; JMP $8000
+        let mut cpu =
+            new_cpu_with_program(vec![0xE8, 0xE8, 0x86, 0x10, 0xA5, 0x10, 0x4C, 0x00, 0x80]);
+        let mut debugger = Debugger::new();
+        debugger.add_watchpoint(0x0010..=0x0011, false, true);
+        cpu.debugger = Some(debugger);
+
+        cpu.run().unwrap();
+        // stops after the write
+        assert_eq!(cpu.pc, 0x8004);
+        assert_eq!(
+            cpu.take_break(),
+            Some(BreakReason::Watchpoint {
+                addr: 0x10,
+                value: 2,
+                access: Access::Write,
+                pc: 0x8002
+            })
+        );
+        // tracing peeks at $10 without hitting the watchpoint
+        cpu.debugger
+            .as_mut()
+            .unwrap()
+            .add_watchpoint(0x0010..=0x0010, true, false);
+        cpu.trace();
+        assert_eq!(cpu.take_break(), None);
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.pc, 0x8006);
+        assert!(matches!(
+            cpu.take_break(),
+            Some(BreakReason::Watchpoint {
+                access: Access::Read,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_dummy_accesses() {
+        // the first access to `addr` in the first 2 instructions of `program`
+        fn first_access(program: Vec<u8>, addr: u16, access: Access) -> Option<BreakReason> {
+            let mut cpu = new_cpu_with_program(program);
+            let mut debugger = Debugger::new();
+            debugger.add_watchpoint(addr..=addr, access == Access::Read, access == Access::Write);
+            cpu.debugger = Some(debugger);
+            for _ in 0..2 {
+                cpu.step().unwrap();
+            }
+            cpu.take_break()
+        }
+
+        // LDX #$20; STA $01F0,X: reads $0110 before the high byte is fixed
+        let program = vec![0xA2, 0x20, 0x9D, 0xF0, 0x01];
+        assert!(matches!(
+            first_access(program, 0x0110, Access::Read),
+            Some(BreakReason::Watchpoint { pc: 0x8002, .. })
+        ));
+        // LDX #$01; STA $0110,X: even without crossing a page
+        let program = vec![0xA2, 0x01, 0x9D, 0x10, 0x01];
+        assert!(matches!(
+            first_access(program, 0x0111, Access::Read),
+            Some(BreakReason::Watchpoint { pc: 0x8002, .. })
+        ));
+        // INC $10; NOP: writes the old value back before the result
+        let program = vec![0xE6, 0x10, 0xEA];
+        assert_eq!(
+            first_access(program, 0x10, Access::Write),
+            Some(BreakReason::Watchpoint {
+                addr: 0x10,
+                value: 0x00,
+                access: Access::Write,
+                pc: 0x8000
+            })
+        );
+
+        // PPUDATA reads advance the VRAM address, LDX #$10; LDA $20F7,X
+        // reads $2007 before $2107, its mirror. LDA $2007,X does not cross
+        // a page and reads once.
+        for (program, expected) in [
+            (vec![0xA2, 0x10, 0xBD, 0xF7, 0x20, 0xAD, 0x07, 0x20], 0x11),
+            (vec![0xA2, 0x00, 0xBD, 0x07, 0x20, 0xAD, 0x07, 0x20], 0x00),
+        ] {
+            let mut cpu = new_cpu_with_program(program);
+            cpu.bus.ppu.write_addr_reg(0x20);
+            cpu.bus.ppu.write_addr_reg(0x00);
+            cpu.bus.ppu.write_data_reg(0x00);
+            cpu.bus.ppu.write_data_reg(0x11);
+            cpu.bus.ppu.write_addr_reg(0x20);
+            cpu.bus.ppu.write_addr_reg(0x00);
+            for _ in 0..3 {
+                cpu.execute_next_instruction().unwrap();
+            }
+            assert_eq!(cpu.acc, expected);
+        }
+    }
+
+    #[test]
+    fn test_debugger_break_on_nmi() {
+        // LDA #$80; STA $2000; JMP $8005
+        let mut cpu = new_cpu_with_program(vec![0xA9, 0x80, 0x8D, 0x00, 0x20, 0x4C, 0x05, 0x80]);
+        let mut debugger = Debugger::new();
+        debugger.break_on_nmi = true;
+        cpu.debugger = Some(debugger);
+
+        cpu.run().unwrap();
+        assert_eq!(
+            cpu.take_break(),
+            Some(BreakReason::Interrupt(Interrupt::NMI))
+        );
+        assert!(cpu.bus.ppu.is_in_vblank());
+        // the handler at the NMI vector is about to run
+        let vector = cpu.read_u16(0xFFFA);
+        assert_eq!(cpu.pc, vector);
+    }
+
+    #[test]
+    fn test_run_stops_on_break() {
+        // INX; JMP $8000
+        let mut cpu = new_cpu_with_program(vec![0xE8, 0x4C, 0x00, 0x80]);
+        let mut instructions = 0;
+        cpu.run_with_callback(|_| {
+            instructions += 1;
+            if instructions == 10 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+        .unwrap();
+        // stopped right before the 10th instruction, 5 INX were executed
+        assert_eq!(cpu.reg_x, 5);
+        assert_eq!(cpu.pc, 0x8001);
+
+        cpu.request_stop();
+        cpu.run().unwrap();
+        assert_eq!(cpu.pc, 0x8001);
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+        // INX; STX $10; JMP $8000
+        let mut cpu = new_cpu_with_program(vec![0xE8, 0x86, 0x10, 0x4C, 0x00, 0x80]);
+        for _ in 0..6 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.reg_x, 2);
+        let state = cpu.save_state();
+
+        for _ in 0..6 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.reg_x, 4);
+        assert_eq!(cpu.read(0x10), 4);
+
+        cpu.load_state(&state).unwrap();
+        assert_eq!(cpu.save_state(), state);
+
+        // a broken state doesn't touch the machine
+        assert!(cpu.load_state(&state[..state.len() - 1]).is_err());
+        assert!(cpu.load_state(b"garbage").is_err());
+        assert_eq!(cpu.save_state(), state);
+
+        // reading changes the open bus, check memory last
+        assert_eq!(cpu.reg_x, 2);
+        assert_eq!(cpu.read(0x10), 2);
+    }
+
+    #[test]
+    fn test_unofficial_immediate_opcodes() {
+        // LDA #$C3; ANC #$81; ALR #$FF; LDX #$0F; AXS #$02
+        let program = vec![0xA9, 0xC3, 0x0B, 0x81, 0x4B, 0xFF, 0xA2, 0x0F, 0xCB, 0x02];
+        let mut cpu = new_cpu_with_program(program);
+        cpu.step().unwrap();
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.acc, 0x81);
+        assert!(cpu.get_status(CPUStatusBit::C));
+        assert!(cpu.get_status(CPUStatusBit::N));
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.acc, 0x40);
+        assert!(cpu.get_status(CPUStatusBit::C));
+        assert!(!cpu.get_status(CPUStatusBit::N));
+
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        // (0x40 & 0x0F) - 2 wraps around without borrow in
+        assert_eq!(cpu.reg_x, 0xFE);
+        assert!(!cpu.get_status(CPUStatusBit::C));
+    }
+
+    #[test]
+    fn test_arr() {
+        // LDA #$FF; SEC; ARR #$C0; CLC; LDA #$FF; ARR #$40
+        let program = vec![0xA9, 0xFF, 0x38, 0x6B, 0xC0, 0x18, 0xA9, 0xFF, 0x6B, 0x40];
+        let mut cpu = new_cpu_with_program(program);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        // the old carry is rotated into bit 7, C is bit 6 and V is bit 6 ^ bit 5
+        assert_eq!(cpu.acc, 0xE0);
+        assert!(cpu.get_status(CPUStatusBit::C));
+        assert!(!cpu.get_status(CPUStatusBit::V));
+        assert!(cpu.get_status(CPUStatusBit::N));
+
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.acc, 0x20);
+        assert!(!cpu.get_status(CPUStatusBit::C));
+        assert!(cpu.get_status(CPUStatusBit::V));
+    }
+
+    #[test]
+    fn test_unofficial_store_opcodes() {
+        // NOP; SHY $0200,X; SHX $02FF,Y; AHX $0200,Y; TAS $02FE,Y; AHX ($10),Y
+        let program = vec![
+            0xEA, 0x9C, 0x00, 0x02, 0x9E, 0xFF, 0x02, 0x9F, 0x00, 0x02, 0x9B, 0xFE, 0x02, 0x93,
+            0x10,
+        ];
+        let mut cpu = new_cpu_with_program(program);
+        cpu.step().unwrap();
+
+        // the stored value is reg & (high byte + 1)
+        cpu.reg_x = 0x01;
+        cpu.reg_y = 0xFF;
+        cpu.step().unwrap();
+        assert_eq!(cpu.bus.cpu_ram[0x0201], 0x03);
+
+        // crossing a page replaces the high byte of the address by the value
+        cpu.reg_x = 0x05;
+        cpu.reg_y = 0x02;
+        cpu.step().unwrap();
+        assert_eq!(cpu.bus.cpu_ram[0x0101], 0x01);
+        assert_eq!(cpu.bus.cpu_ram[0x0301], 0x00);
+
+        cpu.acc = 0xF6;
+        cpu.reg_x = 0x3F;
+        cpu.reg_y = 0x10;
+        cpu.step().unwrap();
+        assert_eq!(cpu.bus.cpu_ram[0x0210], 0x02);
+
+        // SP = A & X, stored like AHX
+        cpu.step().unwrap();
+        assert_eq!(cpu.sp, 0x36);
+        assert_eq!(cpu.bus.cpu_ram[0x020E], 0x02);
+        assert_eq!(cpu.bus.cpu_ram[0x030E], 0x00);
+
+        cpu.bus.cpu_ram[0x10] = 0x00;
+        cpu.bus.cpu_ram[0x11] = 0x04;
+        cpu.step().unwrap();
+        assert_eq!(cpu.bus.cpu_ram[0x0410], 0x04);
+    }
+
+    #[test]
+    fn test_unofficial_load_opcodes() {
+        // NOP; LAS $0300,Y; XAA #$0F; LXA #$F0
+        let program = vec![0xEA, 0xBB, 0x00, 0x03, 0x8B, 0x0F, 0xAB, 0xF0];
+        let mut cpu = new_cpu_with_program(program);
+        cpu.step().unwrap();
+
+        // A, X and SP = memory & SP
+        cpu.sp = 0xF0;
+        cpu.reg_y = 0x10;
+        cpu.bus.cpu_ram[0x0310] = 0x3C;
+        cpu.step().unwrap();
+        assert_eq!((cpu.acc, cpu.reg_x, cpu.sp), (0x30, 0x30, 0x30));
+
+        // A = (A | $EE) & X & #imm
+        cpu.acc = 0x11;
+        cpu.reg_x = 0x3C;
+        cpu.step().unwrap();
+        assert_eq!(cpu.acc, 0x0C);
+        assert_eq!(cpu.reg_x, 0x3C);
+
+        // A = X = (A | $EE) & #imm
+        cpu.acc = 0x01;
+        cpu.step().unwrap();
+        assert_eq!((cpu.acc, cpu.reg_x), (0xE0, 0xE0));
+        assert!(cpu.get_status(CPUStatusBit::N));
+    }
+
+    #[test]
+    fn test_decimal_mode() {
+        // SED; CLC; LDA #$19; ADC #$28; ADC #$53; SEC; LDA #$00; SBC #$01
+        let program = vec![
+            0xF8, 0x18, 0xA9, 0x19, 0x69, 0x28, 0x69, 0x53, 0x38, 0xA9, 0x00, 0xE9, 0x01,
+        ];
+        let mut cpu = new_cpu_with_program(program.clone());
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        // the 2A03 ignores D
+        assert_eq!(cpu.acc, 0x41);
+
+        let mut cpu = new_cpu_with_program(program);
+        cpu.set_variant(CpuVariant::Nmos6502);
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.acc, 0x47);
+        assert!(!cpu.get_status(CPUStatusBit::C));
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.acc, 0x00);
+        assert!(cpu.get_status(CPUStatusBit::C));
+        // Z is set from the binary sum, 0x9A
+        assert!(!cpu.get_status(CPUStatusBit::Z));
+
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.acc, 0x99);
+        assert!(!cpu.get_status(CPUStatusBit::C));
+        assert!(cpu.get_status(CPUStatusBit::N));
+    }
+
+    #[test]
+    fn test_kil_jams_cpu() {
+        // INX; KIL; INX
+        let mut cpu = new_cpu_with_program(vec![0xE8, 0x02, 0xE8]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.step(), Err(CpuError::Jammed { pc: 0x8001 }));
+        assert!(cpu.is_jammed());
+        assert_eq!(cpu.reg_x, 1);
+    }
+
     #[test]
     fn test_cpu_status() {
         use super::CPUStatusBit::*;
@@ -1148,24 +2407,24 @@ mod test {
         ]);
         // run PPU writes
         for _ in 0..10 {
-            cpu.execute_next_instruction();
+            cpu.execute_next_instruction().unwrap();
             println!("executed");
         }
         // set PPU address
         for _ in 0..4 {
-            cpu.execute_next_instruction();
+            cpu.execute_next_instruction().unwrap();
         }
         // dummy read
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
         assert_eq!(cpu.acc, 0x00);
         // read [0x2000]
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
         assert_eq!(cpu.acc, 0x00);
         // read [0x2001]
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
         assert_eq!(cpu.acc, 0x11);
         // read [0x2002]
-        cpu.execute_next_instruction();
+        cpu.execute_next_instruction().unwrap();
         assert_eq!(cpu.acc, 0x22);
     }
 }
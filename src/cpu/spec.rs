@@ -308,6 +308,43 @@ const SPEC_TABLE: &'static [(u8, Opcode, AddrMode, u8, bool, bool)] = {
         (0x7B, RRA, AbsoluteY, 7, false, false),
         (0x63, RRA, IndexedIndirect, 8, false, false),
         (0x73, RRA, IndirectIndexed, 8, false, false),
+        // ANC
+        (0x0B, ANC, Immediate, 2, false, false),
+        (0x2B, ANC, Immediate, 2, false, false),
+        // ALR
+        (0x4B, ALR, Immediate, 2, false, false),
+        // ARR
+        (0x6B, ARR, Immediate, 2, false, false),
+        // AXS
+        (0xCB, AXS, Immediate, 2, false, false),
+        // SHY
+        (0x9C, SHY, AbsoluteX, 5, false, false),
+        // SHX
+        (0x9E, SHX, AbsoluteY, 5, false, false),
+        // LAS
+        (0xBB, LAS, AbsoluteY, 4, true, false),
+        // XAA
+        (0x8B, XAA, Immediate, 2, false, false),
+        // AHX
+        (0x93, AHX, IndirectIndexed, 6, false, false),
+        (0x9F, AHX, AbsoluteY, 5, false, false),
+        // TAS
+        (0x9B, TAS, AbsoluteY, 5, false, false),
+        // LXA
+        (0xAB, LXA, Immediate, 2, false, false),
+        // KIL
+        (0x02, KIL, Implicit, 2, false, false),
+        (0x12, KIL, Implicit, 2, false, false),
+        (0x22, KIL, Implicit, 2, false, false),
+        (0x32, KIL, Implicit, 2, false, false),
+        (0x42, KIL, Implicit, 2, false, false),
+        (0x52, KIL, Implicit, 2, false, false),
+        (0x62, KIL, Implicit, 2, false, false),
+        (0x72, KIL, Implicit, 2, false, false),
+        (0x92, KIL, Implicit, 2, false, false),
+        (0xB2, KIL, Implicit, 2, false, false),
+        (0xD2, KIL, Implicit, 2, false, false),
+        (0xF2, KIL, Implicit, 2, false, false),
     ]
 };
 
@@ -379,6 +416,18 @@ pub enum Opcode {
     RLA,
     SRE,
     RRA,
+    ANC,
+    ALR,
+    ARR,
+    AXS,
+    SHY,
+    SHX,
+    LAS,
+    XAA,
+    AHX,
+    TAS,
+    LXA,
+    KIL,
 }
 
 #[derive(Clone, Copy)]
@@ -391,13 +440,24 @@ pub struct Spec {
     pub is_official: bool,
 }
 
-pub fn opcode_to_spec() -> HashMap<u8, Spec> {
-    let mut map: HashMap<u8, Spec> = HashMap::with_capacity(SPEC_TABLE.len());
-    for (opcode_byte, opcode, addr_mode, base_cycles, inc_cycle_on_page_crossed, is_official) in
-        SPEC_TABLE
-    {
-        map.insert(
-            *opcode_byte,
+// Opcode bytes of a mnemonic in an addressing mode. Several unofficial
+// opcodes do the same, the first one in SPEC_TABLE is used.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Encoding {
+    pub official: Option<u8>,
+    pub unofficial: Option<u8>,
+}
+
+impl Opcode {
+    pub fn mnemonic(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+// All specs, in the order of SPEC_TABLE
+pub fn specs() -> impl Iterator<Item = Spec> {
+    SPEC_TABLE.iter().map(
+        |(opcode_byte, opcode, addr_mode, base_cycles, inc_cycle_on_page_crossed, is_official)| {
             Spec {
                 opcode_byte: *opcode_byte,
                 opcode: *opcode,
@@ -405,8 +465,77 @@ pub fn opcode_to_spec() -> HashMap<u8, Spec> {
                 base_cycles: *base_cycles,
                 inc_cycle_on_page_crossed: *inc_cycle_on_page_crossed,
                 is_official: *is_official,
-            },
+            }
+        },
+    )
+}
+
+// Specs indexed by opcode byte. Every byte has one, `CPU::disable_opcode`
+// clears them.
+pub fn opcode_table() -> [Option<Spec>; 256] {
+    let mut table: [Option<Spec>; 256] = [None; 256];
+    for spec in specs() {
+        table[spec.opcode_byte as usize] = Some(spec);
+    }
+    table
+}
+
+// Encodings by mnemonic and addressing mode, for the assembler
+pub fn encoding_table() -> HashMap<String, HashMap<AddrMode, Encoding>> {
+    let mut table: HashMap<String, HashMap<AddrMode, Encoding>> = HashMap::new();
+    for spec in specs() {
+        let encoding = table
+            .entry(spec.opcode.mnemonic())
+            .or_default()
+            .entry(spec.addr_mode)
+            .or_default();
+        let byte = if spec.is_official {
+            &mut encoding.official
+        } else {
+            &mut encoding.unofficial
+        };
+        byte.get_or_insert(spec.opcode_byte);
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_opcode_table() {
+        let table = opcode_table();
+        // no opcode byte is listed twice in SPEC_TABLE
+        assert_eq!(
+            table.iter().filter(|s| s.is_some()).count(),
+            SPEC_TABLE.len()
+        );
+        for (idx, spec) in table.iter().enumerate() {
+            assert_eq!(spec.unwrap().opcode_byte as usize, idx);
+        }
+    }
+
+    #[test]
+    fn test_encoding_table() {
+        let table = encoding_table();
+        let encoding = |mnemonic: &str, mode| table[mnemonic][&mode];
+        assert_eq!(
+            encoding("LDA", AddrMode::Immediate),
+            Encoding {
+                official: Some(0xA9),
+                unofficial: None
+            }
+        );
+        assert_eq!(
+            encoding("SBC", AddrMode::Immediate),
+            Encoding {
+                official: Some(0xE9),
+                unofficial: Some(0xEB)
+            }
         );
+        assert_eq!(encoding("NOP", AddrMode::Implicit).unofficial, Some(0x1A));
+        assert_eq!(encoding("NOP", AddrMode::ZeroPageX).unofficial, Some(0x14));
+        assert!(!table["STA"].contains_key(&AddrMode::Immediate));
     }
-    map
 }
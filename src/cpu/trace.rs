@@ -1,10 +1,57 @@
 use super::Instruction;
 use super::CPU;
+use crate::trace_log::TraceRecord;
 
 impl CPU<'_> {
+    // The instruction at PC, the registers and the PPU position, in the
+    // nestest log format
     pub fn trace(&mut self) -> String {
+        let (scanline, dot) = self.bus.ppu_position();
+        format!(
+            "{}  A:{:02X?} X:{:02X?} Y:{:02X?} P:{:02X?} SP:{:02X?} PPU:{:3},{:3} CYC:{}",
+            self.trace_inst(),
+            self.acc,
+            self.reg_x,
+            self.reg_y,
+            self.status.bits,
+            self.sp,
+            scanline,
+            dot,
+            self.total_cycles
+        )
+    }
+
+    // The PC, bytes and disassembly of the instruction at PC, padded to the
+    // width of the nestest log columns
+    pub(crate) fn trace_inst(&mut self) -> String {
+        self.peeking = true;
+        let line = self.format_inst();
+        self.peeking = false;
+        line
+    }
+
+    pub(crate) fn trace_record(&mut self) -> TraceRecord {
+        let pc = self.pc;
+        let (scanline, dot) = self.bus.ppu_position();
+        TraceRecord {
+            registers: self.registers(),
+            bytes: [
+                self.bus.peek(pc),
+                self.bus.peek(pc.wrapping_add(1)),
+                self.bus.peek(pc.wrapping_add(2)),
+            ],
+            scanline: scanline as u16,
+            dot: dot as u16,
+            cycles: self.total_cycles,
+        }
+    }
+
+    fn format_inst(&mut self) -> String {
         let pc = self.pc;
-        let inst = self.peak_next_instruction();
+        let inst = match self.peak_next_instruction() {
+            Ok(inst) => inst,
+            Err(e) => return format!("{:04X?}  {:40}", pc, e),
+        };
         let inst_bytes: Vec<u8> = match inst.spec.addr_mode.size() {
             0 => vec![inst.opcode_byte],
             1 => vec![inst.opcode_byte, self.read(pc + 1)],
@@ -17,18 +64,47 @@ impl CPU<'_> {
             .collect::<Vec<String>>()
             .join(" ");
         let asm = CPU::disassemble(self, &inst);
-        format!(
-            "{:04X?}  {:8} {:31}  A:{:02X?} X:{:02X?} Y:{:02X?} P:{:02X?} SP:{:02X?} CYC:{}",
-            pc,
-            inst_bytes_str,
-            asm,
-            self.acc,
-            self.reg_x,
-            self.reg_y,
-            self.status.bits,
-            self.sp,
-            self.total_cycles
-        )
+        format!("{:04X?}  {:8} {:31}", pc, inst_bytes_str, asm)
+    }
+
+    // Disassemble `count` instructions starting at `addr`, one line each
+    // with the address and instruction bytes. Operand values are read from
+    // memory like `trace` does.
+    pub fn disassemble_at(&mut self, addr: u16, count: usize) -> Vec<String> {
+        self.peeking = true;
+        let pc = self.pc;
+        let mut lines = Vec::with_capacity(count);
+        self.pc = addr;
+        for _ in 0..count {
+            let inst_pc = self.pc;
+            let inst = match self.peak_next_instruction() {
+                Ok(inst) => inst,
+                Err(_) => {
+                    let byte = self.read(inst_pc);
+                    lines.push(format!(
+                        "{:04X}  {:02X}        .db ${:02X}",
+                        inst_pc, byte, byte
+                    ));
+                    self.pc = inst_pc.wrapping_add(1);
+                    continue;
+                }
+            };
+            let size = inst.spec.addr_mode.size() as u16;
+            let bytes: Vec<String> = (0..=size)
+                .map(|i| format!("{:02X}", self.read(inst_pc.wrapping_add(i))))
+                .collect();
+            let asm = CPU::disassemble(self, &inst);
+            lines.push(format!(
+                "{:04X}  {:8} {}",
+                inst_pc,
+                bytes.join(" "),
+                asm.trim_end()
+            ));
+            self.pc = inst_pc.wrapping_add(1 + size);
+        }
+        self.pc = pc;
+        self.peeking = false;
+        lines
     }
 
     fn disassemble(&mut self, inst: &Instruction) -> String {
@@ -45,44 +121,44 @@ impl CPU<'_> {
         let next_u16: u16 = self.read_u16(self.pc + 1);
         let oprands_asm: String = match inst.spec.addr_mode {
             Absolute => match inst.spec.opcode {
-                JMP | JSR => format!("${:04X?}", inst.oprand_addr),
+                JMP | JSR => self.symbol_or_hex(inst.oprand_addr, false),
                 _ => format!(
-                    "${:04X?} = {:02X?}",
-                    inst.oprand_addr,
+                    "{} = {:02X?}",
+                    self.symbol_or_hex(inst.oprand_addr, false),
                     self.read(inst.oprand_addr)
                 ),
             },
             AbsoluteX => format!(
-                "${:04X?},X @ {:04X?} = {:02X?}",
-                next_u16,
+                "{},X @ {:04X?} = {:02X?}",
+                self.symbol_or_hex(next_u16, false),
                 inst.oprand_addr,
                 self.read(inst.oprand_addr)
             ),
             AbsoluteY => format!(
-                "${:04X?},Y @ {:04X?} = {:02X?}",
-                next_u16,
+                "{},Y @ {:04X?} = {:02X?}",
+                self.symbol_or_hex(next_u16, false),
                 inst.oprand_addr,
                 self.read(inst.oprand_addr)
             ),
             ZeroPage => format!(
-                "${:02X?} = {:02X?}",
-                inst.oprand_addr,
+                "{} = {:02X?}",
+                self.symbol_or_hex(inst.oprand_addr, true),
                 self.read(inst.oprand_addr)
             ),
             ZeroPageX => format!(
-                "${:02X?},X @ {:02X?} = {:02X?}",
-                next_u8,
+                "{},X @ {:02X?} = {:02X?}",
+                self.symbol_or_hex(next_u8 as u16, true),
                 inst.oprand_addr as u8,
                 self.read(inst.oprand_addr)
             ),
             ZeroPageY => format!(
-                "${:02X?},Y @ {:02X?} = {:02X?}",
-                next_u8,
+                "{},Y @ {:02X?} = {:02X?}",
+                self.symbol_or_hex(next_u8 as u16, true),
                 inst.oprand_addr as u8,
                 self.read(inst.oprand_addr)
             ),
             Immediate => format!("#${:02X?}", self.read(inst.oprand_addr)),
-            Relative => format!("${:04X}", inst.oprand_addr),
+            Relative => self.symbol_or_hex(inst.oprand_addr, false),
             Implicit => match inst.spec.opcode {
                 ASL | LSR | ROL | ROR => "A".to_string(),
                 _ => "".to_string(),
@@ -102,12 +178,16 @@ impl CPU<'_> {
                 } else {
                     inst.oprand_addr
                 };
-                format!("(${:04X?}) = {:04X?}", addr_before_indirect, oprand_addr)
+                format!(
+                    "({}) = {:04X?}",
+                    self.symbol_or_hex(addr_before_indirect, false),
+                    oprand_addr
+                )
             }
             IndexedIndirect => {
                 format!(
-                    "(${:02X?},X) @ {:02X?} = {:04X?} = {:02X?}",
-                    next_u8,
+                    "({},X) @ {:02X?} = {:04X?} = {:02X?}",
+                    self.symbol_or_hex(next_u8 as u16, true),
                     next_u8.wrapping_add(self.reg_x),
                     inst.oprand_addr,
                     self.read(inst.oprand_addr)
@@ -122,8 +202,8 @@ impl CPU<'_> {
                     self.read_u16(next_u8 as u16)
                 };
                 format!(
-                    "(${:02X?}),Y = {:04X?} @ {:04X?} = {:02X?}",
-                    next_u8,
+                    "({}),Y = {:04X?} @ {:04X?} = {:02X?}",
+                    self.symbol_or_hex(next_u8 as u16, true),
                     addr_before_add_y,
                     inst.oprand_addr,
                     self.read(inst.oprand_addr)
@@ -134,4 +214,13 @@ impl CPU<'_> {
         asm.push_str(&oprands_asm);
         asm
     }
+
+    // The name of `addr` from `symbols`, or the address in hex
+    fn symbol_or_hex(&self, addr: u16, zero_page: bool) -> String {
+        match self.symbols.as_ref().and_then(|symbols| symbols.get(addr)) {
+            Some(name) => name.to_string(),
+            None if zero_page => format!("${:02X}", addr),
+            None => format!("${:04X}", addr),
+        }
+    }
 }
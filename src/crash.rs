@@ -0,0 +1,173 @@
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::cpu::{CpuError, CPU};
+use crate::trace_log::{TraceFields, TraceRecord};
+
+// Crash reports: when the emulation can't go on, because the CPU jammed or
+// hit an illegal opcode, or because of a bug in the emulator (a panic),
+// `Emulator::run_frame` returns an `EmulationError` with the state the
+// console was in instead of taking the process down. The frontend can show
+// it and reset or load a state to keep going.
+
+// How many of the last instructions a report has, when the CPU records a
+// history (see `CPU::history`)
+pub const REPORT_HISTORY: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cause {
+    Cpu(CpuError),
+    // a panic in the emulator, with its message
+    Panic(String),
+}
+
+impl fmt::Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cause::Cpu(e) => write!(f, "{}", e),
+            Cause::Panic(message) => write!(f, "emulator crashed: {}", message),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmulationError {
+    pub cause: Cause,
+    pub frame: u64,
+    // the registers and the instruction at PC when it stopped
+    pub state: TraceRecord,
+    // the instructions that ran before, the oldest first
+    pub history: Vec<TraceRecord>,
+}
+
+impl EmulationError {
+    pub(crate) fn new(cause: Cause, cpu: &mut CPU) -> Self {
+        let history = match &cpu.history {
+            Some(history) => history.last(REPORT_HISTORY).copied().collect(),
+            None => vec![],
+        };
+        EmulationError {
+            cause,
+            frame: cpu.bus.ppu.frame_number(),
+            state: cpu.trace_record(),
+            history,
+        }
+    }
+
+    // The error and the state dump as text, to show or save with a bug
+    // report
+    pub fn report(&self) -> String {
+        let mut report = format!("{}\nframe {}\n", self, self.frame);
+        if !self.history.is_empty() {
+            report.push_str("last instructions:\n");
+            for record in &self.history {
+                report.push_str(&record.to_text(TraceFields::all()));
+                report.push('\n');
+            }
+        }
+        report.push_str("stopped at:\n");
+        report.push_str(&self.state.to_text(TraceFields::all()));
+        report.push('\n');
+        report
+    }
+}
+
+impl fmt::Display for EmulationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.cause)
+    }
+}
+
+impl std::error::Error for EmulationError {}
+
+// Run `f` with the CPU, its errors and panics become an `EmulationError`.
+// The console is left as the panic found it, which is enough to look at
+// or to reset.
+pub(crate) fn catch<'a, T, F>(cpu: &mut CPU<'a>, f: F) -> Result<T, EmulationError>
+where
+    F: FnOnce(&mut CPU<'a>) -> Result<T, CpuError>,
+{
+    let cause = match panic::catch_unwind(AssertUnwindSafe(|| f(cpu))) {
+        Ok(Ok(value)) => return Ok(value),
+        Ok(Err(e)) => Cause::Cpu(e),
+        Err(payload) => Cause::Panic(panic_message(&*payload)),
+    };
+    Err(EmulationError::new(cause, cpu))
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::emulator::Emulator;
+
+    #[test]
+    fn test_cpu_error() {
+        // LDX #$10; INX; TAS, disabled
+        let mut program = vec![0xA2, 0x10, 0xE8, 0x9B];
+        program.resize(0x3FFC, 0);
+        program.extend_from_slice(&[0x00, 0x80]);
+        let mut emulator = Emulator::new(Cartridge::new_from_program(program));
+        emulator.cpu_mut().disable_opcode(0x9B);
+        emulator.attach_history(16);
+        let e = emulator.run_frame().err().unwrap();
+        let cpu_error = CpuError::IllegalOpcode {
+            opcode: 0x9B,
+            pc: 0x8003,
+        };
+        assert_eq!(e.cause, Cause::Cpu(cpu_error));
+        assert_eq!(e.to_string(), "illegal opcode 9B at 8003");
+        assert_eq!(e.frame, 0);
+        assert_eq!(e.state.registers.x, 0x11);
+
+        let report = e.report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines[..3],
+            ["illegal opcode 9B at 8003", "frame 0", "last instructions:"]
+        );
+        assert!(
+            lines[3].starts_with("8000  A2 10     LDX #$10"),
+            "{}",
+            report
+        );
+        assert_eq!(lines[lines.len() - 2], "stopped at:");
+        assert!(
+            lines[lines.len() - 1].starts_with("8003  9B "),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn test_panic() {
+        let mut program = vec![0xEA; 0x3FFC];
+        program.extend_from_slice(&[0x00, 0x80]);
+        let mut emulator = Emulator::new(Cartridge::new_from_program(program));
+        let e = catch(emulator.cpu_mut(), |cpu| -> Result<(), CpuError> {
+            cpu.step()?;
+            panic!("bad {}", "mapper")
+        })
+        .unwrap_err();
+        assert_eq!(e.cause, Cause::Panic("bad mapper".to_string()));
+        assert_eq!(e.to_string(), "emulator crashed: bad mapper");
+        assert_eq!(e.state.registers.pc, 0x8001);
+        assert!(e.history.is_empty());
+        assert!(!e.report().contains("last instructions"));
+
+        // the emulator goes on
+        emulator.run_frame().unwrap();
+        assert_eq!(emulator.frame_number(), 1);
+    }
+}
@@ -0,0 +1,500 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::cpu::Interrupt;
+use crate::expr::Expr;
+
+// Breakpoints and watchpoints. A `Debugger` attached to the CPU (see
+// `CPU::debugger`) makes `CPU::run` return at the next instruction boundary
+// when one of them is hit. The caller gets the reason from `CPU::take_break`
+// and decides what to do, e.g. open a monitor prompt, before running again.
+//
+// Execution stops:
+//   - before the instruction at a PC breakpoint, when its condition (see
+//     `expr`) is true or it has none
+//   - after the instruction that accessed a watched address
+//   - before the first instruction of an NMI or IRQ handler
+//   - where a step over a JSR, a step out of a routine or a run to an
+//     address ends. These stops are forgotten once execution stops.
+//
+// Watch expressions don't stop anything, they're kept for the frontend to
+// show, e.g. the monitor after every step.
+//
+// The debugger also keeps the call stack: JSR, BRK and interrupts add a
+// frame, RTS and RTI remove it. Games don't always return where they were
+// called, so a return removes every frame it goes back past (PLA PLA RTS
+// returns to the caller's caller), one that doesn't go back past the
+// frame's return address removes none (RTS used as a jump), and a call
+// from above a frame removes it as its return address can't be on the
+// stack any more (TXS, or JMP out of a routine). Calls made before the
+// debugger was attached aren't known.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakReason {
+    Breakpoint {
+        pc: u16,
+    },
+    // `pc` is the instruction that made the access
+    Watchpoint {
+        addr: u16,
+        value: u8,
+        access: Access,
+        pc: u16,
+    },
+    Interrupt(Interrupt),
+    // a step over, step out or run to an address ended
+    Until {
+        pc: u16,
+    },
+}
+
+impl fmt::Display for BreakReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BreakReason::Breakpoint { pc } => write!(f, "breakpoint at {:04X}", pc),
+            BreakReason::Watchpoint {
+                addr,
+                value,
+                access,
+                pc,
+            } => {
+                let access = match access {
+                    Access::Read => "read",
+                    Access::Write => "write",
+                };
+                write!(
+                    f,
+                    "{} {:02X} at {:04X} by the instruction at {:04X}",
+                    access, value, addr, pc
+                )
+            }
+            BreakReason::Interrupt(interrupt) => write!(f, "{:?}", interrupt),
+            BreakReason::Until { pc } => write!(f, "stopped at {:04X}", pc),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<u16>,
+    pub read: bool,
+    pub write: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CallKind {
+    Jsr,
+    Brk,
+    Interrupt(Interrupt),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    pub kind: CallKind,
+    // the routine called
+    pub entry: u16,
+    // where it returns to: after the JSR or BRK, or the interrupted
+    // instruction
+    pub return_pc: u16,
+    // the stack pointer before the call
+    pub sp: u8,
+}
+
+// Each call takes at least 2 bytes of the stack page
+const MAX_CALL_DEPTH: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Until {
+    // before the instruction at `pc`, with the stack pointer at `sp` or
+    // above
+    Reach { pc: u16, sp: Option<u8> },
+    // after the return that takes the stack pointer above `sp`
+    Return { sp: u8 },
+}
+
+pub struct Debugger {
+    // PC to the condition of the breakpoint
+    breakpoints: BTreeMap<u16, Option<Expr>>,
+    watchpoints: Vec<Watchpoint>,
+    watch_exprs: Vec<Expr>,
+    pub break_on_nmi: bool,
+    pub break_on_irq: bool,
+
+    // Break waiting to be reported, execution doesn't continue until it is
+    // taken
+    pending: Option<BreakReason>,
+    // The breakpoint we stopped at, so running again doesn't stop there
+    // right away
+    resume_pc: Option<u16>,
+    until: Option<Until>,
+    // outermost first
+    call_stack: Vec<Frame>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: BTreeMap::new(),
+            watchpoints: vec![],
+            watch_exprs: vec![],
+            break_on_nmi: false,
+            break_on_irq: false,
+            pending: None,
+            resume_pc: None,
+            until: None,
+            call_stack: vec![],
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, pc: u16) {
+        self.breakpoints.insert(pc, None);
+    }
+
+    // A breakpoint that only stops when `condition` is true. It replaces
+    // the one at `pc`.
+    pub fn add_conditional_breakpoint(&mut self, pc: u16, condition: Expr) {
+        self.breakpoints.insert(pc, Some(condition));
+    }
+
+    // Returns whether there was a breakpoint at `pc`
+    pub fn remove_breakpoint(&mut self, pc: u16) -> bool {
+        self.breakpoints.remove(&pc).is_some()
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.keys().copied()
+    }
+
+    pub fn breakpoint_condition(&self, pc: u16) -> Option<&Expr> {
+        self.breakpoints.get(&pc)?.as_ref()
+    }
+
+    // Returns the index of the watchpoint, for `remove_watchpoint`
+    pub fn add_watchpoint(&mut self, range: RangeInclusive<u16>, read: bool, write: bool) -> usize {
+        self.watchpoints.push(Watchpoint { range, read, write });
+        self.watchpoints.len() - 1
+    }
+
+    pub fn remove_watchpoint(&mut self, idx: usize) -> Watchpoint {
+        self.watchpoints.remove(idx)
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    // Returns the index of the expression, for `remove_watch_expr`
+    pub fn add_watch_expr(&mut self, expr: Expr) -> usize {
+        self.watch_exprs.push(expr);
+        self.watch_exprs.len() - 1
+    }
+
+    pub fn remove_watch_expr(&mut self, idx: usize) -> Expr {
+        self.watch_exprs.remove(idx)
+    }
+
+    pub fn watch_exprs(&self) -> &[Expr] {
+        &self.watch_exprs
+    }
+
+    // Stop before the instruction at `pc`, once
+    pub fn run_to(&mut self, pc: u16) {
+        self.until = Some(Until::Reach { pc, sp: None });
+    }
+
+    // Stop when the JSR about to run returns to `next_pc`, the instruction
+    // after it. `sp` is the stack pointer before the JSR, recursive calls
+    // that come back to `next_pc` deeper in the stack don't stop.
+    pub fn step_over(&mut self, next_pc: u16, sp: u8) {
+        self.until = Some(Until::Reach {
+            pc: next_pc,
+            sp: Some(sp),
+        });
+    }
+
+    // Stop after the routine running with the stack pointer at `sp`
+    // returns, the routines it calls and interrupts return to it
+    pub fn step_out(&mut self, sp: u8) {
+        self.until = Some(Until::Return { sp });
+    }
+
+    // Remove all breakpoints and watchpoints
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+        self.break_on_nmi = false;
+        self.break_on_irq = false;
+    }
+
+    // The calls that haven't returned, outermost first
+    pub fn call_stack(&self) -> &[Frame] {
+        &self.call_stack
+    }
+
+    pub fn has_break(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    pub fn take_break(&mut self) -> Option<BreakReason> {
+        // a step that stopped at a breakpoint is over too
+        self.until = None;
+        self.pending.take()
+    }
+
+    // ------------------------------------------------------------------------
+    // Hooks called by the CPU
+    // ------------------------------------------------------------------------
+
+    // At an instruction boundary, before the instruction at `pc` with the
+    // stack pointer at `sp`. Returns true when execution should stop.
+    // `is_true` evaluates breakpoint conditions.
+    pub(crate) fn check_instruction(
+        &mut self,
+        pc: u16,
+        sp: u8,
+        is_true: impl FnOnce(&Expr) -> bool,
+    ) -> bool {
+        if self.pending.is_some() {
+            return true;
+        }
+        if self.resume_pc.take() == Some(pc) {
+            return false;
+        }
+        if let Some(Until::Reach {
+            pc: until_pc,
+            sp: until_sp,
+        }) = self.until
+        {
+            if pc == until_pc && until_sp.is_none_or(|until_sp| sp >= until_sp) {
+                self.until = None;
+                self.pending = Some(BreakReason::Until { pc });
+                return true;
+            }
+        }
+        let hit = match self.breakpoints.get(&pc) {
+            Some(Some(condition)) => is_true(condition),
+            Some(None) => true,
+            None => false,
+        };
+        if hit {
+            self.pending = Some(BreakReason::Breakpoint { pc });
+            self.resume_pc = Some(pc);
+            return true;
+        }
+        false
+    }
+
+    pub(crate) fn on_access(&mut self, addr: u16, value: u8, access: Access, pc: u16) {
+        if self.pending.is_some() {
+            return;
+        }
+        let hit = self.watchpoints.iter().any(|w| {
+            w.range.contains(&addr)
+                && match access {
+                    Access::Read => w.read,
+                    Access::Write => w.write,
+                }
+        });
+        if hit {
+            self.pending = Some(BreakReason::Watchpoint {
+                addr,
+                value,
+                access,
+                pc,
+            });
+        }
+    }
+
+    // After a JSR or BRK, or once an interrupt pushed the return address
+    pub(crate) fn on_call(&mut self, frame: Frame) {
+        while self.call_stack.last().is_some_and(|f| f.sp <= frame.sp) {
+            self.call_stack.pop();
+        }
+        if self.call_stack.len() == MAX_CALL_DEPTH {
+            self.call_stack.remove(0);
+        }
+        self.call_stack.push(frame);
+    }
+
+    // After an RTS or RTI that returned to `pc` with the stack pointer at
+    // `sp`
+    pub(crate) fn on_return(&mut self, pc: u16, sp: u8) {
+        while self.call_stack.last().is_some_and(|f| f.sp <= sp) {
+            self.call_stack.pop();
+        }
+        if let Some(Until::Return { sp: until_sp }) = self.until {
+            if sp > until_sp && self.pending.is_none() {
+                self.until = None;
+                self.pending = Some(BreakReason::Until { pc });
+            }
+        }
+    }
+
+    pub(crate) fn on_interrupt(&mut self, interrupt: Interrupt) {
+        let enabled = match interrupt {
+            Interrupt::NMI => self.break_on_nmi,
+            Interrupt::IRQ => self.break_on_irq,
+        };
+        if enabled && self.pending.is_none() {
+            self.pending = Some(BreakReason::Interrupt(interrupt));
+        }
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_breakpoint_resume() {
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(0x8000);
+        assert!(!debugger.check_instruction(0x7FFF, 0xFD, |_| true));
+        assert!(debugger.check_instruction(0x8000, 0xFD, |_| true));
+        // not taken yet
+        assert!(debugger.check_instruction(0x8000, 0xFD, |_| true));
+        assert_eq!(
+            debugger.take_break(),
+            Some(BreakReason::Breakpoint { pc: 0x8000 })
+        );
+        // continuing executes the instruction at the breakpoint
+        assert!(!debugger.check_instruction(0x8000, 0xFD, |_| true));
+        assert!(!debugger.check_instruction(0x8001, 0xFD, |_| true));
+        assert!(debugger.check_instruction(0x8000, 0xFD, |_| true));
+
+        assert!(debugger.remove_breakpoint(0x8000));
+        assert!(!debugger.remove_breakpoint(0x8000));
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        let mut debugger = Debugger::new();
+        let condition = Expr::parse("A == $3F").unwrap();
+        debugger.add_conditional_breakpoint(0x8000, condition.clone());
+        assert_eq!(debugger.breakpoint_condition(0x8000), Some(&condition));
+        assert!(!debugger.check_instruction(0x8000, 0xFD, |_| false));
+        assert!(!debugger.has_break());
+        assert!(debugger.check_instruction(0x8000, 0xFD, |c| c == &condition));
+        assert_eq!(
+            debugger.take_break(),
+            Some(BreakReason::Breakpoint { pc: 0x8000 })
+        );
+
+        // a plain breakpoint replaces it
+        debugger.add_breakpoint(0x8000);
+        assert_eq!(debugger.breakpoint_condition(0x8000), None);
+        assert!(!debugger.check_instruction(0x8001, 0xFD, |_| false));
+        assert!(debugger.check_instruction(0x8000, 0xFD, |_| false));
+    }
+
+    #[test]
+    fn test_until() {
+        let mut debugger = Debugger::new();
+        debugger.run_to(0x8010);
+        assert!(!debugger.check_instruction(0x8000, 0xFD, |_| true));
+        assert!(debugger.check_instruction(0x8010, 0xFD, |_| true));
+        assert_eq!(
+            debugger.take_break(),
+            Some(BreakReason::Until { pc: 0x8010 })
+        );
+        // only once
+        assert!(!debugger.check_instruction(0x8010, 0xFD, |_| true));
+
+        // a recursive call returning deeper in the stack
+        debugger.step_over(0x8003, 0xFD);
+        assert!(!debugger.check_instruction(0x8003, 0xFB, |_| true));
+        assert!(debugger.check_instruction(0x8003, 0xFD, |_| true));
+        debugger.take_break();
+
+        debugger.step_out(0xFB);
+        debugger.on_return(0x9000, 0xFB);
+        assert!(!debugger.has_break());
+        debugger.on_return(0x8003, 0xFD);
+        assert_eq!(
+            debugger.take_break(),
+            Some(BreakReason::Until { pc: 0x8003 })
+        );
+
+        // another break ends the step
+        debugger.add_breakpoint(0x8006);
+        debugger.step_over(0x8003, 0xFD);
+        assert!(debugger.check_instruction(0x8006, 0xFB, |_| true));
+        debugger.take_break();
+        assert!(!debugger.check_instruction(0x8003, 0xFD, |_| true));
+    }
+
+    #[test]
+    fn test_call_stack() {
+        let frame = |entry: u16, sp: u8| Frame {
+            kind: CallKind::Jsr,
+            entry,
+            return_pc: 0x8003,
+            sp,
+        };
+        let entries = |debugger: &Debugger| -> Vec<u16> {
+            debugger.call_stack().iter().map(|f| f.entry).collect()
+        };
+        let mut debugger = Debugger::new();
+        debugger.on_call(frame(0x8100, 0xFD));
+        debugger.on_call(frame(0x8200, 0xFB));
+        debugger.on_call(frame(0x8300, 0xF9));
+        debugger.on_return(0x8003, 0xF9);
+        assert_eq!(entries(&debugger), [0x8100, 0x8200]);
+        // an address pushed and returned to
+        debugger.on_return(0x8400, 0xF9);
+        assert_eq!(entries(&debugger), [0x8100, 0x8200]);
+        // the return address was pulled, returns to the outer caller
+        debugger.on_return(0x8003, 0xFD);
+        assert!(debugger.call_stack().is_empty());
+
+        // a call with the stack pointer reset takes the place of the others
+        debugger.on_call(frame(0x8100, 0xFD));
+        debugger.on_call(frame(0x8200, 0xFB));
+        debugger.on_call(frame(0x8300, 0xFD));
+        assert_eq!(entries(&debugger), [0x8300]);
+
+        for i in 2..200 {
+            debugger.on_call(frame(0x8100, 0xFF - i as u8));
+        }
+        assert_eq!(debugger.call_stack().len(), MAX_CALL_DEPTH);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut debugger = Debugger::new();
+        debugger.add_watchpoint(0x0200..=0x02FF, false, true);
+        debugger.on_access(0x0200, 1, Access::Read, 0x8000);
+        debugger.on_access(0x0300, 1, Access::Write, 0x8000);
+        assert!(!debugger.has_break());
+        debugger.on_access(0x02FF, 1, Access::Write, 0x8000);
+        // the first access is reported
+        debugger.on_access(0x0200, 2, Access::Write, 0x8000);
+        let reason = debugger.take_break().unwrap();
+        assert_eq!(
+            reason,
+            BreakReason::Watchpoint {
+                addr: 0x02FF,
+                value: 1,
+                access: Access::Write,
+                pc: 0x8000
+            }
+        );
+        assert_eq!(
+            reason.to_string(),
+            "write 01 at 02FF by the instruction at 8000"
+        );
+    }
+}
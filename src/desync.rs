@@ -0,0 +1,201 @@
+use std::fmt;
+
+use crate::emulator::Emulator;
+use crate::input_log::Movie;
+use crate::savestate::StateHash;
+
+// Desync detection: play a movie twice and compare the state after every
+// frame. Any difference means the emulation isn't deterministic, or that
+// a save state doesn't capture everything, which breaks movies, rewind and
+// netplay.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Desync {
+    // first frame (0-based) after which the states differ
+    pub frame: usize,
+    pub expected: StateHash,
+    pub actual: StateHash,
+}
+
+impl Desync {
+    // The parts of the machine whose state differs
+    pub fn components(&self) -> Vec<&'static str> {
+        let mut components = vec![];
+        if self.expected.cpu != self.actual.cpu {
+            components.push("CPU");
+        }
+        if self.expected.ppu != self.actual.ppu {
+            components.push("PPU");
+        }
+        if self.expected.apu != self.actual.apu {
+            components.push("APU");
+        }
+        if components.is_empty() {
+            components.push("cartridge/DMA/joypads");
+        }
+        components
+    }
+}
+
+impl fmt::Display for Desync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "desync after frame {} in {}",
+            self.frame,
+            self.components().join(", ")
+        )
+    }
+}
+
+// Play `movie` and hash the state after every frame
+pub fn frame_hashes(movie: &Movie, emulator: &mut Emulator) -> Result<Vec<StateHash>, String> {
+    movie.start_playback(emulator)?;
+    let mut hashes = Vec::with_capacity(movie.len());
+    for frame in movie.frames.iter() {
+        frame.apply(emulator);
+        emulator.run_frame().map_err(|e| e.to_string())?;
+        hashes.push(emulator.state_hash());
+    }
+    Ok(hashes)
+}
+
+// Play `movie` on two emulators from `new_emulator` and report the first
+// frame where they diverge
+pub fn check_replay<F>(movie: &Movie, mut new_emulator: F) -> Result<Option<Desync>, String>
+where
+    F: FnMut() -> Result<Emulator, String>,
+{
+    let expected = frame_hashes(movie, &mut new_emulator()?)?;
+    let actual = frame_hashes(movie, &mut new_emulator()?)?;
+    Ok(first_desync(&expected, &actual, 0))
+}
+
+// Play `movie`, but after `frame` frames move to a new emulator through a
+// save state and play the rest there. Reports where this diverges from
+// playing the whole movie on one emulator.
+pub fn check_save_state<F>(
+    movie: &Movie,
+    frame: usize,
+    mut new_emulator: F,
+) -> Result<Option<Desync>, String>
+where
+    F: FnMut() -> Result<Emulator, String>,
+{
+    if frame > movie.len() {
+        return Err(format!("the movie has only {} frames", movie.len()));
+    }
+    let expected = frame_hashes(movie, &mut new_emulator()?)?;
+
+    let mut emulator = new_emulator()?;
+    movie.start_playback(&mut emulator)?;
+    for input in movie.frames[..frame].iter() {
+        input.apply(&mut emulator);
+        emulator.run_frame().map_err(|e| e.to_string())?;
+    }
+    let state = emulator.save_state();
+
+    let mut emulator = new_emulator()?;
+    emulator.load_state(&state)?;
+    let mut actual = vec![];
+    for input in movie.frames[frame..].iter() {
+        input.apply(&mut emulator);
+        emulator.run_frame().map_err(|e| e.to_string())?;
+        actual.push(emulator.state_hash());
+    }
+    Ok(first_desync(&expected[frame..], &actual, frame))
+}
+
+// `offset` is the frame number of the first hashes
+fn first_desync(expected: &[StateHash], actual: &[StateHash], offset: usize) -> Option<Desync> {
+    expected
+        .iter()
+        .zip(actual.iter())
+        .position(|(e, a)| e != a)
+        .map(|i| Desync {
+            frame: offset + i,
+            expected: expected[i],
+            actual: actual[i],
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::input_log::MovieFrame;
+    use crate::joypad::JoypadStatus;
+
+    // Mixes the joypad into RAM every frame, so any input difference shows:
+    //
+    //   LDA #$80 : STA $2000      ; NMI on
+    //   loop: INC $01 : JMP loop
+    //   nmi:  LDA #1 : STA $4016 : LSR A : STA $4016
+    //         LDA $4016 : EOR $00 : ROL A : STA $00 : RTI
+    fn new_emulator() -> Result<Emulator, String> {
+        let mut program = vec![
+            0xA9, 0x80, 0x8D, 0x00, 0x20, // 8000
+            0xE6, 0x01, 0x4C, 0x05, 0x80, // 8005
+            0xA9, 0x01, 0x8D, 0x16, 0x40, 0x4A, 0x8D, 0x16, 0x40, // 800A
+            0xAD, 0x16, 0x40, 0x45, 0x00, 0x2A, 0x85, 0x00, 0x40, // 8013
+        ];
+        program.resize(0x3FFA, 0);
+        program.extend_from_slice(&[0x0A, 0x80, 0x00, 0x80, 0x00, 0x80]);
+        Ok(Emulator::new(Cartridge::new_from_program(program)))
+    }
+
+    fn test_movie() -> Movie {
+        let mut movie = Movie::new(None, None);
+        for i in 0..12 {
+            let a = if i % 3 == 0 {
+                JoypadStatus::BUTTON_A
+            } else {
+                JoypadStatus::empty()
+            };
+            movie.record(MovieFrame::new([a, JoypadStatus::empty()]));
+        }
+        movie
+    }
+
+    #[test]
+    fn test_deterministic_replay() {
+        let movie = test_movie();
+        assert_eq!(check_replay(&movie, new_emulator), Ok(None));
+        for frame in [0, 5, 12] {
+            assert_eq!(check_save_state(&movie, frame, new_emulator), Ok(None));
+        }
+        assert!(check_save_state(&movie, 13, new_emulator).is_err());
+    }
+
+    #[test]
+    fn test_detects_desync() {
+        let movie = test_movie();
+        // the second emulator gets a different start
+        let mut runs = 0;
+        let desync = check_replay(&movie, || {
+            runs += 1;
+            let mut emulator = new_emulator()?;
+            if runs == 2 {
+                emulator.set_buttons(0, JoypadStatus::BUTTON_A);
+                emulator.run_frame().map_err(|e| e.to_string())?;
+            }
+            Ok(emulator)
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(desync.frame, 0);
+        assert!(desync.components().contains(&"CPU"));
+        assert!(desync
+            .to_string()
+            .starts_with("desync after frame 0 in CPU"));
+
+        let expected = frame_hashes(&movie, &mut new_emulator().unwrap()).unwrap();
+        let mut actual = expected.clone();
+        actual[7].ppu ^= 1;
+        actual[7].all ^= 1;
+        let desync = first_desync(&expected, &actual, 0).unwrap();
+        assert_eq!(desync.frame, 7);
+        assert_eq!(desync.components(), ["PPU"]);
+        assert_eq!(first_desync(&expected, &expected, 0), None);
+    }
+}
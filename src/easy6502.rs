@@ -0,0 +1,219 @@
+use std::path::Path;
+
+use crate::bus::Bus;
+use crate::cartridge::Cartridge;
+use crate::cpu::assembler::{assemble_file, assemble_with_start_addr};
+use crate::cpu::{CpuError, CPU};
+use crate::graphics::{NesFrame, NES_HEIGHT, NES_WIDTH};
+
+// Runs programs written for the Easy6502 tutorial's simulator
+// (https://skilldrick.github.io/easy6502/) on the NES CPU and RAM:
+//
+//   $0200-$05FF  a 32x32 screen, one byte per pixel, the low 4 bits pick
+//                one of 16 colors
+//   $0600        where the program is loaded and starts
+//   $FE          a new random byte before every instruction
+//   $FF          the ASCII code of the last key pressed
+//
+// The program stops at BRK. All of it fits in the 2K of internal RAM, the
+// rest of the NES is idle.
+
+pub const PROGRAM_ADDR: u16 = 0x0600;
+pub const SCREEN_ADDR: u16 = 0x0200;
+pub const SCREEN_SIZE: u32 = 32;
+pub const RANDOM_ADDR: u16 = 0xFE;
+pub const LAST_KEY_ADDR: u16 = 0xFF;
+
+// The simulator runs about this many instructions every 1/60 s, programs
+// like its snake game are paced for it
+pub const INSTRUCTIONS_PER_FRAME: usize = 100;
+
+// Each pixel of the screen is drawn as a square this big, centered in the
+// NES frame
+const PIXEL_SIZE: u32 = 7;
+
+// The simulator's colors
+const PALETTE: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00],
+    [0xFF, 0xFF, 0xFF],
+    [0x88, 0x00, 0x00],
+    [0xAA, 0xFF, 0xEE],
+    [0xCC, 0x44, 0xCC],
+    [0x00, 0xCC, 0x55],
+    [0x00, 0x00, 0xAA],
+    [0xEE, 0xEE, 0x77],
+    [0xDD, 0x88, 0x55],
+    [0x66, 0x44, 0x00],
+    [0xFF, 0x77, 0x77],
+    [0x33, 0x33, 0x33],
+    [0x77, 0x77, 0x77],
+    [0xAA, 0xFF, 0x66],
+    [0x00, 0x88, 0xFF],
+    [0xBB, 0xBB, 0xBB],
+];
+
+pub struct Easy6502 {
+    cpu: CPU<'static>,
+    // xorshift state for $FE
+    random: u32,
+    stopped: bool,
+}
+
+impl Easy6502 {
+    // Load machine code at PROGRAM_ADDR
+    pub fn new(program: &[u8]) -> Result<Easy6502, String> {
+        let start = PROGRAM_ADDR as usize;
+        let mut cpu = CPU::new(Bus::new(Cartridge::new_dummy()));
+        let ram = &mut cpu.bus.cpu_ram;
+        if program.len() > ram.len() - start {
+            return Err(format!(
+                "the program is {} bytes, only {} fit at ${:04X}",
+                program.len(),
+                ram.len() - start,
+                PROGRAM_ADDR
+            ));
+        }
+        ram[start..start + program.len()].copy_from_slice(program);
+        cpu.reset();
+        cpu.pc = PROGRAM_ADDR;
+        Ok(Easy6502 {
+            cpu,
+            random: 0x6502,
+            stopped: false,
+        })
+    }
+
+    pub fn from_asm(asm: &str) -> Result<Easy6502, String> {
+        let program = assemble_with_start_addr(asm, PROGRAM_ADDR).map_err(|e| e.to_string())?;
+        Easy6502::new(&program)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Easy6502, String> {
+        let program = assemble_file(path, PROGRAM_ADDR).map_err(|e| e.to_string())?;
+        Easy6502::new(&program)
+    }
+
+    // Seed the random numbers at $FE, they are the same on every run
+    // otherwise
+    pub fn set_seed(&mut self, seed: u32) {
+        // xorshift never leaves 0
+        self.random = seed.max(1);
+    }
+
+    pub fn press_key(&mut self, ascii: u8) {
+        self.cpu.bus.cpu_ram[LAST_KEY_ADDR as usize] = ascii;
+    }
+
+    // The program ran into BRK
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    // Run INSTRUCTIONS_PER_FRAME instructions, or until BRK
+    pub fn run_frame(&mut self) -> Result<(), CpuError> {
+        for _ in 0..INSTRUCTIONS_PER_FRAME {
+            if self.stopped || self.cpu.bus.peek(self.cpu.pc) == 0x00 {
+                self.stopped = true;
+                break;
+            }
+            self.random ^= self.random << 13;
+            self.random ^= self.random >> 17;
+            self.random ^= self.random << 5;
+            self.cpu.bus.cpu_ram[RANDOM_ADDR as usize] = self.random as u8;
+            self.cpu.step()?;
+        }
+        Ok(())
+    }
+
+    // Draw the screen memory, with a black border around it
+    pub fn render(&self, frame: &mut NesFrame) {
+        let left = (NES_WIDTH - SCREEN_SIZE * PIXEL_SIZE) / 2;
+        let top = (NES_HEIGHT - SCREEN_SIZE * PIXEL_SIZE) / 2;
+        for y in 0..NES_HEIGHT {
+            for x in 0..NES_WIDTH {
+                let [r, g, b] = match (x.checked_sub(left), y.checked_sub(top)) {
+                    (Some(sx), Some(sy))
+                        if sx / PIXEL_SIZE < SCREEN_SIZE && sy / PIXEL_SIZE < SCREEN_SIZE =>
+                    {
+                        let addr =
+                            SCREEN_ADDR as u32 + (sy / PIXEL_SIZE) * SCREEN_SIZE + sx / PIXEL_SIZE;
+                        PALETTE[(self.cpu.bus.cpu_ram[addr as usize] & 0x0F) as usize]
+                    }
+                    _ => [0, 0, 0],
+                };
+                frame.set_pixel(x, y, r, g, b);
+            }
+        }
+    }
+
+    pub fn cpu(&self) -> &CPU<'static> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU<'static> {
+        &mut self.cpu
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_program() {
+        let mut sandbox = Easy6502::from_asm(
+            "
+            lda #1
+            sta $0200
+            lda #5
+            sta $05ff
+          wait:
+            lda $ff
+            beq wait
+            sta $0201
+            lda $fe
+            sta $0202
+            lda $fe
+            sta $0203
+            brk",
+        )
+        .unwrap();
+        sandbox.run_frame().unwrap();
+        assert!(!sandbox.is_stopped());
+        sandbox.press_key(b'w');
+        sandbox.run_frame().unwrap();
+        assert!(sandbox.is_stopped());
+        let ram = &sandbox.cpu().bus.cpu_ram;
+        assert_eq!(ram[0x201], b'w');
+        // a new random number for every instruction
+        assert_ne!(ram[0x202], ram[0x203]);
+
+        let mut frame = NesFrame::new();
+        sandbox.render(&mut frame);
+        // 7x7 pixels at (16, 8) for the top left, white
+        assert_eq!(frame.pixel(16, 8), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(frame.pixel(22, 14), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(frame.pixel(15, 8), [0, 0, 0]);
+        // green in the bottom right
+        assert_eq!(frame.pixel(239, 231), [0x00, 0xCC, 0x55]);
+        assert_eq!(frame.pixel(240, 231), [0, 0, 0]);
+
+        assert!(Easy6502::new(&[0xEA; 0x201]).is_err());
+    }
+
+    #[test]
+    fn test_snake() {
+        let mut sandbox = Easy6502::from_asm(include_str!("../examples/snake.asm")).unwrap();
+        for _ in 0..100 {
+            sandbox.run_frame().unwrap();
+        }
+        sandbox.press_key(b's');
+        for _ in 0..100 {
+            sandbox.run_frame().unwrap();
+        }
+        assert!(!sandbox.is_stopped());
+        // the snake is white
+        let screen = &sandbox.cpu().bus.cpu_ram[0x200..0x600];
+        assert!(screen.iter().filter(|&&pixel| pixel == 1).count() >= 2);
+    }
+}
@@ -0,0 +1,770 @@
+use std::cell::Ref;
+use std::io;
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::apu::mixer::Channel;
+use crate::apu_log::ApuLog;
+use crate::audio::wav::WavWriter;
+use crate::audio::{AudioSampler, RingBuffer};
+use crate::bus::{Bus, RamInit};
+use crate::cartridge::Cartridge;
+use crate::cdl::CodeDataLog;
+use crate::cheats::{Cheats, Freezes};
+use crate::cpu::{StepInfo, CPU};
+use crate::crash::{self, EmulationError};
+use crate::debugger::{BreakReason, Debugger};
+use crate::event_log::EventLog;
+use crate::graphics::NesFrame;
+use crate::history::InstructionHistory;
+use crate::joypad::{Joypad, JoypadKind, JoypadStatus};
+use crate::osd::Osd;
+use crate::ppu::{Accuracy, PPU};
+use crate::profiler::Profiler;
+use crate::savestate::StateHash;
+use crate::symbols::Symbols;
+use crate::trace_log::TraceLogger;
+use crate::zapper::Zapper;
+
+// A NES console with a cartridge inserted. This is the entry point for
+// frontends: it hides the wiring between Cartridge, Bus and CPU, and runs
+// the emulation one frame at a time so the caller owns the main loop.
+//
+// With the serde feature the whole machine can be serialized, in any format
+// serde supports. The debugger, logs, symbols, cheats and
+// freezes aren't part of it.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Emulator {
+    cpu: CPU<'static>,
+    speed: f64,
+    // messages for the player, for the frontend to draw over the frame
+    #[cfg_attr(feature = "serde", serde(skip))]
+    osd: Osd,
+}
+
+// NTSC frames run at ~60.0988 Hz (1789773 CPU cycles/s / 29780.5 cycles)
+pub const FRAME_RATE: f64 = 60.0988;
+pub const FRAME_DURATION: Duration = Duration::from_nanos(16_639_267);
+
+// Stops the CPU at the end of every frame
+fn end_frame(_ppu: &PPU, _joypads: &mut [Joypad; 2], _audio: &mut RingBuffer) -> ControlFlow<()> {
+    ControlFlow::Break(())
+}
+
+impl Emulator {
+    pub fn new(cart: Cartridge) -> Emulator {
+        let bus = Bus::new_with_gameloop_callback(cart, end_frame);
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        Emulator {
+            cpu,
+            speed: 1.0,
+            osd: Osd::new(),
+        }
+    }
+
+    // Load an iNES image
+    pub fn from_rom_bytes(rom: &[u8]) -> Result<Emulator, String> {
+        let cart = Cartridge::new(&rom.to_vec())?;
+        Ok(Emulator::new(cart))
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Emulator, String> {
+        let cart = Cartridge::new_from_file(path)?;
+        Ok(Emulator::new(cart))
+    }
+
+    // The inserted cartridge, for its hashes and what the ROM database
+    // says about it
+    pub fn cartridge(&self) -> Ref<'_, Cartridge> {
+        self.cpu.bus.cart.borrow()
+    }
+
+    // Press the reset button. The game restarts from its reset vector with
+    // RAM as it left it, see `Bus::reset`.
+    pub fn reset(&mut self) {
+        self.cpu.bus.reset();
+        self.cpu.reset();
+    }
+
+    // Turn the console off and on, which is like inserting the cartridge in
+    // a new console. Cheats, the Zapper, the debugger and logs are kept.
+    pub fn power_cycle(&mut self) {
+        self.cpu.power_cycle();
+    }
+
+    // Run until the PPU completes a frame and return it. Input set before
+    // the call is seen by the frame's NMI handler. Returns early when the
+    // debugger breaks, check `take_break`.
+    pub fn run_frame(&mut self) -> Result<&NesFrame, EmulationError> {
+        self.osd.tick();
+        crash::catch(&mut self.cpu, |cpu| cpu.run())?;
+        Ok(self.cpu.bus.ppu.frame())
+    }
+
+    // Execute one instruction, like `CPU::step` but crashes are caught like
+    // in `run_frame`
+    pub fn step(&mut self) -> Result<StepInfo, EmulationError> {
+        crash::catch(&mut self.cpu, |cpu| cpu.step())
+    }
+
+    // Run a frame the player doesn't see, like those netplay runs again
+    // after rolling back: on-screen messages stay and its audio is dropped
+    pub fn replay_frame(&mut self) -> Result<(), EmulationError> {
+        let samples = self.cpu.bus.audio.buffer.drain();
+        crash::catch(&mut self.cpu, |cpu| cpu.run())?;
+        self.cpu.bus.audio.buffer.drain();
+        for sample in samples {
+            self.cpu.bus.audio.buffer.push(sample);
+        }
+        Ok(())
+    }
+
+    // The last frame rendered by `run_frame`
+    pub fn frame(&self) -> &NesFrame {
+        self.cpu.bus.ppu.frame()
+    }
+
+    // Frames run since power on
+    pub fn frame_number(&self) -> u64 {
+        self.cpu.bus.ppu.frame_number()
+    }
+
+    // The last frame as system palette colors, see `PPU::indexed_frame`
+    pub fn indexed_frame(&self) -> &[u16] {
+        self.cpu.bus.ppu.indexed_frame()
+    }
+
+    // player is 0 or 1
+    pub fn set_buttons(&mut self, player: usize, buttons: JoypadStatus) {
+        self.cpu.bus.joypads[player].set_status(buttons);
+    }
+
+    // Official controllers by default. Some games check what a controller
+    // returns past its 8 buttons.
+    pub fn set_joypad_kind(&mut self, player: usize, kind: JoypadKind) {
+        self.cpu.bus.joypads[player].set_kind(kind);
+    }
+
+    // Plug a Zapper into the second port, or unplug it
+    pub fn connect_zapper(&mut self, connected: bool) {
+        self.cpu.bus.zapper = if connected { Some(Zapper::new()) } else { None };
+    }
+
+    // Where the Zapper points at in NES pixels (None when off screen) and
+    // whether its trigger is pulled. Ignored when no Zapper is connected.
+    pub fn set_zapper(&mut self, aim: Option<(u32, u32)>, trigger: bool) {
+        if let Some(zapper) = &mut self.cpu.bus.zapper {
+            zapper.set_aim(aim, trigger);
+        }
+    }
+
+    // Whether the PPU has the hardware's bugs, see `ppu::Accuracy`. It's
+    // kept after `power_cycle`.
+    pub fn set_accuracy(&mut self, accuracy: Accuracy) {
+        self.cpu.bus.ppu.set_accuracy(accuracy);
+    }
+
+    // What RAM holds at power on, now and after `power_cycle`. Set it
+    // before running.
+    pub fn set_ram_init(&mut self, init: RamInit) {
+        self.cpu.bus.set_ram_init(init);
+    }
+
+    // Game Genie and Action Replay codes, see `cheats`
+    pub fn cheats(&self) -> &Cheats {
+        &self.cpu.bus.cheats
+    }
+
+    pub fn cheats_mut(&mut self) -> &mut Cheats {
+        &mut self.cpu.bus.cheats
+    }
+
+    // Lock RAM against CPU writes, see `Freezes`. With a value the address
+    // is set to it right away and at the end of every frame.
+    pub fn freeze(&mut self, addr: u16, value: Option<u8>) -> Result<(), String> {
+        let has_prg_ram = self
+            .cpu
+            .bus
+            .cart
+            .borrow_mut()
+            .mapper
+            .prg_ram_mut()
+            .is_some();
+        self.cpu.bus.freezes.add(addr, value, has_prg_ram)?;
+        self.cpu.bus.apply_freezes();
+        Ok(())
+    }
+
+    // Returns whether the address was frozen
+    pub fn unfreeze(&mut self, addr: u16) -> bool {
+        self.cpu.bus.freezes.remove(addr)
+    }
+
+    pub fn freezes(&self) -> &Freezes {
+        &self.cpu.bus.freezes
+    }
+
+    // Audio samples at the sample rate produced since the last call
+    pub fn audio_samples(&mut self) -> Vec<f32> {
+        self.cpu.bus.audio.buffer.drain()
+    }
+
+    // `audio::SAMPLE_RATE` unless set, usually to the audio device's rate.
+    // The samples not taken yet are dropped, and a running audio recording
+    // is finished since a WAV file has a single rate.
+    pub fn set_audio_sample_rate(&mut self, sample_rate: u32) {
+        assert!(sample_rate > 0, "invalid sample rate {}", sample_rate);
+        self.cpu.bus.audio = AudioSampler::new_with_sample_rate(sample_rate);
+    }
+
+    pub fn audio_sample_rate(&self) -> u32 {
+        self.cpu.bus.audio.sample_rate()
+    }
+
+    // Nudge the audio sample rate to keep the device's queue at its level,
+    // see `audio::rate_adjustment`. 1.0 is the exact rate.
+    pub fn set_audio_rate_adjustment(&mut self, adjustment: f64) {
+        self.cpu.bus.audio.set_rate_adjustment(adjustment);
+    }
+
+    // Write the audio to a WAV file from now on, as it is sampled. Nothing
+    // has to play or drain `audio_samples` for this, so it works headless.
+    // A recording that was running is finished.
+    pub fn start_audio_recording<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let recorder = WavWriter::create(path, self.cpu.bus.audio.sample_rate())?;
+        if let Some(old) = self.cpu.bus.audio.recorder.replace(recorder) {
+            old.finish()?;
+        }
+        Ok(())
+    }
+
+    // The recording so far, `finish` completes the file
+    pub fn stop_audio_recording(&mut self) -> Option<WavWriter> {
+        self.cpu.bus.audio.recorder.take()
+    }
+
+    pub fn audio_recording(&self) -> Option<&WavWriter> {
+        self.cpu.bus.audio.recorder.as_ref()
+    }
+
+    // Names of the cartridge's expansion audio channels, none for most
+    // games. Volumes are set by index in this list.
+    pub fn expansion_channels(&self) -> &'static [&'static str] {
+        match self.cartridge().expansion_audio() {
+            Some(audio) => audio.channels(),
+            None => &[],
+        }
+    }
+
+    // 1.0 is the channel's normal level and 0.0 mutes it
+    pub fn set_expansion_volume(&mut self, channel: usize, volume: f32) {
+        self.cpu.bus.mixer.set_expansion_volume(channel, volume);
+    }
+
+    pub fn expansion_volume(&self, channel: usize) -> f32 {
+        self.cpu.bus.mixer.expansion_volume(channel)
+    }
+
+    // Leave a channel out of the audio, e.g. to rip a game's music without
+    // the sound effects on the noise channel
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.cpu.bus.mixer.set_muted(channel, muted);
+    }
+
+    pub fn is_muted(&self, channel: Channel) -> bool {
+        self.cpu.bus.mixer.is_muted(channel)
+    }
+
+    // Hear only `channel`, or None for all the channels that aren't muted
+    pub fn set_solo(&mut self, channel: Option<Channel>) {
+        self.cpu.bus.mixer.set_solo(channel);
+    }
+
+    pub fn solo(&self) -> Option<Channel> {
+        self.cpu.bus.mixer.solo()
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        self.cpu.save_state()
+    }
+
+    // Save into `state`, reusing its memory
+    pub fn save_state_into(&self, state: &mut Vec<u8>) {
+        self.cpu.save_state_into(state)
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        self.cpu.load_state(data)
+    }
+
+    // Cheap fingerprint of the current state, see `desync`
+    pub fn state_hash(&self) -> StateHash {
+        self.cpu.state_hash()
+    }
+
+    // Emulation speed relative to real hardware, e.g. 4.0 to fast-forward or
+    // 0.5 for slow motion. This doesn't change the emulation itself, it is
+    // the pace frontends should run frames at.
+    pub fn set_speed(&mut self, speed: f64) {
+        assert!(speed > 0.0, "invalid speed {}", speed);
+        self.speed = speed;
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    // Wall clock time one frame should take at the current speed
+    pub fn frame_duration(&self) -> Duration {
+        FRAME_DURATION.div_f64(self.speed)
+    }
+
+    // On-screen messages, see `osd`
+    pub fn osd(&self) -> &Osd {
+        &self.osd
+    }
+
+    pub fn osd_mut(&mut self) -> &mut Osd {
+        &mut self.osd
+    }
+
+    // The debugger, attached on first use
+    pub fn debugger(&mut self) -> &mut Debugger {
+        self.cpu.debugger.get_or_insert_with(Debugger::new)
+    }
+
+    pub fn detach_debugger(&mut self) {
+        self.cpu.debugger = None;
+    }
+
+    // Record PPU events from now on, keeping the last `capacity`. Replaces
+    // any previous log.
+    pub fn attach_event_log(&mut self, capacity: usize) {
+        self.cpu.bus.event_log = Some(EventLog::new(capacity));
+    }
+
+    pub fn detach_event_log(&mut self) {
+        self.cpu.bus.event_log = None;
+    }
+
+    pub fn event_log(&self) -> Option<&EventLog> {
+        self.cpu.bus.event_log.as_ref()
+    }
+
+    // Record APU register writes from now on, keeping the last
+    // `capacity`. Replaces any previous log.
+    pub fn attach_apu_log(&mut self, capacity: usize) {
+        self.cpu.bus.apu_log = Some(ApuLog::new(capacity));
+    }
+
+    pub fn detach_apu_log(&mut self) {
+        self.cpu.bus.apu_log = None;
+    }
+
+    pub fn apu_log(&self) -> Option<&ApuLog> {
+        self.cpu.bus.apu_log.as_ref()
+    }
+
+    // Keep the last `capacity` instructions from now on, see `history`.
+    // Replaces any previous history.
+    pub fn attach_history(&mut self, capacity: usize) {
+        self.cpu.history = Some(InstructionHistory::new(capacity));
+    }
+
+    pub fn detach_history(&mut self) {
+        self.cpu.history = None;
+    }
+
+    pub fn history(&self) -> Option<&InstructionHistory> {
+        self.cpu.history.as_ref()
+    }
+
+    // Log which ROM bytes are code and data from now on, see `cdl`. `saved`
+    // is a .cdl file of this ROM to continue. The log is read with
+    // `cartridge().code_data_log()`.
+    pub fn start_code_data_log(&mut self, saved: Option<&[u8]>) -> Result<(), String> {
+        self.cpu.bus.cart.borrow_mut().start_code_data_log(saved)
+    }
+
+    pub fn stop_code_data_log(&mut self) -> Option<CodeDataLog> {
+        self.cpu.bus.cart.borrow_mut().stop_code_data_log()
+    }
+
+    // Log every instruction from now on, replacing any previous logger
+    pub fn start_trace(&mut self, logger: TraceLogger) {
+        self.cpu.trace_logger = Some(logger);
+    }
+
+    // Detach the logger, dropping it flushes the output
+    pub fn stop_trace(&mut self) -> Option<TraceLogger> {
+        self.cpu.trace_logger.take()
+    }
+
+    pub fn trace_logger(&mut self) -> Option<&mut TraceLogger> {
+        self.cpu.trace_logger.as_mut()
+    }
+
+    // Count the cycles spent in each subroutine from now on, see `profiler`
+    pub fn start_profiling(&mut self) {
+        self.cpu.profiler = Some(Profiler::new(self.cpu.total_cycles()));
+    }
+
+    pub fn stop_profiling(&mut self) -> Option<Profiler> {
+        let cycle = self.cpu.total_cycles();
+        let mut profiler = self.cpu.profiler.take()?;
+        profiler.sync(cycle);
+        Some(profiler)
+    }
+
+    // The profile up to now
+    pub fn profiler(&mut self) -> Option<&Profiler> {
+        let cycle = self.cpu.total_cycles();
+        let profiler = self.cpu.profiler.as_mut()?;
+        profiler.sync(cycle);
+        Some(profiler)
+    }
+
+    // The profile's `count` busiest routines, named with the loaded symbols
+    pub fn profile_report(&mut self, count: usize) -> Option<String> {
+        let cycle = self.cpu.total_cycles();
+        let profiler = self.cpu.profiler.as_mut()?;
+        profiler.sync(cycle);
+        Some(profiler.report(count, self.cpu.symbols.as_ref()))
+    }
+
+    // Name addresses in traces and disassembly with a .nl or .mlb file.
+    // The names add to those of files loaded before.
+    pub fn load_symbols<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+        let prg_banks = self.cpu.bus.cart.borrow().num_prg_banks;
+        let symbols = Symbols::load(path, prg_banks)?;
+        self.cpu
+            .symbols
+            .get_or_insert_with(Symbols::new)
+            .extend(symbols);
+        Ok(())
+    }
+
+    pub fn clear_symbols(&mut self) {
+        self.cpu.symbols = None;
+    }
+
+    // Why the last `run_frame` stopped early, see `CPU::take_break`
+    pub fn take_break(&mut self) -> Option<BreakReason> {
+        self.cpu.take_break()
+    }
+
+    // Direct access to the hardware, for debugging tools
+    pub fn cpu(&self) -> &CPU<'static> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU<'static> {
+        &mut self.cpu
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Emulator {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        struct State {
+            cpu: CPU<'static>,
+            speed: f64,
+        }
+
+        let State { mut cpu, speed } = State::deserialize(deserializer)?;
+        cpu.bus.set_gameloop_callback(end_frame);
+        Ok(Emulator {
+            cpu,
+            speed,
+            osd: Osd::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::event_log::EventKind;
+
+    #[test]
+    fn test_run_frame() {
+        // JMP $8000
+        let cart = Cartridge::new_from_program(vec![0x4C, 0x00, 0x80]);
+        let mut emu = Emulator::new(cart);
+
+        emu.run_frame().unwrap();
+        let first = emu.cpu().total_cycles();
+        emu.run_frame().unwrap();
+        let cycles_per_frame = emu.cpu().total_cycles() - first;
+        assert_eq!(emu.frame_number(), 2);
+        // 262 scanlines * 341 dots / 3, give or take an instruction since
+        // frames end on instruction boundaries
+        assert!(
+            (29780 - 7..=29781 + 7).contains(&cycles_per_frame),
+            "{}",
+            cycles_per_frame
+        );
+
+        assert!(!emu.audio_samples().is_empty());
+        assert!(emu.audio_samples().is_empty());
+    }
+
+    #[test]
+    fn test_cheats() {
+        // LDA #$01 : STA $00 : JMP $8004
+        let mut program = vec![0xA9, 0x01, 0x85, 0x00, 0x4C, 0x04, 0x80];
+        program.resize(0x3FFC, 0);
+        // reset vector
+        program.extend_from_slice(&[0x00, 0x80]);
+        let mut emu = Emulator::new(Cartridge::new_from_program(program));
+        // LDA #$05 instead
+        let idx = emu.cheats_mut().add("8001?01:05").unwrap();
+        emu.reset();
+        emu.run_frame().unwrap();
+        assert_eq!(emu.cpu().bus.cpu_ram[0], 0x05);
+
+        emu.cheats_mut().remove(idx);
+        emu.reset();
+        emu.run_frame().unwrap();
+        assert_eq!(emu.cpu().bus.cpu_ram[0], 0x01);
+    }
+
+    #[test]
+    fn test_freeze() {
+        // INC $00 : INC $01 : JMP $8000
+        let mut program = vec![0xE6, 0x00, 0xE6, 0x01, 0x4C, 0x00, 0x80];
+        program.resize(0x3FFC, 0);
+        program.extend_from_slice(&[0x00, 0x80]);
+        let mut emu = Emulator::new(Cartridge::new_from_program(program));
+        emu.freeze(0x0800, None).unwrap();
+        emu.freeze(0x0001, Some(0x42)).unwrap();
+        assert_eq!(emu.cpu().bus.cpu_ram[1], 0x42);
+        emu.run_frame().unwrap();
+        assert_eq!(emu.cpu().bus.cpu_ram[0], 0);
+        assert_eq!(emu.cpu().bus.cpu_ram[1], 0x42);
+
+        // set again when it changes without a CPU write, like on state loads
+        emu.cpu_mut().bus.cpu_ram[1] = 0;
+        emu.run_frame().unwrap();
+        assert_eq!(emu.cpu().bus.cpu_ram[1], 0x42);
+
+        assert!(emu.unfreeze(0x0000));
+        emu.run_frame().unwrap();
+        assert_ne!(emu.cpu().bus.cpu_ram[0], 0);
+        assert_eq!(emu.freezes().iter().count(), 1);
+
+        // no PRG RAM
+        assert!(emu.freeze(0x6000, Some(0x42)).is_err());
+    }
+
+    #[test]
+    fn test_freeze_prg_ram() {
+        // STA $6000 : JMP $E003
+        let program = [0x8D, 0x00, 0x60, 0x4C, 0x03, 0xE0];
+        let rom = |flags_6: u8, flags_7: u8| {
+            let mut rom = vec![b'N', b'E', b'S', 0x1A, 0x01, 0x01, flags_6, flags_7];
+            rom.resize(16, 0);
+            let mut prg = vec![0; 0x4000];
+            prg[0x2000..0x2000 + program.len()].copy_from_slice(&program);
+            prg[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xE0]);
+            rom.extend_from_slice(&prg);
+            rom.resize(16 + 0x4000 + 0x2000, 0);
+            rom
+        };
+
+        // VRC6a
+        let mut emu = Emulator::from_rom_bytes(&rom(0x80, 0x10)).unwrap();
+        emu.freeze(0x6000, Some(0x42)).unwrap();
+        emu.run_frame().unwrap();
+        let prg_ram = |emu: &Emulator| {
+            let mut cart = emu.cpu().bus.cart.borrow_mut();
+            cart.mapper.prg_ram_mut().unwrap()[0]
+        };
+        assert_eq!(prg_ram(&emu), 0x42);
+
+        // mapper 87 has its CHR bank register at $6000
+        let mut emu = Emulator::from_rom_bytes(&rom(0x70, 0x50)).unwrap();
+        assert!(emu.freeze(0x6000, Some(0x42)).is_err());
+    }
+
+    #[test]
+    fn test_reset_and_power_cycle() {
+        // INC $00 : LDA #$01 : STA $4015 : LDA #$08 : STA $4003 : JMP $800C
+        let mut program = vec![
+            0xE6, 0x00, 0xA9, 0x01, 0x8D, 0x15, 0x40, 0xA9, 0x08, 0x8D, 0x03, 0x40, 0x4C, 0x0C,
+            0x80,
+        ];
+        program.resize(0x3FFC, 0);
+        program.extend_from_slice(&[0x00, 0x80]);
+        let mut emu = Emulator::new(Cartridge::new_from_program(program));
+        emu.run_frame().unwrap();
+        assert_eq!(emu.cpu().bus.cpu_ram[0], 1);
+        assert_eq!(emu.cpu().registers().sp, 0xFD);
+
+        // RAM is kept and the stack pointer moves down, the APU is silenced
+        emu.reset();
+        assert_eq!(emu.cpu().registers().sp, 0xFA);
+        assert_eq!(emu.cpu_mut().bus.apu.cpu_read(0x4015), 0);
+        emu.run_frame().unwrap();
+        assert_eq!(emu.cpu().bus.cpu_ram[0], 2);
+        assert_eq!(emu.cpu_mut().bus.apu.cpu_read(0x4015), 1);
+
+        emu.power_cycle();
+        assert_eq!(emu.cpu().registers().sp, 0xFD);
+        assert_eq!(emu.frame_number(), 0);
+        assert_eq!(emu.cpu().bus.cpu_ram[0], 0);
+        assert_eq!(emu.cpu_mut().bus.apu.cpu_read(0x4015), 0);
+        emu.run_frame().unwrap();
+        assert_eq!(emu.cpu().bus.cpu_ram[0], 1);
+    }
+
+    #[test]
+    fn test_event_log() {
+        // LDA #$80 : STA $2000 : JMP $8005, NMI: STA $2005 : RTI
+        let mut program = vec![0xA9, 0x80, 0x8D, 0x00, 0x20, 0x4C, 0x05, 0x80];
+        program.extend_from_slice(&[0x8D, 0x05, 0x20, 0x40]);
+        program.resize(0x3FFA, 0);
+        program.extend_from_slice(&[0x08, 0x80, 0x00, 0x80, 0x00, 0x80]);
+        let mut emu = Emulator::new(Cartridge::new_from_program(program));
+        emu.reset();
+        emu.attach_event_log(100);
+        emu.run_frame().unwrap();
+
+        let log = emu.event_log().unwrap();
+        let events: Vec<EventKind> = log.events().map(|event| event.kind).collect();
+        assert_eq!(
+            events,
+            [
+                EventKind::PpuWrite {
+                    addr: 0x2000,
+                    value: 0x80
+                },
+                EventKind::Nmi,
+                EventKind::PpuWrite {
+                    addr: 0x2005,
+                    value: 0x80
+                },
+            ]
+        );
+        let nmi = log.events().nth(1).unwrap();
+        assert_eq!((nmi.frame, nmi.scanline), (0, 241));
+        // the frame ends after the NMI handler, with the pre-render line
+        assert_eq!(log.frame(), 1);
+        emu.run_frame().unwrap();
+        assert_eq!(emu.event_log().unwrap().frame_events(1).count(), 2);
+    }
+
+    #[test]
+    fn test_expansion_audio() {
+        let program = [
+            0xA9, 0x8F, 0x8D, 0x00, 0x90, // E000 VRC6 pulse 1 constant, volume 15
+            0xA9, 0x80, 0x8D, 0x02, 0x90, // E005 enabled
+            0x4C, 0x0A, 0xE0, // E00A JMP $E00A
+        ];
+        // VRC6a, the last 8KB PRG bank is at $E000
+        let mut rom = b"NES\x1a\x01\x01\x80\x10".to_vec();
+        rom.resize(16, 0);
+        let mut prg = vec![0; 0x4000];
+        prg[0x2000..0x2000 + program.len()].copy_from_slice(&program);
+        prg[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xE0]);
+        rom.extend_from_slice(&prg);
+        rom.resize(16 + 0x4000 + 0x2000, 0);
+        let mut emulator = Emulator::from_rom_bytes(&rom).unwrap();
+        assert_eq!(emulator.expansion_channels().len(), 3);
+        assert_eq!(emulator.expansion_channels()[2], "VRC6 sawtooth");
+        emulator.run_frame().unwrap();
+
+        // the expansion part of the output
+        let output = |emulator: &Emulator| {
+            let bus = &emulator.cpu().bus;
+            bus.mixer.mix(&bus.apu, bus.cart.borrow().expansion_audio())
+                - bus.mixer.mix(&bus.apu, None)
+        };
+        assert!((output(&emulator) - 0.15).abs() < 1e-6);
+        emulator.set_expansion_volume(0, 0.5);
+        assert_eq!(emulator.expansion_volume(0), 0.5);
+        assert!((output(&emulator) - 0.075).abs() < 1e-6);
+        emulator.set_expansion_volume(0, 0.0);
+        assert!(output(&emulator).abs() < 1e-6);
+        emulator.set_expansion_volume(0, 1.0);
+        emulator.set_solo(Some(Channel::Noise));
+        assert!(output(&emulator).abs() < 1e-6);
+        emulator.set_solo(None);
+        emulator.set_muted(Channel::Expansion, true);
+        assert!(emulator.is_muted(Channel::Expansion));
+        assert!(output(&emulator).abs() < 1e-6);
+
+        let emulator = Emulator::new(Cartridge::new_from_program(vec![0xEA; 0x4000]));
+        assert!(emulator.expansion_channels().is_empty());
+    }
+
+    #[test]
+    fn test_symbols() {
+        // JSR $8006 : STA $0200, $8006: RTS
+        let program = vec![0x20, 0x06, 0x80, 0x8D, 0x00, 0x02, 0x60];
+        let mut emu = Emulator::new(Cartridge::new_from_program(program));
+        let path = std::env::temp_dir().join(format!("nes-symbols-{}.nl", std::process::id()));
+        std::fs::write(&path, "$8006#initSnake#\n$0200/2#snakeLength#\n").unwrap();
+        emu.load_symbols(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines = emu.cpu_mut().disassemble_at(0x8000, 2);
+        assert!(lines[0].ends_with("JSR initSnake"), "{}", lines[0]);
+        assert!(lines[1].ends_with("STA snakeLength = 00"), "{}", lines[1]);
+
+        assert!(emu.load_symbols("missing.nl").is_err());
+        emu.clear_symbols();
+        let lines = emu.cpu_mut().disassemble_at(0x8000, 1);
+        assert!(lines[0].ends_with("JSR $8006"), "{}", lines[0]);
+    }
+
+    #[test]
+    fn test_audio_sample_rate() {
+        let cart = Cartridge::new_from_program(vec![0x4C, 0x00, 0x80]);
+        let mut emu = Emulator::new(cart);
+        assert_eq!(emu.audio_sample_rate(), 44_100);
+        emu.set_audio_sample_rate(48_000);
+        assert_eq!(emu.audio_sample_rate(), 48_000);
+        emu.run_frame().unwrap();
+        // 48000 / 60.0988
+        let samples = emu.audio_samples().len();
+        assert!((797..=800).contains(&samples), "{}", samples);
+    }
+
+    #[test]
+    fn test_speed() {
+        let mut emu = Emulator::new(Cartridge::new_from_program(vec![0x4C, 0x00, 0x80]));
+        assert_eq!(emu.frame_duration(), FRAME_DURATION);
+        assert!((1.0 / FRAME_DURATION.as_secs_f64() - FRAME_RATE).abs() < 0.0001);
+
+        emu.set_speed(4.0);
+        assert_eq!(emu.speed(), 4.0);
+        // durations are scaled as floats, allow for rounding
+        assert!(emu.frame_duration().abs_diff(FRAME_DURATION / 4) < Duration::from_micros(1));
+        emu.set_speed(0.5);
+        assert!(emu.frame_duration().abs_diff(FRAME_DURATION * 2) < Duration::from_micros(1));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        // INC $00 : JMP $8000
+        let mut program = vec![0xE6, 0x00, 0x4C, 0x00, 0x80];
+        program.resize(0x3FFC, 0);
+        program.extend_from_slice(&[0x00, 0x80]);
+        let mut emu = Emulator::new(Cartridge::new_from_program(program));
+        emu.run_frame().unwrap();
+
+        let json = serde_json::to_string(&emu).unwrap();
+        let mut loaded: Emulator = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.state_hash(), emu.state_hash());
+        // the PPU reads the new cartridge and the frame ends as before
+        emu.run_frame().unwrap();
+        loaded.run_frame().unwrap();
+        assert_eq!(loaded.state_hash(), emu.state_hash());
+        assert_eq!(loaded.frame_number(), 2);
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::graphics::{NesFrame, NES_HEIGHT, NES_WIDTH};
+
+// PPU event log, for debugging mid-frame effects like Mesen's event viewer.
+// With a log attached to the bus (see `Bus::event_log`) every PPU register
+// access, OAM DMA, mapper register write, NMI, IRQ and sprite 0 hit is
+// recorded with the frame, scanline and dot it happened at. The log keeps
+// the most recent events up to its capacity.
+
+// Enough for a few frames of a game that does a lot of mid-frame updates
+pub const DEFAULT_CAPACITY: usize = 16 * 1024;
+
+// PPU timing, a frame is 262 scanlines of 341 dots
+const SCANLINES: u32 = 262;
+const DOTS: u32 = 341;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    // $2000-$2007 and their mirrors, shown as $2000-$2007
+    PpuRead { addr: u16, value: u8 },
+    PpuWrite { addr: u16, value: u8 },
+    // $4014
+    OamDma { page: u8 },
+    // a write the cartridge handled, e.g. a bank switch or IRQ setup
+    MapperWrite { addr: u16, value: u8 },
+    // the CPU started servicing the interrupt
+    Nmi,
+    Irq,
+    SpriteZeroHit,
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventKind::PpuRead { addr, value } => write!(f, "read {:04X} = {:02X}", addr, value),
+            EventKind::PpuWrite { addr, value } => write!(f, "write {:04X} = {:02X}", addr, value),
+            EventKind::OamDma { page } => write!(f, "OAM DMA from {:02X}00", page),
+            EventKind::MapperWrite { addr, value } => {
+                write!(f, "mapper write {:04X} = {:02X}", addr, value)
+            }
+            EventKind::Nmi => write!(f, "NMI"),
+            EventKind::Irq => write!(f, "IRQ"),
+            EventKind::SpriteZeroHit => write!(f, "sprite 0 hit"),
+        }
+    }
+}
+
+impl EventKind {
+    // Marker color in `EventLog::render`
+    pub fn color(&self) -> (u8, u8, u8) {
+        match self {
+            EventKind::PpuRead { .. } => (0x40, 0xA0, 0xFF),
+            EventKind::PpuWrite { .. } => (0xFF, 0x40, 0x40),
+            EventKind::OamDma { .. } => (0xFF, 0xFF, 0x40),
+            EventKind::MapperWrite { .. } => (0xFF, 0x80, 0xFF),
+            EventKind::Nmi => (0x40, 0xFF, 0x40),
+            EventKind::Irq => (0xFF, 0xA0, 0x00),
+            EventKind::SpriteZeroHit => (0xFF, 0xFF, 0xFF),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PpuEvent {
+    // frames since the log was attached
+    pub frame: u64,
+    pub scanline: u32,
+    pub dot: u32,
+    pub kind: EventKind,
+}
+
+impl fmt::Display for PpuEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:6} {:3},{:3}  {}",
+            self.frame, self.scanline, self.dot, self.kind
+        )
+    }
+}
+
+pub struct EventLog {
+    events: VecDeque<PpuEvent>,
+    capacity: usize,
+    frame: u64,
+}
+
+impl EventLog {
+    // Keeps the last `capacity` events
+    pub fn new(capacity: usize) -> Self {
+        EventLog {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+            frame: 0,
+        }
+    }
+
+    // The frame being recorded
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn events(&self) -> impl Iterator<Item = &PpuEvent> + '_ {
+        self.events.iter()
+    }
+
+    // Events of one frame, possibly incomplete if older events of the frame
+    // were dropped
+    pub fn frame_events(&self, frame: u64) -> impl Iterator<Item = &PpuEvent> + '_ {
+        self.events.iter().filter(move |event| event.frame == frame)
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    // Events of `frame` one per line: frame, scanline, dot and what
+    // happened
+    pub fn dump<W: Write>(&self, frame: u64, out: &mut W) -> io::Result<()> {
+        for event in self.frame_events(frame) {
+            writeln!(out, "{}", event)?;
+        }
+        Ok(())
+    }
+
+    // Timing diagram of `frame`: the whole frame including blanking, scaled
+    // down to the NES resolution, with a marker per event. The visible
+    // part is dark grey and blanking is black.
+    pub fn render(&self, frame: u64, out: &mut NesFrame) {
+        for y in 0..NES_HEIGHT {
+            let scanline = y * SCANLINES / NES_HEIGHT;
+            for x in 0..NES_WIDTH {
+                let dot = x * DOTS / NES_WIDTH;
+                if scanline < NES_HEIGHT && (1..=NES_WIDTH).contains(&dot) {
+                    out.set_pixel(x, y, 0x30, 0x30, 0x30);
+                } else {
+                    out.set_pixel(x, y, 0, 0, 0);
+                }
+            }
+        }
+        for event in self.frame_events(frame) {
+            let x = event.dot * NES_WIDTH / DOTS;
+            let y = event.scanline * NES_HEIGHT / SCANLINES;
+            let (r, g, b) = event.kind.color();
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                out.set_pixel(x + dx, y + dy, r, g, b);
+            }
+        }
+    }
+
+    pub(crate) fn record(&mut self, scanline: u32, dot: u32, kind: EventKind) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(PpuEvent {
+            frame: self.frame,
+            scanline,
+            dot,
+            kind,
+        });
+    }
+
+    pub(crate) fn next_frame(&mut self) {
+        self.frame += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ring_buffer() {
+        let mut log = EventLog::new(3);
+        log.record(241, 1, EventKind::Nmi);
+        log.next_frame();
+        log.record(30, 100, EventKind::SpriteZeroHit);
+        log.record(
+            30,
+            120,
+            EventKind::PpuWrite {
+                addr: 0x2005,
+                value: 0x80,
+            },
+        );
+        log.record(240, 0, EventKind::OamDma { page: 0x02 });
+        // the oldest event was dropped
+        assert_eq!(log.events().count(), 3);
+        assert_eq!(log.frame_events(0).count(), 0);
+        assert_eq!(log.frame_events(1).count(), 3);
+
+        let mut out = vec![];
+        log.dump(1, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "     1  30,100  sprite 0 hit\n\
+             \x20    1  30,120  write 2005 = 80\n\
+             \x20    1 240,  0  OAM DMA from 0200\n"
+        );
+
+        let mut frame = NesFrame::new();
+        log.render(1, &mut frame);
+        assert_eq!(frame.pixel(75, 27), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(frame.pixel(10, 10), [0x30, 0x30, 0x30]);
+        assert_eq!(frame.pixel(10, 230), [0, 0, 0]);
+    }
+}
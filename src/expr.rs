@@ -0,0 +1,405 @@
+use std::fmt;
+
+use crate::cpu::Registers;
+
+// Expressions over the CPU state, for conditional breakpoints and watch
+// expressions in the debugger: `A == $3F and X > 2`, `[$0200] & $80`.
+//
+//   numbers     decimal, or hex with a $ or 0x prefix
+//   registers   A X Y P SP PC
+//   flags       N V D I Z C, 1 when set
+//   memory      [addr], the byte at addr, read without side effects
+//   operators   from the loosest: or ||, and &&, == != < <= > >=, |, ^, &,
+//               + -, and the unary not ! - ~
+//
+// Names are case insensitive. Comparisons give 1 or 0, and a condition is
+// true when it's not 0.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operand {
+    A,
+    X,
+    Y,
+    P,
+    SP,
+    PC,
+    // mask of the flag in P
+    Flag(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Add,
+    Sub,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UnOp {
+    Not,
+    Neg,
+    Complement,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Num(i64),
+    Operand(Operand),
+    Mem(Box<Node>),
+    Unary(UnOp, Box<Node>),
+    Binary(BinOp, Box<Node>, Box<Node>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    // shown back to the user
+    text: String,
+    root: Node,
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            pos: 0,
+        };
+        let root = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!("unexpected {}", token));
+        }
+        Ok(Expr {
+            text: text.split_whitespace().collect::<Vec<_>>().join(" "),
+            root,
+        })
+    }
+
+    // The value with the registers `regs`, reading memory with `peek`
+    pub fn eval(&self, regs: &Registers, peek: &mut dyn FnMut(u16) -> u8) -> i64 {
+        eval(&self.root, regs, peek)
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+fn eval(node: &Node, regs: &Registers, peek: &mut dyn FnMut(u16) -> u8) -> i64 {
+    match node {
+        Node::Num(value) => *value,
+        Node::Operand(operand) => match operand {
+            Operand::A => regs.a as i64,
+            Operand::X => regs.x as i64,
+            Operand::Y => regs.y as i64,
+            Operand::P => regs.p as i64,
+            Operand::SP => regs.sp as i64,
+            Operand::PC => regs.pc as i64,
+            Operand::Flag(mask) => (regs.p & mask != 0) as i64,
+        },
+        Node::Mem(addr) => {
+            let addr = eval(addr, regs, peek) as u16;
+            peek(addr) as i64
+        }
+        Node::Unary(op, node) => {
+            let value = eval(node, regs, peek);
+            match op {
+                UnOp::Not => (value == 0) as i64,
+                UnOp::Neg => value.wrapping_neg(),
+                UnOp::Complement => !value,
+            }
+        }
+        Node::Binary(op, left, right) => {
+            let left = eval(left, regs, peek);
+            // the right side of and/or isn't evaluated when it doesn't matter
+            match op {
+                BinOp::Or if left != 0 => return 1,
+                BinOp::And if left == 0 => return 0,
+                _ => {}
+            }
+            let right = eval(right, regs, peek);
+            match op {
+                BinOp::Or | BinOp::And => (right != 0) as i64,
+                BinOp::Eq => (left == right) as i64,
+                BinOp::Ne => (left != right) as i64,
+                BinOp::Lt => (left < right) as i64,
+                BinOp::Le => (left <= right) as i64,
+                BinOp::Gt => (left > right) as i64,
+                BinOp::Ge => (left >= right) as i64,
+                BinOp::BitOr => left | right,
+                BinOp::BitXor => left ^ right,
+                BinOp::BitAnd => left & right,
+                BinOp::Add => left.wrapping_add(right),
+                BinOp::Sub => left.wrapping_sub(right),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(i64),
+    Name(String),
+    Sym(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Num(value) => write!(f, "{}", value),
+            Token::Name(name) => write!(f, "{}", name),
+            Token::Sym(sym) => write!(f, "{}", sym),
+        }
+    }
+}
+
+// longest first, so `<=` isn't read as `<`
+const SYMBOLS: [&str; 19] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "|", "^", "&", "+", "-", "!", "~", "(", ")", "[",
+    "]",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let hex = rest
+            .strip_prefix('$')
+            .or_else(|| rest.strip_prefix("0x"))
+            .or_else(|| rest.strip_prefix("0X"));
+        let (token, len) = if let Some(digits) = hex {
+            let end = digits
+                .find(|c: char| !c.is_ascii_hexdigit())
+                .unwrap_or(digits.len());
+            let value = i64::from_str_radix(&digits[..end], 16).map_err(|_| {
+                format!(
+                    "invalid number {}",
+                    &rest[..rest.len() - digits.len() + end]
+                )
+            })?;
+            (Token::Num(value), rest.len() - digits.len() + end)
+        } else if rest.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            let token = if word.starts_with(|c: char| c.is_ascii_digit()) {
+                Token::Num(
+                    word.parse()
+                        .map_err(|_| format!("invalid number {}", word))?,
+                )
+            } else {
+                Token::Name(word.to_ascii_uppercase())
+            };
+            (token, end)
+        } else {
+            match SYMBOLS.iter().find(|sym| rest.starts_with(*sym)) {
+                Some(sym) => (Token::Sym(sym), sym.len()),
+                None => return Err(format!("unexpected {}", rest.chars().next().unwrap())),
+            }
+        };
+        tokens.push(token);
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    // Take the next token if it's one of `ops`, which are symbols or
+    // keywords
+    fn take_op(&mut self, ops: &[&str]) -> Option<&'static str> {
+        let op = match self.peek()? {
+            Token::Sym(sym) => ops.iter().find(|op| *op == sym).map(|_| *sym),
+            Token::Name(name) => match name.as_str() {
+                "OR" if ops.contains(&"||") => Some("||"),
+                "AND" if ops.contains(&"&&") => Some("&&"),
+                "NOT" if ops.contains(&"!") => Some("!"),
+                _ => None,
+            },
+            Token::Num(_) => None,
+        }?;
+        self.pos += 1;
+        Some(op)
+    }
+
+    // One level of left associative binary operators
+    fn binary(
+        &mut self,
+        ops: &[(&str, BinOp)],
+        next: fn(&mut Parser) -> Result<Node, String>,
+    ) -> Result<Node, String> {
+        let symbols: Vec<&str> = ops.iter().map(|(sym, _)| *sym).collect();
+        let mut node = next(self)?;
+        while let Some(sym) = self.take_op(&symbols) {
+            let op = ops.iter().find(|(s, _)| *s == sym).unwrap().1;
+            node = Node::Binary(op, Box::new(node), Box::new(next(self)?));
+        }
+        Ok(node)
+    }
+
+    fn or(&mut self) -> Result<Node, String> {
+        self.binary(&[("||", BinOp::Or)], Parser::and)
+    }
+
+    fn and(&mut self) -> Result<Node, String> {
+        self.binary(&[("&&", BinOp::And)], Parser::comparison)
+    }
+
+    fn comparison(&mut self) -> Result<Node, String> {
+        self.binary(
+            &[
+                ("==", BinOp::Eq),
+                ("!=", BinOp::Ne),
+                ("<", BinOp::Lt),
+                ("<=", BinOp::Le),
+                (">", BinOp::Gt),
+                (">=", BinOp::Ge),
+            ],
+            Parser::bit_or,
+        )
+    }
+
+    fn bit_or(&mut self) -> Result<Node, String> {
+        self.binary(&[("|", BinOp::BitOr)], Parser::bit_xor)
+    }
+
+    fn bit_xor(&mut self) -> Result<Node, String> {
+        self.binary(&[("^", BinOp::BitXor)], Parser::bit_and)
+    }
+
+    fn bit_and(&mut self) -> Result<Node, String> {
+        self.binary(&[("&", BinOp::BitAnd)], Parser::sum)
+    }
+
+    fn sum(&mut self) -> Result<Node, String> {
+        self.binary(&[("+", BinOp::Add), ("-", BinOp::Sub)], Parser::unary)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        let op = match self.take_op(&["!", "-", "~"]) {
+            Some("!") => UnOp::Not,
+            Some("-") => UnOp::Neg,
+            Some(_) => UnOp::Complement,
+            None => return self.primary(),
+        };
+        Ok(Node::Unary(op, Box::new(self.unary()?)))
+    }
+
+    fn primary(&mut self) -> Result<Node, String> {
+        let token = self.peek().cloned().ok_or("unexpected end")?;
+        self.pos += 1;
+        match token {
+            Token::Num(value) => Ok(Node::Num(value)),
+            Token::Name(name) => {
+                let operand = match name.as_str() {
+                    "A" => Operand::A,
+                    "X" => Operand::X,
+                    "Y" => Operand::Y,
+                    "P" => Operand::P,
+                    "SP" => Operand::SP,
+                    "PC" => Operand::PC,
+                    "N" => Operand::Flag(0x80),
+                    "V" => Operand::Flag(0x40),
+                    "D" => Operand::Flag(0x08),
+                    "I" => Operand::Flag(0x04),
+                    "Z" => Operand::Flag(0x02),
+                    "C" => Operand::Flag(0x01),
+                    _ => return Err(format!("unknown name {}", name)),
+                };
+                Ok(Node::Operand(operand))
+            }
+            Token::Sym("(") => {
+                let node = self.or()?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            Token::Sym("[") => {
+                let node = self.or()?;
+                self.expect("]")?;
+                Ok(Node::Mem(Box::new(node)))
+            }
+            token => Err(format!("unexpected {}", token)),
+        }
+    }
+
+    fn expect(&mut self, sym: &'static str) -> Result<(), String> {
+        match self.peek() {
+            Some(Token::Sym(s)) if *s == sym => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(token) => Err(format!("expected {} instead of {}", sym, token)),
+            None => Err(format!("missing {}", sym)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn eval(text: &str) -> Result<i64, String> {
+        let regs = Registers {
+            pc: 0x8123,
+            sp: 0xFD,
+            a: 0x3F,
+            x: 3,
+            y: 0,
+            p: 0x25,
+        };
+        let mut memory = [0u8; 0x800];
+        memory[0x200] = 0x81;
+        memory[0x10] = 0x02;
+        let mut peek = |addr: u16| memory[addr as usize & 0x7FF];
+        Ok(Expr::parse(text)?.eval(&regs, &mut peek))
+    }
+
+    #[test]
+    fn test_eval() {
+        assert_eq!(eval("A == $3F and X > 2"), Ok(1));
+        assert_eq!(eval("a == 0x3f && x > 3"), Ok(0));
+        assert_eq!(eval("PC - $8000"), Ok(0x123));
+        assert_eq!(eval("[$0200] & $80"), Ok(0x80));
+        assert_eq!(eval("[$1FE + [$10]]"), Ok(0x81));
+        assert_eq!(eval("C and not Z and I"), Ok(1));
+        assert_eq!(eval("!(Y | SP - $FD)"), Ok(1));
+        assert_eq!(eval("-1 < 0 == 1"), Ok(1));
+        // the right side isn't needed
+        assert_eq!(eval("X == 3 or [A] == 1"), Ok(1));
+        assert_eq!(eval("~0 ^ P"), Ok(!0x25));
+        // precedence: & binds tighter than ==
+        assert_eq!(eval("P & 1 == 1"), Ok(1));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(eval("A =="), Err("unexpected end".to_string()));
+        assert_eq!(eval("(A"), Err("missing )".to_string()));
+        assert_eq!(eval("[A)"), Err("expected ] instead of )".to_string()));
+        assert_eq!(eval("B == 1"), Err("unknown name B".to_string()));
+        assert_eq!(eval("A 1"), Err("unexpected 1".to_string()));
+        assert_eq!(eval("$G"), Err("invalid number $".to_string()));
+        assert_eq!(eval("12ab"), Err("invalid number 12ab".to_string()));
+        assert_eq!(eval("A = 1"), Err("unexpected =".to_string()));
+        assert_eq!(Expr::parse("  A ==   1 ").unwrap().to_string(), "A == 1");
+    }
+}
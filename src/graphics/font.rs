@@ -0,0 +1,172 @@
+use super::{NesFrame, NES_HEIGHT, NES_WIDTH};
+
+// A built-in 8x8 font for text drawn over the picture, like the FPS
+// overlay. Glyphs are 5x7 with room between them. Only printable ASCII up to
+// '_' is there, lowercase letters are drawn in uppercase and anything else
+// as '?'.
+
+pub const GLYPH_SIZE: u32 = 8;
+
+// Rows from the top, bit 7 is the leftmost pixel and always clear. From ' ' (0x20) to '_'
+// (0x5F).
+#[rustfmt::skip]
+const GLYPHS: [[u8; 8]; 64] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x10, 0x10, 0x10, 0x10, 0x10, 0x00, 0x10, 0x00], // '!'
+    [0x28, 0x28, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '"'
+    [0x28, 0x28, 0x7C, 0x28, 0x7C, 0x28, 0x28, 0x00], // '#'
+    [0x10, 0x3C, 0x50, 0x38, 0x14, 0x78, 0x10, 0x00], // '$'
+    [0x60, 0x64, 0x08, 0x10, 0x20, 0x4C, 0x0C, 0x00], // '%'
+    [0x30, 0x48, 0x50, 0x20, 0x54, 0x48, 0x34, 0x00], // '&'
+    [0x10, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // "'"
+    [0x08, 0x10, 0x20, 0x20, 0x20, 0x10, 0x08, 0x00], // '('
+    [0x20, 0x10, 0x08, 0x08, 0x08, 0x10, 0x20, 0x00], // ')'
+    [0x00, 0x10, 0x54, 0x38, 0x54, 0x10, 0x00, 0x00], // '*'
+    [0x00, 0x10, 0x10, 0x7C, 0x10, 0x10, 0x00, 0x00], // '+'
+    [0x00, 0x00, 0x00, 0x00, 0x10, 0x10, 0x20, 0x00], // ','
+    [0x00, 0x00, 0x00, 0x7C, 0x00, 0x00, 0x00, 0x00], // '-'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x30, 0x30, 0x00], // '.'
+    [0x00, 0x04, 0x08, 0x10, 0x20, 0x40, 0x00, 0x00], // '/'
+    [0x38, 0x44, 0x4C, 0x54, 0x64, 0x44, 0x38, 0x00], // '0'
+    [0x10, 0x30, 0x10, 0x10, 0x10, 0x10, 0x38, 0x00], // '1'
+    [0x38, 0x44, 0x04, 0x08, 0x10, 0x20, 0x7C, 0x00], // '2'
+    [0x7C, 0x08, 0x10, 0x08, 0x04, 0x44, 0x38, 0x00], // '3'
+    [0x08, 0x18, 0x28, 0x48, 0x7C, 0x08, 0x08, 0x00], // '4'
+    [0x7C, 0x40, 0x78, 0x04, 0x04, 0x44, 0x38, 0x00], // '5'
+    [0x18, 0x20, 0x40, 0x78, 0x44, 0x44, 0x38, 0x00], // '6'
+    [0x7C, 0x04, 0x08, 0x10, 0x20, 0x20, 0x20, 0x00], // '7'
+    [0x38, 0x44, 0x44, 0x38, 0x44, 0x44, 0x38, 0x00], // '8'
+    [0x38, 0x44, 0x44, 0x3C, 0x04, 0x08, 0x30, 0x00], // '9'
+    [0x00, 0x30, 0x30, 0x00, 0x30, 0x30, 0x00, 0x00], // ':'
+    [0x00, 0x30, 0x30, 0x00, 0x30, 0x10, 0x20, 0x00], // ';'
+    [0x08, 0x10, 0x20, 0x40, 0x20, 0x10, 0x08, 0x00], // '<'
+    [0x00, 0x00, 0x7C, 0x00, 0x7C, 0x00, 0x00, 0x00], // '='
+    [0x20, 0x10, 0x08, 0x04, 0x08, 0x10, 0x20, 0x00], // '>'
+    [0x38, 0x44, 0x04, 0x08, 0x10, 0x00, 0x10, 0x00], // '?'
+    [0x38, 0x44, 0x04, 0x34, 0x54, 0x54, 0x38, 0x00], // '@'
+    [0x38, 0x44, 0x44, 0x7C, 0x44, 0x44, 0x44, 0x00], // 'A'
+    [0x78, 0x44, 0x44, 0x78, 0x44, 0x44, 0x78, 0x00], // 'B'
+    [0x38, 0x44, 0x40, 0x40, 0x40, 0x44, 0x38, 0x00], // 'C'
+    [0x70, 0x48, 0x44, 0x44, 0x44, 0x48, 0x70, 0x00], // 'D'
+    [0x7C, 0x40, 0x40, 0x78, 0x40, 0x40, 0x7C, 0x00], // 'E'
+    [0x7C, 0x40, 0x40, 0x78, 0x40, 0x40, 0x40, 0x00], // 'F'
+    [0x38, 0x44, 0x40, 0x5C, 0x44, 0x44, 0x3C, 0x00], // 'G'
+    [0x44, 0x44, 0x44, 0x7C, 0x44, 0x44, 0x44, 0x00], // 'H'
+    [0x38, 0x10, 0x10, 0x10, 0x10, 0x10, 0x38, 0x00], // 'I'
+    [0x1C, 0x08, 0x08, 0x08, 0x08, 0x48, 0x30, 0x00], // 'J'
+    [0x44, 0x48, 0x50, 0x60, 0x50, 0x48, 0x44, 0x00], // 'K'
+    [0x40, 0x40, 0x40, 0x40, 0x40, 0x40, 0x7C, 0x00], // 'L'
+    [0x44, 0x6C, 0x54, 0x54, 0x44, 0x44, 0x44, 0x00], // 'M'
+    [0x44, 0x44, 0x64, 0x54, 0x4C, 0x44, 0x44, 0x00], // 'N'
+    [0x38, 0x44, 0x44, 0x44, 0x44, 0x44, 0x38, 0x00], // 'O'
+    [0x78, 0x44, 0x44, 0x78, 0x40, 0x40, 0x40, 0x00], // 'P'
+    [0x38, 0x44, 0x44, 0x44, 0x54, 0x48, 0x34, 0x00], // 'Q'
+    [0x78, 0x44, 0x44, 0x78, 0x50, 0x48, 0x44, 0x00], // 'R'
+    [0x3C, 0x40, 0x40, 0x38, 0x04, 0x04, 0x78, 0x00], // 'S'
+    [0x7C, 0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x00], // 'T'
+    [0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x38, 0x00], // 'U'
+    [0x44, 0x44, 0x44, 0x44, 0x44, 0x28, 0x10, 0x00], // 'V'
+    [0x44, 0x44, 0x44, 0x54, 0x54, 0x54, 0x28, 0x00], // 'W'
+    [0x44, 0x44, 0x28, 0x10, 0x28, 0x44, 0x44, 0x00], // 'X'
+    [0x44, 0x44, 0x28, 0x10, 0x10, 0x10, 0x10, 0x00], // 'Y'
+    [0x7C, 0x04, 0x08, 0x10, 0x20, 0x40, 0x7C, 0x00], // 'Z'
+    [0x38, 0x20, 0x20, 0x20, 0x20, 0x20, 0x38, 0x00], // '['
+    [0x00, 0x40, 0x20, 0x10, 0x08, 0x04, 0x00, 0x00], // '\\'
+    [0x38, 0x08, 0x08, 0x08, 0x08, 0x08, 0x38, 0x00], // ']'
+    [0x10, 0x28, 0x44, 0x00, 0x00, 0x00, 0x00, 0x00], // '^'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x7C, 0x00], // '_'
+];
+
+fn glyph(c: char) -> &'static [u8; 8] {
+    let c = c.to_ascii_uppercase();
+    match c {
+        ' '..='_' => &GLYPHS[c as usize - 0x20],
+        _ => &GLYPHS['?' as usize - 0x20],
+    }
+}
+
+// Width in pixels of `text`
+pub fn text_width(text: &str) -> u32 {
+    text.chars().count() as u32 * GLYPH_SIZE
+}
+
+// Calls `f` with the offsets of the pixels to draw and whether they are the
+// shadow, which goes one pixel down and right of the text so it reads on
+// any background. Shadow pixels come first.
+fn for_each_pixel<F: FnMut(u32, u32, bool)>(text: &str, mut f: F) {
+    for shadow in [true, false] {
+        let offset = shadow as u32;
+        for (i, c) in text.chars().enumerate() {
+            for (row, bits) in glyph(c).iter().enumerate() {
+                for col in 0..GLYPH_SIZE {
+                    if bits & (0x80 >> col) != 0 {
+                        f(
+                            i as u32 * GLYPH_SIZE + col + offset,
+                            row as u32 + offset,
+                            shadow,
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Draw `text` with its top left corner at (x, y), clipped to the frame
+pub fn draw_text(frame: &mut NesFrame, x: u32, y: u32, text: &str, [r, g, b]: [u8; 3]) {
+    for_each_pixel(text, |dx, dy, shadow| {
+        if shadow {
+            frame.set_pixel(x + dx, y + dy, 0, 0, 0);
+        } else {
+            frame.set_pixel(x + dx, y + dy, r, g, b);
+        }
+    });
+}
+
+// The same for a frame of system palette indexes, see
+// `PPU::indexed_frame`
+pub fn draw_text_indexed(pixels: &mut [u16], x: u32, y: u32, text: &str, color: u16) {
+    for_each_pixel(text, |dx, dy, shadow| {
+        let (px, py) = (x + dx, y + dy);
+        if px < NES_WIDTH && py < NES_HEIGHT {
+            // $0F is black
+            pixels[(py * NES_WIDTH + px) as usize] = if shadow { 0x0F } else { color };
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_draw_text() {
+        assert_eq!(text_width("60.1 fps"), 64);
+        assert_eq!(glyph('a'), glyph('A'));
+        assert_eq!(glyph('~'), glyph('?'));
+
+        let mut frame = NesFrame::new();
+        for y in 0..NES_HEIGHT {
+            for x in 0..NES_WIDTH {
+                frame.set_pixel(x, y, 0, 0, 0xFF);
+            }
+        }
+        let white = [0xFF, 0xFF, 0xFF];
+        draw_text(&mut frame, 10, 20, "I", white);
+        // the top bar of the I, with its shadow one pixel down and right
+        assert_eq!(frame.pixel(11, 20), [0, 0, 0xFF]);
+        assert_eq!(frame.pixel(12, 20), white);
+        assert_eq!(frame.pixel(14, 20), white);
+        assert_eq!(frame.pixel(15, 21), [0, 0, 0]);
+        assert_eq!(frame.pixel(13, 21), white);
+        assert_eq!(frame.pixel(12, 21), [0, 0, 0xFF]);
+        // clipped at the edges
+        draw_text(&mut frame, 250, 235, "WW", white);
+
+        let mut pixels = vec![0x21; (NES_WIDTH * NES_HEIGHT) as usize];
+        draw_text_indexed(&mut pixels, 0, 0, "-", 0x30);
+        assert_eq!(pixels[3 * NES_WIDTH as usize + 1], 0x30);
+        assert_eq!(pixels[3 * NES_WIDTH as usize], 0x21);
+        assert_eq!(pixels[4 * NES_WIDTH as usize + 6], 0x0F);
+    }
+}
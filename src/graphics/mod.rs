@@ -1,94 +1,83 @@
-use sdl2::pixels::Color;
-use sdl2::rect::Rect;
-use sdl2::render::WindowCanvas;
-use sdl2::VideoSubsystem;
-use std::ops::{Deref, DerefMut};
+pub mod font;
+pub mod ntsc;
+#[cfg(feature = "sdl")]
+mod sdl;
+#[cfg(feature = "sdl")]
+pub use sdl::NesSDLScreen;
 
-const NES_WIDTH: u32 = 32 * 8;
-const NES_HEIGHT: u32 = 30 * 8;
+pub const NES_WIDTH: u32 = 32 * 8;
+pub const NES_HEIGHT: u32 = 30 * 8;
 
 // ----------------------------------------------------------------------------
-// NesSDLScreen
+// NesFrame
 // ----------------------------------------------------------------------------
 
-pub struct NesSDLScreen {
-    canvas: WindowCanvas,
-    scaling_factor: u32,
+// How a frame is fit into a window of any size. The frame is centered, the
+// rest of the window is left black.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScaleMode {
+    // the largest whole multiple of the NES resolution that fits, pixels
+    // stay sharp and square
+    Integer,
+    // fill the whole window
+    Stretch,
+    // NTSC pixels are 8:7, wider than tall, as large as fits
+    AspectCorrect,
 }
 
-impl NesSDLScreen {
-    pub fn new(video: &VideoSubsystem, scaling_factor: u32) -> NesSDLScreen {
-        let window = video
-            .window(
-                "NES",
-                NES_WIDTH * scaling_factor,
-                NES_HEIGHT * scaling_factor,
-            )
-            .position_centered()
-            .opengl()
-            .build()
-            .map_err(|e| e.to_string())
-            .unwrap();
-        let canvas = window
-            .into_canvas()
-            .build()
-            .map_err(|e| e.to_string())
-            .unwrap();
-        NesSDLScreen {
-            canvas: canvas,
-            scaling_factor: scaling_factor,
+impl ScaleMode {
+    pub fn next(self) -> ScaleMode {
+        match self {
+            ScaleMode::Integer => ScaleMode::Stretch,
+            ScaleMode::Stretch => ScaleMode::AspectCorrect,
+            ScaleMode::AspectCorrect => ScaleMode::Integer,
         }
     }
 
-    pub fn draw(&mut self, x: u32, y: u32, r: u8, g: u8, b: u8) {
-        let prev_color = self.canvas.draw_color();
-        self.canvas.set_draw_color(Color::RGB(r, g, b));
-        self.canvas
-            .fill_rect(Rect::new(
-                (x * self.scaling_factor) as i32,
-                (y * self.scaling_factor) as i32,
-                self.scaling_factor,
-                self.scaling_factor,
-            ))
-            .unwrap();
-        self.canvas.set_draw_color(prev_color);
-    }
-
-    pub fn draw_frame(&mut self, frame: &NesFrame) {
-        for (y, row) in frame.pixels.iter().enumerate() {
-            for (x, color) in row.iter().enumerate() {
-                self.draw(x as u32, y as u32, color[0], color[1], color[2]);
+    // Where the frame goes in a `width` x `height` window: x, y, width and
+    // height
+    pub fn viewport(self, width: u32, height: u32) -> (u32, u32, u32, u32) {
+        let (w, h) = match self {
+            ScaleMode::Integer => {
+                let scale = (width / NES_WIDTH).min(height / NES_HEIGHT).max(1);
+                (NES_WIDTH * scale, NES_HEIGHT * scale)
             }
-        }
-    }
-}
-
-impl Deref for NesSDLScreen {
-    type Target = WindowCanvas;
-
-    fn deref(&self) -> &Self::Target {
-        &self.canvas
-    }
-}
-
-impl DerefMut for NesSDLScreen {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.canvas
+            ScaleMode::Stretch => (width, height),
+            ScaleMode::AspectCorrect => {
+                // 256 * 8/7 by 240
+                let (aspect_w, aspect_h) = (NES_WIDTH * 8, NES_HEIGHT * 7);
+                if width * aspect_h > height * aspect_w {
+                    (height * aspect_w / aspect_h, height)
+                } else {
+                    (width, width * aspect_h / aspect_w)
+                }
+            }
+        };
+        (
+            width.saturating_sub(w) / 2,
+            height.saturating_sub(h) / 2,
+            w,
+            h,
+        )
     }
 }
 
-// ----------------------------------------------------------------------------
-// NesFrame
-// ----------------------------------------------------------------------------
+// Bytes per pixel of the RGB24 data
+pub const BYTES_PER_PIXEL: usize = 3;
+// Bytes per row of the RGB24 data
+pub const PITCH: usize = NES_WIDTH as usize * BYTES_PER_PIXEL;
 
+// Row-major RGB24 pixels in one contiguous buffer, ready to be uploaded to a
+// texture as is
+#[derive(Clone)]
 pub struct NesFrame {
-    pixels: [[[u8; 3]; NES_WIDTH as usize]; NES_HEIGHT as usize],
+    pixels: Vec<u8>,
 }
 
 impl NesFrame {
     pub fn new() -> NesFrame {
         NesFrame {
-            pixels: [[[0; 3]; NES_WIDTH as usize]; NES_HEIGHT as usize],
+            pixels: vec![0; PITCH * NES_HEIGHT as usize],
         }
     }
 
@@ -96,6 +85,75 @@ impl NesFrame {
         if x >= NES_WIDTH || y >= NES_HEIGHT {
             return;
         }
-        self.pixels[y as usize][x as usize] = [r, g, b]
+        let offset = Self::offset(x, y);
+        self.pixels[offset..offset + BYTES_PER_PIXEL].copy_from_slice(&[r, g, b]);
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 3] {
+        let offset = Self::offset(x, y);
+        [
+            self.pixels[offset],
+            self.pixels[offset + 1],
+            self.pixels[offset + 2],
+        ]
+    }
+
+    // Row-major RGB24 data, NES_HEIGHT rows of PITCH bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    // Row-major RGBA32 data with opaque alpha, e.g. for an HTML canvas
+    pub fn to_rgba_bytes(&self) -> Vec<u8> {
+        let mut rgba = Vec::with_capacity(NES_WIDTH as usize * NES_HEIGHT as usize * 4);
+        for pixel in self.pixels.chunks(BYTES_PER_PIXEL) {
+            rgba.extend_from_slice(pixel);
+            rgba.push(0xFF);
+        }
+        rgba
+    }
+
+    fn offset(x: u32, y: u32) -> usize {
+        y as usize * PITCH + x as usize * BYTES_PER_PIXEL
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_viewport() {
+        assert_eq!(ScaleMode::Integer.viewport(800, 600), (144, 60, 512, 480));
+        // never smaller than the NES resolution
+        assert_eq!(ScaleMode::Integer.viewport(200, 200), (0, 0, 256, 240));
+        assert_eq!(ScaleMode::Stretch.viewport(800, 600), (0, 0, 800, 600));
+        assert_eq!(
+            ScaleMode::AspectCorrect.viewport(800, 480),
+            (107, 0, 585, 480)
+        );
+        assert_eq!(
+            ScaleMode::AspectCorrect.viewport(585, 800),
+            (0, 160, 585, 479)
+        );
+    }
+
+    #[test]
+    fn test_frame_bytes() {
+        let mut frame = NesFrame::new();
+        frame.set_pixel(1, 2, 0x10, 0x20, 0x30);
+        // off screen pixels are ignored
+        frame.set_pixel(NES_WIDTH, 0, 0xFF, 0xFF, 0xFF);
+        assert_eq!(frame.pixel(1, 2), [0x10, 0x20, 0x30]);
+
+        let bytes = frame.as_bytes();
+        assert_eq!(bytes.len(), PITCH * NES_HEIGHT as usize);
+        assert_eq!(bytes[2 * PITCH + 3..2 * PITCH + 6], [0x10, 0x20, 0x30]);
+        assert!(bytes[..2 * PITCH].iter().all(|b| *b == 0));
+
+        let rgba = frame.to_rgba_bytes();
+        let offset = (2 * NES_WIDTH as usize + 1) * 4;
+        assert_eq!(rgba[offset..offset + 4], [0x10, 0x20, 0x30, 0xFF]);
+        assert_eq!(rgba[..4], [0, 0, 0, 0xFF]);
     }
 }
@@ -0,0 +1,202 @@
+use std::f32::consts::PI;
+
+use super::{BYTES_PER_PIXEL, NES_HEIGHT, NES_WIDTH};
+
+// NTSC composite video filter. The PPU outputs a square wave per pixel:
+// its phase is the hue, its levels the brightness. This encodes a frame of
+// system palette colors into that signal and decodes it like a TV does,
+// which blurs colors into their neighbours (color fringing) and leaves a
+// checkered pattern on sharp edges that moves from frame to frame (dot
+// crawl). See https://www.nesdev.org/wiki/NTSC_video for the signal.
+
+// Output width, 7 pixels for every 3 NES pixels like Blargg's nes_ntsc
+pub const NTSC_WIDTH: u32 = 602;
+pub const NTSC_PITCH: usize = NTSC_WIDTH as usize * BYTES_PER_PIXEL;
+
+// Each NES pixel is 8 samples of the 21.48 MHz master clock, a color
+// subcarrier cycle is 12
+const SAMPLES_PER_PIXEL: usize = 8;
+const SAMPLES_PER_CYCLE: usize = 12;
+const SAMPLES_PER_LINE: usize = NES_WIDTH as usize * SAMPLES_PER_PIXEL;
+// A scanline is 341 pixels and a frame 262 scanlines, both move the
+// subcarrier phase by 4 samples
+const PHASE_PER_LINE: usize = 341 * SAMPLES_PER_PIXEL % SAMPLES_PER_CYCLE;
+const PHASE_PER_FRAME: usize = 4;
+
+// Signal voltages relative to sync, for brightness 0-3
+const LOW_LEVELS: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const HIGH_LEVELS: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const BLACK: f32 = 0.518;
+const WHITE: f32 = 1.962;
+// Emphasized channels leave the signal at this fraction
+const ATTENUATION: f32 = 0.746;
+// Lines the decoded hues up with the RGB palette
+const HUE_OFFSET: f32 = 3.9;
+
+pub struct NtscFilter {
+    // RGB24, NTSC_WIDTH x NES_HEIGHT
+    pixels: Vec<u8>,
+    // subcarrier phase at the start of the frame
+    frame_phase: usize,
+    // cos and sin of the subcarrier at each phase, doubled since
+    // demodulating recovers half the amplitude
+    carrier: [(f32, f32); SAMPLES_PER_CYCLE],
+}
+
+impl NtscFilter {
+    pub fn new() -> NtscFilter {
+        let mut carrier = [(0.0, 0.0); SAMPLES_PER_CYCLE];
+        for (phase, wave) in carrier.iter_mut().enumerate() {
+            let angle = PI * (phase as f32 + HUE_OFFSET) / 6.0;
+            *wave = (2.0 * angle.cos(), 2.0 * angle.sin());
+        }
+        NtscFilter {
+            pixels: vec![0; NTSC_PITCH * NES_HEIGHT as usize],
+            frame_phase: 0,
+            carrier,
+        }
+    }
+
+    // Filter a frame as returned by `PPU::indexed_frame`. Each call is the
+    // next frame, the dot crawl moves along.
+    pub fn apply(&mut self, indexed: &[u16]) {
+        let mut signal = [0f32; SAMPLES_PER_LINE];
+        for y in 0..NES_HEIGHT as usize {
+            let phase = self.frame_phase + y * PHASE_PER_LINE;
+            let line = &indexed[y * NES_WIDTH as usize..(y + 1) * NES_WIDTH as usize];
+            for (x, pixel) in line.iter().enumerate() {
+                for i in 0..SAMPLES_PER_PIXEL {
+                    let sample = x * SAMPLES_PER_PIXEL + i;
+                    signal[sample] = encode(*pixel, phase + sample);
+                }
+            }
+            self.decode_line(&signal, phase, y);
+        }
+        self.frame_phase = (self.frame_phase + PHASE_PER_FRAME) % SAMPLES_PER_CYCLE;
+    }
+
+    // Row-major RGB24 data, NES_HEIGHT rows of NTSC_PITCH bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 3] {
+        let offset = y as usize * NTSC_PITCH + x as usize * BYTES_PER_PIXEL;
+        [
+            self.pixels[offset],
+            self.pixels[offset + 1],
+            self.pixels[offset + 2],
+        ]
+    }
+
+    // Average a subcarrier cycle of signal around each output pixel for the
+    // luma, and demodulate it for the two color components
+    fn decode_line(&mut self, signal: &[f32; SAMPLES_PER_LINE], phase: usize, y: usize) {
+        let row = &mut self.pixels[y * NTSC_PITCH..(y + 1) * NTSC_PITCH];
+        for x in 0..NTSC_WIDTH as usize {
+            let center = (x * 2 + 1) * SAMPLES_PER_LINE / (NTSC_WIDTH as usize * 2);
+            let start = center.saturating_sub(SAMPLES_PER_CYCLE / 2);
+            let end = (center + SAMPLES_PER_CYCLE / 2).min(SAMPLES_PER_LINE);
+            let (mut luma, mut i, mut q) = (0.0, 0.0, 0.0);
+            for (sample, level) in signal.iter().enumerate().take(end).skip(start) {
+                let level = level / SAMPLES_PER_CYCLE as f32;
+                let (cos, sin) = self.carrier[(phase + sample) % SAMPLES_PER_CYCLE];
+                luma += level;
+                i += level * cos;
+                q += level * sin;
+            }
+            let rgb = [
+                luma + 0.946882 * i + 0.623557 * q,
+                luma - 0.274788 * i - 0.635691 * q,
+                luma - 1.108545 * i + 1.709007 * q,
+            ];
+            for (out, value) in row[x * BYTES_PER_PIXEL..].iter_mut().zip(rgb) {
+                *out = (value * 255.0).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+}
+
+impl Default for NtscFilter {
+    fn default() -> Self {
+        NtscFilter::new()
+    }
+}
+
+// The signal level of a pixel at subcarrier phase `phase`, 0 is black and
+// 1 white
+fn encode(pixel: u16, phase: usize) -> f32 {
+    let in_phase = |hue: usize| (hue + phase) % SAMPLES_PER_CYCLE < SAMPLES_PER_CYCLE / 2;
+    let hue = (pixel & 0x0F) as usize;
+    let emphasis = pixel >> 6;
+    // hues E and F are always black
+    let brightness = if hue > 13 {
+        1
+    } else {
+        ((pixel >> 4) & 0x03) as usize
+    };
+    let mut low = LOW_LEVELS[brightness];
+    let mut high = HIGH_LEVELS[brightness];
+    // hue 0 is a grey at the high level, D-F at the low level
+    if hue == 0 {
+        low = high;
+    } else if hue > 12 {
+        high = low;
+    }
+
+    let mut level = if in_phase(hue) { high } else { low };
+    if (emphasis & 0b001 != 0 && in_phase(0))
+        || (emphasis & 0b010 != 0 && in_phase(4))
+        || (emphasis & 0b100 != 0 && in_phase(8))
+    {
+        level *= ATTENUATION;
+    }
+    (level - BLACK) / (WHITE - BLACK)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn filtered(color: u16) -> NtscFilter {
+        let mut filter = NtscFilter::new();
+        filter.apply(&vec![color; (NES_WIDTH * NES_HEIGHT) as usize]);
+        filter
+    }
+
+    #[test]
+    fn test_colors() {
+        assert_eq!(filtered(0x30).pixel(300, 100), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(filtered(0x0F).pixel(300, 100), [0, 0, 0]);
+        let [r, g, b] = filtered(0x00).pixel(300, 100);
+        assert!(r.abs_diff(g) <= 2 && g.abs_diff(b) <= 2, "{:?}", (r, g, b));
+
+        let [r, g, b] = filtered(0x16).pixel(300, 100);
+        assert!(r > 2 * g && r > 2 * b, "{:?}", (r, g, b));
+        let [r, g, b] = filtered(0x1A).pixel(300, 100);
+        assert!(g > 2 * r && g > b, "{:?}", (r, g, b));
+        let [r, g, b] = filtered(0x12).pixel(300, 100);
+        assert!(b > 2 * r && b > g, "{:?}", (r, g, b));
+    }
+
+    #[test]
+    fn test_dot_crawl() {
+        // a vertical edge between black and white
+        let mut frame = vec![0x0F; (NES_WIDTH * NES_HEIGHT) as usize];
+        for y in 0..NES_HEIGHT as usize {
+            frame[y * NES_WIDTH as usize + 128..(y + 1) * NES_WIDTH as usize].fill(0x30);
+        }
+        let mut filter = NtscFilter::new();
+        filter.apply(&frame);
+        // 7 pixels per 3, the edge is at 301
+        let edge = filter.pixel(300, 10);
+        // color fringes on the edge
+        assert!(edge[0] != edge[1] || edge[1] != edge[2], "{:?}", edge);
+        // the fringes change from line to line and frame to frame
+        assert_ne!(filter.pixel(300, 11), edge);
+        filter.apply(&frame);
+        assert_ne!(filter.pixel(300, 10), edge);
+        assert_eq!(filter.pixel(100, 10), [0, 0, 0]);
+        assert_eq!(filter.pixel(500, 10), [0xFF, 0xFF, 0xFF]);
+    }
+}
@@ -0,0 +1,166 @@
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::rect::Rect;
+use sdl2::render::{Texture, WindowCanvas};
+use sdl2::video::FullscreenType;
+use sdl2::VideoSubsystem;
+use std::ops::{Deref, DerefMut};
+
+use super::ntsc::{NtscFilter, NTSC_PITCH, NTSC_WIDTH};
+use super::{NesFrame, ScaleMode, NES_HEIGHT, NES_WIDTH, PITCH};
+
+pub struct NesSDLScreen {
+    canvas: WindowCanvas,
+    // Streaming texture the frame is uploaded to. With the unsafe_textures
+    // feature it doesn't borrow the canvas, SDL frees it with the renderer.
+    texture: Texture,
+    // The same for NTSC filtered frames, created on first use
+    ntsc_texture: Option<Texture>,
+    scale_mode: ScaleMode,
+}
+
+impl NesSDLScreen {
+    // The window starts at `scaling_factor` times the NES resolution and can
+    // be resized, the frame is scaled with integer scaling
+    pub fn new(video: &VideoSubsystem, scaling_factor: u32) -> NesSDLScreen {
+        NesSDLScreen::new_with_vsync(video, scaling_factor, false)
+    }
+
+    // With vsync `present` blocks until the next display refresh
+    pub fn new_with_vsync(
+        video: &VideoSubsystem,
+        scaling_factor: u32,
+        vsync: bool,
+    ) -> NesSDLScreen {
+        let window = video
+            .window(
+                "NES",
+                NES_WIDTH * scaling_factor,
+                NES_HEIGHT * scaling_factor,
+            )
+            .position_centered()
+            .resizable()
+            .opengl()
+            .build()
+            .map_err(|e| e.to_string())
+            .unwrap();
+        let mut canvas = window.into_canvas();
+        if vsync {
+            canvas = canvas.present_vsync();
+        }
+        let canvas = canvas.build().map_err(|e| e.to_string()).unwrap();
+        let texture = canvas
+            .texture_creator()
+            .create_texture_streaming(PixelFormatEnum::RGB24, NES_WIDTH, NES_HEIGHT)
+            .map_err(|e| e.to_string())
+            .unwrap();
+        NesSDLScreen {
+            canvas,
+            texture,
+            ntsc_texture: None,
+            scale_mode: ScaleMode::Integer,
+        }
+    }
+
+    pub fn scale_mode(&self) -> ScaleMode {
+        self.scale_mode
+    }
+
+    pub fn set_scale_mode(&mut self, mode: ScaleMode) {
+        self.scale_mode = mode;
+    }
+
+    // Desktop fullscreen, the frame is letterboxed like in a window
+    pub fn set_fullscreen(&mut self, fullscreen: bool) -> Result<(), String> {
+        let mode = if fullscreen {
+            FullscreenType::Desktop
+        } else {
+            FullscreenType::Off
+        };
+        self.canvas.window_mut().set_fullscreen(mode)
+    }
+
+    pub fn is_fullscreen(&self) -> bool {
+        self.canvas.window().fullscreen_state() != FullscreenType::Off
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        // only fails on titles with a nul byte
+        let _ = self.canvas.window_mut().set_title(title);
+    }
+
+    pub fn window_size(&self) -> (u32, u32) {
+        self.canvas.window().size()
+    }
+
+    pub fn set_window_size(&mut self, width: u32, height: u32) -> Result<(), String> {
+        let window = self.canvas.window_mut();
+        window.set_size(width, height).map_err(|e| e.to_string())?;
+        window.set_position(
+            sdl2::video::WindowPos::Centered,
+            sdl2::video::WindowPos::Centered,
+        );
+        Ok(())
+    }
+
+    // The part of a `width` x `height` area the frame is drawn to
+    fn viewport(&self, (width, height): (u32, u32)) -> Rect {
+        let (x, y, w, h) = self.scale_mode.viewport(width, height);
+        Rect::new(x as i32, y as i32, w, h)
+    }
+
+    // Window coordinates to NES pixels
+    pub fn to_nes_coords(&self, x: i32, y: i32) -> Option<(u32, u32)> {
+        // mouse positions are in window coordinates, which can differ from
+        // the renderer's pixels on high DPI displays
+        let viewport = self.viewport(self.canvas.window().size());
+        if !viewport.contains_point((x, y)) {
+            return None;
+        }
+        Some((
+            (x - viewport.x()) as u32 * NES_WIDTH / viewport.width(),
+            (y - viewport.y()) as u32 * NES_HEIGHT / viewport.height(),
+        ))
+    }
+
+    // Upload the frame and copy it to the window as the scale mode says
+    pub fn draw_frame(&mut self, frame: &NesFrame) {
+        self.texture
+            .update(None, frame.as_bytes(), PITCH)
+            .map_err(|e| e.to_string())
+            .unwrap();
+        let viewport = self.viewport(self.canvas.output_size().unwrap());
+        self.canvas.copy(&self.texture, None, viewport).unwrap();
+    }
+
+    // Like `draw_frame`, the wider image is scaled into the same viewport
+    pub fn draw_ntsc(&mut self, filter: &NtscFilter) {
+        let viewport = self.viewport(self.canvas.output_size().unwrap());
+        let canvas = &self.canvas;
+        let texture = self.ntsc_texture.get_or_insert_with(|| {
+            canvas
+                .texture_creator()
+                .create_texture_streaming(PixelFormatEnum::RGB24, NTSC_WIDTH, NES_HEIGHT)
+                .map_err(|e| e.to_string())
+                .unwrap()
+        });
+        texture
+            .update(None, filter.as_bytes(), NTSC_PITCH)
+            .map_err(|e| e.to_string())
+            .unwrap();
+        self.canvas.copy(texture, None, viewport).unwrap();
+    }
+}
+
+impl Deref for NesSDLScreen {
+    type Target = WindowCanvas;
+
+    fn deref(&self) -> &Self::Target {
+        &self.canvas
+    }
+}
+
+impl DerefMut for NesSDLScreen {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.canvas
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+use crate::trace_log::{TraceFields, TraceRecord};
+
+// The last executed instructions, to see how execution got to a breakpoint
+// or a crash without writing a whole trace. With a history attached to the
+// CPU (see `CPU::history`) every instruction is recorded before it runs,
+// like the trace logger does, and the most recent ones up to the capacity
+// are kept.
+
+pub const DEFAULT_CAPACITY: usize = 64 * 1024;
+
+pub struct InstructionHistory {
+    records: VecDeque<TraceRecord>,
+    capacity: usize,
+}
+
+impl InstructionHistory {
+    pub fn new(capacity: usize) -> Self {
+        InstructionHistory {
+            records: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub(crate) fn push(&mut self, record: TraceRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // The `count` most recent instructions, the oldest first
+    pub fn last(&self, count: usize) -> impl Iterator<Item = &TraceRecord> {
+        self.records
+            .iter()
+            .skip(self.records.len().saturating_sub(count))
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    // Write the `count` most recent instructions as trace lines
+    pub fn dump<W: Write>(&self, count: usize, out: &mut W) -> io::Result<()> {
+        for record in self.last(count) {
+            writeln!(out, "{}", record.to_text(TraceFields::all()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cartridge::Cartridge;
+    use crate::emulator::Emulator;
+
+    #[test]
+    fn test_history() {
+        // LDX #$10; INX; STX $0200; JMP $8002
+        let mut program = vec![0xA2, 0x10, 0xE8, 0x8E, 0x00, 0x02, 0x4C, 0x02, 0x80];
+        program.resize(0x3FFC, 0);
+        program.extend_from_slice(&[0x00, 0x80]);
+        let mut emulator = Emulator::new(Cartridge::new_from_program(program));
+        emulator.attach_history(4);
+        for _ in 0..6 {
+            emulator.cpu_mut().step().unwrap();
+        }
+
+        let history = emulator.history().unwrap();
+        assert_eq!(history.len(), 4);
+        let pcs: Vec<u16> = history.last(10).map(|r| r.registers.pc).collect();
+        assert_eq!(pcs, [0x8003, 0x8006, 0x8002, 0x8003]);
+        // registers before the instruction ran
+        assert_eq!(history.last(1).next().unwrap().registers.x, 0x12);
+
+        let mut out = vec![];
+        history.dump(2, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("8002  E8        INX"), "{}", out);
+        assert!(lines[1].contains(" STX $0200 "), "{}", out);
+
+        emulator.detach_history();
+        assert!(emulator.history().is_none());
+    }
+}
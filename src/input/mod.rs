@@ -0,0 +1,196 @@
+use std::path::Path;
+
+use crate::joypad::JoypadStatus;
+
+#[cfg(feature = "sdl")]
+mod sdl;
+#[cfg(feature = "sdl")]
+pub use sdl::NesSDLGamepads;
+
+// ----------------------------------------------------------------------------
+// InputConfig
+// ----------------------------------------------------------------------------
+
+// Key bindings for the two controllers, loaded from a small TOML file:
+//
+//   # player 1
+//   [player1]
+//   up = "Up"
+//   a = "A"
+//
+//   [player2]
+//   start = "Keypad Enter"
+//
+// Keys are named like SDL names them (SDL_GetKeyName), so the bindings
+// don't depend on the SDL frontend. Buttons left out of the file keep
+// their default key.
+pub struct InputConfig {
+    // (key name, player, button)
+    bindings: Vec<(String, usize, JoypadStatus)>,
+}
+
+const BUTTON_NAMES: [(&str, JoypadStatus); 8] = [
+    ("up", JoypadStatus::UP),
+    ("down", JoypadStatus::DOWN),
+    ("left", JoypadStatus::LEFT),
+    ("right", JoypadStatus::RIGHT),
+    ("select", JoypadStatus::SELECT),
+    ("start", JoypadStatus::START),
+    ("a", JoypadStatus::BUTTON_A),
+    ("b", JoypadStatus::BUTTON_B),
+];
+
+// "a", "start", "up"...
+pub fn button_by_name(name: &str) -> Option<JoypadStatus> {
+    BUTTON_NAMES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, b)| *b)
+}
+
+impl InputConfig {
+    pub fn new() -> Self {
+        let mut config = InputConfig { bindings: vec![] };
+        let defaults = [
+            ["Up", "Down", "Left", "Right", "Space", "Return", "A", "S"],
+            ["I", "K", "J", "L", "T", "Y", "H", "G"],
+        ];
+        for (player, keys) in defaults.iter().enumerate() {
+            for (key, (_, button)) in keys.iter().zip(BUTTON_NAMES.iter()) {
+                config.bind(key, player, *button);
+            }
+        }
+        config
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("{}: {}", path.as_ref().display(), e))?;
+        InputConfig::parse(&text)
+    }
+
+    // Bindings in `text` override the defaults
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut config = InputConfig::new();
+        let mut player = None;
+        for (i, line) in text.lines().enumerate() {
+            let err = |msg: &str| format!("line {}: {}", i + 1, msg);
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                player = match section.trim() {
+                    "player1" => Some(0),
+                    "player2" => Some(1),
+                    s => return Err(err(&format!("unknown section [{}]", s))),
+                };
+                continue;
+            }
+            let player = player.ok_or_else(|| err("binding outside of a [playerN] section"))?;
+            let (name, key) = line
+                .split_once('=')
+                .ok_or_else(|| err("expected `button = \"key\"`"))?;
+            let name = name.trim();
+            let button =
+                button_by_name(name).ok_or_else(|| err(&format!("unknown button {}", name)))?;
+            let key = key
+                .trim()
+                .strip_prefix('"')
+                .and_then(|k| k.strip_suffix('"'))
+                .ok_or_else(|| err("key names must be quoted"))?;
+            config.bind(key, player, button);
+        }
+        Ok(config)
+    }
+
+    // Bind a button to a key, replacing the key it was bound to before
+    pub fn bind(&mut self, key: &str, player: usize, button: JoypadStatus) {
+        self.bindings
+            .retain(|(_, p, b)| !(*p == player && *b == button));
+        self.bindings.push((key.to_string(), player, button));
+    }
+
+    // (key name, player, button)
+    pub fn bindings(&self) -> impl Iterator<Item = (&str, usize, JoypadStatus)> {
+        self.bindings.iter().map(|(k, p, b)| (k.as_str(), *p, *b))
+    }
+}
+
+// Comments start with a # outside of quotes, "#" is a key
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        InputConfig::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key_for(config: &InputConfig, player: usize, button: JoypadStatus) -> Option<&str> {
+        config
+            .bindings()
+            .find(|&(_, p, b)| p == player && b == button)
+            .map(|(k, _, _)| k)
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = InputConfig::parse(
+            "# swap A and B\n\
+             [player1]\n\
+             a = \"S\"\n\
+             b = \"A\" # trailing comment\n\
+             \n\
+             [player2]\n\
+             start = \"Keypad Enter\"\n",
+        )
+        .unwrap();
+        assert_eq!(key_for(&config, 0, JoypadStatus::BUTTON_A), Some("S"));
+        assert_eq!(key_for(&config, 0, JoypadStatus::BUTTON_B), Some("A"));
+        assert_eq!(
+            key_for(&config, 1, JoypadStatus::START),
+            Some("Keypad Enter")
+        );
+        // defaults are kept
+        assert_eq!(key_for(&config, 0, JoypadStatus::UP), Some("Up"));
+        assert_eq!(config.bindings().count(), 16);
+    }
+
+    #[test]
+    fn test_parse_hash_key() {
+        let config = InputConfig::parse(
+            "[player1]\n\
+             select = \"#\" # the # key\n\
+             start = \"#\"\n",
+        )
+        .unwrap();
+        assert_eq!(key_for(&config, 0, JoypadStatus::SELECT), Some("#"));
+        assert_eq!(key_for(&config, 0, JoypadStatus::START), Some("#"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(InputConfig::parse("a = \"S\"").is_err());
+        assert!(InputConfig::parse("[player3]").is_err());
+        assert!(InputConfig::parse("[player1]\nturbo = \"X\"").is_err());
+        assert!(InputConfig::parse("[player1]\na = S").is_err());
+        assert_eq!(
+            InputConfig::parse("[player1]\n\nfoo").err(),
+            Some("line 3: expected `button = \"key\"`".to_string())
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::event::Event;
+use sdl2::GameControllerSubsystem;
+
+use crate::joypad::JoypadStatus;
+
+// Stick deflection needed to count as a d-pad press, out of 32767
+const AXIS_DEAD_ZONE: i16 = 16_000;
+
+// The bottom and right face buttons act as B and A, like on the NES pad
+const BUTTON_MAP: [(Button, JoypadStatus); 8] = [
+    (Button::DPadUp, JoypadStatus::UP),
+    (Button::DPadDown, JoypadStatus::DOWN),
+    (Button::DPadLeft, JoypadStatus::LEFT),
+    (Button::DPadRight, JoypadStatus::RIGHT),
+    (Button::Back, JoypadStatus::SELECT),
+    (Button::Start, JoypadStatus::START),
+    (Button::A, JoypadStatus::BUTTON_B),
+    (Button::B, JoypadStatus::BUTTON_A),
+];
+
+// Game controllers, each assigned to a player. Pads are opened when SDL
+// reports them, which it also does at startup for the ones already
+// connected, so feed every event to `handle_event`.
+pub struct NesSDLGamepads {
+    subsystem: GameControllerSubsystem,
+    // (pad, player)
+    pads: Vec<(GameController, usize)>,
+}
+
+impl NesSDLGamepads {
+    pub fn new(subsystem: &GameControllerSubsystem) -> NesSDLGamepads {
+        NesSDLGamepads {
+            subsystem: subsystem.clone(),
+            pads: vec![],
+        }
+    }
+
+    // Opens and closes pads as they are plugged in and out
+    pub fn handle_event(&mut self, event: &Event) {
+        match *event {
+            Event::ControllerDeviceAdded { which, .. } => {
+                let pad = match self.subsystem.open(which) {
+                    Ok(pad) => pad,
+                    Err(e) => {
+                        eprintln!("failed to open game controller {}: {}", which, e);
+                        return;
+                    }
+                };
+                // the first free player, extra pads share player 2
+                let player = if self.pads.iter().any(|(_, p)| *p == 0) {
+                    1
+                } else {
+                    0
+                };
+                eprintln!("{} connected as player {}", pad.name(), player + 1);
+                self.pads.push((pad, player));
+            }
+            Event::ControllerDeviceRemoved { which, .. } => {
+                self.pads.retain(|(pad, _)| pad.instance_id() != which);
+            }
+            _ => {}
+        }
+    }
+
+    // Buttons held on all pads of a player
+    pub fn buttons(&self, player: usize) -> JoypadStatus {
+        let mut buttons = JoypadStatus::empty();
+        for (pad, _) in self.pads.iter().filter(|(_, p)| *p == player) {
+            for (button, status) in BUTTON_MAP.iter() {
+                if pad.button(*button) {
+                    buttons.insert(*status);
+                }
+            }
+            let x = pad.axis(Axis::LeftX);
+            let y = pad.axis(Axis::LeftY);
+            if x < -AXIS_DEAD_ZONE {
+                buttons.insert(JoypadStatus::LEFT);
+            } else if x > AXIS_DEAD_ZONE {
+                buttons.insert(JoypadStatus::RIGHT);
+            }
+            if y < -AXIS_DEAD_ZONE {
+                buttons.insert(JoypadStatus::UP);
+            } else if y > AXIS_DEAD_ZONE {
+                buttons.insert(JoypadStatus::DOWN);
+            }
+        }
+        buttons
+    }
+}
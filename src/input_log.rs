@@ -0,0 +1,418 @@
+use std::fmt::Write;
+
+use crate::emulator::Emulator;
+use crate::joypad::JoypadStatus;
+use crate::savestate::{crc32, StateReader, StateWriter};
+
+// Movies: the input of both controllers for every frame, which replays
+// deterministically from the same starting point. That is either power on,
+// or a save state taken when recording started.
+//
+// Movies are stored in a binary format using the save state encoding:
+//
+//   "NESM" magic | u16 version | u32 ROM CRC-32 (0 = unknown)
+//   | vec start state (empty = power on) | u32 frame count
+//   | frames: u8 player 1, u8 player 2, u8 commands
+//
+// and can be converted from/to FCEUX's .fm2 text format.
+
+const MOVIE_MAGIC: &[u8; 4] = b"NESM";
+const MOVIE_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MovieFrame {
+    pub buttons: [JoypadStatus; 2],
+    // reset the console before running the frame
+    pub reset: bool,
+}
+
+impl MovieFrame {
+    pub fn new(buttons: [JoypadStatus; 2]) -> Self {
+        MovieFrame {
+            buttons,
+            reset: false,
+        }
+    }
+
+    // Set up the frame's input, the caller then runs the frame
+    pub fn apply(&self, emulator: &mut Emulator) {
+        if self.reset {
+            emulator.reset();
+        }
+        for (player, buttons) in self.buttons.iter().enumerate() {
+            emulator.set_buttons(player, *buttons);
+        }
+    }
+}
+
+pub struct Movie {
+    // CRC-32 of the iNES file the movie was recorded with
+    pub rom_hash: Option<u32>,
+    // None: the movie starts at power on, play it on a fresh Emulator
+    pub start_state: Option<Vec<u8>>,
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    pub fn new(rom_hash: Option<u32>, start_state: Option<Vec<u8>>) -> Self {
+        Movie {
+            rom_hash,
+            start_state,
+            frames: vec![],
+        }
+    }
+
+    // Start recording from the emulator's current state
+    pub fn start_recording(emulator: &Emulator, rom: &[u8]) -> Self {
+        Movie::new(Some(crc32(rom)), Some(emulator.save_state()))
+    }
+
+    pub fn record(&mut self, frame: MovieFrame) {
+        self.frames.push(frame);
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    // Errors if the movie was recorded with another ROM
+    pub fn check_rom(&self, rom: &[u8]) -> Result<(), String> {
+        match self.rom_hash {
+            Some(hash) if hash != crc32(rom) => Err(format!(
+                "movie was recorded with another ROM (CRC-32 {:08X}, this one is {:08X})",
+                hash,
+                crc32(rom)
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Rewind the emulator to where the movie starts
+    pub fn start_playback(&self, emulator: &mut Emulator) -> Result<(), String> {
+        match &self.start_state {
+            Some(state) => emulator.load_state(state),
+            None => Ok(()),
+        }
+    }
+
+    // Play the whole movie
+    pub fn play(&self, emulator: &mut Emulator) -> Result<(), String> {
+        self.start_playback(emulator)?;
+        for frame in self.frames.iter() {
+            frame.apply(emulator);
+            emulator.run_frame().map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    // ------------------------------------------------------------------------
+    // Native format
+    // ------------------------------------------------------------------------
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::new_with_header(MOVIE_MAGIC, MOVIE_VERSION);
+        w.write_u32(self.rom_hash.unwrap_or(0));
+        w.write_vec(self.start_state.as_deref().unwrap_or(&[]));
+        w.write_u32(self.frames.len() as u32);
+        for frame in self.frames.iter() {
+            w.write_u8(frame.buttons[0].bits());
+            w.write_u8(frame.buttons[1].bits());
+            w.write_u8(frame.reset as u8);
+        }
+        w.into_bytes()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        let mut r = StateReader::new_with_header(data, MOVIE_MAGIC, MOVIE_VERSION, "movie")?;
+        let rom_hash = Some(r.read_u32()?).filter(|&hash| hash != 0);
+        let start_state = r.read_vec()?;
+        let mut movie = Movie::new(
+            rom_hash,
+            Some(start_state).filter(|state| !state.is_empty()),
+        );
+        let count = r.read_u32()?;
+        for _ in 0..count {
+            let p1 = JoypadStatus::from_bits_truncate(r.read_u8()?);
+            let p2 = JoypadStatus::from_bits_truncate(r.read_u8()?);
+            let reset = r.read_u8()? & 1 != 0;
+            movie.record(MovieFrame {
+                buttons: [p1, p2],
+                reset,
+            });
+        }
+        if !r.is_at_end() {
+            return Err("trailing data after the movie".to_string());
+        }
+        Ok(movie)
+    }
+
+    // ------------------------------------------------------------------------
+    // FCEUX .fm2
+    // ------------------------------------------------------------------------
+
+    // Only text movies of standard controllers starting at power on are
+    // supported. The ROM checksum can't be verified, FCEUX uses MD5.
+    //
+    // Ref: https://fceux.com/web/help/fm2.html
+    pub fn from_fm2(text: &str) -> Result<Self, String> {
+        let mut movie = Movie::new(None, None);
+        for (i, line) in text.lines().enumerate() {
+            let err = |msg: String| format!("line {}: {}", i + 1, msg);
+            if line.starts_with('|') {
+                movie.record(parse_fm2_frame(line).map_err(err)?);
+                continue;
+            }
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            let value = value.trim();
+            match key {
+                "binary" if value != "0" => {
+                    return Err("binary fm2 movies are not supported".to_string())
+                }
+                "fourscore" if value != "0" => {
+                    return Err("four score movies are not supported".to_string())
+                }
+                "palFlag" if value != "0" => return Err("PAL movies are not supported".to_string()),
+                "port0" | "port1" if value != "0" && value != "1" => {
+                    return Err(err(format!("unsupported {} device {}", key, value)))
+                }
+                "savestate" => {
+                    return Err("movies starting from a save state are not supported".to_string())
+                }
+                _ => {}
+            }
+        }
+        Ok(movie)
+    }
+
+    // Fails for movies starting from a save state, which FCEUX can't load
+    pub fn to_fm2(&self, rom_name: &str) -> Result<String, String> {
+        if self.start_state.is_some() {
+            return Err("movies starting from a save state can't be exported".to_string());
+        }
+        let mut out = String::new();
+        out.push_str("version 3\n");
+        out.push_str("emuVersion 22020\n");
+        out.push_str("rerecordCount 0\n");
+        out.push_str("palFlag 0\n");
+        writeln!(out, "romFilename {}", rom_name).unwrap();
+        // FCEUX only warns about a checksum mismatch
+        out.push_str("romChecksum base64:AAAAAAAAAAAAAAAAAAAAAA==\n");
+        out.push_str("guid 00000000-0000-0000-0000-000000000000\n");
+        out.push_str("fourscore 0\n");
+        out.push_str("microphone 0\n");
+        out.push_str("port0 1\n");
+        out.push_str("port1 1\n");
+        out.push_str("port2 0\n");
+        out.push_str("FDS 0\n");
+        out.push_str("NewPPU 0\n");
+        for frame in self.frames.iter() {
+            writeln!(
+                out,
+                "|{}|{}|{}||",
+                frame.reset as u8,
+                fm2_buttons(frame.buttons[0]),
+                fm2_buttons(frame.buttons[1])
+            )
+            .unwrap();
+        }
+        Ok(out)
+    }
+}
+
+// fm2 lists the buttons in the same order as the JoypadStatus bits
+const FM2_BUTTONS: &[u8; 8] = b"RLDUTSBA";
+
+// |commands|port0|port1|port2|
+fn parse_fm2_frame(line: &str) -> Result<MovieFrame, String> {
+    let fields: Vec<&str> = line.split('|').collect();
+    if fields.len() < 5 {
+        return Err(format!("invalid frame {}", line));
+    }
+    let commands: u8 = fields[1]
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid commands {}", fields[1]))?;
+    // bit 0 is a soft reset, bit 1 power cycling which is treated the same
+    let reset = commands & 0b11 != 0;
+    Ok(MovieFrame {
+        buttons: [parse_fm2_buttons(fields[2])?, parse_fm2_buttons(fields[3])?],
+        reset,
+    })
+}
+
+// A button is released when its column is ' ' or '.'
+fn parse_fm2_buttons(field: &str) -> Result<JoypadStatus, String> {
+    if field.is_empty() {
+        return Ok(JoypadStatus::empty());
+    }
+    if field.len() != 8 {
+        return Err(format!("invalid controller input {}", field));
+    }
+    let mut bits = 0;
+    for (i, c) in field.bytes().enumerate() {
+        if c != b' ' && c != b'.' {
+            bits |= 0x80 >> i;
+        }
+    }
+    Ok(JoypadStatus::from_bits_truncate(bits))
+}
+
+fn fm2_buttons(buttons: JoypadStatus) -> String {
+    FM2_BUTTONS
+        .iter()
+        .enumerate()
+        .map(|(i, &c)| {
+            if buttons.bits() & (0x80 >> i) != 0 {
+                c as char
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Cartridge;
+
+    // Counts START presses at $00, reading the joypad every frame:
+    //
+    //   LDA #$80 : STA $2000    ; NMI on
+    //   loop: JMP loop
+    //   nmi:  LDA #1 : STA $4016 : LDA #0 : STA $4016
+    //         LDA $4016 x4      ; A, B, Select, Start
+    //         AND #1 : CLC : ADC $00 : STA $00 : RTI
+    fn test_program() -> Vec<u8> {
+        let mut program = vec![
+            0xA9, 0x80, 0x8D, 0x00, 0x20, // 8000
+            0x4C, 0x05, 0x80, // 8005
+            0xA9, 0x01, 0x8D, 0x16, 0x40, 0xA9, 0x00, 0x8D, 0x16, 0x40, // 8008
+            0xAD, 0x16, 0x40, 0xAD, 0x16, 0x40, 0xAD, 0x16, 0x40, 0xAD, 0x16, 0x40, // 801A
+            0x29, 0x01, 0x18, 0x65, 0x00, 0x85, 0x00, 0x40, // 801E
+        ];
+        program.resize(0x3FFA, 0);
+        // NMI, reset and IRQ vectors
+        program.extend_from_slice(&[0x08, 0x80, 0x00, 0x80, 0x00, 0x80]);
+        program
+    }
+
+    fn new_emulator() -> Emulator {
+        Emulator::new(Cartridge::new_from_program(test_program()))
+    }
+
+    fn start_presses(emulator: &Emulator) -> u8 {
+        emulator.cpu().bus.cpu_ram[0]
+    }
+
+    fn test_movie() -> Movie {
+        let mut movie = Movie::new(None, None);
+        for i in 0..20 {
+            let mut frame = MovieFrame::new([JoypadStatus::empty(); 2]);
+            if i % 4 == 0 {
+                frame.buttons[0] = JoypadStatus::START | JoypadStatus::RIGHT;
+            }
+            if i == 9 {
+                frame.buttons[1] = JoypadStatus::BUTTON_A;
+            }
+            movie.record(frame);
+        }
+        movie
+    }
+
+    #[test]
+    fn test_playback() {
+        let movie = test_movie();
+        let mut emulator = new_emulator();
+        movie.play(&mut emulator).unwrap();
+        // START is held on frames 0, 4, 8, 12 and 16, the NMI handler of
+        // each frame reads that frame's input
+        assert_eq!(start_presses(&emulator), 5);
+
+        // replaying gives the same result
+        let mut again = new_emulator();
+        movie.play(&mut again).unwrap();
+        assert_eq!(emulator.save_state(), again.save_state());
+    }
+
+    #[test]
+    fn test_record_from_state() {
+        let mut emulator = new_emulator();
+        emulator.set_buttons(0, JoypadStatus::START);
+        emulator.run_frame().unwrap();
+        emulator.run_frame().unwrap();
+
+        let mut movie = Movie::start_recording(&emulator, &test_program());
+        let recorded = start_presses(&emulator);
+        for _ in 0..5 {
+            let frame = MovieFrame::new([JoypadStatus::START, JoypadStatus::empty()]);
+            frame.apply(&mut emulator);
+            movie.record(frame);
+            emulator.run_frame().unwrap();
+        }
+        let end_state = emulator.save_state();
+
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        let mut emulator = new_emulator();
+        movie.start_playback(&mut emulator).unwrap();
+        assert_eq!(start_presses(&emulator), recorded);
+        movie.play(&mut emulator).unwrap();
+        assert_eq!(emulator.save_state(), end_state);
+
+        assert!(movie.check_rom(&test_program()).is_ok());
+        assert!(movie.check_rom(&[0; 16]).is_err());
+        assert!(movie.to_fm2("test").is_err());
+    }
+
+    #[test]
+    fn test_native_format() {
+        let mut movie = test_movie();
+        movie.frames[3].reset = true;
+        let bytes = movie.to_bytes();
+        let loaded = Movie::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.frames, movie.frames);
+        assert_eq!(loaded.rom_hash, None);
+        assert_eq!(loaded.start_state, None);
+
+        assert!(Movie::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Movie::from_bytes(&StateWriter::new().into_bytes()).is_err());
+
+        // a 4GB start state, the file ends before it
+        let mut corrupt = bytes;
+        corrupt[10..14].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Movie::from_bytes(&corrupt).is_err());
+    }
+
+    #[test]
+    fn test_fm2() {
+        let mut movie = test_movie();
+        movie.frames[3].reset = true;
+        let text = movie.to_fm2("test").unwrap();
+        assert!(text.contains("\n|0|R...T...|........||\n"));
+        assert!(text.contains("\n|1|........|........||\n"));
+        assert!(text.contains("\n|0|........|.......A||\n"));
+
+        let loaded = Movie::from_fm2(&text).unwrap();
+        assert_eq!(loaded.frames, movie.frames);
+
+        // FCEUX also writes spaces for released buttons and omits unused ports
+        let movie = Movie::from_fm2("version 3\nport1 0\n|0|R  U  B |||\n").unwrap();
+        assert_eq!(
+            movie.frames,
+            [MovieFrame::new([
+                JoypadStatus::RIGHT | JoypadStatus::UP | JoypadStatus::BUTTON_B,
+                JoypadStatus::empty()
+            ])]
+        );
+
+        assert!(Movie::from_fm2("binary 1\n").is_err());
+        assert!(Movie::from_fm2("fourscore 1\n").is_err());
+        assert!(Movie::from_fm2("port0 2\n").is_err());
+        assert!(Movie::from_fm2("|0|RLDU|||\n").is_err());
+        assert!(Movie::from_fm2("|x|........|........||\n").is_err());
+    }
+}
@@ -1,7 +1,10 @@
 use bitflags::bitflags;
 
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
 bitflags! {
     // Ref: https://wiki.nesdev.org/w/index.php/Controller_reading_code
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct JoypadStatus: u8 {
         const RIGHT             = 0b10000000;
         const LEFT              = 0b01000000;
@@ -14,12 +17,26 @@ bitflags! {
     }
 }
 
+// What a controller reports after the 8 buttons. Nintendo's controllers
+// shift in 1s, many third party ones 0s, and some games tell them apart
+// this way.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum JoypadKind {
+    #[default]
+    Official,
+    ThirdParty,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Joypad {
     // strobe bit on - controller reports only status of the button A on every read
     // strobe bit off - controller cycles through all buttons
     strobe: bool,
     next_btn_idx: u8,
     status: JoypadStatus,
+    #[cfg_attr(feature = "serde", serde(default))]
+    kind: JoypadKind,
 }
 
 impl Joypad {
@@ -28,9 +45,26 @@ impl Joypad {
             strobe: false,
             next_btn_idx: 0,
             status: JoypadStatus::from_bits_truncate(0),
+            kind: JoypadKind::Official,
         }
     }
 
+    pub fn kind(&self) -> JoypadKind {
+        self.kind
+    }
+
+    pub fn set_kind(&mut self, kind: JoypadKind) {
+        self.kind = kind;
+    }
+
+    // The console was power cycled, the same controller stays plugged in
+    pub fn power_on(&mut self) {
+        *self = Joypad {
+            kind: self.kind,
+            ..Joypad::new()
+        };
+    }
+
     pub fn write(&mut self, value: u8) {
         // first bit indicates strobe mode on/off
         self.strobe = (value & 1) == 1;
@@ -39,13 +73,18 @@ impl Joypad {
         }
     }
 
+    // A button in bit 0, the only bit a controller drives. The bus fills in
+    // the others.
     pub fn read(&mut self) -> u8 {
         fn is_btn_on(status: &JoypadStatus, btn_idx: u8) -> bool {
             (status.bits & (1 << btn_idx)) > 0
         }
 
         if self.next_btn_idx > 7 {
-            return 1;
+            return match self.kind {
+                JoypadKind::Official => 1,
+                JoypadKind::ThirdParty => 0,
+            };
         }
         let response: u8 = if is_btn_on(&self.status, self.next_btn_idx) {
             1
@@ -65,6 +104,26 @@ impl Joypad {
     pub fn unset(&mut self, status: &JoypadStatus) {
         self.status.set(*status, false);
     }
+
+    // Replace the state of all buttons at once
+    pub fn set_status(&mut self, status: JoypadStatus) {
+        self.status = status;
+    }
+}
+
+impl SaveState for Joypad {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.strobe);
+        w.write_u8(self.next_btn_idx);
+        w.write_u8(self.status.bits());
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.strobe = r.read_bool()?;
+        self.next_btn_idx = r.read_u8()?;
+        self.status = JoypadStatus::from_bits_truncate(r.read_u8()?);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -108,4 +167,21 @@ mod test {
             joypad.write(0);
         }
     }
+
+    #[test]
+    fn test_third_party_reads_past_8() {
+        let mut joypad = Joypad::new();
+        joypad.set_kind(JoypadKind::ThirdParty);
+        joypad.set(&JoypadStatus::RIGHT);
+        joypad.write(1);
+        joypad.write(0);
+        let reads: Vec<u8> = (0..10).map(|_| joypad.read()).collect();
+        assert_eq!(reads, [0, 0, 0, 0, 0, 0, 0, 1, 0, 0]);
+
+        // still a third party controller after a power cycle
+        joypad.power_on();
+        assert_eq!(joypad.kind(), JoypadKind::ThirdParty);
+        let reads: Vec<u8> = (0..9).map(|_| joypad.read()).collect();
+        assert_eq!(reads[8], 0);
+    }
 }
@@ -1,7 +1,43 @@
+pub mod apu;
+pub mod apu_log;
+pub mod audio;
 pub mod bus;
 pub mod cartridge;
+pub mod cdl;
+pub mod cheats;
+pub mod clock;
 pub mod cpu;
+pub mod crash;
+pub mod debugger;
+pub mod desync;
+pub mod easy6502;
+pub mod emulator;
+pub mod event_log;
+pub mod expr;
 pub mod graphics;
+pub mod history;
+pub mod input;
+pub mod input_log;
 pub mod joypad;
 mod mapper;
+pub mod monitor;
+pub mod netplay;
+pub mod osd;
 pub mod ppu;
+pub mod profiler;
+pub mod recent_roms;
+pub mod romdb;
+pub mod savestate;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod settings;
+pub mod state_slots;
+pub mod symbols;
+pub mod trace_log;
+pub mod unif;
+pub mod video_recorder;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod zapper;
+
+pub use emulator::Emulator;
@@ -1,2 +1,12 @@
+pub mod chr_memory;
 pub mod mapper;
 pub mod mapper_0;
+pub mod mapper_1;
+pub mod mapper_11;
+pub mod mapper_185;
+pub mod mapper_21;
+pub mod mapper_24;
+pub mod mapper_66;
+pub mod mapper_7;
+pub mod mapper_87;
+pub mod vrc_irq;
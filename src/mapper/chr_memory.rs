@@ -0,0 +1,107 @@
+use crate::savestate::{StateReader, StateWriter};
+
+const CHR_RAM_SIZE: usize = 0x2000;
+
+// The pattern table memory of a cartridge: its CHR ROM, or 8KB of CHR RAM
+// when it has no CHR ROM. Mappers bank switch it by mapping PPU addresses
+// to offsets in it, only RAM can be written and is part of save states.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChrMemory {
+    data: Vec<u8>,
+    is_ram: bool,
+}
+
+impl ChrMemory {
+    pub fn new(chr_rom: Vec<u8>) -> Self {
+        let is_ram = chr_rom.is_empty();
+        let data = if is_ram {
+            vec![0; CHR_RAM_SIZE]
+        } else {
+            chr_rom
+        };
+        ChrMemory { data, is_ram }
+    }
+
+    // Of the ROM or the RAM, never 0
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn read(&self, offset: usize) -> u8 {
+        self.data[offset]
+    }
+
+    // Returns false for ROM, which ignores writes
+    pub fn write(&mut self, offset: usize, value: u8) -> bool {
+        if !self.is_ram {
+            return false;
+        }
+        self.data[offset] = value;
+        true
+    }
+
+    // For `Mapper::chr_rom_offset`, None for RAM
+    pub fn rom_offset(&self, offset: usize) -> Option<usize> {
+        (!self.is_ram).then_some(offset)
+    }
+
+    // For `Mapper::rom_sizes`, 0 for RAM
+    pub fn rom_size(&self) -> usize {
+        if self.is_ram {
+            0
+        } else {
+            self.data.len()
+        }
+    }
+
+    // The ROM to build the mapper again with, empty for RAM
+    pub fn take_rom(&mut self) -> Vec<u8> {
+        if self.is_ram {
+            vec![]
+        } else {
+            std::mem::take(&mut self.data)
+        }
+    }
+
+    pub fn power_on(&mut self) {
+        if self.is_ram {
+            self.data.fill(0);
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        if self.is_ram {
+            w.write_vec(&self.data);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        if self.is_ram {
+            r.read_vec_into(&mut self.data)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rom_and_ram() {
+        let mut rom = ChrMemory::new(vec![0x42; 0x4000]);
+        assert!(!rom.write(0x10, 0));
+        assert_eq!(rom.read(0x10), 0x42);
+        assert_eq!((rom.size(), rom.rom_size()), (0x4000, 0x4000));
+        assert_eq!(rom.rom_offset(0x10), Some(0x10));
+
+        let mut ram = ChrMemory::new(vec![]);
+        assert!(ram.write(0x10, 0x42));
+        assert_eq!(ram.read(0x10), 0x42);
+        assert_eq!((ram.size(), ram.rom_size()), (CHR_RAM_SIZE, 0));
+        assert_eq!(ram.rom_offset(0x10), None);
+        assert!(ram.take_rom().is_empty());
+        ram.power_on();
+        assert_eq!(ram.read(0x10), 0);
+    }
+}
@@ -1,20 +1,101 @@
+use crate::apu::mixer::ExpansionAudio;
+use crate::cartridge::Mirror;
+use crate::savestate::{StateReader, StateWriter};
+
+// A mapper owns the cartridge's PRG and CHR memory and decides what the
+// CPU and PPU see at each address. Bank switching mappers change their
+// state through writes to the ROM address space, so every access goes
+// through the mapper with a mutable reference.
+#[cfg_attr(feature = "serde", typetag::serde)]
 pub trait Mapper {
-    fn cpu_read_mapping(&self, addr: u16) -> Option<u16>;
-    fn cpu_write_mapping(&self, addr: u16) -> Option<u16>;
-    fn ppu_read_mapping(&self, addr: u16) -> Option<u16>;
-    fn ppu_write_mapping(&self, addr: u16) -> Option<u16>;
+    // Return None if the address is not handled by the cartridge
+    fn cpu_read(&mut self, addr: u16) -> Option<u8>;
+    // Return false if the address is not handled by the cartridge
+    fn cpu_write(&mut self, addr: u16, value: u8) -> bool;
+    fn ppu_read(&mut self, addr: u16) -> Option<u8>;
+    fn ppu_write(&mut self, addr: u16, value: u8) -> bool;
+
+    // Mappers with scanline/cycle counters (e.g. MMC3) can assert the CPU IRQ line
+    fn has_irq(&self) -> bool {
+        false
+    }
+
+    fn acknowledge_irq(&mut self) {}
+
+    // Called on every CPU cycle, for mappers with CPU cycle based IRQ
+    // counters (e.g. FME-7, VRC)
+    fn cpu_clock(&mut self) {}
+
+    // Expansion audio (e.g. VRC6), mixed with the APU output by the bus
+    fn expansion_audio(&self) -> Option<&dyn ExpansionAudio> {
+        None
+    }
+
+    // Mappers that switch the nametable mirroring (e.g. MMC1, AxROM) return
+    // it here, otherwise the mirroring from the iNES header applies
+    fn mirroring(&self) -> Option<Mirror> {
+        None
+    }
+
+    // Where CPU and PPU addresses are in the PRG and CHR ROM as currently
+    // mapped, for the code/data log (see `cdl`). None for RAM, registers
+    // and CHR RAM.
+    fn prg_rom_offset(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+
+    fn chr_rom_offset(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+
+    // PRG and CHR ROM sizes, without CHR RAM
+    fn rom_sizes(&self) -> (usize, usize) {
+        (0, 0)
+    }
+
+    // The PRG RAM at $6000-$7FFF, for boards that have it
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    // Bank registers, counters and RAM. ROM is not part of save states.
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    fn load_state(&mut self, _r: &mut StateReader) -> Result<(), String> {
+        Ok(())
+    }
+
+    // The console was power cycled: registers, counters and RAM go back to
+    // their power on values. The reset button doesn't reach the cartridge.
+    fn power_on(&mut self) {}
 }
 
 impl core::fmt::Debug for dyn Mapper {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "{:?}", self)
+        write!(f, "dyn Mapper")
     }
 }
 
-pub fn new(mapper_id: u8, num_prg_banks: u8, num_chr_banks: u8) -> Option<Box<dyn Mapper>> {
+pub fn new(mapper_id: u8, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Option<Box<dyn Mapper>> {
     use super::mapper_0::Mapper0;
+    use super::mapper_1::Mapper1;
+    use super::mapper_11::Mapper11;
+    use super::mapper_185::Mapper185;
+    use super::mapper_21::Mapper21;
+    use super::mapper_24::Mapper24;
+    use super::mapper_66::Mapper66;
+    use super::mapper_7::Mapper7;
+    use super::mapper_87::Mapper87;
     match mapper_id {
-        0 => Some(Box::new(Mapper0::new(num_prg_banks, num_chr_banks))),
+        0 => Some(Box::new(Mapper0::new(prg_rom, chr_rom))),
+        1 => Some(Box::new(Mapper1::new(prg_rom, chr_rom))),
+        7 => Some(Box::new(Mapper7::new(prg_rom, chr_rom))),
+        11 => Some(Box::new(Mapper11::new(prg_rom, chr_rom))),
+        21 | 22 | 23 | 25 => Some(Box::new(Mapper21::new(mapper_id, prg_rom, chr_rom))),
+        24 | 26 => Some(Box::new(Mapper24::new(mapper_id, prg_rom, chr_rom))),
+        66 => Some(Box::new(Mapper66::new(prg_rom, chr_rom))),
+        87 => Some(Box::new(Mapper87::new(prg_rom, chr_rom))),
+        185 => Some(Box::new(Mapper185::new(prg_rom, chr_rom))),
         _ => None,
     }
 }
@@ -1,21 +1,21 @@
+use super::chr_memory::ChrMemory;
+use crate::savestate::{StateReader, StateWriter};
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Mapper0 {
-    num_prg_banks: u8,
-    num_chr_banks: u8,
+    prg_rom: Vec<u8>,
+    chr: ChrMemory,
 }
 
 impl Mapper0 {
-    pub fn new(num_prg_banks: u8, num_chr_banks: u8) -> Mapper0 {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Mapper0 {
         Mapper0 {
-            num_prg_banks,
-            num_chr_banks,
+            prg_rom,
+            chr: ChrMemory::new(chr_rom),
         }
     }
-}
-impl super::mapper::Mapper for Mapper0 {
-    fn cpu_read_mapping(&self, addr: u16) -> Option<u16> {
-        if addr < 0x8000 {
-            return None;
-        }
+
+    fn map_cpu_addr(&self, addr: u16) -> usize {
         // if PRGROM is 16KB
         //     CPU Address Bus          PRG ROM
         //     0x8000 -> 0xBFFF: Map    0x0000 -> 0x3FFF
@@ -23,44 +23,94 @@ impl super::mapper::Mapper for Mapper0 {
         // if PRGROM is 32KB
         //     CPU Address Bus          PRG ROM
         //     0x8000 -> 0xFFFF: Map    0x0000 -> 0x7FFF
-        let mapped_addr = addr
-            & (if self.num_prg_banks > 1 {
-                0x7FFF
-            } else {
-                0x3FFF
-            });
-        return Some(mapped_addr);
+        let mask = if self.prg_rom.len() > 0x4000 {
+            0x7FFF
+        } else {
+            0x3FFF
+        };
+        (addr & mask) as usize
     }
+}
 
-    fn cpu_write_mapping(&self, addr: u16) -> Option<u16> {
+#[cfg_attr(feature = "serde", typetag::serde)]
+impl super::mapper::Mapper for Mapper0 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
         if addr < 0x8000 {
             return None;
         }
-        let mapped_addr = addr
-            & (if self.num_prg_banks > 1 {
-                0x7FFF
-            } else {
-                0x3FFF
-            });
-        return Some(mapped_addr);
+        Some(self.prg_rom[self.map_cpu_addr(addr)])
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr >= 0x8000).then(|| self.map_cpu_addr(addr))
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr <= 0x1FFF)
+            .then_some(addr as usize)
+            .and_then(|offset| self.chr.rom_offset(offset))
+    }
+
+    fn rom_sizes(&self) -> (usize, usize) {
+        (self.prg_rom.len(), self.chr.rom_size())
+    }
+
+    fn cpu_write(&mut self, addr: u16, _value: u8) -> bool {
+        // PRG ROM is read-only, writes are swallowed
+        addr >= 0x8000
     }
 
-    fn ppu_read_mapping(&self, addr: u16) -> Option<u16> {
+    fn ppu_read(&mut self, addr: u16) -> Option<u8> {
         // There is no mapping required for PPU
         // PPU Address Bus          CHR ROM
         // 0x0000 -> 0x1FFF: Map    0x0000 -> 0x1FFF
         if addr <= 0x1FFF {
-            return Some(addr);
+            return Some(self.chr.read(addr as usize));
         }
-        return None;
+        None
     }
 
-    fn ppu_write_mapping(&self, addr: u16) -> Option<u16> {
-        if addr <= 0x1FFF {
-            if self.num_chr_banks == 0 {
-                return Some(addr);
-            }
-        }
-        return None;
+    fn ppu_write(&mut self, addr: u16, value: u8) -> bool {
+        addr <= 0x1FFF && self.chr.write(addr as usize, value)
+    }
+
+    fn power_on(&mut self) {
+        self.chr.power_on();
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        self.chr.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.chr.load_state(r)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::mapper::Mapper;
+    use super::*;
+
+    #[test]
+    fn test_16k_prg_rom_is_mirrored() {
+        let mut prg_rom = vec![0; 0x4000];
+        prg_rom[0x0010] = 0x42;
+        let mut mapper = Mapper0::new(prg_rom, vec![0; 0x2000]);
+        assert_eq!(mapper.cpu_read(0x8010), Some(0x42));
+        assert_eq!(mapper.cpu_read(0xC010), Some(0x42));
+        assert_eq!(mapper.cpu_read(0x6010), None);
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        let mut mapper = Mapper0::new(vec![0; 0x4000], vec![0; 0x2000]);
+        assert!(!mapper.ppu_write(0x0010, 0x42));
+        assert_eq!(mapper.ppu_read(0x0010), Some(0));
+
+        let mut mapper = Mapper0::new(vec![0; 0x4000], vec![]);
+        assert!(mapper.ppu_write(0x0010, 0x42));
+        assert_eq!(mapper.ppu_read(0x0010), Some(0x42));
     }
 }
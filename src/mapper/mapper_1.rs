@@ -0,0 +1,298 @@
+use super::chr_memory::ChrMemory;
+use crate::cartridge::Mirror;
+use crate::savestate::{StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x1000;
+const PRG_RAM_SIZE: usize = 0x2000;
+// SUROM and SXROM have 512KB of PRG ROM, in two halves selected by bit 4
+// of the CHR bank registers
+const PRG_OUTER_BANK_SIZE: usize = 0x40000;
+
+// MMC1 (SxROM boards): 16KB or 32KB PRG ROM banks, 4KB or 8KB CHR banks,
+// switchable mirroring and 8KB of PRG RAM at $6000-$7FFF. The registers
+// are written a bit at a time through a shift register at $8000-$FFFF:
+// five writes shift in bit 0, the fifth one goes to the register picked by
+// address bits 13 and 14. A write with bit 7 set clears the shift register
+// and selects PRG mode 3.
+//
+//   $8000-$9FFF  control
+//     4  bit  0
+//     ---------
+//     CPPMM
+//     |||++- mirroring, 0: one screen lo, 1: one screen hi, 2: vertical,
+//     |||    3: horizontal
+//     |++--- PRG mode, 0 and 1: 32KB at $8000, 2: first bank at $8000 and
+//     |      switchable $C000, 3: switchable $8000 and last bank at $C000
+//     +----- CHR mode, 0: 8KB, 1: two 4KB banks
+//   $A000-$BFFF  CHR bank 0
+//   $C000-$DFFF  CHR bank 1, ignored in 8KB mode
+//   $E000-$FFFF  PRG bank, bit 4 disables the PRG RAM on the MMC1B. It is
+//                ignored, like the MMC1A does, as some games leave it set.
+//
+// Writes on consecutive CPU cycles, from read-modify-write instructions,
+// are not filtered out.
+// Ref: https://wiki.nesdev.org/w/index.php/MMC1
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Mapper1 {
+    prg_rom: Vec<u8>,
+    chr: ChrMemory,
+    prg_ram: Vec<u8>,
+    shift: u8,
+    shift_count: u8,
+    control: u8,
+    chr_banks: [u8; 2],
+    prg_bank: u8,
+}
+
+impl Mapper1 {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Mapper1 {
+        Mapper1 {
+            prg_rom,
+            chr: ChrMemory::new(chr_rom),
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            shift: 0,
+            shift_count: 0,
+            control: 0x0C,
+            chr_banks: [0; 2],
+            prg_bank: 0,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x8000..=0x9FFF => self.control = value,
+            0xA000..=0xBFFF => self.chr_banks[0] = value,
+            0xC000..=0xDFFF => self.chr_banks[1] = value,
+            _ => self.prg_bank = value,
+        }
+    }
+
+    fn map_prg_addr(&self, addr: u16) -> usize {
+        let num_banks = (self.prg_rom.len() / PRG_BANK_SIZE).clamp(1, 16);
+        let last_bank = num_banks - 1;
+        let bank = self.prg_bank as usize & 0x0F;
+        let bank = match ((self.control >> 2) & 0b11, addr) {
+            (0 | 1, _) => (bank & !1) | ((addr as usize >> 14) & 1),
+            (2, 0x8000..=0xBFFF) => 0,
+            (2, _) => bank,
+            (_, 0x8000..=0xBFFF) => bank,
+            (_, _) => last_bank,
+        };
+        let outer = if self.prg_rom.len() > PRG_OUTER_BANK_SIZE {
+            ((self.chr_banks[0] >> 4) & 1) as usize * PRG_OUTER_BANK_SIZE
+        } else {
+            0
+        };
+        let offset = outer + (bank % num_banks) * PRG_BANK_SIZE + (addr as usize & 0x3FFF);
+        offset % self.prg_rom.len()
+    }
+
+    fn map_chr_addr(&self, addr: u16) -> usize {
+        let bank = if self.control & 0x10 == 0 {
+            (self.chr_banks[0] as usize & !1) | (addr as usize / CHR_BANK_SIZE)
+        } else {
+            self.chr_banks[addr as usize / CHR_BANK_SIZE] as usize
+        };
+        let offset = bank * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1));
+        offset % self.chr.size()
+    }
+}
+
+#[cfg_attr(feature = "serde", typetag::serde)]
+impl super::mapper::Mapper for Mapper1 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF => Some(self.prg_ram[addr as usize - 0x6000]),
+            0x8000..=0xFFFF => Some(self.prg_rom[self.map_prg_addr(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) -> bool {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram[addr as usize - 0x6000] = value,
+            0x8000..=0xFFFF if value & 0x80 != 0 => {
+                self.shift = 0;
+                self.shift_count = 0;
+                self.control |= 0x0C;
+            }
+            0x8000..=0xFFFF => {
+                self.shift |= (value & 1) << self.shift_count;
+                self.shift_count += 1;
+                if self.shift_count == 5 {
+                    self.write_register(addr, self.shift);
+                    self.shift = 0;
+                    self.shift_count = 0;
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Option<u8> {
+        if addr <= 0x1FFF {
+            return Some(self.chr.read(self.map_chr_addr(addr)));
+        }
+        None
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) -> bool {
+        addr <= 0x1FFF && self.chr.write(self.map_chr_addr(addr), value)
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr >= 0x8000).then(|| self.map_prg_addr(addr))
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr <= 0x1FFF)
+            .then(|| self.map_chr_addr(addr))
+            .and_then(|offset| self.chr.rom_offset(offset))
+    }
+
+    fn rom_sizes(&self) -> (usize, usize) {
+        (self.prg_rom.len(), self.chr.rom_size())
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn mirroring(&self) -> Option<Mirror> {
+        let mirror = match self.control & 0b11 {
+            0 => Mirror::SingleScreenLo,
+            1 => Mirror::SingleScreenHi,
+            2 => Mirror::Vertical,
+            _ => Mirror::Horizontal,
+        };
+        Some(mirror)
+    }
+
+    fn power_on(&mut self) {
+        let prg_rom = std::mem::take(&mut self.prg_rom);
+        let chr_rom = self.chr.take_rom();
+        *self = Mapper1::new(prg_rom, chr_rom);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_vec(&self.prg_ram);
+        w.write_u8(self.shift);
+        w.write_u8(self.shift_count);
+        w.write_u8(self.control);
+        w.write_bytes(&self.chr_banks);
+        w.write_u8(self.prg_bank);
+        self.chr.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.read_vec_into(&mut self.prg_ram)?;
+        self.shift = r.read_u8()?;
+        self.shift_count = r.read_u8()? % 5;
+        self.control = r.read_u8()?;
+        r.read_bytes(&mut self.chr_banks)?;
+        self.prg_bank = r.read_u8()?;
+        self.chr.load_state(r)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::mapper::Mapper;
+    use super::*;
+
+    // Five writes, bit 0 first
+    fn write_register(mapper: &mut Mapper1, addr: u16, value: u8) {
+        for bit in 0..5 {
+            assert!(mapper.cpu_write(addr, value >> bit));
+        }
+    }
+
+    // 256KB PRG ROM and 128KB CHR ROM, each bank filled with its number
+    fn new_mapper() -> Mapper1 {
+        let prg_rom = (0..16u8)
+            .flat_map(|bank| vec![bank; PRG_BANK_SIZE])
+            .collect();
+        let chr_rom = (0..32u8)
+            .flat_map(|bank| vec![bank; CHR_BANK_SIZE])
+            .collect();
+        Mapper1::new(prg_rom, chr_rom)
+    }
+
+    #[test]
+    fn test_prg_banks() {
+        let mut mapper = new_mapper();
+        // PRG mode 3 at power on, the last bank is fixed at $C000
+        assert_eq!(mapper.cpu_read(0x8000), Some(0));
+        assert_eq!(mapper.cpu_read(0xC000), Some(15));
+        write_register(&mut mapper, 0xE000, 5);
+        assert_eq!(mapper.cpu_read(0x8000), Some(5));
+        assert_eq!(mapper.cpu_read(0xFFFF), Some(15));
+
+        // mode 2, the first bank is fixed at $8000
+        write_register(&mut mapper, 0x8000, 0b01000);
+        assert_eq!(mapper.cpu_read(0x8000), Some(0));
+        assert_eq!(mapper.cpu_read(0xC000), Some(5));
+
+        // 32KB mode drops the low bit
+        write_register(&mut mapper, 0x8000, 0b00000);
+        assert_eq!(mapper.cpu_read(0x8000), Some(4));
+        assert_eq!(mapper.cpu_read(0xC000), Some(5));
+
+        // bit 7 clears the shift register and sets mode 3
+        mapper.cpu_write(0x8000, 1);
+        mapper.cpu_write(0x8000, 0x80);
+        write_register(&mut mapper, 0xE000, 2);
+        assert_eq!(mapper.cpu_read(0x8000), Some(2));
+        assert_eq!(mapper.cpu_read(0xC000), Some(15));
+        assert_eq!(mapper.mirroring(), Some(Mirror::SingleScreenLo));
+    }
+
+    #[test]
+    fn test_chr_banks_and_mirroring() {
+        let mut mapper = new_mapper();
+        // 8KB mode drops the low bit
+        write_register(&mut mapper, 0xA000, 3);
+        assert_eq!(mapper.ppu_read(0x0000), Some(2));
+        assert_eq!(mapper.ppu_read(0x1000), Some(3));
+
+        write_register(&mut mapper, 0x8000, 0b11111);
+        write_register(&mut mapper, 0xC000, 7);
+        assert_eq!(mapper.ppu_read(0x0000), Some(3));
+        assert_eq!(mapper.ppu_read(0x1000), Some(7));
+        assert_eq!(mapper.chr_rom_offset(0x1000), Some(7 * CHR_BANK_SIZE));
+        assert_eq!(mapper.mirroring(), Some(Mirror::Horizontal));
+        write_register(&mut mapper, 0x8000, 0b11110);
+        assert_eq!(mapper.mirroring(), Some(Mirror::Vertical));
+    }
+
+    #[test]
+    fn test_prg_ram_and_chr_ram() {
+        let mut mapper = Mapper1::new(vec![0; 0x8000], vec![]);
+        assert!(mapper.cpu_write(0x6000, 0x42));
+        assert_eq!(mapper.cpu_read(0x6000), Some(0x42));
+        assert!(mapper.ppu_write(0x1000, 0x43));
+        assert_eq!(mapper.ppu_read(0x1000), Some(0x43));
+        assert_eq!(mapper.rom_sizes(), (0x8000, 0));
+
+        mapper.power_on();
+        assert_eq!(mapper.cpu_read(0x6000), Some(0));
+        assert_eq!(mapper.ppu_read(0x1000), Some(0));
+    }
+
+    #[test]
+    fn test_512k_prg_rom() {
+        let prg_rom = (0..32u8)
+            .flat_map(|bank| vec![bank; PRG_BANK_SIZE])
+            .collect();
+        let mut mapper = Mapper1::new(prg_rom, vec![]);
+        // the last bank of the first half
+        assert_eq!(mapper.cpu_read(0xC000), Some(15));
+        write_register(&mut mapper, 0xA000, 0x10);
+        write_register(&mut mapper, 0xE000, 1);
+        assert_eq!(mapper.cpu_read(0x8000), Some(17));
+        assert_eq!(mapper.cpu_read(0xC000), Some(31));
+    }
+}
@@ -0,0 +1,117 @@
+use super::chr_memory::ChrMemory;
+use crate::savestate::{StateReader, StateWriter};
+
+// CNROM boards with copy protection: the CHR ROM is only enabled by the
+// right values written to $8000-$FFFF, otherwise the PPU reads open bus.
+// Games check this at boot and refuse to run on the wrong board. Without a
+// submapper we go by the common rule, any value with a non-zero low
+// nibble except $13 enables it.
+// Ref: https://wiki.nesdev.org/w/index.php/INES_Mapper_185
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Mapper185 {
+    prg_rom: Vec<u8>,
+    chr: ChrMemory,
+    chr_enabled: bool,
+}
+
+impl Mapper185 {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Mapper185 {
+        Mapper185 {
+            prg_rom,
+            chr: ChrMemory::new(chr_rom),
+            chr_enabled: true,
+        }
+    }
+}
+
+#[cfg_attr(feature = "serde", typetag::serde)]
+impl super::mapper::Mapper for Mapper185 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        if addr < 0x8000 {
+            return None;
+        }
+        Some(self.prg_rom[(addr & 0x7FFF) as usize % self.prg_rom.len()])
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) -> bool {
+        if addr < 0x8000 {
+            return false;
+        }
+        self.chr_enabled = value & 0x0F != 0 && value != 0x13;
+        true
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Option<u8> {
+        if addr > 0x1FFF {
+            return None;
+        }
+        if !self.chr_enabled {
+            return Some(0xFF);
+        }
+        Some(self.chr.read(addr as usize % self.chr.size()))
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) -> bool {
+        addr <= 0x1FFF && self.chr_enabled && self.chr.write(addr as usize, value)
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr >= 0x8000).then(|| (addr & 0x7FFF) as usize % self.prg_rom.len())
+    }
+
+    // nothing is read while the CHR ROM is disabled
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr <= 0x1FFF && self.chr_enabled)
+            .then(|| addr as usize % self.chr.size())
+            .and_then(|offset| self.chr.rom_offset(offset))
+    }
+
+    fn rom_sizes(&self) -> (usize, usize) {
+        (self.prg_rom.len(), self.chr.rom_size())
+    }
+
+    fn power_on(&mut self) {
+        self.chr_enabled = true;
+        self.chr.power_on();
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.chr_enabled);
+        self.chr.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.chr_enabled = r.read_bool()?;
+        self.chr.load_state(r)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::mapper::Mapper;
+    use super::*;
+
+    #[test]
+    fn test_chr_enable() {
+        let mut mapper = Mapper185::new(vec![0; 0x8000], vec![0x42; 0x2000]);
+        assert_eq!(mapper.ppu_read(0x0000), Some(0x42));
+        mapper.cpu_write(0x8000, 0x00);
+        assert_eq!(mapper.ppu_read(0x0000), Some(0xFF));
+        mapper.cpu_write(0x8000, 0x13);
+        assert_eq!(mapper.ppu_read(0x0000), Some(0xFF));
+        mapper.cpu_write(0x8000, 0x21);
+        assert_eq!(mapper.ppu_read(0x0000), Some(0x42));
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        let mut mapper = Mapper185::new(vec![0; 0x8000], vec![]);
+        assert!(mapper.ppu_write(0x0010, 0x42));
+        assert_eq!(mapper.ppu_read(0x0010), Some(0x42));
+        assert_eq!(mapper.rom_sizes(), (0x8000, 0));
+        mapper.cpu_write(0x8000, 0x00);
+        assert!(!mapper.ppu_write(0x0010, 0x24));
+        assert_eq!(mapper.ppu_read(0x0010), Some(0xFF));
+    }
+}
@@ -0,0 +1,304 @@
+use super::chr_memory::ChrMemory;
+use super::vrc_irq::VrcIrq;
+use crate::cartridge::Mirror;
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+const PRG_RAM_SIZE: usize = 0x2000;
+
+// Konami VRC2 and VRC4, iNES mappers 21, 22, 23 and 25: two switchable 8KB
+// PRG ROM banks, eight 1KB CHR banks and, on the VRC4, an IRQ counter.
+//
+// The boards wire different CPU address lines to the chip's register
+// select pins. Without a submapper we can't tell which, so each mapper
+// number listens to all the wirings it's used with:
+//
+//   21  VRC4a (A1, A2), VRC4c (A6, A7)
+//   22  VRC2a (A1, A0), which also drops the low bit of CHR banks
+//   23  VRC2b, VRC4f (A0, A1), VRC4e (A2, A3)
+//   25  VRC2c, VRC4b (A1, A0), VRC4d (A3, A2)
+//
+// Registers, with the two pins giving the low address digit
+//   $8000-$8003  PRG bank at $8000, or $C000 in swapped mode
+//   $9000        mirroring
+//   $9002        bit 1: PRG swap mode (VRC4)
+//   $A000-$A003  PRG bank at $A000
+//   $B000-$E003  CHR banks, low and high nibbles of two banks per $x000
+//   $F000-$F003  IRQ latch low and high nibbles, control and acknowledge
+// Ref: https://wiki.nesdev.org/w/index.php/VRC2_and_VRC4
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Mapper21 {
+    mapper_id: u8,
+    prg_rom: Vec<u8>,
+    chr: ChrMemory,
+    prg_ram: Vec<u8>,
+    prg_banks: [u8; 2],
+    prg_swap: bool,
+    chr_banks: [u16; 8],
+    mirror: Mirror,
+    irq: VrcIrq,
+}
+
+impl Mapper21 {
+    pub fn new(mapper_id: u8, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Mapper21 {
+        Mapper21 {
+            mapper_id,
+            prg_rom,
+            chr: ChrMemory::new(chr_rom),
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            prg_banks: [0; 2],
+            prg_swap: false,
+            chr_banks: [0; 8],
+            mirror: Mirror::Vertical,
+            irq: VrcIrq::new(),
+        }
+    }
+
+    fn is_vrc2(&self) -> bool {
+        self.mapper_id == 22
+    }
+
+    // The register 0 to 3 that `addr` selects within its $x000 group
+    fn register(&self, addr: u16) -> u16 {
+        let line = |n: u16| (addr >> n) & 1;
+        let (low, high) = match self.mapper_id {
+            21 => (line(1) | line(6), line(2) | line(7)),
+            22 => (line(1), line(0)),
+            23 => (line(0) | line(2), line(1) | line(3)),
+            _ => (line(1) | line(3), line(0) | line(2)),
+        };
+        (high << 1) | low
+    }
+
+    fn map_prg_addr(&self, addr: u16) -> usize {
+        let num_banks = self.prg_rom.len() / PRG_BANK_SIZE;
+        let second_last = num_banks.saturating_sub(2) as u8;
+        let bank = match (addr - 0x8000) / PRG_BANK_SIZE as u16 {
+            0 if self.prg_swap => second_last,
+            0 => self.prg_banks[0],
+            1 => self.prg_banks[1],
+            2 if self.prg_swap => self.prg_banks[0],
+            2 => second_last,
+            _ => (num_banks - 1) as u8,
+        };
+        let bank = bank as usize % num_banks;
+        bank * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1))
+    }
+
+    fn map_chr_addr(&self, addr: u16) -> usize {
+        let mut bank = self.chr_banks[addr as usize / CHR_BANK_SIZE] as usize;
+        if self.is_vrc2() {
+            bank >>= 1;
+        }
+        let num_banks = self.chr.size() / CHR_BANK_SIZE;
+        (bank % num_banks) * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1))
+    }
+
+    fn write_chr_bank(&mut self, addr: u16, reg: u16, value: u8) {
+        // $B000-$B003 are banks 0 and 1, ..., $E000-$E003 banks 6 and 7
+        let bank = ((addr - 0xB000) >> 12) as usize * 2 + (reg >> 1) as usize;
+        let value = value as u16;
+        self.chr_banks[bank] = if reg & 1 == 0 {
+            (self.chr_banks[bank] & 0x1F0) | (value & 0x0F)
+        } else {
+            (self.chr_banks[bank] & 0x00F) | ((value & 0x1F) << 4)
+        };
+    }
+}
+
+#[cfg_attr(feature = "serde", typetag::serde)]
+impl super::mapper::Mapper for Mapper21 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF => Some(self.prg_ram[addr as usize - 0x6000]),
+            0x8000..=0xFFFF => Some(self.prg_rom[self.map_prg_addr(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) -> bool {
+        if addr < 0x6000 {
+            return false;
+        }
+        if addr < 0x8000 {
+            self.prg_ram[addr as usize - 0x6000] = value;
+            return true;
+        }
+        let reg = self.register(addr);
+        match (addr & 0xF000, reg) {
+            (0x8000, _) => self.prg_banks[0] = value & 0x1F,
+            (0x9000, 0) if self.is_vrc2() => {
+                self.mirror = if value & 1 == 0 {
+                    Mirror::Vertical
+                } else {
+                    Mirror::Horizontal
+                }
+            }
+            (0x9000, 0) => {
+                self.mirror = match value & 0b11 {
+                    0 => Mirror::Vertical,
+                    1 => Mirror::Horizontal,
+                    2 => Mirror::SingleScreenLo,
+                    _ => Mirror::SingleScreenHi,
+                }
+            }
+            (0x9000, 2) if !self.is_vrc2() => self.prg_swap = value & 0b10 != 0,
+            (0x9000, _) => {}
+            (0xA000, _) => self.prg_banks[1] = value & 0x1F,
+            (0xB000..=0xE000, _) => self.write_chr_bank(addr, reg, value),
+            (_, 0) => self.irq.latch = (self.irq.latch & 0xF0) | (value & 0x0F),
+            (_, 1) => self.irq.latch = (self.irq.latch & 0x0F) | (value << 4),
+            (_, 2) => self.irq.write_control(value),
+            (_, _) => self.irq.acknowledge(),
+        }
+        true
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Option<u8> {
+        if addr <= 0x1FFF {
+            return Some(self.chr.read(self.map_chr_addr(addr)));
+        }
+        None
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) -> bool {
+        addr <= 0x1FFF && self.chr.write(self.map_chr_addr(addr), value)
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr >= 0x8000).then(|| self.map_prg_addr(addr))
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr <= 0x1FFF)
+            .then(|| self.map_chr_addr(addr))
+            .and_then(|offset| self.chr.rom_offset(offset))
+    }
+
+    fn rom_sizes(&self) -> (usize, usize) {
+        (self.prg_rom.len(), self.chr.rom_size())
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn mirroring(&self) -> Option<Mirror> {
+        Some(self.mirror)
+    }
+
+    fn has_irq(&self) -> bool {
+        self.irq.has_irq()
+    }
+
+    fn acknowledge_irq(&mut self) {
+        self.irq.clear_irq();
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.cpu_clock();
+    }
+
+    fn power_on(&mut self) {
+        let mapper_id = self.mapper_id;
+        let prg_rom = std::mem::take(&mut self.prg_rom);
+        let chr_rom = self.chr.take_rom();
+        *self = Mapper21::new(mapper_id, prg_rom, chr_rom);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_vec(&self.prg_ram);
+        w.write_bytes(&self.prg_banks);
+        w.write_bool(self.prg_swap);
+        for bank in self.chr_banks.iter() {
+            w.write_u16(*bank);
+        }
+        self.mirror.save_state(w);
+        self.irq.save_state(w);
+        self.chr.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.read_vec_into(&mut self.prg_ram)?;
+        r.read_bytes(&mut self.prg_banks)?;
+        self.prg_swap = r.read_bool()?;
+        for bank in self.chr_banks.iter_mut() {
+            *bank = r.read_u16()?;
+        }
+        self.mirror.load_state(r)?;
+        self.irq.load_state(r)?;
+        self.chr.load_state(r)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::mapper::Mapper;
+    use super::*;
+
+    // 128KB PRG ROM and 128KB CHR ROM, each bank filled with its number
+    fn new_mapper(mapper_id: u8) -> Mapper21 {
+        let prg_rom = (0..16u8)
+            .flat_map(|bank| vec![bank; PRG_BANK_SIZE])
+            .collect();
+        let chr_rom = (0..128u8)
+            .flat_map(|bank| vec![bank; CHR_BANK_SIZE])
+            .collect();
+        Mapper21::new(mapper_id, prg_rom, chr_rom)
+    }
+
+    #[test]
+    fn test_prg_banks() {
+        let mut mapper = new_mapper(21);
+        mapper.cpu_write(0x8000, 3);
+        mapper.cpu_write(0xA000, 5);
+        assert_eq!(mapper.cpu_read(0x8000), Some(3));
+        assert_eq!(mapper.cpu_read(0xA000), Some(5));
+        assert_eq!(mapper.cpu_read(0xC000), Some(14));
+        assert_eq!(mapper.cpu_read(0xE000), Some(15));
+
+        // swap mode, $9004 is $9002 on VRC4a
+        mapper.cpu_write(0x9004, 0b10);
+        assert_eq!(mapper.cpu_read(0x8000), Some(14));
+        assert_eq!(mapper.cpu_read(0xC000), Some(3));
+    }
+
+    #[test]
+    fn test_chr_banks_and_wiring() {
+        // VRC4c uses A6 and A7: $B040 is the high nibble of bank 0
+        let mut mapper = new_mapper(21);
+        mapper.cpu_write(0xB000, 0x05);
+        mapper.cpu_write(0xB040, 0x02);
+        assert_eq!(mapper.ppu_read(0x0000), Some(0x25));
+
+        // VRC4d uses A3 and A2 swapped: $C004 is the low nibble of bank 3
+        let mut mapper = new_mapper(25);
+        mapper.cpu_write(0xC004, 0x07);
+        assert_eq!(mapper.ppu_read(0x0C00), Some(0x07));
+
+        // VRC2a drops the low bit
+        let mut mapper = new_mapper(22);
+        mapper.cpu_write(0xB000, 0x07);
+        assert_eq!(mapper.ppu_read(0x0000), Some(0x03));
+
+        mapper.cpu_write(0x9000, 1);
+        assert_eq!(mapper.mirroring(), Some(Mirror::Horizontal));
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut mapper = new_mapper(23);
+        mapper.cpu_write(0xF000, 0x0E);
+        mapper.cpu_write(0xF001, 0x0F);
+        // cycle mode
+        mapper.cpu_write(0xF002, 0b110);
+        mapper.cpu_clock();
+        assert!(!mapper.has_irq());
+        mapper.cpu_clock();
+        assert!(mapper.has_irq());
+        mapper.cpu_write(0xF003, 0);
+        assert!(!mapper.has_irq());
+    }
+}
@@ -0,0 +1,556 @@
+use super::chr_memory::ChrMemory;
+use super::vrc_irq::VrcIrq;
+use crate::apu::mixer::ExpansionAudio;
+use crate::cartridge::Mirror;
+use crate::savestate::{StateReader, StateWriter};
+
+const PRG_RAM_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+// A full volume VRC6 pulse is about as loud as a full volume APU pulse
+const VOLUME: f32 = 0.15 / 15.0;
+
+// Konami VRC6, iNES mappers 24 (VRC6a) and 26 (VRC6b, A0 and A1 swapped):
+// a 16KB and an 8KB switchable PRG ROM bank, eight 1KB CHR banks, the VRC
+// IRQ counter and three extra sound channels, two pulses and a sawtooth.
+//
+//   $8000-$8003  16KB PRG bank at $8000
+//   $9000-$9002  pulse 1, $9003 frequency scaling
+//   $A000-$A002  pulse 2
+//   $B000-$B002  sawtooth, $B003 PPU banking mode and mirroring
+//   $C000-$C003  8KB PRG bank at $C000, $E000 is the last bank
+//   $D000-$E003  CHR banks 0 to 7
+//   $F000-$F002  IRQ latch, control and acknowledge
+// Ref: https://wiki.nesdev.org/w/index.php/VRC6
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Mapper24 {
+    mapper_id: u8,
+    prg_rom: Vec<u8>,
+    chr: ChrMemory,
+    prg_ram: Vec<u8>,
+    prg_16k_bank: u8,
+    prg_8k_bank: u8,
+    chr_regs: [u8; 8],
+    // $B003
+    banking_mode: u8,
+    irq: VrcIrq,
+    pulse_1: Vrc6Pulse,
+    pulse_2: Vrc6Pulse,
+    sawtooth: Vrc6Sawtooth,
+    // $9003: bit 0 halts all channels, bits 1 and 2 make them 16 or 256
+    // times faster
+    audio_halt: bool,
+    frequency_shift: u8,
+}
+
+impl Mapper24 {
+    pub fn new(mapper_id: u8, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Mapper24 {
+        Mapper24 {
+            mapper_id,
+            prg_rom,
+            chr: ChrMemory::new(chr_rom),
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            prg_16k_bank: 0,
+            prg_8k_bank: 0,
+            chr_regs: [0; 8],
+            banking_mode: 0,
+            irq: VrcIrq::new(),
+            pulse_1: Vrc6Pulse::new(),
+            pulse_2: Vrc6Pulse::new(),
+            sawtooth: Vrc6Sawtooth::new(),
+            audio_halt: false,
+            frequency_shift: 0,
+        }
+    }
+
+    fn map_prg_addr(&self, addr: u16) -> usize {
+        let offset = match addr {
+            0x8000..=0xBFFF => self.prg_16k_bank as usize * 0x4000 + (addr as usize & 0x3FFF),
+            0xC000..=0xDFFF => self.prg_8k_bank as usize * 0x2000 + (addr as usize & 0x1FFF),
+            _ => self.prg_rom.len() - 0x2000 + (addr as usize & 0x1FFF),
+        };
+        offset % self.prg_rom.len()
+    }
+
+    // The 1KB CHR bank at PPU $0000-$1FFF. Modes 1 to 3 use 2KB banks for
+    // part of the pattern tables, whose low bit can come from PPU A10.
+    fn chr_bank(&self, slot: usize) -> usize {
+        let (mask, or) = if self.banking_mode & 0x20 != 0 {
+            (0xFE, 1)
+        } else {
+            (0xFF, 0)
+        };
+        let two_k = |reg: usize| {
+            let bank = self.chr_regs[reg] & mask;
+            if slot & 1 == 0 {
+                bank
+            } else {
+                bank | or
+            }
+        };
+        let bank = match (self.banking_mode & 0b11, slot) {
+            (0, _) => self.chr_regs[slot],
+            (1, _) => two_k(slot / 2),
+            (_, 0..=3) => self.chr_regs[slot],
+            (_, _) => two_k(slot / 2 + 2),
+        };
+        bank as usize
+    }
+
+    fn map_chr_addr(&self, addr: u16) -> usize {
+        let bank = self.chr_bank(addr as usize / CHR_BANK_SIZE);
+        let num_banks = self.chr.size() / CHR_BANK_SIZE;
+        (bank % num_banks) * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1))
+    }
+}
+
+#[cfg_attr(feature = "serde", typetag::serde)]
+impl super::mapper::Mapper for Mapper24 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF => Some(self.prg_ram[addr as usize - 0x6000]),
+            0x8000..=0xFFFF => Some(self.prg_rom[self.map_prg_addr(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) -> bool {
+        if addr < 0x6000 {
+            return false;
+        }
+        if addr < 0x8000 {
+            self.prg_ram[addr as usize - 0x6000] = value;
+            return true;
+        }
+        let reg = if self.mapper_id == 26 {
+            ((addr & 1) << 1) | ((addr >> 1) & 1)
+        } else {
+            addr & 0b11
+        };
+        match (addr & 0xF000, reg) {
+            (0x8000, _) => self.prg_16k_bank = value & 0x0F,
+            (0x9000, 3) => {
+                self.audio_halt = value & 1 != 0;
+                self.frequency_shift = if value & 0b100 != 0 {
+                    8
+                } else if value & 0b010 != 0 {
+                    4
+                } else {
+                    0
+                };
+            }
+            (0x9000, _) => self.pulse_1.write(reg, value),
+            (0xA000, 3) => {}
+            (0xA000, _) => self.pulse_2.write(reg, value),
+            (0xB000, 3) => self.banking_mode = value,
+            (0xB000, _) => self.sawtooth.write(reg, value),
+            (0xC000, _) => self.prg_8k_bank = value & 0x1F,
+            (0xD000, _) => self.chr_regs[reg as usize] = value,
+            (0xE000, _) => self.chr_regs[4 + reg as usize] = value,
+            (_, 0) => self.irq.latch = value,
+            (_, 1) => self.irq.write_control(value),
+            (_, 2) => self.irq.acknowledge(),
+            (_, _) => {}
+        }
+        true
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Option<u8> {
+        if addr <= 0x1FFF {
+            return Some(self.chr.read(self.map_chr_addr(addr)));
+        }
+        None
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) -> bool {
+        addr <= 0x1FFF && self.chr.write(self.map_chr_addr(addr), value)
+    }
+
+    // Nametables from CHR ROM (bit 4) aren't supported
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr >= 0x8000).then(|| self.map_prg_addr(addr))
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr <= 0x1FFF)
+            .then(|| self.map_chr_addr(addr))
+            .and_then(|offset| self.chr.rom_offset(offset))
+    }
+
+    fn rom_sizes(&self) -> (usize, usize) {
+        (self.prg_rom.len(), self.chr.rom_size())
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn mirroring(&self) -> Option<Mirror> {
+        let mirror = match self.banking_mode & 0x2F {
+            0x20 | 0x27 => Mirror::Vertical,
+            0x23 | 0x24 => Mirror::Horizontal,
+            0x28 | 0x2F => Mirror::SingleScreenLo,
+            0x2B | 0x2C => Mirror::SingleScreenHi,
+            _ => match self.banking_mode & 0x0C {
+                0x00 => Mirror::Vertical,
+                0x04 => Mirror::Horizontal,
+                0x08 => Mirror::SingleScreenLo,
+                _ => Mirror::SingleScreenHi,
+            },
+        };
+        Some(mirror)
+    }
+
+    fn has_irq(&self) -> bool {
+        self.irq.has_irq()
+    }
+
+    fn acknowledge_irq(&mut self) {
+        self.irq.clear_irq();
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.cpu_clock();
+        if !self.audio_halt {
+            self.pulse_1.clock(self.frequency_shift);
+            self.pulse_2.clock(self.frequency_shift);
+            self.sawtooth.clock(self.frequency_shift);
+        }
+    }
+
+    fn expansion_audio(&self) -> Option<&dyn ExpansionAudio> {
+        Some(self)
+    }
+
+    fn power_on(&mut self) {
+        let mapper_id = self.mapper_id;
+        let prg_rom = std::mem::take(&mut self.prg_rom);
+        let chr_rom = self.chr.take_rom();
+        *self = Mapper24::new(mapper_id, prg_rom, chr_rom);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_vec(&self.prg_ram);
+        w.write_u8(self.prg_16k_bank);
+        w.write_u8(self.prg_8k_bank);
+        w.write_bytes(&self.chr_regs);
+        w.write_u8(self.banking_mode);
+        self.irq.save_state(w);
+        self.pulse_1.save_state(w);
+        self.pulse_2.save_state(w);
+        self.sawtooth.save_state(w);
+        w.write_bool(self.audio_halt);
+        w.write_u8(self.frequency_shift);
+        self.chr.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.read_vec_into(&mut self.prg_ram)?;
+        self.prg_16k_bank = r.read_u8()?;
+        self.prg_8k_bank = r.read_u8()?;
+        r.read_bytes(&mut self.chr_regs)?;
+        self.banking_mode = r.read_u8()?;
+        self.irq.load_state(r)?;
+        self.pulse_1.load_state(r)?;
+        self.pulse_2.load_state(r)?;
+        self.sawtooth.load_state(r)?;
+        self.audio_halt = r.read_bool()?;
+        self.frequency_shift = r.read_u8()?;
+        self.chr.load_state(r)?;
+        Ok(())
+    }
+}
+
+impl ExpansionAudio for Mapper24 {
+    fn channels(&self) -> &'static [&'static str] {
+        &["VRC6 pulse 1", "VRC6 pulse 2", "VRC6 sawtooth"]
+    }
+
+    fn sample(&self, channel: usize) -> f32 {
+        let level = match channel {
+            0 => self.pulse_1.output(),
+            1 => self.pulse_2.output(),
+            2 => self.sawtooth.output(),
+            _ => 0,
+        };
+        level as f32 * VOLUME
+    }
+}
+
+// Timer shared by the channels: a 12 bit period, counted down on every
+// CPU cycle
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Vrc6Timer {
+    period: u16,
+    counter: u16,
+    enabled: bool,
+}
+
+impl Vrc6Timer {
+    fn new() -> Self {
+        Vrc6Timer {
+            period: 0,
+            counter: 0,
+            enabled: false,
+        }
+    }
+
+    // $x001 frequency low 8 bits, $x002 E... FFFF enable and high 4 bits
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            1 => self.period = (self.period & 0x0F00) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.enabled = value & 0x80 != 0;
+            }
+        }
+    }
+
+    // True when the channel steps
+    fn clock(&mut self, frequency_shift: u8) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        if self.counter == 0 {
+            self.counter = self.period >> frequency_shift;
+            true
+        } else {
+            self.counter -= 1;
+            false
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.period);
+        w.write_u16(self.counter);
+        w.write_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.period = r.read_u16()?;
+        self.counter = r.read_u16()?;
+        self.enabled = r.read_bool()?;
+        Ok(())
+    }
+}
+
+// $x000: MDDD VVVV, M ignores the duty and outputs the volume constantly.
+// The duty cycle is (D + 1) / 16.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Vrc6Pulse {
+    timer: Vrc6Timer,
+    constant: bool,
+    duty: u8,
+    volume: u8,
+    // counts down from 15
+    step: u8,
+}
+
+impl Vrc6Pulse {
+    fn new() -> Self {
+        Vrc6Pulse {
+            timer: Vrc6Timer::new(),
+            constant: false,
+            duty: 0,
+            volume: 0,
+            step: 15,
+        }
+    }
+
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.constant = value & 0x80 != 0;
+                self.duty = (value >> 4) & 0b111;
+                self.volume = value & 0x0F;
+            }
+            _ => {
+                self.timer.write(reg, value);
+                // disabling restarts the duty cycle
+                if !self.timer.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, frequency_shift: u8) {
+        if self.timer.clock(frequency_shift) {
+            self.step = self.step.checked_sub(1).unwrap_or(15);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.timer.enabled && (self.constant || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        self.timer.save_state(w);
+        w.write_bool(self.constant);
+        w.write_u8(self.duty);
+        w.write_u8(self.volume);
+        w.write_u8(self.step);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.timer.load_state(r)?;
+        self.constant = r.read_bool()?;
+        self.duty = r.read_u8()?;
+        self.volume = r.read_u8()?;
+        self.step = r.read_u8()?;
+        Ok(())
+    }
+}
+
+// $B000: ..AA AAAA, the rate added to the accumulator every other step.
+// After 14 steps (7 additions) the accumulator goes back to 0, its high 5
+// bits are the output.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Vrc6Sawtooth {
+    timer: Vrc6Timer,
+    rate: u8,
+    accumulator: u8,
+    step: u8,
+}
+
+impl Vrc6Sawtooth {
+    fn new() -> Self {
+        Vrc6Sawtooth {
+            timer: Vrc6Timer::new(),
+            rate: 0,
+            accumulator: 0,
+            step: 0,
+        }
+    }
+
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => self.rate = value & 0x3F,
+            _ => {
+                self.timer.write(reg, value);
+                if !self.timer.enabled {
+                    self.accumulator = 0;
+                    self.step = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, frequency_shift: u8) {
+        if !self.timer.clock(frequency_shift) {
+            return;
+        }
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step & 1 == 0 {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        self.timer.save_state(w);
+        w.write_u8(self.rate);
+        w.write_u8(self.accumulator);
+        w.write_u8(self.step);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.timer.load_state(r)?;
+        self.rate = r.read_u8()?;
+        self.accumulator = r.read_u8()?;
+        self.step = r.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::mapper::Mapper;
+    use super::*;
+
+    fn new_mapper(mapper_id: u8) -> Mapper24 {
+        // 128KB PRG ROM and 64KB CHR ROM, each 8KB/1KB bank filled with its
+        // number
+        let prg_rom = (0..16u8).flat_map(|bank| vec![bank; 0x2000]).collect();
+        let chr_rom = (0..64u8)
+            .flat_map(|bank| vec![bank; CHR_BANK_SIZE])
+            .collect();
+        Mapper24::new(mapper_id, prg_rom, chr_rom)
+    }
+
+    fn audio_output(mapper: &Mapper24) -> f32 {
+        (0..mapper.channels().len()).map(|c| mapper.sample(c)).sum()
+    }
+
+    #[test]
+    fn test_banks() {
+        let mut mapper = new_mapper(24);
+        mapper.cpu_write(0x8000, 2);
+        mapper.cpu_write(0xC000, 7);
+        mapper.cpu_write(0xD003, 9);
+        assert_eq!(mapper.cpu_read(0x8000), Some(4));
+        assert_eq!(mapper.cpu_read(0xA000), Some(5));
+        assert_eq!(mapper.cpu_read(0xC000), Some(7));
+        assert_eq!(mapper.cpu_read(0xE000), Some(15));
+        assert_eq!(mapper.ppu_read(0x0C00), Some(9));
+
+        mapper.cpu_write(0xB003, 0x24);
+        assert_eq!(mapper.mirroring(), Some(Mirror::Horizontal));
+
+        // VRC6b swaps A0 and A1: $D001 is register 2
+        let mut mapper = new_mapper(26);
+        mapper.cpu_write(0xD001, 9);
+        assert_eq!(mapper.ppu_read(0x0800), Some(9));
+    }
+
+    #[test]
+    fn test_audio() {
+        let mut mapper = new_mapper(24);
+        assert_eq!(audio_output(&mapper), 0.0);
+        assert!(mapper.expansion_audio().is_some());
+
+        // pulse 1 at volume 15, 50% duty, period 1
+        mapper.cpu_write(0x9000, 0x7F);
+        mapper.cpu_write(0x9001, 1);
+        mapper.cpu_write(0x9002, 0x80);
+        let mut levels = vec![];
+        for _ in 0..32 {
+            mapper.cpu_clock();
+            levels.push(mapper.pulse_1.output());
+        }
+        // high for 8 of every 16 steps, a step every 2 cycles
+        assert_eq!(levels.iter().filter(|&&level| level == 15).count(), 16);
+
+        // halted, the output stays
+        mapper.cpu_write(0x9003, 1);
+        let output = audio_output(&mapper);
+        for _ in 0..32 {
+            mapper.cpu_clock();
+            assert_eq!(audio_output(&mapper), output);
+        }
+        mapper.cpu_write(0x9003, 0);
+        mapper.cpu_write(0x9002, 0);
+        assert_eq!(audio_output(&mapper), 0.0);
+
+        // sawtooth with rate 8 goes up by 8 every 2 steps, to 48
+        mapper.cpu_write(0xB000, 8);
+        mapper.cpu_write(0xB001, 0);
+        mapper.cpu_write(0xB002, 0x80);
+        let mut levels = vec![];
+        for _ in 0..14 {
+            mapper.cpu_clock();
+            levels.push(mapper.sawtooth.output());
+        }
+        assert_eq!(levels, [0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 0]);
+    }
+}
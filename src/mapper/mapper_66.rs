@@ -0,0 +1,140 @@
+use super::chr_memory::ChrMemory;
+use crate::savestate::{StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+const CHR_BANK_SIZE: usize = 0x2000;
+
+// GxROM (GNROM, MHROM): a write to $8000-$FFFF latches a 32KB PRG ROM bank
+// in bits 4-5 and an 8KB CHR ROM bank in bits 0-1. The board has bus
+// conflicts, the ROM drives the bus too and zero bits win.
+// Ref: https://wiki.nesdev.org/w/index.php/GxROM
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Mapper66 {
+    prg_rom: Vec<u8>,
+    chr: ChrMemory,
+    prg_bank: u8,
+    chr_bank: u8,
+}
+
+impl Mapper66 {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Mapper66 {
+        Mapper66 {
+            prg_rom,
+            chr: ChrMemory::new(chr_rom),
+            prg_bank: 0,
+            chr_bank: 0,
+        }
+    }
+
+    fn map_cpu_addr(&self, addr: u16) -> usize {
+        let offset = self.prg_bank as usize * PRG_BANK_SIZE + (addr & 0x7FFF) as usize;
+        offset % self.prg_rom.len()
+    }
+
+    fn map_ppu_addr(&self, addr: u16) -> usize {
+        let offset = self.chr_bank as usize * CHR_BANK_SIZE + addr as usize;
+        offset % self.chr.size()
+    }
+}
+
+#[cfg_attr(feature = "serde", typetag::serde)]
+impl super::mapper::Mapper for Mapper66 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        if addr < 0x8000 {
+            return None;
+        }
+        Some(self.prg_rom[self.map_cpu_addr(addr)])
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) -> bool {
+        if addr < 0x8000 {
+            return false;
+        }
+        let value = value & self.prg_rom[self.map_cpu_addr(addr)];
+        self.prg_bank = (value >> 4) & 0b11;
+        self.chr_bank = value & 0b11;
+        true
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Option<u8> {
+        if addr > 0x1FFF {
+            return None;
+        }
+        Some(self.chr.read(self.map_ppu_addr(addr)))
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) -> bool {
+        addr <= 0x1FFF && self.chr.write(self.map_ppu_addr(addr), value)
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr >= 0x8000).then(|| self.map_cpu_addr(addr))
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr <= 0x1FFF)
+            .then(|| self.map_ppu_addr(addr))
+            .and_then(|offset| self.chr.rom_offset(offset))
+    }
+
+    fn rom_sizes(&self) -> (usize, usize) {
+        (self.prg_rom.len(), self.chr.rom_size())
+    }
+
+    fn power_on(&mut self) {
+        self.prg_bank = 0;
+        self.chr_bank = 0;
+        self.chr.power_on();
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.prg_bank);
+        w.write_u8(self.chr_bank);
+        self.chr.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.prg_bank = r.read_u8()?;
+        self.chr_bank = r.read_u8()?;
+        self.chr.load_state(r)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::mapper::Mapper;
+    use super::*;
+
+    #[test]
+    fn test_bank_select() {
+        let mut prg_rom: Vec<u8> = (0..4u8)
+            .flat_map(|bank| vec![bank; PRG_BANK_SIZE])
+            .collect();
+        prg_rom[0x7FFF] = 0xFF;
+        let chr_rom = (0..4u8)
+            .flat_map(|bank| vec![bank; CHR_BANK_SIZE])
+            .collect();
+        let mut mapper = Mapper66::new(prg_rom, chr_rom);
+        assert!(mapper.cpu_write(0xFFFF, 0x21));
+        assert_eq!(mapper.cpu_read(0x8000), Some(2));
+        assert_eq!(mapper.ppu_read(0x0000), Some(1));
+
+        // bank 2 has $02 everywhere, only bit 1 of the write gets through
+        mapper.cpu_write(0x8000, 0x33);
+        assert_eq!(mapper.cpu_read(0x8000), Some(0));
+        assert_eq!(mapper.ppu_read(0x0000), Some(2));
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        let mut mapper = Mapper66::new(vec![0xFF; PRG_BANK_SIZE], vec![]);
+        assert!(mapper.ppu_write(0x0010, 0x42));
+        assert_eq!(mapper.ppu_read(0x0010), Some(0x42));
+        // 8KB of CHR RAM is a single bank
+        mapper.cpu_write(0x8000, 0x01);
+        assert_eq!(mapper.ppu_read(0x0010), Some(0x42));
+        assert_eq!(mapper.chr_rom_offset(0x0010), None);
+        assert_eq!(mapper.rom_sizes(), (PRG_BANK_SIZE, 0));
+    }
+}
@@ -0,0 +1,157 @@
+use super::chr_memory::ChrMemory;
+use crate::cartridge::Mirror;
+use crate::savestate::{StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+
+// AxROM (AMROM, ANROM, AN1ROM, AOROM): a 32KB PRG ROM bank switched by
+// writes to $8000-$FFFF, and one of the two nametables shown on all four
+// screens. Used by Rare games like Battletoads and many homebrew games.
+//
+// Bank select ($8000-$FFFF)
+//   7  bit  0
+//   ---- ----
+//   xxxS xPPP
+//      |  |||
+//      |  +++- 32KB PRG ROM bank at $8000
+//      +------ nametable, 0: first 1K of VRAM, 1: second
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Mapper7 {
+    prg_rom: Vec<u8>,
+    // 8KB of CHR RAM on all the original boards
+    chr: ChrMemory,
+    prg_bank: u8,
+    mirror: Mirror,
+}
+
+impl Mapper7 {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Mapper7 {
+        Mapper7 {
+            prg_rom,
+            chr: ChrMemory::new(chr_rom),
+            prg_bank: 0,
+            mirror: Mirror::SingleScreenLo,
+        }
+    }
+
+    fn num_prg_banks(&self) -> usize {
+        (self.prg_rom.len() / PRG_BANK_SIZE).max(1)
+    }
+
+    fn map_cpu_addr(&self, addr: u16) -> usize {
+        let bank = self.prg_bank as usize % self.num_prg_banks();
+        let offset = bank * PRG_BANK_SIZE + (addr & 0x7FFF) as usize;
+        // 16KB ROMs show up twice
+        offset % self.prg_rom.len()
+    }
+}
+
+#[cfg_attr(feature = "serde", typetag::serde)]
+impl super::mapper::Mapper for Mapper7 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        if addr < 0x8000 {
+            return None;
+        }
+        Some(self.prg_rom[self.map_cpu_addr(addr)])
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr >= 0x8000).then(|| self.map_cpu_addr(addr))
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr <= 0x1FFF)
+            .then_some(addr as usize)
+            .and_then(|offset| self.chr.rom_offset(offset))
+    }
+
+    fn rom_sizes(&self) -> (usize, usize) {
+        (self.prg_rom.len(), self.chr.rom_size())
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) -> bool {
+        if addr < 0x8000 {
+            return false;
+        }
+        self.prg_bank = value & 0b0000_0111;
+        self.mirror = if value & 0b0001_0000 != 0 {
+            Mirror::SingleScreenHi
+        } else {
+            Mirror::SingleScreenLo
+        };
+        true
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Option<u8> {
+        if addr <= 0x1FFF {
+            return Some(self.chr.read(addr as usize));
+        }
+        None
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) -> bool {
+        addr <= 0x1FFF && self.chr.write(addr as usize, value)
+    }
+
+    fn mirroring(&self) -> Option<Mirror> {
+        Some(self.mirror)
+    }
+
+    fn power_on(&mut self) {
+        self.prg_bank = 0;
+        self.mirror = Mirror::SingleScreenLo;
+        self.chr.power_on();
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.prg_bank);
+        w.write_bool(self.mirror == Mirror::SingleScreenHi);
+        self.chr.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.prg_bank = r.read_u8()? & 0b0000_0111;
+        self.mirror = if r.read_bool()? {
+            Mirror::SingleScreenHi
+        } else {
+            Mirror::SingleScreenLo
+        };
+        self.chr.load_state(r)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::mapper::Mapper;
+    use super::*;
+
+    #[test]
+    fn test_bank_select() {
+        // 128KB, each bank filled with its number
+        let prg_rom: Vec<u8> = (0..4u8)
+            .flat_map(|bank| vec![bank; PRG_BANK_SIZE])
+            .collect();
+        let mut mapper = Mapper7::new(prg_rom, vec![]);
+        assert_eq!(mapper.cpu_read(0x8000), Some(0));
+        assert_eq!(mapper.mirroring(), Some(Mirror::SingleScreenLo));
+
+        assert!(mapper.cpu_write(0xFFFF, 0b0001_0010));
+        assert_eq!(mapper.cpu_read(0x8000), Some(2));
+        assert_eq!(mapper.cpu_read(0xFFFF), Some(2));
+        assert_eq!(mapper.mirroring(), Some(Mirror::SingleScreenHi));
+
+        // bank 5 of a 4 bank ROM wraps to bank 1
+        mapper.cpu_write(0x8000, 0b0000_0101);
+        assert_eq!(mapper.cpu_read(0xC000), Some(1));
+        assert_eq!(mapper.mirroring(), Some(Mirror::SingleScreenLo));
+
+        assert!(!mapper.cpu_write(0x6000, 0));
+        assert!(mapper.ppu_write(0x0010, 0x42));
+        assert_eq!(mapper.ppu_read(0x0010), Some(0x42));
+
+        mapper.power_on();
+        assert_eq!(mapper.cpu_read(0x8000), Some(0));
+        assert_eq!(mapper.ppu_read(0x0010), Some(0));
+    }
+}
@@ -0,0 +1,122 @@
+use super::chr_memory::ChrMemory;
+use crate::savestate::{StateReader, StateWriter};
+
+const CHR_BANK_SIZE: usize = 0x2000;
+
+// Jaleco, Konami and Taito boards with NROM PRG ROM and an 8KB CHR ROM
+// bank latched by writes to $6000-$7FFF, from bits 0 and 1 swapped
+// Ref: https://wiki.nesdev.org/w/index.php/INES_Mapper_087
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Mapper87 {
+    prg_rom: Vec<u8>,
+    chr: ChrMemory,
+    chr_bank: u8,
+}
+
+impl Mapper87 {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Mapper87 {
+        Mapper87 {
+            prg_rom,
+            chr: ChrMemory::new(chr_rom),
+            chr_bank: 0,
+        }
+    }
+
+    fn map_ppu_addr(&self, addr: u16) -> usize {
+        let offset = self.chr_bank as usize * CHR_BANK_SIZE + addr as usize;
+        offset % self.chr.size()
+    }
+}
+
+#[cfg_attr(feature = "serde", typetag::serde)]
+impl super::mapper::Mapper for Mapper87 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        if addr < 0x8000 {
+            return None;
+        }
+        // 16KB ROMs are mirrored like NROM-128
+        Some(self.prg_rom[(addr & 0x7FFF) as usize % self.prg_rom.len()])
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) -> bool {
+        match addr {
+            0x6000..=0x7FFF => {
+                self.chr_bank = ((value & 0b01) << 1) | ((value & 0b10) >> 1);
+                true
+            }
+            0x8000..=0xFFFF => true,
+            _ => false,
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Option<u8> {
+        if addr > 0x1FFF {
+            return None;
+        }
+        Some(self.chr.read(self.map_ppu_addr(addr)))
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) -> bool {
+        addr <= 0x1FFF && self.chr.write(self.map_ppu_addr(addr), value)
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr >= 0x8000).then(|| (addr & 0x7FFF) as usize % self.prg_rom.len())
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr <= 0x1FFF)
+            .then(|| self.map_ppu_addr(addr))
+            .and_then(|offset| self.chr.rom_offset(offset))
+    }
+
+    fn rom_sizes(&self) -> (usize, usize) {
+        (self.prg_rom.len(), self.chr.rom_size())
+    }
+
+    fn power_on(&mut self) {
+        self.chr_bank = 0;
+        self.chr.power_on();
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.chr_bank);
+        self.chr.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.chr_bank = r.read_u8()?;
+        self.chr.load_state(r)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::mapper::Mapper;
+    use super::*;
+
+    #[test]
+    fn test_bank_select() {
+        let chr_rom = (0..4u8)
+            .flat_map(|bank| vec![bank; CHR_BANK_SIZE])
+            .collect();
+        let mut mapper = Mapper87::new(vec![0x42; 0x4000], chr_rom);
+        assert!(mapper.cpu_write(0x6000, 0b01));
+        assert_eq!(mapper.ppu_read(0x0000), Some(2));
+        assert_eq!(mapper.cpu_read(0xC000), Some(0x42));
+        assert!(!mapper.cpu_write(0x5000, 0b10));
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        let mut mapper = Mapper87::new(vec![0; 0x4000], vec![]);
+        assert!(mapper.ppu_write(0x0010, 0x42));
+        assert_eq!(mapper.ppu_read(0x0010), Some(0x42));
+        // 8KB of CHR RAM is a single bank
+        mapper.cpu_write(0x6000, 0b11);
+        assert_eq!(mapper.ppu_read(0x0010), Some(0x42));
+        assert_eq!(mapper.chr_rom_offset(0x0010), None);
+        assert_eq!(mapper.rom_sizes(), (0x4000, 0));
+    }
+}
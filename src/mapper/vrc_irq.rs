@@ -0,0 +1,144 @@
+use crate::savestate::{StateReader, StateWriter};
+
+// CPU cycles per scanline are 341 PPU dots / 3
+const PRESCALER_PERIOD: i16 = 341;
+
+// The IRQ counter of the Konami VRC4, VRC6 and VRC7. It counts up from a
+// latch to $FF and raises an IRQ when it wraps, either every CPU cycle or
+// every scanline, which it times with the CPU clock as it can't see the
+// PPU.
+// Ref: https://wiki.nesdev.org/w/index.php/VRC_IRQ
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VrcIrq {
+    pub latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    // `enabled` after the IRQ is acknowledged
+    enable_after_ack: bool,
+    cycle_mode: bool,
+    irq: bool,
+}
+
+impl VrcIrq {
+    pub fn new() -> Self {
+        VrcIrq {
+            latch: 0,
+            counter: 0,
+            prescaler: PRESCALER_PERIOD,
+            enabled: false,
+            enable_after_ack: false,
+            cycle_mode: false,
+            irq: false,
+        }
+    }
+
+    // IRQ control: bit 0 enable after acknowledge, bit 1 enable, bit 2
+    // cycle mode
+    pub fn write_control(&mut self, value: u8) {
+        self.enable_after_ack = value & 0b001 != 0;
+        self.enabled = value & 0b010 != 0;
+        self.cycle_mode = value & 0b100 != 0;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = PRESCALER_PERIOD;
+        }
+        self.irq = false;
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.irq = false;
+        self.enabled = self.enable_after_ack;
+    }
+
+    pub fn has_irq(&self) -> bool {
+        self.irq
+    }
+
+    pub fn clear_irq(&mut self) {
+        self.irq = false;
+    }
+
+    pub fn cpu_clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.cycle_mode {
+            self.clock_counter();
+            return;
+        }
+        // three scanlines in 341 CPU cycles
+        self.prescaler -= 3;
+        if self.prescaler <= 0 {
+            self.prescaler += PRESCALER_PERIOD;
+            self.clock_counter();
+        }
+    }
+
+    fn clock_counter(&mut self) {
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.irq = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.latch);
+        w.write_u8(self.counter);
+        w.write_u16(self.prescaler as u16);
+        w.write_bool(self.enabled);
+        w.write_bool(self.enable_after_ack);
+        w.write_bool(self.cycle_mode);
+        w.write_bool(self.irq);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.latch = r.read_u8()?;
+        self.counter = r.read_u8()?;
+        self.prescaler = r.read_u16()? as i16;
+        self.enabled = r.read_bool()?;
+        self.enable_after_ack = r.read_bool()?;
+        self.cycle_mode = r.read_bool()?;
+        self.irq = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_vrc_irq() {
+        let mut irq = VrcIrq::new();
+        irq.latch = 0xFE;
+        // cycle mode, enabled, enable after acknowledge
+        irq.write_control(0b111);
+        irq.cpu_clock();
+        assert!(!irq.has_irq());
+        irq.cpu_clock();
+        assert!(irq.has_irq());
+        irq.acknowledge();
+        assert!(!irq.has_irq());
+        irq.cpu_clock();
+        irq.cpu_clock();
+        assert!(irq.has_irq());
+
+        // scanline mode fires after a scanline, 113.67 CPU cycles
+        irq.latch = 0xFF;
+        irq.write_control(0b010);
+        for _ in 0..113 {
+            irq.cpu_clock();
+        }
+        assert!(!irq.has_irq());
+        irq.cpu_clock();
+        assert!(irq.has_irq());
+        irq.acknowledge();
+        for _ in 0..1000 {
+            irq.cpu_clock();
+        }
+        assert!(!irq.has_irq());
+    }
+}
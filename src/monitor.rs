@@ -0,0 +1,738 @@
+use std::io::{self, BufRead, Write};
+
+use crate::apu_log;
+use crate::debugger::CallKind;
+use crate::emulator::Emulator;
+use crate::event_log;
+use crate::expr::Expr;
+use crate::history;
+
+// A terminal monitor on top of the debugger: the frontend drops into
+// `Monitor::prompt` when the debugger breaks, and emulation continues when
+// the user types `cont`. Watch expressions are shown on every stop and step.
+
+const HELP: &str = "\
+commands (addresses and counts are hex, $ or 0x prefixes are allowed):
+  s, step [n]              execute n instructions (1)
+  n, next                  step over a JSR, running until it returns
+  finish                   run until the current subroutine returns
+  u, until <addr>          run until addr
+  c, cont                  continue running
+  r, regs                  show the registers
+  bt, backtrace            show the calls that haven't returned, the
+                           innermost first
+  m, mem <addr> [len]      dump memory ($40 bytes)
+  d, dis [addr] [n]        disassemble n instructions at addr (PC, $10)
+  b, break <addr> [if <expr>]
+                           set a breakpoint, which only stops when expr
+                           is true, e.g. if A == $3F and [$10] > 2
+  del, delete <addr>       delete a breakpoint
+  w, watch <addr>[-<end>] [r|w|rw]
+                           watch memory accesses (writes)
+  unwatch <n>              delete watchpoint n
+  display [expr]           show expr after every step, or show them all
+  undisplay <n>            delete watch expression n
+  freeze <addr> [value]    ignore writes to a RAM address, and keep it
+                           at value
+  unfreeze <addr>          let the address be written again
+  nmi, irq                 toggle breaking on NMI/IRQ
+  oam                      list the sprites in OAM
+  events [off]             record PPU events, or show the events of the
+                           previous and the current frame
+  apu [off]                record APU register writes, or show the
+                           writes of the previous and the current frame
+  history [n|off]          record the executed instructions, or show the
+                           last n ($20)
+  profile [n|off]          count the cycles of each subroutine, or show
+                           the n busiest ($10)
+  l, list                  list breakpoints, watchpoints and freezes
+  q, quit                  quit the emulator
+  h, help                  show this help
+";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MonitorAction {
+    // stay in the prompt
+    Stay,
+    Continue,
+    Quit,
+}
+
+pub struct Monitor {
+    // an empty line repeats the last command, like in gdb
+    last_command: String,
+}
+
+impl Monitor {
+    pub fn new() -> Self {
+        Monitor {
+            last_command: String::new(),
+        }
+    }
+
+    // Read commands until one resumes emulation. Returns false to quit.
+    pub fn prompt<R: BufRead, W: Write>(
+        &mut self,
+        emulator: &mut Emulator,
+        input: &mut R,
+        out: &mut W,
+    ) -> io::Result<bool> {
+        writeln!(out, "{}", emulator.cpu_mut().trace())?;
+        write_watch_exprs(emulator, out)?;
+        loop {
+            write!(out, "> ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                // end of input
+                return Ok(false);
+            }
+            match self.execute(emulator, &line, out)? {
+                MonitorAction::Stay => {}
+                MonitorAction::Continue => return Ok(true),
+                MonitorAction::Quit => return Ok(false),
+            }
+        }
+    }
+
+    // Run one command line
+    pub fn execute<W: Write>(
+        &mut self,
+        emulator: &mut Emulator,
+        line: &str,
+        out: &mut W,
+    ) -> io::Result<MonitorAction> {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => line.to_string(),
+        };
+        self.last_command = line.clone();
+        let args: Vec<&str> = line.split_whitespace().collect();
+        if args.is_empty() {
+            return Ok(MonitorAction::Stay);
+        }
+        match self.run_command(emulator, &args, out) {
+            Ok(action) => Ok(action),
+            Err(CommandError::Io(e)) => Err(e),
+            Err(CommandError::Usage(msg)) => {
+                writeln!(out, "{}", msg)?;
+                Ok(MonitorAction::Stay)
+            }
+        }
+    }
+
+    fn run_command<W: Write>(
+        &mut self,
+        emulator: &mut Emulator,
+        args: &[&str],
+        out: &mut W,
+    ) -> Result<MonitorAction, CommandError> {
+        match args[0] {
+            "s" | "step" => {
+                let count = parse_arg(args.get(1), 1)?;
+                step(emulator, count, out)?;
+            }
+            "n" | "next" => {
+                let regs = emulator.cpu().registers();
+                // JSR
+                if emulator.cpu_mut().bus.peek(regs.pc) != 0x20 {
+                    step(emulator, 1, out)?;
+                } else {
+                    let next_pc = regs.pc.wrapping_add(3);
+                    emulator.debugger().step_over(next_pc, regs.sp);
+                    return Ok(MonitorAction::Continue);
+                }
+            }
+            "finish" => {
+                let sp = emulator.cpu().registers().sp;
+                emulator.debugger().step_out(sp);
+                return Ok(MonitorAction::Continue);
+            }
+            "u" | "until" => {
+                let addr = parse_addr(args.get(1))?;
+                emulator.debugger().run_to(addr);
+                return Ok(MonitorAction::Continue);
+            }
+            "c" | "cont" => return Ok(MonitorAction::Continue),
+            "r" | "regs" => {
+                let regs = emulator.cpu().registers();
+                writeln!(
+                    out,
+                    "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} [{}] SP:{:02X} CYC:{}",
+                    regs.pc,
+                    regs.a,
+                    regs.x,
+                    regs.y,
+                    regs.p,
+                    flags(regs.p),
+                    regs.sp,
+                    emulator.cpu().total_cycles()
+                )?;
+            }
+            "bt" | "backtrace" => {
+                let cpu = emulator.cpu();
+                let frames = match cpu.debugger.as_ref() {
+                    Some(debugger) => debugger.call_stack(),
+                    None => &[],
+                };
+                let name = |addr: u16| match cpu.symbols.as_ref().and_then(|s| s.get(addr)) {
+                    Some(name) => format!("{:04X} {}", addr, name),
+                    None => format!("{:04X}", addr),
+                };
+                // where each routine is: at PC, or where the call it made
+                // returns to
+                let mut pc = cpu.pc;
+                for (depth, frame) in frames.iter().rev().enumerate() {
+                    let kind = match frame.kind {
+                        CallKind::Jsr => String::new(),
+                        CallKind::Brk => " [BRK]".to_string(),
+                        CallKind::Interrupt(interrupt) => format!(" [{:?}]", interrupt),
+                    };
+                    writeln!(
+                        out,
+                        "#{:<2} {:04X}  in {}{}",
+                        depth,
+                        pc,
+                        name(frame.entry),
+                        kind
+                    )?;
+                    pc = frame.return_pc;
+                }
+                writeln!(out, "#{:<2} {:04X}", frames.len(), pc)?;
+            }
+            "m" | "mem" => {
+                let addr = parse_addr(args.get(1))?;
+                let len = parse_arg(args.get(2), 0x40)?;
+                let bus = &mut emulator.cpu_mut().bus;
+                for row in (0..len).step_by(16) {
+                    let start = addr.wrapping_add(row as u16);
+                    let bytes: Vec<String> = (0..16.min(len - row))
+                        .map(|i| format!("{:02X}", bus.peek(start.wrapping_add(i as u16))))
+                        .collect();
+                    writeln!(out, "{:04X}  {}", start, bytes.join(" "))?;
+                }
+            }
+            "d" | "dis" => {
+                let cpu = emulator.cpu_mut();
+                let addr = match args.get(1) {
+                    Some(_) => parse_addr(args.get(1))?,
+                    None => cpu.pc,
+                };
+                let count = parse_arg(args.get(2), 0x10)?;
+                for line in cpu.disassemble_at(addr, count) {
+                    writeln!(out, "{}", line)?;
+                }
+            }
+            "b" | "break" => {
+                let addr = parse_addr(args.get(1))?;
+                match args.get(2) {
+                    None => emulator.debugger().add_breakpoint(addr),
+                    Some(&"if") => {
+                        let condition = parse_expr(&args[3..])?;
+                        emulator
+                            .debugger()
+                            .add_conditional_breakpoint(addr, condition);
+                    }
+                    Some(arg) => return Err(usage(&format!("expected if instead of {}", arg))),
+                }
+            }
+            "del" | "delete" => {
+                let addr = parse_addr(args.get(1))?;
+                if !emulator.debugger().remove_breakpoint(addr) {
+                    writeln!(out, "no breakpoint at {:04X}", addr)?;
+                }
+            }
+            "w" | "watch" => {
+                let range = args.get(1).ok_or_else(|| usage("missing address"))?;
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (parse_addr(Some(&start))?, parse_addr(Some(&end))?),
+                    None => {
+                        let addr = parse_addr(Some(range))?;
+                        (addr, addr)
+                    }
+                };
+                let (read, write) = match args.get(2).copied().unwrap_or("w") {
+                    "r" => (true, false),
+                    "w" => (false, true),
+                    "rw" => (true, true),
+                    access => return Err(usage(&format!("invalid access {}", access))),
+                };
+                let idx = emulator.debugger().add_watchpoint(start..=end, read, write);
+                writeln!(out, "watchpoint {}", idx)?;
+            }
+            "unwatch" => {
+                let idx = parse_arg(args.get(1), usize::MAX)?;
+                if idx >= emulator.debugger().watchpoints().len() {
+                    return Err(usage("no such watchpoint"));
+                }
+                emulator.debugger().remove_watchpoint(idx);
+            }
+            "display" => {
+                if args.len() == 1 {
+                    write_watch_exprs(emulator, out)?;
+                } else {
+                    let expr = parse_expr(&args[1..])?;
+                    let value = emulator.cpu_mut().eval(&expr);
+                    let shown = format!("{} = {}", expr, format_value(value));
+                    let idx = emulator.debugger().add_watch_expr(expr);
+                    writeln!(out, "{}: {}", idx, shown)?;
+                }
+            }
+            "undisplay" => {
+                let idx = parse_arg(args.get(1), usize::MAX)?;
+                if idx >= emulator.debugger().watch_exprs().len() {
+                    return Err(usage("no such watch expression"));
+                }
+                emulator.debugger().remove_watch_expr(idx);
+            }
+            "freeze" => {
+                let addr = parse_addr(args.get(1))?;
+                let value = match args.get(2) {
+                    Some(_) => Some(parse_byte(args.get(2))?),
+                    None => None,
+                };
+                emulator.freeze(addr, value).map_err(|e| usage(&e))?;
+            }
+            "unfreeze" => {
+                let addr = parse_addr(args.get(1))?;
+                if !emulator.unfreeze(addr) {
+                    writeln!(out, "{:04X} isn't frozen", addr)?;
+                }
+            }
+            "nmi" => {
+                let debugger = emulator.debugger();
+                debugger.break_on_nmi = !debugger.break_on_nmi;
+                writeln!(out, "break on NMI: {}", debugger.break_on_nmi)?;
+            }
+            "irq" => {
+                let debugger = emulator.debugger();
+                debugger.break_on_irq = !debugger.break_on_irq;
+                writeln!(out, "break on IRQ: {}", debugger.break_on_irq)?;
+            }
+            "l" | "list" => {
+                let debugger = emulator.debugger();
+                for pc in debugger.breakpoints() {
+                    match debugger.breakpoint_condition(pc) {
+                        Some(condition) => writeln!(out, "break {:04X} if {}", pc, condition)?,
+                        None => writeln!(out, "break {:04X}", pc)?,
+                    }
+                }
+                for (i, w) in debugger.watchpoints().iter().enumerate() {
+                    let access = match (w.read, w.write) {
+                        (true, true) => "rw",
+                        (true, false) => "r",
+                        _ => "w",
+                    };
+                    writeln!(
+                        out,
+                        "watch {} {:04X}-{:04X} {}",
+                        i,
+                        w.range.start(),
+                        w.range.end(),
+                        access
+                    )?;
+                }
+                for freeze in emulator.freezes().iter() {
+                    match freeze.value {
+                        Some(value) => writeln!(out, "freeze {:04X} {:02X}", freeze.addr, value)?,
+                        None => writeln!(out, "freeze {:04X}", freeze.addr)?,
+                    }
+                }
+            }
+            "oam" => {
+                for sprite in emulator.cpu().bus.ppu.sprites() {
+                    writeln!(out, "{}", sprite)?;
+                }
+            }
+            "events" => match (args.get(1).copied(), emulator.event_log()) {
+                (Some("off"), _) => emulator.detach_event_log(),
+                (Some(arg), _) => return Err(usage(&format!("invalid argument {}", arg))),
+                (None, None) => {
+                    emulator.attach_event_log(event_log::DEFAULT_CAPACITY);
+                    writeln!(out, "recording PPU events")?;
+                }
+                (None, Some(log)) => {
+                    if log.frame() > 0 {
+                        log.dump(log.frame() - 1, out)?;
+                    }
+                    log.dump(log.frame(), out)?;
+                }
+            },
+            "apu" => match (args.get(1).copied(), emulator.apu_log()) {
+                (Some("off"), _) => emulator.detach_apu_log(),
+                (Some(arg), _) => return Err(usage(&format!("invalid argument {}", arg))),
+                (None, None) => {
+                    emulator.attach_apu_log(apu_log::DEFAULT_CAPACITY);
+                    writeln!(out, "recording APU writes")?;
+                }
+                (None, Some(log)) => {
+                    if log.frame() > 0 {
+                        log.dump(log.frame() - 1, out)?;
+                    }
+                    log.dump(log.frame(), out)?;
+                }
+            },
+            "history" => match args.get(1) {
+                Some(&"off") => emulator.detach_history(),
+                arg => {
+                    let count = parse_arg(arg, 0x20)?;
+                    match emulator.history() {
+                        Some(history) => history.dump(count, out)?,
+                        None => {
+                            emulator.attach_history(history::DEFAULT_CAPACITY);
+                            writeln!(out, "recording instructions")?;
+                        }
+                    }
+                }
+            },
+            "profile" => match args.get(1) {
+                Some(&"off") => {
+                    emulator.stop_profiling();
+                }
+                arg => {
+                    let count = parse_arg(arg, 0x10)?;
+                    match emulator.profile_report(count) {
+                        Some(report) => write!(out, "{}", report)?,
+                        None => {
+                            emulator.start_profiling();
+                            writeln!(out, "profiling subroutines")?;
+                        }
+                    }
+                }
+            },
+            "q" | "quit" => return Ok(MonitorAction::Quit),
+            "h" | "help" | "?" => write!(out, "{}", HELP)?,
+            cmd => writeln!(out, "unknown command {}, try help", cmd)?,
+        }
+        Ok(MonitorAction::Stay)
+    }
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Monitor::new()
+    }
+}
+
+enum CommandError {
+    Io(io::Error),
+    // bad arguments, reported to the user
+    Usage(String),
+}
+
+impl From<io::Error> for CommandError {
+    fn from(e: io::Error) -> Self {
+        CommandError::Io(e)
+    }
+}
+
+fn usage(msg: &str) -> CommandError {
+    CommandError::Usage(msg.to_string())
+}
+
+fn parse_hex(arg: &str) -> Option<usize> {
+    let digits = arg
+        .strip_prefix('$')
+        .or_else(|| arg.strip_prefix("0x"))
+        .unwrap_or(arg);
+    usize::from_str_radix(digits, 16).ok()
+}
+
+fn parse_addr(arg: Option<&&str>) -> Result<u16, CommandError> {
+    let arg = arg.ok_or_else(|| usage("missing address"))?;
+    match parse_hex(arg) {
+        Some(addr) if addr <= 0xFFFF => Ok(addr as u16),
+        _ => Err(usage(&format!("invalid address {}", arg))),
+    }
+}
+
+fn parse_byte(arg: Option<&&str>) -> Result<u8, CommandError> {
+    let arg = arg.ok_or_else(|| usage("missing value"))?;
+    match parse_hex(arg) {
+        Some(value) if value <= 0xFF => Ok(value as u8),
+        _ => Err(usage(&format!("invalid value {}", arg))),
+    }
+}
+
+// Execute `count` instructions, stopping early on breaks and errors
+fn step<W: Write>(emulator: &mut Emulator, count: usize, out: &mut W) -> io::Result<()> {
+    for _ in 0..count {
+        if let Err(e) = emulator.step() {
+            writeln!(out, "{}", e)?;
+            break;
+        }
+        if let Some(reason) = emulator.take_break() {
+            writeln!(out, "{}", reason)?;
+            break;
+        }
+    }
+    writeln!(out, "{}", emulator.cpu_mut().trace())?;
+    write_watch_exprs(emulator, out)
+}
+
+fn parse_expr(args: &[&str]) -> Result<Expr, CommandError> {
+    if args.is_empty() {
+        return Err(usage("missing expression"));
+    }
+    Expr::parse(&args.join(" ")).map_err(|e| usage(&e))
+}
+
+// Bytes and addresses in hex like everywhere else, other values in decimal
+fn format_value(value: i64) -> String {
+    match value {
+        0..=0xFF => format!("${:02X}", value),
+        0x100..=0xFFFF => format!("${:04X}", value),
+        _ => value.to_string(),
+    }
+}
+
+fn write_watch_exprs<W: Write>(emulator: &mut Emulator, out: &mut W) -> io::Result<()> {
+    let exprs = match emulator.cpu().debugger.as_ref() {
+        Some(debugger) => debugger.watch_exprs().to_vec(),
+        None => return Ok(()),
+    };
+    for (i, expr) in exprs.iter().enumerate() {
+        let value = emulator.cpu_mut().eval(expr);
+        writeln!(out, "{}: {} = {}", i, expr, format_value(value))?;
+    }
+    Ok(())
+}
+
+fn parse_arg(arg: Option<&&str>, default: usize) -> Result<usize, CommandError> {
+    match arg {
+        Some(arg) => parse_hex(arg).ok_or_else(|| usage(&format!("invalid number {}", arg))),
+        None => Ok(default),
+    }
+}
+
+// NV-BDIZC, lowercase when clear
+fn flags(p: u8) -> String {
+    "NV-BDIZC"
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if p & (0x80 >> i) != 0 {
+                c
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Cartridge;
+
+    // LDX #$10; INX; STX $0200; JMP $8002
+    fn new_emulator() -> Emulator {
+        let mut program = vec![0xA2, 0x10, 0xE8, 0x8E, 0x00, 0x02, 0x4C, 0x02, 0x80];
+        program.resize(0x3FFC, 0);
+        program.extend_from_slice(&[0x00, 0x80]);
+        Emulator::new(Cartridge::new_from_program(program))
+    }
+
+    fn run(monitor: &mut Monitor, emulator: &mut Emulator, line: &str) -> (MonitorAction, String) {
+        let mut out = vec![];
+        let action = monitor.execute(emulator, line, &mut out).unwrap();
+        (action, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_step_and_inspect() {
+        let mut emulator = new_emulator();
+        let mut monitor = Monitor::new();
+
+        let (action, out) = run(&mut monitor, &mut emulator, "step 2");
+        assert_eq!(action, MonitorAction::Stay);
+        assert!(out.starts_with("8003  8E 00 02  STX $0200 = 00"), "{}", out);
+        // repeat the last command
+        run(&mut monitor, &mut emulator, "");
+        let (_, out) = run(&mut monitor, &mut emulator, "regs");
+        assert!(out.starts_with("PC:8002 A:00 X:11 Y:00"), "{}", out);
+
+        let (_, out) = run(&mut monitor, &mut emulator, "mem $01FE 4");
+        assert_eq!(out, "01FE  00 00 11 00\n");
+        let (_, out) = run(&mut monitor, &mut emulator, "dis 8000 2");
+        assert_eq!(out, "8000  A2 10     LDX #$10\n8002  E8        INX\n");
+
+        let (_, out) = run(&mut monitor, &mut emulator, "mem zz");
+        assert_eq!(out, "invalid address zz\n");
+        let (_, out) = run(&mut monitor, &mut emulator, "events");
+        assert_eq!(out, "recording PPU events\n");
+        let (_, out) = run(&mut monitor, &mut emulator, "events");
+        assert_eq!(out, "");
+        let (_, out) = run(&mut monitor, &mut emulator, "apu");
+        assert_eq!(out, "recording APU writes\n");
+        run(&mut monitor, &mut emulator, "apu off");
+        assert!(emulator.apu_log().is_none());
+        let (_, out) = run(&mut monitor, &mut emulator, "history");
+        assert_eq!(out, "recording instructions\n");
+        run(&mut monitor, &mut emulator, "step 2");
+        let (_, out) = run(&mut monitor, &mut emulator, "history 1");
+        assert!(out.starts_with("8003  8E 00 02  STX $0200"), "{}", out);
+        assert_eq!(out.lines().count(), 1);
+        run(&mut monitor, &mut emulator, "history off");
+        assert!(emulator.history().is_none());
+        let (_, out) = run(&mut monitor, &mut emulator, "profile");
+        assert_eq!(out, "profiling subroutines\n");
+        run(&mut monitor, &mut emulator, "step");
+        let (_, out) = run(&mut monitor, &mut emulator, "profile 1");
+        assert!(out.ends_with("top level\n"), "{}", out);
+        run(&mut monitor, &mut emulator, "profile off");
+        assert!(emulator.profiler().is_none());
+        let (_, out) = run(&mut monitor, &mut emulator, "oam");
+        assert_eq!(out.lines().count(), 64);
+        assert!(out.starts_with("00: x   0 y   0 tile 00 bank 0 8x8  palette 4 --\n"));
+        let (action, _) = run(&mut monitor, &mut emulator, "quit");
+        assert_eq!(action, MonitorAction::Quit);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut emulator = new_emulator();
+        let mut monitor = Monitor::new();
+        run(&mut monitor, &mut emulator, "break 8006");
+        run(&mut monitor, &mut emulator, "watch 0200-02FF w");
+        let (_, out) = run(&mut monitor, &mut emulator, "list");
+        assert_eq!(out, "break 8006\nwatch 0 0200-02FF w\n");
+
+        let mut input = io::Cursor::new("cont\n");
+        let mut out = vec![];
+        assert!(monitor.prompt(&mut emulator, &mut input, &mut out).unwrap());
+        emulator.run_frame().unwrap();
+        let reason = emulator.take_break().unwrap();
+        assert_eq!(
+            reason.to_string(),
+            "write 11 at 0200 by the instruction at 8003"
+        );
+        emulator.run_frame().unwrap();
+        assert_eq!(
+            emulator.take_break().unwrap().to_string(),
+            "breakpoint at 8006"
+        );
+
+        run(&mut monitor, &mut emulator, "unwatch 0");
+        run(&mut monitor, &mut emulator, "delete 8006");
+        let (_, out) = run(&mut monitor, &mut emulator, "list");
+        assert_eq!(out, "");
+
+        // end of input quits
+        let mut input = io::Cursor::new("");
+        assert!(!monitor
+            .prompt(&mut emulator, &mut input, &mut vec![])
+            .unwrap());
+    }
+
+    #[test]
+    fn test_conditions() {
+        let mut emulator = new_emulator();
+        let mut monitor = Monitor::new();
+        run(
+            &mut monitor,
+            &mut emulator,
+            "break 8003 if X == $14 and [$200] >= 2",
+        );
+        let (_, out) = run(&mut monitor, &mut emulator, "break 8006 when X");
+        assert_eq!(out, "expected if instead of when\n");
+        let (_, out) = run(&mut monitor, &mut emulator, "break 8006 if X ==");
+        assert_eq!(out, "unexpected end\n");
+        let (_, out) = run(&mut monitor, &mut emulator, "list");
+        assert_eq!(out, "break 8003 if X == $14 and [$200] >= 2\n");
+
+        emulator.run_frame().unwrap();
+        assert_eq!(
+            emulator.take_break().unwrap().to_string(),
+            "breakpoint at 8003"
+        );
+        assert_eq!(emulator.cpu().registers().x, 0x14);
+
+        let (_, out) = run(&mut monitor, &mut emulator, "display X");
+        assert_eq!(out, "0: X = $14\n");
+        run(&mut monitor, &mut emulator, "display [$0200] - $100");
+        let (_, out) = run(&mut monitor, &mut emulator, "step");
+        assert!(
+            out.ends_with("0: X = $14\n1: [$0200] - $100 = -236\n"),
+            "{}",
+            out
+        );
+        run(&mut monitor, &mut emulator, "undisplay 0");
+        let (_, out) = run(&mut monitor, &mut emulator, "display");
+        assert_eq!(out, "0: [$0200] - $100 = -236\n");
+        let (_, out) = run(&mut monitor, &mut emulator, "undisplay 1");
+        assert_eq!(out, "no such watch expression\n");
+    }
+
+    #[test]
+    fn test_step_over_and_out() {
+        let program = [
+            0x20, 0x08, 0x80, // 8000 JSR $8008
+            0xE8, // 8003 INX
+            0x4C, 0x03, 0x80, // 8004 JMP $8003
+            0xEA, // 8007 NOP
+            0xC8, // 8008 INY
+            0x20, 0x0D, 0x80, // 8009 JSR $800D
+            0x60, // 800C RTS
+            0xC8, // 800D INY
+            0x60, // 800E RTS
+        ];
+        let mut prg = program.to_vec();
+        prg.resize(0x3FFC, 0);
+        prg.extend_from_slice(&[0x00, 0x80]);
+        let mut emulator = Emulator::new(Cartridge::new_from_program(prg.clone()));
+        let mut monitor = Monitor::new();
+        let stop = |emulator: &mut Emulator| {
+            emulator.run_frame().unwrap();
+            emulator.take_break().unwrap().to_string()
+        };
+
+        let (action, _) = run(&mut monitor, &mut emulator, "next");
+        assert_eq!(action, MonitorAction::Continue);
+        assert_eq!(stop(&mut emulator), "stopped at 8003");
+        assert_eq!(emulator.cpu().registers().y, 2);
+        // not a JSR
+        let (action, out) = run(&mut monitor, &mut emulator, "next");
+        assert_eq!(action, MonitorAction::Stay);
+        assert!(out.starts_with("8004  4C 03 80  JMP $8003"), "{}", out);
+
+        let mut emulator = Emulator::new(Cartridge::new_from_program(prg));
+        // calls are tracked from when the debugger is attached
+        emulator.debugger();
+        run(&mut monitor, &mut emulator, "step 3");
+        assert_eq!(emulator.cpu().registers().pc, 0x800D);
+        let (_, out) = run(&mut monitor, &mut emulator, "bt");
+        assert_eq!(out, "#0  800D  in 800D\n#1  800C  in 8008\n#2  8003\n");
+        run(&mut monitor, &mut emulator, "finish");
+        assert_eq!(stop(&mut emulator), "stopped at 800C");
+        run(&mut monitor, &mut emulator, "finish");
+        assert_eq!(stop(&mut emulator), "stopped at 8003");
+
+        run(&mut monitor, &mut emulator, "until 8004");
+        assert_eq!(stop(&mut emulator), "stopped at 8004");
+    }
+
+    #[test]
+    fn test_freeze() {
+        let mut emulator = new_emulator();
+        let mut monitor = Monitor::new();
+        run(&mut monitor, &mut emulator, "freeze 200");
+        run(&mut monitor, &mut emulator, "freeze 300 7f");
+        let (_, out) = run(&mut monitor, &mut emulator, "freeze 2000");
+        assert_eq!(out, "2000 isn't RAM, only RAM can be frozen\n");
+        run(&mut monitor, &mut emulator, "step 3");
+        let (_, out) = run(&mut monitor, &mut emulator, "mem 200 1");
+        assert_eq!(out, "0200  00\n");
+        let (_, out) = run(&mut monitor, &mut emulator, "mem 300 1");
+        assert_eq!(out, "0300  7F\n");
+        let (_, out) = run(&mut monitor, &mut emulator, "list");
+        assert_eq!(out, "freeze 0200\nfreeze 0300 7F\n");
+
+        run(&mut monitor, &mut emulator, "unfreeze 200");
+        let (_, out) = run(&mut monitor, &mut emulator, "unfreeze 200");
+        assert_eq!(out, "0200 isn't frozen\n");
+        run(&mut monitor, &mut emulator, "step 3");
+        let (_, out) = run(&mut monitor, &mut emulator, "mem 200 1");
+        assert_eq!(out, "0200  12\n");
+    }
+}
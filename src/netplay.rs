@@ -0,0 +1,811 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use crate::bus::RamInit;
+use crate::desync::Desync;
+use crate::emulator::Emulator;
+use crate::input_log::MovieFrame;
+use crate::joypad::JoypadStatus;
+use crate::ppu::Accuracy;
+use crate::savestate::{crc32, StateHash};
+
+// Netplay: two players on two machines, each running the whole emulation.
+// Emulation is deterministic, so both stay in sync as long as they run
+// every frame with the same input (lockstep). The host plays with the first
+// controller and the guest with the second.
+//
+// Input is sent `delay` frames ahead of when it is used, which hides the
+// network latency: a frame only runs once the peer's input for it arrived,
+// and with enough delay it is already there. Every CHECK_INTERVAL frames
+// the peers also exchange state hashes, a difference means they desynced.
+//
+// With rollback on, frames don't wait for the peer's input, the peer is
+// assumed to still hold what it held last. The state before each frame is
+// saved until the peer's input for it arrives, when that differs from the
+// prediction the emulator goes back to the frame and runs the frames since
+// again, out of sight. Frames wait again once the peer is more than the
+// rollback window behind. Each side picks its mode, the messages are the
+// same.
+//
+// The peers talk over TCP, which keeps messages in order and doesn't lose
+// them. Messages are, numbers little-endian:
+//
+//   hello: "NESN" magic | u16 version | u32 ROM CRC-32 | u8 delay
+//          | u8 RAM init | u32 RAM seed | u8 accuracy
+//   input: 1 | u32 frame | u8 buttons | u8 reset
+//   hash:  2 | u32 frame | u32 CPU | u32 PPU | u32 APU | u32 all
+//
+// Both send hello first, the guest uses the delay and the power on
+// settings of the host. RAM init is 0 for zeros, 1 for $FF, 2 for stripes
+// and 3 for random with the seed, accuracy 0 for correct and 1 for
+// hardware.
+
+pub const DEFAULT_DELAY: u8 = 2;
+// Frames between state hash checks
+pub const CHECK_INTERVAL: u32 = 60;
+
+const MAGIC: &[u8; 4] = b"NESN";
+const VERSION: u16 = 2;
+const INPUT: u8 = 1;
+const HASH: u8 = 2;
+// A peer that sends nothing for this long is considered gone
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// The buttons held and whether reset was pressed
+type Input = (JoypadStatus, bool);
+
+// What the console powers on with. Both sides need the same, or they
+// desync from the first frame, e.g. with random RAM.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PowerOnSettings {
+    pub ram_init: RamInit,
+    pub accuracy: Accuracy,
+}
+
+impl PowerOnSettings {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let (kind, seed) = match self.ram_init {
+            RamInit::AllZero => (0, 0),
+            RamInit::AllFF => (1, 0),
+            RamInit::Striped => (2, 0),
+            RamInit::Random(seed) => (3, seed),
+        };
+        out.push(kind);
+        out.extend_from_slice(&seed.to_le_bytes());
+        out.push((self.accuracy == Accuracy::Hardware) as u8);
+    }
+
+    fn decode(bytes: &[u8]) -> Result<PowerOnSettings, String> {
+        let seed = u32::from_le_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
+        let ram_init = match bytes[0] {
+            0 => RamInit::AllZero,
+            1 => RamInit::AllFF,
+            2 => RamInit::Striped,
+            3 => RamInit::Random(seed),
+            kind => return Err(format!("the peer sent an unknown RAM init {}", kind)),
+        };
+        let accuracy = match bytes[5] {
+            0 => Accuracy::Correct,
+            1 => Accuracy::Hardware,
+            value => return Err(format!("the peer sent an unknown accuracy {}", value)),
+        };
+        Ok(PowerOnSettings { ram_init, accuracy })
+    }
+}
+
+enum Message {
+    Input(u32, Input),
+    Hash(u32, StateHash),
+}
+
+// A frame run with a guess of the peer's input
+struct Snapshot {
+    frame: u32,
+    // the state before the frame
+    state: Vec<u8>,
+    remote_input: Input,
+    // the state after the frame, on frames that are checked
+    hash: Option<StateHash>,
+}
+
+pub struct Netplay {
+    stream: TcpStream,
+    // read on another thread, so rollback can go on without them
+    messages: Receiver<Result<Message, String>>,
+    // 0 for the host, 1 for the guest
+    player: usize,
+    delay: u32,
+    // the host's
+    settings: PowerOnSettings,
+    // the next frame to run
+    frame: u32,
+    // input not used yet, or still needed for rollback, by frame
+    local_input: HashMap<u32, Input>,
+    remote_input: HashMap<u32, Input>,
+    // the peer's input is known for the frames before this
+    confirmed: u32,
+    // the peer's latest input, the guess for the frames after it
+    last_remote_input: Input,
+    // frames that can run before the peer's input arrives, 0 for lockstep
+    rollback_window: u32,
+    // frames after `confirmed`, oldest first
+    snapshots: VecDeque<Snapshot>,
+    // memory of dropped snapshots, for the next ones
+    free_states: Vec<Vec<u8>>,
+    // frames run again after wrong guesses
+    rolled_back_frames: u64,
+    // hashes waiting for the peer's, by frame
+    local_hashes: HashMap<u32, StateHash>,
+    remote_hashes: HashMap<u32, StateHash>,
+}
+
+impl Netplay {
+    // Wait for a guest on `port` and play the game in the `rom` iNES file
+    // with it
+    pub fn host(
+        port: u16,
+        rom: &[u8],
+        delay: u8,
+        settings: PowerOnSettings,
+    ) -> Result<Netplay, String> {
+        let listener = TcpListener::bind(("0.0.0.0", port))
+            .map_err(|e| format!("failed to listen on port {}: {}", port, e))?;
+        Netplay::accept(&listener, rom, delay, settings)
+    }
+
+    // Wait for a guest on an open listener
+    pub fn accept(
+        listener: &TcpListener,
+        rom: &[u8],
+        delay: u8,
+        settings: PowerOnSettings,
+    ) -> Result<Netplay, String> {
+        let (stream, _) = listener
+            .accept()
+            .map_err(|e| format!("failed to accept a guest: {}", e))?;
+        Netplay::start(stream, 0, rom, delay, settings)
+    }
+
+    // Join the host at `addr`, like "192.168.1.2:7000"
+    pub fn connect<A: ToSocketAddrs>(addr: A, rom: &[u8]) -> Result<Netplay, String> {
+        let stream =
+            TcpStream::connect(addr).map_err(|e| format!("failed to connect to host: {}", e))?;
+        Netplay::start(stream, 1, rom, DEFAULT_DELAY, PowerOnSettings::default())
+    }
+
+    fn start(
+        mut stream: TcpStream,
+        player: usize,
+        rom: &[u8],
+        delay: u8,
+        settings: PowerOnSettings,
+    ) -> Result<Netplay, String> {
+        // input messages are small and can't wait
+        stream.set_nodelay(true).map_err(net_error)?;
+        stream.set_read_timeout(Some(TIMEOUT)).map_err(net_error)?;
+
+        let mut hello = MAGIC.to_vec();
+        hello.extend_from_slice(&VERSION.to_le_bytes());
+        hello.extend_from_slice(&crc32(rom).to_le_bytes());
+        hello.push(delay);
+        settings.encode(&mut hello);
+        stream.write_all(&hello).map_err(net_error)?;
+        // the version first, older peers send a shorter hello
+        let mut peer = [0; 17];
+        stream.read_exact(&mut peer[..6]).map_err(net_error)?;
+        if &peer[0..4] != MAGIC {
+            return Err("the peer isn't a NES netplay peer".to_string());
+        }
+        let version = u16::from_le_bytes([peer[4], peer[5]]);
+        if version != VERSION {
+            return Err(format!(
+                "the peer uses netplay version {}, this is {}",
+                version, VERSION
+            ));
+        }
+        stream.read_exact(&mut peer[6..]).map_err(net_error)?;
+        let rom_hash = u32::from_le_bytes([peer[6], peer[7], peer[8], peer[9]]);
+        if rom_hash != crc32(rom) {
+            return Err(format!(
+                "the peer plays another ROM (CRC-32 {:08X}, this one is {:08X})",
+                rom_hash,
+                crc32(rom)
+            ));
+        }
+        let delay = if player == 1 { peer[10] } else { delay } as u32;
+        let settings = if player == 1 {
+            PowerOnSettings::decode(&peer[11..])?
+        } else {
+            settings
+        };
+
+        // the reader thread waits as long as it takes, timeouts are up to
+        // whoever waits for its messages
+        stream.set_read_timeout(None).map_err(net_error)?;
+        let reader = stream.try_clone().map_err(net_error)?;
+        let (sender, messages) = mpsc::channel();
+        thread::spawn(move || read_messages(reader, sender));
+
+        // nobody pressed anything before the game started
+        let idle = (JoypadStatus::empty(), false);
+        Ok(Netplay {
+            stream,
+            messages,
+            player,
+            delay,
+            settings,
+            frame: 0,
+            local_input: (0..delay).map(|frame| (frame, idle)).collect(),
+            remote_input: (0..delay).map(|frame| (frame, idle)).collect(),
+            confirmed: delay,
+            last_remote_input: idle,
+            rollback_window: 0,
+            snapshots: VecDeque::new(),
+            free_states: vec![],
+            rolled_back_frames: 0,
+            local_hashes: HashMap::new(),
+            remote_hashes: HashMap::new(),
+        })
+    }
+
+    // The controller this side plays with
+    pub fn player(&self) -> usize {
+        self.player
+    }
+
+    pub fn delay(&self) -> u32 {
+        self.delay
+    }
+
+    // The host's power on settings
+    pub fn settings(&self) -> PowerOnSettings {
+        self.settings
+    }
+
+    // Power the console on with the host's settings, before the first
+    // frame. Both sides call it, they then start from the same state.
+    pub fn power_on(&self, emulator: &mut Emulator) {
+        emulator.set_ram_init(self.settings.ram_init);
+        emulator.set_accuracy(self.settings.accuracy);
+        emulator.power_cycle();
+    }
+
+    // The next frame to run, counted from the start of the session
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    // Let `advance` run up to `window` frames ahead of the peer's input,
+    // 0 goes back to lockstep. Set before the first frame.
+    pub fn set_rollback_window(&mut self, window: u32) {
+        self.rollback_window = window;
+    }
+
+    pub fn rollback_window(&self) -> u32 {
+        self.rollback_window
+    }
+
+    // How many frames were run again since the start, for stats
+    pub fn rolled_back_frames(&self) -> u64 {
+        self.rolled_back_frames
+    }
+
+    // Run the next frame with this side's input, in lockstep or with
+    // rollback. The input is used `delay` frames from now. Reports desyncs
+    // found so far, see `frame_done`.
+    pub fn advance(
+        &mut self,
+        emulator: &mut Emulator,
+        buttons: JoypadStatus,
+        reset: bool,
+    ) -> Result<Option<Desync>, String> {
+        if self.rollback_window == 0 {
+            let input = self.exchange(buttons, reset)?;
+            input.apply(emulator);
+            emulator.run_frame().map_err(|e| e.to_string())?;
+            return self.frame_done(emulator.state_hash());
+        }
+
+        self.send_input(buttons, reset)?;
+        self.poll_messages()?;
+        while self.frame.saturating_sub(self.confirmed) >= self.rollback_window {
+            self.receive_message()?;
+        }
+        self.roll_back(emulator)?;
+        self.run_frame(emulator, self.frame, false)?;
+        self.frame += 1;
+        self.drop_confirmed()?;
+        Ok(self.compare_hashes())
+    }
+
+    // Wait for the peer's input of every frame run and correct the frames
+    // it was guessed wrong for. Both sides then have the same state, e.g.
+    // to save it.
+    pub fn sync(&mut self, emulator: &mut Emulator) -> Result<(), String> {
+        while self.confirmed < self.frame {
+            self.receive_message()?;
+        }
+        self.roll_back(emulator)?;
+        self.drop_confirmed()
+    }
+
+    // Lockstep: send this side's input, which is used `delay` frames from
+    // now, and return the input of the next frame, waiting for the peer's.
+    // The caller then applies it and runs the frame. Reset goes through
+    // here too, either side can press it.
+    pub fn exchange(&mut self, buttons: JoypadStatus, reset: bool) -> Result<MovieFrame, String> {
+        self.send_input(buttons, reset)?;
+        while self.confirmed <= self.frame {
+            self.receive_message()?;
+        }
+        let local = self.local_input.remove(&self.frame).unwrap();
+        let remote = self.remote_input.remove(&self.frame).unwrap();
+        let input = self.frame_input(local, remote);
+        self.frame += 1;
+        Ok(input)
+    }
+
+    // Call after running the frame `exchange` returned, with the state
+    // hash. Reports desyncs found so far, the frame of a desync counts from
+    // the start of the session.
+    pub fn frame_done(&mut self, hash: StateHash) -> Result<Option<Desync>, String> {
+        let frame = self.frame - 1;
+        if frame.is_multiple_of(CHECK_INTERVAL) {
+            self.send_hash(frame, hash)?;
+        }
+        // the peer's hash may come a few frames later
+        Ok(self.compare_hashes())
+    }
+
+    fn frame_input(&self, local: Input, remote: Input) -> MovieFrame {
+        let mut input = MovieFrame::new([local.0, remote.0]);
+        if self.player == 1 {
+            input.buttons.swap(0, 1);
+        }
+        input.reset = local.1 || remote.1;
+        input
+    }
+
+    // Run `frame` with the peer's input or a guess of it, keeping what is
+    // needed to run it again
+    fn run_frame(
+        &mut self,
+        emulator: &mut Emulator,
+        frame: u32,
+        replay: bool,
+    ) -> Result<(), String> {
+        let local_input = self.local_input[&frame];
+        let remote_input = match self.remote_input.get(&frame) {
+            Some(&input) => input,
+            // a reset is too rare to guess
+            None => (self.last_remote_input.0, false),
+        };
+        let mut state = self.free_states.pop().unwrap_or_default();
+        emulator.save_state_into(&mut state);
+        self.frame_input(local_input, remote_input).apply(emulator);
+        if replay {
+            emulator.replay_frame()
+        } else {
+            emulator.run_frame().map(|_| ())
+        }
+        .map_err(|e| e.to_string())?;
+        let hash = frame
+            .is_multiple_of(CHECK_INTERVAL)
+            .then(|| emulator.state_hash());
+        self.snapshots.push_back(Snapshot {
+            frame,
+            state,
+            remote_input,
+            hash,
+        });
+        Ok(())
+    }
+
+    // Go back to the first frame run with a wrong guess and run the frames
+    // from there again
+    fn roll_back(&mut self, emulator: &mut Emulator) -> Result<(), String> {
+        let wrong = self.snapshots.iter().position(|snapshot| {
+            self.remote_input
+                .get(&snapshot.frame)
+                .is_some_and(|&input| input != snapshot.remote_input)
+        });
+        let wrong = match wrong {
+            Some(wrong) => wrong,
+            None => return Ok(()),
+        };
+        emulator.load_state(&self.snapshots[wrong].state)?;
+        let snapshots: Vec<Snapshot> = self.snapshots.drain(wrong..).collect();
+        self.rolled_back_frames += snapshots.len() as u64;
+        for snapshot in snapshots {
+            self.free_states.push(snapshot.state);
+            self.run_frame(emulator, snapshot.frame, true)?;
+        }
+        Ok(())
+    }
+
+    // Frames whose input is all known can't be rolled back anymore, and
+    // their state can be checked
+    fn drop_confirmed(&mut self) -> Result<(), String> {
+        while self
+            .snapshots
+            .front()
+            .is_some_and(|snapshot| snapshot.frame < self.confirmed)
+        {
+            let snapshot = self.snapshots.pop_front().unwrap();
+            self.local_input.remove(&snapshot.frame);
+            self.remote_input.remove(&snapshot.frame);
+            self.free_states.push(snapshot.state);
+            if let Some(hash) = snapshot.hash {
+                self.send_hash(snapshot.frame, hash)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn send_input(&mut self, buttons: JoypadStatus, reset: bool) -> Result<(), String> {
+        let frame = self.frame + self.delay;
+        let mut message = vec![INPUT];
+        message.extend_from_slice(&frame.to_le_bytes());
+        message.push(buttons.bits());
+        message.push(reset as u8);
+        self.stream.write_all(&message).map_err(net_error)?;
+        self.local_input.insert(frame, (buttons, reset));
+        Ok(())
+    }
+
+    fn send_hash(&mut self, frame: u32, hash: StateHash) -> Result<(), String> {
+        let mut message = vec![HASH];
+        message.extend_from_slice(&frame.to_le_bytes());
+        for part in [hash.cpu, hash.ppu, hash.apu, hash.all] {
+            message.extend_from_slice(&part.to_le_bytes());
+        }
+        self.stream.write_all(&message).map_err(net_error)?;
+        self.local_hashes.insert(frame, hash);
+        Ok(())
+    }
+
+    fn compare_hashes(&mut self) -> Option<Desync> {
+        let mut frames: Vec<u32> = self
+            .local_hashes
+            .keys()
+            .filter(|frame| self.remote_hashes.contains_key(frame))
+            .copied()
+            .collect();
+        frames.sort_unstable();
+        for frame in frames {
+            let actual = self.local_hashes.remove(&frame).unwrap();
+            let expected = self.remote_hashes.remove(&frame).unwrap();
+            if actual != expected {
+                return Some(Desync {
+                    frame: frame as usize,
+                    expected,
+                    actual,
+                });
+            }
+        }
+        None
+    }
+
+    // Wait for the next message
+    fn receive_message(&mut self) -> Result<(), String> {
+        match self.messages.recv_timeout(TIMEOUT) {
+            Ok(message) => self.handle_message(message?),
+            Err(RecvTimeoutError::Timeout) => Err("the peer stopped responding".to_string()),
+            Err(RecvTimeoutError::Disconnected) => Err("the peer disconnected".to_string()),
+        }
+    }
+
+    // Take the messages that arrived, without waiting
+    fn poll_messages(&mut self) -> Result<(), String> {
+        loop {
+            match self.messages.try_recv() {
+                Ok(message) => self.handle_message(message?)?,
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::Disconnected) => return Err("the peer disconnected".to_string()),
+            }
+        }
+    }
+
+    fn handle_message(&mut self, message: Message) -> Result<(), String> {
+        match message {
+            Message::Input(frame, input) => {
+                if frame != self.confirmed {
+                    return Err(format!(
+                        "the peer sent the input of frame {}, expected {}",
+                        frame, self.confirmed
+                    ));
+                }
+                self.remote_input.insert(frame, input);
+                self.last_remote_input = input;
+                self.confirmed += 1;
+            }
+            Message::Hash(frame, hash) => {
+                self.remote_hashes.insert(frame, hash);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Netplay {
+    fn drop(&mut self) {
+        // wakes up the reader thread, which then ends
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+fn read_messages(mut stream: TcpStream, messages: Sender<Result<Message, String>>) {
+    loop {
+        let message = read_message(&mut stream);
+        let failed = message.is_err();
+        if messages.send(message).is_err() || failed {
+            return;
+        }
+    }
+}
+
+fn read_message(stream: &mut TcpStream) -> Result<Message, String> {
+    let mut kind = [0; 1];
+    stream.read_exact(&mut kind).map_err(net_error)?;
+    match kind[0] {
+        INPUT => {
+            let mut input = [0; 6];
+            stream.read_exact(&mut input).map_err(net_error)?;
+            let frame = u32::from_le_bytes([input[0], input[1], input[2], input[3]]);
+            let buttons = JoypadStatus::from_bits_truncate(input[4]);
+            Ok(Message::Input(frame, (buttons, input[5] != 0)))
+        }
+        HASH => {
+            let mut hash = [0; 20];
+            stream.read_exact(&mut hash).map_err(net_error)?;
+            let word = |i: usize| {
+                u32::from_le_bytes([
+                    hash[i * 4],
+                    hash[i * 4 + 1],
+                    hash[i * 4 + 2],
+                    hash[i * 4 + 3],
+                ])
+            };
+            Ok(Message::Hash(
+                word(0),
+                StateHash {
+                    cpu: word(1),
+                    ppu: word(2),
+                    apu: word(3),
+                    all: word(4),
+                },
+            ))
+        }
+        kind => Err(format!("invalid netplay message {}", kind)),
+    }
+}
+
+fn net_error(e: std::io::Error) -> String {
+    match e.kind() {
+        ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset => {
+            "the peer disconnected".to_string()
+        }
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => "the peer stopped responding".to_string(),
+        _ => format!("netplay: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::emulator::Emulator;
+    use std::thread;
+
+    // Mixes both joypads into RAM every frame:
+    //
+    //   LDA #$80 : STA $2000      ; NMI on
+    //   loop: JMP loop
+    //   nmi:  LDA #1 : STA $4016 : LSR A : STA $4016
+    //         LDA $4016 : EOR $4017 : EOR $00 : ROL A : STA $00 : RTI
+    fn program() -> Vec<u8> {
+        let mut program = vec![
+            0xA9, 0x80, 0x8D, 0x00, 0x20, // 8000
+            0x4C, 0x05, 0x80, // 8005
+            0xA9, 0x01, 0x8D, 0x16, 0x40, 0x4A, 0x8D, 0x16, 0x40, // 8008
+            0xAD, 0x16, 0x40, 0x4D, 0x17, 0x40, 0x45, 0x00, 0x2A, 0x85, 0x00, 0x40, // 8011
+        ];
+        program.resize(0x3FFA, 0);
+        program.extend_from_slice(&[0x08, 0x80, 0x00, 0x80, 0x00, 0x80]);
+        program
+    }
+
+    // Plays 70 frames, pressing A on some of them, and returns the hashes
+    fn play(netplay: &mut Netplay, corrupt_at: Option<u32>) -> Result<Vec<StateHash>, String> {
+        let mut emulator = Emulator::new(Cartridge::new_from_program(program()));
+        let mut hashes = vec![];
+        for frame in 0..70u32 {
+            let buttons = if frame.is_multiple_of(3 + netplay.player() as u32) {
+                JoypadStatus::BUTTON_A
+            } else {
+                JoypadStatus::empty()
+            };
+            let input = netplay.exchange(buttons, false)?;
+            input.apply(&mut emulator);
+            emulator.run_frame().map_err(|e| e.to_string())?;
+            if corrupt_at == Some(frame) {
+                emulator.cpu_mut().bus.cpu_ram[0x10] ^= 1;
+            }
+            if let Some(desync) = netplay.frame_done(emulator.state_hash())? {
+                return Err(desync.to_string());
+            }
+            hashes.push(emulator.state_hash());
+        }
+        Ok(hashes)
+    }
+
+    fn session(
+        host_rom: Vec<u8>,
+        guest_rom: Vec<u8>,
+        corrupt_at: Option<u32>,
+    ) -> (
+        Result<Vec<StateHash>, String>,
+        Result<Vec<StateHash>, String>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // the connections stay open until both sides are done, the one
+        // that finishes first would leave while the other still sends
+        let host = thread::spawn(move || {
+            let mut netplay = Netplay::accept(&listener, &host_rom, 3, PowerOnSettings::default())?;
+            play(&mut netplay, None).map(|hashes| (hashes, netplay))
+        });
+        let guest = Netplay::connect(addr, &guest_rom).and_then(|mut netplay| {
+            assert_eq!(netplay.delay(), 3);
+            play(&mut netplay, corrupt_at).map(|hashes| (hashes, netplay))
+        });
+        let host = host.join().unwrap();
+        (
+            host.map(|(hashes, _)| hashes),
+            guest.map(|(hashes, _)| hashes),
+        )
+    }
+
+    #[test]
+    fn test_lockstep() {
+        let rom = program();
+        let (host, guest) = session(rom.clone(), rom, None);
+        let host = host.unwrap();
+        assert_eq!(host, guest.unwrap());
+        // the input made a difference
+        assert_ne!(host[10].cpu, host[11].cpu);
+    }
+
+    #[test]
+    fn test_rom_mismatch() {
+        let mut other = program();
+        other[0x100] = 1;
+        let (host, guest) = session(program(), other, None);
+        assert!(host.unwrap_err().contains("another ROM"));
+        assert!(guest.unwrap_err().contains("another ROM"));
+    }
+
+    #[test]
+    fn test_power_on_settings() {
+        let rom = program();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let settings = PowerOnSettings {
+            ram_init: RamInit::Random(1234),
+            accuracy: Accuracy::Hardware,
+        };
+        let host_rom = rom.clone();
+        let host =
+            thread::spawn(move || Netplay::accept(&listener, &host_rom, 0, settings).unwrap());
+        let guest = Netplay::connect(addr, &rom).unwrap();
+        let host = host.join().unwrap();
+        assert_eq!(host.settings(), settings);
+        assert_eq!(guest.settings(), settings);
+
+        // the guest's own settings don't matter
+        let mut host_emulator = Emulator::new(Cartridge::new_from_program(program()));
+        let mut guest_emulator = Emulator::new(Cartridge::new_from_program(program()));
+        guest_emulator.set_ram_init(RamInit::Random(1));
+        host.power_on(&mut host_emulator);
+        guest.power_on(&mut guest_emulator);
+        assert_ne!(host_emulator.cpu_mut().bus.cpu_ram[0x10..0x20], [0; 16]);
+        assert_eq!(
+            host_emulator.cpu_mut().bus.cpu_ram,
+            guest_emulator.cpu_mut().bus.cpu_ram
+        );
+        assert_eq!(host_emulator.state_hash(), guest_emulator.state_hash());
+    }
+
+    #[test]
+    fn test_settings_encoding() {
+        for ram_init in [
+            RamInit::AllZero,
+            RamInit::AllFF,
+            RamInit::Striped,
+            RamInit::Random(0xDEADBEEF),
+        ] {
+            let settings = PowerOnSettings {
+                ram_init,
+                accuracy: Accuracy::Hardware,
+            };
+            let mut bytes = vec![];
+            settings.encode(&mut bytes);
+            let decoded = PowerOnSettings::decode(&bytes);
+            assert_eq!(decoded, Ok(settings));
+        }
+        assert!(PowerOnSettings::decode(&[4, 0, 0, 0, 0, 0]).is_err());
+        assert!(PowerOnSettings::decode(&[0, 0, 0, 0, 0, 2]).is_err());
+    }
+
+    #[test]
+    fn test_desync() {
+        let rom = program();
+        let (host, guest) = session(rom.clone(), rom, Some(30));
+        // found at the check after frame 60 by the side that gets the
+        // other's hash first, the other one sees it leave
+        let errors = [host.unwrap_err(), guest.unwrap_err()];
+        assert!(
+            errors
+                .iter()
+                .any(|e| e.starts_with("desync after frame 60 in CPU")),
+            "{:?}",
+            errors
+        );
+        for e in errors.iter() {
+            assert!(e.contains("desync") || e.contains("disconnected"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_rollback() {
+        let rom = program();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let host_rom = rom.clone();
+        let host = thread::spawn(move || {
+            Netplay::accept(&listener, &host_rom, 0, PowerOnSettings::default()).unwrap()
+        });
+        let mut guest = Netplay::connect(addr, &rom).unwrap();
+        let mut host = host.join().unwrap();
+        host.set_rollback_window(8);
+        guest.set_rollback_window(8);
+
+        let buttons = |player: usize, frame: u32| {
+            if frame.is_multiple_of(3 + player as u32) {
+                JoypadStatus::BUTTON_A
+            } else {
+                JoypadStatus::empty()
+            }
+        };
+        let new_emulator = || Emulator::new(Cartridge::new_from_program(program()));
+        let (mut host_emulator, mut guest_emulator) = (new_emulator(), new_emulator());
+        // the host runs 4 frames behind, the guest guesses its input
+        for frame in 0..40 {
+            let desync = guest.advance(&mut guest_emulator, buttons(1, frame), false);
+            assert_eq!(desync, Ok(None));
+            if frame >= 4 {
+                let desync = host.advance(&mut host_emulator, buttons(0, frame - 4), false);
+                assert_eq!(desync, Ok(None));
+            }
+        }
+        for frame in 36..40 {
+            let desync = host.advance(&mut host_emulator, buttons(0, frame), false);
+            assert_eq!(desync, Ok(None));
+        }
+        host.sync(&mut host_emulator).unwrap();
+        guest.sync(&mut guest_emulator).unwrap();
+        assert!(guest.rolled_back_frames() > 0);
+
+        let mut expected = new_emulator();
+        for frame in 0..40 {
+            MovieFrame::new([buttons(0, frame), buttons(1, frame)]).apply(&mut expected);
+            expected.run_frame().unwrap();
+        }
+        assert_eq!(host_emulator.state_hash(), expected.state_hash());
+        assert_eq!(guest_emulator.state_hash(), expected.state_hash());
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+
+use crate::graphics::font::{draw_text, draw_text_indexed, GLYPH_SIZE};
+use crate::graphics::{NesFrame, NES_HEIGHT};
+
+// On-screen display: short messages about what the emulator did, like
+// "State saved" or "Cheat enabled", drawn over the bottom left of the
+// picture for about two seconds. The newest message is at the bottom.
+
+// How long a message stays, in frames
+pub const MESSAGE_FRAMES: u32 = 120;
+// Older messages are dropped when there are more
+pub const MAX_MESSAGES: usize = 4;
+
+// TVs cut off about 8 lines at the top and bottom
+const MARGIN: u32 = 10;
+const LINE_HEIGHT: u32 = GLYPH_SIZE + 1;
+
+#[derive(Default)]
+pub struct Osd {
+    // text and frames left
+    messages: VecDeque<(String, u32)>,
+}
+
+impl Osd {
+    pub fn new() -> Self {
+        Osd::default()
+    }
+
+    pub fn show<S: Into<String>>(&mut self, message: S) {
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.messages.push_back((message.into(), MESSAGE_FRAMES));
+    }
+
+    // A frame went by, messages that have been shown long enough go away
+    pub fn tick(&mut self) {
+        for (_, frames_left) in self.messages.iter_mut() {
+            *frames_left -= 1;
+        }
+        self.messages.retain(|&(_, frames_left)| frames_left > 0);
+    }
+
+    pub fn clear(&mut self) {
+        self.messages.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    // Oldest first
+    pub fn messages(&self) -> impl Iterator<Item = &str> {
+        self.messages.iter().map(|(message, _)| message.as_str())
+    }
+
+    // Where each message goes, x and y of its top left corner
+    fn lines(&self) -> impl Iterator<Item = (u32, u32, &str)> {
+        let top = NES_HEIGHT - MARGIN - self.messages.len() as u32 * LINE_HEIGHT;
+        self.messages()
+            .enumerate()
+            .map(move |(i, message)| (MARGIN, top + i as u32 * LINE_HEIGHT, message))
+    }
+
+    pub fn draw(&self, frame: &mut NesFrame) {
+        for (x, y, message) in self.lines() {
+            draw_text(frame, x, y, message, [0xFF, 0xFF, 0xFF]);
+        }
+    }
+
+    // The same for a frame of system palette indexes
+    pub fn draw_indexed(&self, pixels: &mut [u16]) {
+        for (x, y, message) in self.lines() {
+            // $30 is white
+            draw_text_indexed(pixels, x, y, message, 0x30);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_messages() {
+        let mut osd = Osd::new();
+        osd.show("State saved");
+        for _ in 0..MESSAGE_FRAMES / 2 {
+            osd.tick();
+        }
+        osd.show("Cheat enabled".to_string());
+        assert_eq!(
+            osd.messages().collect::<Vec<_>>(),
+            ["State saved", "Cheat enabled"]
+        );
+
+        let mut frame = NesFrame::new();
+        osd.draw(&mut frame);
+        // the bottom message's first letter, C
+        let y = NES_HEIGHT - MARGIN - LINE_HEIGHT;
+        assert_eq!(frame.pixel(MARGIN + 2, y), [0xFF, 0xFF, 0xFF]);
+
+        for _ in 0..MESSAGE_FRAMES / 2 {
+            osd.tick();
+        }
+        assert_eq!(osd.messages().collect::<Vec<_>>(), ["Cheat enabled"]);
+        for _ in 0..MESSAGE_FRAMES / 2 {
+            osd.tick();
+        }
+        assert!(osd.is_empty());
+
+        for i in 0..MAX_MESSAGES + 1 {
+            osd.show(format!("{}", i));
+        }
+        assert_eq!(osd.messages().next(), Some("1"));
+    }
+}
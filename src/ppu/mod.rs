@@ -1,126 +1,316 @@
 pub mod registers;
+pub mod viewer;
+
+use std::cell::RefCell;
+use std::rc::Rc;
 
 use crate::cartridge::Cartridge;
 use crate::cartridge::Mirror;
-use crate::graphics::NesFrame;
-use registers::addr::AddrRegister;
+use crate::cdl;
+use crate::graphics::{NesFrame, NES_HEIGHT, NES_WIDTH};
+use crate::savestate::{SaveState, StateReader, StateWriter};
 use registers::ctrl::CtrlRegister;
+use registers::loopy::LoopyRegister;
 
 use self::registers::mask::MaskRegister;
-use self::registers::scroll::ScrollRegister;
 use self::registers::status::StatusRegister;
 
+// Frames after which the I/O latch reads back as 0
+const IO_LATCH_DECAY_FRAMES: u32 = 36;
+
+// Whether the PPU has the hardware's bugs. Games don't rely on them, but
+// some test ROMs check them.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Accuracy {
+    // What the hardware was meant to do, like setting the sprite overflow
+    // flag when there are more than 8 sprites on a line
+    #[default]
+    Correct,
+    // The quirks of the real chip: the sprite overflow check scans OAM
+    // diagonally, rendering uses OAMADDR (see `set_accurate_oam`) and with
+    // rendering off the palette entry v points at replaces the backdrop
+    Hardware,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PPU {
-    chr_rom: Vec<u8>,
-    vram: [u8; 2048],
+    // shared with the bus, pattern tables are read through the mapper
+    // replaced by the bus when deserializing
+    #[cfg_attr(feature = "serde", serde(skip, default = "detached_cartridge"))]
+    cart: Rc<RefCell<Cartridge>>,
+    // 2K of nametable RAM, plus 2K on four-screen cartridges
+    #[cfg_attr(feature = "serde", serde(with = "crate::savestate::byte_array"))]
+    vram: [u8; 4096],
     palette_table: [u8; 32],
     mirror: Mirror,
 
     // registers
-    addr_reg: AddrRegister,
     ctrl_reg: CtrlRegister,
     status_reg: StatusRegister,
-    scroll_reg: ScrollRegister,
     mask_reg: MaskRegister,
 
+    // scroll position and VRAM address, shared by $2005 and $2006
+    loopy: LoopyRegister,
+
     // OAM
+    #[cfg_attr(feature = "serde", serde(with = "crate::savestate::byte_array"))]
     pub oam_data: [u8; 256],
     oam_addr: u8,
 
+    // Sprites found on the previous scanline by sprite evaluation, drawn on
+    // the current one. At most 8 sprites fit on a line.
+    secondary_oam: [u8; 32],
+    sprite_count: usize,
+    sprite_zero_in_line: bool,
+    // Dot of the current scanline where sprite 0 hits the background
+    sprite_zero_hit_dot: Option<u32>,
+    // Emulate the hardware bug in the sprite overflow check, which makes
+    // the flag unreliable. Off by default.
+    sprite_overflow_bug: bool,
+    // Emulate how rendering uses OAMADDR: $2004 writes during rendering
+    // are glitched, OAMADDR is reset after each line and a non-zero OAMADDR
+    // corrupts OAM when rendering starts. Off by default.
+    accurate_oam: bool,
+    // Show the palette entry v points at instead of the backdrop while
+    // rendering is off, the "background palette hack". Off by default.
+    palette_hack: bool,
+    // What the EXT pins read, the backdrop color in slave mode (PPUCTRL bit
+    // 6 clear). They are grounded on the NES, so it is color 0 as usual,
+    // and what master mode outputs on them goes nowhere.
+    ext_input: u8,
+
     // internal data buffer
     data_buf: u8,
 
+    // The data bus between the CPU and the PPU keeps the last value written
+    // or read, which is what write-only registers and unused bits return.
+    // It decays to 0 when not refreshed for a while.
+    io_latch: u8,
+    io_latch_age: u32,
+
     // NMI status
     nmi: bool,
+    // $2002 was read just before vblank starts, which cancels it
+    suppress_vblank: bool,
+    // Set when the pre-render line ends, the frame is complete. Not saved,
+    // it is taken right away (see `take_frame_complete`).
+    frame_complete: bool,
+    // Frames completed since power on
+    frame_number: u64,
 
     // temp field for tracking PPU cycles and scanlines
     scanlines: u32,
     cycles: u32,
+
+    // The frame being rendered, one scanline at a time
+    #[cfg_attr(feature = "serde", serde(skip, default = "new_frame"))]
+    frame: Box<NesFrame>,
+    // The same frame as system palette colors, for video filters
+    #[cfg_attr(feature = "serde", serde(skip, default = "new_indexed_frame"))]
+    indexed_frame: Vec<u16>,
+}
+
+#[cfg(feature = "serde")]
+fn detached_cartridge() -> Rc<RefCell<Cartridge>> {
+    Rc::new(RefCell::new(Cartridge::new_dummy()))
+}
+
+fn new_frame() -> Box<NesFrame> {
+    Box::new(NesFrame::new())
+}
+
+fn new_indexed_frame() -> Vec<u16> {
+    vec![0; (NES_WIDTH * NES_HEIGHT) as usize]
 }
 
 impl PPU {
-    pub fn new(cart: &Cartridge) -> Self {
+    pub fn new(cart: Rc<RefCell<Cartridge>>) -> Self {
+        let mirror = cart.borrow().mirroring();
         PPU {
-            chr_rom: cart.chr_rom.clone(),
-            vram: [0; 2048],
+            cart,
+            vram: [0; 4096],
             palette_table: [0; 32],
-            mirror: cart.mirror,
-            addr_reg: AddrRegister::new(),
+            mirror,
             ctrl_reg: CtrlRegister::new(),
             status_reg: StatusRegister::new(),
-            scroll_reg: ScrollRegister::new(),
             mask_reg: MaskRegister::new(),
+            loopy: LoopyRegister::new(),
             oam_data: [0; 256],
             oam_addr: 0,
+            secondary_oam: [0xFF; 32],
+            sprite_count: 0,
+            sprite_zero_in_line: false,
+            sprite_zero_hit_dot: None,
+            sprite_overflow_bug: false,
+            accurate_oam: false,
+            palette_hack: false,
+            ext_input: 0,
             data_buf: 0,
+            io_latch: 0,
+            io_latch_age: 0,
             nmi: false,
+            suppress_vblank: false,
+            frame_complete: false,
+            frame_number: 0,
             scanlines: 0,
             cycles: 0,
+            frame: new_frame(),
+            indexed_frame: new_indexed_frame(),
         }
     }
 
+    // The reset button clears PPUCTRL, PPUMASK, the scroll and the $2005/
+    // $2006 write toggle. Memory, OAMADDR and VRAM address are kept and the
+    // PPU doesn't stop, it is in the middle of a frame.
+    // Ref: https://wiki.nesdev.org/w/index.php/PPU_power_up_state
+    pub fn reset(&mut self) {
+        self.ctrl_reg = CtrlRegister::new();
+        self.mask_reg = MaskRegister::new();
+        self.loopy.t = 0;
+        self.loopy.fine_x = 0;
+        self.loopy.reset_latch();
+        self.data_buf = 0;
+        self.nmi = false;
+    }
+
+    // For deserialized PPUs, which share the bus's cartridge
+    #[cfg(feature = "serde")]
+    pub(crate) fn set_cartridge(&mut self, cart: Rc<RefCell<Cartridge>>) {
+        self.cart = cart;
+    }
+
+    // Back to the power on state, keeping the emulation settings
+    pub fn power_on(&mut self) {
+        *self = PPU {
+            sprite_overflow_bug: self.sprite_overflow_bug,
+            accurate_oam: self.accurate_oam,
+            palette_hack: self.palette_hack,
+            ext_input: self.ext_input,
+            ..PPU::new(self.cart.clone())
+        };
+    }
+
     pub fn tick(&mut self) {
         self.cycles += 1;
-        if self.cycles == 341 {
-            if self.is_sprite_zero_hit() {
-                self.status_reg.set_sprite_zero_hit(true);
+
+        if self.sprite_zero_hit_dot == Some(self.cycles) {
+            self.status_reg.set_sprite_zero_hit(true);
+            self.sprite_zero_hit_dot = None;
+        }
+
+        // The visible scanline is drawn once all its pixels would have been
+        // output, so register writes up to this dot are taken into account
+        if self.cycles == 256 && self.scanlines < NES_HEIGHT {
+            self.render_scanline();
+            // done during dots 65-256 on the real PPU, for the next line
+            self.evaluate_sprites();
+        }
+
+        // v is updated as the PPU fetches tiles, but only while rendering.
+        // Ref: https://wiki.nesdev.org/w/index.php/PPU_scrolling#At_dot_256_of_each_scanline
+        if self.is_rendering() {
+            match self.cycles {
+                256 => self.loopy.increment_y(),
+                257 => self.loopy.copy_horizontal(),
+                280 if self.scanlines == 261 => self.loopy.copy_vertical(),
+                _ => {}
             }
+        }
 
-            self.cycles = 0;
-            self.scanlines += 1;
+        // Ref: https://wiki.nesdev.org/w/index.php/PPU_registers#OAMADDR
+        if self.accurate_oam && self.is_rendering() {
+            if self.cycles == 1 && self.scanlines == 261 && self.oam_addr >= 8 {
+                // rendering starts with the row at OAMADDR, which gets
+                // copied over the first 8 bytes of OAM
+                let row = (self.oam_addr & 0xF8) as usize;
+                self.oam_data.copy_within(row..row + 8, 0);
+            }
+            // OAMADDR is used and left at 0 while fetching sprite tiles
+            if self.cycles == 257 {
+                self.oam_addr = 0;
+            }
+        }
 
-            if self.scanlines == 241 {
+        // Ref: https://wiki.nesdev.org/w/index.php/PPU_frame_timing#VBL_Flag_Timing
+        if self.cycles == 1 && self.scanlines == 241 {
+            if !self.suppress_vblank {
                 self.status_reg.set_vblank_started(true);
-                // the sprite zero hit flag should be erased upon entering VBLANK state
-                self.status_reg.set_sprite_zero_hit(false);
                 if self.ctrl_reg.is_generate_nmi() {
                     self.nmi = true;
                 }
             }
+            self.suppress_vblank = false;
+        }
+
+        // pre-render line
+        if self.cycles == 1 && self.scanlines == 261 {
+            self.status_reg.set_vblank_started(false);
+            self.status_reg.set_sprite_zero_hit(false);
+            self.status_reg.set_sprite_overflow(false);
+            self.nmi = false;
+        }
+
+        if self.cycles == 341 {
+            self.cycles = 0;
+            self.scanlines += 1;
 
             if self.scanlines == 262 {
                 self.scanlines = 0;
-                self.status_reg.set_vblank_started(false);
-                self.status_reg.set_sprite_zero_hit(false);
-                self.nmi = false;
+                self.frame_complete = true;
+                self.frame_number += 1;
+                // the latch decays after about 600ms
+                self.io_latch_age += 1;
+                if self.io_latch_age >= IO_LATCH_DECAY_FRAMES {
+                    self.io_latch = 0;
+                }
+                // there are no sprites on the first line
+                self.sprite_count = 0;
+                self.sprite_zero_in_line = false;
+            }
+
+            if self.scanlines < NES_HEIGHT {
+                self.sprite_zero_hit_dot = self.find_sprite_zero_hit();
             }
         }
     }
 
     pub fn cpu_read(&mut self, cpu_addr: u16) -> u8 {
-        match cpu_addr {
+        let value = match cpu_addr {
             0x2000..=0x3FFF => match cpu_addr & 0x0007 {
                 // Ctrl register (write-only)
-                0x0000 => 0,
+                0x0000 => self.io_latch,
                 // Mask register (write-only)
-                0x0001 => 0,
-                // Status register
-                0x0002 => self.read_status_reg(),
+                0x0001 => self.io_latch,
+                // Status register, the low 5 bits are not driven
+                0x0002 => (self.read_status_reg() & 0xE0) | (self.io_latch & 0x1F),
                 // OAM address register (write-only)
-                0x0003 => 0,
+                0x0003 => self.io_latch,
                 // OAM data register
                 0x0004 => self.read_oam_data(),
                 // Scroll register (write-only)
-                0x0005 => 0,
+                0x0005 => self.io_latch,
                 // PPU address register (write-only)
-                0x0006 => 0,
+                0x0006 => self.io_latch,
                 // PPU data register
                 0x0007 => self.read_data_reg(),
                 _ => panic!("impossible"),
             },
             _ => panic!("CPU read address {:04X?} not supported for PPU!", cpu_addr),
-        }
+        };
+        self.refresh_io_latch(value);
+        value
     }
 
     pub fn cpu_write(&mut self, cpu_addr: u16, value: u8) {
+        self.refresh_io_latch(value);
         match cpu_addr {
             0x2000..=0x3FFF => match cpu_addr & 0x0007 {
                 // Ctrl register
                 0x0000 => self.write_ctrl_reg(value),
                 // Mask register
                 0x0001 => self.write_mask_reg(value),
-                // Status register
-                0x0002 => panic!("PPU status register is not writable!"),
+                // Status register (read-only), only the I/O latch is updated
+                0x0002 => {}
                 // OAM address register
                 0x0003 => self.write_oam_addr(value),
                 // OAM data register
@@ -137,25 +327,35 @@ impl PPU {
         }
     }
 
+    fn refresh_io_latch(&mut self, value: u8) {
+        self.io_latch = value;
+        self.io_latch_age = 0;
+    }
+
     pub fn write_addr_reg(&mut self, value: u8) {
-        self.addr_reg.write(value);
+        self.loopy.write_addr(value);
     }
 
     pub fn write_ctrl_reg(&mut self, value: u8) {
+        let nmi_was_enabled = self.ctrl_reg.is_generate_nmi();
         self.ctrl_reg.write(value);
+        self.loopy.write_ctrl(value);
+        // NMI is edge triggered: enabling it during vblank causes one right away
+        if !nmi_was_enabled && self.ctrl_reg.is_generate_nmi() && self.status_reg.is_in_vblank() {
+            self.nmi = true;
+        }
     }
 
     pub fn read_data_reg(&mut self) -> u8 {
-        let addr = self.addr_reg.get() & 0x3FFF;
+        let addr = self.loopy.vram_addr();
         let buf = self.data_buf;
 
-        // reading data reg increases addr
-        self.addr_reg.inc(self.ctrl_reg.get_vram_addr_inc());
+        self.increment_vram_addr();
 
         match addr {
-            // CHR Rom
+            // pattern tables
             0..=0x1FFF => {
-                self.data_buf = self.chr_rom[addr as usize];
+                self.data_buf = self.fetch_chr(addr, cdl::CHR_READ);
                 buf
             }
             // VRAM
@@ -164,74 +364,57 @@ impl PPU {
                 self.data_buf = self.vram[self.get_mirrored_vram_addr(mirrored) as usize];
                 buf
             }
-            // reading from palette table is instant - internal buffer is not involved
-            0x3F00..=0x3FFF => {
-                // Addresses $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
-                // Addresses $3F04/$3F08/$3F0C can contain unique data,
-                // though these values are not used by the PPU when normally rendering
-                let mut mirrored = addr & 0b0000_0000_0001_1111;
-                if mirrored == 0x0010 {
-                    mirrored = 0x0000;
-                }
-                if mirrored == 0x0014 {
-                    mirrored = 0x0004;
-                }
-                if mirrored == 0x0018 {
-                    mirrored = 0x0008;
-                }
-                if mirrored == 0x001C {
-                    mirrored = 0x000C;
-                }
-                if self.mask_reg.grayscale() {
-                    self.palette_table[mirrored as usize] & 0x30
+            // reading from palette table is instant, but the buffer is still
+            // filled with the nametable byte under it at $2F00-$2FFF.
+            // Addresses are 14 bits, this is $3F00-$3FFF.
+            _ => {
+                let mirrored = addr & 0b0000_1111_1111_1111;
+                self.data_buf = self.vram[self.get_mirrored_vram_addr(mirrored) as usize];
+                let value = self.palette_table[mirror_palette_addr(addr)];
+                // palette entries are 6 bits, the top bits come from the I/O latch
+                let value = if self.mask_reg.grayscale() {
+                    value & 0x30
                 } else {
-                    self.palette_table[mirrored as usize] & 0x3F
-                }
+                    value & 0x3F
+                };
+                value | (self.io_latch & 0xC0)
             }
-            _ => panic!(
-                "reading PPU memory at address {:#06x} is not supported",
-                addr
-            ),
         }
     }
 
     pub fn write_data_reg(&mut self, value: u8) {
-        let addr = self.addr_reg.get();
+        let addr = self.loopy.vram_addr();
 
-        // writing data reg increases addr
-        self.addr_reg.inc(self.ctrl_reg.get_vram_addr_inc());
+        self.increment_vram_addr();
 
         match addr {
-            // CHR Rom
+            // pattern tables, ignored unless the cartridge has CHR RAM
             0..=0x1FFF => {
-                panic!("writing to CHR Rom is not supported")
+                self.cart.borrow_mut().ppu_write(addr, value);
             }
             // VRAM
             0x2000..=0x3EFF => {
                 let mirrored = addr & 0b0000_1111_1111_1111;
                 self.vram[self.get_mirrored_vram_addr(mirrored) as usize] = value;
             }
-            // palette table
-            0x3F00..=0x3FFF => {
-                let mut mirrored = addr & 0b0000_0000_0001_1111;
-                if mirrored == 0x0010 {
-                    mirrored = 0x0000;
-                }
-                if mirrored == 0x0014 {
-                    mirrored = 0x0004;
-                }
-                if mirrored == 0x0018 {
-                    mirrored = 0x0008;
-                }
-                if mirrored == 0x001C {
-                    mirrored = 0x000C;
-                }
-                self.palette_table[mirrored as usize] = value;
+            // palette table, $3F00-$3FFF
+            _ => {
+                self.palette_table[mirror_palette_addr(addr)] = value;
             }
-            _ => panic!(
-                "writing PPU memory at address {:#06x} is not supported",
-                addr
-            ),
+        }
+    }
+
+    // $2007 accesses move v to the next address, by 1 or 32. While
+    // rendering they glitch the scroll instead: coarse X and Y are both
+    // incremented, like when the PPU fetches tiles, which some games use
+    // for effects.
+    // Ref: https://wiki.nesdev.org/w/index.php/PPU_scrolling#$2007_reads_and_writes
+    fn increment_vram_addr(&mut self) {
+        if self.is_rendering() {
+            self.loopy.increment_x();
+            self.loopy.increment_y();
+        } else {
+            self.loopy.inc(self.ctrl_reg.get_vram_addr_inc());
         }
     }
 
@@ -257,23 +440,40 @@ impl PPU {
             (Mirror::Horizontal, 0)
             | (Mirror::Horizontal, 1)
             | (Mirror::Vertical, 0)
-            | (Mirror::Vertical, 2) => vram_idx_a,
+            | (Mirror::Vertical, 2)
+            | (Mirror::SingleScreenLo, _) => vram_idx_a,
             // B - the 2nd physical nametable
             (Mirror::Horizontal, 2)
             | (Mirror::Horizontal, 3)
             | (Mirror::Vertical, 1)
-            | (Mirror::Vertical, 3) => vram_idx_b,
-            // TODO more kinds of mirroring?
-            _ => logical_vram_idx,
+            | (Mirror::Vertical, 3)
+            | (Mirror::SingleScreenHi, _) => vram_idx_b,
+            // each nametable has its own memory
+            (Mirror::FourScreen, _) => logical_vram_idx,
+            _ => unreachable!(),
         }
     }
 
+    // Mappers can switch the nametable mirroring at runtime
+    pub fn set_mirroring(&mut self, mirror: Mirror) {
+        self.mirror = mirror;
+    }
+
     pub fn read_status_reg(&mut self) -> u8 {
+        // Reading $2002 races with the vblank flag being set on dot 1
+        if self.scanlines == 241 {
+            match self.cycles {
+                // reads as clear, and the flag won't be set for this frame
+                0 => self.suppress_vblank = true,
+                // reads as set, but no NMI happens
+                1 | 2 => self.nmi = false,
+                _ => {}
+            }
+        }
         let value = self.status_reg.read();
         // reading status register changes some status
         self.status_reg.set_vblank_started(false);
-        self.addr_reg.reset_latch();
-        self.scroll_reg.reset_latch();
+        self.loopy.reset_latch();
         value
     }
 
@@ -282,20 +482,45 @@ impl PPU {
     }
 
     pub fn write_scroll_reg(&mut self, value: u8) {
-        self.scroll_reg.write(value);
+        self.loopy.write_scroll(value);
     }
 
     pub fn write_oam_addr(&mut self, value: u8) {
         self.oam_addr = value;
     }
 
+    // Reads don't increment OAMADDR
     pub fn read_oam_data(&self) -> u8 {
-        self.oam_data[self.oam_addr as usize]
+        let value = self.oam_data[self.oam_addr as usize];
+        // bits 2-4 of the sprite attributes don't exist in OAM
+        if self.oam_addr & 0b11 == 2 {
+            value & 0xE3
+        } else {
+            value
+        }
     }
 
     pub fn write_oam_data(&mut self, value: u8) {
+        if self.accurate_oam && self.is_rendering() {
+            // the write doesn't happen, but OAMADDR moves to the next sprite
+            self.oam_addr = self.oam_addr.wrapping_add(4);
+            return;
+        }
         self.oam_data[self.oam_addr as usize] = value;
-        self.oam_addr += 1;
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
+
+    pub fn is_in_vblank(&self) -> bool {
+        self.status_reg.is_in_vblank()
+    }
+
+    // Whether a frame was completed since the last call
+    pub fn take_frame_complete(&mut self) -> bool {
+        std::mem::take(&mut self.frame_complete)
+    }
+
+    pub fn has_sprite_zero_hit(&self) -> bool {
+        self.status_reg.contains(StatusRegister::SPRITE_ZERO_HIT)
     }
 
     pub fn has_nmi(&self) -> bool {
@@ -306,88 +531,335 @@ impl PPU {
         self.nmi = false;
     }
 
-    pub fn render_ppu(&self, frame: &mut NesFrame) {
-        self.render_background(frame);
-        self.render_sprites(frame);
+    // The frame the PPU draws into, a scanline at a time as it ticks. Lines
+    // below the current scanline are still from the previous frame, the
+    // whole frame is finished once `take_frame_complete` returns true.
+    pub fn frame(&self) -> &NesFrame {
+        &self.frame
+    }
+
+    // `frame` before the colors are looked up, row-major: the system
+    // palette index in bits 0-5 and the emphasis bits (blue, green, red) in
+    // bits 6-8
+    pub fn indexed_frame(&self) -> &[u16] {
+        &self.indexed_frame
+    }
+
+    // Frames completed since power on
+    pub fn frame_number(&self) -> u64 {
+        self.frame_number
     }
 
-    pub fn render_background(&self, frame: &mut NesFrame) {
-        let scroll_x = (self.scroll_reg.scroll_x) as usize;
-        let scroll_y = (self.scroll_reg.scroll_y) as usize;
+    // Scanline the PPU is on, 261 is the pre-render line
+    pub fn scanline(&self) -> u32 {
+        self.scanlines
+    }
+
+    // Dot (PPU cycle) within the scanline
+    pub fn dot(&self) -> u32 {
+        self.cycles
+    }
+
+    pub fn set_accuracy(&mut self, accuracy: Accuracy) {
+        let hardware = accuracy == Accuracy::Hardware;
+        self.sprite_overflow_bug = hardware;
+        self.accurate_oam = hardware;
+        self.palette_hack = hardware;
+    }
+
+    // Boards that wire the EXT pins to something, the low 4 bits are used
+    pub fn set_ext_input(&mut self, value: u8) {
+        self.ext_input = value & 0x0F;
+    }
+
+    pub fn set_sprite_overflow_bug(&mut self, emulate: bool) {
+        self.sprite_overflow_bug = emulate;
+    }
+
+    pub fn set_accurate_oam(&mut self, accurate: bool) {
+        self.accurate_oam = accurate;
+    }
+
+    fn is_rendering_enabled(&self) -> bool {
+        self.mask_reg.show_background() || self.mask_reg.show_sprites()
+    }
+
+    // The PPU is fetching data for the visible lines
+    fn is_rendering(&self) -> bool {
+        self.is_rendering_enabled() && (self.scanlines < NES_HEIGHT || self.scanlines == 261)
+    }
 
-        let (main_nametable_addr, second_nametable_addr) =
-            match (&self.mirror, self.ctrl_reg.get_base_nametable_addr()) {
-                (Mirror::Vertical, 0x2000)
-                | (Mirror::Vertical, 0x2800)
-                | (Mirror::Horizontal, 0x2000)
-                | (Mirror::Horizontal, 0x2400) => (0x0000u16, 0x0400u16),
-                (Mirror::Vertical, 0x2400)
-                | (Mirror::Vertical, 0x2C00)
-                | (Mirror::Horizontal, 0x2800)
-                | (Mirror::Horizontal, 0x2C00) => (0x0400u16, 0x0000u16),
-                (_, _) => {
-                    panic!("Not supported mirroring type {:?}", self.mirror);
+    fn render_scanline(&mut self) {
+        let y = self.scanlines;
+        let backdrop = if self.ctrl_reg.is_master() {
+            0
+        } else {
+            self.ext_input
+        };
+        let mut line = [0u8; NES_WIDTH as usize];
+        if self.is_rendering_enabled() {
+            if self.mask_reg.show_background() {
+                self.render_background_line(&mut line);
+                if !self.mask_reg.show_leftmost_background() {
+                    line[..8].fill(0);
                 }
+            }
+            if self.mask_reg.show_sprites() {
+                self.render_sprites_line(y, &mut line);
+            }
+            if backdrop != 0 {
+                line.iter_mut()
+                    .filter(|idx| **idx == 0)
+                    .for_each(|idx| *idx = backdrop);
+            }
+        } else {
+            // With rendering disabled the PPU outputs the backdrop color,
+            // unless v points into the palette: then it outputs that entry.
+            let addr = self.loopy.vram_addr();
+            if self.palette_hack && addr >= 0x3F00 {
+                line.fill(mirror_palette_addr(addr) as u8);
+            } else {
+                line.fill(backdrop);
+            }
+        }
+
+        // greyscale only keeps the luminance column of the system palette
+        let color_mask = if self.mask_reg.grayscale() {
+            0x30
+        } else {
+            0x3F
+        };
+        let emphasis = self.mask_reg.emphasis();
+        let palette = &EMPHASIS_PALETTES[emphasis as usize];
+        for (x, palette_idx) in line.iter().enumerate() {
+            let color = self.palette_table[*palette_idx as usize] & color_mask;
+            let (r, g, b) = palette[color as usize];
+            self.frame.set_pixel(x as u32, y, r, g, b);
+            self.indexed_frame[(y * NES_WIDTH) as usize + x] =
+                color as u16 | (emphasis as u16) << 6;
+        }
+    }
+
+    // Fill `line` with indexes into the palette table. Background and
+    // sprite colors with color index 0 use the universal background color.
+    fn render_background_line(&self, line: &mut [u8; NES_WIDTH as usize]) {
+        let bank = self.ctrl_reg.get_background_pattern_table_bank() as u16;
+        // v points at the first tile of the line, fine x selects the pixel
+        // within it
+        let fine_y = self.loopy.fine_y();
+        let coarse_y = self.loopy.coarse_y();
+        let mut coarse_x = self.loopy.coarse_x();
+        let mut nametable = self.loopy.nametable();
+        let mut fine_x = self.loopy.fine_x as u16;
+
+        for pixel in line.iter_mut() {
+            let tile_addr = 0x2000 | (nametable << 10) | (coarse_y << 5) | coarse_x;
+            let tile_idx = self.vram[self.get_mirrored_vram_addr(tile_addr) as usize];
+            let color_idx = self.pattern_pixel(bank, tile_idx, fine_y, fine_x);
+            *pixel = if color_idx == 0 {
+                0
+            } else {
+                let attr_addr =
+                    0x23C0 | (nametable << 10) | ((coarse_y >> 2) << 3) | (coarse_x >> 2);
+                let block_attr = self.vram[self.get_mirrored_vram_addr(attr_addr) as usize];
+                // each attribute byte covers 4x4 tiles, 2 bits per 2x2 tiles
+                let shift = (coarse_y & 0b10) * 2 + (coarse_x & 0b10);
+                let palette_idx = (block_attr >> shift) & 0b11;
+                palette_idx * 4 + color_idx
             };
 
-        self.render_nametable(
-            frame,
-            main_nametable_addr,
-            &Rect::new(scroll_x, scroll_y, 256, 240),
-            -(scroll_x as i32),
-            -(scroll_y as i32),
-        );
-        if scroll_x > 0 {
-            self.render_nametable(
-                frame,
-                second_nametable_addr,
-                &Rect::new(0, 0, scroll_x, 240),
-                (256 - scroll_x) as i32,
-                0,
-            );
-        } else if scroll_y > 0 {
-            self.render_nametable(
-                frame,
-                second_nametable_addr,
-                &Rect::new(0, 0, 256, scroll_y),
-                0,
-                (240 - scroll_y) as i32,
-            );
-        }
-    }
-
-    fn render_nametable(
-        &self,
-        frame: &mut NesFrame,
-        nametable_addr: u16,
-        viewport: &Rect,
-        shift_x: i32,
-        shift_y: i32,
-    ) {
-        for tile_y in 0..30 {
-            for tile_x in 0..32 {
-                let tile_idx = self.vram
-                    [self.get_mirrored_vram_addr(nametable_addr + tile_y * 32 + tile_x) as usize];
-                let tile = self
-                    .load_tile(
-                        self.ctrl_reg.get_background_pattern_table_bank() as u8,
-                        tile_idx,
-                    )
-                    .unwrap();
-                let palette = self.load_bg_palette(nametable_addr, tile_x as u8, tile_y as u8);
-                self.render_tile(
-                    frame,
-                    false,
-                    tile_x as u32 * 8,
-                    tile_y as u32 * 8,
-                    &tile,
-                    &palette,
-                    viewport,
-                    shift_x,
-                    shift_y,
-                );
+            fine_x += 1;
+            if fine_x == 8 {
+                fine_x = 0;
+                // wrap into the horizontally adjacent nametable
+                coarse_x += 1;
+                if coarse_x == 32 {
+                    coarse_x = 0;
+                    nametable ^= 1;
+                }
+            }
+        }
+    }
+
+    // Copy the first 8 sprites in range of the next scanline to secondary
+    // OAM, and set the sprite overflow flag if there are more.
+    // Ref: https://wiki.nesdev.org/w/index.php/PPU_sprite_evaluation
+    fn evaluate_sprites(&mut self) {
+        self.sprite_count = 0;
+        self.sprite_zero_in_line = false;
+        self.secondary_oam = [0xFF; 32];
+        if !self.is_rendering_enabled() {
+            return;
+        }
+
+        let height = self.ctrl_reg.get_sprite_size() as u32;
+        // sprites are drawn one line lower than their OAM y coordinate, so
+        // the current scanline is compared with y for the next one
+        let scanline = self.scanlines;
+        let in_range = |y: u8| scanline.wrapping_sub(y as u32) < height;
+
+        let mut n = 0;
+        while n < 64 && self.sprite_count < 8 {
+            let sprite = &self.oam_data[n * 4..n * 4 + 4];
+            if in_range(sprite[0]) {
+                let i = self.sprite_count * 4;
+                self.secondary_oam[i..i + 4].copy_from_slice(sprite);
+                self.sprite_count += 1;
+                if n == 0 {
+                    self.sprite_zero_in_line = true;
+                }
+            }
+            n += 1;
+        }
+
+        let overflow = if self.sprite_overflow_bug {
+            // After 8 sprites, the PPU also increments the byte offset
+            // within each entry when a sprite is not in range, comparing
+            // tile indexes, attributes and x coordinates as if they were y.
+            let mut m = 0;
+            let mut found = false;
+            while n < 64 && !found {
+                found = in_range(self.oam_data[n * 4 + m]);
+                n += 1;
+                m = (m + 1) % 4;
+            }
+            found
+        } else {
+            (n..64).any(|n| in_range(self.oam_data[n * 4]))
+        };
+        if overflow {
+            self.status_reg.set_sprite_overflow(true);
+        }
+    }
+
+    fn render_sprites_line(&self, y: u32, line: &mut [u8; NES_WIDTH as usize]) {
+        // sprites with lower OAM index are drawn in front of the others
+        let mut drawn = [false; NES_WIDTH as usize];
+        for sprite in self.secondary_oam[..self.sprite_count * 4].chunks(4) {
+            let pixels = match self.sprite_line_pixels(sprite, y) {
+                Some(pixels) => pixels,
+                None => continue,
+            };
+            let attr = sprite[2];
+            let behind_background = attr & 0b0010_0000 != 0;
+            let palette_idx = attr & 0b11;
+
+            for (i, color_idx) in pixels.iter().enumerate() {
+                let x = sprite[3] as usize + i;
+                if x >= NES_WIDTH as usize {
+                    break;
+                }
+                if x < 8 && !self.mask_reg.show_leftmost_sprites() {
+                    continue;
+                }
+                if *color_idx == 0 || drawn[x] {
+                    continue;
+                }
+                // The first opaque sprite pixel wins even if it's behind the
+                // background, hiding sprites with a higher OAM index. Games
+                // use this to mask sprites (e.g. SMB3 mushrooms in blocks).
+                drawn[x] = true;
+                // the line only has background pixels where nothing is drawn
+                let background_opaque = line[x] != 0;
+                if behind_background && background_opaque {
+                    continue;
+                }
+                line[x] = 0x10 + palette_idx * 4 + color_idx;
+            }
+        }
+    }
+
+    // Color indexes of the 8 pixels of a sprite on scanline `y`, from left
+    // to right on screen. None if the sprite isn't on that line.
+    fn sprite_line_pixels(&self, sprite: &[u8], y: u32) -> Option<[u8; 8]> {
+        let height = self.ctrl_reg.get_sprite_size() as u32;
+        // sprites are drawn one line lower than their OAM y coordinate
+        let mut row = y.wrapping_sub(sprite[0] as u32 + 1);
+        if row >= height {
+            return None;
+        }
+        let mut tile_idx = sprite[1];
+        let attr = sprite[2];
+        let flip_vertical = attr & 0b1000_0000 != 0;
+        let flip_horizontal = attr & 0b0100_0000 != 0;
+
+        if flip_vertical {
+            row = height - 1 - row;
+        }
+        let bank = if height == 16 {
+            // 8x16 sprites pick the bank with bit 0 of the tile index
+            let bank = tile_idx & 1;
+            tile_idx &= 0xFE;
+            if row >= 8 {
+                tile_idx += 1;
+                row -= 8;
+            }
+            bank
+        } else {
+            self.ctrl_reg.get_sprite_pattern_table_bank()
+        };
+
+        let mut pixels = [0; 8];
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            let col = if flip_horizontal { 7 - i } else { i };
+            *pixel = self.pattern_pixel(bank as u16, tile_idx, row as u16, col as u16);
+        }
+        Some(pixels)
+    }
+
+    // Sprite 0 hit happens at the first opaque pixel of sprite 0 that
+    // overlaps an opaque background pixel. It's computed at the start of the
+    // line from the current scroll position, and the flag is set at the dot
+    // where that pixel is output.
+    // Ref: https://wiki.nesdev.org/w/index.php/PPU_OAM#Sprite_zero_hits
+    fn find_sprite_zero_hit(&self) -> Option<u32> {
+        if !self.sprite_zero_in_line
+            || !self.mask_reg.show_background()
+            || !self.mask_reg.show_sprites()
+        {
+            return None;
+        }
+        let pixels = self.sprite_line_pixels(&self.secondary_oam[0..4], self.scanlines)?;
+        let mut background = [0u8; NES_WIDTH as usize];
+        self.render_background_line(&mut background);
+        let clip_left =
+            !self.mask_reg.show_leftmost_background() || !self.mask_reg.show_leftmost_sprites();
+
+        for (i, color_idx) in pixels.iter().enumerate() {
+            let x = self.secondary_oam[3] as usize + i;
+            // no hit on the last pixel of the line
+            if x >= NES_WIDTH as usize - 1 {
+                break;
+            }
+            if clip_left && x < 8 {
+                continue;
+            }
+            if *color_idx != 0 && background[x] != 0 {
+                // pixel x is output at dot x + 1
+                return Some(x as u32 + 1);
             }
         }
+        None
+    }
+
+    // Pattern table byte, as currently mapped by the cartridge
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.cart.borrow_mut().ppu_read(addr).unwrap_or(0)
+    }
+
+    // The same for rendering or the CPU, which the code/data log records
+    fn fetch_chr(&self, addr: u16, flags: u8) -> u8 {
+        let mut cart = self.cart.borrow_mut();
+        cart.log_chr(addr, flags);
+        cart.ppu_read(addr).unwrap_or(0)
+    }
+
+    // Color index (0..=3) of a pixel in a pattern table tile
+    fn pattern_pixel(&self, bank: u16, tile_idx: u8, row: u16, col: u16) -> u8 {
+        let addr = bank * 0x1000 + tile_idx as u16 * 16 + row;
+        let low_bit = (self.fetch_chr(addr, cdl::CHR_DRAWN) >> (7 - col)) & 1;
+        let high_bit = (self.fetch_chr(addr + 8, cdl::CHR_DRAWN) >> (7 - col)) & 1;
+        (high_bit << 1) | low_bit
     }
 
     pub fn render_tile(
@@ -426,107 +898,17 @@ impl PPU {
         }
     }
 
-    pub fn render_sprites(&self, frame: &mut NesFrame) {
-        for sid in (0..self.oam_data.len()).step_by(4) {
-            // raw sprite info
-            let sprite_y = self.oam_data[sid];
-            let tile_idx = self.oam_data[sid + 1];
-            let attr = self.oam_data[sid + 2];
-            let sprite_x = self.oam_data[sid + 3];
-
-            // detailed attributes
-            let flip_vertical: bool = attr >> 7 == 1;
-            let flip_horizontal: bool = attr >> 6 == 1;
-            let palette_idx: u8 = attr & 0b11; // 0/1/2/3
-
-            let palette = self.load_sprite_palette(palette_idx);
-            let mut tile = self
-                .load_tile(
-                    self.ctrl_reg.get_sprite_pattern_table_bank() as u8,
-                    tile_idx,
-                )
-                .unwrap();
-            if flip_vertical {
-                tile.flip_vertical();
-            }
-            if flip_horizontal {
-                tile.flip_horizontal();
-            }
-            self.render_tile(
-                frame,
-                true,
-                sprite_x as u32,
-                sprite_y as u32,
-                &tile,
-                &palette,
-                &Rect::new(0, 0, 256, 240),
-                0,
-                0,
-            );
-        }
-    }
-
     pub fn load_tile(&self, bank: u8, tile_idx: u8) -> Result<Tile, String> {
         if bank != 0 && bank != 1 {
             return Err(format!("Wrong bank index: {}", bank));
         }
 
-        // Each CHR Rom bank is 4KB
-        let start = 4096 * bank as usize;
-        let end = 4096 * (bank + 1) as usize;
-        let bank_bytes: &[u8] = &self.chr_rom[start..end];
-
-        let low_bytes = &bank_bytes[(tile_idx as usize * 16)..(tile_idx as usize * 16 + 8)];
-        let high_bytes = &bank_bytes[(tile_idx as usize * 16 + 8)..(tile_idx as usize * 16 + 16)];
-        Ok(Tile::new(low_bytes, high_bytes).unwrap())
-    }
-
-    fn load_bg_palette(&self, nametable_addr: u16, tile_x: u8, tile_y: u8) -> Palette {
-        let attr_table_addr = nametable_addr + 960;
-        let block_x = tile_x / 4;
-        let block_y = tile_y / 4;
-        // the attribute table record for this block
-        let block_attr = self.vram[self
-            .get_mirrored_vram_addr(attr_table_addr + block_y as u16 * 8 + block_x as u16)
-            as usize];
-        // index of which palette (out of 4 possible palettes)
-        let logical_palette_idx: u8 = match ((tile_x % 4) / 2, (tile_y % 4) / 2) {
-            (0, 0) => (block_attr & 0b00_00_00_11) >> 0,
-            (1, 0) => (block_attr & 0b00_00_11_00) >> 2,
-            (0, 1) => (block_attr & 0b00_11_00_00) >> 4,
-            (1, 1) => (block_attr & 0b11_00_00_00) >> 6,
-            (_, _) => panic!("impossible!"),
-        };
-        let palette_arr_start = 1 + logical_palette_idx as usize * 4;
-        Palette {
-            colors: [
-                SYSTEM_PALETTE[self.palette_table[0] as usize],
-                SYSTEM_PALETTE[self.palette_table[palette_arr_start] as usize],
-                SYSTEM_PALETTE[self.palette_table[palette_arr_start + 1] as usize],
-                SYSTEM_PALETTE[self.palette_table[palette_arr_start + 2] as usize],
-            ],
-        }
-    }
-
-    fn load_sprite_palette(&self, palette_idx: u8) -> Palette {
-        let palette_arr_start: usize = 16 + 1 + palette_idx as usize * 4;
-        Palette {
-            colors: [
-                SYSTEM_PALETTE[self.palette_table[0] as usize],
-                SYSTEM_PALETTE[self.palette_table[palette_arr_start] as usize],
-                SYSTEM_PALETTE[self.palette_table[palette_arr_start + 1] as usize],
-                SYSTEM_PALETTE[self.palette_table[palette_arr_start + 2] as usize],
-            ],
-        }
-    }
-
-    fn is_sprite_zero_hit(&self) -> bool {
-        let y = self.oam_data[0];
-        let x = self.oam_data[3];
-        (y as u32 == self.scanlines)
-            && (x as u32 <= self.cycles)
-            && self.mask_reg.show_background()
-            && self.mask_reg.show_sprites()
+        // Each pattern table is 4KB, 16 bytes per tile
+        let start = 4096 * bank as u16 + tile_idx as u16 * 16;
+        let bytes: Vec<u8> = (start..start + 16)
+            .map(|addr| self.read_chr(addr))
+            .collect();
+        Ok(Tile::new(&bytes[0..8], &bytes[8..16]).unwrap())
     }
 
     pub fn print_debug_info(&self) {
@@ -542,6 +924,18 @@ impl PPU {
     }
 }
 
+// Index in the palette table of a $3F00-$3FFF address.
+// Addresses $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C.
+// Addresses $3F04/$3F08/$3F0C can contain unique data, though these values
+// are not used by the PPU when normally rendering.
+fn mirror_palette_addr(addr: u16) -> usize {
+    let mirrored = addr & 0x1F;
+    match mirrored {
+        0x10 | 0x14 | 0x18 | 0x1C => (mirrored - 0x10) as usize,
+        _ => mirrored as usize,
+    }
+}
+
 // ----------------------------------------------------------------------------
 // Rect
 // ----------------------------------------------------------------------------
@@ -635,17 +1029,107 @@ pub static SYSTEM_PALETTE: [(u8, u8, u8); 64] = [
     (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
 ];
 
+// SYSTEM_PALETTE for every combination of the mask register color emphasis
+// bits, indexed by mask bits 5-7 (red, green, blue).
+pub static EMPHASIS_PALETTES: [[(u8, u8, u8); 64]; 8] = build_emphasis_palettes(&SYSTEM_PALETTE);
+
+// Each emphasis bit darkens the other two color channels by about 25%, the
+// emphasized channel keeps its level.
+// Ref: https://wiki.nesdev.org/w/index.php/NTSC_video#Color_Tint_Bits
+const fn build_emphasis_palettes(palette: &[(u8, u8, u8); 64]) -> [[(u8, u8, u8); 64]; 8] {
+    const fn attenuate(c: u8) -> u8 {
+        (c as u16 * 3 / 4) as u8
+    }
+
+    let mut palettes = [[(0, 0, 0); 64]; 8];
+    let mut emphasis = 0;
+    while emphasis < 8 {
+        let mut i = 0;
+        while i < 64 {
+            let (mut r, mut g, mut b) = palette[i];
+            if emphasis & 0b001 != 0 {
+                g = attenuate(g);
+                b = attenuate(b);
+            }
+            if emphasis & 0b010 != 0 {
+                r = attenuate(r);
+                b = attenuate(b);
+            }
+            if emphasis & 0b100 != 0 {
+                r = attenuate(r);
+                g = attenuate(g);
+            }
+            palettes[emphasis][i] = (r, g, b);
+            i += 1;
+        }
+        emphasis += 1;
+    }
+    palettes
+}
+
 pub struct Palette {
     pub colors: [(u8, u8, u8); 4],
 }
 
+impl SaveState for PPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.vram);
+        w.write_bytes(&self.palette_table);
+        w.write_u8(self.ctrl_reg.bits());
+        w.write_u8(self.status_reg.bits());
+        w.write_u8(self.mask_reg.bits());
+        self.loopy.save_state(w);
+        w.write_bytes(&self.oam_data);
+        w.write_u8(self.oam_addr);
+        w.write_bytes(&self.secondary_oam);
+        w.write_u8(self.sprite_count as u8);
+        w.write_bool(self.sprite_zero_in_line);
+        // dot 0 is never a hit
+        w.write_u32(self.sprite_zero_hit_dot.unwrap_or(0));
+        w.write_u8(self.data_buf);
+        w.write_u8(self.io_latch);
+        w.write_u32(self.io_latch_age);
+        w.write_bool(self.nmi);
+        w.write_bool(self.suppress_vblank);
+        w.write_u32(self.scanlines);
+        w.write_u32(self.cycles);
+        w.write_u64(self.frame_number);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.read_bytes(&mut self.vram)?;
+        r.read_bytes(&mut self.palette_table)?;
+        self.ctrl_reg = CtrlRegister::from_bits_truncate(r.read_u8()?);
+        self.status_reg = StatusRegister::from_bits_truncate(r.read_u8()?);
+        self.mask_reg = MaskRegister::from_bits_truncate(r.read_u8()?);
+        self.loopy.load_state(r)?;
+        r.read_bytes(&mut self.oam_data)?;
+        self.oam_addr = r.read_u8()?;
+        r.read_bytes(&mut self.secondary_oam)?;
+        self.sprite_count = (r.read_u8()? as usize).min(8);
+        self.sprite_zero_in_line = r.read_bool()?;
+        self.sprite_zero_hit_dot = match r.read_u32()? {
+            0 => None,
+            dot => Some(dot),
+        };
+        self.data_buf = r.read_u8()?;
+        self.io_latch = r.read_u8()?;
+        self.io_latch_age = r.read_u32()?;
+        self.nmi = r.read_bool()?;
+        self.suppress_vblank = r.read_bool()?;
+        self.scanlines = r.read_u32()? % 262;
+        self.cycles = r.read_u32()? % 341;
+        self.frame_number = r.read_u64()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
     fn new_ppu() -> PPU {
-        let cart = Cartridge::new_dummy();
-        PPU::new(&cart)
+        PPU::new(Rc::new(RefCell::new(Cartridge::new_dummy())))
     }
 
     #[test]
@@ -668,7 +1152,7 @@ mod test {
         ppu.write_addr_reg(0x05);
 
         ppu.read_data_reg(); // load_into_buffer
-        assert_eq!(ppu.addr_reg.get(), 0x2306);
+        assert_eq!(ppu.loopy.vram_addr(), 0x2306);
         assert_eq!(ppu.read_data_reg(), 0x66);
     }
 
@@ -796,6 +1280,23 @@ mod test {
         // assert_eq!(ppu.addr.read(), 0x0306)
     }
 
+    #[test]
+    fn test_read_palette_fills_buffer() {
+        let mut ppu = new_ppu();
+        ppu.mirror = Mirror::Vertical;
+        // $2F05 is in the 4th nametable, the 2nd with vertical mirroring
+        ppu.vram[0x0705] = 0x66;
+        ppu.palette_table[0x05] = 0x12;
+
+        ppu.write_addr_reg(0x3F);
+        ppu.write_addr_reg(0x05);
+        assert_eq!(ppu.read_data_reg(), 0x12);
+        // the buffer has the byte under the palette, not the palette entry
+        ppu.write_addr_reg(0x20);
+        ppu.write_addr_reg(0x00);
+        assert_eq!(ppu.read_data_reg(), 0x66);
+    }
+
     #[test]
     fn test_read_status_resets_vblank() {
         let mut ppu = new_ppu();
@@ -806,4 +1307,529 @@ mod test {
         assert_eq!(status >> 7, 1);
         assert_eq!(ppu.status_reg.read() >> 7, 0);
     }
+
+    const BLACK: [u8; 3] = [0x05, 0x05, 0x05];
+    const WHITE: [u8; 3] = [0xFF, 0xFF, 0xFF];
+    const RED: [u8; 3] = [0xFF, 0x22, 0x00];
+
+    // Tile 1 is solid color 1, nametable A is filled with it and nametable B
+    // is blank. Color 1 is white, the backdrop is black.
+    fn new_ppu_with_split_nametables() -> PPU {
+        let mut ppu = new_ppu();
+        ppu.mirror = Mirror::Vertical;
+        for row in 0..8 {
+            ppu.cart.borrow_mut().ppu_write(16 + row, 0xFF);
+        }
+        for tile in ppu.vram[0..960].iter_mut() {
+            *tile = 1;
+        }
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[1] = 0x30;
+        ppu
+    }
+
+    // Tick until the start of the next occurrence of `scanline`
+    fn tick_to(ppu: &mut PPU, scanline: u32) {
+        ppu.tick();
+        while ppu.scanlines != scanline || ppu.cycles != 0 {
+            ppu.tick();
+        }
+    }
+
+    #[test]
+    fn test_frame_complete_without_vblank() {
+        let mut ppu = new_ppu_with_split_nametables();
+        tick_to(&mut ppu, 0);
+        assert!(ppu.take_frame_complete());
+        // reading $2002 as vblank starts suppresses the flag, the frame
+        // still ends
+        tick_to(&mut ppu, 241);
+        ppu.read_status_reg();
+        ppu.tick();
+        ppu.tick();
+        assert_eq!(ppu.read_status_reg() & 0x80, 0);
+        assert!(!ppu.take_frame_complete());
+        tick_to(&mut ppu, 0);
+        assert!(ppu.take_frame_complete());
+    }
+
+    #[test]
+    fn test_mid_frame_scroll_change() {
+        let mut ppu = new_ppu_with_split_nametables();
+        ppu.write_mask_reg(0x0A); // show background, including the left column
+        tick_to(&mut ppu, 119);
+        // switch to nametable B for the bottom half of the screen, this
+        // takes effect on the next line
+        ppu.write_ctrl_reg(0x01);
+        tick_to(&mut ppu, 241);
+
+        assert_eq!(ppu.frame().pixel(10, 119), WHITE);
+        assert_eq!(ppu.frame().pixel(10, 120), BLACK);
+
+        // fine horizontal scroll shows part of both nametables on a line
+        ppu.write_ctrl_reg(0x00);
+        ppu.write_scroll_reg(252);
+        ppu.write_scroll_reg(0);
+        tick_to(&mut ppu, 241);
+        assert_eq!(ppu.frame().pixel(3, 10), WHITE);
+        assert_eq!(ppu.frame().pixel(4, 10), BLACK);
+    }
+
+    #[test]
+    fn test_sprite_rendering() {
+        let mut ppu = new_ppu_with_split_nametables();
+        ppu.vram[0..960].iter_mut().for_each(|tile| *tile = 0);
+        ppu.palette_table[0x11] = 0x16; // red
+        ppu.palette_table[0x15] = 0x30; // white
+        ppu.write_mask_reg(0x18); // show background and sprites
+
+        // sprite 0 at (16, 21), sprite 1 overlaps it from (20, 21)
+        ppu.oam_data[0..8].copy_from_slice(&[20, 1, 0, 16, 20, 1, 1, 20]);
+        tick_to(&mut ppu, 241);
+
+        assert_eq!(ppu.frame().pixel(16, 20), BLACK);
+        assert_eq!(ppu.frame().pixel(16, 21), RED);
+        assert_eq!(ppu.frame().pixel(23, 28), RED);
+        assert_eq!(ppu.frame().pixel(24, 28), WHITE);
+        assert_eq!(ppu.frame().pixel(24, 29), BLACK);
+    }
+
+    #[test]
+    fn test_sprite_priority() {
+        let mut ppu = new_ppu_with_split_nametables();
+        // background only in the top left tile
+        ppu.vram[1..960].iter_mut().for_each(|tile| *tile = 0);
+        ppu.palette_table[0x11] = 0x16; // red
+        ppu.write_mask_reg(0x1E);
+
+        // sprite 0 behind the background at (4, 1), sprite 1 in front of the
+        // background at the same position
+        ppu.oam_data[0..8].copy_from_slice(&[0, 1, 0x20, 4, 0, 1, 0, 4]);
+        tick_to(&mut ppu, 241);
+
+        // the background covers sprite 0, which also hides sprite 1
+        assert_eq!(ppu.frame().pixel(7, 1), WHITE);
+        // sprite 0 shows through the transparent background
+        assert_eq!(ppu.frame().pixel(8, 1), RED);
+    }
+
+    #[test]
+    fn test_sprite_limit() {
+        let mut ppu = new_ppu_with_split_nametables();
+        ppu.vram[0..960].iter_mut().for_each(|tile| *tile = 0);
+        ppu.palette_table[0x11] = 0x16; // red
+        ppu.write_mask_reg(0x18);
+
+        // 9 sprites side by side on lines 11-18
+        for i in 0..9 {
+            ppu.oam_data[i * 4..i * 4 + 4].copy_from_slice(&[10, 1, 0, i as u8 * 8]);
+        }
+        // move the rest of the sprites off screen
+        for i in 9..64 {
+            ppu.oam_data[i * 4] = 0xFF;
+        }
+        tick_to(&mut ppu, 10);
+        assert!(!ppu.status_reg.contains(StatusRegister::SPRITE_OVERFLOW));
+        tick_to(&mut ppu, 11);
+        assert!(ppu.status_reg.contains(StatusRegister::SPRITE_OVERFLOW));
+        tick_to(&mut ppu, 241);
+
+        assert_eq!(ppu.frame().pixel(63, 11), RED);
+        // the 9th sprite is dropped
+        assert_eq!(ppu.frame().pixel(64, 11), BLACK);
+
+        // the flag is cleared at the end of vblank
+        tick_to(&mut ppu, 0);
+        assert!(!ppu.status_reg.contains(StatusRegister::SPRITE_OVERFLOW));
+    }
+
+    #[test]
+    fn test_sprite_overflow_bug() {
+        let mut ppu = new_ppu();
+        ppu.write_mask_reg(0x18);
+        ppu.set_accuracy(Accuracy::Hardware);
+        for i in 0..64 {
+            ppu.oam_data[i * 4..i * 4 + 4].copy_from_slice(&[0xFF, 0, 0, 0]);
+        }
+        for i in 0..8 {
+            ppu.oam_data[i * 4] = 10;
+        }
+        // the buggy check reads the x coordinate of sprite 11 as its y
+        ppu.oam_data[11 * 4 + 3] = 10;
+        tick_to(&mut ppu, 11);
+        assert!(ppu.status_reg.contains(StatusRegister::SPRITE_OVERFLOW));
+
+        // sprite 9 is on the next line, but the bug skips it
+        tick_to(&mut ppu, 0);
+        ppu.oam_data[9 * 4] = 10;
+        ppu.oam_data[11 * 4 + 3] = 0;
+        tick_to(&mut ppu, 11);
+        assert!(!ppu.status_reg.contains(StatusRegister::SPRITE_OVERFLOW));
+
+        // the correct check finds it
+        ppu.set_accuracy(Accuracy::Correct);
+        tick_to(&mut ppu, 0);
+        tick_to(&mut ppu, 11);
+        assert!(ppu.status_reg.contains(StatusRegister::SPRITE_OVERFLOW));
+    }
+
+    #[test]
+    fn test_sprite_zero_hit() {
+        let mut ppu = new_ppu_with_split_nametables();
+        // background only in tile (2, 2)
+        ppu.vram[0..960].iter_mut().for_each(|tile| *tile = 0);
+        ppu.vram[2 * 32 + 2] = 1;
+        ppu.write_mask_reg(0x1E);
+
+        // sprite 0 overlaps the tile from (20, 20)
+        ppu.oam_data[0..4].copy_from_slice(&[19, 1, 0, 20]);
+        tick_to(&mut ppu, 20);
+        while ppu.cycles < 20 {
+            ppu.tick();
+            assert!(!ppu.status_reg.contains(StatusRegister::SPRITE_ZERO_HIT));
+        }
+        ppu.tick();
+        assert!(ppu.status_reg.contains(StatusRegister::SPRITE_ZERO_HIT));
+
+        // the flag is cleared at the end of vblank
+        tick_to(&mut ppu, 0);
+        assert!(!ppu.status_reg.contains(StatusRegister::SPRITE_ZERO_HIT));
+
+        // no hit when the sprite is moved off the tile
+        ppu.oam_data[3] = 24;
+        tick_to(&mut ppu, 241);
+        assert!(!ppu.status_reg.contains(StatusRegister::SPRITE_ZERO_HIT));
+
+        // or when the overlap is in the clipped left column
+        ppu.vram[2 * 32 + 2] = 0;
+        ppu.vram[2 * 32] = 1;
+        ppu.oam_data[3] = 4;
+        ppu.write_mask_reg(0x1C);
+        tick_to(&mut ppu, 241);
+        assert!(!ppu.status_reg.contains(StatusRegister::SPRITE_ZERO_HIT));
+        ppu.write_mask_reg(0x1E);
+        tick_to(&mut ppu, 241);
+        assert!(ppu.status_reg.contains(StatusRegister::SPRITE_ZERO_HIT));
+    }
+
+    #[test]
+    fn test_mask_toggles() {
+        let mut ppu = new_ppu_with_split_nametables();
+        ppu.palette_table[0x11] = 0x16; // red
+        ppu.oam_data[0..4].copy_from_slice(&[0, 1, 0, 0]);
+
+        // background and sprites, without the left column
+        ppu.write_mask_reg(0x18);
+        tick_to(&mut ppu, 241);
+        assert_eq!(ppu.frame().pixel(7, 1), BLACK);
+        assert_eq!(ppu.frame().pixel(8, 1), WHITE);
+
+        // background only, sprites are hidden even in the left column
+        ppu.write_mask_reg(0x0E);
+        tick_to(&mut ppu, 241);
+        assert_eq!(ppu.frame().pixel(0, 1), WHITE);
+        assert_eq!(ppu.frame().pixel(0, 0), WHITE);
+
+        // sprites only
+        ppu.write_mask_reg(0x16);
+        tick_to(&mut ppu, 241);
+        assert_eq!(ppu.frame().pixel(0, 1), RED);
+        assert_eq!(ppu.frame().pixel(8, 1), BLACK);
+
+        // rendering disabled shows the backdrop color
+        ppu.write_mask_reg(0x00);
+        tick_to(&mut ppu, 241);
+        assert_eq!(ppu.frame().pixel(0, 1), BLACK);
+        assert_eq!(ppu.frame().pixel(8, 1), BLACK);
+    }
+
+    #[test]
+    fn test_backdrop() {
+        let mut ppu = new_ppu_with_split_nametables();
+        ppu.palette_table[0x05] = 0x16; // red
+        ppu.write_addr_reg(0x3F);
+        ppu.write_addr_reg(0x05);
+        tick_to(&mut ppu, 241);
+        assert_eq!(ppu.frame().pixel(0, 1), BLACK);
+
+        // the background palette hack
+        ppu.set_accuracy(Accuracy::Hardware);
+        tick_to(&mut ppu, 241);
+        assert_eq!(ppu.frame().pixel(0, 1), RED);
+
+        // EXT pins in slave mode, nametable B is blank
+        ppu.set_ext_input(0x05);
+        ppu.write_addr_reg(0x24);
+        ppu.write_addr_reg(0x00);
+        tick_to(&mut ppu, 241);
+        assert_eq!(ppu.frame().pixel(0, 1), RED);
+        ppu.write_ctrl_reg(0x41);
+        ppu.write_mask_reg(0x0A);
+        tick_to(&mut ppu, 241);
+        assert_eq!(ppu.frame().pixel(0, 1), BLACK);
+        ppu.write_ctrl_reg(0x01);
+        tick_to(&mut ppu, 241);
+        assert_eq!(ppu.frame().pixel(0, 1), RED);
+    }
+
+    #[test]
+    fn test_greyscale_and_emphasis() {
+        let mut ppu = new_ppu_with_split_nametables();
+        ppu.palette_table[1] = 0x16; // red
+
+        ppu.write_mask_reg(0x0B); // greyscale
+        tick_to(&mut ppu, 241);
+        let grey = SYSTEM_PALETTE[0x10];
+        assert_eq!(ppu.frame().pixel(10, 10), [grey.0, grey.1, grey.2]);
+
+        ppu.write_mask_reg(0x4A); // emphasize green
+        tick_to(&mut ppu, 241);
+        assert_eq!(ppu.frame().pixel(10, 10), [0xBF, 0x22, 0x00]);
+
+        // emphasis applies on top of greyscale
+        ppu.write_mask_reg(0xEB);
+        tick_to(&mut ppu, 241);
+        let (r, g, b) = EMPHASIS_PALETTES[0b111][0x10];
+        assert_eq!(ppu.frame().pixel(10, 10), [r, g, b]);
+        assert!(r < grey.0);
+    }
+
+    #[test]
+    fn test_emphasis_palettes() {
+        assert_eq!(EMPHASIS_PALETTES[0], SYSTEM_PALETTE);
+        // white (0x30) with red emphasis
+        assert_eq!(EMPHASIS_PALETTES[0b001][0x30], (0xFF, 0xBF, 0xBF));
+        // all bits darken every channel twice
+        assert_eq!(EMPHASIS_PALETTES[0b111][0x30], (0x8F, 0x8F, 0x8F));
+    }
+
+    #[test]
+    fn test_vram_single_screen_mirror() {
+        let mut ppu = new_ppu();
+        ppu.set_mirroring(Mirror::SingleScreenHi);
+
+        ppu.write_addr_reg(0x20);
+        ppu.write_addr_reg(0x05);
+        ppu.write_data_reg(0x66);
+        for addr in [0x2005u16, 0x2405, 0x2805, 0x2C05].iter() {
+            assert_eq!(ppu.get_mirrored_vram_addr(*addr), 0x0405);
+        }
+
+        // the other nametable is visible after switching
+        ppu.set_mirroring(Mirror::SingleScreenLo);
+        ppu.write_addr_reg(0x2C);
+        ppu.write_addr_reg(0x05);
+        ppu.read_data_reg();
+        assert_eq!(ppu.read_data_reg(), 0x00);
+    }
+
+    #[test]
+    fn test_vram_four_screen() {
+        let mut ppu = new_ppu();
+        ppu.set_mirroring(Mirror::FourScreen);
+
+        for (i, addr) in [0x2005u16, 0x2405, 0x2805, 0x2C05].iter().enumerate() {
+            ppu.write_addr_reg((*addr >> 8) as u8);
+            ppu.write_addr_reg(*addr as u8);
+            ppu.write_data_reg(i as u8 + 1);
+        }
+        for (i, addr) in [0x2005u16, 0x2405, 0x2805, 0x3C05].iter().enumerate() {
+            ppu.write_addr_reg((*addr >> 8) as u8);
+            ppu.write_addr_reg(*addr as u8);
+            ppu.read_data_reg(); // load into buffer
+            assert_eq!(ppu.read_data_reg(), i as u8 + 1);
+        }
+    }
+
+    #[test]
+    fn test_chr_bank_switch_is_visible() {
+        let mut ppu = new_ppu_with_split_nametables();
+        ppu.write_mask_reg(0x0A);
+        tick_to(&mut ppu, 241);
+        assert_eq!(ppu.frame().pixel(10, 10), WHITE);
+
+        // the cartridge changes the tile data after the PPU was created
+        for row in 0..8 {
+            ppu.cart.borrow_mut().ppu_write(16 + row, 0x00);
+        }
+        tick_to(&mut ppu, 241);
+        assert_eq!(ppu.frame().pixel(10, 10), BLACK);
+
+        // CHR RAM is writable through $2007
+        ppu.write_addr_reg(0x00);
+        ppu.write_addr_reg(0x10);
+        ppu.write_data_reg(0x80);
+        assert_eq!(ppu.cart.borrow_mut().ppu_read(0x0010), Some(0x80));
+    }
+
+    #[test]
+    fn test_open_bus() {
+        let mut ppu = new_ppu();
+        ppu.cpu_write(0x2003, 0x5A);
+        // write-only registers return the last value on the bus
+        assert_eq!(ppu.cpu_read(0x2000), 0x5A);
+        assert_eq!(ppu.cpu_read(0x2005), 0x5A);
+
+        ppu.status_reg.set_vblank_started(true);
+        ppu.cpu_write(0x2002, 0x1F);
+        assert_eq!(ppu.cpu_read(0x2002), 0x9F);
+        // the status read refreshed the latch
+        assert_eq!(ppu.cpu_read(0x2006), 0x9F);
+
+        // palette reads only drive the low 6 bits
+        ppu.cpu_write(0x2006, 0x3F);
+        ppu.cpu_write(0x2006, 0x00);
+        ppu.cpu_write(0x2007, 0x21);
+        ppu.cpu_write(0x2006, 0x3F);
+        ppu.cpu_write(0x2006, 0x00);
+        assert_eq!(ppu.cpu_read(0x2007), 0x21);
+        ppu.cpu_write(0x2006, 0x3F);
+        ppu.cpu_write(0x2006, 0x00);
+        ppu.cpu_write(0x2002, 0xC0);
+        assert_eq!(ppu.cpu_read(0x2007), 0xE1);
+
+        // the latch decays when nothing refreshes it
+        for _ in 0..IO_LATCH_DECAY_FRAMES {
+            tick_to(&mut ppu, 0);
+        }
+        assert_eq!(ppu.cpu_read(0x2000), 0x00);
+    }
+
+    #[test]
+    fn test_oam_data() {
+        let mut ppu = new_ppu();
+        ppu.write_oam_addr(0xFE);
+        ppu.write_oam_data(0xFF);
+        ppu.write_oam_data(0x12);
+        ppu.write_oam_data(0x34);
+        // OAMADDR wraps around
+        assert_eq!(ppu.oam_addr, 0x01);
+        assert_eq!(ppu.oam_data[0x00], 0x34);
+
+        // reads don't increment OAMADDR, and unused attribute bits read as 0
+        ppu.write_oam_addr(0xFE);
+        assert_eq!(ppu.read_oam_data(), 0xE3);
+        assert_eq!(ppu.read_oam_data(), 0xE3);
+        ppu.write_oam_addr(0xFF);
+        assert_eq!(ppu.read_oam_data(), 0x12);
+    }
+
+    #[test]
+    fn test_accurate_oam() {
+        let mut ppu = new_ppu();
+        ppu.set_accurate_oam(true);
+        ppu.write_mask_reg(0x18);
+        for (i, byte) in ppu.oam_data.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        // writes during rendering only bump OAMADDR to the next sprite
+        tick_to(&mut ppu, 10);
+        ppu.write_oam_addr(0x21);
+        ppu.write_oam_data(0xAA);
+        assert_eq!(ppu.oam_addr, 0x25);
+        assert_eq!(ppu.oam_data[0x21], 0x21);
+
+        // and OAMADDR is reset after the line
+        tick_to(&mut ppu, 11);
+        assert_eq!(ppu.oam_addr, 0x00);
+
+        // writes in vblank work as usual
+        tick_to(&mut ppu, 241);
+        ppu.write_oam_addr(0x21);
+        ppu.write_oam_data(0xAA);
+        assert_eq!(ppu.oam_data[0x21], 0xAA);
+
+        // OAMADDR isn't 0 when rendering starts
+        tick_to(&mut ppu, 0);
+        assert_eq!(
+            ppu.oam_data[0..8],
+            [0x20, 0xAA, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27]
+        );
+    }
+
+    // Tick until the PPU is at the given dot
+    fn tick_to_dot(ppu: &mut PPU, scanline: u32, dot: u32) {
+        ppu.tick();
+        while ppu.scanlines != scanline || ppu.cycles != dot {
+            ppu.tick();
+        }
+    }
+
+    #[test]
+    fn test_data_access_during_rendering() {
+        let mut ppu = new_ppu();
+        ppu.write_mask_reg(0x08);
+        tick_to_dot(&mut ppu, 10, 100);
+        ppu.write_addr_reg(0x20);
+        ppu.write_addr_reg(0x1F);
+        ppu.write_data_reg(0x66);
+        assert_eq!(ppu.vram[0x1F], 0x66);
+        // coarse X wraps into the next nametable, fine Y goes from 2 to 3
+        assert_eq!(ppu.loopy.v, 0x3400);
+        ppu.read_data_reg();
+        assert_eq!(ppu.loopy.v, 0x4401);
+
+        // +1 in vblank
+        tick_to(&mut ppu, 241);
+        ppu.write_addr_reg(0x20);
+        ppu.write_addr_reg(0x1F);
+        ppu.write_data_reg(0x66);
+        assert_eq!(ppu.loopy.v, 0x2020);
+    }
+
+    #[test]
+    fn test_vblank_nmi() {
+        let mut ppu = new_ppu();
+        ppu.write_ctrl_reg(0x80);
+        tick_to_dot(&mut ppu, 241, 0);
+        assert!(!ppu.is_in_vblank());
+        ppu.tick();
+        assert!(ppu.is_in_vblank());
+        assert!(ppu.has_nmi());
+        ppu.reset_nmi();
+
+        // enabling NMI during vblank triggers it immediately
+        ppu.write_ctrl_reg(0x00);
+        assert!(!ppu.has_nmi());
+        ppu.write_ctrl_reg(0x80);
+        assert!(ppu.has_nmi());
+        ppu.reset_nmi();
+        // but only on the transition
+        ppu.write_ctrl_reg(0x80);
+        assert!(!ppu.has_nmi());
+
+        // vblank ends on the pre-render line
+        tick_to_dot(&mut ppu, 261, 1);
+        assert!(!ppu.is_in_vblank());
+        ppu.write_ctrl_reg(0x00);
+        ppu.write_ctrl_reg(0x80);
+        assert!(!ppu.has_nmi());
+    }
+
+    #[test]
+    fn test_status_read_races_vblank() {
+        let mut ppu = new_ppu();
+        ppu.write_ctrl_reg(0x80);
+
+        // one dot before: the flag is never set and there is no NMI
+        tick_to_dot(&mut ppu, 241, 0);
+        assert_eq!(ppu.read_status_reg() & 0x80, 0);
+        ppu.tick();
+        assert!(!ppu.is_in_vblank());
+        assert!(!ppu.has_nmi());
+
+        // on the same dot: the flag reads as set but NMI is suppressed
+        tick_to_dot(&mut ppu, 241, 1);
+        assert!(ppu.has_nmi());
+        assert_eq!(ppu.read_status_reg() & 0x80, 0x80);
+        assert!(!ppu.has_nmi());
+
+        // later reads only clear the flag
+        tick_to(&mut ppu, 0);
+        tick_to_dot(&mut ppu, 241, 3);
+        assert!(ppu.has_nmi());
+        assert_eq!(ppu.read_status_reg() & 0x80, 0x80);
+        assert!(ppu.has_nmi());
+    }
 }
@@ -17,6 +17,7 @@ bitflags! {
    // |          (0: read backdrop from EXT pins; 1: output color on EXT pins)
    // +--------- Generate an NMI at the start of the
    //            vertical blanking interval (0: off; 1: on)
+   #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
    pub struct CtrlRegister: u8 {
        const NAMETABLE1               = 0b00000001;
        const NAMETABLE2               = 0b00000010;
@@ -72,9 +73,24 @@ impl CtrlRegister {
         }
     }
 
+    // Sprite height in pixels
+    pub fn get_sprite_size(&self) -> u8 {
+        if self.contains(CtrlRegister::SPRITE_SIZE) {
+            16
+        } else {
+            8
+        }
+    }
+
     pub fn is_generate_nmi(&self) -> bool {
         self.contains(CtrlRegister::GENERATE_NMI)
     }
+
+    // Master mode outputs colors on the EXT pins, slave mode reads the
+    // backdrop from them
+    pub fn is_master(&self) -> bool {
+        self.contains(CtrlRegister::MASTER_SLAVE_SELECT)
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,264 @@
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+// The PPU's internal scroll/address registers, named after loopy who
+// documented them. $2005 (scroll) and $2006 (address) are two views of the
+// same registers and share the write toggle.
+//
+// v and t are 15 bits:
+//   yyy NN YYYYY XXXXX
+//   ||| || ||||| +++++-- coarse X scroll
+//   ||| || +++++-------- coarse Y scroll
+//   ||| ++-------------- nametable select
+//   +++----------------- fine Y scroll
+//
+// Ref: https://wiki.nesdev.org/w/index.php/PPU_scrolling
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LoopyRegister {
+    // current VRAM address
+    pub v: u16,
+    // temporary VRAM address, the top left onscreen tile
+    pub t: u16,
+    // fine X scroll (3 bits)
+    pub fine_x: u8,
+    // write toggle, false for the first write
+    pub w: bool,
+}
+
+const COARSE_X: u16 = 0x001F;
+const COARSE_Y: u16 = 0x03E0;
+const NAMETABLE_X: u16 = 0x0400;
+const NAMETABLE_Y: u16 = 0x0800;
+const FINE_Y: u16 = 0x7000;
+
+impl LoopyRegister {
+    pub fn new() -> Self {
+        LoopyRegister {
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            w: false,
+        }
+    }
+
+    // $2000 write: nametable select
+    pub fn write_ctrl(&mut self, value: u8) {
+        self.t = (self.t & !(NAMETABLE_X | NAMETABLE_Y)) | (((value & 0b11) as u16) << 10);
+    }
+
+    // $2005 write: X scroll first, then Y scroll
+    pub fn write_scroll(&mut self, value: u8) {
+        if !self.w {
+            self.t = (self.t & !COARSE_X) | (value >> 3) as u16;
+            self.fine_x = value & 0b111;
+        } else {
+            self.t = (self.t & !(COARSE_Y | FINE_Y))
+                | (((value >> 3) as u16) << 5)
+                | (((value & 0b111) as u16) << 12);
+        }
+        self.w = !self.w;
+    }
+
+    // $2006 write: high byte first, then low byte which also updates v
+    pub fn write_addr(&mut self, value: u8) {
+        if !self.w {
+            // bit 14 of t is cleared
+            self.t = (self.t & 0x00FF) | (((value & 0x3F) as u16) << 8);
+        } else {
+            self.t = (self.t & 0xFF00) | value as u16;
+            self.v = self.t;
+        }
+        self.w = !self.w;
+    }
+
+    // $2002 read
+    pub fn reset_latch(&mut self) {
+        self.w = false;
+    }
+
+    // Address used for $2007 accesses
+    pub fn vram_addr(&self) -> u16 {
+        self.v & 0x3FFF
+    }
+
+    // $2007 accesses outside of rendering increment v by 1 or 32
+    pub fn inc(&mut self, delta: u8) {
+        self.v = self.v.wrapping_add(delta as u16) & 0x7FFF;
+    }
+
+    pub fn coarse_x(&self) -> u16 {
+        self.v & COARSE_X
+    }
+
+    pub fn coarse_y(&self) -> u16 {
+        (self.v & COARSE_Y) >> 5
+    }
+
+    pub fn fine_y(&self) -> u16 {
+        (self.v & FINE_Y) >> 12
+    }
+
+    // 0..=3
+    pub fn nametable(&self) -> u16 {
+        (self.v & (NAMETABLE_X | NAMETABLE_Y)) >> 10
+    }
+
+    // Move v to the next tile, wrapping into the next nametable
+    // horizontally after column 31
+    pub fn increment_x(&mut self) {
+        if self.v & COARSE_X == COARSE_X {
+            self.v &= !COARSE_X;
+            self.v ^= NAMETABLE_X;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    // Move v to the next pixel row, wrapping into the next nametable
+    // vertically after row 29. Rows 30 and 31 (attribute data) wrap to 0
+    // without switching nametables.
+    pub fn increment_y(&mut self) {
+        if self.v & FINE_Y != FINE_Y {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !FINE_Y;
+        let mut coarse_y = self.coarse_y();
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= NAMETABLE_Y;
+        } else if coarse_y == 31 {
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !COARSE_Y) | (coarse_y << 5);
+    }
+
+    // Start of a scanline: horizontal position t -> v
+    pub fn copy_horizontal(&mut self) {
+        let mask = COARSE_X | NAMETABLE_X;
+        self.v = (self.v & !mask) | (self.t & mask);
+    }
+
+    // Start of a frame: vertical position t -> v
+    pub fn copy_vertical(&mut self) {
+        let mask = COARSE_Y | NAMETABLE_Y | FINE_Y;
+        self.v = (self.v & !mask) | (self.t & mask);
+    }
+}
+
+impl Default for LoopyRegister {
+    fn default() -> Self {
+        LoopyRegister::new()
+    }
+}
+
+impl SaveState for LoopyRegister {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.v);
+        w.write_u16(self.t);
+        w.write_u8(self.fine_x);
+        w.write_bool(self.w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.v = r.read_u16()? & 0x7FFF;
+        self.t = r.read_u16()? & 0x7FFF;
+        self.fine_x = r.read_u8()? & 0b111;
+        self.w = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_addr() {
+        let mut loopy = LoopyRegister::new();
+        assert_eq!(loopy.vram_addr(), 0x0000);
+
+        loopy.write_addr(0x12);
+        // v is only updated by the second write
+        assert_eq!(loopy.vram_addr(), 0x0000);
+        loopy.write_addr(0x34);
+        assert_eq!(loopy.vram_addr(), 0x1234);
+
+        loopy.write_addr(0x56);
+        loopy.write_addr(0x78);
+        assert_eq!(loopy.vram_addr(), 0x1678);
+    }
+
+    #[test]
+    fn test_inc() {
+        let mut loopy = LoopyRegister::new();
+        loopy.write_addr(0x12);
+        loopy.write_addr(0x34);
+        loopy.inc(1);
+        assert_eq!(loopy.vram_addr(), 0x1235);
+
+        loopy.write_addr(0x3f);
+        loopy.write_addr(0xff);
+        loopy.inc(1);
+        assert_eq!(loopy.vram_addr(), 0x0000);
+
+        loopy.inc(32);
+        assert_eq!(loopy.vram_addr(), 0x0020);
+    }
+
+    #[test]
+    fn test_scroll_and_addr_share_registers() {
+        let mut loopy = LoopyRegister::new();
+        loopy.write_ctrl(0b10);
+        // X = 0x7D, Y = 0x5E
+        loopy.write_scroll(0x7D);
+        loopy.write_scroll(0x5E);
+        assert_eq!(loopy.fine_x, 0b101);
+        // fine y 6, nametable 2, coarse y 11, coarse x 15
+        assert_eq!(loopy.t, 0x696F);
+
+        // $2005 followed by $2006 uses the same toggle, this is how games
+        // set a full scroll position mid-frame
+        loopy.write_addr(0x04);
+        loopy.write_scroll(0x20);
+        assert!(!loopy.w);
+        // nametable 1, coarse y 4, coarse x unchanged
+        assert_eq!(loopy.t, 0x048F);
+        // the second $2005 write doesn't update v
+        assert_eq!(loopy.v, 0);
+
+        loopy.write_addr(0x3F);
+        loopy.reset_latch();
+        loopy.write_addr(0x21);
+        loopy.write_addr(0x00);
+        assert_eq!(loopy.vram_addr(), 0x2100);
+    }
+
+    #[test]
+    fn test_increment_x() {
+        let mut loopy = LoopyRegister::new();
+        loopy.v = 0x001E;
+        loopy.increment_x();
+        assert_eq!(loopy.coarse_x(), 31);
+        // next nametable to the right
+        loopy.increment_x();
+        assert_eq!(loopy.v, 0x0400);
+    }
+
+    #[test]
+    fn test_increment_y() {
+        let mut loopy = LoopyRegister::new();
+        loopy.v = 0x73A0; // fine y 7, coarse y 29
+        loopy.increment_y();
+        // next nametable down
+        assert_eq!(loopy.v, 0x0800);
+
+        loopy.v = 0x73E0; // fine y 7, coarse y 31
+        loopy.increment_y();
+        assert_eq!(loopy.v, 0);
+
+        loopy.increment_y();
+        assert_eq!(loopy.fine_y(), 1);
+    }
+}
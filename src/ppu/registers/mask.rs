@@ -13,6 +13,7 @@ bitflags! {
     // ||+------- Emphasize red
     // |+-------- Emphasize green
     // +--------- Emphasize blue
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct MaskRegister: u8 {
         const GREYSCALE                 = 0b00000001;
         const SHOW_LEFTMOST_BACKGROUND  = 0b00000010;
@@ -42,6 +43,11 @@ impl MaskRegister {
         self.contains(MaskRegister::GREYSCALE)
     }
 
+    // Emphasis bits as a 3-bit number: blue, green, red
+    pub fn emphasis(&self) -> u8 {
+        self.bits >> 5
+    }
+
     pub fn show_background(&self) -> bool {
         self.contains(MaskRegister::SHOW_BACKGROUND)
     }
@@ -49,4 +55,12 @@ impl MaskRegister {
     pub fn show_sprites(&self) -> bool {
         self.contains(MaskRegister::SHOW_SPRITES)
     }
+
+    pub fn show_leftmost_background(&self) -> bool {
+        self.contains(MaskRegister::SHOW_LEFTMOST_BACKGROUND)
+    }
+
+    pub fn show_leftmost_sprites(&self) -> bool {
+        self.contains(MaskRegister::SHOW_LEFTMOST_SPRITE)
+    }
 }
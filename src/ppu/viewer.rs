@@ -0,0 +1,266 @@
+use std::fmt;
+
+use super::{Palette, Rect, PPU, SYSTEM_PALETTE};
+use crate::graphics::{NesFrame, NES_HEIGHT, NES_WIDTH};
+
+// Debug views of the PPU memory, drawn into a NesFrame. They read pattern
+// tables through the cartridge and colors from palette RAM every time, so
+// they follow bank switching and CHR RAM writes as the game runs.
+
+// Width and height of one pattern table in pixels, 16x16 tiles
+pub const PATTERN_TABLE_SIZE: u32 = 128;
+// Background palettes are 0-3, sprite palettes 4-7
+pub const NUM_PALETTES: u8 = 8;
+// The sprite view is a grid of 8x8 cells, one per OAM entry
+pub const SPRITE_CELL_WIDTH: u32 = NES_WIDTH / 8;
+pub const SPRITE_CELL_HEIGHT: u32 = NES_HEIGHT / 8;
+
+// An OAM entry, decoded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpriteInfo {
+    pub index: u8,
+    // top left corner as stored in OAM, drawn one line lower
+    pub x: u8,
+    pub y: u8,
+    pub tile: u8,
+    // pattern table the tile comes from, for 8x16 sprites bit 0 of `tile`
+    pub bank: u8,
+    // 8 or 16
+    pub height: u8,
+    // sprite palette, 4-7
+    pub palette: u8,
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+    pub behind_background: bool,
+}
+
+impl fmt::Display for SpriteInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}: x {:3} y {:3} tile {:02X} bank {} 8x{:<2} palette {} {}{}{}",
+            self.index,
+            self.x,
+            self.y,
+            self.tile,
+            self.bank,
+            self.height,
+            self.palette,
+            if self.flip_horizontal { "H" } else { "-" },
+            if self.flip_vertical { "V" } else { "-" },
+            if self.behind_background {
+                " behind"
+            } else {
+                ""
+            },
+        )
+    }
+}
+
+impl PPU {
+    // Palette `idx` as currently loaded in palette RAM
+    pub fn loaded_palette(&self, idx: u8) -> Palette {
+        let base = (idx % NUM_PALETTES) as usize * 4;
+        let mut colors = [(0, 0, 0); 4];
+        for (i, color) in colors.iter_mut().enumerate() {
+            // color 0 of every palette shows the backdrop
+            let entry = if i == 0 { 0 } else { base + i };
+            *color = SYSTEM_PALETTE[(self.palette_table[entry] & 0x3F) as usize];
+        }
+        Palette { colors }
+    }
+
+    // Both pattern tables side by side, $0000 on the left and $1000 on the
+    // right, colored with loaded palette `palette`
+    pub fn render_pattern_tables(&self, frame: &mut NesFrame, palette: u8) {
+        let palette = self.loaded_palette(palette);
+        let viewport = Rect::new(0, 0, NES_WIDTH as usize, NES_HEIGHT as usize);
+        for bank in 0..2 {
+            for tile_idx in 0..=255u8 {
+                let tile = self.load_tile(bank, tile_idx).unwrap();
+                let x = bank as u32 * PATTERN_TABLE_SIZE + (tile_idx as u32 % 16) * 8;
+                let y = (tile_idx as u32 / 16) * 8;
+                self.render_tile(frame, false, x, y, &tile, &palette, &viewport, 0, 0);
+            }
+        }
+    }
+
+    // All 64 OAM entries
+    pub fn sprites(&self) -> Vec<SpriteInfo> {
+        let height = self.ctrl_reg.get_sprite_size();
+        self.oam_data
+            .chunks(4)
+            .enumerate()
+            .map(|(index, sprite)| {
+                let attr = sprite[2];
+                SpriteInfo {
+                    index: index as u8,
+                    x: sprite[3],
+                    y: sprite[0],
+                    tile: sprite[1],
+                    bank: if height == 16 {
+                        sprite[1] & 1
+                    } else {
+                        self.ctrl_reg.get_sprite_pattern_table_bank()
+                    },
+                    height,
+                    palette: 4 + (attr & 0b11),
+                    flip_horizontal: attr & 0b0100_0000 != 0,
+                    flip_vertical: attr & 0b1000_0000 != 0,
+                    behind_background: attr & 0b0010_0000 != 0,
+                }
+            })
+            .collect()
+    }
+
+    // The two nametables in RAM as text, a line per row of tiles. Test
+    // ROMs print with tiles numbered like ASCII, other tiles show as
+    // spaces.
+    pub fn nametable_text(&self) -> String {
+        let mut lines = vec![];
+        for nametable in self.vram[..0x800].chunks(0x400) {
+            // the attribute table follows the 30 rows
+            for row in nametable[..0x3C0].chunks(32) {
+                let line: String = row
+                    .iter()
+                    .map(|&tile| match tile {
+                        0x20..=0x7E => tile as char,
+                        _ => ' ',
+                    })
+                    .collect();
+                lines.push(line.trim_end().to_string());
+            }
+        }
+        lines.join("\n")
+    }
+
+    // Every sprite in its own cell, in OAM order from left to right and top
+    // to bottom, flipped and colored like on screen. 8x8 sprites are drawn
+    // at twice their size. Transparent pixels show the backdrop.
+    pub fn render_sprites(&self, frame: &mut NesFrame) {
+        let backdrop = SYSTEM_PALETTE[(self.palette_table[0] & 0x3F) as usize];
+        for y in 0..NES_HEIGHT {
+            for x in 0..NES_WIDTH {
+                frame.set_pixel(x, y, backdrop.0, backdrop.1, backdrop.2);
+            }
+        }
+
+        for (sprite, info) in self.oam_data.chunks(4).zip(self.sprites()) {
+            let palette = self.loaded_palette(info.palette);
+            let scale = if info.height == 8 { 2 } else { 1 };
+            let cell_x = (info.index as u32 % 8) * SPRITE_CELL_WIDTH;
+            let cell_y = (info.index as u32 / 8) * SPRITE_CELL_HEIGHT;
+            let left = cell_x + (SPRITE_CELL_WIDTH - 8 * scale) / 2;
+            let top = cell_y + (SPRITE_CELL_HEIGHT - info.height as u32 * scale) / 2;
+            for row in 0..info.height as u32 {
+                let line = sprite[0] as u32 + 1 + row;
+                let pixels = self.sprite_line_pixels(sprite, line).unwrap();
+                for (col, color_idx) in pixels.iter().enumerate() {
+                    if *color_idx == 0 {
+                        continue;
+                    }
+                    let (r, g, b) = palette.colors[*color_idx as usize];
+                    for dy in 0..scale {
+                        for dx in 0..scale {
+                            let x = left + col as u32 * scale + dx;
+                            frame.set_pixel(x, top + row * scale + dy, r, g, b);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_pattern_tables() {
+        let mut ppu = PPU::new(Rc::new(RefCell::new(Cartridge::new_dummy())));
+        // tile $01 of bank 0: top row color 1; tile $FF of bank 1: color 3
+        ppu.cart.borrow_mut().ppu_write(0x0010, 0xFF);
+        for row in 0..8 {
+            ppu.cart.borrow_mut().ppu_write(0x1FF0 + row, 0xFF);
+            ppu.cart.borrow_mut().ppu_write(0x1FF8 + row, 0xFF);
+        }
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[0x01] = 0x30;
+        ppu.palette_table[0x1D] = 0x16;
+        ppu.palette_table[0x1F] = 0x2A;
+
+        let mut frame = NesFrame::new();
+        ppu.render_pattern_tables(&mut frame, 0);
+        assert_eq!(frame.pixel(8, 0), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(frame.pixel(8, 1), [0x05, 0x05, 0x05]);
+
+        // the sprite palettes share the backdrop
+        ppu.render_pattern_tables(&mut frame, 7);
+        assert_eq!(frame.pixel(255, 127), [0x2B, 0xF0, 0x35]);
+        assert_eq!(frame.pixel(0, 0), [0x05, 0x05, 0x05]);
+
+        // CHR RAM writes show up on the next render
+        ppu.cart.borrow_mut().ppu_write(0x0000, 0x80);
+        ppu.render_pattern_tables(&mut frame, 7);
+        assert_eq!(frame.pixel(0, 0), [0xFF, 0x22, 0x00]);
+    }
+
+    #[test]
+    fn test_nametable_text() {
+        let mut ppu = PPU::new(Rc::new(RefCell::new(Cartridge::new_dummy())));
+        ppu.vram[0x21..0x27].copy_from_slice(b"Passed");
+        ppu.vram[0x400] = b'#';
+        ppu.vram[0x401] = 0x80;
+        ppu.vram[0x402] = b'2';
+        let text = ppu.nametable_text();
+        let lines: Vec<&str> = text.split('\n').collect();
+        assert_eq!(lines.len(), 60);
+        assert_eq!(lines[1], " Passed");
+        assert_eq!(lines[30], "# 2");
+    }
+
+    #[test]
+    fn test_sprites() {
+        let mut ppu = PPU::new(Rc::new(RefCell::new(Cartridge::new_dummy())));
+        // tile $02: left column color 1
+        for row in 0..8 {
+            ppu.cart.borrow_mut().ppu_write(0x0020 + row, 0x80);
+        }
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[0x19] = 0x16;
+        // sprite 9: palette 6, flipped horizontally, behind the background
+        ppu.oam_data[36..40].copy_from_slice(&[0x20, 0x02, 0b0110_0010, 0x30]);
+
+        let sprites = ppu.sprites();
+        assert_eq!(sprites.len(), 64);
+        let sprite = sprites[9];
+        assert_eq!((sprite.x, sprite.y, sprite.tile), (0x30, 0x20, 0x02));
+        assert_eq!((sprite.bank, sprite.height, sprite.palette), (0, 8, 6));
+        assert!(sprite.flip_horizontal && !sprite.flip_vertical && sprite.behind_background);
+        assert_eq!(
+            sprite.to_string(),
+            "09: x  48 y  32 tile 02 bank 0 8x8  palette 6 H- behind"
+        );
+
+        // cell (1, 1), the 16x16 thumbnail is centered, the flip puts the
+        // column on the right
+        let mut frame = NesFrame::new();
+        ppu.render_sprites(&mut frame);
+        let (left, top) = (SPRITE_CELL_WIDTH + 8, SPRITE_CELL_HEIGHT + 7);
+        assert_eq!(frame.pixel(left + 14, top), [0xFF, 0x22, 0x00]);
+        assert_eq!(frame.pixel(left + 15, top + 15), [0xFF, 0x22, 0x00]);
+        assert_eq!(frame.pixel(left + 13, top), [0x05, 0x05, 0x05]);
+        assert_eq!(frame.pixel(left + 14, top + 16), [0x05, 0x05, 0x05]);
+
+        // 8x16 sprites take the bank from the tile index
+        ppu.write_ctrl_reg(0x20);
+        assert_eq!(ppu.sprites()[9].bank, 0);
+        ppu.oam_data[37] = 0x03;
+        let sprite = ppu.sprites()[9];
+        assert_eq!((sprite.bank, sprite.height), (1, 16));
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::symbols::Symbols;
+
+// Cycle accounting per subroutine, to find where 6502 code spends its time.
+// JSR, BRK and interrupts start a call of the routine they jump to, RTS and
+// RTI end it. Every cycle goes to the routine that is running (its self
+// cycles), and when a call ends its length goes to the routine's total.
+//
+// A call ends when a return brings the stack pointer back to where it was
+// before the call: a return that skips frames (PLA PLA RTS) ends them all,
+// and an RTS used as a jump (an address pushed and returned to) ends none.
+//
+// Routines are told apart by their address and 16K PRG ROM bank (the offset
+// in the ROM / $4000). Code in RAM, or behind mappers that don't tell where
+// their banks are, has no bank.
+
+const PRG_BANK_SIZE: usize = 0x4000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Routine {
+    pub addr: u16,
+    pub bank: Option<usize>,
+}
+
+impl Routine {
+    // `prg_rom_offset` is where `addr` is in the PRG ROM, if it is
+    pub fn new(addr: u16, prg_rom_offset: Option<usize>) -> Self {
+        Routine {
+            addr,
+            bank: prg_rom_offset.map(|offset| offset / PRG_BANK_SIZE),
+        }
+    }
+}
+
+// "$C123" or "$8000 bank 2"
+impl fmt::Display for Routine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "${:04X}", self.addr)?;
+        if let Some(bank) = self.bank {
+            write!(f, " bank {}", bank)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RoutineStats {
+    pub calls: u64,
+    // cycles in the routine itself
+    pub self_cycles: u64,
+    // cycles from calls to returns, with the routines it called. Recursive
+    // calls are counted once.
+    pub total_cycles: u64,
+}
+
+struct Call {
+    routine: Routine,
+    // the stack pointer before the call
+    sp: u8,
+    start: u32,
+}
+
+pub struct Profiler {
+    calls: Vec<Call>,
+    routines: BTreeMap<Routine, RoutineStats>,
+    // cycles outside of any call that was seen, like in a main loop jumped
+    // to from RESET
+    top_level_cycles: u64,
+    cycles: u64,
+    // the cycles after this one aren't accounted yet
+    last_cycle: u32,
+}
+
+impl Profiler {
+    // Start at CPU cycle `cycle`
+    pub fn new(cycle: u32) -> Self {
+        Profiler {
+            calls: vec![],
+            routines: BTreeMap::new(),
+            top_level_cycles: 0,
+            cycles: 0,
+            last_cycle: cycle,
+        }
+    }
+
+    // A jump to `routine` at `cycle`, with the stack pointer at `sp` before
+    // the return address was pushed
+    pub fn call(&mut self, routine: Routine, sp: u8, cycle: u32) {
+        self.sync(cycle);
+        self.routines.entry(routine).or_default().calls += 1;
+        self.calls.push(Call {
+            routine,
+            sp,
+            start: cycle,
+        });
+    }
+
+    // A return that ended at `cycle` with the stack pointer at `sp`
+    pub fn ret(&mut self, sp: u8, cycle: u32) {
+        self.sync(cycle);
+        while let Some(call) = self.calls.last() {
+            if call.sp > sp {
+                break;
+            }
+            let call = self.calls.pop().unwrap();
+            if !self.calls.iter().any(|c| c.routine == call.routine) {
+                let stats = self.routines.get_mut(&call.routine).unwrap();
+                stats.total_cycles += cycle.wrapping_sub(call.start) as u64;
+            }
+        }
+    }
+
+    // Account the cycles up to `cycle` to the running routine
+    pub fn sync(&mut self, cycle: u32) {
+        let elapsed = cycle.wrapping_sub(self.last_cycle) as u64;
+        match self.calls.last() {
+            Some(call) => self.routines.get_mut(&call.routine).unwrap().self_cycles += elapsed,
+            None => self.top_level_cycles += elapsed,
+        }
+        self.cycles += elapsed;
+        self.last_cycle = cycle;
+    }
+
+    // Cycles since profiling started
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn top_level_cycles(&self) -> u64 {
+        self.top_level_cycles
+    }
+
+    // Every routine called so far. The totals include the calls that are
+    // still running.
+    pub fn routines(&self) -> BTreeMap<Routine, RoutineStats> {
+        let mut routines = self.routines.clone();
+        for (i, call) in self.calls.iter().enumerate() {
+            if !self.calls[..i].iter().any(|c| c.routine == call.routine) {
+                routines.get_mut(&call.routine).unwrap().total_cycles +=
+                    self.last_cycle.wrapping_sub(call.start) as u64;
+            }
+        }
+        routines
+    }
+
+    // Self cycles of the routines in each bank
+    pub fn banks(&self) -> BTreeMap<Option<usize>, u64> {
+        let mut banks = BTreeMap::new();
+        for (routine, stats) in self.routines.iter() {
+            *banks.entry(routine.bank).or_insert(0) += stats.self_cycles;
+        }
+        banks
+    }
+
+    // The `count` routines with the most self cycles, then the banks. Routines
+    // are named with `symbols` when they have a name.
+    pub fn report(&self, count: usize, symbols: Option<&Symbols>) -> String {
+        let percent = |cycles: u64| cycles as f64 * 100.0 / self.cycles.max(1) as f64;
+        let mut routines: Vec<_> = self.routines().into_iter().collect();
+        routines.sort_by_key(|(routine, stats)| (std::cmp::Reverse(stats.self_cycles), *routine));
+
+        // the columns of the routines
+        let row = |self_cycles: &str, total_cycles: &str, calls: &str, routine: &str| {
+            format!(
+                "{:>17} {:>17} {:>7}  {}\n",
+                self_cycles, total_cycles, calls, routine
+            )
+        };
+        let cycles = |cycles: u64| format!("{} {:>5.1}%", cycles, percent(cycles));
+
+        let mut out = row("self", "total", "calls", "routine");
+        for (routine, stats) in routines.iter().take(count) {
+            let name = match symbols.and_then(|symbols| symbols.get(routine.addr)) {
+                Some(name) => format!("{} {}", routine, name),
+                None => routine.to_string(),
+            };
+            out.push_str(&row(
+                &cycles(stats.self_cycles),
+                &cycles(stats.total_cycles),
+                &stats.calls.to_string(),
+                &name,
+            ));
+        }
+        out.push_str(&row(&cycles(self.top_level_cycles), "", "", "top level"));
+        for (bank, bank_cycles) in self.banks() {
+            let bank = match bank {
+                Some(bank) => format!("bank {}", bank),
+                None => "no bank".to_string(),
+            };
+            out.push_str(&row(&cycles(bank_cycles), "", "", &bank));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::emulator::Emulator;
+
+    fn routine(addr: u16) -> Routine {
+        Routine::new(addr, Some(addr as usize - 0x8000))
+    }
+
+    #[test]
+    fn test_calls() {
+        let mut profiler = Profiler::new(100);
+        profiler.call(routine(0x8000), 0xFD, 110);
+        profiler.call(routine(0xC000), 0xFB, 120);
+        profiler.ret(0xFB, 150);
+        // PLA PLA RTS
+        profiler.call(routine(0xC000), 0xFB, 160);
+        profiler.ret(0xFD, 200);
+        assert_eq!(profiler.cycles(), 100);
+        assert_eq!(profiler.top_level_cycles(), 10);
+        let routines = profiler.routines();
+        let stats = routines[&routine(0x8000)];
+        assert_eq!(
+            (stats.calls, stats.self_cycles, stats.total_cycles),
+            (1, 20, 90)
+        );
+        let stats = routines[&routine(0xC000)];
+        assert_eq!(
+            (stats.calls, stats.self_cycles, stats.total_cycles),
+            (2, 70, 70)
+        );
+        assert_eq!(profiler.banks()[&Some(1)], 70);
+
+        // an RTS to a pushed address stays in the routine, which is still
+        // running
+        profiler.call(routine(0x8000), 0xFD, 210);
+        profiler.ret(0xFB, 220);
+        profiler.sync(230);
+        let stats = profiler.routines()[&routine(0x8000)];
+        assert_eq!(
+            (stats.calls, stats.self_cycles, stats.total_cycles),
+            (2, 40, 110)
+        );
+
+        // a recursive call only counts once in the total
+        profiler.call(routine(0x8000), 0xFB, 240);
+        profiler.ret(0xFB, 250);
+        profiler.ret(0xFD, 260);
+        let stats = profiler.routines()[&routine(0x8000)];
+        assert_eq!(
+            (stats.calls, stats.self_cycles, stats.total_cycles),
+            (3, 70, 140)
+        );
+    }
+
+    #[test]
+    fn test_emulator() {
+        let program = [
+            0x20, 0x09, 0x80, // 8000 JSR $8009
+            0x20, 0x0E, 0x80, // 8003 JSR $800E
+            0x4C, 0x00, 0x80, // 8006 JMP $8000
+            0xA2, 0x05, // 8009 LDX #5
+            0xCA, // 800B DEX
+            0xD0, 0xFD, // 800C BNE $800B
+            0x60, // 800E RTS
+        ];
+        let mut prg = program.to_vec();
+        prg.resize(0x3FFC, 0);
+        prg.extend_from_slice(&[0x00, 0x80]);
+        let mut emulator = Emulator::new(Cartridge::new_from_program(prg));
+        emulator.start_profiling();
+        emulator.run_frame().unwrap();
+        // both calls of the loop done
+        while emulator.cpu().registers().pc != 0x8006 {
+            emulator.cpu_mut().step().unwrap();
+        }
+        let profiler = emulator.profiler().unwrap();
+        let routines = profiler.routines();
+        let delay = routines[&Routine::new(0x8009, Some(0x09))];
+        let rts = routines[&Routine::new(0x800E, Some(0x0E))];
+        // JSR 6, LDX 2, 5 * DEX 2, 4 * BNE 3 + BNE 2, RTS 6
+        assert_eq!(delay.self_cycles, delay.calls * 38);
+        assert_eq!(delay.total_cycles, delay.self_cycles);
+        assert_eq!(rts.self_cycles, rts.calls * 12);
+        assert!(delay.calls > 100);
+        assert_eq!(rts.calls, delay.calls);
+
+        let report = profiler.report(1, None);
+        assert!(report.contains(" $8009 bank 0\n"), "{}", report);
+        assert!(!report.contains("$800E"));
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 4, "{}", report);
+        assert!(lines[2].ends_with("%                            top level"));
+        assert!(lines[3].ends_with("%                            bank 0"));
+        assert!(emulator.stop_profiling().is_some());
+        assert!(emulator.profiler().is_none());
+    }
+}
@@ -0,0 +1,93 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::settings::config_dir;
+
+// The ROMs opened last, newest first, kept in a text file with a path per
+// line. By default the file is recent_roms.txt in `settings::config_dir`.
+
+pub const MAX_RECENT_ROMS: usize = 9;
+const FILE_NAME: &str = "recent_roms.txt";
+
+pub struct RecentRoms {
+    file: PathBuf,
+    paths: Vec<PathBuf>,
+}
+
+impl RecentRoms {
+    // A missing or unreadable file is an empty list
+    pub fn load<P: AsRef<Path>>(file: P) -> Self {
+        let paths = fs::read_to_string(&file)
+            .unwrap_or_default()
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .take(MAX_RECENT_ROMS)
+            .map(PathBuf::from)
+            .collect();
+        RecentRoms {
+            file: file.as_ref().to_path_buf(),
+            paths,
+        }
+    }
+
+    pub fn default_file() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join(FILE_NAME))
+    }
+
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    // Move `rom` to the front, dropping the oldest entry when full
+    pub fn add<P: AsRef<Path>>(&mut self, rom: P) {
+        let rom = rom.as_ref();
+        let rom = rom.canonicalize().unwrap_or_else(|_| rom.to_path_buf());
+        self.paths.retain(|path| *path != rom);
+        self.paths.insert(0, rom);
+        self.paths.truncate(MAX_RECENT_ROMS);
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(dir) = self.file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut text = String::new();
+        for path in &self.paths {
+            text.push_str(&path.to_string_lossy());
+            text.push('\n');
+        }
+        fs::write(&self.file, text)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_recent_roms() {
+        let dir = env::temp_dir().join(format!("nes-recent-test-{}", std::process::id()));
+        let file = dir.join("config").join(FILE_NAME);
+        let mut recent = RecentRoms::load(&file);
+        assert!(recent.paths().is_empty());
+
+        for i in 0..MAX_RECENT_ROMS + 2 {
+            recent.add(format!("/roms/{}.nes", i));
+        }
+        recent.add("/roms/3.nes");
+        recent.save().unwrap();
+
+        let recent = RecentRoms::load(&file);
+        let paths = recent.paths();
+        assert_eq!(paths.len(), MAX_RECENT_ROMS);
+        assert_eq!(paths[0], Path::new("/roms/3.nes"));
+        assert_eq!(paths[1], Path::new("/roms/10.nes"));
+        // the two oldest were dropped
+        assert!(!paths.contains(&PathBuf::from("/roms/1.nes")));
+        assert_eq!(paths.iter().filter(|p| p.ends_with("3.nes")).count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::cartridge::Mirror;
+
+// Identifies games by the CRC-32 and SHA-1 of their PRG and CHR ROM, the
+// iNES header left out, and tells what the cartridge really is. Headers of
+// dumps found in the wild are often wrong (bad mapper numbers, garbage in
+// the unused bytes), a match in the database overrides them.
+//
+// The database is loaded from the XML of the NES 2.0 header database
+// (nes20db.xml), games look like:
+//
+//   <game>
+//     <!-- Super Mario Bros. (World) -->
+//     <rom size="40960" crc32="3337EC46" sha1="..."/>
+//     <pcb mapper="0" submapper="0" mirroring="V" battery="0"/>
+//     ...
+//   </game>
+
+lazy_static! {
+    static ref GAME_RE: Regex = Regex::new(r"(?s)<game>(.*?)</game>").unwrap();
+    static ref TITLE_RE: Regex = Regex::new(r"(?s)<!--\s*(.*?)\s*-->").unwrap();
+    static ref ROM_RE: Regex = Regex::new(r"<rom\s([^>]*)>").unwrap();
+    static ref PCB_RE: Regex = Regex::new(r"<pcb\s([^>]*)>").unwrap();
+    static ref ATTR_RE: Regex = Regex::new(r#"(\w+)="([^"]*)""#).unwrap();
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RomInfo {
+    // the name of the dump, like "Super Mario Bros. (World)"
+    pub title: Option<String>,
+    pub mapper: u16,
+    pub submapper: u8,
+    // None when the mapper controls it
+    pub mirroring: Option<Mirror>,
+    // battery backed RAM
+    pub battery: bool,
+}
+
+impl RomInfo {
+    // The family of boards of the mapper, e.g. NROM for NES-NROM-256
+    pub fn board(&self) -> Option<&'static str> {
+        let board = match self.mapper {
+            0 => "NROM",
+            1 => "SxROM (MMC1)",
+            2 => "UxROM",
+            3 => "CNROM",
+            4 => "TxROM (MMC3)",
+            5 => "ExROM (MMC5)",
+            7 => "AxROM",
+            9 => "PxROM (MMC2)",
+            10 => "FxROM (MMC4)",
+            11 => "Color Dreams",
+            66 => "GxROM",
+            71 => "Camerica",
+            _ => return None,
+        };
+        Some(board)
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    // to tell games with the same CRC-32 apart
+    sha1: Option<[u8; 20]>,
+    info: RomInfo,
+}
+
+#[derive(Debug, Default)]
+pub struct RomDb {
+    // by CRC-32
+    games: HashMap<u32, Vec<Entry>>,
+}
+
+impl RomDb {
+    pub fn new() -> Self {
+        RomDb::default()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<RomDb, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        RomDb::parse_nes20db(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse_nes20db(text: &str) -> Result<RomDb, String> {
+        let mut db = RomDb::new();
+        for (i, game) in GAME_RE.captures_iter(text).enumerate() {
+            let game = &game[1];
+            let error = |msg: &str| format!("game {}: {}", i + 1, msg);
+            let rom = attributes(&ROM_RE, game).ok_or_else(|| error("no <rom>"))?;
+            let pcb = attributes(&PCB_RE, game).ok_or_else(|| error("no <pcb>"))?;
+            let crc32 = rom
+                .get("crc32")
+                .and_then(|crc| u32::from_str_radix(crc, 16).ok())
+                .ok_or_else(|| error("invalid crc32"))?;
+            let sha1 = rom.get("sha1").and_then(|sha1| parse_sha1(sha1));
+            let mapper = pcb
+                .get("mapper")
+                .and_then(|mapper| mapper.parse().ok())
+                .ok_or_else(|| error("invalid mapper"))?;
+            let info = RomInfo {
+                title: TITLE_RE.captures(game).map(|title| title[1].to_string()),
+                mapper,
+                submapper: pcb
+                    .get("submapper")
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or(0),
+                mirroring: match pcb.get("mirroring").map(String::as_str) {
+                    Some("H") => Some(Mirror::Horizontal),
+                    Some("V") => Some(Mirror::Vertical),
+                    Some("4") => Some(Mirror::FourScreen),
+                    _ => None,
+                },
+                battery: pcb.get("battery").map(String::as_str) == Some("1"),
+            };
+            db.insert(crc32, sha1, info);
+        }
+        Ok(db)
+    }
+
+    pub fn insert(&mut self, crc32: u32, sha1: Option<[u8; 20]>, info: RomInfo) {
+        self.games
+            .entry(crc32)
+            .or_default()
+            .push(Entry { sha1, info });
+    }
+
+    // The game with these hashes of its PRG and CHR ROM. Entries without a
+    // SHA-1 match on the CRC-32 alone.
+    pub fn lookup(&self, crc32: u32, sha1: &[u8; 20]) -> Option<&RomInfo> {
+        self.games
+            .get(&crc32)?
+            .iter()
+            .find(|entry| entry.sha1.is_none_or(|entry_sha1| entry_sha1 == *sha1))
+            .map(|entry| &entry.info)
+    }
+
+    pub fn len(&self) -> usize {
+        self.games.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.games.is_empty()
+    }
+}
+
+// The name="value" pairs of the first tag `re` finds
+fn attributes(re: &Regex, text: &str) -> Option<HashMap<String, String>> {
+    let tag = re.captures(text)?;
+    Some(
+        ATTR_RE
+            .captures_iter(&tag[1])
+            .map(|attr| (attr[1].to_string(), attr[2].to_string()))
+            .collect(),
+    )
+}
+
+fn parse_sha1(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return None;
+    }
+    let mut sha1 = [0; 20];
+    for (i, byte) in sha1.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(sha1)
+}
+
+pub fn sha1_hex(sha1: &[u8; 20]) -> String {
+    sha1.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+// SHA-1, the hash ROM databases key games by along with the CRC-32
+// Ref: https://www.rfc-editor.org/rfc/rfc3174
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [
+        0x6745_2301,
+        0xEFCD_AB89,
+        0x98BA_DCFE,
+        0x1032_5476,
+        0xC3D2_E1F0,
+    ];
+    // the message is padded with 0x80, zeros and its length in bits to a
+    // multiple of 64 bytes
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (bytes, word) in digest.chunks_mut(4).zip(h.iter()) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sha1() {
+        assert_eq!(
+            sha1_hex(&sha1(b"")),
+            "DA39A3EE5E6B4B0D3255BFEF95601890AFD80709"
+        );
+        assert_eq!(
+            sha1_hex(&sha1(b"abc")),
+            "A9993E364706816ABA3E25717850C26C9CD0D89D"
+        );
+        // more than one block
+        assert_eq!(
+            sha1_hex(&sha1(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "84983E441C3BD26EBAAE4AA1F95129E5E54670F1"
+        );
+    }
+
+    #[test]
+    fn test_parse_nes20db() {
+        let text = r#"<?xml version="1.0" encoding="UTF-8"?>
+<nes20db date="2024-01-01">
+  <game>
+    <!-- Super Mario Bros. (World) -->
+    <prgrom size="32768" crc32="5CF548D3" sha1="0000000000000000000000000000000000000000"/>
+    <rom size="40960" crc32="3337EC46" sha1="EA343F4E445A9050D4B4FBAC2C77D0693B1D0922"/>
+    <pcb mapper="0" submapper="0" mirroring="V" battery="0"/>
+  </game>
+  <game>
+    <!-- Zelda -->
+    <rom size="131072" crc32="D7AE93DF"/>
+    <pcb mapper="1" mirroring="1" battery="1"/>
+  </game>
+</nes20db>"#;
+        let db = RomDb::parse_nes20db(text).unwrap();
+        assert_eq!(db.len(), 2);
+
+        let smb_sha1 = parse_sha1("EA343F4E445A9050D4B4FBAC2C77D0693B1D0922").unwrap();
+        let smb = db.lookup(0x3337EC46, &smb_sha1).unwrap();
+        assert_eq!(smb.title.as_deref(), Some("Super Mario Bros. (World)"));
+        assert_eq!(smb.mirroring, Some(Mirror::Vertical));
+        assert_eq!(smb.board(), Some("NROM"));
+        // same CRC-32, different SHA-1
+        assert_eq!(db.lookup(0x3337EC46, &[0; 20]), None);
+
+        let zelda = db.lookup(0xD7AE93DF, &[0; 20]).unwrap();
+        assert_eq!(
+            (zelda.mapper, zelda.mirroring, zelda.battery),
+            (1, None, true)
+        );
+
+        assert!(
+            RomDb::parse_nes20db("<game><rom crc32=\"zz\"/><pcb mapper=\"0\"/></game>").is_err()
+        );
+    }
+}
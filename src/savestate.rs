@@ -0,0 +1,321 @@
+// Save states are a hand-rolled little-endian binary format:
+//
+//   "NESS" magic | u16 version | CPU | Bus (RAM, DMA, PPU, APU, cartridge, joypads)
+//
+// Every component writes its fields in a fixed order, so any change to a
+// component's layout must bump SAVE_STATE_VERSION.
+
+const SAVE_STATE_MAGIC: &[u8; 4] = b"NESS";
+pub const SAVE_STATE_VERSION: u16 = 12;
+
+pub trait SaveState {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String>;
+}
+
+// CRC-32 (IEEE), to identify ROMs and compare states cheaply. Netplay
+// hashes states while running, a byte at a time through the table is fast
+// enough for that.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc = (crc >> 8) ^ CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize];
+    }
+    !crc
+}
+
+// The CRC of each byte value
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+// Checksums of the parts of a machine's state, to compare runs without
+// keeping whole save states around
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StateHash {
+    // registers and RAM
+    pub cpu: u32,
+    pub ppu: u32,
+    pub apu: u32,
+    // the whole save state, including the cartridge, DMA and joypads
+    pub all: u32,
+}
+
+// ----------------------------------------------------------------------------
+// StateWriter
+// ----------------------------------------------------------------------------
+
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter::new_with_header(SAVE_STATE_MAGIC, SAVE_STATE_VERSION)
+    }
+
+    // For other files using the same encoding, e.g. movies
+    pub fn new_with_header(magic: &[u8; 4], version: u16) -> Self {
+        StateWriter::new_in(vec![], magic, version)
+    }
+
+    // Writes into `buf`, reusing its memory, for when states are saved
+    // over and over like netplay rollback does
+    pub fn new_in(mut buf: Vec<u8>, magic: &[u8; 4], version: u16) -> Self {
+        buf.clear();
+        let mut w = StateWriter { buf };
+        w.write_bytes(magic);
+        w.write_u16(version);
+        w
+    }
+
+    pub fn new_save_state_in(buf: Vec<u8>) -> Self {
+        StateWriter::new_in(buf, SAVE_STATE_MAGIC, SAVE_STATE_VERSION)
+    }
+
+    pub fn write_u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn write_bool(&mut self, v: bool) {
+        self.write_u8(v as u8);
+    }
+
+    pub fn write_u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    // Fixed size data, the reader must know the length
+    pub fn write_bytes(&mut self, v: &[u8]) {
+        self.buf.extend_from_slice(v);
+    }
+
+    // Variable size data, prefixed with its length
+    pub fn write_vec(&mut self, v: &[u8]) {
+        self.write_u32(v.len() as u32);
+        self.write_bytes(v);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        StateWriter::new()
+    }
+}
+
+// ----------------------------------------------------------------------------
+// StateReader
+// ----------------------------------------------------------------------------
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    // Checks the header of a save state
+    pub fn new(data: &'a [u8]) -> Result<Self, String> {
+        StateReader::new_with_header(data, SAVE_STATE_MAGIC, SAVE_STATE_VERSION, "save state")
+    }
+
+    // `kind` names the file in errors
+    pub fn new_with_header(
+        data: &'a [u8],
+        magic: &[u8; 4],
+        version: u16,
+        kind: &str,
+    ) -> Result<Self, String> {
+        let mut r = StateReader { data, pos: 0 };
+        let mut header = [0; 4];
+        r.read_bytes(&mut header)
+            .map_err(|_| format!("not a {}", kind))?;
+        if &header != magic {
+            return Err(format!("not a {}", kind));
+        }
+        let v = r.read_u16()?;
+        if v != version {
+            return Err(format!(
+                "unsupported {} version {} (expected {})",
+                kind, v, version
+            ));
+        }
+        Ok(r)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < len {
+            return Err("data is truncated".to_string());
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, String> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, String> {
+        let mut bytes = [0; 2];
+        self.read_bytes(&mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, String> {
+        let mut bytes = [0; 4];
+        self.read_bytes(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+        self.read_bytes(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn read_bytes(&mut self, v: &mut [u8]) -> Result<(), String> {
+        v.copy_from_slice(self.take(v.len())?);
+        Ok(())
+    }
+
+    // Reads data written by `write_vec`. The length has to match `v`, save
+    // states can't resize memory (e.g. when loaded into a different game).
+    pub fn read_vec_into(&mut self, v: &mut [u8]) -> Result<(), String> {
+        let len = self.read_u32()? as usize;
+        if len != v.len() {
+            return Err(format!(
+                "save state memory size mismatch: {} != {}",
+                len,
+                v.len()
+            ));
+        }
+        self.read_bytes(v)
+    }
+
+    // Reads data written by `write_vec` into a new vector. The length
+    // comes from the data, it is checked against what is left before
+    // anything is allocated.
+    pub fn read_vec(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.read_u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+// serde only does arrays of up to 32 elements, for RAM use
+// #[serde(with = "crate::savestate::byte_array")]
+#[cfg(feature = "serde")]
+pub(crate) mod byte_array {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::convert::TryInto;
+
+    pub fn serialize<S: Serializer, const N: usize>(
+        array: &[u8; N],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(array.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<[u8; N], D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        let len = bytes.len();
+        bytes
+            .try_into()
+            .map_err(|_| D::Error::invalid_length(len, &format!("{} bytes", N).as_str()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut w = StateWriter::new();
+        w.write_u8(0x12);
+        w.write_bool(true);
+        w.write_u16(0x3456);
+        w.write_u32(0x789A_BCDE);
+        w.write_u64(u64::MAX);
+        w.write_vec(&[1, 2, 3]);
+        w.write_vec(&[4, 5]);
+        w.write_u32(100);
+        let data = w.into_bytes();
+
+        let mut r = StateReader::new(&data).unwrap();
+        assert_eq!(r.read_u8(), Ok(0x12));
+        assert_eq!(r.read_bool(), Ok(true));
+        assert_eq!(r.read_u16(), Ok(0x3456));
+        assert_eq!(r.read_u32(), Ok(0x789A_BCDE));
+        assert_eq!(r.read_u64(), Ok(u64::MAX));
+        let mut v = [0; 3];
+        r.read_vec_into(&mut v).unwrap();
+        assert_eq!(v, [1, 2, 3]);
+        assert_eq!(r.read_vec(), Ok(vec![4, 5]));
+        // a length past the end of the data
+        assert!(r.read_vec().is_err());
+        assert!(r.is_at_end());
+        assert!(r.read_u8().is_err());
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        assert!(StateReader::new(b"NES").is_err());
+        assert!(StateReader::new(b"NES\x1a\x01\x00").is_err());
+        assert!(StateReader::new(b"NESS\xFF\x00").is_err());
+        // states from older versions are rejected
+        let mut data = b"NESS".to_vec();
+        data.extend_from_slice(&(SAVE_STATE_VERSION - 1).to_le_bytes());
+        assert!(StateReader::new(&data).is_err());
+
+        let mut data = StateWriter::new().into_bytes();
+        assert!(StateReader::new(&data).is_ok());
+
+        data.extend_from_slice(&[0x02, 0x00, 0x00, 0x00, 0x01, 0x02]);
+        let mut r = StateReader::new(&data).unwrap();
+        assert!(r.read_vec_into(&mut [0; 3]).is_err());
+    }
+}
@@ -0,0 +1,415 @@
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::rc::Rc;
+
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, AST};
+
+use crate::cartridge::Cartridge;
+use crate::debugger::{Access, BreakReason, Watchpoint};
+use crate::emulator::Emulator;
+use crate::graphics::font::draw_text;
+use crate::graphics::NesFrame;
+use crate::input::button_by_name;
+
+// Scripts in Rhai (https://rhai.rs) that watch and drive the emulator, for
+// bots, trainers and research, like FCEUX's Lua scripts:
+//
+//   // infinite lives
+//   on_frame_start(|| write(0x075A, 3));
+//
+//   let jumps = 0;
+//   on_write(0x009F, |addr, value| if value > 0x80 { jumps += 1 });
+//
+//   on_frame_end(|| draw_text(8, 8, `JUMPS ${jumps}`, 0xFFFFFF));
+//
+// The top level runs once when the script is loaded and registers hooks,
+// closures that keep the script's variables between calls. Memory hooks run
+// right after the instruction that made the access, they are the debugger's
+// watchpoints (see `Debugger`).
+//
+//   on_frame_start(fn)          call fn() before every frame
+//   on_frame_end(fn)            and after
+//   on_read(addr, fn)           call fn(addr, value) when the CPU reads addr
+//   on_read(first, last, fn)    or an address between first and last
+//   on_write(...)               the same for writes
+//   read(addr)                  memory without side effects, see `Bus::peek`
+//   write(addr, value)          a CPU write
+//   frame()                     frames run since power on
+//   press(player, button)       hold a button during the frame, player 1 or
+//                               2 and "a", "b", "select", "start", "up"...
+//   draw_text(x, y, text, color)
+//   draw_rect(x, y, width, height, color)
+//   draw_pixel(x, y, color)
+//
+// Drawings go over the frame until the next one starts, colors are 0xRRGGBB.
+
+enum Drawing {
+    Text(u32, u32, String, [u8; 3]),
+    Rect(u32, u32, u32, u32, [u8; 3]),
+    Pixel(u32, u32, [u8; 3]),
+}
+
+struct MemoryHook {
+    range: RangeInclusive<u16>,
+    access: Access,
+    callback: FnPtr,
+}
+
+// What the script's functions work on
+struct Context {
+    // the emulator is swapped in while the script runs
+    emulator: Emulator,
+    frame_start: Vec<FnPtr>,
+    frame_end: Vec<FnPtr>,
+    memory_hooks: Vec<MemoryHook>,
+    drawings: Vec<Drawing>,
+}
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    context: Rc<RefCell<Context>>,
+}
+
+impl Script {
+    pub fn load<P: AsRef<Path>>(path: P, emulator: &mut Emulator) -> Result<Script, String> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        Script::new(&source, emulator).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // Compile the script and run its top level
+    pub fn new(source: &str, emulator: &mut Emulator) -> Result<Script, String> {
+        let context = Rc::new(RefCell::new(Context {
+            emulator: Emulator::new(Cartridge::new_dummy()),
+            frame_start: vec![],
+            frame_end: vec![],
+            memory_hooks: vec![],
+            drawings: vec![],
+        }));
+        let mut engine = Engine::new();
+        register_api(&mut engine, &context);
+        let ast = engine.compile(source).map_err(|e| e.to_string())?;
+        let mut script = Script {
+            engine,
+            ast,
+            context,
+        };
+        script.with_emulator(emulator, |engine, ast| engine.run_ast(ast))?;
+        Ok(script)
+    }
+
+    // Call before running a frame, after setting its input
+    pub fn frame_start(&mut self, emulator: &mut Emulator) -> Result<(), String> {
+        self.context.borrow_mut().drawings.clear();
+        let callbacks = self.context.borrow().frame_start.clone();
+        for callback in callbacks {
+            self.with_emulator(emulator, |engine, ast| {
+                callback.call::<Dynamic>(engine, ast, ()).map(|_| ())
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn frame_end(&mut self, emulator: &mut Emulator) -> Result<(), String> {
+        let callbacks = self.context.borrow().frame_end.clone();
+        for callback in callbacks {
+            self.with_emulator(emulator, |engine, ast| {
+                callback.call::<Dynamic>(engine, ast, ()).map(|_| ())
+            })?;
+        }
+        Ok(())
+    }
+
+    // Run the memory hooks of a break `run_frame` stopped for. Returns
+    // false when the break isn't the script's, e.g. a watchpoint set in
+    // the monitor. Either way the frame goes on with `run_frame`.
+    pub fn handle_break(
+        &mut self,
+        reason: &BreakReason,
+        emulator: &mut Emulator,
+    ) -> Result<bool, String> {
+        let (addr, value, access) = match *reason {
+            BreakReason::Watchpoint {
+                addr,
+                value,
+                access,
+                ..
+            } => (addr, value, access),
+            _ => return Ok(false),
+        };
+        let callbacks: Vec<FnPtr> = self
+            .context
+            .borrow()
+            .memory_hooks
+            .iter()
+            .filter(|hook| hook.access == access && hook.range.contains(&addr))
+            .map(|hook| hook.callback.clone())
+            .collect();
+        for callback in callbacks.iter() {
+            self.with_emulator(emulator, |engine, ast| {
+                let args = (addr as i64, value as i64);
+                callback.call::<Dynamic>(engine, ast, args).map(|_| ())
+            })?;
+        }
+        Ok(!callbacks.is_empty())
+    }
+
+    // Remove the script's watchpoints, its hooks aren't called anymore
+    pub fn stop(self, emulator: &mut Emulator) {
+        let debugger = emulator.debugger();
+        for hook in self.context.borrow().memory_hooks.iter() {
+            let watchpoint = Watchpoint {
+                range: hook.range.clone(),
+                read: hook.access == Access::Read,
+                write: hook.access == Access::Write,
+            };
+            let idx = debugger.watchpoints().iter().position(|w| *w == watchpoint);
+            if let Some(idx) = idx {
+                debugger.remove_watchpoint(idx);
+            }
+        }
+    }
+
+    pub fn has_drawings(&self) -> bool {
+        !self.context.borrow().drawings.is_empty()
+    }
+
+    // Draw what the script drew during the last frame
+    pub fn draw(&self, frame: &mut NesFrame) {
+        for drawing in self.context.borrow().drawings.iter() {
+            match drawing {
+                Drawing::Text(x, y, text, color) => draw_text(frame, *x, *y, text, *color),
+                Drawing::Rect(x, y, width, height, [r, g, b]) => {
+                    for dy in 0..*height {
+                        for dx in 0..*width {
+                            frame.set_pixel(x + dx, y + dy, *r, *g, *b);
+                        }
+                    }
+                }
+                Drawing::Pixel(x, y, [r, g, b]) => frame.set_pixel(*x, *y, *r, *g, *b),
+            }
+        }
+    }
+
+    // The script's functions use `emulator` during `f`
+    fn with_emulator<F>(&mut self, emulator: &mut Emulator, f: F) -> Result<(), String>
+    where
+        F: FnOnce(&Engine, &AST) -> ScriptResult<()>,
+    {
+        std::mem::swap(emulator, &mut self.context.borrow_mut().emulator);
+        let result = f(&self.engine, &self.ast);
+        std::mem::swap(emulator, &mut self.context.borrow_mut().emulator);
+        result.map_err(|e| e.to_string())
+    }
+}
+
+fn register_api(engine: &mut Engine, context: &Rc<RefCell<Context>>) {
+    let ctx = context.clone();
+    engine.register_fn("on_frame_start", move |callback: FnPtr| {
+        ctx.borrow_mut().frame_start.push(callback);
+    });
+    let ctx = context.clone();
+    engine.register_fn("on_frame_end", move |callback: FnPtr| {
+        ctx.borrow_mut().frame_end.push(callback);
+    });
+    for (name, access) in [("on_read", Access::Read), ("on_write", Access::Write)] {
+        let ctx = context.clone();
+        engine.register_fn(
+            name,
+            move |addr: i64, callback: FnPtr| -> ScriptResult<()> {
+                let addr = address(addr)?;
+                add_memory_hook(&ctx, addr..=addr, access, callback);
+                Ok(())
+            },
+        );
+        let ctx = context.clone();
+        engine.register_fn(
+            name,
+            move |first: i64, last: i64, callback: FnPtr| -> ScriptResult<()> {
+                let range = address(first)?..=address(last)?;
+                add_memory_hook(&ctx, range, access, callback);
+                Ok(())
+            },
+        );
+    }
+
+    let ctx = context.clone();
+    engine.register_fn("read", move |addr: i64| -> ScriptResult<i64> {
+        let addr = address(addr)?;
+        Ok(ctx.borrow_mut().emulator.cpu_mut().bus.peek(addr) as i64)
+    });
+    let ctx = context.clone();
+    engine.register_fn("write", move |addr: i64, value: i64| -> ScriptResult<()> {
+        let addr = address(addr)?;
+        let value = u8::try_from(value).map_err(|_| format!("invalid byte {}", value))?;
+        ctx.borrow_mut()
+            .emulator
+            .cpu_mut()
+            .bus
+            .cpu_write(addr, value);
+        Ok(())
+    });
+    let ctx = context.clone();
+    engine.register_fn("frame", move || ctx.borrow().emulator.frame_number() as i64);
+    let ctx = context.clone();
+    engine.register_fn(
+        "press",
+        move |player: i64, button: &str| -> ScriptResult<()> {
+            if !(1..=2).contains(&player) {
+                return Err(format!("no player {}, there are 1 and 2", player).into());
+            }
+            let button = button_by_name(button).ok_or_else(|| format!("no button {}", button))?;
+            ctx.borrow_mut().emulator.cpu_mut().bus.joypads[player as usize - 1].set(&button);
+            Ok(())
+        },
+    );
+
+    let ctx = context.clone();
+    engine.register_fn(
+        "draw_text",
+        move |x: i64, y: i64, text: &str, color: i64| {
+            let drawing = Drawing::Text(coord(x), coord(y), text.to_string(), rgb(color));
+            ctx.borrow_mut().drawings.push(drawing);
+        },
+    );
+    let ctx = context.clone();
+    engine.register_fn(
+        "draw_rect",
+        move |x: i64, y: i64, width: i64, height: i64, color: i64| {
+            let drawing =
+                Drawing::Rect(coord(x), coord(y), coord(width), coord(height), rgb(color));
+            ctx.borrow_mut().drawings.push(drawing);
+        },
+    );
+    let ctx = context.clone();
+    engine.register_fn("draw_pixel", move |x: i64, y: i64, color: i64| {
+        let drawing = Drawing::Pixel(coord(x), coord(y), rgb(color));
+        ctx.borrow_mut().drawings.push(drawing);
+    });
+}
+
+fn add_memory_hook(
+    context: &Rc<RefCell<Context>>,
+    range: RangeInclusive<u16>,
+    access: Access,
+    callback: FnPtr,
+) {
+    let mut context = context.borrow_mut();
+    let (read, write) = (access == Access::Read, access == Access::Write);
+    context
+        .emulator
+        .debugger()
+        .add_watchpoint(range.clone(), read, write);
+    context.memory_hooks.push(MemoryHook {
+        range,
+        access,
+        callback,
+    });
+}
+
+fn address(addr: i64) -> ScriptResult<u16> {
+    u16::try_from(addr).map_err(|_| format!("invalid address {}", addr).into())
+}
+
+// Off-screen coordinates are clipped when drawing
+fn coord(v: i64) -> u32 {
+    v.clamp(0, u32::MAX as i64) as u32
+}
+
+fn rgb(color: i64) -> [u8; 3] {
+    [(color >> 16) as u8, (color >> 8) as u8, color as u8]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Counts frames at $00 and copies $00 to $01:
+    //
+    //   LDA #$80 : STA $2000      ; NMI on
+    //   loop: JMP loop
+    //   nmi:  INC $00 : LDA $00 : STA $01 : RTI
+    fn new_emulator() -> Emulator {
+        let mut program = vec![
+            0xA9, 0x80, 0x8D, 0x00, 0x20, // 8000
+            0x4C, 0x05, 0x80, // 8005
+            0xE6, 0x00, 0xA5, 0x00, 0x85, 0x01, 0x40, // 8008
+        ];
+        program.resize(0x3FFA, 0);
+        program.extend_from_slice(&[0x08, 0x80, 0x00, 0x80, 0x00, 0x80]);
+        Emulator::new(Cartridge::new_from_program(program))
+    }
+
+    fn run_frame(script: &mut Script, emulator: &mut Emulator) {
+        script.frame_start(emulator).unwrap();
+        emulator.run_frame().unwrap();
+        while let Some(reason) = emulator.take_break() {
+            assert!(script.handle_break(&reason, emulator).unwrap());
+            emulator.run_frame().unwrap();
+        }
+        script.frame_end(emulator).unwrap();
+    }
+
+    #[test]
+    fn test_hooks() {
+        let mut emulator = new_emulator();
+        let mut script = Script::new(
+            r#"
+            let writes = [];
+            on_write(0x0001, |addr, value| writes.push(value));
+            on_frame_start(|| {
+                write(0x0300, frame());
+                press(2, "start");
+            });
+            on_frame_end(|| {
+                draw_text(0, 0, `${writes.len()}`, 0xFF0000);
+                draw_rect(10, 10, 2, 2, 0x00FF00);
+                if writes.len() == 3 {
+                    write(0x0200, writes[0] + writes[2]);
+                }
+            });
+            "#,
+            &mut emulator,
+        )
+        .unwrap();
+        for _ in 0..3 {
+            run_frame(&mut script, &mut emulator);
+        }
+        // the NMI wrote 1, 2 and 3
+        assert_eq!(emulator.cpu().bus.cpu_ram[0x200], 4);
+        assert_eq!(emulator.cpu().bus.cpu_ram[0x300], 2);
+        let joypad = &mut emulator.cpu_mut().bus.joypads[1];
+        joypad.write(1);
+        joypad.write(0);
+        let buttons: Vec<u8> = (0..8).map(|_| joypad.read()).collect();
+        assert_eq!(buttons, [0, 0, 0, 1, 0, 0, 0, 0]);
+
+        assert!(script.has_drawings());
+        let mut frame = NesFrame::new();
+        script.draw(&mut frame);
+        assert_eq!(frame.pixel(11, 11), [0, 0xFF, 0]);
+        // the "3" is red
+        assert!((0..8).any(|x| frame.pixel(x, 3) == [0xFF, 0, 0]));
+
+        script.stop(&mut emulator);
+        assert!(emulator.debugger().watchpoints().is_empty());
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut emulator = new_emulator();
+        assert!(Script::new("let x = ;", &mut emulator).is_err());
+        let e = Script::new("read(0x10000)", &mut emulator).err().unwrap();
+        assert!(e.contains("invalid address 65536"), "{}", e);
+        let mut script =
+            Script::new(r#"on_frame_start(|| press(1, "turbo"))"#, &mut emulator).unwrap();
+        let e = script.frame_start(&mut emulator).unwrap_err();
+        assert!(e.contains("no button turbo"), "{}", e);
+    }
+}
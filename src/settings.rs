@@ -0,0 +1,109 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Frontend settings kept between runs, in nes/settings.toml in the user's
+// config directory:
+//
+//   window_width = 768
+//   window_height = 720
+//
+// Missing or invalid values fall back to the defaults.
+
+const FILE_NAME: &str = "settings.toml";
+
+// $XDG_CONFIG_HOME/nes, %APPDATA%\nes or ~/.config/nes
+pub fn config_dir() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CONFIG_HOME")
+        .or_else(|| env::var_os("APPDATA"))
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(dir.join("nes"))
+}
+
+// Where files the emulator makes go, like save states: $XDG_DATA_HOME/nes,
+// %APPDATA%\nes or ~/.local/share/nes
+pub fn data_dir() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_DATA_HOME")
+        .or_else(|| env::var_os("APPDATA"))
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))?;
+    Some(dir.join("nes"))
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Settings {
+    // size of the window when it was last closed, not in fullscreen
+    pub window_size: Option<(u32, u32)>,
+}
+
+impl Settings {
+    pub fn default_file() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join(FILE_NAME))
+    }
+
+    // A missing or unreadable file gives the defaults
+    pub fn load<P: AsRef<Path>>(file: P) -> Settings {
+        Settings::parse(&fs::read_to_string(file).unwrap_or_default())
+    }
+
+    pub fn parse(text: &str) -> Settings {
+        let mut width = None;
+        let mut height = None;
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap();
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => continue,
+            };
+            match key {
+                "window_width" => width = value.parse().ok(),
+                "window_height" => height = value.parse().ok(),
+                _ => {}
+            }
+        }
+        Settings {
+            window_size: width.zip(height).filter(|&(w, h)| w > 0 && h > 0),
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        if let Some((width, height)) = self.window_size {
+            text.push_str(&format!("window_width = {}\n", width));
+            text.push_str(&format!("window_height = {}\n", height));
+        }
+        text
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, file: P) -> io::Result<()> {
+        if let Some(dir) = file.as_ref().parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(file, self.to_text())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_settings() {
+        let settings = Settings::parse("# window\nwindow_width = 800\nwindow_height=600\nfoo = 1");
+        assert_eq!(settings.window_size, Some((800, 600)));
+        assert_eq!(Settings::parse("window_width = 800"), Settings::default());
+        assert_eq!(
+            Settings::parse("window_width = 0\nwindow_height = 600"),
+            Settings::default()
+        );
+
+        let dir = env::temp_dir().join(format!("nes-settings-test-{}", std::process::id()));
+        let file = dir.join("nes").join(FILE_NAME);
+        assert_eq!(Settings::load(&file), Settings::default());
+        settings.save(&file).unwrap();
+        assert_eq!(Settings::load(&file), settings);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,292 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::emulator::Emulator;
+use crate::graphics::{NesFrame, BYTES_PER_PIXEL, NES_HEIGHT, NES_WIDTH};
+use crate::savestate::{crc32, StateReader, StateWriter};
+use crate::settings::data_dir;
+
+// Numbered save state slots kept on disk, a directory per game named after
+// the CRC-32 of its iNES file:
+//
+//   ~/.local/share/nes/1A2B3C4D/slot1.state ... slot10.state
+//
+// A slot file wraps a save state with what the player sees when picking a
+// slot, in the save state encoding:
+//
+//   "NESL" magic | u16 version | u64 time saved (Unix seconds)
+//   | u64 frame number | u16 thumbnail width | u16 thumbnail height
+//   | vec thumbnail RGB24 pixels | vec save state
+//
+// The thumbnail is the frame on screen when saving, scaled down, for
+// frontends to show when picking a slot.
+
+pub const NUM_SLOTS: usize = 10;
+
+const SLOT_MAGIC: &[u8; 4] = b"NESL";
+const SLOT_VERSION: u16 = 2;
+// Thumbnails are a quarter of the NES width and height
+const THUMBNAIL_SCALE: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SlotInfo {
+    // seconds since the Unix epoch
+    pub timestamp: u64,
+    // `Emulator::frame_number` when saved
+    pub frame_number: u64,
+}
+
+// A scaled down frame, row-major RGB24 like `NesFrame`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thumbnail {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Thumbnail {
+    // Each pixel is the average of a THUMBNAIL_SCALE square of the frame
+    pub fn from_frame(frame: &NesFrame) -> Self {
+        let (width, height) = (NES_WIDTH / THUMBNAIL_SCALE, NES_HEIGHT / THUMBNAIL_SCALE);
+        let mut pixels = Vec::with_capacity((width * height) as usize * BYTES_PER_PIXEL);
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = [0u32; 3];
+                for dy in 0..THUMBNAIL_SCALE {
+                    for dx in 0..THUMBNAIL_SCALE {
+                        let pixel = frame.pixel(x * THUMBNAIL_SCALE + dx, y * THUMBNAIL_SCALE + dy);
+                        for (sum, channel) in sum.iter_mut().zip(pixel.iter()) {
+                            *sum += *channel as u32;
+                        }
+                    }
+                }
+                let count = THUMBNAIL_SCALE * THUMBNAIL_SCALE;
+                pixels.extend(sum.iter().map(|sum| (sum / count) as u8));
+            }
+        }
+        Thumbnail {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 3] {
+        let offset = (y * self.width + x) as usize * BYTES_PER_PIXEL;
+        [
+            self.pixels[offset],
+            self.pixels[offset + 1],
+            self.pixels[offset + 2],
+        ]
+    }
+}
+
+pub struct StateSlots {
+    dir: PathBuf,
+}
+
+impl StateSlots {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        StateSlots {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    // The slots of the game in the `rom` iNES file, in `settings::data_dir`
+    pub fn for_rom(rom: &[u8]) -> Option<Self> {
+        let dir = data_dir()?.join(format!("{:08X}", crc32(rom)));
+        Some(StateSlots::new(dir))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // `slot` is 1 to NUM_SLOTS
+    pub fn path(&self, slot: usize) -> PathBuf {
+        self.dir.join(format!("slot{}.state", slot))
+    }
+
+    pub fn save(&self, slot: usize, emulator: &Emulator) -> Result<SlotInfo, String> {
+        check_slot(slot)?;
+        let info = SlotInfo {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs()),
+            frame_number: emulator.frame_number(),
+        };
+        let thumbnail = Thumbnail::from_frame(emulator.frame());
+        let data = encode_slot(&info, &thumbnail, &emulator.save_state());
+        let path = self.path(slot);
+        fs::create_dir_all(&self.dir)
+            .and_then(|_| fs::write(&path, data))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(info)
+    }
+
+    pub fn load(&self, slot: usize, emulator: &mut Emulator) -> Result<SlotInfo, String> {
+        check_slot(slot)?;
+        let path = self.path(slot);
+        let data = fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let slot = decode_slot(&data).map_err(|e| format!("{}: {}", path.display(), e))?;
+        emulator.load_state(&slot.state)?;
+        Ok(slot.info)
+    }
+
+    // None for empty slots and files that can't be read
+    pub fn info(&self, slot: usize) -> Option<SlotInfo> {
+        self.read(slot).map(|slot| slot.info)
+    }
+
+    // The screen when the slot was saved
+    pub fn thumbnail(&self, slot: usize) -> Option<Thumbnail> {
+        self.read(slot).map(|slot| slot.thumbnail)
+    }
+
+    fn read(&self, slot: usize) -> Option<SlotFile> {
+        let data = fs::read(self.path(slot)).ok()?;
+        decode_slot(&data).ok()
+    }
+}
+
+fn check_slot(slot: usize) -> Result<(), String> {
+    if (1..=NUM_SLOTS).contains(&slot) {
+        Ok(())
+    } else {
+        Err(format!("no slot {}, they go from 1 to {}", slot, NUM_SLOTS))
+    }
+}
+
+struct SlotFile {
+    info: SlotInfo,
+    thumbnail: Thumbnail,
+    state: Vec<u8>,
+}
+
+fn encode_slot(info: &SlotInfo, thumbnail: &Thumbnail, state: &[u8]) -> Vec<u8> {
+    let mut w = StateWriter::new_with_header(SLOT_MAGIC, SLOT_VERSION);
+    w.write_u64(info.timestamp);
+    w.write_u64(info.frame_number);
+    w.write_u16(thumbnail.width as u16);
+    w.write_u16(thumbnail.height as u16);
+    w.write_vec(&thumbnail.pixels);
+    w.write_vec(state);
+    w.into_bytes()
+}
+
+fn decode_slot(data: &[u8]) -> Result<SlotFile, String> {
+    let mut r = StateReader::new_with_header(data, SLOT_MAGIC, SLOT_VERSION, "save state slot")?;
+    let info = SlotInfo {
+        timestamp: r.read_u64()?,
+        frame_number: r.read_u64()?,
+    };
+    let width = r.read_u16()? as u32;
+    let height = r.read_u16()? as u32;
+    let pixels = r.read_vec()?;
+    if pixels.len() != (width * height) as usize * BYTES_PER_PIXEL {
+        return Err("save state slot thumbnail size mismatch".to_string());
+    }
+    let state = r.read_vec()?;
+    if !r.is_at_end() {
+        return Err("save state slot has trailing data".to_string());
+    }
+    Ok(SlotFile {
+        info,
+        thumbnail: Thumbnail {
+            width,
+            height,
+            pixels,
+        },
+        state,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use std::env;
+
+    #[test]
+    fn test_thumbnail() {
+        let mut frame = NesFrame::new();
+        // a white square in the top left, half of one in the next pixel
+        for y in 0..4 {
+            for x in 0..6 {
+                frame.set_pixel(x, y, 0xFF, 0xFF, 0xFF);
+            }
+        }
+        frame.set_pixel(NES_WIDTH - 1, NES_HEIGHT - 1, 0x40, 0, 0);
+        let thumbnail = Thumbnail::from_frame(&frame);
+        assert_eq!(thumbnail.pixels.len(), 64 * 60 * 3);
+        assert_eq!(thumbnail.pixel(0, 0), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(thumbnail.pixel(1, 0), [0x7F, 0x7F, 0x7F]);
+        assert_eq!(thumbnail.pixel(2, 0), [0, 0, 0]);
+        assert_eq!(thumbnail.pixel(63, 59), [0x04, 0, 0]);
+    }
+
+    #[test]
+    fn test_slots() {
+        // INC $00 : JMP $8000
+        let mut program = vec![0xE6, 0x00, 0x4C, 0x00, 0x80];
+        program.resize(0x3FFC, 0);
+        program.extend_from_slice(&[0x00, 0x80]);
+        let mut emu = Emulator::new(Cartridge::new_from_program(program));
+        emu.run_frame().unwrap();
+
+        let dir = env::temp_dir().join(format!("nes-slots-test-{}", std::process::id()));
+        let slots = StateSlots::new(&dir);
+        assert_eq!(slots.info(3), None);
+        let info = slots.save(3, &emu).unwrap();
+        assert_eq!(info.frame_number, 1);
+        assert!(info.timestamp > 0);
+        assert_eq!(slots.info(3), Some(info));
+        let thumbnail = slots.thumbnail(3).unwrap();
+        assert_eq!(thumbnail, Thumbnail::from_frame(emu.frame()));
+        assert_eq!((thumbnail.width, thumbnail.height), (64, 60));
+        assert!(slots.save(0, &emu).is_err());
+        assert!(slots.save(NUM_SLOTS + 1, &emu).is_err());
+
+        let saved = emu.state_hash();
+        emu.run_frame().unwrap();
+        assert_eq!(slots.load(3, &mut emu), Ok(info));
+        assert_eq!(emu.state_hash(), saved);
+        assert_eq!(emu.frame_number(), 1);
+        assert!(slots.load(4, &mut emu).is_err());
+
+        fs::write(slots.path(5), b"NESL").unwrap();
+        assert_eq!(slots.info(5), None);
+        assert!(slots.load(5, &mut emu).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_slot() {
+        let info = SlotInfo {
+            timestamp: 1,
+            frame_number: 2,
+        };
+        let thumbnail = Thumbnail {
+            width: 2,
+            height: 1,
+            pixels: vec![0; 2 * BYTES_PER_PIXEL],
+        };
+        let data = encode_slot(&info, &thumbnail, &[1, 2, 3]);
+        assert_eq!(decode_slot(&data).unwrap().state, [1, 2, 3]);
+
+        // a 4GB state, the file ends before it
+        let mut corrupt = data.clone();
+        let len = corrupt.len();
+        corrupt[len - 7..len - 3].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(decode_slot(&corrupt).is_err());
+
+        // the thumbnail doesn't match its size
+        let mut corrupt = data;
+        corrupt[6 + 16..6 + 20].copy_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(
+            decode_slot(&corrupt).err(),
+            Some("save state slot thumbnail size mismatch".to_string())
+        );
+    }
+}
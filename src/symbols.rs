@@ -0,0 +1,213 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::Path;
+
+// Names for CPU addresses, from the symbol files of other debuggers:
+//
+//   FCEUX .nl    `$C000#name#comment`, with CPU addresses. There is a file
+//                per 16K PRG bank (game.nes.0.nl) and one for RAM
+//                (game.nes.ram.nl). `$0200/10#name#` names 16 bytes.
+//   Mesen .mlb   `P:1F00:name:comment`, the prefix says what the address
+//                is an offset in: P for PRG ROM, R for internal RAM, W and
+//                S for cartridge RAM at $6000, G for registers.
+//                `R:0010-0011:name` names a range.
+//
+// The first address of a range gets the name, the others `name+1` and so
+// on. PRG ROM offsets are placed like `disasm::prg_listing` shows them: a
+// 32K ROM at $8000, the last 16K bank at $C000 and the others at $8000.
+// When banks share an address the first name wins.
+
+const PRG_BANK_SIZE: usize = 0x4000;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Symbols {
+    names: BTreeMap<u16, String>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols::default()
+    }
+
+    // Load a .nl or .mlb file, by its extension. `prg_banks` is the number
+    // of 16K PRG ROM banks, to place .mlb PRG offsets.
+    pub fn load<P: AsRef<Path>>(path: P, prg_banks: u8) -> Result<Symbols, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        let symbols = match path.extension().and_then(|e| e.to_str()) {
+            Some("nl") => Symbols::parse_nl(&text),
+            Some("mlb") => Symbols::parse_mlb(&text, prg_banks),
+            _ => return Err(format!("{}: not a .nl or .mlb file", path.display())),
+        };
+        symbols.map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse_nl(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Symbols::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = || format!("line {}: expected $ADDR#name#comment", i + 1);
+            let mut fields = line.splitn(3, '#');
+            let addr = fields.next().ok_or_else(error)?;
+            let name = fields.next().ok_or_else(error)?.trim();
+            let addr = addr.strip_prefix('$').ok_or_else(error)?;
+            let (addr, len) = match addr.split_once('/') {
+                Some((addr, len)) => (addr, parse_hex(len).ok_or_else(error)?),
+                None => (addr, 1),
+            };
+            let addr = parse_hex(addr).ok_or_else(error)?;
+            symbols.insert_range(addr, len, name);
+        }
+        Ok(symbols)
+    }
+
+    pub fn parse_mlb(text: &str, prg_banks: u8) -> Result<Symbols, String> {
+        let mut symbols = Symbols::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = || format!("line {}: expected TYPE:ADDR:name:comment", i + 1);
+            let mut fields = line.splitn(4, ':');
+            let kind = fields.next().ok_or_else(error)?;
+            let addr = fields.next().ok_or_else(error)?;
+            let name = fields.next().ok_or_else(error)?.trim();
+            let (start, end) = match addr.split_once('-') {
+                Some((start, end)) => (
+                    parse_hex(start).ok_or_else(error)?,
+                    parse_hex(end).ok_or_else(error)?,
+                ),
+                None => {
+                    let addr = parse_hex(addr).ok_or_else(error)?;
+                    (addr, addr)
+                }
+            };
+            if end < start {
+                return Err(format!("line {}: range ends before it starts", i + 1));
+            }
+            let addr = match kind {
+                "P" => prg_addr(start, prg_banks),
+                "R" | "G" => start,
+                "W" | "S" => 0x6000 + start,
+                // other memory types have no CPU address
+                _ => continue,
+            };
+            symbols.insert_range(addr, end - start + 1, name);
+        }
+        Ok(symbols)
+    }
+
+    pub fn insert(&mut self, addr: u16, name: &str) {
+        self.names.entry(addr).or_insert_with(|| name.to_string());
+    }
+
+    // Add the names of `other`, for a program with several symbol files
+    pub fn extend(&mut self, other: Symbols) {
+        for (addr, name) in other.names {
+            self.names.entry(addr).or_insert(name);
+        }
+    }
+
+    pub fn get(&self, addr: u16) -> Option<&str> {
+        self.names.get(&addr).map(|name| name.as_str())
+    }
+
+    // Address to name, like the labels of `disasm::listing`
+    pub fn names(&self) -> &BTreeMap<u16, String> {
+        &self.names
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    fn insert_range(&mut self, addr: usize, len: usize, name: &str) {
+        // entries with only a comment
+        if name.is_empty() {
+            return;
+        }
+        for i in 0..len {
+            let addr = match u16::try_from(addr + i) {
+                Ok(addr) => addr,
+                Err(_) => return,
+            };
+            if i == 0 {
+                self.insert(addr, name);
+            } else {
+                self.insert(addr, &format!("{}+{}", name, i));
+            }
+        }
+    }
+}
+
+fn parse_hex(s: &str) -> Option<usize> {
+    usize::from_str_radix(s.trim(), 16).ok()
+}
+
+fn prg_addr(offset: usize, prg_banks: u8) -> usize {
+    if prg_banks == 2 {
+        return 0x8000 + offset;
+    }
+    let bank = offset / PRG_BANK_SIZE;
+    let base = if bank + 1 >= prg_banks as usize {
+        0xC000
+    } else {
+        0x8000
+    };
+    base + offset % PRG_BANK_SIZE
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_nl() {
+        let symbols =
+            Symbols::parse_nl("$C000#reset#entry point\n\n$0200/3#snake#\n$0300##only a comment\n")
+                .unwrap();
+        assert_eq!(symbols.get(0xC000), Some("reset"));
+        assert_eq!(symbols.get(0x0200), Some("snake"));
+        assert_eq!(symbols.get(0x0202), Some("snake+2"));
+        assert_eq!(symbols.get(0x0203), None);
+        assert_eq!(symbols.get(0x0300), None);
+        assert_eq!(symbols.len(), 4);
+
+        assert_eq!(
+            Symbols::parse_nl("$C000#ok#\nC001#bad#"),
+            Err("line 2: expected $ADDR#name#comment".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_mlb() {
+        let text = "P:0010:main:the loop\nR:0010-0011:ptr\nW:0000:save\nG:2000:PPUCTRL\n\
+                    N:0000:nametable\nP:4000:other";
+        let symbols = Symbols::parse_mlb(text, 1).unwrap();
+        assert_eq!(symbols.get(0xC010), Some("main"));
+        assert_eq!(symbols.get(0x0011), Some("ptr+1"));
+        assert_eq!(symbols.get(0x6000), Some("save"));
+        assert_eq!(symbols.get(0x2000), Some("PPUCTRL"));
+        // offsets past the last bank are placed like it
+        assert_eq!(symbols.len(), 6);
+
+        let symbols = Symbols::parse_mlb(text, 2).unwrap();
+        assert_eq!(symbols.get(0x8010), Some("main"));
+        assert_eq!(symbols.get(0xC000), Some("other"));
+        let symbols = Symbols::parse_mlb(text, 8).unwrap();
+        assert_eq!(symbols.get(0x8010), Some("main"));
+        assert_eq!(symbols.get(0x8000), Some("other"));
+
+        assert!(Symbols::parse_mlb("P:zz:x", 1).is_err());
+    }
+}
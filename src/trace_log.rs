@@ -0,0 +1,363 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::cpu::disasm::DisasmInst;
+use crate::cpu::{Registers, CPU};
+
+// Trace logging: one entry per executed instruction, written by the CPU
+// before the instruction runs (see `CPU::trace_logger`).
+//
+// The text format is the nestest log format, `CPU::trace` followed by the
+// selected fields:
+//
+//   C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+//
+// The binary format is for traces too long to keep as text. It always has
+// all the fields but no disassembly, `BinaryTraceReader` reads it back:
+//
+//   "NEST" magic | u16 version
+//   | records: u16 PC, 3 bytes at PC, u8 A, X, Y, P, SP,
+//              u16 scanline, u16 dot, u32 CPU cycles
+
+const BINARY_TRACE_MAGIC: &[u8; 4] = b"NEST";
+const BINARY_TRACE_VERSION: u16 = 1;
+const RECORD_SIZE: usize = 18;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceFields {
+    pub registers: bool,
+    // PPU scanline and dot
+    pub ppu: bool,
+    // total CPU cycles
+    pub cycles: bool,
+}
+
+impl TraceFields {
+    pub fn all() -> Self {
+        TraceFields {
+            registers: true,
+            ppu: true,
+            cycles: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Text(TraceFields),
+    Binary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceRecord {
+    pub registers: Registers,
+    // the instruction, and whatever follows it for shorter instructions
+    pub bytes: [u8; 3],
+    pub scanline: u16,
+    pub dot: u16,
+    pub cycles: u32,
+}
+
+impl TraceRecord {
+    fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let r = &self.registers;
+        let mut data = [0u8; RECORD_SIZE];
+        data[0..2].copy_from_slice(&r.pc.to_le_bytes());
+        data[2..5].copy_from_slice(&self.bytes);
+        data[5..10].copy_from_slice(&[r.a, r.x, r.y, r.p, r.sp]);
+        data[10..12].copy_from_slice(&self.scanline.to_le_bytes());
+        data[12..14].copy_from_slice(&self.dot.to_le_bytes());
+        data[14..18].copy_from_slice(&self.cycles.to_le_bytes());
+        out.write_all(&data)
+    }
+
+    fn from_bytes(data: &[u8; RECORD_SIZE]) -> Self {
+        TraceRecord {
+            registers: Registers {
+                pc: u16::from_le_bytes([data[0], data[1]]),
+                a: data[5],
+                x: data[6],
+                y: data[7],
+                p: data[8],
+                sp: data[9],
+            },
+            bytes: [data[2], data[3], data[4]],
+            scanline: u16::from_le_bytes([data[10], data[11]]),
+            dot: u16::from_le_bytes([data[12], data[13]]),
+            cycles: u32::from_le_bytes([data[14], data[15], data[16], data[17]]),
+        }
+    }
+
+    // A text line like the logger writes. Memory isn't available any more,
+    // so operands don't show the values they point at.
+    pub fn to_text(&self, fields: TraceFields) -> String {
+        let inst = DisasmInst::decode(&self.bytes, self.registers.pc);
+        let bytes: Vec<String> = inst.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let asm = inst.to_asm(&BTreeMap::new());
+        // official opcodes are indented by a space like in `CPU::trace`
+        let asm = if asm.starts_with('*') {
+            asm
+        } else {
+            format!(" {}", asm)
+        };
+        let inst = format!(
+            "{:04X}  {:8} {:31}",
+            self.registers.pc,
+            bytes.join(" "),
+            asm
+        );
+        format_line(&inst, self, fields)
+    }
+}
+
+fn format_line(inst: &str, record: &TraceRecord, fields: TraceFields) -> String {
+    let mut line = inst.to_string();
+    let r = &record.registers;
+    if fields.registers {
+        line.push_str(&format!(
+            "  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+            r.a, r.x, r.y, r.p, r.sp
+        ));
+    }
+    if fields.ppu {
+        line.push_str(&format!(" PPU:{:3},{:3}", record.scanline, record.dot));
+    }
+    if fields.cycles {
+        line.push_str(&format!(" CYC:{}", record.cycles));
+    }
+    line.trim_end().to_string()
+}
+
+pub struct TraceLogger {
+    out: BufWriter<Box<dyn Write>>,
+    format: TraceFormat,
+    enabled: bool,
+    // The first write error, logging stops when there is one
+    error: Option<io::Error>,
+    header_written: bool,
+}
+
+impl TraceLogger {
+    // An enabled logger writing to `out`
+    pub fn new<W: Write + 'static>(out: W, format: TraceFormat) -> Self {
+        TraceLogger {
+            out: BufWriter::new(Box::new(out)),
+            format,
+            enabled: true,
+            error: None,
+            header_written: false,
+        }
+    }
+
+    pub fn to_file<P: AsRef<Path>>(path: P, format: TraceFormat) -> io::Result<Self> {
+        Ok(TraceLogger::new(File::create(path)?, format))
+    }
+
+    pub fn format(&self) -> TraceFormat {
+        self.format
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled && self.error.is_none()
+    }
+
+    // Returns the write error that stopped logging, if any
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    // Called by the CPU before it executes the instruction at PC
+    pub(crate) fn log(&mut self, cpu: &mut CPU) {
+        if !self.is_enabled() {
+            return;
+        }
+        let record = cpu.trace_record();
+        let result = match self.format {
+            TraceFormat::Text(fields) => {
+                let line = format_line(&cpu.trace_inst(), &record, fields);
+                writeln!(self.out, "{}", line)
+            }
+            TraceFormat::Binary => self.write_binary(&record),
+        };
+        if let Err(e) = result {
+            self.error = Some(e);
+        }
+    }
+
+    fn write_binary(&mut self, record: &TraceRecord) -> io::Result<()> {
+        if !self.header_written {
+            self.out.write_all(BINARY_TRACE_MAGIC)?;
+            self.out.write_all(&BINARY_TRACE_VERSION.to_le_bytes())?;
+            self.header_written = true;
+        }
+        record.write_to(&mut self.out)
+    }
+}
+
+impl Drop for TraceLogger {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+// Reads the records of a binary trace one by one
+pub struct BinaryTraceReader<R: Read> {
+    input: R,
+}
+
+impl<R: Read> BinaryTraceReader<R> {
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut header = [0u8; 6];
+        input.read_exact(&mut header)?;
+        if &header[0..4] != BINARY_TRACE_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a binary trace",
+            ));
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version != BINARY_TRACE_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported binary trace version {}", version),
+            ));
+        }
+        Ok(BinaryTraceReader { input })
+    }
+}
+
+impl<R: Read> Iterator for BinaryTraceReader<R> {
+    type Item = io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut data = [0u8; RECORD_SIZE];
+        let mut read = 0;
+        while read < RECORD_SIZE {
+            match self.input.read(&mut data[read..]) {
+                Ok(0) if read == 0 => return None,
+                Ok(0) => {
+                    return Some(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "trace record is truncated",
+                    )))
+                }
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        Some(Ok(TraceRecord::from_bytes(&data)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::Cartridge;
+    use crate::cpu::assembler::assemble_with_start_addr;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // A Write whose output stays readable after the logger takes it
+    #[derive(Clone)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run_traced(format: TraceFormat, steps: usize) -> Vec<u8> {
+        let mut program = assemble_with_start_addr(
+            "
+            lda #$01
+            sta $0200
+            lda $2002
+            inx",
+            0x8000,
+        )
+        .unwrap();
+        program.resize(0x3FFC, 0);
+        program.extend_from_slice(&[0x00, 0x80]);
+        let mut cpu = CPU::new(Bus::new(Cartridge::new_from_program(program)));
+        cpu.reset();
+        let buffer = SharedBuffer(Rc::new(RefCell::new(vec![])));
+        cpu.trace_logger = Some(TraceLogger::new(buffer.clone(), format));
+        for _ in 0..steps {
+            cpu.step().unwrap();
+        }
+        cpu.trace_logger.as_mut().unwrap().flush().unwrap();
+        let data = buffer.0.borrow().clone();
+        data
+    }
+
+    #[test]
+    fn test_text_trace() {
+        let fields = TraceFields {
+            registers: true,
+            ppu: false,
+            cycles: true,
+        };
+        let trace = String::from_utf8(run_traced(TraceFormat::Text(fields), 2)).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            "8000  A9 01     LDA #$01                        A:00 X:00 Y:00 P:24 SP:FD CYC:7"
+        );
+        assert!(lines[1].starts_with("8002  8D 00 02  STA $0200 = 00"));
+        assert!(lines[1].ends_with("A:01 X:00 Y:00 P:24 SP:FD CYC:9"));
+
+        let fields = TraceFields {
+            registers: false,
+            ppu: false,
+            cycles: false,
+        };
+        let trace = String::from_utf8(run_traced(TraceFormat::Text(fields), 1)).unwrap();
+        assert_eq!(trace, "8000  A9 01     LDA #$01\n");
+    }
+
+    #[test]
+    fn test_binary_trace() {
+        let trace = run_traced(TraceFormat::Binary, 4);
+        assert_eq!(trace.len(), 6 + 4 * RECORD_SIZE);
+        let records: Vec<TraceRecord> = BinaryTraceReader::new(&trace[..])
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[1].registers.pc, 0x8002);
+        assert_eq!(records[1].registers.a, 0x01);
+        assert_eq!(records[1].bytes, [0x8D, 0x00, 0x02]);
+        assert_eq!(records[1].cycles, 9);
+        assert_eq!(
+            records[1].to_text(TraceFields::all()),
+            format!(
+                "8002  8D 00 02  STA $0200                       A:01 X:00 Y:00 P:24 SP:FD PPU:{:3},{:3} CYC:9",
+                records[1].scanline, records[1].dot
+            )
+        );
+
+        assert!(BinaryTraceReader::new(&trace[..2]).is_err());
+        let mut reader = BinaryTraceReader::new(&trace[..6 + RECORD_SIZE + 1]).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().unwrap().is_err());
+    }
+}
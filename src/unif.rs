@@ -0,0 +1,186 @@
+use std::convert::TryInto;
+
+use crate::cartridge::{CartridgeLoadReport, Mirror, RomImage};
+
+// UNIF, the Universal NES Image Format, an alternative to iNES some older
+// dumps and homebrew come in. Instead of a mapper number it names the
+// board, and the file is a list of chunks after a 32 byte header:
+//
+//   "UNIF" | u32 revision | 24 reserved bytes
+//   chunk: 4 byte ID | u32 length | data
+//
+// MAPR has the board name, PRG0-PRGF and CHR0-CHRF the ROM in order and
+// MIRR the mirroring. Numbers are little-endian.
+// Ref: https://wiki.nesdev.org/w/index.php/UNIF
+
+pub const MAGIC: &[u8; 4] = b"UNIF";
+
+const HEADER_SIZE: usize = 32;
+const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+pub(crate) fn parse(raw: &[u8]) -> Result<RomImage, String> {
+    if raw.len() < HEADER_SIZE || !raw.starts_with(MAGIC) {
+        return Err("UNIF header not found".to_string());
+    }
+    let mut board = None;
+    let mut mirror = Mirror::Horizontal;
+    let mut prg_chunks: [Option<&[u8]>; 16] = [None; 16];
+    let mut chr_chunks: [Option<&[u8]>; 16] = [None; 16];
+
+    let mut pos = HEADER_SIZE;
+    while pos < raw.len() {
+        if raw.len() - pos < 8 {
+            return Err(format!("UNIF chunk header at {} is truncated", pos));
+        }
+        let id = &raw[pos..pos + 4];
+        let len = u32::from_le_bytes(raw[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let start = pos + 8;
+        let available = raw.len() - start;
+        if available < len {
+            return Err(format!(
+                "UNIF chunk {} is truncated: expected {} bytes, {} available",
+                String::from_utf8_lossy(id),
+                len,
+                available
+            ));
+        }
+        let data = &raw[start..start + len];
+        let bank = HEX_DIGITS.iter().position(|&digit| digit == id[3]);
+        match (&id[..3], bank) {
+            (b"PRG", Some(bank)) => prg_chunks[bank] = Some(data),
+            (b"CHR", Some(bank)) => chr_chunks[bank] = Some(data),
+            _ if id == b"MAPR" => {
+                let name = data.split(|&byte| byte == 0).next().unwrap_or_default();
+                board = Some(String::from_utf8_lossy(name).into_owned());
+            }
+            _ if id == b"MIRR" => {
+                mirror = match data.first() {
+                    Some(1) => Mirror::Vertical,
+                    Some(2) => Mirror::SingleScreenLo,
+                    Some(3) => Mirror::SingleScreenHi,
+                    Some(4) => Mirror::FourScreen,
+                    // 5 is mapper controlled
+                    _ => Mirror::Horizontal,
+                }
+            }
+            // names, CRCs, battery and TV system
+            _ => {}
+        }
+        pos = start + len;
+    }
+
+    let board = board.ok_or("UNIF file has no MAPR chunk naming the board")?;
+    let prg_rom: Vec<u8> = prg_chunks
+        .iter()
+        .flatten()
+        .flat_map(|c| c.iter())
+        .copied()
+        .collect();
+    let chr_rom: Vec<u8> = chr_chunks
+        .iter()
+        .flatten()
+        .flat_map(|c| c.iter())
+        .copied()
+        .collect();
+    if prg_rom.is_empty() {
+        return Err("UNIF file has no PRG ROM".to_string());
+    }
+    let (mapper_id, mapper_error) = match board_mapper(&board) {
+        Some(mapper_id) => (mapper_id, None),
+        None => (0, Some(format!("UNIF board {} not supported", board))),
+    };
+    let report = CartridgeLoadReport {
+        prg_rom_size: prg_rom.len(),
+        chr_rom_size: chr_rom.len(),
+        ..Default::default()
+    };
+    Ok(RomImage {
+        mapper_id,
+        mirror,
+        prg_rom,
+        chr_rom,
+        trainer: None,
+        mapper_error,
+        report,
+    })
+}
+
+// The iNES mapper of a UNIF board name like "NES-TLROM"
+pub fn board_mapper(board: &str) -> Option<u8> {
+    let name = ["NES-", "HVC-", "UNL-", "BMC-", "BTL-"]
+        .iter()
+        .find_map(|prefix| board.strip_prefix(prefix))
+        .unwrap_or(board);
+    let mapper = match name {
+        "NROM" | "NROM-128" | "NROM-256" | "RROM" | "RROM-128" => 0,
+        "SAROM" | "SBROM" | "SCROM" | "SEROM" | "SGROM" | "SKROM" | "SLROM" | "SL1ROM"
+        | "SNROM" | "SOROM" | "SUROM" | "SXROM" => 1,
+        "UNROM" | "UOROM" => 2,
+        "CNROM" => 3,
+        "TBROM" | "TEROM" | "TFROM" | "TGROM" | "TKROM" | "TLROM" | "TL1ROM" | "TR1ROM"
+        | "TSROM" | "TVROM" => 4,
+        "EKROM" | "ELROM" | "ETROM" | "EWROM" => 5,
+        "AMROM" | "ANROM" | "AN1ROM" | "AOROM" => 7,
+        "PEEOROM" | "PNROM" => 9,
+        "FJROM" | "FKROM" => 10,
+        "CPROM" => 13,
+        "GNROM" | "MHROM" => 66,
+        _ => return None,
+    };
+    Some(mapper)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Cartridge;
+
+    fn chunk(file: &mut Vec<u8>, id: &[u8], data: &[u8]) {
+        file.extend_from_slice(id);
+        file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        file.extend_from_slice(data);
+    }
+
+    #[test]
+    fn test_load_unif() {
+        let mut file = MAGIC.to_vec();
+        file.extend_from_slice(&7u32.to_le_bytes());
+        file.resize(HEADER_SIZE, 0);
+        chunk(&mut file, b"MAPR", b"NES-NROM-256\0");
+        chunk(&mut file, b"NAME", b"Test\0");
+        // out of order, PRG1 goes after PRG0
+        chunk(&mut file, b"PRG1", &[0x22; 0x4000]);
+        chunk(&mut file, b"PRG0", &[0x11; 0x4000]);
+        chunk(&mut file, b"CHR0", &[0x33; 0x2000]);
+        chunk(&mut file, b"MIRR", &[1]);
+
+        let mut cart = Cartridge::new(&file).unwrap();
+        assert_eq!(cart.mapper_id, 0);
+        assert_eq!(cart.mirror, Mirror::Vertical);
+        assert_eq!((cart.num_prg_banks, cart.num_chr_banks), (2, 1));
+        assert_eq!(cart.cpu_read(0x8000), Some(0x11));
+        assert_eq!(cart.cpu_read(0xC000), Some(0x22));
+        assert_eq!(cart.ppu_read(0x0000), Some(0x33));
+
+        let mut truncated = file.clone();
+        // without MIRR and half of CHR0
+        truncated.truncate(file.len() - 9 - 0x1000);
+        assert_eq!(
+            Cartridge::new(&truncated).unwrap_err(),
+            "UNIF chunk CHR0 is truncated: expected 8192 bytes, 4096 available"
+        );
+
+        let mut unknown = file[..HEADER_SIZE].to_vec();
+        chunk(&mut unknown, b"MAPR", b"UNL-Sachen-74LS374N\0");
+        chunk(&mut unknown, b"PRG0", &[0; 0x4000]);
+        assert!(Cartridge::new(&unknown).is_err());
+    }
+
+    #[test]
+    fn test_board_mapper() {
+        assert_eq!(board_mapper("NES-TLROM"), Some(4));
+        assert_eq!(board_mapper("HVC-AN1ROM"), Some(7));
+        assert_eq!(board_mapper("UNROM"), Some(2));
+        assert_eq!(board_mapper("NES-FOO"), None);
+    }
+}
@@ -0,0 +1,137 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::audio::wav::WavWriter;
+use crate::emulator::FRAME_RATE;
+use crate::graphics::{NesFrame, NES_HEIGHT, NES_WIDTH};
+
+// Dumps gameplay to a directory, uncompressed:
+//
+//   frame000000.ppm ...  every completed frame as a binary PPM image
+//   audio.wav            16-bit mono PCM at the emulator's sample rate
+//   video.txt            frame rate, size and frame count, and an ffmpeg
+//                        command line that encodes the dump
+//
+// The WAV header and the manifest are written by `finish`, or when the
+// recorder is dropped.
+
+const AUDIO_FILE: &str = "audio.wav";
+const MANIFEST_FILE: &str = "video.txt";
+
+pub struct VideoRecorder {
+    dir: PathBuf,
+    audio: WavWriter,
+    frames: u64,
+    sample_rate: u32,
+    finished: bool,
+}
+
+impl VideoRecorder {
+    // Creates `dir` if needed. Files of an earlier dump in it are
+    // overwritten.
+    pub fn create<P: AsRef<Path>>(dir: P, sample_rate: u32) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let audio = WavWriter::create(dir.join(AUDIO_FILE), sample_rate)?;
+        Ok(VideoRecorder {
+            dir,
+            audio,
+            frames: 0,
+            sample_rate,
+            finished: false,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    // A completed frame and the audio samples produced while it ran, see
+    // `Emulator::audio_samples`
+    pub fn add_frame(&mut self, frame: &NesFrame, samples: &[f32]) -> io::Result<()> {
+        let path = self.dir.join(format!("frame{:06}.ppm", self.frames));
+        let mut image = BufWriter::new(File::create(path)?);
+        write!(image, "P6\n{} {}\n255\n", NES_WIDTH, NES_HEIGHT)?;
+        image.write_all(frame.as_bytes())?;
+        image.flush()?;
+        self.frames += 1;
+        self.audio.write_samples(samples)
+    }
+
+    // Completes the WAV header and writes the manifest
+    pub fn finish(mut self) -> io::Result<()> {
+        self.write_trailer()
+    }
+
+    fn write_trailer(&mut self) -> io::Result<()> {
+        self.finished = true;
+        self.audio.complete()?;
+
+        let mut manifest = File::create(self.dir.join(MANIFEST_FILE))?;
+        write!(
+            manifest,
+            "frame_rate={}\n\
+             width={}\n\
+             height={}\n\
+             frames={}\n\
+             audio={} Hz mono\n\
+             \n\
+             ffmpeg -framerate {} -i frame%06d.ppm -i {} -c:v libx264 -pix_fmt yuv420p \
+             -vf scale=iw*3:ih*3:flags=neighbor -c:a aac video.mp4\n",
+            FRAME_RATE,
+            NES_WIDTH,
+            NES_HEIGHT,
+            self.frames,
+            self.sample_rate,
+            FRAME_RATE,
+            AUDIO_FILE,
+        )
+    }
+}
+
+impl Drop for VideoRecorder {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.write_trailer();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audio::wav::HEADER_SIZE as WAV_HEADER_SIZE;
+
+    #[test]
+    fn test_recording() {
+        let dir = std::env::temp_dir().join(format!("nes-video-test-{}", std::process::id()));
+        let mut recorder = VideoRecorder::create(&dir, 48_000).unwrap();
+        let mut frame = NesFrame::new();
+        frame.set_pixel(0, 0, 0x10, 0x20, 0x30);
+        recorder.add_frame(&frame, &[0.5, -1.0, 2.0]).unwrap();
+        recorder.add_frame(&frame, &[]).unwrap();
+        assert_eq!(recorder.frames(), 2);
+        recorder.finish().unwrap();
+
+        let image = fs::read(dir.join("frame000001.ppm")).unwrap();
+        assert!(image.starts_with(b"P6\n256 240\n255\n\x10\x20\x30"));
+        assert_eq!(image.len(), 15 + (NES_WIDTH * NES_HEIGHT * 3) as usize);
+
+        let audio = fs::read(dir.join(AUDIO_FILE)).unwrap();
+        assert_eq!(audio.len(), WAV_HEADER_SIZE as usize + 6);
+        assert_eq!(audio[4..8], (36u32 + 6).to_le_bytes());
+        assert_eq!(audio[40..44], 6u32.to_le_bytes());
+        // clamped to full scale
+        assert_eq!(audio[44..], [0xFF, 0x3F, 0x01, 0x80, 0xFF, 0x7F]);
+
+        let manifest = fs::read_to_string(dir.join(MANIFEST_FILE)).unwrap();
+        assert!(manifest.contains("frames=2\n"));
+        assert!(manifest.contains("audio=48000 Hz mono\n"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+use wasm_bindgen::prelude::*;
+
+use crate::graphics::{NES_HEIGHT, NES_WIDTH};
+use crate::joypad::JoypadStatus;
+use crate::Emulator;
+
+// JavaScript interface for running in a browser, built with wasm-pack. The
+// page owns the main loop like the SDL frontend does:
+//
+//   const nes = Nes.load_rom(new Uint8Array(await rom.arrayBuffer()));
+//   function frame() {
+//       nes.set_buttons(0, buttons);
+//       nes.run_frame();
+//       const image = new ImageData(new Uint8ClampedArray(nes.frame_rgba()),
+//                                   Nes.width(), Nes.height());
+//       canvas.getContext("2d").putImageData(image, 0, 0);
+//       requestAnimationFrame(frame);
+//   }
+//
+// requestAnimationFrame runs at the display refresh rate, close enough to
+// the NES' 60.0988 Hz on most displays. See www/index.html.
+
+#[wasm_bindgen]
+pub struct Nes {
+    emulator: Emulator,
+}
+
+#[wasm_bindgen]
+impl Nes {
+    // Load an iNES image and power on
+    pub fn load_rom(rom: &[u8]) -> Result<Nes, JsError> {
+        let emulator = Emulator::from_rom_bytes(rom).map_err(|e| JsError::new(&e))?;
+        Ok(Nes { emulator })
+    }
+
+    pub fn width() -> u32 {
+        NES_WIDTH
+    }
+
+    pub fn height() -> u32 {
+        NES_HEIGHT
+    }
+
+    pub fn reset(&mut self) {
+        self.emulator.reset();
+    }
+
+    pub fn run_frame(&mut self) -> Result<(), JsError> {
+        self.emulator
+            .run_frame()
+            .map(|_| ())
+            .map_err(|e| JsError::new(&e.to_string()))
+    }
+
+    // The last frame as RGBA, the layout of canvas ImageData
+    pub fn frame_rgba(&self) -> Vec<u8> {
+        self.emulator.frame().to_rgba_bytes()
+    }
+
+    // Buttons held by `player` (0 or 1), one bit each: A, B, Select, Start,
+    // Up, Down, Left, Right from bit 0 up
+    pub fn set_buttons(&mut self, player: usize, buttons: u8) {
+        if player < 2 {
+            self.emulator
+                .set_buttons(player, JoypadStatus::from_bits_truncate(buttons));
+        }
+    }
+
+    // Audio samples since the last call, mono at the sample rate, for an
+    // AudioWorklet or a queue of AudioBuffers
+    pub fn audio_samples(&mut self) -> Vec<f32> {
+        self.emulator.audio_samples()
+    }
+
+    // The AudioContext's sampleRate, 44100 by default
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> Result<(), JsError> {
+        if sample_rate == 0 {
+            return Err(JsError::new("invalid sample rate 0"));
+        }
+        self.emulator.set_audio_sample_rate(sample_rate);
+        Ok(())
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        self.emulator.save_state()
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), JsError> {
+        self.emulator.load_state(data).map_err(|e| JsError::new(&e))
+    }
+}
@@ -0,0 +1,111 @@
+use crate::graphics::{NesFrame, NES_HEIGHT, NES_WIDTH};
+
+// The Zapper light gun, plugged into the second controller port. It has no
+// shift register, every $4017 read returns the current sensor state:
+//
+//   7  bit  0
+//   ---- ----
+//   xxxT Wxxx
+//      | |
+//      | +---- light sensed: 0 = detected, 1 = not detected
+//      +------ trigger: 1 = pulled
+//
+// The photodiode only reacts to the CRT beam passing over the spot the gun
+// points at, and stays on for a short while after. Games flash white
+// targets for a frame and poll the sensor while they're being drawn.
+//
+// Ref: https://wiki.nesdev.org/w/index.php/Zapper
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Zapper {
+    // screen position the gun points at, None when off screen
+    aim: Option<(u32, u32)>,
+    trigger: bool,
+}
+
+// Number of scanlines the sensor stays on after the beam passed the aim
+const LIGHT_SENSE_LINES: u32 = 20;
+// Minimum brightness (sum of RGB) that counts as light
+const LIGHT_THRESHOLD: u32 = 3 * 0xC0;
+
+impl Zapper {
+    pub fn new() -> Self {
+        Zapper {
+            aim: None,
+            trigger: false,
+        }
+    }
+
+    // Frontend hook: the gun position in NES pixels and the trigger state.
+    // Positions outside the screen are treated as pointing away from it.
+    pub fn set_aim(&mut self, aim: Option<(u32, u32)>, trigger: bool) {
+        self.aim = aim.filter(|&(x, y)| x < NES_WIDTH && y < NES_HEIGHT);
+        self.trigger = trigger;
+    }
+
+    // $4017 read. `frame` is the frame being rendered and `scanline` the
+    // line the PPU is on, the rows above it belong to the current frame.
+    pub fn read(&self, frame: &NesFrame, scanline: u32) -> u8 {
+        let mut value = 0;
+        if !self.senses_light(frame, scanline) {
+            value |= 0b0000_1000;
+        }
+        if self.trigger {
+            value |= 0b0001_0000;
+        }
+        value
+    }
+
+    fn senses_light(&self, frame: &NesFrame, scanline: u32) -> bool {
+        let (x, y) = match self.aim {
+            Some(aim) => aim,
+            None => return false,
+        };
+        if scanline <= y || scanline > y + LIGHT_SENSE_LINES {
+            return false;
+        }
+        let [r, g, b] = frame.pixel(x, y);
+        r as u32 + g as u32 + b as u32 >= LIGHT_THRESHOLD
+    }
+}
+
+impl Default for Zapper {
+    fn default() -> Self {
+        Zapper::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_trigger() {
+        let frame = NesFrame::new();
+        let mut zapper = Zapper::new();
+        assert_eq!(zapper.read(&frame, 0), 0x08);
+        zapper.set_aim(None, true);
+        assert_eq!(zapper.read(&frame, 0), 0x18);
+    }
+
+    #[test]
+    fn test_light_sense() {
+        let mut frame = NesFrame::new();
+        frame.set_pixel(100, 50, 0xFF, 0xFF, 0xFF);
+        let mut zapper = Zapper::new();
+
+        zapper.set_aim(Some((100, 50)), false);
+        // the beam hasn't reached the target yet
+        assert_eq!(zapper.read(&frame, 50), 0x08);
+        assert_eq!(zapper.read(&frame, 51), 0x00);
+        assert_eq!(zapper.read(&frame, 50 + LIGHT_SENSE_LINES), 0x00);
+        // the sensor turned off again
+        assert_eq!(zapper.read(&frame, 51 + LIGHT_SENSE_LINES), 0x08);
+
+        // dark pixel
+        zapper.set_aim(Some((101, 50)), false);
+        assert_eq!(zapper.read(&frame, 51), 0x08);
+
+        zapper.set_aim(Some((100, NES_HEIGHT)), false);
+        assert_eq!(zapper.read(&frame, 241), 0x08);
+    }
+}
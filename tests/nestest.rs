@@ -1,3 +1,4 @@
+use std::ops::ControlFlow;
 use std::path::PathBuf;
 
 use cpu::CPU;
@@ -18,15 +19,21 @@ fn test_nestest() {
     cpu.pc = 0xC000;
 
     let mut nes_log_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    nes_log_path.push("tests/resources/nestest.simplified.log");
+    nes_log_path.push("tests/resources/nestest.log");
 
     let nes_logs: String = std::fs::read_to_string(nes_log_path).expect("Can't read nestest logs");
-    let nes_log_lines: Vec<&str> = nes_logs.split("\n").collect();
+    let nes_log_lines: Vec<&str> = nes_logs.lines().collect();
     let mut line_idx = 0;
     cpu.run_with_callback(|cpu| {
         let trace_line = cpu.trace();
         // println!("{}", trace_line);
         assert_eq!(trace_line, nes_log_lines[line_idx]);
         line_idx += 1;
-    });
+        if line_idx == nes_log_lines.len() {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    })
+    .unwrap();
 }
@@ -0,0 +1,192 @@
+use std::path::{Path, PathBuf};
+
+use nes::cartridge::Mirror;
+use nes::cpu::assembler::{assemble_to_ines, InesConfig};
+use nes::Emulator;
+
+// Runs blargg's test ROMs headlessly. The ROMs are not in the repository,
+// put them in tests/resources/roms as they come in their archives, e.g.
+// tests/resources/roms/cpu_instrs/cpu_instrs.nes. Tests of missing ROMs
+// pass without running.
+//
+// The result is read like the ROMs report it: a status at $6000, 0x80
+// while running, 0x81 when they want a reset and the result code after,
+// followed by the signature DE B0 61 and the output text from $6004. On
+// cartridges without RAM at $6000 the text printed on screen is checked
+// for "Passed" or "Failed" instead.
+
+const ROMS_DIR: &str = "tests/resources/roms";
+// Longer than the slowest ROM, cpu_instrs, takes
+const TIMEOUT_FRAMES: u32 = 60 * 120;
+// The ROMs ask for a reset no sooner than 100 ms after they say so
+const RESET_DELAY_FRAMES: u32 = 10;
+
+const STATUS_ADDR: u16 = 0x6000;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_RESET: u8 = 0x81;
+
+fn status_text(emulator: &mut Emulator) -> String {
+    let bus = &mut emulator.cpu_mut().bus;
+    let mut text = vec![];
+    for addr in STATUS_ADDR + 4..=0x7FFF {
+        match bus.peek(addr) {
+            0 => break,
+            byte => text.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&text).into_owned()
+}
+
+// None if the ROM is missing
+fn run_rom(name: &str) -> Option<Result<String, String>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push(ROMS_DIR);
+    path.push(name);
+    if !path.exists() {
+        eprintln!("skipping {}, {} is missing", name, path.display());
+        return None;
+    }
+    Some(run_rom_file(&path))
+}
+
+// Ok with the output if the ROM passed, Err with it otherwise
+fn run_rom_file(path: &Path) -> Result<String, String> {
+    let mut emulator = match Emulator::from_file(path) {
+        Ok(emulator) => emulator,
+        Err(e) => return Err(format!("failed to load: {}", e)),
+    };
+
+    let mut reset_at = None;
+    for frame in 0..TIMEOUT_FRAMES {
+        if let Err(e) = emulator.run_frame() {
+            return Err(format!(
+                "{}\n{}",
+                e,
+                emulator.cpu().bus.ppu.nametable_text()
+            ));
+        }
+        if reset_at == Some(frame) {
+            emulator.reset();
+            reset_at = None;
+        }
+
+        let bus = &mut emulator.cpu_mut().bus;
+        let signature = [
+            bus.peek(STATUS_ADDR + 1),
+            bus.peek(STATUS_ADDR + 2),
+            bus.peek(STATUS_ADDR + 3),
+        ];
+        if signature == SIGNATURE {
+            match bus.peek(STATUS_ADDR) {
+                STATUS_RUNNING => {}
+                STATUS_RESET => {
+                    reset_at.get_or_insert(frame + RESET_DELAY_FRAMES);
+                }
+                0 => return Ok(status_text(&mut emulator)),
+                code => {
+                    let text = status_text(&mut emulator);
+                    return Err(format!("result {}\n{}", code, text));
+                }
+            }
+            continue;
+        }
+
+        let screen = emulator.cpu().bus.ppu.nametable_text();
+        if screen.contains("Failed") {
+            return Err(screen);
+        }
+        if screen.contains("Passed") {
+            return Ok(screen);
+        }
+    }
+    let screen = emulator.cpu().bus.ppu.nametable_text();
+    Err(format!(
+        "no result after {} frames\n{}",
+        TIMEOUT_FRAMES, screen
+    ))
+}
+
+fn assert_passes(name: &str) {
+    if let Some(Err(output)) = run_rom(name) {
+        panic!("{} failed:\n{}", name, output.trim());
+    }
+}
+
+#[test]
+fn test_cpu_instrs() {
+    assert_passes("cpu_instrs/cpu_instrs.nes");
+}
+
+#[test]
+fn test_instr_timing() {
+    assert_passes("instr_timing/instr_timing.nes");
+}
+
+#[test]
+fn test_ppu_vbl_nmi() {
+    assert_passes("ppu_vbl_nmi/ppu_vbl_nmi.nes");
+}
+
+#[test]
+fn test_apu_test() {
+    assert_passes("apu_test/apu_test.nes");
+}
+
+// An MMC1 ROM reporting `result` like the blargg ROMs do, to check the
+// harness without them
+fn write_reporting_rom(result: u8) -> PathBuf {
+    let code = format!(
+        "
+          reset:
+            ldx #0
+          text:
+            lda message,x
+            sta $6004,x
+            beq signature
+            inx
+            bne text
+          signature:
+            lda #$80
+            sta $6000
+            lda #$DE
+            sta $6001
+            lda #$B0
+            sta $6002
+            lda #$61
+            sta $6003
+            lda #{}
+            sta $6000
+          loop:
+            jmp loop
+          message:
+            .byte 'd', 'o', 'n', 'e', 0
+        ",
+        result
+    );
+    let config = InesConfig {
+        prg_banks: 2,
+        mapper: 1,
+        mirror: Mirror::Vertical,
+        ..InesConfig::default()
+    };
+    let rom = assemble_to_ines(&code, &config).unwrap();
+    let path = std::env::temp_dir().join(format!(
+        "nes-roms-test-{}-{}.nes",
+        std::process::id(),
+        result
+    ));
+    std::fs::write(&path, rom).unwrap();
+    path
+}
+
+#[test]
+fn test_harness() {
+    let path = write_reporting_rom(0);
+    assert_eq!(run_rom_file(&path), Ok("done".to_string()));
+    std::fs::remove_file(&path).unwrap();
+
+    let path = write_reporting_rom(3);
+    assert_eq!(run_rom_file(&path), Err("result 3\ndone".to_string()));
+    std::fs::remove_file(&path).unwrap();
+}